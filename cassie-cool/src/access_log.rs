@@ -0,0 +1,277 @@
+use crate::config::{data_dir, GatewayConfig};
+use crate::logs::{self, LogRotation, RotatingLog, CURRENT_LOG};
+use crate::metrics;
+use crate::panics::RequestId;
+use crate::util::now_millis;
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::web::Bytes;
+use actix_web::{Error, HttpMessage, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+///等待写入的记录上限 磁盘写入跟不上时丢弃新的记录
+const QUEUE_SIZE: usize = 10_000;
+///读取最近记录时最多读取的字节数
+const TAIL_BYTES: u64 = 1024 * 1024;
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+///访问日志 gateway.json 中的 access_log<br>
+/// 每个请求一行 JSON 写入 data/access_logs 按 rotation 轮转
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessLogConfig {
+  pub enabled: bool,
+  pub rotation: LogRotation,
+}
+
+impl Default for AccessLogConfig {
+  fn default() -> Self {
+    Self {
+      enabled: true,
+      rotation: LogRotation::default(),
+    }
+  }
+}
+
+///一条访问记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessEntry {
+  pub timestamp: u64,               //收到请求的时间
+  pub product_code: Option<String>, //转发到的产品 管理接口等网关自己处理的请求为空
+  pub method: String,
+  pub path: String, //不含查询参数 避免记录其中的凭据
+  pub status: u16,
+  pub latency_ms: u64, //从收到请求到响应体发送完成 客户端提前断开时到断开为止
+  pub bytes: u64,      //实际发送的响应体字节数
+  pub peer_ip: Option<String>,
+  pub request_id: Option<String>,
+}
+
+///转发时记下请求对应的产品
+struct ProductTag(String);
+
+///查询最近的访问记录
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct AccessQuery {
+  pub product_code: Option<String>,
+  pub status: Option<String>, //状态码 例如 404 或者 5xx
+  pub limit: Option<usize>,   //默认 100 最多 1000
+}
+
+lazy_static! {
+  static ref SENDER: Mutex<Option<SyncSender<AccessEntry>>> = Mutex::new(None);
+}
+
+pub fn log_dir() -> PathBuf {
+  data_dir().join("access_logs")
+}
+
+///转发的请求记下产品 预览请求记为预览产品
+pub fn tag(req: &HttpRequest, product_code: &str) {
+  req.extensions_mut().insert(ProductTag(product_code.to_string()));
+}
+
+fn send(entry: AccessEntry) {
+  let sender = SENDER.lock().unwrap();
+  let Some(sender) = sender.as_ref() else {
+    return;
+  };
+  if let Err(TrySendError::Full(_)) = sender.try_send(entry) {
+    metrics::inc_counter(
+      "gateway_access_log_dropped_total",
+      "Access log entries dropped because the writer fell behind",
+      &[],
+      1,
+    );
+  }
+}
+
+///响应体发送完成或者被丢弃时写入记录 同时统计发送的字节数
+struct Counted {
+  body: BoxBody,
+  entry: Option<AccessEntry>,
+  started: Instant,
+}
+
+impl MessageBody for Counted {
+  type Error = Box<dyn std::error::Error>;
+
+  fn size(&self) -> BodySize {
+    self.body.size()
+  }
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+    let this = self.get_mut();
+    let next = Pin::new(&mut this.body).poll_next(cx);
+    if let (Poll::Ready(Some(Ok(chunk))), Some(entry)) = (&next, this.entry.as_mut()) {
+      entry.bytes += chunk.len() as u64;
+    }
+    next
+  }
+}
+
+impl Drop for Counted {
+  fn drop(&mut self) {
+    if let Some(mut entry) = self.entry.take() {
+      entry.latency_ms = self.started.elapsed().as_millis() as u64;
+      send(entry);
+    }
+  }
+}
+
+///记录所有经过网关的请求 包括转发到产品的和管理接口
+pub fn record<S>(req: ServiceRequest, srv: &S) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+  S::Future: 'static,
+{
+  if SENDER.lock().unwrap().is_none() {
+    return Box::pin(srv.call(req));
+  }
+  let started = Instant::now();
+  let mut entry = AccessEntry {
+    timestamp: now_millis(),
+    product_code: None,
+    method: req.method().to_string(),
+    path: req.path().to_string(),
+    status: 0,
+    latency_ms: 0,
+    bytes: 0,
+    peer_ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+    request_id: None,
+  };
+  let fut = srv.call(req);
+  Box::pin(async move {
+    match fut.await {
+      Ok(res) => {
+        entry.status = res.status().as_u16();
+        entry.product_code = res.request().extensions().get::<ProductTag>().map(|t| t.0.clone());
+        entry.request_id = res.request().extensions().get::<RequestId>().map(|r| r.0.clone());
+        Ok(res.map_body(|_, body| {
+          BoxBody::new(Counted {
+            body,
+            entry: Some(entry),
+            started,
+          })
+        }))
+      }
+      Err(err) => {
+        entry.status = err.as_response_error().status_code().as_u16();
+        entry.latency_ms = started.elapsed().as_millis() as u64;
+        send(entry);
+        Err(err)
+      }
+    }
+  })
+}
+
+fn matches(entry: &AccessEntry, query: &AccessQuery) -> bool {
+  let product_ok = match &query.product_code {
+    Some(product_code) => entry.product_code.as_deref() == Some(product_code.as_str()),
+    None => true,
+  };
+  let status_ok = match query.status.as_deref().map(|s| s.trim().to_ascii_lowercase()) {
+    Some(status) if status.len() == 3 && status.ends_with("xx") => entry.status / 100 == status[..1].parse::<u16>().unwrap_or(0),
+    Some(status) => status.parse::<u16>().map(|s| s == entry.status).unwrap_or(false),
+    None => true,
+  };
+  product_ok && status_ok
+}
+
+///按条件过滤 返回最后 limit 条 无法解析的行跳过
+fn select(lines: Vec<String>, query: &AccessQuery) -> Vec<AccessEntry> {
+  let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+  let entries: Vec<AccessEntry> = lines
+    .iter()
+    .filter_map(|line| serde_json::from_str::<AccessEntry>(line).ok())
+    .filter(|entry| matches(entry, query))
+    .collect();
+  entries[entries.len().saturating_sub(limit)..].to_vec()
+}
+
+///正在写入的文件中最近的记录 只读取文件末尾的一段 旧的记录在前
+pub fn recent(query: &AccessQuery) -> std::io::Result<Vec<AccessEntry>> {
+  let lines = logs::tail_file(&log_dir().join(CURRENT_LOG), TAIL_BYTES, usize::MAX)?;
+  Ok(select(lines, query))
+}
+
+///打开访问日志 在单独的线程中写入 请求处理中只把记录放入队列
+pub fn start() {
+  let config = GatewayConfig::load().map(|c| c.access_log).unwrap_or_default();
+  if !config.enabled {
+    return;
+  }
+  let mut file = match RotatingLog::open(log_dir(), config.rotation) {
+    Ok(file) => file,
+    Err(err) => {
+      log::error!("failed to open access log: {}", err);
+      return;
+    }
+  };
+  let (tx, rx) = sync_channel::<AccessEntry>(QUEUE_SIZE);
+  let spawned = std::thread::Builder::new().name("access-log".to_string()).spawn(move || {
+    for entry in rx {
+      let line = match serde_json::to_string(&entry) {
+        Ok(line) => line,
+        Err(_) => continue,
+      };
+      if let Err(err) = file.write(&format!("{}\n", line)) {
+        log::error!("failed to write access log: {}", err);
+      }
+    }
+  });
+  match spawned {
+    Ok(_) => *SENDER.lock().unwrap() = Some(tx),
+    Err(err) => log::error!("failed to start access log writer: {}", err),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn filters_recent_entries() {
+    let entry = |product_code: Option<&str>, status: u16| {
+      let entry = AccessEntry {
+        timestamp: 1,
+        product_code: product_code.map(|p| p.to_string()),
+        method: "GET".to_string(),
+        path: "/".to_string(),
+        status,
+        latency_ms: 3,
+        bytes: 10,
+        peer_ip: None,
+        request_id: None,
+      };
+      serde_json::to_string(&entry).unwrap()
+    };
+    let lines = vec![
+      entry(Some("shop"), 200),
+      "not json".to_string(),
+      entry(Some("shop"), 502),
+      entry(None, 404),
+      entry(Some("blog"), 503),
+      entry(Some("shop"), 500),
+    ];
+    let query = |product_code: Option<&str>, status: Option<&str>, limit: Option<usize>| AccessQuery {
+      product_code: product_code.map(|p| p.to_string()),
+      status: status.map(|s| s.to_string()),
+      limit,
+    };
+    let statuses = |entries: Vec<AccessEntry>| entries.iter().map(|e| e.status).collect::<Vec<_>>();
+    assert_eq!(statuses(select(lines.clone(), &query(None, None, None))), vec![200, 502, 404, 503, 500]);
+    assert_eq!(statuses(select(lines.clone(), &query(Some("shop"), Some("5xx"), None))), vec![502, 500]);
+    assert_eq!(statuses(select(lines.clone(), &query(Some("shop"), None, Some(1)))), vec![500]);
+    assert_eq!(statuses(select(lines, &query(None, Some("404"), None))), vec![404]);
+  }
+}
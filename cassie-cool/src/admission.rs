@@ -0,0 +1,235 @@
+use crate::config::ProductConfig;
+use crate::metrics;
+use crate::util::now_millis;
+use crate::worker_util::PORT_TABLE;
+use deno_runtime::ops::context::reported_pressure;
+use futures_util::future::join_all;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+///检查哪些产品需要轮询的间隔
+const TICK: Duration = Duration::from_millis(500);
+///轮询压力接口的超时 超时后视为没有压力值
+const POLL_TIMEOUT: Duration = Duration::from_secs(1);
+
+///按 runtime 的压力控制接受的请求 cool.json 中的 admission<br>
+/// 压力为 0 到 1 的数 来自轮询 pressure_path 或脚本调用 Platform.reportPressure 两者取较大的值<br>
+/// 压力超过 start 后接受的比例线性下降 压力为 1 时只接受 min_accept 比例的请求
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdmissionPolicy {
+  pub pressure_path: Option<String>, //runtime 中返回压力的路径 响应为数字或 {"pressure": 0.5} 不配置时只使用脚本报告的压力
+  pub interval_ms: u64,              //轮询间隔
+  pub max_age_ms: u64,               //压力超过这个时间没有更新时忽略
+  pub start: f64,                    //压力超过这个值后开始减少接受的请求
+  pub min_accept: f64,               //压力为 1 时仍接受的比例 让压力有机会回落
+}
+
+impl Default for AdmissionPolicy {
+  fn default() -> Self {
+    Self {
+      pressure_path: None,
+      interval_ms: 1000,
+      max_age_ms: 5000,
+      start: 0.6,
+      min_accept: 0.05,
+    }
+  }
+}
+
+impl AdmissionPolicy {
+  ///压力对应的接受比例
+  fn accept_ratio(&self, pressure: f64) -> f64 {
+    if pressure <= self.start || self.start >= 1.0 {
+      return 1.0;
+    }
+    let excess = ((pressure - self.start) / (1.0 - self.start)).min(1.0);
+    1.0 - (1.0 - self.min_accept.clamp(0.0, 1.0)) * excess
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdmissionReport {
+  pub policy: AdmissionPolicy,
+  pub polled: Option<f64>,   //最近一次轮询的压力
+  pub reported: Option<f64>, //脚本报告的压力 多个 runtime 取最大值
+  pub pressure: Option<f64>,
+  pub accept_ratio: f64,
+}
+
+#[derive(Default)]
+struct State {
+  polled: Option<(f64, u64)>, //(压力, 时间)
+  polled_at: u64,             //最近一次轮询的时间 失败也会更新
+  credit: f64,                //每个请求累加接受比例 满 1 时接受一个请求
+}
+
+lazy_static! {
+  static ref STATES: Mutex<HashMap<String, State>> = Mutex::new(HashMap::new());
+}
+
+///解析压力接口的响应 数字或带 pressure 字段的对象
+fn parse_pressure(body: &[u8]) -> Option<f64> {
+  let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+  let pressure = match &value {
+    serde_json::Value::Object(object) => object.get("pressure")?.as_f64()?,
+    value => value.as_f64()?,
+  };
+  pressure.is_finite().then_some(pressure.clamp(0.0, 1.0))
+}
+
+///当前的 (轮询的压力, 脚本报告的压力) 过期的值为空
+fn pressures(product_code: &str, policy: &AdmissionPolicy) -> (Option<f64>, Option<f64>) {
+  let max_age = Duration::from_millis(policy.max_age_ms);
+  let now = now_millis();
+  let polled = STATES
+    .lock()
+    .unwrap()
+    .get(product_code)
+    .and_then(|s| s.polled)
+    .filter(|(_, at)| now.saturating_sub(*at) <= policy.max_age_ms)
+    .map(|(pressure, _)| pressure);
+  (polled, reported_pressure(product_code, max_age))
+}
+
+///累加接受比例 满 1 时接受 不用随机数 接受的请求均匀分布
+fn take_credit(credit: &mut f64, ratio: f64) -> bool {
+  *credit += ratio;
+  if *credit >= 1.0 {
+    *credit -= 1.0;
+    return true;
+  }
+  false
+}
+
+///是否接受产品的这个请求 没有压力值时全部接受
+pub fn admit(product_code: &str, policy: &AdmissionPolicy) -> bool {
+  let (polled, reported) = pressures(product_code, policy);
+  let Some(pressure) = polled.into_iter().chain(reported).reduce(f64::max) else {
+    return true;
+  };
+  let ratio = policy.accept_ratio(pressure);
+  metrics::set_gauge(
+    "product_admission_ratio",
+    "Share of requests accepted under runtime pressure",
+    &[("product", product_code)],
+    ratio,
+  );
+  let mut states = STATES.lock().unwrap();
+  let state = states.entry(product_code.to_string()).or_default();
+  if take_credit(&mut state.credit, ratio) {
+    return true;
+  }
+  metrics::inc_counter(
+    "product_admission_rejected_total",
+    "Requests rejected because the runtime reported pressure",
+    &[("product", product_code)],
+    1,
+  );
+  false
+}
+
+async fn poll(client: &reqwest::Client, product_code: &str, port: u16, path: &str) {
+  let url = format!("http://127.0.0.1:{}{}", port, path);
+  let result = match client.get(&url).send().await {
+    Ok(res) if res.status().is_success() => res.bytes().await.ok().and_then(|body| parse_pressure(&body)),
+    Ok(res) => {
+      log::debug!("{} pressure endpoint returned {}", product_code, res.status());
+      None
+    }
+    Err(err) => {
+      log::debug!("failed to poll {} pressure: {}", product_code, err);
+      None
+    }
+  };
+  let now = now_millis();
+  let mut states = STATES.lock().unwrap();
+  let state = states.entry(product_code.to_string()).or_default();
+  state.polled_at = now;
+  if let Some(pressure) = result {
+    state.polled = Some((pressure, now));
+    metrics::set_gauge(
+      "product_pressure",
+      "Pressure polled from the runtime",
+      &[("product", product_code)],
+      pressure,
+    );
+  }
+}
+
+///轮询到期的产品 运行中的产品在 PORT_TABLE 中 有多个端口副本时轮询第一个
+async fn tick(client: &reqwest::Client) {
+  let ports: Vec<(String, u16)> = PORT_TABLE
+    .read()
+    .unwrap()
+    .iter()
+    .filter_map(|(id, ports)| ports.first().map(|entry| (id.0.clone(), entry.port.0)))
+    .collect();
+  let now = now_millis();
+  let mut due = vec![];
+  for (product_code, port) in ports {
+    let policy = ProductConfig::load(&product_code).map(|c| c.admission).unwrap_or_default();
+    let Some(path) = policy.pressure_path else {
+      continue;
+    };
+    let polled_at = STATES.lock().unwrap().get(&product_code).map(|s| s.polled_at).unwrap_or(0);
+    if now.saturating_sub(polled_at) >= policy.interval_ms {
+      due.push((product_code, port, path));
+    }
+  }
+  join_all(due.iter().map(|(product_code, port, path)| poll(client, product_code, *port, path))).await;
+}
+
+///产品当前的压力和接受比例
+pub fn report(product_code: &str) -> AdmissionReport {
+  let policy = ProductConfig::load(product_code).map(|c| c.admission).unwrap_or_default();
+  let (polled, reported) = pressures(product_code, &policy);
+  let pressure = polled.into_iter().chain(reported).reduce(f64::max);
+  AdmissionReport {
+    accept_ratio: pressure.map(|p| policy.accept_ratio(p)).unwrap_or(1.0),
+    policy,
+    polled,
+    reported,
+    pressure,
+  }
+}
+
+///定时轮询配置了压力接口的产品
+pub fn start() {
+  let client = match reqwest::Client::builder().timeout(POLL_TIMEOUT).build() {
+    Ok(client) => client,
+    Err(err) => {
+      log::error!("failed to create the pressure polling client: {}", err);
+      return;
+    }
+  };
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+      interval.tick().await;
+      tick(&client).await;
+    }
+  });
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn accepts_fewer_requests_as_pressure_rises() {
+    let policy = AdmissionPolicy::default();
+    assert_eq!(policy.accept_ratio(0.3), 1.0);
+    assert!((policy.accept_ratio(0.8) - 0.525).abs() < 1e-9);
+    assert!((policy.accept_ratio(1.0) - 0.05).abs() < 1e-9);
+    let mut credit = 0.0;
+    let accepted = (0..100).filter(|_| take_credit(&mut credit, 0.25)).count();
+    assert_eq!(accepted, 25);
+    assert_eq!(parse_pressure(b"0.7"), Some(0.7));
+    assert_eq!(parse_pressure(br#"{"pressure": 3}"#), Some(1.0));
+    assert_eq!(parse_pressure(br#"{"status": "ok"}"#), None);
+  }
+}
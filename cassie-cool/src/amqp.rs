@@ -0,0 +1,270 @@
+use crate::upstream::Conn;
+use deno_core::error::{generic_error, AnyError};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const PROTOCOL_HEADER: &[u8] = b"AMQP\x00\x00\x09\x01";
+const FRAME_METHOD: u8 = 1;
+const FRAME_HEADER: u8 = 2;
+const FRAME_BODY: u8 = 3;
+const FRAME_END: u8 = 0xce;
+const CHANNEL: u16 = 1;
+///服务端不限制帧大小时使用的值
+const DEFAULT_FRAME_MAX: u32 = 128 * 1024;
+
+///收到的一条消息
+#[derive(Debug, Clone)]
+pub struct Delivery {
+  pub delivery_tag: u64,
+  pub redelivered: bool,
+  pub routing_key: String,
+  pub content_type: Option<String>,
+  pub body: Vec<u8>,
+}
+
+///AMQP 0-9-1 的消费端 只实现消费队列需要的方法<br>
+/// 不使用心跳 消息手动确认 连接断开时没有确认的消息回到队列
+pub struct AmqpSession {
+  conn: Box<dyn Conn>,
+}
+
+#[derive(Default)]
+struct Args(Vec<u8>);
+
+impl Args {
+  fn u8(mut self, value: u8) -> Self {
+    self.0.push(value);
+    self
+  }
+
+  fn u16(mut self, value: u16) -> Self {
+    self.0.extend(value.to_be_bytes());
+    self
+  }
+
+  fn u32(mut self, value: u32) -> Self {
+    self.0.extend(value.to_be_bytes());
+    self
+  }
+
+  fn u64(mut self, value: u64) -> Self {
+    self.0.extend(value.to_be_bytes());
+    self
+  }
+
+  fn shortstr(mut self, value: &str) -> Self {
+    self.0.push(value.len().min(255) as u8);
+    self.0.extend(&value.as_bytes()[..value.len().min(255)]);
+    self
+  }
+
+  fn longstr(self, value: &[u8]) -> Self {
+    let mut args = self.u32(value.len() as u32);
+    args.0.extend(value);
+    args
+  }
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, n: usize) -> Result<&'a [u8], AnyError> {
+    if n > self.bytes.len() {
+      return Err(generic_error("truncated amqp frame"));
+    }
+    let (head, rest) = self.bytes.split_at(n);
+    self.bytes = rest;
+    Ok(head)
+  }
+
+  fn u8(&mut self) -> Result<u8, AnyError> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn u16(&mut self) -> Result<u16, AnyError> {
+    Ok(u16::from_be_bytes(self.take(2)?.try_into()?))
+  }
+
+  fn u32(&mut self) -> Result<u32, AnyError> {
+    Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+  }
+
+  fn u64(&mut self) -> Result<u64, AnyError> {
+    Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+  }
+
+  fn shortstr(&mut self) -> Result<String, AnyError> {
+    let len = self.u8()? as usize;
+    Ok(String::from_utf8_lossy(self.take(len)?).to_string())
+  }
+}
+
+fn frame(kind: u8, channel: u16, payload: &[u8]) -> Vec<u8> {
+  let mut out = vec![kind];
+  out.extend(channel.to_be_bytes());
+  out.extend((payload.len() as u32).to_be_bytes());
+  out.extend(payload);
+  out.push(FRAME_END);
+  out
+}
+
+fn method(channel: u16, class: u16, method: u16, args: Args) -> Vec<u8> {
+  let payload = Args::default().u16(class).u16(method).0.into_iter().chain(args.0).collect::<Vec<_>>();
+  frame(FRAME_METHOD, channel, &payload)
+}
+
+///Connection.Close 和 Channel.Close 中的原因
+fn close_reason(args: &[u8]) -> String {
+  let mut reader = Reader { bytes: args };
+  match (reader.u16(), reader.shortstr()) {
+    (Ok(code), Ok(text)) => format!("amqp server closed the connection: {} {}", code, text),
+    _ => "amqp server closed the connection".to_string(),
+  }
+}
+
+impl AmqpSession {
+  ///握手 打开 vhost 和通道 1
+  pub async fn connect(conn: Box<dyn Conn>, vhost: &str, username: &str, password: &str) -> Result<Self, AnyError> {
+    let mut session = Self { conn };
+    session.conn.write_all(PROTOCOL_HEADER).await?;
+    session.expect(0, 10, 10).await?;
+    let response = format!("\0{}\0{}", username, password);
+    let start_ok = Args::default().u32(0).shortstr("PLAIN").longstr(response.as_bytes()).shortstr("en_US");
+    session.send(method(0, 10, 11, start_ok)).await?;
+    let tune = session.expect(0, 10, 30).await?;
+    let mut reader = Reader { bytes: &tune };
+    let (channel_max, frame_max) = (reader.u16()?, reader.u32()?);
+    let frame_max = if frame_max == 0 { DEFAULT_FRAME_MAX } else { frame_max };
+    session
+      .send(method(0, 10, 31, Args::default().u16(channel_max).u32(frame_max).u16(0)))
+      .await?;
+    session
+      .send(method(0, 10, 40, Args::default().shortstr(vhost).shortstr("").u8(0)))
+      .await?;
+    session.expect(0, 10, 41).await?;
+    session.send(method(CHANNEL, 20, 10, Args::default().shortstr(""))).await?;
+    session.expect(CHANNEL, 20, 11).await?;
+    Ok(session)
+  }
+
+  ///设置 prefetch 后开始消费队列 需要手动确认
+  pub async fn consume(&mut self, queue: &str, prefetch: u16) -> Result<(), AnyError> {
+    self.send(method(CHANNEL, 60, 10, Args::default().u32(0).u16(prefetch).u8(0))).await?;
+    self.expect(CHANNEL, 60, 11).await?;
+    let consume = Args::default().u16(0).shortstr(queue).shortstr("").u8(0).u32(0);
+    self.send(method(CHANNEL, 60, 20, consume)).await?;
+    self.expect(CHANNEL, 60, 21).await?;
+    Ok(())
+  }
+
+  ///下一条消息 Basic.Deliver 之后是内容头和若干内容帧
+  pub async fn next(&mut self) -> Result<Delivery, AnyError> {
+    let args = self.expect(CHANNEL, 60, 60).await?;
+    let mut reader = Reader { bytes: &args };
+    reader.shortstr()?;
+    let delivery_tag = reader.u64()?;
+    let redelivered = reader.u8()? & 0x01 != 0;
+    reader.shortstr()?;
+    let routing_key = reader.shortstr()?;
+    let header = self.read_content(FRAME_HEADER).await?;
+    let mut reader = Reader { bytes: &header };
+    reader.take(4)?;
+    let body_size = reader.u64()? as usize;
+    //content-type 是第一个属性
+    let content_type = match reader.u16()? & 0x8000 {
+      0 => None,
+      _ => Some(reader.shortstr()?),
+    };
+    let mut body = Vec::with_capacity(body_size);
+    while body.len() < body_size {
+      body.extend(self.read_content(FRAME_BODY).await?);
+    }
+    Ok(Delivery {
+      delivery_tag,
+      redelivered,
+      routing_key,
+      content_type,
+      body,
+    })
+  }
+
+  ///处理完成后确认 消息从队列中删除
+  pub async fn ack(&mut self, delivery_tag: u64) -> Result<(), AnyError> {
+    self.send(method(CHANNEL, 60, 80, Args::default().u64(delivery_tag).u8(0))).await
+  }
+
+  async fn send(&mut self, bytes: Vec<u8>) -> Result<(), AnyError> {
+    self.conn.write_all(&bytes).await?;
+    self.conn.flush().await?;
+    Ok(())
+  }
+
+  async fn read_frame(&mut self) -> Result<(u8, u16, Vec<u8>), AnyError> {
+    let mut header = [0u8; 7];
+    self.conn.read_exact(&mut header).await?;
+    let size = u32::from_be_bytes(header[3..7].try_into()?) as usize;
+    let mut payload = vec![0u8; size + 1];
+    self.conn.read_exact(&mut payload).await?;
+    if payload.pop() != Some(FRAME_END) {
+      return Err(generic_error("malformed amqp frame"));
+    }
+    Ok((header[0], u16::from_be_bytes([header[1], header[2]]), payload))
+  }
+
+  ///等待指定的方法 跳过心跳 服务端关闭连接或通道时返回错误
+  async fn expect(&mut self, channel: u16, class: u16, method_id: u16) -> Result<Vec<u8>, AnyError> {
+    loop {
+      let (kind, frame_channel, payload) = self.read_frame().await?;
+      if kind != FRAME_METHOD {
+        continue;
+      }
+      let mut reader = Reader { bytes: &payload };
+      let (got_class, got_method) = (reader.u16()?, reader.u16()?);
+      match (got_class, got_method) {
+        (10, 50) | (20, 40) => return Err(generic_error(close_reason(reader.bytes))),
+        _ if frame_channel == channel && got_class == class && got_method == method_id => return Ok(reader.bytes.to_vec()),
+        _ => continue,
+      }
+    }
+  }
+
+  async fn read_content(&mut self, kind: u8) -> Result<Vec<u8>, AnyError> {
+    loop {
+      let (frame_kind, _, payload) = self.read_frame().await?;
+      match frame_kind {
+        FRAME_METHOD => {
+          let mut reader = Reader { bytes: &payload };
+          if let (Ok(10), Ok(50)) | (Ok(20), Ok(40)) = (reader.u16(), reader.u16()) {
+            return Err(generic_error(close_reason(reader.bytes)));
+          }
+          return Err(generic_error("amqp content frame expected"));
+        }
+        frame_kind if frame_kind == kind => return Ok(payload),
+        _ => continue,
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn encodes_method_frames() {
+    let bytes = method(CHANNEL, 60, 80, Args::default().u64(42).u8(0));
+    assert_eq!(&bytes[..7], &[FRAME_METHOD, 0, 1, 0, 0, 0, 13]);
+    assert_eq!(bytes.last(), Some(&FRAME_END));
+    let mut reader = Reader {
+      bytes: &bytes[7..bytes.len() - 1],
+    };
+    assert_eq!((reader.u16().unwrap(), reader.u16().unwrap(), reader.u64().unwrap()), (60, 80, 42));
+    let args = Args::default().u16(404).shortstr("NOT_FOUND - no queue 'orders'");
+    assert_eq!(
+      close_reason(&args.0),
+      "amqp server closed the connection: 404 NOT_FOUND - no queue 'orders'"
+    );
+    assert!(Reader { bytes: &[0, 1] }.u32().is_err());
+  }
+}
@@ -0,0 +1,310 @@
+use crate::config::{GatewayConfig, ProductConfig};
+use crate::metrics;
+use crate::notifier::{self, Notification, Severity};
+use crate::util::now_millis;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+///内存中保留的异常事件数
+const MAX_EVENTS: usize = 500;
+
+///请求量和错误率的异常检测 gateway.json 中的 anomaly<br>
+/// 每个窗口统计一次请求数和 5xx 比例 与 EWMA 基线比较 z-score 超过阈值时产生事件
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AnomalyConfig {
+  pub window_secs: u64,      //统计窗口 0 表示关闭检测
+  pub alpha: f64,            //EWMA 平滑系数 越大基线越快适应新的流量
+  pub sensitivity: f64,      //z-score 阈值 越小越敏感
+  pub warmup_windows: u32,   //建立基线需要的窗口数 之前不产生事件
+  pub min_requests: u64,     //窗口内请求数低于这个值时不判断错误率和流量下跌
+  pub cooldown_windows: u32, //同一类异常产生事件后 这么多个窗口内不再重复
+}
+
+impl Default for AnomalyConfig {
+  fn default() -> Self {
+    Self {
+      window_secs: 60,
+      alpha: 0.1,
+      sensitivity: 4.0,
+      warmup_windows: 30,
+      min_requests: 20,
+      cooldown_windows: 10,
+    }
+  }
+}
+
+///产品的异常检测设置 cool.json 中的 anomaly
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AnomalyPolicy {
+  pub enabled: bool,
+  pub sensitivity: Option<f64>, //覆盖网关的阈值
+}
+
+impl Default for AnomalyPolicy {
+  fn default() -> Self {
+    Self {
+      enabled: true,
+      sensitivity: None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyKind {
+  TrafficSpike, //请求量突增
+  TrafficDrop,  //请求量骤降
+  ErrorSpike,   //5xx 比例突增
+}
+
+impl AnomalyKind {
+  fn as_str(&self) -> &'static str {
+    match self {
+      Self::TrafficSpike => "traffic_spike",
+      Self::TrafficDrop => "traffic_drop",
+      Self::ErrorSpike => "error_spike",
+    }
+  }
+}
+
+///指数加权的均值和方差
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Ewma {
+  pub mean: f64,
+  pub variance: f64,
+  pub samples: u32,
+}
+
+impl Ewma {
+  fn update(&mut self, value: f64, alpha: f64) {
+    if self.samples == 0 {
+      self.mean = value;
+      self.variance = 0.0;
+    } else {
+      let diff = value - self.mean;
+      let incr = alpha * diff;
+      self.mean += incr;
+      self.variance = (1.0 - alpha) * (self.variance + diff * incr);
+    }
+    self.samples = self.samples.saturating_add(1);
+  }
+
+  ///标准差低于 min_std 时按 min_std 计算 避免平稳流量上的微小波动被放大
+  fn z_score(&self, value: f64, min_std: f64) -> f64 {
+    (value - self.mean) / self.variance.sqrt().max(min_std)
+  }
+}
+
+///产品的基线
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Baseline {
+  pub requests: Ewma,   //每个窗口的请求数
+  pub error_rate: Ewma, //每个窗口的 5xx 比例 请求数足够的窗口才计入
+  pub last_requests: u64,
+  pub last_errors: u64,
+  pub cooldown: BTreeMap<AnomalyKind, u32>, //剩余的静默窗口数
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnomalyEvent {
+  pub product_code: String,
+  pub kind: AnomalyKind,
+  pub value: f64,    //本窗口的请求数或错误率
+  pub expected: f64, //基线均值
+  pub z_score: f64,
+  pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnomalyReport {
+  pub baseline: Option<Baseline>,
+  pub events: Vec<AnomalyEvent>, //最近的事件 新的在前
+}
+
+lazy_static! {
+  static ref COUNTS: Mutex<HashMap<String, (u64, u64)>> = Mutex::new(HashMap::new()); //当前窗口的 (请求数, 错误数)
+  static ref BASELINES: Mutex<HashMap<String, Baseline>> = Mutex::new(HashMap::new());
+  static ref EVENTS: Mutex<VecDeque<AnomalyEvent>> = Mutex::new(VecDeque::new());
+}
+
+///记录一次转发的请求 error 为 runtime 返回了 5xx
+pub fn record(product_code: &str, error: bool) {
+  let mut counts = COUNTS.lock().unwrap();
+  let entry = counts.entry(product_code.to_string()).or_default();
+  entry.0 += 1;
+  if error {
+    entry.1 += 1;
+  }
+}
+
+///用一个窗口的数据与基线比较 然后更新基线 返回 (类型, 值, 基线均值, z-score)
+fn evaluate(baseline: &mut Baseline, requests: u64, errors: u64, config: &AnomalyConfig, sensitivity: f64) -> Vec<(AnomalyKind, f64, f64, f64)> {
+  let mut found = vec![];
+  let rate = requests as f64;
+  let error_rate = if requests > 0 { errors as f64 / requests as f64 } else { 0.0 };
+  let enough = requests >= config.min_requests;
+  if baseline.requests.samples >= config.warmup_windows {
+    //请求数近似泊松分布 标准差至少为均值的平方根
+    let mean = baseline.requests.mean;
+    let z = baseline.requests.z_score(rate, mean.sqrt().max(1.0));
+    if z >= sensitivity {
+      found.push((AnomalyKind::TrafficSpike, rate, mean, z));
+    } else if z <= -sensitivity && mean >= config.min_requests as f64 {
+      found.push((AnomalyKind::TrafficDrop, rate, mean, z));
+    }
+  }
+  if enough && baseline.error_rate.samples >= config.warmup_windows {
+    let z = baseline.error_rate.z_score(error_rate, 0.01);
+    if z >= sensitivity {
+      found.push((AnomalyKind::ErrorSpike, error_rate, baseline.error_rate.mean, z));
+    }
+  }
+  baseline.cooldown.retain(|_, left| {
+    *left -= 1;
+    *left > 0
+  });
+  found.retain(|(kind, ..)| !baseline.cooldown.contains_key(kind));
+  for (kind, ..) in &found {
+    if config.cooldown_windows > 0 {
+      baseline.cooldown.insert(*kind, config.cooldown_windows);
+    }
+  }
+  baseline.requests.update(rate, config.alpha);
+  if enough {
+    baseline.error_rate.update(error_rate, config.alpha);
+  }
+  baseline.last_requests = requests;
+  baseline.last_errors = errors;
+  found
+}
+
+fn raise(event: AnomalyEvent) {
+  let kind = event.kind.as_str();
+  log::warn!(
+    "{} anomaly {}: value {:.3} expected {:.3} z-score {:.1}",
+    event.product_code,
+    kind,
+    event.value,
+    event.expected,
+    event.z_score
+  );
+  metrics::inc_counter(
+    "gateway_anomalies_total",
+    "Traffic and error rate anomalies detected per product",
+    &[("product", &event.product_code), ("kind", kind)],
+    1,
+  );
+  let severity = match event.kind {
+    AnomalyKind::ErrorSpike => Severity::Critical,
+    _ => Severity::Warning,
+  };
+  let message = match event.kind {
+    AnomalyKind::ErrorSpike => format!(
+      "error rate {:.1}% against a baseline of {:.1}%",
+      event.value * 100.0,
+      event.expected * 100.0
+    ),
+    _ => format!("{} requests in the last window against a baseline of {:.0}", event.value, event.expected),
+  };
+  notifier::notify(Notification::new(
+    &format!("anomaly.{}", kind),
+    &event.product_code,
+    severity,
+    message,
+    serde_json::to_value(&event).unwrap_or_default(),
+  ));
+  let mut events = EVENTS.lock().unwrap();
+  if events.len() >= MAX_EVENTS {
+    events.pop_front();
+  }
+  events.push_back(event);
+}
+
+///结束一个窗口 所有有基线或有请求的产品都参与比较 没有请求的窗口按 0 计算
+fn tick(config: &AnomalyConfig) {
+  let counts = std::mem::take(&mut *COUNTS.lock().unwrap());
+  let mut products: Vec<String> = BASELINES.lock().unwrap().keys().cloned().collect();
+  products.extend(counts.keys().filter(|p| !products.contains(p)).cloned().collect::<Vec<_>>());
+  for product_code in products {
+    let policy = ProductConfig::load(&product_code).map(|c| c.anomaly).unwrap_or_default();
+    let (requests, errors) = counts.get(&product_code).copied().unwrap_or_default();
+    let found = {
+      let mut baselines = BASELINES.lock().unwrap();
+      let baseline = baselines.entry(product_code.clone()).or_default();
+      evaluate(baseline, requests, errors, config, policy.sensitivity.unwrap_or(config.sensitivity))
+    };
+    if !policy.enabled {
+      continue;
+    }
+    for (kind, value, expected, z_score) in found {
+      raise(AnomalyEvent {
+        product_code: product_code.clone(),
+        kind,
+        value,
+        expected,
+        z_score,
+        created_at: now_millis(),
+      });
+    }
+  }
+}
+
+///产品当前的基线和最近的异常事件
+pub fn report(product_code: &str) -> AnomalyReport {
+  AnomalyReport {
+    baseline: BASELINES.lock().unwrap().get(product_code).cloned(),
+    events: EVENTS
+      .lock()
+      .unwrap()
+      .iter()
+      .rev()
+      .filter(|e| e.product_code == product_code)
+      .cloned()
+      .collect(),
+  }
+}
+
+///按网关配置的窗口定时检测
+pub fn start() {
+  let config = GatewayConfig::load().map(|c| c.anomaly).unwrap_or_default();
+  if config.window_secs == 0 {
+    return;
+  }
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.window_secs));
+    interval.tick().await;
+    loop {
+      interval.tick().await;
+      tick(&config);
+    }
+  });
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn detects_error_spike_after_warmup() {
+    let config = AnomalyConfig {
+      warmup_windows: 5,
+      ..Default::default()
+    };
+    let mut baseline = Baseline::default();
+    for _ in 0..5 {
+      assert!(evaluate(&mut baseline, 100, 1, &config, 4.0).is_empty());
+    }
+    let found = evaluate(&mut baseline, 100, 40, &config, 4.0);
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].0, AnomalyKind::ErrorSpike);
+    //冷却期内不重复
+    assert!(evaluate(&mut baseline, 100, 40, &config, 4.0).is_empty());
+    let found = evaluate(&mut baseline, 1000, 10, &config, 4.0);
+    assert_eq!(found[0].0, AnomalyKind::TrafficSpike);
+  }
+}
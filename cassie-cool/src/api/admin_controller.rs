@@ -0,0 +1,530 @@
+use crate::auth::{self, error_response, Role};
+use crate::billing::{self, ExportRequest};
+use crate::config::GatewayConfig;
+use crate::dry_run::{self, DryRunQuery};
+use crate::list_query::{self, ListQuery};
+use crate::product_package::{self, ImportRequest};
+use crate::staging::{self, CloneRequest};
+use crate::tenants::{self, Tenant, TenantQuota};
+use crate::trusted_cas::{self, AddTrustedCa};
+use crate::users::{self, UserUpdate};
+use crate::{artifacts, audit_log, doctor, encryption, git_hooks, gitops, graphql, overload, panics, retention, state_snapshot, Res};
+use actix_web::{get, http::header, post, web, HttpRequest, HttpResponse};
+use deno_core::error::generic_error;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+
+///导入的产品包大小上限
+const MAX_PACKAGE_BYTES: usize = 200 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CreateTenant {
+  name: String,
+  #[serde(default)]
+  quota: TenantQuota,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CreatedTenant {
+  tenant: Tenant,
+  api_key: String, //只返回这一次
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AssignOwner {
+  tenant_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Rewrap {
+  master_key_env: String, //保存新主密钥的环境变量
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RestoreState {
+  snapshot: String,       //快照名称
+  target: String,         //恢复到的产品 不能是快照来自的产品 需要先停止
+  worker: Option<String>, //使用哪个 runtime 的临时目录 默认第一个
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CreateUser {
+  email: String,
+  password: String,
+  role: Role,
+  tenant_id: Option<String>, //绑定租户后只能访问 /tenant 下的接口
+}
+
+///所有租户
+#[get("/tenants")]
+pub async fn get_tenants() -> HttpResponse {
+  match tenants::list_tenants() {
+    Ok(tenants) => Res { code: 0, data: tenants }.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///创建租户 返回的 api key 用于访问 /tenant 下的接口
+#[post("/tenants")]
+pub async fn create_tenant(info: web::Json<CreateTenant>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  let info = info.into_inner();
+  if query.dry_run {
+    return dry_run::respond(tenants::create_tenant(&info.name, info.quota, true).and_then(|(tenant, _)| dry_run::preview(&(), &tenant)));
+  }
+  match tenants::create_tenant(&info.name, info.quota, false) {
+    Ok((tenant, api_key)) => Res {
+      code: 0,
+      data: CreatedTenant { tenant, api_key },
+    }
+    .respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///指定产品归属的租户
+#[post("/products/{product_code}/owner")]
+pub async fn assign_owner(path: web::Path<(String,)>, info: web::Json<AssignOwner>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  if query.dry_run {
+    let after = tenants::assign_owner(&product_code, &info.tenant_id, true);
+    return dry_run::respond(tenants::product_meta(&product_code).and_then(|before| dry_run::preview(&before, &after?)));
+  }
+  match tenants::assign_owner(&product_code, &info.into_inner().tenant_id, false) {
+    Ok(meta) => Res { code: 0, data: meta }.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///导出用量报告 <br>
+/// 按产品和周期统计请求数 CPU 秒数 出口流量 存储 后台生成 CSV 或 JSON<br>
+/// 返回导出任务 完成后任务中带下载链接
+#[post("/usage/export")]
+pub async fn export_usage(info: web::Json<ExportRequest>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  if query.dry_run {
+    return dry_run::unsupported();
+  }
+  match billing::start_export(info.into_inner()) {
+    Ok(job) => Res { code: 0, data: job }.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///查询导出任务
+#[get("/usage/export/{id}")]
+pub async fn get_usage_export(path: web::Path<(String,)>) -> HttpResponse {
+  let id = path.into_inner().0;
+  match billing::export_job(&id) {
+    Some(job) => Res { code: 0, data: job }.respond_to(),
+    None => Res {
+      code: -1,
+      data: "export not found".to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///下载平台生成的文件
+#[get("/artifacts/{kind}/{name}")]
+pub async fn download_artifact(path: web::Path<(String, String)>) -> HttpResponse {
+  let (kind, name) = path.into_inner();
+  let content_type = match name.rsplit('.').next() {
+    Some("csv") => "text/csv",
+    Some("json") => "application/json",
+    _ => "application/octet-stream",
+  };
+  match artifacts::read_artifact(&kind, &name).await {
+    Ok(bytes) => HttpResponse::Ok()
+      .content_type(content_type)
+      .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", name)))
+      .body(bytes),
+    Err(_) => Res {
+      code: -1,
+      data: "artifact not found".to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///控制台用户
+#[get("/users")]
+pub async fn get_users() -> HttpResponse {
+  match users::list_users() {
+    Ok(users) => Res { code: 0, data: users }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+#[post("/users")]
+pub async fn create_user(info: web::Json<CreateUser>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  let info = info.into_inner();
+  if query.dry_run {
+    let after = users::create_user(&info.email, &info.password, info.role, info.tenant_id, true);
+    return dry_run::respond(after.and_then(|user| dry_run::preview(&(), &user)));
+  }
+  match users::create_user(&info.email, &info.password, info.role, info.tenant_id, false) {
+    Ok(user) => Res { code: 0, data: user }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///修改角色 重置密码 绑定租户
+#[post("/users/{id}")]
+pub async fn update_user(path: web::Path<(String,)>, info: web::Json<UserUpdate>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  let id = path.into_inner().0;
+  if query.dry_run {
+    let after = users::update_user(&id, info.into_inner(), true);
+    return dry_run::respond(users::get_user(&id).and_then(|before| dry_run::preview(&before, &after?)));
+  }
+  match users::update_user(&id, info.into_inner(), false) {
+    Ok(user) => Res { code: 0, data: user }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+#[post("/users/{id}/delete")]
+pub async fn delete_user(path: web::Path<(String,)>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  let id = path.into_inner().0;
+  if query.dry_run {
+    return dry_run::respond(users::delete_user(&id, true).and_then(|user| dry_run::preview(&user, &())));
+  }
+  match users::delete_user(&id, false) {
+    Ok(_) => Res {
+      code: 0,
+      data: "删除成功".to_string(),
+    }
+    .respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///用户丢失验证器时由管理员关闭两步验证
+#[post("/users/{id}/totp/reset")]
+pub async fn reset_user_totp(path: web::Path<(String,)>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  let id = path.into_inner().0;
+  if query.dry_run {
+    let after = users::disable_totp(&id, None, true);
+    return dry_run::respond(users::get_user(&id).and_then(|before| dry_run::preview(&before, &after?)));
+  }
+  match users::disable_totp(&id, None, false) {
+    Ok(user) => Res { code: 0, data: user }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///校验产品审计日志的 hash 链 并与检查点比对
+#[get("/audit/{product_code}/verify")]
+pub async fn verify_audit_log(path: web::Path<(String,)>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  match audit_log::verify(&product_code) {
+    Ok(report) => Res {
+      code: if report.valid { 0 } else { -1 },
+      data: report,
+    }
+    .respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+#[get("/audit/{product_code}/checkpoints")]
+pub async fn get_audit_checkpoints(path: web::Path<(String,)>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  match audit_log::read_checkpoints(&product_code) {
+    Ok(checkpoints) => Res { code: 0, data: checkpoints }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///立即生成检查点并导出日志 链头没有变化时返回 null
+#[post("/audit/{product_code}/checkpoint")]
+pub async fn create_audit_checkpoint(path: web::Path<(String,)>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  if query.dry_run {
+    return dry_run::unsupported();
+  }
+  let product_code = path.into_inner().0;
+  let export_dir = GatewayConfig::load().ok().and_then(|c| c.audit.export_dir);
+  match audit_log::checkpoint(&product_code, export_dir.as_deref()).await {
+    Ok(checkpoint) => Res { code: 0, data: checkpoint }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///按保留期会删除的数据 不实际删除
+#[get("/retention/report")]
+pub async fn retention_report() -> HttpResponse {
+  match retention::run_with_gateway_config(true).await {
+    Ok(report) => Res { code: 0, data: report }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///立即按保留期清理 dry_run 时与 /retention/report 相同
+#[post("/retention/run")]
+pub async fn run_retention(query: web::Query<DryRunQuery>) -> HttpResponse {
+  match retention::run_with_gateway_config(query.dry_run).await {
+    Ok(report) => Res { code: 0, data: report }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///静态加密的状态和数据密钥
+#[get("/encryption")]
+pub async fn encryption_status() -> HttpResponse {
+  match encryption::status() {
+    Ok(status) => Res { code: 0, data: status }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///生成新的数据密钥并重新加密产品代码和版本快照
+#[post("/encryption/rotate")]
+pub async fn rotate_data_key(query: web::Query<DryRunQuery>) -> HttpResponse {
+  if query.dry_run {
+    return dry_run::unsupported();
+  }
+  match tokio::task::spawn_blocking(encryption::rotate_data_key).await {
+    Ok(Ok(report)) => Res { code: 0, data: report }.respond_to(),
+    Ok(Err(err)) => error_response(err),
+    Err(err) => error_response(err.into()),
+  }
+}
+
+///用新的主密钥重新包装数据密钥 之后需要修改 gateway.json 的主密钥配置
+#[post("/encryption/rewrap")]
+pub async fn rewrap_master_key(info: web::Json<Rewrap>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  if query.dry_run {
+    return dry_run::unsupported();
+  }
+  match encryption::rewrap(&info.master_key_env) {
+    Ok(status) => Res { code: 0, data: status }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///导出产品的 KV 存储和临时目录
+#[post("/state/{product_code}/snapshot")]
+pub async fn snapshot_state(path: web::Path<String>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  if query.dry_run {
+    return dry_run::unsupported();
+  }
+  match state_snapshot::snapshot(&path.into_inner()).await {
+    Ok(snapshot) => Res { code: 0, data: snapshot }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+#[get("/state/{product_code}/snapshots")]
+pub async fn get_state_snapshots(path: web::Path<String>) -> HttpResponse {
+  match state_snapshot::list(&path.into_inner()).await {
+    Ok(snapshots) => Res { code: 0, data: snapshots }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///把状态快照恢复到预发产品
+#[post("/state/restore")]
+pub async fn restore_state(info: web::Json<RestoreState>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  if query.dry_run {
+    return dry_run::unsupported();
+  }
+  let info = info.into_inner();
+  match state_snapshot::restore(&info.snapshot, &info.target, info.worker).await {
+    Ok(report) => Res { code: 0, data: report }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///复制产品 用于创建预发环境
+#[post("/clone")]
+pub async fn clone_product(info: web::Json<CloneRequest>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  if query.dry_run {
+    return dry_run::unsupported();
+  }
+  match staging::clone_product(&info.into_inner()).await {
+    Ok(report) => Res { code: 0, data: report }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///git 钩子的投递记录
+#[get("/hooks/{hook}/deliveries")]
+pub async fn get_hook_deliveries(path: web::Path<String>) -> HttpResponse {
+  match git_hooks::deliveries(&path.into_inner()) {
+    Ok(deliveries) => Res { code: 0, data: deliveries }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///用原投递的提交重新部署
+#[post("/hooks/{hook}/deliveries/{id}/replay")]
+pub async fn replay_hook_delivery(path: web::Path<(String, String)>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  if query.dry_run {
+    return dry_run::unsupported();
+  }
+  let (hook, id) = path.into_inner();
+  match git_hooks::replay(&hook, &id) {
+    Ok(delivery) => Res { code: 0, data: delivery }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///网关请求处理中最近的 panic
+#[get("/panics")]
+pub async fn get_panics(list: web::Query<ListQuery>) -> HttpResponse {
+  match list_query::apply(panics::list(), &list, &panics::LIST_SPEC) {
+    Ok(page) => page.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///网关的负载 过载时拒绝的优先级和最近的级别变化
+#[get("/overload")]
+pub async fn get_overload() -> HttpResponse {
+  Res {
+    code: 0,
+    data: overload::report(),
+  }
+  .respond_to()
+}
+
+///网关 GraphQL 入口注册的产品 根字段和冲突
+#[get("/graphql")]
+pub async fn get_graphql() -> HttpResponse {
+  Res {
+    code: 0,
+    data: graphql::report(),
+  }
+  .respond_to()
+}
+
+///GitOps 同步状态和平台与清单的偏差
+#[get("/gitops")]
+pub async fn get_gitops_status() -> HttpResponse {
+  match gitops::status() {
+    Ok(status) => Res { code: 0, data: status }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///立即拉取配置仓库并同步 dry_run 时只计算偏差<br>
+/// 需要批准时只生成计划
+#[post("/gitops/sync")]
+pub async fn sync_gitops(query: web::Query<DryRunQuery>) -> HttpResponse {
+  match gitops::sync(query.dry_run).await {
+    Ok(status) => Res { code: 0, data: status }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///批准并执行待批准的同步计划
+#[post("/gitops/plans/{id}/approve")]
+pub async fn approve_gitops_plan(req: HttpRequest, path: web::Path<String>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  if query.dry_run {
+    return dry_run::unsupported();
+  }
+  let approved_by = auth::authenticate(&req).ok().map(|p| p.email);
+  match gitops::approve(&path.into_inner(), approved_by).await {
+    Ok(report) => Res { code: 0, data: report }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExportQuery {
+  #[serde(default)]
+  version: Option<String>, //默认最新部署的版本 current 为正在编辑的代码
+}
+
+///导出产品为签名的包 包含代码版本 配置 import map 远程模块锁定表和产品信息
+#[get("/export/{product_code}")]
+pub async fn export_product(path: web::Path<String>, query: web::Query<ExportQuery>) -> HttpResponse {
+  match product_package::export(&path.into_inner(), query.into_inner().version).await {
+    Ok((name, bytes)) => HttpResponse::Ok()
+      .content_type("application/gzip")
+      .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", name)))
+      .body(bytes),
+    Err(err) => error_response(err),
+  }
+}
+
+///导入其他网关导出的产品包 请求体为包的原始字节 校验签名和文件摘要后写入
+#[post("/import")]
+pub async fn import_product(mut payload: web::Payload, query: web::Query<ImportRequest>, dry_run: web::Query<DryRunQuery>) -> HttpResponse {
+  let mut body = web::BytesMut::new();
+  while let Some(chunk) = payload.next().await {
+    let chunk = match chunk {
+      Ok(chunk) => chunk,
+      Err(err) => return error_response(generic_error(err.to_string())),
+    };
+    if body.len() + chunk.len() > MAX_PACKAGE_BYTES {
+      return HttpResponse::PayloadTooLarge().finish();
+    }
+    body.extend_from_slice(&chunk);
+  }
+  match product_package::import(body.to_vec(), &query, dry_run.dry_run).await {
+    Ok(report) => Res { code: 0, data: report }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///运行自检 端口检查只在启动时进行
+#[get("/doctor")]
+pub async fn run_doctor() -> HttpResponse {
+  Res {
+    code: 0,
+    data: doctor::run(false).await,
+  }
+  .respond_to()
+}
+
+///runtime fetch 额外信任的根证书
+#[get("/cas")]
+pub async fn get_trusted_cas() -> HttpResponse {
+  match trusted_cas::list() {
+    Ok(cas) => Res { code: 0, data: cas }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///上传 PEM 证书 products 为空时对所有产品生效 runtime 下次启动时生效
+#[post("/cas")]
+pub async fn add_trusted_ca(info: web::Json<AddTrustedCa>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  if query.dry_run {
+    return dry_run::respond(trusted_cas::add(info.into_inner(), true).and_then(|ca| dry_run::preview(&(), &ca)));
+  }
+  match trusted_cas::add(info.into_inner(), false) {
+    Ok(ca) => Res { code: 0, data: ca }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+#[post("/cas/{id}/delete")]
+pub async fn remove_trusted_ca(path: web::Path<(String,)>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  let id = path.into_inner().0;
+  if query.dry_run {
+    return dry_run::respond(trusted_cas::remove(&id, true).and_then(|ca| dry_run::preview(&ca, &())));
+  }
+  match trusted_cas::remove(&id, false) {
+    Ok(_) => Res {
+      code: 0,
+      data: "删除成功".to_string(),
+    }
+    .respond_to(),
+    Err(err) => error_response(err),
+  }
+}
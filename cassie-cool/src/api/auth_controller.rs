@@ -0,0 +1,136 @@
+use crate::auth::{self, error_response, CSRF_COOKIE, SESSION_COOKIE};
+use crate::users::{self, User};
+use crate::Res;
+use actix_web::cookie::time::Duration;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Login {
+  email: String,
+  password: String,
+  totp_code: Option<String>, //开启两步验证后必填
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LoginResult {
+  user: User,
+  csrf_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChangePassword {
+  old_password: String,
+  new_password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TotpCode {
+  code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TotpSetup {
+  secret: String,
+  otpauth_url: String, //生成二维码给验证器扫描
+}
+
+fn cookie(name: &str, value: String, http_only: bool, max_age: Duration) -> Cookie<'static> {
+  Cookie::build(name.to_string(), value)
+    .path("/")
+    .http_only(http_only)
+    .same_site(SameSite::Strict)
+    .max_age(max_age)
+    .finish()
+}
+
+///邮箱密码登录 使用 LDAP 时为目录中的登录名和密码 开启了两步验证时还需要验证码<br>
+/// 会话放在 HttpOnly 的 cookie 中 修改类请求需要在 x-csrf-token 请求头中带上返回的 csrf_token
+#[post("/login")]
+pub async fn login(info: web::Json<Login>) -> HttpResponse {
+  let info = info.into_inner();
+  match auth::login(&info.email, &info.password, info.totp_code.as_deref()).await {
+    Ok((token, session, user)) => {
+      let max_age = Duration::seconds(auth::session_ttl_secs() as i64);
+      let mut res = Res {
+        code: 0,
+        data: LoginResult {
+          user,
+          csrf_token: session.csrf_token.clone(),
+        },
+      }
+      .respond_to();
+      let _ = res.add_cookie(&cookie(SESSION_COOKIE, token, true, max_age));
+      let _ = res.add_cookie(&cookie(CSRF_COOKIE, session.csrf_token, false, max_age));
+      res
+    }
+    Err(err) => error_response(err),
+  }
+}
+
+#[post("/logout")]
+pub async fn logout(req: HttpRequest) -> HttpResponse {
+  if let Err(err) = auth::authenticate(&req) {
+    return error_response(err);
+  }
+  if let Some(token) = req.cookie(SESSION_COOKIE) {
+    users::logout(token.value());
+  }
+  let mut res = Res {
+    code: 0,
+    data: "已退出".to_string(),
+  }
+  .respond_to();
+  let _ = res.add_cookie(&cookie(SESSION_COOKIE, String::new(), true, Duration::ZERO));
+  let _ = res.add_cookie(&cookie(CSRF_COOKIE, String::new(), false, Duration::ZERO));
+  res
+}
+
+///当前登录的用户
+#[get("/me")]
+pub async fn me(req: HttpRequest) -> HttpResponse {
+  match auth::authenticate(&req).and_then(|p| users::get_user(&p.user_id)) {
+    Ok(user) => Res { code: 0, data: user }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+#[post("/password")]
+pub async fn change_password(req: HttpRequest, info: web::Json<ChangePassword>) -> HttpResponse {
+  let info = info.into_inner();
+  match auth::authenticate(&req).and_then(|p| users::change_password(&p.user_id, &info.old_password, &info.new_password)) {
+    Ok(user) => Res { code: 0, data: user }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///生成两步验证的密钥 用 /totp/enable 确认后生效
+#[post("/totp/setup")]
+pub async fn setup_totp(req: HttpRequest) -> HttpResponse {
+  match auth::authenticate(&req).and_then(|p| users::begin_totp(&p.user_id)) {
+    Ok((secret, otpauth_url)) => Res {
+      code: 0,
+      data: TotpSetup { secret, otpauth_url },
+    }
+    .respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+#[post("/totp/enable")]
+pub async fn enable_totp(req: HttpRequest, info: web::Json<TotpCode>) -> HttpResponse {
+  match auth::authenticate(&req).and_then(|p| users::enable_totp(&p.user_id, &info.code)) {
+    Ok(user) => Res { code: 0, data: user }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///关闭两步验证 需要当前的验证码
+#[post("/totp/disable")]
+pub async fn disable_totp(req: HttpRequest, info: web::Json<TotpCode>) -> HttpResponse {
+  match auth::authenticate(&req).and_then(|p| users::disable_totp(&p.user_id, Some(info.code.as_str()), false)) {
+    Ok(user) => Res { code: 0, data: user }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
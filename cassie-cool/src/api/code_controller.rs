@@ -1,14 +1,31 @@
-use crate::Res;
-use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use crate::auth::{self, error_response, Role};
+use crate::catalog::{self, CatalogMeta, CatalogQuery};
+use crate::config::product_dir;
+use crate::dry_run::{self, DryRunQuery};
+use crate::list_query::{self, ListQuery};
+use crate::patch::{self, PatchRequest};
+use crate::search::{self, SearchQuery};
+use crate::templates::{self, InsertTemplate};
+use crate::tree_index::{self, TreeSnapshot};
+use crate::{bench, collab, encryption, media, tenants, test_watch, trash, Res};
+use actix_web::http::header::{self, HeaderName};
+use actix_web::{get, post, web, Error, HttpRequest, HttpResponse};
 use build_fs_tree::{dir, file, Build, MergeableFileSystemTree};
+use deno_core::error::{custom_error, generic_error, AnyError};
+use deno_runtime::at_rest;
+use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
 use std::{
   collections::HashMap,
   path::{Path, PathBuf},
   sync::Mutex,
 };
-use tokio::fs::{read_to_string, remove_dir_all, remove_file, rename, File};
-use walkdir::WalkDir;
+use tokio::fs::{rename, File};
+
+///目录树索引的版本 文件有变化时递增
+const TREE_GENERATION_HEADER: &str = "x-tree-generation";
+///上传文件的大小上限
+const MAX_UPLOAD_BYTES: usize = 20 * 1024 * 1024;
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CodeFile {
   id: String,
@@ -62,8 +79,22 @@ pub async fn get_code(req: HttpRequest, path: web::Path<(String,)>) -> HttpRespo
   let file = File::open(initial_cwd.clone()).await;
   match file {
     Ok(_) => {
-      let contents = read_to_string(initial_cwd).await.unwrap();
-      let res = Res { code: 0, data: contents };
+      let bytes = match read_file(&initial_cwd).await {
+        Ok(bytes) => bytes,
+        Err(err) => return error_response(err),
+      };
+      //二进制文件通过 raw 接口读取
+      if media::is_binary(&bytes) {
+        return Res {
+          code: -1,
+          data: format!("binary file, use /code/{}/raw", path.0),
+        }
+        .respond_to();
+      }
+      let res = Res {
+        code: 0,
+        data: String::from_utf8_lossy(&bytes).to_string(),
+      };
       return res.respond_to();
     }
     Err(_) => {
@@ -76,6 +107,103 @@ pub async fn get_code(req: HttpRequest, path: web::Path<(String,)>) -> HttpRespo
   }
 }
 
+///编辑器请求中的文件路径 各级用 | 分隔 不能跳出产品目录
+fn code_path(req: &HttpRequest, id: &str) -> Result<PathBuf, AnyError> {
+  let product_code = req
+    .headers()
+    .get("product_code")
+    .and_then(|p| p.to_str().ok())
+    .ok_or_else(|| custom_error("NotFound", "product_code not found"))?;
+  resolve_id(product_code, id)
+}
+
+fn resolve_id(product_code: &str, id: &str) -> Result<PathBuf, AnyError> {
+  //product_code 来自请求头 和路径一样不能跳出产品目录
+  if !tenants::valid_code(product_code) {
+    return Err(generic_error(format!("invalid product code {}", product_code)));
+  }
+  let mut path = product_dir(product_code);
+  for part in id.split('|') {
+    if part.is_empty() || part == "." || part == ".." || part.contains(['/', '\\']) {
+      return Err(generic_error(format!("invalid path {}", id)));
+    }
+    path.push(part);
+  }
+  Ok(path)
+}
+
+///读取并解密文件
+async fn read_file(path: &Path) -> Result<Vec<u8>, AnyError> {
+  match tokio::fs::read(path).await {
+    Ok(bytes) => at_rest::decrypt(bytes),
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => Err(custom_error("NotFound", "file not found")),
+    Err(err) => Err(err.into()),
+  }
+}
+
+///按原始字节读取文件 图片字体等二进制文件使用 content-type 按文件头和扩展名识别
+#[get("/{id}/raw")]
+pub async fn get_raw(req: HttpRequest, path: web::Path<(String,)>) -> HttpResponse {
+  let file = match code_path(&req, &path.0) {
+    Ok(file) => file,
+    Err(err) => return error_response(err),
+  };
+  match read_file(&file).await {
+    Ok(bytes) => HttpResponse::Ok().content_type(media::content_type(&path.0, &bytes)).body(bytes),
+    Err(err) => error_response(err),
+  }
+}
+
+///文件类型 大小 是否二进制和图片尺寸 编辑器据此选择编辑或预览
+#[get("/{id}/meta")]
+pub async fn get_meta(req: HttpRequest, path: web::Path<(String,)>) -> HttpResponse {
+  let file = match code_path(&req, &path.0) {
+    Ok(file) => file,
+    Err(err) => return error_response(err),
+  };
+  match read_file(&file).await {
+    Ok(bytes) => Res {
+      code: 0,
+      data: media::file_meta(&path.0, &bytes),
+    }
+    .respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///上传文件 请求体为文件的原始字节 上级目录不存在时创建
+#[post("/{id}/raw")]
+pub async fn put_raw(req: HttpRequest, path: web::Path<(String,)>, mut payload: web::Payload) -> HttpResponse {
+  let file = match code_path(&req, &path.0) {
+    Ok(file) => file,
+    Err(err) => return error_response(err),
+  };
+  let mut body = web::BytesMut::new();
+  while let Some(chunk) = payload.next().await {
+    let chunk = match chunk {
+      Ok(chunk) => chunk,
+      Err(err) => return error_response(generic_error(err.to_string())),
+    };
+    if body.len() + chunk.len() > MAX_UPLOAD_BYTES {
+      return HttpResponse::PayloadTooLarge().finish();
+    }
+    body.extend_from_slice(&chunk);
+  }
+  let res = async {
+    tokio::fs::create_dir_all(file.parent().unwrap()).await?;
+    encryption::write(&file, &body).await
+  }
+  .await;
+  match res {
+    Ok(_) => Res {
+      code: 0,
+      data: media::file_meta(&path.0, &body),
+    }
+    .respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
 //文件操作
 #[post("/file/{op}/operation")]
 pub async fn operation(
@@ -137,13 +265,11 @@ pub async fn operation(
       }
       .respond_to();
     }
+    //删除的文件移到回收站 可以通过 /code/trash 恢复
     "delete" => {
-      if isfile {
-        initial_cwd.push(cname);
-        let _ = remove_file(initial_cwd).await;
-      } else {
-        initial_cwd.push(cname);
-        let _ = remove_dir_all(initial_cwd).await;
+      initial_cwd.push(cname);
+      if let Err(err) = trash::discard(product_code, &initial_cwd, "delete") {
+        return error_response(err);
       }
       return Res {
         code: 0,
@@ -173,6 +299,12 @@ pub async fn operation(
           before.push(bname);
           let mut after = initial_cwd.clone();
           after.push(cname);
+          //会被覆盖的文件先移到回收站
+          if before != after {
+            if let Err(err) = trash::discard(product_code, &after, "rename") {
+              return error_response(err);
+            }
+          }
           let _ = rename(before.to_str().unwrap(), after.to_str().unwrap()).await;
         }
       };
@@ -208,15 +340,39 @@ pub async fn update_content(req: HttpRequest, info: web::Json<CodeFile>) -> Http
   parent_path.for_each(|item: &str| {
     initial_cwd.push(item);
   });
+  //协作编辑中的文件通过协作通道修改
+  if info.r#type == "file" && collab::is_open(&initial_cwd.join(&name)) {
+    return Res {
+      code: -1,
+      data: format!("{} is being edited together, changes go through the collab channel", name),
+    }
+    .respond_to();
+  }
+  //二进制文件不能按文本覆盖 通过 raw 接口上传
+  if info.r#type == "file" {
+    if let Ok(bytes) = read_file(&initial_cwd.join(&name)).await {
+      if media::is_binary(&bytes) {
+        return Res {
+          code: -1,
+          data: format!("{} is a binary file, upload it with the raw endpoint", name),
+        }
+        .respond_to();
+      }
+    }
+  }
   let res = match info.r#type.as_str() {
-    "file" => MergeableFileSystemTree::<String, String>::from(dir! {
-      name => file!(contents)
-    })
-    .build(initial_cwd),
+    //开启静态加密时文件内容加密后写入
+    "file" => async {
+      tokio::fs::create_dir_all(&initial_cwd).await?;
+      encryption::write(initial_cwd.join(name), contents).await
+    }
+    .await
+    .map_err(|err| err.to_string()),
     _ => MergeableFileSystemTree::<String, String>::from(dir! {
       name => dir!{}
     })
-    .build(initial_cwd),
+    .build(initial_cwd)
+    .map_err(|err| err.to_string()),
   };
   match res {
     Ok(_) => {
@@ -236,12 +392,65 @@ pub async fn update_content(req: HttpRequest, info: web::Json<CodeFile>) -> Http
   }
 }
 
-///获取代码文件目录树
+///目录树查询参数
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TreeQuery {
+  #[serde(default = "default_contents")]
+  contents: bool, //大目录树可以关闭 只返回结构 文件内容通过 get_code 按需读取
+}
+
+fn default_contents() -> bool {
+  true
+}
+
+///从索引生成目录树 文件内容分批并行读取
+fn build_tree(snapshot: TreeSnapshot, with_contents: bool) -> Vec<CodeFile> {
+  let ids: HashMap<&str, &str> = snapshot.entries.iter().map(|(k, e)| (k.as_str(), e.id.as_str())).collect();
+  let contents = match with_contents {
+    true => tree_index::batched(snapshot.entries.iter().collect(), |(key, entry)| match entry.is_dir {
+      true => None,
+      false => {
+        let path = key.split('|').fold(snapshot.root.clone(), |p, part| p.join(part));
+        std::fs::read(path)
+          .ok()
+          .and_then(|bytes| at_rest::decrypt(bytes).ok())
+          .and_then(|bytes| String::from_utf8(bytes).ok())
+      }
+    }),
+    false => vec![None; snapshot.entries.len()],
+  };
+  snapshot
+    .entries
+    .iter()
+    .zip(contents)
+    .map(|((key, entry), contents)| {
+      //如果是顶级目录的话为root
+      let (parent_path, name) = match key.rsplit_once('|') {
+        Some((parent_path, name)) => (parent_path.to_string(), name.to_string()),
+        None => ("root".to_string(), key.clone()),
+      };
+      CodeFile {
+        id: entry.id.clone(),
+        name,
+        r#type: if entry.is_dir { "directory" } else { "file" }.to_string(),
+        parent: ids
+          .get(parent_path.as_str())
+          .map(|id| id.to_string())
+          .unwrap_or_else(|| parent_path.clone()),
+        parent_path,
+        created_at: entry.created_at,
+        contents,
+      }
+    })
+    .collect()
+}
+
+///获取代码文件目录树<br>
+/// 目录树来自文件监听维护的索引 响应头 ETag 为索引的 generation 请求带上 If-None-Match 且没有变化时返回 304
 #[get("/file_tree")]
-pub async fn file_tree(req: HttpRequest) -> HttpResponse {
-  let mut initial_cwd = std::env::current_dir().unwrap();
+pub async fn file_tree(req: HttpRequest, query: web::Query<TreeQuery>) -> HttpResponse {
   let product_code = match req.headers().get("product_code") {
-    Some(p) => p.to_str().unwrap(),
+    Some(p) => p.to_str().unwrap().to_string(),
     None => {
       return Res {
         code: 0,
@@ -250,56 +459,195 @@ pub async fn file_tree(req: HttpRequest) -> HttpResponse {
       .respond_to();
     }
   };
-  let mut code_path = PathBuf::new();
-  code_path.push("code");
-  code_path.push(product_code.clone());
-  initial_cwd.push("code");
-  initial_cwd.push(product_code.clone());
-  let base = initial_cwd.clone();
-  let mut result = vec![];
-  let mut path_map = HashMap::new();
-  for entry in WalkDir::new(initial_cwd).follow_links(true).into_iter().filter_map(|e| e.ok()) {
-    let metadata = entry.metadata().unwrap();
-    let path = entry.path();
-    if path.ends_with(product_code) {
-      continue;
-    }
-    let (ftype, contents) = match metadata.is_dir() {
-      true => ("directory".to_string(), None),
-      false => {
-        let contents = read_to_string(path.to_str().unwrap()).await.unwrap();
-        ("file".to_string(), Some(contents))
-      }
-    };
-    let name = entry.file_name().clone().to_str().unwrap();
-
-    //如果是顶级目录的话为root
-    let mut parent_path = "root".to_string();
-    //去掉前缀
-    let path = path.strip_prefix(base.clone()).unwrap();
-    let ids: Vec<String> = path.iter().map(|item| item.to_str().unwrap().to_string()).collect();
-    let curr_path = ids.join("|");
-    let id: String = uuid::Uuid::new_v4().to_string();
-    path_map.insert(curr_path.clone(), id.clone());
-    if let Some(p) = path.parent() {
-      if Path::new("") != p {
-        let pids: Vec<String> = p.iter().map(|item| item.to_str().unwrap().to_string()).collect();
-        parent_path = pids.join("|");
-      }
+  let snapshot = match tokio::task::spawn_blocking(move || tree_index::snapshot(&product_code)).await {
+    Ok(Ok(snapshot)) => snapshot,
+    Ok(Err(err)) => return error_response(err),
+    Err(err) => return error_response(err.into()),
+  };
+  let etag = format!("\"{}\"", snapshot.generation);
+  if req.headers().get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()) == Some(etag.as_str()) {
+    return HttpResponse::NotModified().insert_header((header::ETAG, etag)).finish();
+  }
+  let generation = snapshot.generation;
+  let with_contents = query.contents;
+  let result = match tokio::task::spawn_blocking(move || build_tree(snapshot, with_contents)).await {
+    Ok(result) => result,
+    Err(err) => return error_response(err.into()),
+  };
+  let mut res = Res { code: 0, data: result }.respond_to();
+  res.headers_mut().insert(header::ETAG, etag.parse().unwrap());
+  res
+    .headers_mut()
+    .insert(HeaderName::from_static(TREE_GENERATION_HEADER), generation.into());
+  res
+}
+
+///应用补丁 unified diff 或按文件的编辑操作 <br>
+/// 所有文件都没有冲突时才写入 有冲突时返回冲突列表 dry_run 只检查不写入
+#[post("/patch/{product_code}")]
+pub async fn patch_code(path: web::Path<(String,)>, info: web::Json<PatchRequest>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  match patch::apply(&product_code, &info).await {
+    Ok(report) => Res {
+      code: if report.conflicts.is_empty() { 0 } else { -1 },
+      data: report,
     }
-    let parent = match path_map.get(&parent_path) {
-      Some(path) => path.clone(),
-      None => parent_path.clone(),
-    };
-    result.push(CodeFile {
-      id,
-      name: name.to_string(),
-      r#type: ftype,
-      parent: parent,
-      parent_path,
-      created_at: 0,
-      contents,
-    });
+    .respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TrashRestore {
+  id: String,
+  to: Option<String>, //恢复到其他位置 相对产品目录 用 / 分隔
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TrashPurge {
+  id: Option<String>, //为空时清空回收站
+}
+
+///回收站 最近删除的在前 超过保留期的会被自动清理
+#[get("/trash/{product_code}")]
+pub async fn get_trash(path: web::Path<(String,)>) -> HttpResponse {
+  match trash::list(&path.0) {
+    Ok(entries) => Res { code: 0, data: entries }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///从回收站恢复 目标已存在时不覆盖
+#[post("/trash/{product_code}/restore")]
+pub async fn restore_trash(path: web::Path<(String,)>, info: web::Json<TrashRestore>) -> HttpResponse {
+  match trash::restore(&path.0, &info.id, info.to.as_deref()) {
+    Ok(entry) => Res { code: 0, data: entry }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///永久删除回收站中的一项或全部
+#[post("/trash/{product_code}/purge")]
+pub async fn purge_trash(path: web::Path<(String,)>, info: web::Json<TrashPurge>) -> HttpResponse {
+  match trash::purge(&path.0, info.id.as_deref()) {
+    Ok(entries) => Res { code: 0, data: entries }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///新建文件可用的模板和变量
+#[get("/templates")]
+pub async fn get_templates() -> HttpResponse {
+  Res {
+    code: 0,
+    data: templates::list(),
+  }
+  .respond_to()
+}
+
+///按模板新建文件 替换变量后写入产品目录 文件已存在时不覆盖
+#[post("/templates/{product_code}/insert")]
+pub async fn insert_template(path: web::Path<(String,)>, info: web::Json<InsertTemplate>) -> HttpResponse {
+  match templates::insert(&path.0, &info).await {
+    Ok(file) => Res { code: 0, data: file }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///文件的协作编辑通道 WebSocket 多人同时编辑时合并各自的修改并同步光标<br>
+/// 通道中的修改会保存到文件 升级前要求 developer
+#[get("/collab/{product_code}/{id}")]
+pub async fn collab_file(req: HttpRequest, path: web::Path<(String, String)>, payload: web::Payload) -> Result<HttpResponse, Error> {
+  if let Err(err) = auth::require(&req, Role::Developer) {
+    return Ok(error_response(err));
+  }
+  let (product_code, id) = path.into_inner();
+  match resolve_id(&product_code, &id) {
+    Ok(file) => collab::join(&req, payload, file).await,
+    Err(err) => Ok(error_response(err)),
+  }
+}
+
+///编辑器的监听测试通道 WebSocket 文件变化后重新运行受影响的测试并推送结果<br>
+/// 会执行产品的测试代码 升级前要求 developer
+#[get("/test-watch/{product_code}")]
+pub async fn watch_tests(req: HttpRequest, path: web::Path<(String,)>, payload: web::Payload) -> Result<HttpResponse, Error> {
+  if let Err(err) = auth::require(&req, Role::Developer) {
+    return Ok(error_response(err));
+  }
+  test_watch::join(&req, payload, &path.0).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BenchQuery {
+  pub baseline: Option<String>,
+}
+
+///运行产品的基准测试 <br>
+/// 结果按版本保存 与 baseline 指定的版本比较 不指定时使用 cool.json 中的 bench.baseline 或最新的版本<br>
+/// 平均耗时增加超过 bench.threshold_percent 的标记为性能退化 会执行产品代码 要求 developer
+#[get("/bench/{product_code}")]
+pub async fn run_bench(req: HttpRequest, path: web::Path<(String,)>, query: web::Query<BenchQuery>) -> HttpResponse {
+  if let Err(err) = auth::require(&req, Role::Developer) {
+    return error_response(err);
+  }
+  match bench::bench_product(&path.0, query.into_inner().baseline).await {
+    Ok(report) => Res { code: 0, data: report }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///产品目录 可以按关键字和标签搜索
+#[get("/catalog")]
+pub async fn get_catalog(query: web::Query<CatalogQuery>, list: web::Query<ListQuery>) -> HttpResponse {
+  match catalog::search(&query).and_then(|entries| list_query::apply(entries, &list, &catalog::LIST_SPEC)) {
+    Ok(page) => page.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///产品的名称 描述 负责人 标签和渲染后的 README
+#[get("/meta/{product_code}")]
+pub async fn get_product_meta(path: web::Path<(String,)>) -> HttpResponse {
+  match catalog::detail(&path.0).await {
+    Ok(detail) => Res { code: 0, data: detail }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///修改产品的目录信息 写入 cool.json 中的 catalog
+#[post("/meta/{product_code}")]
+pub async fn update_product_meta(path: web::Path<(String,)>, info: web::Json<CatalogMeta>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  if query.dry_run {
+    return dry_run::respond(catalog::preview_update(&path.0, &info).await);
+  }
+  match catalog::update(&path.0, &info).await {
+    Ok(detail) => Res { code: 0, data: detail }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///搜索所有产品的代码 目录信息和最近的日志 结果带类型并分页
+#[get("/search")]
+pub async fn search_all(query: web::Query<SearchQuery>) -> HttpResponse {
+  let query = query.into_inner();
+  let res = tokio::task::spawn_blocking(move || search::search(&catalog::product_codes()?, &query)).await;
+  match res {
+    Ok(Ok(page)) => Res { code: 0, data: page }.respond_to(),
+    Ok(Err(err)) => error_response(err),
+    Err(err) => error_response(err.into()),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn rejects_paths_outside_the_product() {
+    assert!(resolve_id("shop", "src|app.ts").is_ok());
+    assert!(resolve_id("shop", "src|..|..|data").is_err());
+    assert!(resolve_id("..", "data|users.json").is_err());
+    assert!(resolve_id("../data", "users.json").is_err());
   }
-  return Res { code: 0, data: result }.respond_to();
 }
@@ -0,0 +1,126 @@
+use crate::dep_update::{self, UpdateRequest};
+use crate::dry_run::DryRunQuery;
+use crate::{dep_audit, licenses, node_compat, size_budget, Res};
+use actix_web::{get, http::header, post, web, HttpResponse};
+
+///依赖漏洞审计 <br>
+/// 分析产品模块图和 npm 依赖 到 OSV 查询漏洞
+#[get("/audit-deps/{product_code}")]
+pub async fn audit_deps(path: web::Path<(String,)>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  match dep_audit::audit_product(&product_code).await {
+    Ok(report) => Res { code: 0, data: report }.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///依赖许可证扫描 <br>
+/// 按 cool.json 中的 licenses 策略标记不允许的许可证
+#[get("/licenses/{product_code}")]
+pub async fn scan_licenses(path: web::Path<(String,)>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  match licenses::scan_product(&product_code).await {
+    Ok(report) => Res { code: 0, data: report }.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///声明了安装脚本的 npm 包 <br>
+/// runtime 不会执行安装脚本 按 cool.json 中的 node.allow_scripts 标记确认过的包
+#[get("/install-scripts/{product_code}")]
+pub async fn install_scripts(path: web::Path<(String,)>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  match node_compat::scan_install_scripts(&product_code).await {
+    Ok(report) => Res { code: 0, data: report }.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///Node.js 兼容层覆盖报告 <br>
+/// 产品用到的 node: 内置模块和 npm 包 分为支持 部分支持和不支持 用于部署前评估迁移可行性
+#[get("/node-coverage/{product_code}")]
+pub async fn node_coverage(path: web::Path<(String,)>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  match node_compat::coverage(&product_code).await {
+    Ok(report) => Res { code: 0, data: report }.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///依赖升级助手 <br>
+/// 远程 deno.land 和 npm 依赖的当前版本与最新版本 declared 为 true 的依赖可以升级
+#[get("/outdated/{product_code}")]
+pub async fn outdated_deps(path: web::Path<(String,)>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  match dep_update::outdated(&product_code).await {
+    Ok(deps) => Res { code: 0, data: deps }.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///把选中的依赖改为新版本 <br>
+/// 改写代码中的导入说明符和锁定表 生成只包含依赖修改的新版本 之后按正常流程部署
+#[post("/outdated/{product_code}/apply")]
+pub async fn update_deps(path: web::Path<(String,)>, info: web::Json<UpdateRequest>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  match dep_update::apply(&product_code, &info.into_inner(), query.dry_run).await {
+    Ok(report) => Res { code: 0, data: report }.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///打包体积分析 <br>
+/// 按模块和依赖包统计字节数 并对照 cool.json 中的 size_budget 会执行打包并保存报告
+#[post("/bundle-report/{product_code}")]
+pub async fn bundle_report(path: web::Path<(String,)>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  match size_budget::analyze_product(&product_code).await {
+    Ok(report) => Res { code: 0, data: report }.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///下载最近一次的体积分析报告
+#[get("/bundle-report/{product_code}/download")]
+pub async fn download_bundle_report(path: web::Path<(String,)>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  match tokio::fs::read(size_budget::report_path(&product_code)).await {
+    Ok(bytes) => HttpResponse::Ok()
+      .content_type("application/json")
+      .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}-bundle.json\"", product_code)))
+      .body(bytes),
+    Err(_) => Res {
+      code: -1,
+      data: "report not found".to_string(),
+    }
+    .respond_to(),
+  }
+}
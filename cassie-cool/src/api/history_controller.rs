@@ -0,0 +1,71 @@
+use crate::deploy::{self, read_history, DeployRecord};
+use crate::list_query::{self, ListQuery};
+use crate::{versions, Res};
+use actix_web::{get, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct History {
+  versions: Vec<String>,
+  deploys: Vec<DeployRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DiffQuery {
+  from: String,
+  to: Option<String>,
+}
+
+///部署历史 <br>
+/// 按时间先后返回所有版本和每次部署的作者 时间 流水线结果<br>
+/// 分页 排序和过滤只作用于部署记录 版本列表总是完整返回
+#[get("/history/{product_code}")]
+pub async fn get_history(path: web::Path<(String,)>, list: web::Query<ListQuery>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  let versions = match versions::list_versions(&product_code) {
+    Ok(versions) => versions,
+    Err(err) => {
+      return Res {
+        code: -1,
+        data: err.to_string(),
+      }
+      .respond_to();
+    }
+  };
+  let deploys = read_history(&product_code).await;
+  match deploys.and_then(|deploys| list_query::apply(deploys, &list, &deploy::LIST_SPEC)) {
+    Ok(page) => {
+      let mut res = Res {
+        code: 0,
+        data: History {
+          versions,
+          deploys: page.items.clone(),
+        },
+      }
+      .respond_to();
+      page.headers(&mut res);
+      res
+    }
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///两个版本之间的差异 <br>
+/// from to 为版本号 to 不传时与当前代码对比
+#[get("/history/{product_code}/diff")]
+pub async fn diff_history(path: web::Path<(String,)>, query: web::Query<DiffQuery>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  let to = query.to.clone().unwrap_or_else(|| versions::CURRENT_VERSION.to_string());
+  match versions::diff_versions(&product_code, &query.from, &to).await {
+    Ok(diffs) => Res { code: 0, data: diffs }.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
@@ -0,0 +1,19 @@
+use crate::auth::error_response;
+use crate::{git_hooks, Res};
+use actix_web::{post, web, HttpRequest, HttpResponse};
+
+///GitHub GitLab 的推送钩子 不需要登录 通过钩子密钥校验<br>
+/// 立即返回投递记录 拉取和部署在后台执行 结果通过 /admin/hooks/{hook}/deliveries 查询
+#[post("/git")]
+pub async fn receive_git(req: HttpRequest, body: web::Bytes) -> HttpResponse {
+  match git_hooks::receive(&req, &body) {
+    Ok(Some(delivery)) => Res { code: 0, data: delivery }.respond_to(),
+    //ping 等其他事件
+    Ok(None) => Res {
+      code: 0,
+      data: "ignored".to_string(),
+    }
+    .respond_to(),
+    Err(err) => error_response(err),
+  }
+}
@@ -1,31 +1,197 @@
 use actix_web::web;
 
+pub mod admin_controller;
+pub mod auth_controller;
 pub mod code_controller;
+pub mod deps_controller;
+pub mod history_controller;
+pub mod hooks_controller;
 pub mod runtime_controller;
+pub mod secrets_controller;
+pub mod shared_controller;
+pub mod tasks_controller;
+pub mod tenant_controller;
 
-use crate::api::code_controller::{file_tree, get_code, operation, update_content};
-use crate::api::runtime_controller::{get_runtime_info, start_pro_runtime, stop_pro_runtime};
+use crate::api::admin_controller::{
+  add_trusted_ca, approve_gitops_plan, assign_owner, clone_product, create_audit_checkpoint, create_tenant, create_user, delete_user,
+  download_artifact, encryption_status, export_product, export_usage, get_audit_checkpoints, get_gitops_status, get_graphql, get_hook_deliveries,
+  get_overload, get_panics, get_state_snapshots, get_tenants, get_trusted_cas, get_usage_export, get_users, import_product, remove_trusted_ca,
+  replay_hook_delivery, reset_user_totp, restore_state, retention_report, rewrap_master_key, rotate_data_key, run_doctor, run_retention,
+  snapshot_state, sync_gitops, update_user, verify_audit_log,
+};
+use crate::api::code_controller::{
+  collab_file, file_tree, get_catalog, get_code, get_meta, get_product_meta, get_raw, get_templates, get_trash, insert_template, operation,
+  patch_code, purge_trash, put_raw, restore_trash, run_bench, search_all, update_content, update_product_meta, watch_tests,
+};
+use crate::api::deps_controller::{
+  audit_deps, bundle_report, download_bundle_report, install_scripts, node_coverage, outdated_deps, scan_licenses, update_deps,
+};
+use crate::api::history_controller::{diff_history, get_history};
+use crate::api::runtime_controller::{
+  bulk_operation, check_upstreams, deploy, download_log, flush_dns_cache, get_access_log, get_admission, get_anomalies, get_audit_events,
+  get_bridges, get_crashes, get_dead_letters, get_dns_cache, get_egress, get_egress_hosts, get_heap, get_logs, get_metrics, get_profile, get_recycle,
+  get_roles, get_runtime_info, get_usage, start_pro_runtime, start_profile, stop_pro_runtime, stop_profile,
+};
+use crate::api::secrets_controller::{get_secrets, retire_secret_version, rotate_secret};
+use crate::api::shared_controller::{publish_shared, shared_modules};
+use crate::api::tasks_controller::{cancel_task_run, get_task_info, get_tasks};
+use crate::api_version::API_PREFIX;
+use crate::auth::{self, Role};
 use runtime_controller::{exit, start_runtime, stop_runtime};
 
 use self::runtime_controller::start_debugger_runtime;
 
+///管理接口挂在 /api/v1 下 不带版本前缀的旧路径在下线之前继续可用 响应中带弃用提示
 pub fn api_routers(cfg: &mut web::ServiceConfig) {
+  cfg
+    .service(web::scope(API_PREFIX).configure(management_routes))
+    .configure(management_routes);
+}
+
+fn management_routes(cfg: &mut web::ServiceConfig) {
   cfg
     .service(
       web::scope("/runtime")
+        .wrap_fn(|req, srv| auth::guard(req, srv, Role::Viewer))
+        .service(check_upstreams)
+        .service(get_tasks)
+        .service(get_task_info)
+        .service(cancel_task_run)
         .service(start_runtime)
         .service(stop_runtime)
         .service(start_pro_runtime)
         .service(stop_pro_runtime)
         .service(start_debugger_runtime)
         .service(exit)
-        .service(get_runtime_info),
+        .service(get_runtime_info)
+        .service(deploy)
+        .service(bulk_operation)
+        .service(get_roles)
+        .service(get_audit_events)
+        .service(get_egress)
+        .service(get_egress_hosts)
+        .service(get_dns_cache)
+        .service(flush_dns_cache)
+        .service(get_metrics)
+        .service(get_usage)
+        .service(get_anomalies)
+        .service(get_heap)
+        .service(get_recycle)
+        .service(get_admission)
+        .service(get_access_log)
+        .service(get_logs)
+        .service(download_log)
+        .service(get_crashes)
+        .service(get_bridges)
+        .service(get_dead_letters)
+        .service(get_profile)
+        .service(start_profile)
+        .service(stop_profile),
     )
     .service(
       web::scope("/code")
+        .wrap_fn(|req, srv| auth::guard(req, srv, Role::Viewer))
+        .service(get_trash)
+        .service(restore_trash)
+        .service(purge_trash)
+        .service(get_templates)
+        .service(insert_template)
+        .service(collab_file)
+        .service(watch_tests)
+        .service(run_bench)
+        .service(get_catalog)
+        .service(get_product_meta)
+        .service(update_product_meta)
+        .service(search_all)
         .service(get_code)
+        .service(get_raw)
+        .service(get_meta)
+        .service(patch_code)
+        .service(put_raw)
         .service(update_content)
         .service(file_tree)
-        .service(operation),
+        .service(operation)
+        .service(audit_deps)
+        .service(scan_licenses)
+        .service(install_scripts)
+        .service(node_coverage)
+        .service(outdated_deps)
+        .service(update_deps)
+        .service(bundle_report)
+        .service(download_bundle_report)
+        .service(get_history)
+        .service(diff_history)
+        .service(shared_modules)
+        .service(publish_shared)
+        .service(get_secrets)
+        .service(rotate_secret)
+        .service(retire_secret_version),
+    )
+    .service(
+      web::scope("/admin")
+        .wrap_fn(|req, srv| auth::guard(req, srv, Role::Admin))
+        .service(export_usage)
+        .service(get_usage_export)
+        .service(download_artifact)
+        .service(get_tenants)
+        .service(create_tenant)
+        .service(assign_owner)
+        .service(get_users)
+        .service(create_user)
+        .service(update_user)
+        .service(delete_user)
+        .service(reset_user_totp)
+        .service(verify_audit_log)
+        .service(get_audit_checkpoints)
+        .service(create_audit_checkpoint)
+        .service(retention_report)
+        .service(run_retention)
+        .service(encryption_status)
+        .service(rotate_data_key)
+        .service(rewrap_master_key)
+        .service(snapshot_state)
+        .service(get_state_snapshots)
+        .service(restore_state)
+        .service(clone_product)
+        .service(get_hook_deliveries)
+        .service(replay_hook_delivery)
+        .service(get_panics)
+        .service(get_overload)
+        .service(get_graphql)
+        .service(get_gitops_status)
+        .service(sync_gitops)
+        .service(approve_gitops_plan)
+        .service(export_product)
+        .service(import_product)
+        .service(run_doctor)
+        .service(get_trusted_cas)
+        .service(add_trusted_ca)
+        .service(remove_trusted_ca),
+    )
+    .service(
+      web::scope("/auth")
+        .service(auth_controller::login)
+        .service(auth_controller::logout)
+        .service(auth_controller::me)
+        .service(auth_controller::change_password)
+        .service(auth_controller::setup_totp)
+        .service(auth_controller::enable_totp)
+        .service(auth_controller::disable_totp),
+    )
+    .service(web::scope("/hooks").service(hooks_controller::receive_git))
+    .service(
+      web::scope("/tenant")
+        .service(tenant_controller::list_products)
+        .service(tenant_controller::create_product)
+        .service(tenant_controller::get_product)
+        .service(tenant_controller::update_product)
+        .service(tenant_controller::delete_product)
+        .service(tenant_controller::write_file)
+        .service(tenant_controller::deploy_product)
+        .service(tenant_controller::get_history)
+        .service(tenant_controller::get_audit_events)
+        .service(tenant_controller::get_metrics)
+        .service(tenant_controller::get_usage)
+        .service(tenant_controller::search_products),
     );
 }
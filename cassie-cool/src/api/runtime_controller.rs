@@ -1,5 +1,16 @@
-use crate::{worker_util, Res};
-use actix_web::{get, web, HttpResponse};
+use crate::access_log::{self, AccessQuery};
+use crate::auth::{self, error_response, Role};
+use crate::bulk::{self, BulkRequest};
+use crate::dry_run::{self, DryRunQuery};
+use crate::list_query::{self, ListQuery};
+use crate::profiler::{self, ProfileRequest};
+use crate::roles::{self, RoleStatus};
+use crate::{
+  admission, anomaly, audit_log, bridge, crash, deploy, dns_cache, egress, heap_trend, hot_reload, logs, metrics, recycle, upstream, usage,
+  worker_util, Res,
+};
+use deno_core::error::AnyError;
+use actix_web::{get, http::header, post, web, HttpRequest, HttpResponse};
 use serde::{Deserialize, Serialize};
 use worker_util::{Project, ScriptWorkerId, ScriptWorkerThread, WORKER_TABLE};
 
@@ -46,23 +57,311 @@ pub async fn get_runtime_info(path: web::Path<(String,)>) -> HttpResponse {
   }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RolesInfo {
+  http_instances: usize,
+  roles: Vec<RoleStatus>,
+}
+
+///各角色的运行状态 <br>
+/// http 角色为生产 runtime 数量 其他角色单独报告状态
+#[get("/{product_code}/roles")]
+pub async fn get_roles(path: web::Path<(String,)>) -> HttpResponse {
+  let params = path.into_inner().0;
+  let http_instances = match WORKER_TABLE.lock().unwrap().get(&ScriptWorkerId(params.clone())) {
+    Some(w) => w.worker_handlers.lock().unwrap().len(),
+    None => 0,
+  };
+  Res {
+    code: 0,
+    data: RolesInfo {
+      http_instances,
+      roles: roles::role_status(&params),
+    },
+  }
+  .respond_to()
+}
+
+///网关指标 Prometheus 文本格式
+#[get("/metrics")]
+pub async fn get_metrics() -> HttpResponse {
+  HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(metrics::render())
+}
+
+///产品的审计事件 例如离线模式下的违规访问
+#[get("/{product_code}/audit-events")]
+pub async fn get_audit_events(path: web::Path<(String,)>, list: web::Query<ListQuery>) -> HttpResponse {
+  let params = path.into_inner().0;
+  match audit_log::read_events(&params).and_then(|events| list_query::apply(events, &list, &audit_log::LIST_SPEC)) {
+    Ok(page) => page.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///产品 runtime 通过 fetch 发出的请求 需要在 cool.json 中开启 egress
+#[get("/egress/{product_code}")]
+pub async fn get_egress(path: web::Path<(String,)>, list: web::Query<ListQuery>) -> HttpResponse {
+  let params = path.into_inner().0;
+  match list_query::apply(egress::list(&params), &list, &egress::LIST_SPEC) {
+    Ok(page) => page.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///按主机汇总产品的外部请求
+#[get("/egress/{product_code}/hosts")]
+pub async fn get_egress_hosts(path: web::Path<(String,)>) -> HttpResponse {
+  let params = path.into_inner().0;
+  Res {
+    code: 0,
+    data: egress::summary(&params),
+  }
+  .respond_to()
+}
+
+///产品 runtime 的 DNS 缓存状态 需要在 cool.json 中开启 dns
+#[get("/{product_code}/dns")]
+pub async fn get_dns_cache(path: web::Path<(String,)>) -> HttpResponse {
+  let params = path.into_inner().0;
+  Res {
+    code: 0,
+    data: dns_cache::stats(&params),
+  }
+  .respond_to()
+}
+
+///清空产品的 DNS 缓存 返回清除的主机数
+#[post("/{product_code}/dns/flush")]
+pub async fn flush_dns_cache(path: web::Path<(String,)>) -> HttpResponse {
+  let params = path.into_inner().0;
+  Res {
+    code: 0,
+    data: dns_cache::flush(&params),
+  }
+  .respond_to()
+}
+
+///产品的 CPU 采样状态 每个时间窗口和合并后的 folded stacks 下载链接
+#[get("/profile/{product_code}")]
+pub async fn get_profile(path: web::Path<(String,)>) -> HttpResponse {
+  let params = path.into_inner().0;
+  Res {
+    code: 0,
+    data: profiler::status(&params),
+  }
+  .respond_to()
+}
+
+///开始持续采样产品的生产 runtime 再次调用时以新的参数重新开始
+#[post("/profile/{product_code}/start")]
+pub async fn start_profile(path: web::Path<(String,)>, body: Option<web::Json<ProfileRequest>>) -> HttpResponse {
+  let params = path.into_inner().0;
+  let request = body.map(|b| b.into_inner()).unwrap_or_default();
+  match profiler::start(&params, request) {
+    Ok(status) => Res { code: 0, data: status }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///停止采样 正在进行的窗口会导出
+#[post("/profile/{product_code}/stop")]
+pub async fn stop_profile(path: web::Path<(String,)>) -> HttpResponse {
+  let params = path.into_inner().0;
+  Res {
+    code: 0,
+    data: profiler::stop(&params),
+  }
+  .respond_to()
+}
+
+///从网关的网络检查产品声明的外部依赖 dns tcp tls 和协议握手 有凭据时在安全的连接上校验
+#[get("/deps-check/{product_code}")]
+pub async fn check_upstreams(path: web::Path<(String,)>) -> HttpResponse {
+  let params = path.into_inner().0;
+  match upstream::check_all(&params).await {
+    Ok(statuses) => Res { code: 0, data: statuses }.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///产品请求量和错误率的基线 以及最近检测到的异常
+#[get("/{product_code}/anomalies")]
+pub async fn get_anomalies(path: web::Path<(String,)>) -> HttpResponse {
+  let params = path.into_inner().0;
+  Res {
+    code: 0,
+    data: anomaly::report(&params),
+  }
+  .respond_to()
+}
+
+///产品每个生产 runtime 的堆内存趋势 以及最近检测到的持续增长
+#[get("/{product_code}/heap")]
+pub async fn get_heap(path: web::Path<(String,)>) -> HttpResponse {
+  let params = path.into_inner().0;
+  Res {
+    code: 0,
+    data: heap_trend::report(&params),
+  }
+  .respond_to()
+}
+
+///产品的回收策略 每个生产 runtime 的用量和最近的回收
+#[get("/{product_code}/recycle")]
+pub async fn get_recycle(path: web::Path<(String,)>) -> HttpResponse {
+  let params = path.into_inner().0;
+  Res {
+    code: 0,
+    data: recycle::report(&params),
+  }
+  .respond_to()
+}
+
+///产品 runtime 当前的压力和接受请求的比例
+#[get("/{product_code}/admission")]
+pub async fn get_admission(path: web::Path<(String,)>) -> HttpResponse {
+  let params = path.into_inner().0;
+  Res {
+    code: 0,
+    data: admission::report(&params),
+  }
+  .respond_to()
+}
+
+///产品 runtime 最近的崩溃 url 为完整报告的下载链接
+#[get("/{product_code}/crashes")]
+pub async fn get_crashes(path: web::Path<(String,)>, list: web::Query<ListQuery>) -> HttpResponse {
+  let params = path.into_inner().0;
+  match list_query::apply(crash::list(&params), &list, &crash::LIST_SPEC) {
+    Ok(page) => page.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///产品的消息订阅 连接状态 投递和确认情况
+#[get("/{product_code}/bridges")]
+pub async fn get_bridges(path: web::Path<(String,)>) -> HttpResponse {
+  let params = path.into_inner().0;
+  Res {
+    code: 0,
+    data: bridge::status(&params),
+  }
+  .respond_to()
+}
+
+///超过重试次数的消息 status 按订阅名过滤
+#[get("/{product_code}/dead-letters")]
+pub async fn get_dead_letters(path: web::Path<(String,)>, list: web::Query<ListQuery>) -> HttpResponse {
+  let params = path.into_inner().0;
+  match bridge::dead_letters(&params).and_then(|letters| list_query::apply(letters, &list, &bridge::LIST_SPEC)) {
+    Ok(page) => page.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///网关最近的访问记录 可以按产品和状态码过滤 只读取正在写入的文件
+#[get("/logs/access")]
+pub async fn get_access_log(query: web::Query<AccessQuery>) -> HttpResponse {
+  match access_log::recent(&query) {
+    Ok(entries) => Res { code: 0, data: entries }.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///产品的 runtime 输出日志 包括正在写入的文件和轮转后的文件
+#[get("/{product_code}/logs")]
+pub async fn get_logs(path: web::Path<(String,)>, list: web::Query<ListQuery>) -> HttpResponse {
+  let params = path.into_inner().0;
+  match logs::list(&params).and_then(|files| list_query::apply(files, &list, &logs::LIST_SPEC)) {
+    Ok(page) => page.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///下载一个日志文件 轮转后压缩的文件按 gzip 返回
+#[get("/{product_code}/logs/{name}")]
+pub async fn download_log(path: web::Path<(String, String)>) -> HttpResponse {
+  let (product_code, name) = path.into_inner();
+  let file = match logs::file_path(&product_code, &name) {
+    Ok(path) => tokio::fs::File::open(path).await.map_err(AnyError::from),
+    Err(err) => Err(err),
+  };
+  match file {
+    Ok(file) => HttpResponse::Ok()
+      .content_type(if name.ends_with(".gz") { "application/gzip" } else { "text/plain; charset=utf-8" })
+      .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}-{}\"", product_code, name)))
+      .streaming(tokio_util::io::ReaderStream::new(file)),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UsageQuery {
+  date: Option<String>, //YYYY-MM-DD 默认当天 UTC
+}
+
+///产品某天按接口统计的用量 cpu 和内存为采样值
+#[get("/{product_code}/usage")]
+pub async fn get_usage(path: web::Path<(String,)>, query: web::Query<UsageQuery>) -> HttpResponse {
+  let params = path.into_inner().0;
+  let date = query.into_inner().date.unwrap_or_else(|| chrono::Utc::now().format("%Y-%m-%d").to_string());
+  match usage::daily_usage(&params, &date) {
+    Ok(usage) => Res { code: 0, data: usage }.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///开发 runtime 跟随代码目录的变化自动重启 监听失败时只能手动重启
+fn watch_code(product_code: &str) {
+  if let Err(err) = hot_reload::watch(product_code) {
+    log::warn!("failed to watch {} code: {}", product_code, err);
+  }
+}
+
 #[get("/{product_code}/restart")]
-pub async fn restart_runtime(path: web::Path<(String,)>) -> HttpResponse {
+pub async fn restart_runtime(req: HttpRequest, path: web::Path<(String,)>) -> HttpResponse {
+  if let Err(err) = auth::require(&req, Role::Developer) {
+    return error_response(err);
+  }
   let params = path.into_inner().0;
   let mut script_table = WORKER_TABLE.lock().unwrap();
   let work = script_table.get_mut(&ScriptWorkerId(params.clone()));
-  let path = format!("code/{}/app.ts", params.clone());
   match work {
     Some(w) => {
       w.stop_watch_runtime();
       w.start_watch_runtime().await;
     }
     None => {
-      let mut worker: ScriptWorkerThread = ScriptWorkerThread::new(Project { name: params.clone(), path });
+      let mut worker: ScriptWorkerThread = ScriptWorkerThread::new(Project::from_product(&params));
       worker.start_watch_runtime().await;
       script_table.insert(worker.id.clone(), worker);
     }
   }
+  watch_code(&params);
   return Res {
     code: 0,
     data: "成功启动".to_string(),
@@ -76,11 +375,13 @@ pub async fn restart_runtime(path: web::Path<(String,)>) -> HttpResponse {
 /// cur_port当前使用的端口<br>
 /// hand_port所有 runtime使用到的 port 集合
 #[get("/{product_code}/start")]
-pub async fn start_runtime(path: web::Path<(String,)>) -> HttpResponse {
+pub async fn start_runtime(req: HttpRequest, path: web::Path<(String,)>) -> HttpResponse {
+  if let Err(err) = auth::require(&req, Role::Developer) {
+    return error_response(err);
+  }
   let params = path.into_inner().0;
   let mut script_table = WORKER_TABLE.lock().unwrap();
   let work = script_table.get_mut(&ScriptWorkerId(params.clone()));
-  let path = format!("code/{}/app.ts", params.clone());
   match work {
     Some(w) => {
       if w.watch_tx.is_none() {
@@ -88,11 +389,12 @@ pub async fn start_runtime(path: web::Path<(String,)>) -> HttpResponse {
       }
     }
     None => {
-      let mut worker: ScriptWorkerThread = ScriptWorkerThread::new(Project { name: params, path });
+      let mut worker: ScriptWorkerThread = ScriptWorkerThread::new(Project::from_product(&params));
       worker.start_watch_runtime().await;
       script_table.insert(worker.id.clone(), worker);
     }
   }
+  watch_code(&params);
   return Res {
     code: 0,
     data: "成功启动".to_string(),
@@ -100,17 +402,19 @@ pub async fn start_runtime(path: web::Path<(String,)>) -> HttpResponse {
   .respond_to();
 }
 #[get("/{product_code}/start_debugger")]
-pub async fn start_debugger_runtime(path: web::Path<(String,)>) -> HttpResponse {
+pub async fn start_debugger_runtime(req: HttpRequest, path: web::Path<(String,)>) -> HttpResponse {
+  if let Err(err) = auth::require(&req, Role::Developer) {
+    return error_response(err);
+  }
   let params = path.into_inner().0;
   let mut script_table = WORKER_TABLE.lock().unwrap();
   let work = script_table.get_mut(&ScriptWorkerId(params.clone()));
-  let path: String = format!("code/{}/app.ts", params.clone());
   match work {
     Some(w) => {
       w.start_debugger_runtime().await;
     }
     None => {
-      let mut worker: ScriptWorkerThread = ScriptWorkerThread::new(Project { name: params, path });
+      let mut worker: ScriptWorkerThread = ScriptWorkerThread::new(Project::from_product(&params));
       worker.start_debugger_runtime().await;
       script_table.insert(worker.id.clone(), worker);
     }
@@ -125,9 +429,13 @@ pub async fn start_debugger_runtime(path: web::Path<(String,)>) -> HttpResponse
 /// product_code 指产品代码<br>
 /// 调用一次停止一个 runtime
 #[get("/{product_code}/stop")]
-pub async fn stop_runtime(path: web::Path<(String,)>) -> HttpResponse {
+pub async fn stop_runtime(req: HttpRequest, path: web::Path<(String,)>) -> HttpResponse {
+  if let Err(err) = auth::require(&req, Role::Developer) {
+    return error_response(err);
+  }
   let mut script_table = WORKER_TABLE.lock().unwrap();
   let name = path.into_inner().0;
+  hot_reload::unwatch(&name);
   let work = script_table.get_mut(&ScriptWorkerId(name));
   match work {
     Some(w) => {
@@ -145,9 +453,13 @@ pub async fn stop_runtime(path: web::Path<(String,)>) -> HttpResponse {
 ///停止服务 <br>
 /// product_code 产品code
 #[get("/{product_code}/exit")]
-pub async fn exit(path: web::Path<(String,)>) -> HttpResponse {
+pub async fn exit(req: HttpRequest, path: web::Path<(String,)>) -> HttpResponse {
+  if let Err(err) = auth::require(&req, Role::Developer) {
+    return error_response(err);
+  }
   let mut script_table = WORKER_TABLE.lock().unwrap();
   let name = path.into_inner().0;
+  hot_reload::unwatch(&name);
   let work: Option<ScriptWorkerThread> = script_table.remove(&ScriptWorkerId(name));
   match work {
     Some(w) => {
@@ -169,17 +481,19 @@ pub async fn exit(path: web::Path<(String,)>) -> HttpResponse {
 }
 
 #[get("/pro/{product_code}/restart")]
-pub async fn restart_pro_runtime(path: web::Path<(String,)>) -> HttpResponse {
+pub async fn restart_pro_runtime(req: HttpRequest, path: web::Path<(String,)>) -> HttpResponse {
+  if let Err(err) = auth::require(&req, Role::Developer) {
+    return error_response(err);
+  }
   let params = path.into_inner().0;
   let mut script_table = WORKER_TABLE.lock().unwrap();
   let work = script_table.get_mut(&ScriptWorkerId(params.clone()));
-  let path = format!("code/{}/app.ts", params.clone());
   match work {
     Some(w) => {
       w.start_runtime().await;
     }
     None => {
-      let mut worker: ScriptWorkerThread = ScriptWorkerThread::new(Project { name: params.clone(), path });
+      let mut worker: ScriptWorkerThread = ScriptWorkerThread::new(Project::from_product(&params));
       worker.start_runtime().await;
       script_table.insert(worker.id.clone(), worker);
     }
@@ -191,25 +505,47 @@ pub async fn restart_pro_runtime(path: web::Path<(String,)>) -> HttpResponse {
   .respond_to();
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReplicaQuery {
+  replicas: Option<usize>, //端口副本数 每个副本监听自己的端口 不传时在连接最少的端口上再启动一个 runtime
+}
+
 ///启动runtime <br>
 /// product_code 产品code<br>
+/// replicas 端口副本数 多出的副本排空后退出<br>
 /// script_table所有runtime集合<br>
 /// cur_port当前使用的端口<br>
 /// hand_port所有 runtime使用到的 port 集合
 #[get("/pro/{product_code}/start")]
-pub async fn start_pro_runtime(path: web::Path<(String,)>) -> HttpResponse {
+pub async fn start_pro_runtime(req: HttpRequest, path: web::Path<(String,)>, query: web::Query<ReplicaQuery>) -> HttpResponse {
+  if let Err(err) = auth::require(&req, Role::Developer) {
+    return error_response(err);
+  }
   let params = path.into_inner().0;
+  if let Err(err) = deploy::ensure_deployable(&params).await {
+    return Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to();
+  }
+  let replicas = query.into_inner().replicas;
   let mut script_table = WORKER_TABLE.lock().unwrap();
   let work = script_table.get_mut(&ScriptWorkerId(params.clone()));
-  let path = format!("code/{}/app.ts", params.clone());
 
-  match work {
-    Some(w) => {
+  match (work, replicas) {
+    (Some(w), Some(replicas)) => {
+      w.set_replicas(replicas).await;
+    }
+    (Some(w), None) => {
       w.start_runtime().await;
     }
-    None => {
-      let mut worker: ScriptWorkerThread = ScriptWorkerThread::new(Project { name: params.clone(), path });
-      worker.start_runtime().await;
+    (None, replicas) => {
+      let mut worker: ScriptWorkerThread = ScriptWorkerThread::new(Project::from_product(&params));
+      match replicas {
+        Some(replicas) => worker.set_replicas(replicas).await,
+        None => worker.start_runtime().await,
+      }
       script_table.insert(worker.id.clone(), worker);
     }
   }
@@ -224,7 +560,10 @@ pub async fn start_pro_runtime(path: web::Path<(String,)>) -> HttpResponse {
 /// product_code 指产品代码<br>
 /// 调用一次停止一个 runtime
 #[get("/pro/{product_code}/stop")]
-pub async fn stop_pro_runtime(path: web::Path<(String,)>) -> HttpResponse {
+pub async fn stop_pro_runtime(req: HttpRequest, path: web::Path<(String,)>) -> HttpResponse {
+  if let Err(err) = auth::require(&req, Role::Developer) {
+    return error_response(err);
+  }
   let mut script_table = WORKER_TABLE.lock().unwrap();
   let name = path.into_inner().0;
   let work = script_table.get_mut(&ScriptWorkerId(name));
@@ -240,3 +579,42 @@ pub async fn stop_pro_runtime(path: web::Path<(String,)>) -> HttpResponse {
   }
   .respond_to();
 }
+
+///部署产品 <br>
+/// 依次执行 check lint test bundle 流水线<br>
+/// 必需步骤全部通过后才会切换生产 runtime 之后执行冒烟测试 失败自动回滚
+#[post("/deploy/{product_code}")]
+pub async fn deploy(req: HttpRequest, path: web::Path<(String,)>) -> HttpResponse {
+  let params = path.into_inner().0;
+  let author = auth::principal(&req).map(|p| p.email);
+  match deploy::deploy_product(&params, author).await {
+    Ok(record) => Res {
+      code: if record.status == deploy::DeployStatus::Deployed { 0 } else { -1 },
+      data: record,
+    }
+    .respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///批量启动 停止 重启或部署多个产品 <br>
+/// 每个产品单独返回结果 atomic 部署时任一产品失败则全部不切换或回滚
+#[post("/bulk")]
+pub async fn bulk_operation(req: HttpRequest, body: web::Json<BulkRequest>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  if query.dry_run {
+    return dry_run::unsupported();
+  }
+  let author = auth::principal(&req).map(|p| p.email);
+  match bulk::run(body.into_inner(), author).await {
+    Ok(report) => Res {
+      code: if report.success { 0 } else { -1 },
+      data: report,
+    }
+    .respond_to(),
+    Err(err) => error_response(err),
+  }
+}
@@ -0,0 +1,69 @@
+use crate::dry_run::{self, DryRunQuery};
+use crate::secrets::{list_secrets, put_secret, retire_secret, secret_info};
+use crate::Res;
+use actix_web::{get, post, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SecretValue {
+  value: String,
+}
+
+///产品的密钥及版本 不返回密钥值
+#[get("/secrets/{product_code}")]
+pub async fn get_secrets(path: web::Path<(String,)>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  match list_secrets(&product_code) {
+    Ok(secrets) => Res { code: 0, data: secrets }.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///写入密钥的新版本 <br>
+/// 轮换时先写入新版本 调用方切换完成后再停用旧版本
+#[post("/secrets/{product_code}/{name}")]
+pub async fn rotate_secret(path: web::Path<(String, String)>, info: web::Json<SecretValue>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  let (product_code, name) = path.into_inner();
+  if query.dry_run {
+    let after = put_secret(&product_code, &name, info.into_inner().value, true);
+    return dry_run::respond(secret_info(&product_code, &name).and_then(|before| dry_run::preview(&before, &after?)));
+  }
+  match put_secret(&product_code, &name, info.into_inner().value, false) {
+    Ok(secret) => Res {
+      code: 0,
+      data: secret.versions.last().map(|v| v.version).unwrap_or_default(),
+    }
+    .respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///停用密钥的某个版本
+#[post("/secrets/{product_code}/{name}/{version}/retire")]
+pub async fn retire_secret_version(path: web::Path<(String, String, u32)>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  let (product_code, name, version) = path.into_inner();
+  if query.dry_run {
+    let after = retire_secret(&product_code, &name, version, true);
+    return dry_run::respond(secret_info(&product_code, &name).and_then(|before| dry_run::preview(&before, &after?)));
+  }
+  match retire_secret(&product_code, &name, version, false) {
+    Ok(_) => Res {
+      code: 0,
+      data: "停用成功".to_string(),
+    }
+    .respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
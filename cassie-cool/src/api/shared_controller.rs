@@ -0,0 +1,38 @@
+use crate::shared::{list_shared_modules, publish_shared_module};
+use crate::Res;
+use actix_web::{get, post, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PublishSource {
+  product_code: String,
+  path: String, //相对产品目录
+}
+
+///共享模块列表
+#[get("/shared")]
+pub async fn shared_modules() -> HttpResponse {
+  match list_shared_modules() {
+    Ok(modules) => Res { code: 0, data: modules }.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///发布共享模块 <br>
+/// 把产品目录下的子目录复制为 shared/{name}/{version}
+#[post("/shared/{name}/{version}")]
+pub async fn publish_shared(path: web::Path<(String, String)>, info: web::Json<PublishSource>) -> HttpResponse {
+  let (name, version) = path.into_inner();
+  match publish_shared_module(&name, &version, &info.product_code, &info.path).await {
+    Ok(bytes) => Res { code: 0, data: bytes }.respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
@@ -0,0 +1,47 @@
+use crate::Res;
+use actix_web::{get, post, web, HttpResponse};
+use deno_runtime::ops::tasks::{cancel_task, get_task, list_tasks};
+
+///产品脚本通过 Deno.tasks.start 启动的后台任务 按创建时间排序
+#[get("/tasks/{product_code}")]
+pub async fn get_tasks(path: web::Path<(String,)>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  Res {
+    code: 0,
+    data: list_tasks(&product_code),
+  }
+  .respond_to()
+}
+
+///单个任务的状态和进度
+#[get("/tasks/{product_code}/{task_id}")]
+pub async fn get_task_info(path: web::Path<(String, String)>) -> HttpResponse {
+  let (product_code, task_id) = path.into_inner();
+  match get_task(&product_code, &task_id) {
+    Some(task) => Res { code: 0, data: task }.respond_to(),
+    None => Res {
+      code: -1,
+      data: "任务不存在".to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///取消任务 <br>
+/// 脚本在下一次上报进度时得知任务已取消 由脚本自行结束
+#[post("/tasks/{product_code}/{task_id}/cancel")]
+pub async fn cancel_task_run(path: web::Path<(String, String)>) -> HttpResponse {
+  let (product_code, task_id) = path.into_inner();
+  match cancel_task(&product_code, &task_id) {
+    true => Res {
+      code: 0,
+      data: "取消成功".to_string(),
+    }
+    .respond_to(),
+    false => Res {
+      code: -1,
+      data: "任务不存在或已结束".to_string(),
+    }
+    .respond_to(),
+  }
+}
@@ -0,0 +1,241 @@
+use crate::auth::error_response;
+use crate::config::product_dir;
+use crate::deploy::{self, read_history};
+use crate::dry_run::{self, DryRunQuery};
+use crate::list_query::{self, ListQuery};
+use crate::search::{self, SearchQuery};
+use crate::tenants::{self, Tenant};
+use crate::worker_util::{ScriptWorkerId, WORKER_TABLE};
+use crate::{audit_log, encryption, hot_reload, metrics, roles, tree_index, usage, Res};
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use deno_core::error::AnyError;
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CreateProduct {
+  product_code: String,
+  description: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UpdateProduct {
+  description: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WriteFile {
+  path: String, //相对产品目录
+  contents: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TenantUsageQuery {
+  date: Option<String>,
+}
+
+///认证租户并检查产品归属
+fn authorize(req: &HttpRequest, product_code: &str) -> Result<Tenant, AnyError> {
+  let tenant = tenants::authenticate(req)?;
+  tenants::ensure_owner(&tenant, product_code)?;
+  Ok(tenant)
+}
+
+///当前租户的产品
+#[get("/products")]
+pub async fn list_products(req: HttpRequest, list: web::Query<ListQuery>) -> HttpResponse {
+  let products = tenants::authenticate(&req).and_then(|t| tenants::owned_products(&t.id));
+  match products.and_then(|products| list_query::apply(products, &list, &tenants::LIST_SPEC)) {
+    Ok(page) => page.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///在配额内创建产品
+#[post("/products")]
+pub async fn create_product(req: HttpRequest, info: web::Json<CreateProduct>, query: web::Query<DryRunQuery>) -> HttpResponse {
+  let info = info.into_inner();
+  if query.dry_run {
+    let after = tenants::authenticate(&req).and_then(|t| tenants::create_product(&t, &info.product_code, info.description, true));
+    return dry_run::respond(after.and_then(|meta| dry_run::preview(&(), &meta)));
+  }
+  match tenants::authenticate(&req).and_then(|t| tenants::create_product(&t, &info.product_code, info.description, false)) {
+    Ok(meta) => Res { code: 0, data: meta }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+#[get("/products/{product_code}")]
+pub async fn get_product(req: HttpRequest, path: web::Path<(String,)>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  match tenants::authenticate(&req).and_then(|t| tenants::ensure_owner(&t, &product_code)) {
+    Ok(meta) => Res { code: 0, data: meta }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+#[post("/products/{product_code}")]
+pub async fn update_product(
+  req: HttpRequest,
+  path: web::Path<(String,)>,
+  info: web::Json<UpdateProduct>,
+  query: web::Query<DryRunQuery>,
+) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  if query.dry_run {
+    let result = tenants::authenticate(&req).and_then(|t| {
+      let before = tenants::ensure_owner(&t, &product_code)?;
+      let after = tenants::update_product(&t, &product_code, info.into_inner().description, true)?;
+      dry_run::preview(&before, &after)
+    });
+    return dry_run::respond(result);
+  }
+  match tenants::authenticate(&req).and_then(|t| tenants::update_product(&t, &product_code, info.into_inner().description, false)) {
+    Ok(meta) => Res { code: 0, data: meta }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///删除产品 先停止所有 runtime 和角色 再删除代码
+#[post("/products/{product_code}/delete")]
+pub async fn delete_product(req: HttpRequest, path: web::Path<(String,)>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  let tenant = match authorize(&req, &product_code) {
+    Ok(tenant) => tenant,
+    Err(err) => return error_response(err),
+  };
+  hot_reload::unwatch(&product_code);
+  drop(WORKER_TABLE.lock().unwrap().remove(&ScriptWorkerId(product_code.clone())));
+  roles::stop_roles(&product_code);
+  match tenants::delete_product(&tenant, &product_code) {
+    Ok(_) => {
+      tree_index::forget(&product_code);
+      Res {
+        code: 0,
+        data: "删除成功".to_string(),
+      }
+      .respond_to()
+    }
+    Err(err) => error_response(err),
+  }
+}
+
+///写入产品代码文件 路径不能跳出产品目录
+#[post("/products/{product_code}/files")]
+pub async fn write_file(req: HttpRequest, path: web::Path<(String,)>, info: web::Json<WriteFile>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  if let Err(err) = authorize(&req, &product_code) {
+    return error_response(err);
+  }
+  let info = info.into_inner();
+  let relative = Path::new(&info.path);
+  if info.path.is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+    return Res {
+      code: -1,
+      data: format!("invalid path {}", info.path),
+    }
+    .respond_to();
+  }
+  let target = product_dir(&product_code).join(relative);
+  let result = async {
+    tokio::fs::create_dir_all(target.parent().unwrap()).await?;
+    encryption::write(&target, info.contents).await
+  }
+  .await;
+  match result {
+    Ok(_) => Res {
+      code: 0,
+      data: "保存成功".to_string(),
+    }
+    .respond_to(),
+    Err(err) => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///部署自己的产品 与管理端的部署流程相同 作者记为租户名
+#[post("/products/{product_code}/deploy")]
+pub async fn deploy_product(req: HttpRequest, path: web::Path<(String,)>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  let tenant = match authorize(&req, &product_code) {
+    Ok(tenant) => tenant,
+    Err(err) => return error_response(err),
+  };
+  match deploy::deploy_product(&product_code, Some(tenant.name)).await {
+    Ok(record) => Res {
+      code: if record.status == deploy::DeployStatus::Deployed { 0 } else { -1 },
+      data: record,
+    }
+    .respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///部署历史
+#[get("/products/{product_code}/history")]
+pub async fn get_history(req: HttpRequest, path: web::Path<(String,)>, list: web::Query<ListQuery>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  if let Err(err) = authorize(&req, &product_code) {
+    return error_response(err);
+  }
+  let history = read_history(&product_code).await;
+  match history.and_then(|history| list_query::apply(history, &list, &deploy::LIST_SPEC)) {
+    Ok(page) => page.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///审计日志
+#[get("/products/{product_code}/audit-events")]
+pub async fn get_audit_events(req: HttpRequest, path: web::Path<(String,)>, list: web::Query<ListQuery>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  let events = authorize(&req, &product_code).and_then(|_| audit_log::read_events(&product_code));
+  match events.and_then(|events| list_query::apply(events, &list, &audit_log::LIST_SPEC)) {
+    Ok(page) => page.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///只包含该产品的网关指标 Prometheus 文本格式
+#[get("/products/{product_code}/metrics")]
+pub async fn get_metrics(req: HttpRequest, path: web::Path<(String,)>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  if let Err(err) = authorize(&req, &product_code) {
+    return error_response(err);
+  }
+  HttpResponse::Ok()
+    .content_type("text/plain; version=0.0.4")
+    .body(metrics::render_product(&product_code))
+}
+
+///某天的用量
+#[get("/products/{product_code}/usage")]
+pub async fn get_usage(req: HttpRequest, path: web::Path<(String,)>, query: web::Query<TenantUsageQuery>) -> HttpResponse {
+  let product_code = path.into_inner().0;
+  let date = query
+    .into_inner()
+    .date
+    .unwrap_or_else(|| chrono::Utc::now().format("%Y-%m-%d").to_string());
+  match authorize(&req, &product_code).and_then(|_| usage::daily_usage(&product_code, &date)) {
+    Ok(usage) => Res { code: 0, data: usage }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///在租户自己的产品中搜索代码 目录信息和最近的日志
+#[get("/search")]
+pub async fn search_products(req: HttpRequest, query: web::Query<SearchQuery>) -> HttpResponse {
+  let products = match tenants::authenticate(&req).and_then(|t| tenants::owned_products(&t.id)) {
+    Ok(products) => products.into_iter().map(|p| p.product_code).collect::<Vec<_>>(),
+    Err(err) => return error_response(err),
+  };
+  let query = query.into_inner();
+  match tokio::task::spawn_blocking(move || search::search(&products, &query)).await {
+    Ok(Ok(page)) => Res { code: 0, data: page }.respond_to(),
+    Ok(Err(err)) => error_response(err),
+    Err(err) => error_response(err.into()),
+  }
+}
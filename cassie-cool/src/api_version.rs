@@ -0,0 +1,205 @@
+use crate::config::GatewayConfig;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue, LINK, WARNING};
+use actix_web::http::Method;
+use actix_web::{Error, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+
+///当前的管理接口版本 路由挂在 /api/v1 下
+pub const API_VERSION: u32 = 1;
+pub const API_PREFIX: &str = "/api/v1";
+///仍然可以使用的版本 请求头指定其他版本时拒绝
+pub const SUPPORTED_VERSIONS: [u32; 1] = [1];
+///客户端用这个请求头声明期望的版本 响应中返回实际处理请求的版本
+pub const API_VERSION_HEADER: &str = "x-api-version";
+///管理接口的路径前缀 其余请求都转发给产品
+pub const API_SCOPES: [&str; 6] = ["/runtime/", "/code/", "/admin/", "/auth/", "/hooks/", "/tenant/"];
+
+///管理接口版本 gateway.json 中的 api
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiConfig {
+  pub legacy_sunset: Option<String>,  //不带版本前缀的旧路径下线的日期 YYYY-MM-DD
+  pub deprecations: Vec<Deprecation>, //弃用的接口 响应中带 Deprecation Sunset 和 Warning 头
+}
+
+///弃用的接口 path 为去掉版本前缀后的路径前缀
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Deprecation {
+  pub path: String,
+  #[serde(default)]
+  pub method: Option<String>, //不配置时所有方法都弃用
+  #[serde(default)]
+  pub sunset: Option<String>, //下线日期 YYYY-MM-DD
+  #[serde(default)]
+  pub replacement: Option<String>, //替代的接口路径
+  #[serde(default)]
+  pub message: Option<String>,
+}
+
+///响应中附加的弃用提示
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Notice {
+  sunset: Option<String>, //HTTP 日期
+  link: Option<String>,
+  warning: String,
+}
+
+lazy_static! {
+  static ref CONFIG: RwLock<ApiConfig> = RwLock::new(ApiConfig::default());
+}
+
+///去掉版本前缀后的管理接口路径和路径中的版本 不是管理接口时返回 None
+pub fn management_path(path: &str) -> Option<(Option<u32>, &str)> {
+  let (version, rest) = match path.strip_prefix("/api/v").and_then(|rest| rest.split_once('/')) {
+    Some((version, rest)) => match version.parse::<u32>() {
+      Ok(version) => (Some(version), &path[path.len() - rest.len() - 1..]),
+      Err(_) => (None, path),
+    },
+    None => (None, path),
+  };
+  API_SCOPES.iter().any(|scope| rest.starts_with(scope)).then_some((version, rest))
+}
+
+///YYYY-MM-DD 转为 Sunset 头使用的 HTTP 日期
+fn http_date(date: &str) -> Option<String> {
+  match chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+    Ok(date) => Some(date.format("%a, %d %b %Y 00:00:00 GMT").to_string()),
+    Err(_) => {
+      log::warn!("invalid sunset date {}, expected YYYY-MM-DD", date);
+      None
+    }
+  }
+}
+
+///请求命中的弃用提示 旧路径先于配置的弃用接口
+fn notice(config: &ApiConfig, method: &Method, path: &str, legacy: bool) -> Option<Notice> {
+  if legacy {
+    return Some(Notice {
+      sunset: config.legacy_sunset.as_deref().and_then(http_date),
+      link: Some(format!("<{}{}>; rel=\"successor-version\"", API_PREFIX, path)),
+      warning: format!("unversioned management routes are deprecated, use {}{}", API_PREFIX, path),
+    });
+  }
+  let deprecation = config
+    .deprecations
+    .iter()
+    .find(|d| path.starts_with(&d.path) && d.method.as_deref().map(|m| m.eq_ignore_ascii_case(method.as_str())).unwrap_or(true))?;
+  Some(Notice {
+    sunset: deprecation.sunset.as_deref().and_then(http_date),
+    link: deprecation
+      .replacement
+      .as_ref()
+      .map(|r| format!("<{}{}>; rel=\"successor-version\"", API_PREFIX, r)),
+    warning: deprecation
+      .message
+      .clone()
+      .unwrap_or_else(|| format!("{} is deprecated", deprecation.path)),
+  })
+}
+
+///版本协商和弃用提示的中间件 用法 .wrap_fn(api_version::negotiate)<br>
+/// 请求头或路径指定了不支持的版本时返回 400
+pub fn negotiate<S>(req: ServiceRequest, srv: &S) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+  S::Future: 'static,
+{
+  let (path_version, path) = match management_path(req.path()) {
+    Some((version, path)) => (version, path.to_string()),
+    None => return Box::pin(srv.call(req)),
+  };
+  let requested = match req
+    .headers()
+    .get(API_VERSION_HEADER)
+    .map(|v| v.to_str().unwrap_or_default().trim().trim_start_matches('v').parse::<u32>())
+  {
+    None => None,
+    Some(Ok(version)) => Some(version),
+    Some(Err(_)) => return reject(req, format!("invalid {} header", API_VERSION_HEADER)),
+  };
+  for version in [path_version, requested].into_iter().flatten() {
+    if !SUPPORTED_VERSIONS.contains(&version) {
+      return reject(
+        req,
+        format!("api version {} is not supported, supported versions: {:?}", version, SUPPORTED_VERSIONS),
+      );
+    }
+  }
+  if let (Some(path_version), Some(requested)) = (path_version, requested) {
+    if path_version != requested {
+      return reject(
+        req,
+        format!(
+          "{} header {} does not match the path version {}",
+          API_VERSION_HEADER, requested, path_version
+        ),
+      );
+    }
+  }
+  let notice = notice(&CONFIG.read().unwrap(), req.method(), &path, path_version.is_none());
+  let fut = srv.call(req);
+  Box::pin(async move {
+    let mut res = fut.await?;
+    let headers = res.headers_mut();
+    headers.insert(HeaderName::from_static(API_VERSION_HEADER), HeaderValue::from(API_VERSION));
+    if let Some(notice) = notice {
+      headers.insert(HeaderName::from_static("deprecation"), HeaderValue::from_static("true"));
+      if let Some(sunset) = notice.sunset.and_then(|s| HeaderValue::from_str(&s).ok()) {
+        headers.insert(HeaderName::from_static("sunset"), sunset);
+      }
+      if let Some(link) = notice.link.and_then(|l| HeaderValue::from_str(&l).ok()) {
+        headers.append(LINK, link);
+      }
+      if let Ok(warning) = HeaderValue::from_str(&format!("299 - \"{}\"", notice.warning.replace('"', "'"))) {
+        headers.append(WARNING, warning);
+      }
+    }
+    Ok(res)
+  })
+}
+
+fn reject(req: ServiceRequest, message: String) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>> {
+  let res = req.into_response(HttpResponse::BadRequest().insert_header((API_VERSION_HEADER, API_VERSION)).body(message));
+  Box::pin(async move { Ok(res) })
+}
+
+///读取弃用配置
+pub fn start() {
+  let config = GatewayConfig::load().map(|c| c.api).unwrap_or_default();
+  if let Some(sunset) = &config.legacy_sunset {
+    log::info!("unversioned management routes sunset on {}", sunset);
+  }
+  *CONFIG.write().unwrap() = config;
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn strips_version_and_marks_legacy_routes() {
+    assert_eq!(management_path("/api/v1/admin/users"), Some((Some(1), "/admin/users")));
+    assert_eq!(management_path("/admin/users"), Some((None, "/admin/users")));
+    assert_eq!(management_path("/api/v1/orders"), None);
+    assert_eq!(management_path("/orders"), None);
+    let config = ApiConfig {
+      legacy_sunset: Some("2027-01-31".to_string()),
+      deprecations: vec![Deprecation {
+        path: "/runtime/pro/".to_string(),
+        method: None,
+        sunset: None,
+        replacement: Some("/runtime/shop/start".to_string()),
+        message: None,
+      }],
+    };
+    let legacy = notice(&config, &Method::GET, "/admin/users", true).unwrap();
+    assert_eq!(legacy.sunset.as_deref(), Some("Sun, 31 Jan 2027 00:00:00 GMT"));
+    assert_eq!(legacy.link.as_deref(), Some("</api/v1/admin/users>; rel=\"successor-version\""));
+    assert!(notice(&config, &Method::GET, "/admin/users", false).is_none());
+    assert!(notice(&config, &Method::GET, "/runtime/pro/shop/start", false).is_some());
+  }
+}
@@ -0,0 +1,52 @@
+use crate::api_version::API_PREFIX;
+use crate::storage::{self, Object};
+use deno_core::error::{custom_error, generic_error, AnyError};
+
+//名称来自请求路径 不允许跳出 artifacts 目录
+fn valid_segment(s: &str) -> bool {
+  !s.is_empty() && s != "." && s != ".." && !s.contains(['/', '\\'])
+}
+
+///平台生成的文件 存储中的 artifacts/{kind}/{name} 例如用量导出 通过下载链接获取
+pub fn artifact_key(kind: &str, name: &str) -> Result<String, AnyError> {
+  if !valid_segment(kind) || !valid_segment(name) {
+    return Err(generic_error(format!("invalid artifact {}/{}", kind, name)));
+  }
+  Ok(format!("artifacts/{}/{}", kind, name))
+}
+
+///下载链接
+pub fn download_url(kind: &str, name: &str) -> String {
+  format!("{}/admin/artifacts/{}/{}", API_PREFIX, kind, name)
+}
+
+///保存文件 返回下载链接
+pub async fn put_artifact(kind: &str, name: &str, bytes: Vec<u8>) -> Result<String, AnyError> {
+  storage::storage().put(&artifact_key(kind, name)?, bytes).await?;
+  Ok(download_url(kind, name))
+}
+
+pub async fn read_artifact(kind: &str, name: &str) -> Result<Vec<u8>, AnyError> {
+  storage::storage()
+    .get(&artifact_key(kind, name)?)
+    .await?
+    .ok_or_else(|| custom_error("NotFound", format!("artifact {}/{} not found", kind, name)))
+}
+
+pub async fn delete_artifact(kind: &str, name: &str) -> Result<(), AnyError> {
+  storage::storage().delete(&artifact_key(kind, name)?).await
+}
+
+///某类文件 kind 为空时列出所有文件 返回的键为 {kind}/{name}
+pub async fn list_artifacts(kind: Option<&str>) -> Result<Vec<Object>, AnyError> {
+  let prefix = match kind {
+    Some(kind) if !valid_segment(kind) => return Err(generic_error(format!("invalid artifact kind {}", kind))),
+    Some(kind) => format!("artifacts/{}/", kind),
+    None => "artifacts/".to_string(),
+  };
+  let mut objects = storage::storage().list(&prefix).await?;
+  for object in objects.iter_mut() {
+    object.key = object.key.trim_start_matches("artifacts/").to_string();
+  }
+  Ok(objects)
+}
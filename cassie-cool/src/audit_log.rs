@@ -0,0 +1,515 @@
+use crate::artifacts;
+use crate::config::{data_dir, GatewayConfig};
+use crate::list_query::ListSpec;
+use crate::replica;
+use crate::storage;
+use crate::util::now_millis;
+use deno_core::error::{generic_error, AnyError};
+use lazy_static::lazy_static;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+///链上第一条事件的 prev_hash
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+///审计日志 gateway.json 中的 audit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuditConfig {
+  pub checkpoint_secs: u64,       //生成检查点的间隔 0 表示只在手动调用时生成
+  pub export_dir: Option<String>, //检查点同时追加写到这个目录 例如挂载的只写存储
+}
+
+impl Default for AuditConfig {
+  fn default() -> Self {
+    Self {
+      checkpoint_secs: 3600,
+      export_dir: None,
+    }
+  }
+}
+
+///审计事件类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+  OfflineViolation, //离线模式下访问了白名单以外的网络地址
+  RetentionPruned,  //按保留期删除了数据
+  StateSnapshot,    //导出了产品的持久化状态
+  StateRestored,    //状态快照恢复到了这个产品
+  ProductCloned,    //这个产品由其他产品复制而来
+  ProductExported,  //导出了产品包
+  ProductImported,  //从产品包导入了代码
+  FileTrashed,      //删除或被覆盖的文件移到了回收站
+  TrashRestored,    //从回收站恢复了文件
+  TrashPurged,      //永久删除了回收站中的文件
+}
+
+///审计事件<br>
+/// 每条事件带上前一条的 hash 修改或删除其中任何一条都会让之后的链校验失败
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+  #[serde(default)]
+  pub seq: u64, //产品内从 1 开始连续递增 升级之前的事件为 0
+  pub kind: AuditKind,
+  pub product_code: String,
+  pub created_at: u64,
+  pub detail: serde_json::Value, //事件相关的结构化数据 按 kind 区分
+  #[serde(default)]
+  pub prev_hash: String,
+  #[serde(default)]
+  pub hash: String, //sha256(prev_hash 和事件内容)
+}
+
+///审计事件列表的排序和过滤 status 按事件类型过滤
+pub const LIST_SPEC: ListSpec = ListSpec {
+  id: "seq",
+  sort: &["seq", "created_at", "kind"],
+  default_sort: "seq",
+  status: Some("kind"),
+  tag: None,
+  owner: None,
+};
+
+impl AuditEvent {
+  pub fn new(kind: AuditKind, product_code: &str, detail: serde_json::Value) -> Self {
+    Self {
+      seq: 0,
+      kind,
+      product_code: product_code.to_string(),
+      created_at: now_millis(),
+      detail,
+      prev_hash: String::new(),
+      hash: String::new(),
+    }
+  }
+
+  ///参与 hash 的内容 不包含 hash 本身
+  fn compute_hash(&self) -> String {
+    let content = serde_json::json!({
+      "seq": self.seq,
+      "kind": self.kind,
+      "product_code": self.product_code,
+      "created_at": self.created_at,
+      "detail": self.detail,
+    });
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(self.prev_hash.as_bytes());
+    ctx.update(b"\n");
+    ctx.update(content.to_string().as_bytes());
+    hex::encode(ctx.finish())
+  }
+}
+
+///检查点 记录某个时刻链头的 seq 和 hash 保存在日志之外 防止整条链被重写
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+  pub product_code: String,
+  pub seq: u64,
+  pub hash: String,
+  pub created_at: u64,
+  pub export: Option<String>, //截至检查点的日志副本下载链接
+  #[serde(default)]
+  pub pruned: bool, //保留期清理时留下的锚点 这个 seq 及之前的事件已删除
+}
+
+///链校验结果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyReport {
+  pub product_code: String,
+  pub valid: bool,
+  pub entries: usize,
+  pub legacy_entries: usize, //升级之前没有 hash 的事件 不参与校验
+  pub last_seq: u64,
+  pub last_hash: String,
+  pub checkpoints_checked: usize,
+  pub error: Option<String>, //第一个校验失败的原因
+}
+
+lazy_static! {
+  //产品链头的 seq 和 hash 持有锁时才追加 保证链是连续的
+  static ref CHAIN: Mutex<HashMap<String, (u64, String)>> = Mutex::new(HashMap::new());
+}
+
+///审计事件 data/audit/{product_code}.jsonl 每行一条
+pub fn audit_path(product_code: &str) -> PathBuf {
+  let mut path = data_dir();
+  path.push("audit");
+  path.push(format!("{}.jsonl", product_code));
+  path
+}
+
+///检查点 data/audit-checkpoints/{product_code}.jsonl
+fn checkpoints_path(product_code: &str) -> PathBuf {
+  let mut path = data_dir();
+  path.push("audit-checkpoints");
+  path.push(format!("{}.jsonl", product_code));
+  path
+}
+
+fn read_lines(path: PathBuf) -> Result<Vec<String>, AnyError> {
+  match std::fs::read_to_string(path) {
+    Ok(text) => Ok(text.lines().filter(|l| !l.is_empty()).map(String::from).collect()),
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+    Err(err) => Err(err.into()),
+  }
+}
+
+fn append_line<T: Serialize>(path: PathBuf, value: &T) -> Result<(), AnyError> {
+  std::fs::create_dir_all(path.parent().unwrap())?;
+  let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+  let mut line = serde_json::to_vec(value)?;
+  line.push(b'\n');
+  file.write_all(&line)?;
+  file.sync_data()?;
+  Ok(())
+}
+
+///日志中最后一条带 hash 的事件
+fn load_head(product_code: &str) -> Result<(u64, String), AnyError> {
+  let head = read_lines(audit_path(product_code))?
+    .iter()
+    .rev()
+    .filter_map(|l| serde_json::from_str::<AuditEvent>(l).ok())
+    .find(|e| !e.hash.is_empty())
+    .map(|e| (e.seq, e.hash));
+  Ok(head.unwrap_or((0, GENESIS_HASH.to_string())))
+}
+
+fn chain_head(chain: &mut HashMap<String, (u64, String)>, product_code: &str) -> Result<(u64, String), AnyError> {
+  if let Some(head) = chain.get(product_code) {
+    return Ok(head.clone());
+  }
+  let head = load_head(product_code)?;
+  chain.insert(product_code.to_string(), head.clone());
+  Ok(head)
+}
+
+///记录审计事件 只追加 接在链头后面<br>
+/// 同步写入 runtime 线程里的权限检查也会调用
+pub fn record(event: &AuditEvent) -> Result<(), AnyError> {
+  let mut chain = CHAIN.lock().unwrap();
+  let (seq, prev_hash) = chain_head(&mut chain, &event.product_code)?;
+  let mut event = event.clone();
+  event.seq = seq + 1;
+  event.prev_hash = prev_hash;
+  event.hash = event.compute_hash();
+  append_line(audit_path(&event.product_code), &event)?;
+  chain.insert(event.product_code.clone(), (event.seq, event.hash.clone()));
+  log::warn!("{} audit event {:?} {}", event.product_code, event.kind, event.detail);
+  Ok(())
+}
+
+///读取产品的审计事件 按时间先后
+pub fn read_events(product_code: &str) -> Result<Vec<AuditEvent>, AnyError> {
+  Ok(
+    read_lines(audit_path(product_code))?
+      .iter()
+      .filter_map(|l| serde_json::from_str(l).ok())
+      .collect(),
+  )
+}
+
+pub fn read_checkpoints(product_code: &str) -> Result<Vec<Checkpoint>, AnyError> {
+  Ok(
+    read_lines(checkpoints_path(product_code))?
+      .iter()
+      .filter_map(|l| serde_json::from_str(l).ok())
+      .collect(),
+  )
+}
+
+///校验事件链 lines 为日志的每一行
+fn verify_lines(product_code: &str, lines: &[String], checkpoints: &[Checkpoint]) -> VerifyReport {
+  let mut report = VerifyReport {
+    product_code: product_code.to_string(),
+    valid: true,
+    entries: lines.len(),
+    legacy_entries: 0,
+    last_seq: 0,
+    last_hash: GENESIS_HASH.to_string(),
+    checkpoints_checked: 0,
+    error: None,
+  };
+  //清理过的日志从最后一个锚点接着校验
+  let anchor = checkpoints.iter().filter(|c| c.pruned).max_by_key(|c| c.seq);
+  if let Some(anchor) = anchor {
+    report.last_seq = anchor.seq;
+    report.last_hash = anchor.hash.clone();
+  }
+  let mut chained = false;
+  let mut hashes = HashMap::new();
+  for (index, line) in lines.iter().enumerate() {
+    let problem = match serde_json::from_str::<AuditEvent>(line) {
+      Err(_) => Some(format!("line {} is not a valid event", index + 1)),
+      //升级之前的事件只能出现在链的前面
+      Ok(event) if event.hash.is_empty() && !chained => {
+        report.legacy_entries += 1;
+        None
+      }
+      Ok(event) if event.seq != report.last_seq + 1 => Some(format!("line {} has seq {}, expected {}", index + 1, event.seq, report.last_seq + 1)),
+      Ok(event) if event.prev_hash != report.last_hash => Some(format!("event {} does not reference the previous hash", event.seq)),
+      Ok(event) if event.compute_hash() != event.hash => Some(format!("event {} was modified", event.seq)),
+      Ok(event) => {
+        chained = true;
+        hashes.insert(event.seq, event.hash.clone());
+        report.last_seq = event.seq;
+        report.last_hash = event.hash;
+        None
+      }
+    };
+    if problem.is_some() {
+      report.valid = false;
+      report.error = problem;
+      return report;
+    }
+  }
+  let pruned_seq = anchor.map(|a| a.seq).unwrap_or(0);
+  for checkpoint in checkpoints.iter().filter(|c| c.seq > pruned_seq) {
+    report.checkpoints_checked += 1;
+    let problem = match hashes.get(&checkpoint.seq) {
+      Some(hash) if *hash == checkpoint.hash => None,
+      Some(_) => Some(format!("event {} does not match the checkpoint", checkpoint.seq)),
+      None => Some(format!("event {} from the checkpoint is missing", checkpoint.seq)),
+    };
+    if problem.is_some() {
+      report.valid = false;
+      report.error = problem;
+      break;
+    }
+  }
+  report
+}
+
+///校验产品的审计日志 包括和检查点的比对
+pub fn verify(product_code: &str) -> Result<VerifyReport, AnyError> {
+  let lines = read_lines(audit_path(product_code))?;
+  let checkpoints = read_checkpoints(product_code)?;
+  Ok(verify_lines(product_code, &lines, &checkpoints))
+}
+
+///为当前链头生成检查点 并导出截至检查点的日志副本 链头没有变化时返回 None
+pub async fn checkpoint(product_code: &str, export_dir: Option<&str>) -> Result<Option<Checkpoint>, AnyError> {
+  let report = verify(product_code)?;
+  if !report.valid {
+    return Err(generic_error(format!(
+      "audit log of {} failed verification: {}",
+      product_code,
+      report.error.unwrap_or_default()
+    )));
+  }
+  let last = read_checkpoints(product_code)?.last().map(|c| c.seq).unwrap_or(0);
+  if report.last_seq == 0 || report.last_seq == last {
+    return Ok(None);
+  }
+  let bytes = tokio::fs::read(audit_path(product_code)).await?;
+  if storage::is_remote() {
+    storage::storage().put(&format!("audit/{}.jsonl", product_code), bytes.clone()).await?;
+  }
+  let export = artifacts::put_artifact("audit", &format!("{}-{}.jsonl", product_code, report.last_seq), bytes).await?;
+  let checkpoint = Checkpoint {
+    product_code: product_code.to_string(),
+    seq: report.last_seq,
+    hash: report.last_hash,
+    created_at: now_millis(),
+    export: Some(export),
+    pruned: false,
+  };
+  append_line(checkpoints_path(product_code), &checkpoint)?;
+  if storage::is_remote() {
+    let checkpoints = tokio::fs::read(checkpoints_path(product_code)).await?;
+    storage::storage()
+      .put(&format!("audit-checkpoints/{}.jsonl", product_code), checkpoints)
+      .await?;
+  }
+  if let Some(dir) = export_dir {
+    append_line(PathBuf::from(dir).join(format!("{}.jsonl", product_code)), &checkpoint)?;
+  }
+  Ok(Some(checkpoint))
+}
+
+///删除 cutoff 之前的事件 链头的事件始终保留<br>
+/// 在检查点中留下锚点 之后的校验从锚点开始 返回删除的条数和字节数
+pub fn prune(product_code: &str, cutoff: u64, dry_run: bool) -> Result<(u64, u64), AnyError> {
+  let chain = CHAIN.lock().unwrap();
+  let lines = read_lines(audit_path(product_code))?;
+  let events: Vec<Option<AuditEvent>> = lines.iter().map(|l| serde_json::from_str(l).ok()).collect();
+  let head = events.iter().rposition(|e| e.as_ref().map(|e| !e.hash.is_empty()).unwrap_or(false));
+  //只删除开头连续的旧事件
+  let count = events
+    .iter()
+    .enumerate()
+    .take_while(|(index, e)| Some(*index) != head && e.as_ref().map(|e| e.created_at < cutoff).unwrap_or(false))
+    .count();
+  let bytes = lines[..count].iter().map(|l| l.len() as u64 + 1).sum();
+  if dry_run || count == 0 {
+    return Ok((count as u64, bytes));
+  }
+  if let Some(last) = events[..count].iter().rev().flatten().find(|e| !e.hash.is_empty()) {
+    let anchor = Checkpoint {
+      product_code: product_code.to_string(),
+      seq: last.seq,
+      hash: last.hash.clone(),
+      created_at: now_millis(),
+      export: None,
+      pruned: true,
+    };
+    append_line(checkpoints_path(product_code), &anchor)?;
+  }
+  let path = audit_path(product_code);
+  let tmp = path.with_extension("jsonl.tmp");
+  let mut text = lines[count..].join("\n");
+  text.push('\n');
+  std::fs::write(&tmp, text)?;
+  std::fs::rename(tmp, path)?;
+  drop(chain);
+  Ok((count as u64, bytes))
+}
+
+///有审计日志的产品
+pub fn products() -> Result<Vec<String>, AnyError> {
+  let mut dir = data_dir();
+  dir.push("audit");
+  let entries = match std::fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+    Err(err) => return Err(err.into()),
+  };
+  let mut products = vec![];
+  for entry in entries {
+    let name = entry?.file_name().to_string_lossy().to_string();
+    if let Some(product_code) = name.strip_suffix(".jsonl") {
+      products.push(product_code.to_string());
+    }
+  }
+  products.sort();
+  Ok(products)
+}
+
+///使用外部存储时 从最近一次检查点恢复本地没有的审计日志和检查点 返回恢复的文件数
+pub async fn hydrate() -> Result<usize, AnyError> {
+  let mut restored = 0;
+  for dir in ["audit", "audit-checkpoints"] {
+    for object in storage::storage().list(&format!("{}/", dir)).await? {
+      let path = data_dir().join(&object.key);
+      if path.exists() {
+        continue;
+      }
+      if let Some(bytes) = storage::storage().get(&object.key).await? {
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        tokio::fs::write(&path, bytes).await?;
+        restored += 1;
+      }
+    }
+  }
+  Ok(restored)
+}
+
+///按网关配置定时为所有产品生成检查点
+pub fn start() {
+  let config = GatewayConfig::load().map(|c| c.audit).unwrap_or_default();
+  //副本不记录部署类审计事件 检查点由主实例生成
+  if config.checkpoint_secs == 0 || replica::enabled() {
+    return;
+  }
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.checkpoint_secs));
+    loop {
+      interval.tick().await;
+      for product_code in products().unwrap_or_default() {
+        if let Err(err) = checkpoint(&product_code, config.export_dir.as_deref()).await {
+          log::error!("failed to checkpoint audit log of {}: {}", product_code, err);
+        }
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn chain(count: u64) -> Vec<AuditEvent> {
+    let mut prev_hash = GENESIS_HASH.to_string();
+    (1..=count)
+      .map(|seq| {
+        let mut event = AuditEvent::new(AuditKind::OfflineViolation, "shop", serde_json::json!({ "host": "example.com" }));
+        event.seq = seq;
+        event.prev_hash = prev_hash.clone();
+        event.hash = event.compute_hash();
+        prev_hash = event.hash.clone();
+        event
+      })
+      .collect()
+  }
+
+  fn lines(events: &[AuditEvent]) -> Vec<String> {
+    events.iter().map(|e| serde_json::to_string(e).unwrap()).collect()
+  }
+
+  #[test]
+  fn verifies_intact_chain() {
+    let events = chain(3);
+    let checkpoint = Checkpoint {
+      product_code: "shop".to_string(),
+      seq: 2,
+      hash: events[1].hash.clone(),
+      created_at: 0,
+      export: None,
+      pruned: false,
+    };
+    let report = verify_lines("shop", &lines(&events), &[checkpoint]);
+    assert!(report.valid, "{:?}", report.error);
+    assert_eq!(report.last_seq, 3);
+    assert_eq!(report.checkpoints_checked, 1);
+  }
+
+  #[test]
+  fn detects_modified_and_removed_events() {
+    let mut events = chain(3);
+    events[1].detail = serde_json::json!({ "host": "other.com" });
+    let report = verify_lines("shop", &lines(&events), &[]);
+    assert!(!report.valid);
+    assert_eq!(report.error.as_deref(), Some("event 2 was modified"));
+
+    let mut events = chain(3);
+    events.remove(1);
+    assert!(!verify_lines("shop", &lines(&events), &[]).valid);
+  }
+
+  #[test]
+  fn detects_rewritten_chain_with_checkpoint() {
+    let events = chain(2);
+    let checkpoint = Checkpoint {
+      product_code: "shop".to_string(),
+      seq: 2,
+      hash: "f".repeat(64),
+      created_at: 0,
+      export: None,
+      pruned: false,
+    };
+    assert!(!verify_lines("shop", &lines(&events), &[checkpoint]).valid);
+  }
+
+  #[test]
+  fn continues_from_prune_anchor() {
+    let events = chain(4);
+    let anchor = Checkpoint {
+      product_code: "shop".to_string(),
+      seq: 2,
+      hash: events[1].hash.clone(),
+      created_at: 0,
+      export: None,
+      pruned: true,
+    };
+    let report = verify_lines("shop", &lines(&events[2..]), &[anchor.clone()]);
+    assert!(report.valid, "{:?}", report.error);
+    assert_eq!(report.last_seq, 4);
+    assert!(!verify_lines("shop", &lines(&events[3..]), &[anchor]).valid);
+  }
+}
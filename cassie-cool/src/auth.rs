@@ -0,0 +1,215 @@
+use crate::config::GatewayConfig;
+use crate::ldap::{self, LdapConfig};
+use crate::users::{self, Session, User, UserSource};
+use crate::Res;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::{Error, HttpMessage, HttpRequest, HttpResponse};
+use deno_core::error::{custom_error, get_custom_error_class, AnyError};
+use futures_util::future::LocalBoxFuture;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+pub const SESSION_COOKIE: &str = "cool_session";
+///前端从这个 cookie 读取 csrf token 修改类请求放在 x-csrf-token 请求头里
+pub const CSRF_COOKIE: &str = "cool_csrf";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+///管理接口的角色 权限依次增加
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+  Viewer,    //只读
+  Developer, //可以修改代码 部署 启停 runtime
+  Admin,     //可以管理租户 用户 导出用量
+}
+
+///控制台用户的认证方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthBackend {
+  #[default]
+  Local, //data/users.json 中的邮箱密码
+  Ldap, //LDAP / Active Directory 绑定校验 本地有密码的用户仍可登录
+}
+
+///控制台认证 gateway.json 中的 auth
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+  pub enabled: bool,            //关闭时管理接口不做认证 兼容之前的部署
+  pub session_ttl_secs: u64,    //登录会话有效期
+  pub backend: AuthBackend,     //认证方式
+  pub ldap: Option<LdapConfig>, //backend 为 ldap 时必填
+}
+
+impl Default for AuthConfig {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      session_ttl_secs: 12 * 3600,
+      backend: AuthBackend::Local,
+      ldap: None,
+    }
+  }
+}
+
+///当前请求的用户 认证通过后放在请求的 extensions 里
+#[derive(Debug, Clone)]
+pub struct Principal {
+  pub user_id: String,
+  pub email: String,
+  pub role: Role,
+  pub tenant_id: Option<String>,
+}
+
+impl From<User> for Principal {
+  fn from(user: User) -> Self {
+    Self {
+      user_id: user.id,
+      email: user.email,
+      role: user.role,
+      tenant_id: user.tenant_id,
+    }
+  }
+}
+
+lazy_static! {
+  static ref ENABLED: AtomicBool = AtomicBool::new(false);
+  static ref LDAP_ENABLED: AtomicBool = AtomicBool::new(false);
+  static ref SESSION_TTL_SECS: AtomicU64 = AtomicU64::new(AuthConfig::default().session_ttl_secs);
+}
+
+pub fn enabled() -> bool {
+  ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn session_ttl_secs() -> u64 {
+  SESSION_TTL_SECS.load(Ordering::Relaxed)
+}
+
+fn is_safe_method(method: &Method) -> bool {
+  matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+///认证中间件放入的当前用户 没有开启认证时为空
+pub fn principal(req: &HttpRequest) -> Option<Principal> {
+  req.extensions().get::<Principal>().cloned()
+}
+
+///按配置的认证方式登录 返回会话 token
+pub async fn login(email: &str, password: &str, totp: Option<&str>) -> Result<(String, Session, User), AnyError> {
+  if LDAP_ENABLED.load(Ordering::Relaxed) && !users::has_local_password(email)? {
+    let identity = ldap::verify(email, password).await?;
+    return users::login_external(&identity.email, UserSource::Ldap, identity.role, totp);
+  }
+  users::login(email, password, totp)
+}
+
+///从会话 cookie 识别用户 修改类请求还要校验 csrf token
+pub fn authenticate(req: &HttpRequest) -> Result<Principal, AnyError> {
+  let token = req.cookie(SESSION_COOKIE).ok_or_else(|| custom_error("Unauthorized", "not logged in"))?;
+  let (session, user) = users::session(token.value())?;
+  if !is_safe_method(req.method()) {
+    let csrf = req.headers().get(CSRF_HEADER).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    if ring::constant_time::verify_slices_are_equal(csrf.as_bytes(), session.csrf_token.as_bytes()).is_err() {
+      return Err(custom_error("Forbidden", "invalid csrf token"));
+    }
+  }
+  Ok(user.into())
+}
+
+///认证并检查角色 修改类请求至少需要 developer 绑定租户的用户不能访问平台接口
+pub fn authorize(req: &HttpRequest, min_role: Role) -> Result<Principal, AnyError> {
+  let principal = authenticate(req)?;
+  let required = match is_safe_method(req.method()) {
+    true => min_role,
+    false => min_role.max(Role::Developer),
+  };
+  if principal.tenant_id.is_some() || principal.role < required {
+    return Err(custom_error("Forbidden", "permission denied"));
+  }
+  Ok(principal)
+}
+
+///接口单独要求的角色 用于启停 runtime 这类使用 GET 的修改类接口 没有开启认证时不检查
+pub fn require(req: &HttpRequest, min_role: Role) -> Result<(), AnyError> {
+  if !enabled() {
+    return Ok(());
+  }
+  match principal(req) {
+    Some(principal) if principal.tenant_id.is_none() && principal.role >= min_role => Ok(()),
+    Some(_) => Err(custom_error("Forbidden", "permission denied")),
+    None => authorize(req, min_role).map(|_| ()),
+  }
+}
+
+///认证错误 未认证返回 401 没有权限返回 403 其他错误按统一格式返回
+pub fn error_response(err: AnyError) -> HttpResponse {
+  match get_custom_error_class(&err) {
+    Some("Unauthorized") => HttpResponse::Unauthorized().body(err.to_string()),
+    Some("Forbidden") => HttpResponse::Forbidden().body(err.to_string()),
+    Some("NotFound") => HttpResponse::NotFound().body(err.to_string()),
+    _ => Res {
+      code: -1,
+      data: err.to_string(),
+    }
+    .respond_to(),
+  }
+}
+
+///scope 的认证中间件 用法 .wrap_fn(|req, srv| auth::guard(req, srv, Role::Admin))
+pub fn guard<S>(req: ServiceRequest, srv: &S, min_role: Role) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+  S::Future: 'static,
+{
+  if !enabled() {
+    return Box::pin(srv.call(req));
+  }
+  match authorize(req.request(), min_role) {
+    Ok(principal) => {
+      req.extensions_mut().insert(principal);
+      Box::pin(srv.call(req))
+    }
+    Err(err) => {
+      let res = req.into_response(error_response(err));
+      Box::pin(async move { Ok(res) })
+    }
+  }
+}
+
+///按网关配置开启认证 还没有用户时用 COOL_ADMIN_EMAIL COOL_ADMIN_PASSWORD 创建管理员
+pub fn start() {
+  let config = GatewayConfig::load().map(|c| c.auth).unwrap_or_default();
+  ENABLED.store(config.enabled, Ordering::Relaxed);
+  SESSION_TTL_SECS.store(config.session_ttl_secs, Ordering::Relaxed);
+  if !config.enabled {
+    return;
+  }
+  if config.backend == AuthBackend::Ldap {
+    match config.ldap {
+      Some(ldap_config) => {
+        ldap::init(ldap_config);
+        LDAP_ENABLED.store(true, Ordering::Relaxed);
+      }
+      None => log::error!("auth backend is ldap but auth.ldap is not configured, only local users can log in"),
+    }
+  }
+  if let (Ok(email), Ok(password)) = (std::env::var("COOL_ADMIN_EMAIL"), std::env::var("COOL_ADMIN_PASSWORD")) {
+    match users::bootstrap_admin(&email, &password) {
+      Ok(Some(user)) => log::info!("created admin user {}", user.email),
+      Ok(None) => {}
+      Err(err) => log::error!("failed to create admin user: {}", err),
+    }
+  }
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(600));
+    loop {
+      interval.tick().await;
+      users::purge_sessions();
+    }
+  });
+}
@@ -0,0 +1,161 @@
+use crate::{metrics, usage};
+use actix_web::web::Bytes;
+use futures_util::{Stream, StreamExt};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+///带宽上限 cool.json 中的 bandwidth 单位 字节/秒 同一产品的所有连接共享
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BandwidthLimit {
+  pub upload_bytes_per_sec: Option<u64>,   //请求体 客户端到 worker
+  pub download_bytes_per_sec: Option<u64>, //响应体 worker 到客户端
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+  Upload,
+  Download,
+}
+
+impl Direction {
+  fn as_str(&self) -> &'static str {
+    match self {
+      Direction::Upload => "upload",
+      Direction::Download => "download",
+    }
+  }
+}
+
+///令牌桶 容量为一秒的流量 令牌不足时先借用 再按欠下的量等待
+pub struct TokenBucket {
+  rate: u64,
+  state: Mutex<(f64, Instant)>, //(剩余令牌, 上次补充时间)
+}
+
+impl TokenBucket {
+  pub fn new(rate: u64) -> Self {
+    Self {
+      rate,
+      state: Mutex::new((rate as f64, Instant::now())),
+    }
+  }
+
+  ///取出 n 个令牌 返回需要等待的时间
+  fn take(&self, n: usize, now: Instant) -> Duration {
+    let mut state = self.state.lock().unwrap();
+    let (tokens, last) = *state;
+    let refilled = (tokens + now.duration_since(last).as_secs_f64() * self.rate as f64).min(self.rate as f64);
+    let remaining = refilled - n as f64;
+    *state = (remaining, now);
+    match remaining < 0.0 {
+      true => Duration::from_secs_f64(-remaining / self.rate as f64),
+      false => Duration::ZERO,
+    }
+  }
+
+  pub async fn acquire(&self, n: usize) {
+    let wait = self.take(n, Instant::now());
+    if !wait.is_zero() {
+      tokio::time::sleep(wait).await;
+    }
+  }
+}
+
+///按秒统计的吞吐量
+struct Meter {
+  window_start: Instant,
+  bytes: u64,
+}
+
+lazy_static! {
+  static ref BUCKETS: Mutex<HashMap<(String, Direction), Arc<TokenBucket>>> = Mutex::new(HashMap::new());
+  static ref METERS: Mutex<HashMap<(String, Direction), Meter>> = Mutex::new(HashMap::new());
+}
+
+///产品某个方向的令牌桶 没有限制时返回 None 限速调整后重新创建
+pub fn bucket(product_code: &str, direction: Direction, limit: &BandwidthLimit) -> Option<Arc<TokenBucket>> {
+  let key = (product_code.to_string(), direction);
+  let rate = match direction {
+    Direction::Upload => limit.upload_bytes_per_sec,
+    Direction::Download => limit.download_bytes_per_sec,
+  };
+  let mut buckets = BUCKETS.lock().unwrap();
+  match rate.filter(|rate| *rate > 0) {
+    Some(rate) => {
+      let bucket = buckets.entry(key).or_insert_with(|| Arc::new(TokenBucket::new(rate)));
+      if bucket.rate != rate {
+        *bucket = Arc::new(TokenBucket::new(rate));
+      }
+      Some(bucket.clone())
+    }
+    None => {
+      buckets.remove(&key);
+      None
+    }
+  }
+}
+
+///记录流量 累加字节数 每秒更新一次当前吞吐量
+pub fn record(product_code: &str, direction: Direction, bytes: usize) {
+  let labels = [("product", product_code), ("direction", direction.as_str())];
+  metrics::inc_counter("gateway_body_bytes_total", "Request and response body bytes proxied by the gateway", &labels, bytes as u64);
+  if direction == Direction::Download {
+    usage::record_egress(product_code, bytes);
+  }
+  let mut meters = METERS.lock().unwrap();
+  let meter = meters.entry((product_code.to_string(), direction)).or_insert_with(|| Meter {
+    window_start: Instant::now(),
+    bytes: 0,
+  });
+  meter.bytes += bytes as u64;
+  let elapsed = meter.window_start.elapsed();
+  if elapsed >= Duration::from_secs(1) {
+    let rate = meter.bytes as f64 / elapsed.as_secs_f64();
+    metrics::set_gauge("gateway_throughput_bytes_per_second", "Body throughput over the last second", &labels, rate.round());
+    meter.window_start = Instant::now();
+    meter.bytes = 0;
+  }
+}
+
+///包装请求体或响应体 按产品限速并统计流量
+pub fn throttle<S, E>(stream: S, product_code: &str, direction: Direction, limit: &BandwidthLimit) -> impl Stream<Item = Result<Bytes, E>> + 'static
+where
+  S: Stream<Item = Result<Bytes, E>> + 'static,
+  E: 'static,
+{
+  let product_code = product_code.to_string();
+  let bucket = bucket(&product_code, direction, limit);
+  stream.then(move |chunk| {
+    let bucket = bucket.clone();
+    let product_code = product_code.clone();
+    async move {
+      if let Ok(bytes) = &chunk {
+        if let Some(bucket) = bucket {
+          bucket.acquire(bytes.len()).await;
+        }
+        record(&product_code, direction, bytes.len());
+      }
+      chunk
+    }
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn token_bucket_waits_for_debt() {
+    let bucket = TokenBucket::new(1000);
+    let now = Instant::now();
+    assert_eq!(bucket.take(600, now), Duration::ZERO);
+    //剩余 400 再取 900 欠 500 需要等待半秒
+    assert_eq!(bucket.take(900, now), Duration::from_millis(500));
+    //一秒后补充 1000 个令牌 还清欠款后剩余 500
+    assert_eq!(bucket.take(500, now + Duration::from_secs(1)), Duration::ZERO);
+  }
+}
@@ -0,0 +1,261 @@
+use crate::config::{data_dir, product_dir, ProductConfig};
+use crate::test_watch::module_path;
+use crate::util::now_millis;
+use crate::versions::{self, CURRENT_VERSION};
+use crate::worker_util::{run_tool, tool_flags};
+use deno_core::error::{generic_error, AnyError};
+use serde::{Deserialize, Serialize};
+use service::args::{CliOptions, DenoSubcommand};
+use service::tools::bench;
+use service::tools::test::format_test_error;
+use std::path::PathBuf;
+
+///基准测试 cool.json 中的 bench<br>
+/// 流水线中加入 bench 步骤后作为部署门禁 与对比版本相比出现性能退化时失败
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BenchConfig {
+  pub baseline: Option<String>, //对比的版本 为空时使用最新的版本 即当前部署的版本
+  pub threshold_percent: f64,   //平均耗时增加超过这个比例视为性能退化 默认 10
+}
+
+impl Default for BenchConfig {
+  fn default() -> Self {
+    Self {
+      baseline: None,
+      threshold_percent: 10.0,
+    }
+  }
+}
+
+///一个基准测试的结果 耗时单位为纳秒
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BenchMeasurement {
+  pub module: String, //产品目录下的相对路径
+  pub name: String,
+  pub group: Option<String>,
+  pub n: u64,
+  pub avg: f64,
+  pub min: f64,
+  pub max: f64,
+  pub p75: f64,
+  pub p99: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BenchFailure {
+  pub module: String,
+  pub name: String,
+  pub error: String,
+}
+
+///一次运行的结果 按版本保存
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BenchRun {
+  pub version: String,
+  pub created_at: u64,
+  pub measurements: Vec<BenchMeasurement>,
+  pub failures: Vec<BenchFailure>,
+}
+
+///与对比版本中同一模块同名基准测试的比较 change_percent 为平均耗时的变化
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BenchComparison {
+  pub module: String,
+  pub name: String,
+  pub baseline_avg: f64,
+  pub avg: f64,
+  pub change_percent: f64,
+  pub regression: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BenchReport {
+  pub product_code: String,
+  pub baseline: Option<String>, //没有可对比的结果时为空
+  pub threshold_percent: f64,
+  pub run: BenchRun,
+  pub comparisons: Vec<BenchComparison>,
+  pub regressions: Vec<String>,
+}
+
+///某个版本的结果 data/reports/{product_code}/bench/{version}.json 当前代码的版本为 current
+pub fn result_path(product_code: &str, version: &str) -> PathBuf {
+  let mut path = data_dir();
+  path.push("reports");
+  path.push(product_code);
+  path.push("bench");
+  path.push(format!("{}.json", version));
+  path
+}
+
+pub fn load_run(product_code: &str, version: &str) -> Option<BenchRun> {
+  let bytes = std::fs::read(result_path(product_code, version)).ok()?;
+  serde_json::from_slice(&bytes).ok()
+}
+
+///运行当前代码的基准测试 结果保存为 current
+async fn run_current(product_code: &str) -> Result<BenchRun, AnyError> {
+  let root = product_dir(product_code);
+  let dir = root.to_string_lossy().to_string();
+  let report = run_tool(format!("product-{}-bench", product_code), move || async move {
+    let flags = tool_flags("bench", &dir)?;
+    let bench_flags = match flags.subcommand.clone() {
+      DenoSubcommand::Bench(bench_flags) => bench_flags,
+      _ => unreachable!(),
+    };
+    let cli_options = CliOptions::from_flags(flags)?;
+    let bench_options = cli_options.resolve_bench_options(bench_flags)?;
+    bench::collect_benchmarks(cli_options, bench_options).await
+  })
+  .await?;
+  let measurements = report
+    .measurements
+    .into_iter()
+    .map(|(desc, stats)| BenchMeasurement {
+      module: module_path(&root, &desc.origin),
+      name: desc.name,
+      group: desc.group,
+      n: stats.n,
+      avg: stats.avg,
+      min: stats.min,
+      max: stats.max,
+      p75: stats.p75,
+      p99: stats.p99,
+    })
+    .collect();
+  let failures = report
+    .failures
+    .into_iter()
+    .map(|(desc, error)| BenchFailure {
+      module: module_path(&root, &desc.origin),
+      name: desc.name,
+      error: format_test_error(&error),
+    })
+    .collect();
+  let run = BenchRun {
+    version: CURRENT_VERSION.to_string(),
+    created_at: now_millis(),
+    measurements,
+    failures,
+  };
+  let path = result_path(product_code, CURRENT_VERSION);
+  tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+  tokio::fs::write(&path, serde_json::to_vec_pretty(&run)?).await?;
+  Ok(run)
+}
+
+///按模块和名称对比 只在一边存在的基准测试不比较
+fn compare(run: &BenchRun, baseline: &BenchRun, threshold_percent: f64) -> Vec<BenchComparison> {
+  run
+    .measurements
+    .iter()
+    .filter_map(|m| {
+      let base = baseline.measurements.iter().find(|b| b.module == m.module && b.name == m.name)?;
+      let change_percent = match base.avg > 0.0 {
+        true => (m.avg - base.avg) / base.avg * 100.0,
+        false => 0.0,
+      };
+      Some(BenchComparison {
+        module: m.module.clone(),
+        name: m.name.clone(),
+        baseline_avg: base.avg,
+        avg: m.avg,
+        change_percent,
+        regression: change_percent > threshold_percent,
+      })
+    })
+    .collect()
+}
+
+///运行当前代码的基准测试并与对比版本比较<br>
+/// 指定的版本没有结果时失败 使用默认版本时没有结果则不比较
+pub async fn bench_product(product_code: &str, baseline: Option<String>) -> Result<BenchReport, AnyError> {
+  let config = ProductConfig::load(product_code)?.bench;
+  let baseline = match baseline.or(config.baseline) {
+    Some(version) => match load_run(product_code, &version) {
+      Some(run) => Some(run),
+      None => return Err(generic_error(format!("no bench results for version {}", version))),
+    },
+    None => versions::latest_version(product_code)?.and_then(|v| load_run(product_code, &v)),
+  };
+  let run = run_current(product_code).await?;
+  let comparisons = baseline.as_ref().map(|b| compare(&run, b, config.threshold_percent)).unwrap_or_default();
+  let regressions = comparisons
+    .iter()
+    .filter(|c| c.regression)
+    .map(|c| format!("{} {} is {:.1}% slower", c.module, c.name, c.change_percent))
+    .collect();
+  Ok(BenchReport {
+    product_code: product_code.to_string(),
+    baseline: baseline.map(|b| b.version),
+    threshold_percent: config.threshold_percent,
+    run,
+    comparisons,
+    regressions,
+  })
+}
+
+///流水线的 bench 步骤 基准测试失败或出现性能退化时失败
+pub async fn ensure_no_regression(product_code: &str) -> Result<(), AnyError> {
+  let report = bench_product(product_code, None).await?;
+  if !report.run.failures.is_empty() {
+    let names: Vec<String> = report.run.failures.iter().map(|f| format!("{} {}", f.module, f.name)).collect();
+    return Err(generic_error(format!("benchmarks failed: {}", names.join(", "))));
+  }
+  match report.regressions.is_empty() {
+    true => Ok(()),
+    false => Err(generic_error(report.regressions.join("; "))),
+  }
+}
+
+///部署保存版本后 把流水线中当前代码的结果记为这个版本的结果
+pub async fn keep_for_version(product_code: &str, version: &str) -> Result<(), AnyError> {
+  let Some(run) = load_run(product_code, CURRENT_VERSION) else {
+    return Ok(());
+  };
+  let run = BenchRun {
+    version: version.to_string(),
+    ..run
+  };
+  tokio::fs::write(result_path(product_code, version), serde_json::to_vec_pretty(&run)?).await?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn measurement(name: &str, avg: f64) -> BenchMeasurement {
+    BenchMeasurement {
+      module: "bench/cart_bench.ts".to_string(),
+      name: name.to_string(),
+      group: None,
+      n: 100,
+      avg,
+      min: avg,
+      max: avg,
+      p75: avg,
+      p99: avg,
+    }
+  }
+
+  #[test]
+  fn flags_regressions_over_threshold() {
+    let run = |version: &str, measurements| BenchRun {
+      version: version.to_string(),
+      created_at: 0,
+      measurements,
+      failures: vec![],
+    };
+    let baseline = run("1", vec![measurement("add", 100.0), measurement("total", 200.0)]);
+    let current = run(
+      CURRENT_VERSION,
+      vec![measurement("add", 125.0), measurement("total", 210.0), measurement("remove", 50.0)],
+    );
+    let comparisons = compare(&current, &baseline, 10.0);
+    assert_eq!(comparisons.len(), 2);
+    assert_eq!((comparisons[0].change_percent, comparisons[0].regression), (25.0, true));
+    assert_eq!((comparisons[1].change_percent, comparisons[1].regression), (5.0, false));
+  }
+}
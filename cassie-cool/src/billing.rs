@@ -0,0 +1,239 @@
+use crate::artifacts;
+use crate::config::product_dir;
+use crate::usage;
+use crate::util::now_millis;
+use crate::versions::versions_dir;
+use chrono::{Duration, NaiveDate};
+use deno_core::error::{generic_error, AnyError};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Mutex;
+
+///单次导出最多包含的天数
+const MAX_EXPORT_DAYS: i64 = 366;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+  Csv,
+  Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+  Daily,
+  Monthly,
+}
+
+///导出参数 日期为 YYYY-MM-DD 包含首尾两天
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportRequest {
+  pub start: String,
+  pub end: String,
+  #[serde(default = "default_granularity")]
+  pub granularity: Granularity,
+  #[serde(default = "default_format")]
+  pub format: ExportFormat,
+  #[serde(default)]
+  pub products: Option<Vec<String>>, //不传时导出所有有用量的产品
+}
+
+fn default_granularity() -> Granularity {
+  Granularity::Monthly
+}
+
+fn default_format() -> ExportFormat {
+  ExportFormat::Csv
+}
+
+///导出中的一行 一个产品一个周期
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageRow {
+  pub product_code: String,
+  pub period: String, //按天为 YYYY-MM-DD 按月为 YYYY-MM
+  pub requests: u64,
+  pub cpu_seconds: f64, //按采样估算
+  pub egress_bytes: u64,
+  pub storage_bytes: u64, //导出时产品代码和历史版本占用的空间
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportStatus {
+  Pending,
+  Done,
+  Failed,
+}
+
+///导出任务 完成后 download 为下载链接
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportJob {
+  pub id: String,
+  pub request: ExportRequest,
+  pub status: ExportStatus,
+  pub created_at: u64,
+  pub finished_at: Option<u64>,
+  pub download: Option<String>,
+  pub error: Option<String>,
+}
+
+lazy_static! {
+  static ref EXPORT_JOBS: Mutex<HashMap<String, ExportJob>> = Mutex::new(HashMap::new());
+}
+
+fn parse_date(date: &str) -> Result<NaiveDate, AnyError> {
+  NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| generic_error(format!("invalid date {}, expected YYYY-MM-DD", date)))
+}
+
+fn dir_size(dir: &Path) -> u64 {
+  walkdir::WalkDir::new(dir)
+    .into_iter()
+    .filter_map(|e| e.ok())
+    .filter_map(|e| e.metadata().ok())
+    .filter(|m| m.is_file())
+    .map(|m| m.len())
+    .sum()
+}
+
+///汇总用量 每个产品每个周期一行
+pub fn collect_rows(request: &ExportRequest) -> Result<Vec<UsageRow>, AnyError> {
+  let start = parse_date(&request.start)?;
+  let end = parse_date(&request.end)?;
+  if end < start {
+    return Err(generic_error("end is before start"));
+  }
+  if (end - start).num_days() >= MAX_EXPORT_DAYS {
+    return Err(generic_error(format!("at most {} days can be exported at once", MAX_EXPORT_DAYS)));
+  }
+  let products = match &request.products {
+    Some(products) => products.clone(),
+    None => usage::products()?,
+  };
+  let mut rows = vec![];
+  for product_code in products {
+    let storage_bytes = dir_size(&product_dir(&product_code)) + dir_size(&versions_dir(&product_code));
+    let mut periods: BTreeMap<String, UsageRow> = BTreeMap::new();
+    let mut day = start;
+    while day <= end {
+      let date = day.format("%Y-%m-%d").to_string();
+      let period = match request.granularity {
+        Granularity::Daily => date.clone(),
+        Granularity::Monthly => date[..7].to_string(),
+      };
+      let daily = usage::daily_usage(&product_code, &date)?;
+      let row = periods.entry(period.clone()).or_insert_with(|| UsageRow {
+        product_code: product_code.clone(),
+        period,
+        requests: 0,
+        cpu_seconds: 0.0,
+        egress_bytes: 0,
+        storage_bytes,
+      });
+      for endpoint in daily.endpoints.values() {
+        row.requests += endpoint.requests;
+        row.cpu_seconds += endpoint.estimated_cpu_secs();
+      }
+      row.egress_bytes += daily.egress_bytes;
+      day += Duration::days(1);
+    }
+    rows.extend(periods.into_values());
+  }
+  Ok(rows)
+}
+
+fn csv_field(value: &str) -> String {
+  match value.contains([',', '"', '\n']) {
+    true => format!("\"{}\"", value.replace('"', "\"\"")),
+    false => value.to_string(),
+  }
+}
+
+pub fn to_csv(rows: &[UsageRow]) -> String {
+  let mut out = String::from("product_code,period,requests,cpu_seconds,egress_bytes,storage_bytes\n");
+  for row in rows {
+    out.push_str(&format!(
+      "{},{},{},{:.3},{},{}\n",
+      csv_field(&row.product_code),
+      row.period,
+      row.requests,
+      row.cpu_seconds,
+      row.egress_bytes,
+      row.storage_bytes
+    ));
+  }
+  out
+}
+
+async fn run_export(id: &str, request: &ExportRequest) -> Result<String, AnyError> {
+  //先把内存中的用量写入磁盘 保证当天的数据也包含在内
+  usage::flush()?;
+  let rows = collect_rows(request)?;
+  let (name, bytes) = match request.format {
+    ExportFormat::Csv => (format!("{}.csv", id), to_csv(&rows).into_bytes()),
+    ExportFormat::Json => (format!("{}.json", id), serde_json::to_vec_pretty(&rows)?),
+  };
+  artifacts::put_artifact("usage", &name, bytes).await
+}
+
+///开始一次导出 在后台生成文件 通过 export_job 查询进度
+pub fn start_export(request: ExportRequest) -> Result<ExportJob, AnyError> {
+  //参数错误直接返回 不创建任务
+  parse_date(&request.start)?;
+  parse_date(&request.end)?;
+  let job = ExportJob {
+    id: uuid::Uuid::new_v4().to_string(),
+    request,
+    status: ExportStatus::Pending,
+    created_at: now_millis(),
+    finished_at: None,
+    download: None,
+    error: None,
+  };
+  EXPORT_JOBS.lock().unwrap().insert(job.id.clone(), job.clone());
+  let (id, request) = (job.id.clone(), job.request.clone());
+  tokio::spawn(async move {
+    let result = run_export(&id, &request).await;
+    if let Some(job) = EXPORT_JOBS.lock().unwrap().get_mut(&id) {
+      job.finished_at = Some(now_millis());
+      match result {
+        Ok(download) => {
+          job.status = ExportStatus::Done;
+          job.download = Some(download);
+        }
+        Err(err) => {
+          job.status = ExportStatus::Failed;
+          job.error = Some(err.to_string());
+        }
+      }
+    }
+  });
+  Ok(job)
+}
+
+pub fn export_job(id: &str) -> Option<ExportJob> {
+  EXPORT_JOBS.lock().unwrap().get(id).cloned()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn writes_csv_with_escaping() {
+    let rows = vec![UsageRow {
+      product_code: "a,b".to_string(),
+      period: "2023-06".to_string(),
+      requests: 10,
+      cpu_seconds: 1.5,
+      egress_bytes: 2048,
+      storage_bytes: 100,
+    }];
+    assert_eq!(
+      to_csv(&rows),
+      "product_code,period,requests,cpu_seconds,egress_bytes,storage_bytes\n\"a,b\",2023-06,10,1.500,2048,100\n"
+    );
+  }
+}
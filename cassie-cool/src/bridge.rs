@@ -0,0 +1,466 @@
+use crate::amqp::AmqpSession;
+use crate::catalog;
+use crate::config::{data_dir, ProductConfig};
+use crate::list_query::ListSpec;
+use crate::mqtt::MqttSession;
+use crate::upstream::{self, Conn};
+use crate::util::now_millis;
+use crate::worker_util::{self, ScriptWorkerId, WorkerPort};
+use crate::{metrics, offline, secrets};
+use deno_core::error::{generic_error, AnyError};
+use deno_runtime::deno_fetch::reqwest;
+use lazy_static::lazy_static;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use url::Url;
+
+///按配置启动和停止订阅的间隔
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+///连接断开后重连的间隔
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+///产品没有可用的 runtime 时等待的间隔 不计入重试次数
+const UNAVAILABLE_DELAY: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MQTT_KEEP_ALIVE: Duration = Duration::from_secs(60);
+///死信文件名
+const DEAD_LETTERS: &str = "dead_letters.jsonl";
+
+///消息桥接 cool.json 中的 bridges<br>
+/// 网关订阅 MQTT topic 或 AMQP 队列 每条消息以 POST 调用产品的 handler 2xx 之后才向 broker 确认 至少投递一次<br>
+/// 失败时按 retry_delay_ms 指数退避重试 超过 max_attempts 写入死信后确认 产品没有运行中的 runtime 时等待 不消费新消息
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BridgeSubscription {
+  pub name: String,
+  pub url: String, //mqtt:// mqtts:// amqp:// amqps:// AMQP 的路径为 vhost
+  #[serde(default)]
+  pub secret: Option<String>, //凭据所在的产品密钥 格式为 用户名:密码 只通过 tls 或本机连接发送
+  pub source: String, //MQTT 的 topic filter 或 AMQP 的队列名
+  pub handler: String, //处理消息的路径
+  #[serde(default = "default_max_attempts")]
+  pub max_attempts: u32,
+  #[serde(default = "default_retry_delay_ms")]
+  pub retry_delay_ms: u64, //第一次重试前等待的时间 之后每次翻倍
+  #[serde(default = "default_timeout_ms")]
+  pub timeout_ms: u64, //handler 的超时
+  #[serde(default = "default_prefetch")]
+  pub prefetch: u16, //AMQP 未确认消息的上限
+}
+
+fn default_max_attempts() -> u32 {
+  5
+}
+
+fn default_retry_delay_ms() -> u64 {
+  1000
+}
+
+fn default_timeout_ms() -> u64 {
+  30_000
+}
+
+fn default_prefetch() -> u16 {
+  10
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BridgeProtocol {
+  Mqtt,
+  Amqp,
+}
+
+///订阅的运行状态
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BridgeStatus {
+  pub name: String,
+  pub protocol: Option<BridgeProtocol>,
+  pub connected: bool,
+  pub delivered: u64,             //handler 处理成功的消息
+  pub retried: u64,               //重试的次数
+  pub dead_lettered: u64,         //写入死信的消息
+  pub last_ack: Option<String>,   //最后确认的消息 MQTT 的 packet id 或 AMQP 的 delivery tag
+  pub last_error: Option<String>, //最近一次连接或投递失败的原因
+  pub updated_at: u64,
+}
+
+///超过重试次数的消息 payload 为 base64
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+  pub id: String,
+  pub subscription: String,
+  pub topic: String,
+  pub content_type: Option<String>,
+  pub payload: String,
+  pub attempts: u32,
+  pub error: String,
+  pub created_at: u64,
+}
+
+///死信列表的排序和过滤 status 按订阅名过滤
+pub const LIST_SPEC: ListSpec = ListSpec {
+  id: "id",
+  sort: &["created_at", "subscription"],
+  default_sort: "-created_at",
+  status: Some("subscription"),
+  tag: None,
+  owner: None,
+};
+
+///从 broker 收到 还没有确认的消息
+struct Message {
+  ack: Option<u64>, //确认时使用的 id MQTT QoS 0 的消息不需要确认
+  topic: String,
+  content_type: Option<String>,
+  payload: Vec<u8>,
+  redelivered: bool,
+}
+
+enum Consumer {
+  Mqtt(MqttSession),
+  Amqp(AmqpSession),
+}
+
+enum Delivery {
+  Done,
+  Failed(String),
+  Unavailable,
+}
+
+lazy_static! {
+  //(产品, 订阅名) -> (配置, 消费任务)
+  static ref TASKS: Mutex<HashMap<(String, String), (BridgeSubscription, JoinHandle<()>)>> = Mutex::new(HashMap::new());
+  static ref STATUS: Mutex<HashMap<(String, String), BridgeStatus>> = Mutex::new(HashMap::new());
+}
+
+pub fn bridge_dir(product_code: &str) -> PathBuf {
+  data_dir().join("bridge").join(product_code)
+}
+
+fn protocol(url: &Url) -> Result<(BridgeProtocol, bool, u16), AnyError> {
+  match url.scheme() {
+    "mqtt" => Ok((BridgeProtocol::Mqtt, false, 1883)),
+    "mqtts" => Ok((BridgeProtocol::Mqtt, true, 8883)),
+    "amqp" => Ok((BridgeProtocol::Amqp, false, 5672)),
+    "amqps" => Ok((BridgeProtocol::Amqp, true, 5671)),
+    scheme => Err(generic_error(format!("unsupported bridge scheme {}", scheme))),
+  }
+}
+
+///AMQP 的 vhost 路径为空时是 /
+fn vhost(url: &Url) -> String {
+  let path = url.path().trim_start_matches('/');
+  match path {
+    "" => "/".to_string(),
+    path => percent_decode_str(path).decode_utf8_lossy().to_string(),
+  }
+}
+
+///第 attempt 次失败后等待的时间
+fn backoff(retry_delay_ms: u64, attempt: u32) -> Duration {
+  Duration::from_millis(retry_delay_ms.saturating_mul(1u64 << attempt.saturating_sub(1).min(16)))
+}
+
+fn update(product_code: &str, name: &str, f: impl FnOnce(&mut BridgeStatus)) {
+  let mut status = STATUS.lock().unwrap();
+  let entry = status
+    .entry((product_code.to_string(), name.to_string()))
+    .or_insert_with(|| BridgeStatus {
+      name: name.to_string(),
+      protocol: None,
+      connected: false,
+      delivered: 0,
+      retried: 0,
+      dead_lettered: 0,
+      last_ack: None,
+      last_error: None,
+      updated_at: 0,
+    });
+  f(entry);
+  entry.updated_at = now_millis();
+}
+
+fn count(product_code: &str, subscription: &str, outcome: &str) {
+  metrics::inc_counter(
+    "bridge_messages_total",
+    "Messages consumed by the bridge by outcome",
+    &[("product", product_code), ("subscription", subscription), ("outcome", outcome)],
+    1,
+  );
+}
+
+impl Consumer {
+  async fn connect(product_code: &str, sub: &BridgeSubscription) -> Result<Self, AnyError> {
+    let url = Url::parse(&sub.url)?;
+    let (protocol, tls, default_port) = protocol(&url)?;
+    let host = url.host_str().ok_or_else(|| generic_error("bridge url has no host"))?.to_string();
+    let port = url.port().unwrap_or(default_port);
+    if !upstream::allowed(&offline::net_allowlist(product_code), &host, port) {
+      return Err(generic_error(format!("{}:{} is not allowed in offline mode", host, port)));
+    }
+    let tcp = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host.as_str(), port)))
+      .await
+      .map_err(|_| generic_error("connect timed out"))??;
+    let loopback = tcp.peer_addr().map(|a| a.ip().is_loopback()).unwrap_or(false);
+    let conn: Box<dyn Conn> = match tls {
+      true => upstream::tls_connect(&host, tcp).await?,
+      false => Box::new(tcp),
+    };
+    let secret = match sub.secret.as_deref() {
+      Some(name) => secrets::active_values(product_code, name)?.into_iter().next(),
+      None => None,
+    };
+    if secret.is_some() && !tls && !loopback {
+      return Err(generic_error("credentials are only sent over tls"));
+    }
+    let credentials = secret.as_deref().and_then(|s| s.split_once(':'));
+    update(product_code, &sub.name, |s| s.protocol = Some(protocol));
+    match protocol {
+      BridgeProtocol::Mqtt => {
+        //持久会话的 client id 固定 重连后 broker 重发没有确认的消息
+        let client_id = format!("cool-{}-{}", product_code, sub.name);
+        let mut session = MqttSession::connect(conn, &client_id, credentials, MQTT_KEEP_ALIVE).await?;
+        session.subscribe(&sub.source).await?;
+        Ok(Self::Mqtt(session))
+      }
+      BridgeProtocol::Amqp => {
+        let (username, password) = credentials.unwrap_or(("guest", "guest"));
+        let mut session = AmqpSession::connect(conn, &vhost(&url), username, password).await?;
+        session.consume(&sub.source, sub.prefetch).await?;
+        Ok(Self::Amqp(session))
+      }
+    }
+  }
+
+  async fn next(&mut self) -> Result<Message, AnyError> {
+    match self {
+      Self::Mqtt(session) => {
+        let publish = session.next().await?;
+        Ok(Message {
+          ack: publish.packet_id.map(|id| id as u64),
+          topic: publish.topic,
+          content_type: None,
+          payload: publish.payload,
+          redelivered: publish.dup,
+        })
+      }
+      Self::Amqp(session) => {
+        let delivery = session.next().await?;
+        Ok(Message {
+          ack: Some(delivery.delivery_tag),
+          topic: delivery.routing_key,
+          content_type: delivery.content_type,
+          payload: delivery.body,
+          redelivered: delivery.redelivered,
+        })
+      }
+    }
+  }
+
+  async fn ack(&mut self, id: u64) -> Result<(), AnyError> {
+    match self {
+      Self::Mqtt(session) => session.ack(id as u16).await,
+      Self::Amqp(session) => session.ack(id).await,
+    }
+  }
+}
+
+///调用一次 handler 经过负载均衡 和普通请求一样避开排空和不健康的端口
+async fn deliver(client: &reqwest::Client, product_code: &str, sub: &BridgeSubscription, message: &Message, attempt: u32) -> Delivery {
+  let balance = ProductConfig::load(product_code).map(|c| c.balance).unwrap_or_default();
+  let Ok(lease) = worker_util::lease_port(&ScriptWorkerId(product_code.to_string()), balance) else {
+    return Delivery::Unavailable;
+  };
+  let WorkerPort(port) = lease.0;
+  let url = format!("http://127.0.0.1:{}/{}", port, sub.handler.trim_start_matches('/'));
+  let req = client
+    .post(url)
+    .timeout(Duration::from_millis(sub.timeout_ms))
+    .header("content-type", message.content_type.as_deref().unwrap_or("application/octet-stream"))
+    .header("x-bridge-subscription", sub.name.as_str())
+    .header("x-bridge-topic", message.topic.as_str())
+    .header("x-bridge-attempt", attempt.to_string())
+    .header("x-bridge-redelivered", message.redelivered.to_string())
+    .body(message.payload.clone());
+  match req.send().await {
+    Ok(res) if res.status().is_success() => Delivery::Done,
+    Ok(res) => Delivery::Failed(format!("handler responded {}", res.status())),
+    Err(err) => Delivery::Failed(err.to_string()),
+  }
+}
+
+///追加到产品的死信文件 写入成功后消息才会被确认
+fn dead_letter(product_code: &str, sub: &BridgeSubscription, message: &Message, attempts: u32, error: &str) -> Result<(), AnyError> {
+  let letter = DeadLetter {
+    id: uuid::Uuid::new_v4().to_string(),
+    subscription: sub.name.clone(),
+    topic: message.topic.clone(),
+    content_type: message.content_type.clone(),
+    payload: base64::encode(&message.payload),
+    attempts,
+    error: error.to_string(),
+    created_at: now_millis(),
+  };
+  let dir = bridge_dir(product_code);
+  std::fs::create_dir_all(&dir)?;
+  let mut file = std::fs::OpenOptions::new().create(true).append(true).open(dir.join(DEAD_LETTERS))?;
+  writeln!(file, "{}", serde_json::to_string(&letter)?)?;
+  file.sync_data()?;
+  Ok(())
+}
+
+///投递一条消息直到成功或写入死信
+async fn handle(client: &reqwest::Client, product_code: &str, sub: &BridgeSubscription, message: &Message) -> Result<(), AnyError> {
+  let mut attempt = 1;
+  loop {
+    match deliver(client, product_code, sub, message, attempt).await {
+      Delivery::Done => {
+        count(product_code, &sub.name, "delivered");
+        update(product_code, &sub.name, |s| s.delivered += 1);
+        return Ok(());
+      }
+      Delivery::Unavailable => tokio::time::sleep(UNAVAILABLE_DELAY).await,
+      Delivery::Failed(err) if attempt < sub.max_attempts => {
+        count(product_code, &sub.name, "retried");
+        update(product_code, &sub.name, |s| {
+          s.retried += 1;
+          s.last_error = Some(err.clone());
+        });
+        tokio::time::sleep(backoff(sub.retry_delay_ms, attempt)).await;
+        attempt += 1;
+      }
+      Delivery::Failed(err) => {
+        dead_letter(product_code, sub, message, attempt, &err)?;
+        count(product_code, &sub.name, "dead_lettered");
+        update(product_code, &sub.name, |s| {
+          s.dead_lettered += 1;
+          s.last_error = Some(err);
+        });
+        return Ok(());
+      }
+    }
+  }
+}
+
+async fn consume(product_code: &str, sub: &BridgeSubscription) -> Result<(), AnyError> {
+  let client = reqwest::Client::builder().build()?;
+  let mut consumer = Consumer::connect(product_code, sub).await?;
+  update(product_code, &sub.name, |s| s.connected = true);
+  loop {
+    let message = consumer.next().await?;
+    handle(&client, product_code, sub, &message).await?;
+    if let Some(id) = message.ack {
+      consumer.ack(id).await?;
+      update(product_code, &sub.name, |s| s.last_ack = Some(id.to_string()));
+    }
+  }
+}
+
+///一个订阅的消费任务 连接断开后重连
+async fn run(product_code: String, sub: BridgeSubscription) {
+  loop {
+    if let Err(err) = consume(&product_code, &sub).await {
+      log::warn!("bridge {} of {} disconnected: {}", sub.name, product_code, err);
+      update(&product_code, &sub.name, |s| {
+        s.connected = false;
+        s.last_error = Some(err.to_string());
+      });
+    }
+    tokio::time::sleep(RECONNECT_DELAY).await;
+  }
+}
+
+///所有产品声明的订阅
+fn wanted() -> Vec<(String, BridgeSubscription)> {
+  let products = catalog::product_codes().unwrap_or_default();
+  products
+    .into_iter()
+    .flat_map(|product_code| {
+      let bridges = ProductConfig::load(&product_code).map(|c| c.bridges).unwrap_or_default();
+      bridges.into_iter().map(move |sub| (product_code.clone(), sub))
+    })
+    .collect()
+}
+
+///按配置启动新的订阅 停止删除或修改了的订阅 停止时没有确认的消息由 broker 重发
+fn reconcile(wanted: Vec<(String, BridgeSubscription)>) {
+  let mut tasks = TASKS.lock().unwrap();
+  let keys: Vec<(String, String)> = wanted.iter().map(|(p, s)| (p.clone(), s.name.clone())).collect();
+  tasks.retain(|key, (sub, handle)| {
+    let keep = wanted.iter().any(|(p, s)| *p == key.0 && s == sub) && !handle.is_finished();
+    if !keep {
+      handle.abort();
+    }
+    keep
+  });
+  STATUS.lock().unwrap().retain(|key, _| keys.contains(key));
+  for (product_code, sub) in wanted {
+    let key = (product_code.clone(), sub.name.clone());
+    if tasks.contains_key(&key) {
+      continue;
+    }
+    let handle = tokio::spawn(run(product_code, sub.clone()));
+    tasks.insert(key, (sub, handle));
+  }
+}
+
+pub fn status(product_code: &str) -> Vec<BridgeStatus> {
+  let status = STATUS.lock().unwrap();
+  let mut list: Vec<BridgeStatus> = status.iter().filter(|(key, _)| key.0 == product_code).map(|(_, s)| s.clone()).collect();
+  list.sort_by(|a, b| a.name.cmp(&b.name));
+  list
+}
+
+///产品的死信 无法解析的行跳过
+pub fn dead_letters(product_code: &str) -> Result<Vec<DeadLetter>, AnyError> {
+  let file = match std::fs::File::open(bridge_dir(product_code).join(DEAD_LETTERS)) {
+    Ok(file) => file,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+    Err(err) => return Err(err.into()),
+  };
+  let mut letters = vec![];
+  for line in BufReader::new(file).lines() {
+    if let Ok(letter) = serde_json::from_str::<DeadLetter>(&line?) {
+      letters.push(letter);
+    }
+  }
+  Ok(letters)
+}
+
+pub fn start() {
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+    loop {
+      interval.tick().await;
+      if let Ok(wanted) = tokio::task::spawn_blocking(wanted).await {
+        reconcile(wanted);
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn parses_broker_urls_and_backs_off() {
+    let url = Url::parse("amqps://broker.internal/%2Fprod").unwrap();
+    assert_eq!(protocol(&url).unwrap(), (BridgeProtocol::Amqp, true, 5671));
+    assert_eq!(vhost(&url), "/prod");
+    assert_eq!(vhost(&Url::parse("amqp://localhost:5673").unwrap()), "/");
+    assert_eq!(
+      protocol(&Url::parse("mqtt://localhost").unwrap()).unwrap(),
+      (BridgeProtocol::Mqtt, false, 1883)
+    );
+    assert!(protocol(&Url::parse("kafka://localhost").unwrap()).is_err());
+    assert_eq!(backoff(1000, 1), Duration::from_secs(1));
+    assert_eq!(backoff(1000, 4), Duration::from_secs(8));
+  }
+}
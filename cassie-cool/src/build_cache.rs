@@ -0,0 +1,89 @@
+use crate::config::{data_dir, module_pins_path, product_dir};
+use crate::pipeline::{Step, StepStatus};
+use crate::shared::list_shared_modules;
+use crate::util::{now_millis, read_json, write_json};
+use deno_core::error::AnyError;
+use deno_runtime::at_rest;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use walkdir::WalkDir;
+
+///步骤结果是否来自缓存
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheStatus {
+  Hit,
+  Miss,
+}
+
+///缓存的步骤结果 只缓存通过的步骤 失败的步骤每次重新执行
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CachedStep {
+  pub status: Option<StepStatus>,
+  pub message: Option<String>,
+  pub product_code: String, //第一次执行这个步骤的产品 内容相同的产品共用缓存
+  pub created_at: u64,
+}
+
+///可以缓存的步骤 漏洞审计依赖不断更新的漏洞库 基准测试要和对比版本比较 每次都要执行
+pub fn cacheable(step: Step) -> bool {
+  !matches!(step, Step::Audit | Step::Bench)
+}
+
+///缓存文件 data/build-cache/{step}/{key}.json
+fn entry_path(step: Step, key: &str) -> PathBuf {
+  data_dir()
+    .join("build-cache")
+    .join(format!("{:?}", step).to_lowercase())
+    .join(format!("{}.json", key))
+}
+
+///步骤输入的 hash 包括产品的所有文件 cool.json 在其中 远程模块锁定表 共享模块版本和工具链版本<br>
+/// 文件按解密后的内容计算 重新加密不会让缓存失效
+pub fn input_key(product_code: &str) -> Result<String, AnyError> {
+  let mut ctx = digest::Context::new(&digest::SHA256);
+  ctx.update(service::version::deno().as_bytes());
+  ctx.update(env!("CARGO_PKG_VERSION").as_bytes());
+  let root = product_dir(product_code);
+  let mut files: Vec<PathBuf> = WalkDir::new(&root)
+    .into_iter()
+    .filter_map(|e| e.ok())
+    .filter(|e| e.file_type().is_file())
+    .map(|e| e.into_path())
+    .collect();
+  files.sort();
+  for path in files {
+    let relative = path.strip_prefix(&root).unwrap().to_string_lossy().replace('\\', "/");
+    let content = at_rest::decrypt(std::fs::read(&path)?)?;
+    ctx.update(relative.as_bytes());
+    ctx.update(&(content.len() as u64).to_be_bytes());
+    ctx.update(&content);
+  }
+  match std::fs::read(module_pins_path(product_code)) {
+    Ok(pins) => ctx.update(&pins),
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+    Err(err) => return Err(err.into()),
+  }
+  //不带版本号引用的共享模块解析到最新版本
+  ctx.update(&serde_json::to_vec(&list_shared_modules()?)?);
+  Ok(hex::encode(ctx.finish()))
+}
+
+pub fn lookup(step: Step, key: &str) -> Option<CachedStep> {
+  let path = entry_path(step, key);
+  if !path.exists() {
+    return None;
+  }
+  read_json::<CachedStep>(path).ok().filter(|c| c.status == Some(StepStatus::Passed))
+}
+
+pub fn store(step: Step, key: &str, product_code: &str, message: Option<String>) -> Result<(), AnyError> {
+  let cached = CachedStep {
+    status: Some(StepStatus::Passed),
+    message,
+    product_code: product_code.to_string(),
+    created_at: now_millis(),
+  };
+  write_json(entry_path(step, key), &cached)
+}
@@ -0,0 +1,260 @@
+use crate::catalog;
+use crate::config::ProductConfig;
+use crate::deploy::{self, DeployRecord, DeployStatus};
+use deno_core::error::{custom_error, generic_error, AnyError};
+use futures_util::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::future::Future;
+
+///一次批量操作的产品数上限
+const MAX_PRODUCTS: usize = 100;
+const DEFAULT_CONCURRENCY: usize = 4;
+const MAX_CONCURRENCY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkOperation {
+  Start,   //启动生产 runtime 先执行部署门禁
+  Stop,    //停止所有生产 runtime
+  Restart, //启动新 runtime 后停止旧的 不中断服务
+  Deploy,  //执行流水线并部署
+}
+
+///批量操作请求<br>
+/// atomic 只用于部署 任一产品流水线或冒烟测试失败时所有产品都不切换或回滚到上一个版本
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BulkRequest {
+  pub operation: BulkOperation,
+  pub products: Vec<String>,
+  #[serde(default)]
+  pub atomic: bool,
+  #[serde(default)]
+  pub concurrency: Option<usize>, //同时执行的产品数 默认 4
+}
+
+///单个产品的结果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BulkItem {
+  pub product_code: String,
+  pub success: bool,
+  pub error: Option<String>,
+  pub deploy: Option<DeployRecord>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BulkReport {
+  pub operation: BulkOperation,
+  pub atomic: bool,
+  pub success: bool, //所有产品都成功
+  pub items: Vec<BulkItem>,
+}
+
+impl BulkItem {
+  fn ok(product_code: &str) -> Self {
+    Self {
+      product_code: product_code.to_string(),
+      success: true,
+      error: None,
+      deploy: None,
+    }
+  }
+
+  fn failed(product_code: &str, error: impl Into<String>) -> Self {
+    Self {
+      product_code: product_code.to_string(),
+      success: false,
+      error: Some(error.into()),
+      deploy: None,
+    }
+  }
+
+  fn deployed(record: DeployRecord) -> Self {
+    let error = match record.status {
+      DeployStatus::Deployed => None,
+      DeployStatus::Blocked => Some("pipeline failed".to_string()),
+      DeployStatus::RolledBack => Some("rolled back to the previous version".to_string()),
+      DeployStatus::Aborted => Some("aborted, another product failed".to_string()),
+    };
+    Self {
+      product_code: record.product_code.clone(),
+      success: error.is_none(),
+      error,
+      deploy: Some(record),
+    }
+  }
+}
+
+fn validate(req: &BulkRequest) -> Result<(), AnyError> {
+  if req.products.is_empty() {
+    return Err(generic_error("no products given"));
+  }
+  if req.products.len() > MAX_PRODUCTS {
+    return Err(generic_error(format!("at most {} products per request", MAX_PRODUCTS)));
+  }
+  if req.atomic && req.operation != BulkOperation::Deploy {
+    return Err(generic_error("atomic is only supported for deploy"));
+  }
+  let mut seen = HashSet::new();
+  if let Some(duplicate) = req.products.iter().find(|p| !seen.insert(p.as_str())) {
+    return Err(generic_error(format!("product {} is listed twice", duplicate)));
+  }
+  let existing = catalog::product_codes()?;
+  if let Some(missing) = req.products.iter().find(|p| !existing.contains(p)) {
+    return Err(custom_error("NotFound", format!("product {} not found", missing)));
+  }
+  Ok(())
+}
+
+///按请求的顺序返回结果 同时最多执行 concurrency 个
+async fn each<T, F, Fut>(products: &[String], concurrency: usize, f: F) -> Vec<T>
+where
+  F: Fn(String) -> Fut,
+  Fut: Future<Output = T>,
+{
+  let mut results: Vec<(usize, T)> = stream::iter(products.iter().cloned().enumerate())
+    .map(|(i, product_code)| {
+      let fut = f(product_code);
+      async move { (i, fut.await) }
+    })
+    .buffer_unordered(concurrency)
+    .collect()
+    .await;
+  results.sort_by_key(|(i, _)| *i);
+  results.into_iter().map(|(_, result)| result).collect()
+}
+
+async fn run_one(operation: BulkOperation, product_code: String, author: Option<String>) -> BulkItem {
+  let result = match operation {
+    BulkOperation::Start => match deploy::ensure_deployable(&product_code).await {
+      Ok(_) => {
+        deploy::start_product_runtime(&product_code).await;
+        Ok(BulkItem::ok(&product_code))
+      }
+      Err(err) => Err(err),
+    },
+    BulkOperation::Stop => match deploy::stop_product_runtime(&product_code).await {
+      0 => Ok(BulkItem::failed(&product_code, "not running")),
+      _ => Ok(BulkItem::ok(&product_code)),
+    },
+    BulkOperation::Restart => {
+      deploy::swap_product_runtime(&product_code).await;
+      Ok(BulkItem::ok(&product_code))
+    }
+    BulkOperation::Deploy => deploy::deploy_product(&product_code, author).await.map(BulkItem::deployed),
+  };
+  result.unwrap_or_else(|err| BulkItem::failed(&product_code, err.to_string()))
+}
+
+///全部通过流水线后才切换 切换或冒烟测试失败时已切换的产品全部回滚<br>
+/// 切换按请求顺序依次进行 没有上一个版本的产品无法回滚
+async fn deploy_atomic(products: &[String], concurrency: usize, author: Option<String>) -> Result<Vec<BulkItem>, AnyError> {
+  let prepared = each(products, concurrency, |product_code| {
+    let author = author.clone();
+    async move { deploy::prepare(&product_code, author).await }
+  })
+  .await;
+  let ready = prepared.iter().all(|r| matches!(r, Ok((_, record)) if record.pipeline.success));
+  if !ready {
+    let mut items = vec![];
+    for (product_code, result) in products.iter().zip(prepared) {
+      match result {
+        Ok((_, mut record)) => {
+          if record.pipeline.success {
+            record.status = DeployStatus::Aborted;
+          }
+          deploy::append_history(&record).await?;
+          items.push(BulkItem::deployed(record));
+        }
+        Err(err) => items.push(BulkItem::failed(product_code, err.to_string())),
+      }
+    }
+    return Ok(items);
+  }
+  let mut staged: Vec<(ProductConfig, DeployRecord)> = prepared.into_iter().flatten().collect();
+
+  let mut switched = 0;
+  let mut switch_error = None;
+  for (_, record) in staged.iter_mut() {
+    match deploy::switch(record).await {
+      Ok(_) => switched += 1,
+      Err(err) => {
+        switch_error = Some((record.product_code.clone(), err.to_string()));
+        break;
+      }
+    }
+  }
+  let mut failed = switch_error.is_some();
+  if !failed {
+    let results = stream::iter(staged.iter_mut())
+      .map(|(config, record)| deploy::verify(config, record))
+      .buffer_unordered(concurrency)
+      .collect::<Vec<bool>>()
+      .await;
+    failed = results.contains(&false);
+  }
+  if failed {
+    log::warn!("bulk deploy failed, rolling back {} products", switched);
+    for (_, record) in staged.iter_mut().take(switched) {
+      deploy::rollback(record).await?;
+    }
+    for (_, record) in staged.iter_mut().skip(switched) {
+      record.status = DeployStatus::Aborted;
+    }
+  }
+  let mut items = vec![];
+  for (_, record) in staged {
+    deploy::append_history(&record).await?;
+    let mut item = BulkItem::deployed(record);
+    match &switch_error {
+      Some((product_code, error)) if *product_code == item.product_code => item.error = Some(error.clone()),
+      _ if failed && item.success => item.error = Some("no previous version to roll back to".to_string()),
+      _ => {}
+    }
+    item.success = item.error.is_none();
+    items.push(item);
+  }
+  Ok(items)
+}
+
+///批量启动 停止 重启或部署产品 每个产品的结果单独返回
+pub async fn run(req: BulkRequest, author: Option<String>) -> Result<BulkReport, AnyError> {
+  validate(&req)?;
+  let concurrency = req.concurrency.unwrap_or(DEFAULT_CONCURRENCY).clamp(1, MAX_CONCURRENCY);
+  let items = match req.atomic {
+    true => deploy_atomic(&req.products, concurrency, author).await?,
+    false => {
+      each(&req.products, concurrency, |product_code| {
+        run_one(req.operation, product_code, author.clone())
+      })
+      .await
+    }
+  };
+  Ok(BulkReport {
+    operation: req.operation,
+    atomic: req.atomic,
+    success: items.iter().all(|i| i.success),
+    items,
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn rejects_invalid_requests() {
+    let mut req = BulkRequest {
+      operation: BulkOperation::Start,
+      products: vec!["shop".to_string()],
+      atomic: true,
+      concurrency: None,
+    };
+    assert!(validate(&req).is_err());
+    req.operation = BulkOperation::Deploy;
+    req.products = vec!["shop".to_string(), "shop".to_string()];
+    assert!(validate(&req).unwrap_err().to_string().contains("twice"));
+    req.products = vec![];
+    assert!(validate(&req).is_err());
+  }
+}
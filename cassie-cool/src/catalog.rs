@@ -0,0 +1,226 @@
+use crate::config::{product_dir, ProductConfig, PRODUCT_CONFIG_FILE};
+use crate::dry_run::{self, DryRun};
+use crate::list_query::ListSpec;
+use crate::util::list_dir;
+use crate::{encryption, tenants};
+use deno_core::error::{custom_error, generic_error, AnyError};
+use deno_runtime::at_rest;
+use pulldown_cmark::{html, Event, Options, Parser, Tag};
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path};
+
+///README 渲染的大小上限
+const MAX_README_BYTES: usize = 512 * 1024;
+
+///产品目录信息 cool.json 中的 catalog 控制台的产品目录按它展示和搜索
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CatalogMeta {
+  pub display_name: Option<String>, //展示名称 为空时使用产品编码
+  pub description: Option<String>,
+  pub owner: Option<String>, //负责的团队或联系人
+  pub tags: Vec<String>,
+  pub readme: String, //README 文件 相对产品目录
+}
+
+impl Default for CatalogMeta {
+  fn default() -> Self {
+    Self {
+      display_name: None,
+      description: None,
+      owner: None,
+      tags: vec![],
+      readme: "README.md".to_string(),
+    }
+  }
+}
+
+///产品目录中的一项
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogEntry {
+  pub product_code: String,
+  pub display_name: String,
+  pub description: Option<String>,
+  pub owner: Option<String>,
+  pub tags: Vec<String>,
+  pub tenant: Option<String>, //所属租户 id 平台产品为空
+}
+
+///产品列表的排序和过滤 owner 为目录中的负责人
+pub const LIST_SPEC: ListSpec = ListSpec {
+  id: "product_code",
+  sort: &["product_code", "display_name", "owner"],
+  default_sort: "product_code",
+  status: None,
+  tag: Some("tags"),
+  owner: Some("owner"),
+};
+
+///单个产品的目录信息 带渲染后的 README
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProductDetail {
+  #[serde(flatten)]
+  pub entry: CatalogEntry,
+  pub readme: String,
+  pub readme_html: Option<String>, //README 不存在时为空
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CatalogQuery {
+  pub q: Option<String>,   //匹配编码 名称 描述 负责人和标签 不区分大小写
+  pub tag: Option<String>, //只返回带这个标签的产品
+}
+
+fn entry(product_code: &str) -> Result<CatalogEntry, AnyError> {
+  let catalog = ProductConfig::load(product_code)?.catalog;
+  Ok(CatalogEntry {
+    product_code: product_code.to_string(),
+    display_name: catalog.display_name.unwrap_or_else(|| product_code.to_string()),
+    description: catalog.description,
+    owner: catalog.owner,
+    tags: catalog.tags,
+    tenant: tenants::product_meta(product_code)?.map(|m| m.owner),
+  })
+}
+
+fn is_script_url(url: &str) -> bool {
+  let url = url.trim().to_ascii_lowercase();
+  url.starts_with("javascript:") || url.starts_with("vbscript:") || url.starts_with("data:text/html")
+}
+
+///markdown 渲染为 html 其中的原始 html 按文本转义 脚本链接替换为 # 避免在控制台执行脚本
+pub fn render_markdown(markdown: &str) -> String {
+  let parser = Parser::new_ext(
+    markdown,
+    Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS,
+  )
+  .map(|event| match event {
+    Event::Html(raw) => Event::Text(raw),
+    Event::Start(Tag::Link(kind, dest, title)) if is_script_url(&dest) => Event::Start(Tag::Link(kind, "#".into(), title)),
+    Event::Start(Tag::Image(kind, dest, title)) if is_script_url(&dest) => Event::Start(Tag::Image(kind, "#".into(), title)),
+    event => event,
+  });
+  let mut out = String::new();
+  html::push_html(&mut out, parser);
+  out
+}
+
+fn matches(entry: &CatalogEntry, query: &CatalogQuery) -> bool {
+  if let Some(tag) = &query.tag {
+    if !entry.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)) {
+      return false;
+    }
+  }
+  let q = match query.q.as_deref().map(str::trim) {
+    Some(q) if !q.is_empty() => q.to_lowercase(),
+    _ => return true,
+  };
+  [
+    Some(&entry.product_code),
+    Some(&entry.display_name),
+    entry.description.as_ref(),
+    entry.owner.as_ref(),
+  ]
+  .into_iter()
+  .flatten()
+  .chain(entry.tags.iter())
+  .any(|field| field.to_lowercase().contains(&q))
+}
+
+///code 目录下的所有产品 按产品编码排序
+pub fn product_codes() -> Result<Vec<String>, AnyError> {
+  Ok(
+    list_dir(product_dir(""))?
+      .into_iter()
+      .filter(|(product_code, path)| path.is_dir() && tenants::valid_code(product_code))
+      .map(|(product_code, _)| product_code)
+      .collect(),
+  )
+}
+
+///产品目录 按产品编码排序 cool.json 读取失败的产品跳过
+pub fn search(query: &CatalogQuery) -> Result<Vec<CatalogEntry>, AnyError> {
+  let mut entries = vec![];
+  for product_code in product_codes()? {
+    match entry(&product_code) {
+      Ok(entry) if matches(&entry, query) => entries.push(entry),
+      Ok(_) => {}
+      Err(err) => log::warn!("{} catalog entry skipped: {}", product_code, err),
+    }
+  }
+  Ok(entries)
+}
+
+///产品的目录信息和 README
+pub async fn detail(product_code: &str) -> Result<ProductDetail, AnyError> {
+  if !product_dir(product_code).is_dir() {
+    return Err(custom_error("NotFound", format!("product {} not found", product_code)));
+  }
+  let readme = ProductConfig::load(product_code)?.catalog.readme;
+  let relative = Path::new(&readme);
+  let readme_html = match !readme.is_empty() && relative.components().all(|c| matches!(c, Component::Normal(_))) {
+    true => match tokio::fs::read(product_dir(product_code).join(relative)).await {
+      Ok(bytes) => {
+        let bytes = at_rest::decrypt(bytes)?;
+        let markdown = String::from_utf8_lossy(&bytes[..bytes.len().min(MAX_README_BYTES)]);
+        Some(render_markdown(&markdown))
+      }
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+      Err(err) => return Err(err.into()),
+    },
+    false => None,
+  };
+  Ok(ProductDetail {
+    entry: entry(product_code)?,
+    readme,
+    readme_html,
+  })
+}
+
+///修改前后的 cool.json 修改后的配置必须能按产品配置解析
+async fn merge_catalog(product_code: &str, catalog: &CatalogMeta) -> Result<(serde_json::Value, serde_json::Value), AnyError> {
+  let path = product_dir(product_code).join(PRODUCT_CONFIG_FILE);
+  let before: serde_json::Value = match tokio::fs::read(&path).await {
+    Ok(bytes) => serde_json::from_slice(&at_rest::decrypt(bytes)?)?,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => serde_json::json!({}),
+    Err(err) => return Err(err.into()),
+  };
+  if !product_dir(product_code).is_dir() {
+    return Err(custom_error("NotFound", format!("product {} not found", product_code)));
+  }
+  if !before.is_object() {
+    return Err(generic_error(format!("{} must be a json object", PRODUCT_CONFIG_FILE)));
+  }
+  let mut after = before.clone();
+  after["catalog"] = serde_json::to_value(catalog)?;
+  serde_json::from_value::<ProductConfig>(after.clone()).map_err(|err| generic_error(format!("invalid {}: {}", PRODUCT_CONFIG_FILE, err)))?;
+  Ok((before, after))
+}
+
+///修改 cool.json 中的 catalog 其他配置保持不变
+pub async fn update(product_code: &str, catalog: &CatalogMeta) -> Result<ProductDetail, AnyError> {
+  let (_, config) = merge_catalog(product_code, catalog).await?;
+  let path = product_dir(product_code).join(PRODUCT_CONFIG_FILE);
+  encryption::write(&path, serde_json::to_vec_pretty(&config)?).await?;
+  detail(product_code).await
+}
+
+///预览修改后的 cool.json 不写入
+pub async fn preview_update(product_code: &str, catalog: &CatalogMeta) -> Result<DryRun, AnyError> {
+  let (before, after) = merge_catalog(product_code, catalog).await?;
+  dry_run::preview(&before, &after)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn escapes_raw_html() {
+    let html = render_markdown("# Shop\n\n<script>alert(1)</script>\n");
+    assert!(html.contains("<h1>Shop</h1>"));
+    assert!(!html.contains("<script>"));
+    assert!(!render_markdown("[x](javascript:alert(1))").contains("javascript:"));
+  }
+}
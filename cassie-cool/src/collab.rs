@@ -0,0 +1,411 @@
+use crate::{auth, encryption, media};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_ws::{CloseCode, CloseReason, Message, Session};
+use deno_core::error::{generic_error, AnyError};
+use deno_runtime::at_rest;
+use futures_util::StreamExt;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::select;
+use tokio::sync::broadcast;
+
+///协作文档的字符上限 包括已删除的字符
+const MAX_NODES: usize = 2 * 1024 * 1024;
+
+///字符的 id (lamport 时钟, 客户端编号) 加载文件时的字符客户端编号为 0
+pub type Id = (u64, u32);
+
+///编辑操作 insert 中 text 的第 i 个字符 id 为 (clock + i, site) 依次插在前一个字符后面
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Op {
+  Insert { id: Id, after: Option<Id>, text: String }, //after 为空时插在开头
+  Delete { id: Id },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Node {
+  pub id: Id,
+  pub ch: char,
+  pub deleted: bool, //删除的字符保留位置 之后的插入可能引用它
+}
+
+///在线的编辑者
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Presence {
+  pub site: u32,
+  pub user: String,
+  pub cursor: Option<Id>, //光标所在字符 为空时在开头
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ClientMsg {
+  Ops { ops: Vec<Op> },
+  Presence { cursor: Option<Id> },
+  Save,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ServerMsg {
+  Init {
+    site: u32,
+    clock: u64,
+    nodes: Vec<Node>,
+    presence: Vec<Presence>,
+  },
+  Ops {
+    site: u32,
+    ops: Vec<Op>,
+  },
+  Presence(Presence),
+  Leave {
+    site: u32,
+  },
+  Saved {
+    site: u32,
+  },
+  Error {
+    message: String,
+  },
+}
+
+///RGA 序列 并发插入在同一位置时 id 大的在前 各客户端按任意顺序应用得到相同的结果
+#[derive(Debug, Default)]
+pub struct Rga {
+  nodes: Vec<Node>,
+  clock: u64,
+}
+
+impl Rga {
+  pub fn from_text(text: &str) -> Self {
+    let nodes: Vec<Node> = text
+      .chars()
+      .enumerate()
+      .map(|(i, ch)| Node {
+        id: (i as u64 + 1, 0),
+        ch,
+        deleted: false,
+      })
+      .collect();
+    Self {
+      clock: nodes.len() as u64,
+      nodes,
+    }
+  }
+
+  fn position(&self, id: Id) -> Option<usize> {
+    self.nodes.iter().position(|n| n.id == id)
+  }
+
+  fn insert(&mut self, id: Id, after: Option<Id>, ch: char) -> Result<bool, String> {
+    if self.position(id).is_some() {
+      return Ok(false);
+    }
+    let mut at = match after {
+      Some(after) => self.position(after).ok_or_else(|| format!("unknown character {:?}", after))? + 1,
+      None => 0,
+    };
+    //跳过并发插入在同一位置且 id 更大的字符及其后续
+    while at < self.nodes.len() && self.nodes[at].id > id {
+      at += 1;
+    }
+    self.nodes.insert(at, Node { id, ch, deleted: false });
+    self.clock = self.clock.max(id.0);
+    Ok(true)
+  }
+
+  ///应用操作 已经应用过的操作忽略 返回是否有变化
+  pub fn apply(&mut self, op: &Op) -> Result<bool, String> {
+    match op {
+      Op::Insert { id, after, text } => {
+        if self.nodes.len() + text.chars().count() > MAX_NODES {
+          return Err("document too large".to_string());
+        }
+        let mut changed = false;
+        let mut after = *after;
+        for (i, ch) in text.chars().enumerate() {
+          let id = (id.0 + i as u64, id.1);
+          changed |= self.insert(id, after, ch)?;
+          after = Some(id);
+        }
+        Ok(changed)
+      }
+      Op::Delete { id } => {
+        let at = self.position(*id).ok_or_else(|| format!("unknown character {:?}", id))?;
+        let changed = !self.nodes[at].deleted;
+        self.nodes[at].deleted = true;
+        Ok(changed)
+      }
+    }
+  }
+
+  pub fn text(&self) -> String {
+    self.nodes.iter().filter(|n| !n.deleted).map(|n| n.ch).collect()
+  }
+}
+
+///打开的协作文档 所有连接共享
+struct Doc {
+  file: PathBuf,
+  rga: Rga,
+  next_site: u32,
+  presence: BTreeMap<u32, Presence>,
+  clients: usize,
+  dirty: bool,
+  tx: broadcast::Sender<(u32, String)>, //(发送者, 消息) 发送者自己不会收到
+}
+
+lazy_static! {
+  static ref DOCS: Mutex<HashMap<PathBuf, Arc<Mutex<Doc>>>> = Mutex::new(HashMap::new());
+}
+
+///文件是否正在协作编辑 这时整文件覆盖会丢失别人的修改
+pub fn is_open(file: &Path) -> bool {
+  DOCS.lock().unwrap().contains_key(file)
+}
+
+fn encode(msg: &ServerMsg) -> String {
+  serde_json::to_string(msg).unwrap()
+}
+
+///打开文档并分配客户端编号 第一个连接时从文件加载
+async fn open(file: &Path, user: &str) -> Result<(Arc<Mutex<Doc>>, u32), AnyError> {
+  let existing = DOCS.lock().unwrap().get(file).cloned();
+  let doc = match existing {
+    Some(doc) => doc,
+    None => {
+      let bytes = match tokio::fs::read(file).await {
+        Ok(bytes) => at_rest::decrypt(bytes)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(err) => return Err(err.into()),
+      };
+      if media::is_binary(&bytes) {
+        return Err(generic_error("binary file can not be edited together"));
+      }
+      let doc = Doc {
+        file: file.to_path_buf(),
+        rga: Rga::from_text(&String::from_utf8(bytes)?),
+        next_site: 1,
+        presence: BTreeMap::new(),
+        clients: 0,
+        dirty: false,
+        tx: broadcast::channel(256).0,
+      };
+      DOCS
+        .lock()
+        .unwrap()
+        .entry(file.to_path_buf())
+        .or_insert_with(|| Arc::new(Mutex::new(doc)))
+        .clone()
+    }
+  };
+  let site = {
+    let mut locked = doc.lock().unwrap();
+    let site = locked.next_site;
+    locked.next_site += 1;
+    locked.clients += 1;
+    let presence = Presence {
+      site,
+      user: user.to_string(),
+      cursor: None,
+    };
+    let _ = locked.tx.send((site, encode(&ServerMsg::Presence(presence.clone()))));
+    locked.presence.insert(site, presence);
+    site
+  };
+  Ok((doc, site))
+}
+
+///保存当前内容到文件
+async fn save(doc: &Arc<Mutex<Doc>>) -> Result<(), AnyError> {
+  let (file, text) = {
+    let mut locked = doc.lock().unwrap();
+    locked.dirty = false;
+    (locked.file.clone(), locked.rga.text())
+  };
+  encryption::write(file, text).await
+}
+
+///连接断开 最后一个编辑者离开时保存并关闭文档
+async fn leave(doc: &Arc<Mutex<Doc>>, site: u32) {
+  let (last, dirty, file) = {
+    let mut locked = doc.lock().unwrap();
+    locked.clients -= 1;
+    locked.presence.remove(&site);
+    let _ = locked.tx.send((site, encode(&ServerMsg::Leave { site })));
+    (locked.clients == 0, locked.dirty, locked.file.clone())
+  };
+  if !last {
+    return;
+  }
+  if dirty {
+    if let Err(err) = save(doc).await {
+      log::error!("{} collaborative save failed: {}", file.display(), err);
+    }
+  }
+  let mut docs = DOCS.lock().unwrap();
+  //保存期间可能有新的连接
+  if doc.lock().unwrap().clients == 0 {
+    docs.remove(&file);
+  }
+}
+
+///处理客户端消息 返回发给这个客户端的回复
+async fn handle(doc: &Arc<Mutex<Doc>>, site: u32, text: &str) -> Option<ServerMsg> {
+  let msg = match serde_json::from_str::<ClientMsg>(text) {
+    Ok(msg) => msg,
+    Err(err) => return Some(ServerMsg::Error { message: err.to_string() }),
+  };
+  match msg {
+    ClientMsg::Ops { ops } => {
+      let mut locked = doc.lock().unwrap();
+      let mut applied = vec![];
+      let mut error = None;
+      for op in ops {
+        match locked.rga.apply(&op) {
+          Ok(true) => applied.push(op),
+          Ok(false) => {}
+          Err(message) => {
+            error = Some(ServerMsg::Error { message });
+            break;
+          }
+        }
+      }
+      if !applied.is_empty() {
+        locked.dirty = true;
+        let _ = locked.tx.send((site, encode(&ServerMsg::Ops { site, ops: applied })));
+      }
+      error
+    }
+    ClientMsg::Presence { cursor } => {
+      let mut locked = doc.lock().unwrap();
+      let presence = locked.presence.get_mut(&site)?;
+      presence.cursor = cursor;
+      let msg = encode(&ServerMsg::Presence(presence.clone()));
+      let _ = locked.tx.send((site, msg));
+      None
+    }
+    ClientMsg::Save => match save(doc).await {
+      Ok(_) => {
+        let msg = ServerMsg::Saved { site };
+        let _ = doc.lock().unwrap().tx.send((site, encode(&msg)));
+        Some(msg)
+      }
+      Err(err) => Some(ServerMsg::Error { message: err.to_string() }),
+    },
+  }
+}
+
+async fn close(session: Session, code: CloseCode, description: &str) {
+  let _ = session
+    .close(Some(CloseReason {
+      code,
+      description: Some(description.to_string()),
+    }))
+    .await;
+}
+
+///加入文件的协作编辑<br>
+/// 连接后先收到 init 包含全部字符和在线的编辑者 之后收发 ops presence save 消息<br>
+/// 客户端的 lamport 时钟取见过的最大值加一 最后一个编辑者离开时自动保存
+pub async fn join(req: &HttpRequest, payload: web::Payload, file: PathBuf) -> Result<HttpResponse, Error> {
+  let user = auth::authenticate(req).map(|p| p.email).unwrap_or_else(|_| "anonymous".to_string());
+  let (doc, site) = match open(&file, &user).await {
+    Ok(opened) => opened,
+    Err(err) => return Ok(auth::error_response(err)),
+  };
+  let (response, mut session, mut stream) = match actix_ws::handle(req, payload) {
+    Ok(handled) => handled,
+    Err(err) => {
+      leave(&doc, site).await;
+      return Err(err);
+    }
+  };
+  let (init, mut rx) = {
+    let locked = doc.lock().unwrap();
+    let init = ServerMsg::Init {
+      site,
+      clock: locked.rga.clock,
+      nodes: locked.rga.nodes.clone(),
+      presence: locked.presence.values().cloned().collect(),
+    };
+    (encode(&init), locked.tx.subscribe())
+  };
+  actix_web::rt::spawn(async move {
+    if session.text(init).await.is_err() {
+      leave(&doc, site).await;
+      return;
+    }
+    loop {
+      select! {
+        msg = stream.next() => {
+          let sent = match msg {
+            Some(Ok(Message::Text(text))) => match handle(&doc, site, &text).await {
+              Some(reply) => session.text(encode(&reply)).await,
+              None => Ok(()),
+            },
+            Some(Ok(Message::Ping(bytes))) => session.pong(&bytes).await,
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+            Some(Ok(_)) => Ok(()),
+          };
+          if sent.is_err() {
+            break;
+          }
+        }
+        msg = rx.recv() => match msg {
+          Ok((from, text)) if from != site => {
+            if session.text(text).await.is_err() {
+              break;
+            }
+          }
+          Ok(_) => {}
+          //落后太多时断开 客户端重连后重新 init
+          Err(broadcast::error::RecvError::Lagged(_)) => {
+            close(session, CloseCode::Again, "too far behind, reconnect").await;
+            leave(&doc, site).await;
+            return;
+          }
+          Err(broadcast::error::RecvError::Closed) => break,
+        }
+      }
+    }
+    leave(&doc, site).await;
+    let _ = session.close(None).await;
+  });
+  Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn concurrent_inserts_converge() {
+    let a = Op::Insert {
+      id: (3, 1),
+      after: Some((1, 0)),
+      text: "xy".to_string(),
+    };
+    let b = Op::Insert {
+      id: (3, 2),
+      after: Some((1, 0)),
+      text: "z".to_string(),
+    };
+    let delete = Op::Delete { id: (2, 0) };
+    let mut left = Rga::from_text("ab");
+    let mut right = Rga::from_text("ab");
+    for op in [&a, &b, &delete] {
+      left.apply(op).unwrap();
+    }
+    for op in [&delete, &b, &a, &a] {
+      right.apply(op).unwrap();
+    }
+    assert_eq!(left.text(), "azxy");
+    assert_eq!(left.text(), right.text());
+  }
+}
@@ -0,0 +1,387 @@
+use crate::access_log::AccessLogConfig;
+use crate::admission::AdmissionPolicy;
+use crate::anomaly::{AnomalyConfig, AnomalyPolicy};
+use crate::api_version::ApiConfig;
+use crate::audit_log::AuditConfig;
+use crate::auth::AuthConfig;
+use crate::bandwidth::BandwidthLimit;
+use crate::bench::BenchConfig;
+use crate::bridge::BridgeSubscription;
+use crate::catalog::CatalogMeta;
+use crate::cookies::CookiePolicy;
+use crate::crawler::CrawlerPolicy;
+use crate::dep_audit::AuditPolicy;
+use crate::dns_cache::DnsCacheConfig;
+use crate::doctor::DoctorConfig;
+use crate::egress::EgressLog;
+use crate::encryption::EncryptionConfig;
+use crate::etag::EtagPolicy;
+use crate::geoip::{GeoIpConfig, GeoPolicy};
+use crate::git_hooks::GitHook;
+use crate::gitops::GitOpsConfig;
+use crate::graphql::{GraphqlConfig, GraphqlSchema};
+use crate::grpc_web::GrpcWebPolicy;
+use crate::heap_trend::HeapPolicy;
+use crate::licenses::LicensePolicy;
+use crate::log_shipping::LogSink;
+use crate::logs::LogRotation;
+use crate::msgpack::MsgpackPolicy;
+use crate::mtls::MtlsPolicy;
+use crate::node_compat::NodeCompat;
+use crate::notifier::Notifier;
+use crate::offline::OfflineConfig;
+use crate::otel::OtelConfig;
+use crate::outbound::OutboundConfig;
+use crate::overload::{OverloadConfig, Priority};
+use crate::panics::PanicConfig;
+use crate::pipeline::{PipelineConfig, TestCassettes};
+use crate::preview::PreviewRouting;
+use crate::product_package::PackageConfig;
+use crate::rate_limit::RateLimitConfig;
+use crate::recycle::RecyclePolicy;
+use crate::replica::ReplicaConfig;
+use crate::retention::RetentionConfig;
+use crate::roles::EntryConfig;
+use crate::routes::Route;
+use crate::sandbox::FilesystemPolicy;
+use crate::security_headers::{SecurityHeaders, SecurityHeadersConfig};
+use crate::signature::SignaturePolicy;
+use crate::size_budget::SizeBudget;
+use crate::smoke::{SmokeOptions, SmokeTest};
+use crate::storage::StorageConfig;
+use crate::upstream::Upstream;
+use crate::usage::UsageConfig;
+use crate::websocket::WebSocketLimits;
+use crate::worker_util::{Balance, HealthCheck, ShutdownPolicy};
+use deno_core::error::{generic_error, AnyError};
+use deno_runtime::at_rest;
+use deno_runtime::deno_fetch::FetchMocks;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+///产品配置文件名 放在产品代码目录下
+pub const PRODUCT_CONFIG_FILE: &str = "cool.json";
+
+///网关配置文件名 放在网关启动目录下
+pub const GATEWAY_CONFIG_FILE: &str = "gateway.json";
+
+///http 监听地址
+pub const HTTP_BIND: &str = "127.0.0.1:9999";
+
+///转发使用的产品配置缓存 每个产品最多每隔这么久检查一次 cool.json 是否修改
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+///缓存的产品配置
+struct CachedConfig {
+  config: Arc<ProductConfig>,
+  modified: Option<SystemTime>, //读取时 cool.json 的修改时间 没有配置文件时为空
+  checked_at: Instant,
+}
+
+lazy_static! {
+  static ref PRODUCT_CONFIGS: RwLock<HashMap<String, CachedConfig>> = RwLock::new(HashMap::new());
+}
+
+///产品代码目录 code/{product_code}
+pub fn product_dir(product_code: &str) -> PathBuf {
+  let mut dir = std::env::current_dir().unwrap();
+  dir.push("code");
+  dir.push(product_code);
+  dir
+}
+
+///平台数据目录 data 存放报告等平台生成的数据
+pub fn data_dir() -> PathBuf {
+  let mut dir = std::env::current_dir().unwrap();
+  dir.push("data");
+  dir
+}
+
+///产品远程模块的锁定表 data/pins/{product_code}.json<br>
+/// 远程模块按内容存放在共享的模块仓库 每个产品只记录自己用到的版本
+pub fn module_pins_path(product_code: &str) -> PathBuf {
+  let mut path = data_dir();
+  path.push("pins");
+  path.push(format!("{}.json", product_code));
+  path
+}
+
+///产品的持久化存储目录 data/storage/{product_code} KV 和 localStorage 都在这里<br>
+/// 产品的所有入口共用 部署新版本后保持不变
+pub fn storage_dir(product_code: &str) -> PathBuf {
+  let mut dir = data_dir();
+  dir.push("storage");
+  dir.push(product_code);
+  dir
+}
+
+///产品入口模块 读取 cool.json 中的 runtime.entry
+pub fn product_entry(product_code: &str) -> String {
+  let config = ProductConfig::load(product_code).unwrap_or_default();
+  config.runtime.entry_path(product_code).to_string_lossy().to_string()
+}
+
+///runtime 启动参数 cool.json 中的 runtime
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RuntimeConfig {
+  pub entry: String,       //入口模块 相对产品目录
+  pub args: Vec<String>,   //脚本参数 Deno.args
+  pub cwd: Option<String>, //工作目录 相对产品目录 用于模块解析和配置文件查找
+  pub lazy_imports: bool,  //动态导入的模块第一次执行时才加载 适合路由很多的大型产品
+}
+
+impl Default for RuntimeConfig {
+  fn default() -> Self {
+    Self {
+      entry: "app.ts".to_string(),
+      args: vec![],
+      cwd: None,
+      lazy_imports: false,
+    }
+  }
+}
+
+impl RuntimeConfig {
+  pub fn entry_path(&self, product_code: &str) -> PathBuf {
+    product_dir(product_code).join(&self.entry)
+  }
+
+  pub fn cwd_path(&self, product_code: &str) -> Option<PathBuf> {
+    self.cwd.as_ref().map(|cwd| product_dir(product_code).join(cwd))
+  }
+
+  ///部署前校验 入口和工作目录必须存在且不能超出产品目录
+  pub fn validate(&self, product_code: &str) -> Result<(), AnyError> {
+    validate_module(product_code, &self.entry)?;
+    self.validate_cwd(product_code)
+  }
+
+  ///工作目录必须在产品目录内
+  pub fn validate_cwd(&self, product_code: &str) -> Result<(), AnyError> {
+    let base = product_dir(product_code).canonicalize()?;
+    if let Some(cwd) = self.cwd_path(product_code) {
+      let cwd = cwd.canonicalize().map_err(|_| generic_error("working directory not found"))?;
+      if !cwd.starts_with(&base) || !cwd.is_dir() {
+        return Err(generic_error("working directory must be a directory inside the product"));
+      }
+    }
+    Ok(())
+  }
+}
+
+///入口模块必须存在且不能超出产品目录 module 相对产品目录
+pub fn validate_module(product_code: &str, module: &str) -> Result<(), AnyError> {
+  let base = product_dir(product_code).canonicalize()?;
+  let entry = product_dir(product_code)
+    .join(module)
+    .canonicalize()
+    .map_err(|_| generic_error(format!("entry module {} not found", module)))?;
+  if !entry.starts_with(&base) || !entry.is_file() {
+    return Err(generic_error(format!("entry module {} must be a file inside the product", module)));
+  }
+  Ok(())
+}
+
+///产品配置 没有配置文件时全部使用默认值
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProductConfig {
+  pub runtime: RuntimeConfig,            //入口 参数 工作目录
+  pub entries: Vec<EntryConfig>,         //按角色声明的多个入口
+  pub audit: AuditPolicy,                //依赖漏洞审计策略
+  pub licenses: LicensePolicy,           //依赖许可证策略
+  pub size_budget: SizeBudget,           //打包体积预算
+  pub pipeline: PipelineConfig,          //部署流水线
+  pub smoke_tests: Vec<SmokeTest>,       //部署后执行的冒烟测试 失败自动回滚
+  pub smoke: SmokeOptions,               //冒烟测试执行参数
+  pub offline: OfflineConfig,            //离线模式 网关开启时对所有产品生效
+  pub signature: SignaturePolicy,        //机器调用方的请求签名校验
+  pub mtls: MtlsPolicy,                  //需要客户端证书的路径
+  pub geo: GeoPolicy,                    //按国家允许或拒绝访问
+  pub bandwidth: BandwidthLimit,         //上传下载带宽上限
+  pub websocket: WebSocketLimits,        //WebSocket 连接上限
+  pub filesystem: FilesystemPolicy,      //文件系统沙箱 代码只读 临时目录有配额
+  pub preview: PreviewRouting,           //按请求头或 cookie 转发到预览产品
+  pub catalog: CatalogMeta,              //控制台产品目录中的名称 描述 标签和 README
+  pub upstreams: Vec<Upstream>,          //依赖的数据库和外部 API 用于连通性检查
+  pub anomaly: AnomalyPolicy,            //请求量和错误率的异常检测
+  pub logs: Option<LogRotation>,         //日志轮转 为空时使用网关的设置
+  pub node: NodeCompat,                  //Node.js 兼容模式
+  pub routes: Vec<Route>,                //路由清单 由 runtime 直接调用处理函数 不再使用入口模块
+  pub security_headers: SecurityHeaders, //响应中注入的安全头 覆盖网关的默认值
+  pub cookies: CookiePolicy,             //Set-Cookie 的属性和名称前缀
+  pub crawler: CrawlerPolicy,            //网关直接返回的 robots.txt 和 sitemap.xml
+  pub egress: EgressLog,                 //记录 runtime 发出的 fetch 请求
+  pub fetch_mocks: FetchMocks,           //平台运行测试时 fetch 返回的模拟响应 strict 时没有匹配的请求失败
+  pub cassettes: TestCassettes,          //平台运行测试时录制和回放外部请求
+  pub dns: DnsCacheConfig,               //runtime fetch 的 DNS 缓存
+  pub outbound: OutboundConfig,          //runtime fetch 的地址族 连接超时和并发上限 覆盖网关的设置
+  pub bench: BenchConfig,                //基准测试的对比版本和性能退化阈值
+  pub heap: HeapPolicy,                  //生产 runtime 的堆内存增长检测
+  pub recycle: RecyclePolicy,            //生产 runtime 达到请求数 运行时间或堆大小上限后平滑替换
+  pub priority: Priority,                //网关过载时按优先级从低到高拒绝请求
+  pub admission: AdmissionPolicy,        //按 runtime 报告的压力逐步减少接受的请求
+  pub health: HealthCheck,               //端口健康检查 不健康时网关直接返回 503
+  pub etag: EtagPolicy,                  //网关生成 ETag 并处理条件请求
+  pub msgpack: MsgpackPolicy,            //请求和响应体在 JSON 与 MessagePack 之间转换
+  pub grpc_web: GrpcWebPolicy,           //浏览器的 gRPC-web 请求转成 gRPC 转发 以及相关的跨域设置
+  pub shutdown: ShutdownPolicy,          //停止 runtime 前等待转发中的请求完成
+  pub graphql: GraphqlSchema,            //注册到网关 GraphQL 入口的 schema 和处理请求的路径
+  pub balance: Balance,                  //多个端口副本之间的负载均衡 round_robin 或 least_connections
+  pub bridges: Vec<BridgeSubscription>,  //订阅 MQTT topic 或 AMQP 队列 消息投递给产品的 handler
+}
+
+impl ProductConfig {
+  ///读取 code/{product_code}/cool.json
+  pub fn load(product_code: &str) -> Result<Self, AnyError> {
+    let mut path = product_dir(product_code);
+    path.push(PRODUCT_CONFIG_FILE);
+    match std::fs::read(&path) {
+      Ok(bytes) => Ok(serde_json::from_slice(&at_rest::decrypt(bytes)?)?),
+      Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+      Err(err) => Err(err.into()),
+    }
+  }
+
+  ///转发请求使用的配置 产品不存在时为 None<br>
+  /// 按 cool.json 的修改时间缓存 修改后最多 CONFIG_CHECK_INTERVAL 生效 检查和读取都在阻塞线程中执行<br>
+  /// 读取失败时继续使用上一次的配置 例如文件正在写入 从未读取成功时返回错误 不能用默认配置放开产品的访问策略
+  pub async fn cached(product_code: &str) -> Result<Option<Arc<Self>>, AnyError> {
+    let previous = match PRODUCT_CONFIGS.read().unwrap().get(product_code) {
+      Some(cached) if cached.checked_at.elapsed() < CONFIG_CHECK_INTERVAL => return Ok(Some(cached.config.clone())),
+      Some(cached) => Some((cached.modified, cached.config.clone())),
+      None => None,
+    };
+    let code = product_code.to_string();
+    let loaded = tokio::task::spawn_blocking(move || {
+      let dir = product_dir(&code);
+      if !dir.is_dir() {
+        return Ok(None);
+      }
+      let modified = std::fs::metadata(dir.join(PRODUCT_CONFIG_FILE)).and_then(|m| m.modified()).ok();
+      refresh(previous, modified, || Self::load(&code)).map(Some)
+    })
+    .await?;
+    let mut cache = PRODUCT_CONFIGS.write().unwrap();
+    match loaded {
+      Ok(Some((modified, config))) => {
+        let cached = CachedConfig {
+          config: config.clone(),
+          modified,
+          checked_at: Instant::now(),
+        };
+        cache.insert(product_code.to_string(), cached);
+        Ok(Some(config))
+      }
+      Ok(None) => {
+        cache.remove(product_code);
+        Ok(None)
+      }
+      Err(err) => {
+        log::warn!("failed to load {} config: {}", product_code, err);
+        Err(err)
+      }
+    }
+  }
+}
+
+///修改时间没变时使用上一次的配置 否则重新读取 读取失败时退回上一次的配置 没有上一次的配置时返回错误
+fn refresh(
+  previous: Option<(Option<SystemTime>, Arc<ProductConfig>)>,
+  modified: Option<SystemTime>,
+  load: impl FnOnce() -> Result<ProductConfig, AnyError>,
+) -> Result<(Option<SystemTime>, Arc<ProductConfig>), AnyError> {
+  match previous {
+    Some((previous_modified, config)) if previous_modified == modified => Ok((modified, config)),
+    previous => match load() {
+      Ok(config) => Ok((modified, Arc::new(config))),
+      Err(err) => previous.ok_or(err),
+    },
+  }
+}
+
+///网关配置 对所有产品生效 没有配置文件时全部使用默认值
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GatewayConfig {
+  pub offline: OfflineConfig,                  //离线模式
+  pub tls: Option<TlsConfig>,                  //https 监听 不配置时只监听 http
+  pub geoip: Option<GeoIpConfig>,              //GeoIP 数据库 不配置时不做地区识别
+  pub usage: UsageConfig,                      //用量采样
+  pub auth: AuthConfig,                        //控制台认证
+  pub audit: AuditConfig,                      //审计日志检查点
+  pub retention: RetentionConfig,              //数据保留期
+  pub encryption: EncryptionConfig,            //静态加密
+  pub git_hooks: Vec<GitHook>,                 //推送后自动部署的仓库和分支
+  pub anomaly: AnomalyConfig,                  //请求量和错误率的异常检测
+  pub notifiers: Vec<Notifier>,                //告警事件投递的 webhook
+  pub logs: LogRotation,                       //runtime 输出日志的轮转
+  pub access_log: AccessLogConfig,             //网关访问日志 每个请求一行 JSON
+  pub log_sinks: Vec<LogSink>,                 //runtime 和网关日志投递到 syslog Loki 或 Elasticsearch
+  pub otel: OtelConfig,                        //OTLP 指标和 trace 导出
+  pub panics: PanicConfig,                     //请求处理中的 panic 超过阈值时重启
+  pub gitops: GitOpsConfig,                    //从配置仓库同步产品
+  pub packages: PackageConfig,                 //产品包的签名密钥
+  pub storage: StorageConfig,                  //版本 产出文件等持久化数据的存储后端
+  pub replica: ReplicaConfig,                  //只读副本
+  pub doctor: DoctorConfig,                    //启动自检
+  pub api: ApiConfig,                          //管理接口版本和弃用策略
+  pub rate_limit: RateLimitConfig,             //按客户端 ip 的请求限流
+  pub security_headers: SecurityHeadersConfig, //响应安全头的默认值和默认注入的产品
+  pub crawler: CrawlerPolicy,                  //robots.txt 和 sitemap.xml 的默认内容
+  pub outbound: OutboundConfig,                //runtime fetch 的地址族 连接超时和并发上限
+  pub overload: OverloadConfig,                //过载时按产品优先级拒绝请求
+  pub graphql: GraphqlConfig,                  //按根字段拆分到各个产品的 GraphQL 入口
+}
+
+///https 监听配置 证书均为 pem 文件路径
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+  #[serde(default = "default_tls_bind")]
+  pub bind: String,
+  pub cert: String,
+  pub key: String,
+  #[serde(default)]
+  pub client_ca: Option<String>, //校验客户端证书的 CA 不配置时不请求客户端证书
+}
+
+fn default_tls_bind() -> String {
+  "127.0.0.1:9443".to_string()
+}
+
+impl GatewayConfig {
+  ///读取启动目录下的 gateway.json
+  pub fn load() -> Result<Self, AnyError> {
+    let mut path = std::env::current_dir().unwrap();
+    path.push(GATEWAY_CONFIG_FILE);
+    match std::fs::read_to_string(&path) {
+      Ok(text) => Ok(serde_json::from_str(&text)?),
+      Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+      Err(err) => Err(err.into()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn fails_closed_until_the_config_loads() {
+    let broken = || Err(generic_error("expected value at line 1 column 1"));
+    assert!(refresh(None, None, broken).is_err());
+    let previous = Arc::new(ProductConfig::default());
+    let (t1, t2) = (SystemTime::UNIX_EPOCH, SystemTime::UNIX_EPOCH + Duration::from_secs(1));
+    let (modified, config) = refresh(Some((Some(t1), previous.clone())), Some(t2), broken).unwrap();
+    assert_eq!(modified, Some(t2));
+    assert!(Arc::ptr_eq(&config, &previous));
+    let (_, config) = refresh(Some((Some(t1), previous.clone())), Some(t1), || unreachable!()).unwrap();
+    assert!(Arc::ptr_eq(&config, &previous));
+  }
+}
@@ -0,0 +1,123 @@
+use actix_web::cookie::{Cookie, SameSite};
+use serde::{Deserialize, Serialize};
+
+///浏览器要求这两个前缀必须在名称开头 产品前缀加在它们后面
+const BROWSER_PREFIXES: [&str; 2] = ["__Host-", "__Secure-"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSitePolicy {
+  Strict,
+  Lax,
+  None, //同时强制 Secure
+}
+
+///经过网关的 cookie 的处理策略 cool.json 中的 cookies<br>
+/// 配置了 prefix 时响应中的 cookie 名称加上前缀 请求中带前缀的 cookie 去掉前缀后交给 runtime<br>
+/// 多个产品共用一个域名时 同名的 cookie 不会互相覆盖
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CookiePolicy {
+  pub secure: bool,                      //强制 Secure
+  pub http_only: bool,                   //强制 HttpOnly
+  pub same_site: Option<SameSitePolicy>, //强制 SameSite 覆盖 runtime 设置的值
+  pub prefix: Option<String>,            //名称前缀 例如 shop_
+  pub bypass: Vec<String>,               //不做任何处理的 cookie 名称 runtime 中的名称
+}
+
+impl CookiePolicy {
+  fn is_noop(&self) -> bool {
+    !self.secure && !self.http_only && self.same_site.is_none() && self.prefix.is_none()
+  }
+}
+
+fn split_browser_prefix(name: &str) -> (&str, &str) {
+  match BROWSER_PREFIXES.iter().find(|p| name.starts_with(*p)) {
+    Some(p) => name.split_at(p.len()),
+    None => ("", name),
+  }
+}
+
+///按策略改写响应中的一个 Set-Cookie 无法解析或在 bypass 中时保持不变
+pub fn rewrite_set_cookie(policy: &CookiePolicy, value: &str) -> String {
+  if policy.is_noop() {
+    return value.to_string();
+  }
+  let mut cookie = match Cookie::parse(value.to_string()) {
+    Ok(cookie) => cookie,
+    Err(_) => return value.to_string(),
+  };
+  if policy.bypass.iter().any(|b| b == cookie.name()) {
+    return value.to_string();
+  }
+  if let Some(prefix) = &policy.prefix {
+    let (browser_prefix, name) = split_browser_prefix(cookie.name());
+    let name = format!("{}{}{}", browser_prefix, prefix, name);
+    cookie.set_name(name);
+  }
+  if policy.secure {
+    cookie.set_secure(true);
+  }
+  if policy.http_only {
+    cookie.set_http_only(true);
+  }
+  match policy.same_site {
+    Some(SameSitePolicy::Strict) => cookie.set_same_site(SameSite::Strict),
+    Some(SameSitePolicy::Lax) => cookie.set_same_site(SameSite::Lax),
+    Some(SameSitePolicy::None) => {
+      cookie.set_same_site(SameSite::None);
+      cookie.set_secure(true);
+    }
+    None => {}
+  }
+  cookie.to_string()
+}
+
+///改写请求中的 Cookie 带前缀的去掉前缀 同名时带前缀的优先<br>
+/// 没有配置 prefix 时返回 None 不需要改写
+pub fn rewrite_cookie_header(policy: &CookiePolicy, value: &str) -> Option<String> {
+  let prefix = policy.prefix.as_deref()?;
+  let pairs: Vec<(&str, &str)> = value.split(';').filter_map(|pair| pair.trim().split_once('=')).collect();
+  let mut cookies: Vec<(String, &str)> = vec![];
+  for &(name, value) in &pairs {
+    let (browser_prefix, rest) = split_browser_prefix(name);
+    if let Some(stripped) = rest.strip_prefix(prefix) {
+      let name = format!("{}{}", browser_prefix, stripped);
+      cookies.retain(|(n, _)| *n != name);
+      cookies.push((name, value));
+    } else if policy.bypass.iter().any(|b| b == name) || !pairs.iter().any(|(n, _)| is_prefixed_form(n, name, prefix)) {
+      cookies.push((name.to_string(), value));
+    }
+  }
+  Some(cookies.iter().map(|(n, v)| format!("{}={}", n, v)).collect::<Vec<_>>().join("; "))
+}
+
+///candidate 是否为 name 加上前缀后的名称
+fn is_prefixed_form(candidate: &str, name: &str, prefix: &str) -> bool {
+  let (browser_prefix, rest) = split_browser_prefix(name);
+  candidate == format!("{}{}{}", browser_prefix, prefix, rest)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn prefixes_and_enforces_attributes() {
+    let policy = CookiePolicy {
+      http_only: true,
+      same_site: Some(SameSitePolicy::None),
+      prefix: Some("shop_".to_string()),
+      bypass: vec!["locale".to_string()],
+      ..Default::default()
+    };
+    let cookie = rewrite_set_cookie(&policy, "sid=abc; Path=/; SameSite=Lax");
+    assert!(cookie.starts_with("shop_sid=abc"));
+    assert!(cookie.contains("HttpOnly") && cookie.contains("SameSite=None") && cookie.contains("Secure"));
+    assert!(rewrite_set_cookie(&policy, "__Host-token=1; Path=/").starts_with("__Host-shop_token=1"));
+    assert_eq!(rewrite_set_cookie(&policy, "locale=de"), "locale=de");
+    let header = rewrite_cookie_header(&policy, "sid=other; shop_sid=abc; locale=de; theme=dark").unwrap();
+    assert_eq!(header, "sid=abc; locale=de; theme=dark");
+    assert_eq!(rewrite_cookie_header(&CookiePolicy::default(), "sid=abc"), None);
+  }
+}
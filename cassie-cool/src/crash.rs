@@ -0,0 +1,268 @@
+use crate::artifacts;
+use crate::list_query::ListSpec;
+use crate::logs::{self, Stream};
+use crate::metrics;
+use crate::notifier::{self, Notification, Severity};
+use crate::util::now_millis;
+use deno_core::error::{AnyError, JsError};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use service::tools::run::{CrashHook, IsolateStats};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+///崩溃报告保存的 artifact 类型
+pub const CRASH_ARTIFACTS: &str = "crashes";
+///报告中附带的最近日志行数
+const LOG_LINES: usize = 50;
+///内存中保留的报告摘要数
+const MAX_REPORTS: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CrashKind {
+  UncaughtException,  //未捕获的异常
+  UnhandledRejection, //未处理的 promise rejection
+  LoadError,          //模块加载失败 例如语法错误或依赖不存在
+}
+
+impl CrashKind {
+  fn as_str(&self) -> &'static str {
+    match self {
+      Self::UncaughtException => "uncaught_exception",
+      Self::UnhandledRejection => "unhandled_rejection",
+      Self::LoadError => "load_error",
+    }
+  }
+}
+
+///崩溃时网关正在转发给这个产品的请求
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InflightRequest {
+  pub method: String,
+  pub path: String,
+  pub started_at: u64,
+}
+
+///runtime 崩溃报告 保存为 artifacts/crashes/{id}.json
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrashReport {
+  pub id: String,
+  pub product_code: String,
+  pub runtime: String, //runtime 线程名
+  pub kind: CrashKind,
+  pub message: String,
+  pub stack: String,
+  pub recent_logs: Vec<String>,       //崩溃前产品日志的最后几行
+  pub requests: Vec<InflightRequest>, //同一产品的 runtime 共用端口 包括所有 runtime 上未完成的请求
+  pub isolate: Option<IsolateStats>,  //崩溃时的堆统计 热加载模式下没有
+  pub created_at: u64,
+}
+
+///报告摘要 url 为报告的下载链接
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrashSummary {
+  pub id: String,
+  pub product_code: String,
+  pub runtime: String,
+  pub kind: CrashKind,
+  pub message: String,
+  pub url: Option<String>,
+  pub created_at: u64,
+}
+
+///崩溃列表的排序和过滤 status 按崩溃类型过滤
+pub const LIST_SPEC: ListSpec = ListSpec {
+  id: "id",
+  sort: &["created_at", "kind"],
+  default_sort: "-created_at",
+  status: Some("kind"),
+  tag: None,
+  owner: None,
+};
+
+static NEXT_REQUEST: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+  static ref INFLIGHT: Mutex<HashMap<u64, (String, InflightRequest)>> = Mutex::new(HashMap::new());
+  static ref SENDER: Mutex<Option<mpsc::UnboundedSender<CrashReport>>> = Mutex::new(None);
+  static ref REPORTS: Mutex<VecDeque<CrashSummary>> = Mutex::new(VecDeque::new());
+}
+
+///转发中的请求 drop 时移除
+pub struct TrackedRequest(u64);
+
+impl Drop for TrackedRequest {
+  fn drop(&mut self) {
+    INFLIGHT.lock().unwrap().remove(&self.0);
+  }
+}
+
+///下一个转发的请求的编号 停止 runtime 时以此区分之前已经开始的请求
+pub fn next_request_id() -> u64 {
+  NEXT_REQUEST.load(Ordering::Relaxed)
+}
+
+///产品中编号小于 before 且还没有完成的请求数
+pub fn inflight_before(product_code: &str, before: u64) -> usize {
+  INFLIGHT
+    .lock()
+    .unwrap()
+    .iter()
+    .filter(|(id, (p, _))| **id < before && p == product_code)
+    .count()
+}
+
+///记录一个转发中的请求 崩溃报告中列出崩溃时未完成的请求
+pub fn track(product_code: &str, method: &str, path: &str) -> TrackedRequest {
+  let id = NEXT_REQUEST.fetch_add(1, Ordering::Relaxed);
+  let request = InflightRequest {
+    method: method.to_string(),
+    path: path.to_string(),
+    started_at: now_millis(),
+  };
+  INFLIGHT.lock().unwrap().insert(id, (product_code.to_string(), request));
+  TrackedRequest(id)
+}
+
+fn classify(err: &AnyError) -> (CrashKind, String, String) {
+  match err.downcast_ref::<JsError>() {
+    Some(e) => {
+      let kind = match e.exception_message.starts_with("Uncaught (in promise)") {
+        true => CrashKind::UnhandledRejection,
+        false => CrashKind::UncaughtException,
+      };
+      let stack = e.stack.clone().unwrap_or_else(|| e.exception_message.clone());
+      (kind, e.exception_message.clone(), stack)
+    }
+    None => (CrashKind::LoadError, err.to_string(), format!("{:?}", err)),
+  }
+}
+
+///在 runtime 线程中收集报告 交给网关的任务保存和投递
+fn capture(product_code: &str, err: &AnyError, isolate: Option<IsolateStats>) {
+  let (kind, message, stack) = classify(err);
+  let recent_logs = logs::tail(product_code, LOG_LINES).unwrap_or_else(|err| {
+    log::warn!("failed to read {} logs: {}", product_code, err);
+    vec![]
+  });
+  //异常同时写入产品的错误输出 与 console 的输出放在一起
+  for line in stack.lines() {
+    let _ = logs::append(product_code, Stream::Stderr, line);
+  }
+  let requests = INFLIGHT
+    .lock()
+    .unwrap()
+    .values()
+    .filter(|(p, _)| p == product_code)
+    .map(|(_, r)| r.clone())
+    .collect();
+  let report = CrashReport {
+    id: uuid::Uuid::new_v4().to_string(),
+    product_code: product_code.to_string(),
+    runtime: std::thread::current().name().unwrap_or_default().to_string(),
+    kind,
+    message,
+    stack,
+    recent_logs,
+    requests,
+    isolate,
+    created_at: now_millis(),
+  };
+  match SENDER.lock().unwrap().as_ref() {
+    Some(tx) => {
+      let _ = tx.send(report);
+    }
+    None => log::error!("{} runtime {} crashed: {}", product_code, report.runtime, report.message),
+  }
+}
+
+///产品 runtime 的崩溃回调
+pub fn hook(product_code: &str) -> CrashHook {
+  let product_code = product_code.to_string();
+  Arc::new(move |err, isolate| capture(&product_code, err, isolate))
+}
+
+async fn publish(report: CrashReport) {
+  log::error!("{} runtime {} crashed: {}", report.product_code, report.runtime, report.message);
+  metrics::inc_counter(
+    "runtime_crashes_total",
+    "Runtimes that exited with an uncaught error per product",
+    &[("product", &report.product_code), ("kind", report.kind.as_str())],
+    1,
+  );
+  let name = format!("{}.json", report.id);
+  let url = match serde_json::to_vec_pretty(&report) {
+    Ok(bytes) => artifacts::put_artifact(CRASH_ARTIFACTS, &name, bytes).await,
+    Err(err) => Err(err.into()),
+  };
+  let url = url.map_err(|err| log::error!("failed to save crash report {}: {}", report.id, err)).ok();
+  let mut detail = serde_json::to_value(&report).unwrap_or_default();
+  detail["url"] = serde_json::json!(url);
+  notifier::notify(Notification::new(
+    &format!("crash.{}", report.kind.as_str()),
+    &report.product_code,
+    Severity::Critical,
+    format!("runtime {} crashed: {}", report.runtime, report.message),
+    detail,
+  ));
+  let mut reports = REPORTS.lock().unwrap();
+  if reports.len() >= MAX_REPORTS {
+    reports.pop_front();
+  }
+  reports.push_back(CrashSummary {
+    id: report.id,
+    product_code: report.product_code,
+    runtime: report.runtime,
+    kind: report.kind,
+    message: report.message,
+    url,
+    created_at: report.created_at,
+  });
+}
+
+///产品最近的崩溃 新的在前
+pub fn list(product_code: &str) -> Vec<CrashSummary> {
+  REPORTS
+    .lock()
+    .unwrap()
+    .iter()
+    .rev()
+    .filter(|r| r.product_code == product_code)
+    .cloned()
+    .collect()
+}
+
+///runtime 线程结束后不能再投递 报告交给网关运行时中的任务处理
+pub fn start() {
+  let (tx, mut rx) = mpsc::unbounded_channel();
+  *SENDER.lock().unwrap() = Some(tx);
+  tokio::spawn(async move {
+    while let Some(report) = rx.recv().await {
+      publish(report).await;
+    }
+  });
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn tracks_inflight_requests() {
+    let first = track("crash-test", "GET", "/a");
+    let second = track("crash-test", "POST", "/b");
+    drop(first);
+    let paths: Vec<String> = INFLIGHT
+      .lock()
+      .unwrap()
+      .values()
+      .filter(|(p, _)| p == "crash-test")
+      .map(|(_, r)| r.path.clone())
+      .collect();
+    assert_eq!(paths, vec!["/b".to_string()]);
+    drop(second);
+  }
+}
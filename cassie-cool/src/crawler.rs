@@ -0,0 +1,115 @@
+use crate::config::GatewayConfig;
+use actix_web::http::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use actix_web::http::Method;
+use actix_web::{HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+
+const DEFAULT_MAX_AGE_SECS: u64 = 3600;
+
+///robots.txt 和 sitemap.xml 由网关直接返回 cool.json 和 gateway.json 中的 crawler<br>
+/// 产品没有配置的内容使用网关的默认值 多个产品共用域名时可以统一爬虫策略
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CrawlerPolicy {
+  pub robots: Option<String>,    //robots.txt 的内容
+  pub sitemap: Option<String>,   //sitemap.xml 的内容
+  pub passthrough: bool,         //产品中配置时不使用网关的默认值 没有配置内容的文件转发给 runtime
+  pub max_age_secs: Option<u64>, //Cache-Control 的 max-age 默认 3600
+}
+
+///网关直接返回的文件
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CrawlerFile {
+  content_type: &'static str,
+  content: String,
+  max_age_secs: u64,
+}
+
+lazy_static! {
+  static ref DEFAULTS: RwLock<CrawlerPolicy> = RwLock::new(CrawlerPolicy::default());
+}
+
+///需要转发给 runtime 时返回 None
+fn resolve(defaults: &CrawlerPolicy, product: &CrawlerPolicy, path: &str) -> Option<CrawlerFile> {
+  let (content_type, content, default) = match path {
+    "/robots.txt" => ("text/plain; charset=utf-8", &product.robots, &defaults.robots),
+    "/sitemap.xml" => ("application/xml; charset=utf-8", &product.sitemap, &defaults.sitemap),
+    _ => return None,
+  };
+  let content = match (content, product.passthrough) {
+    (Some(content), _) => content.clone(),
+    (None, false) => default.clone()?,
+    (None, true) => return None,
+  };
+  Some(CrawlerFile {
+    content_type,
+    content,
+    max_age_secs: product.max_age_secs.or(defaults.max_age_secs).unwrap_or(DEFAULT_MAX_AGE_SECS),
+  })
+}
+
+///内容的 sha256 前 16 位
+fn etag(content: &str) -> String {
+  let digest = ring::digest::digest(&ring::digest::SHA256, content.as_bytes());
+  format!("\"{}\"", &hex::encode(digest.as_ref())[..16])
+}
+
+///配置了内容时由网关返回 支持 If-None-Match
+pub fn respond(req: &HttpRequest, product: &CrawlerPolicy) -> Option<HttpResponse> {
+  if req.method() != Method::GET && req.method() != Method::HEAD {
+    return None;
+  }
+  let file = resolve(&DEFAULTS.read().unwrap(), product, req.uri().path())?;
+  let etag = etag(&file.content);
+  let cache_control = format!("public, max-age={}", file.max_age_secs);
+  let not_modified = req
+    .headers()
+    .get(IF_NONE_MATCH)
+    .and_then(|v| v.to_str().ok())
+    .map(|v| v.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"))
+    .unwrap_or(false);
+  let mut res = match not_modified {
+    true => HttpResponse::NotModified(),
+    false => HttpResponse::Ok(),
+  };
+  res.insert_header((ETAG, etag)).insert_header((CACHE_CONTROL, cache_control));
+  if not_modified {
+    return Some(res.finish());
+  }
+  Some(res.insert_header((CONTENT_TYPE, file.content_type)).body(file.content))
+}
+
+///读取网关的默认内容
+pub fn start() {
+  let defaults = GatewayConfig::load().map(|c| c.crawler).unwrap_or_default();
+  *DEFAULTS.write().unwrap() = defaults;
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn product_content_overrides_gateway_default() {
+    let defaults = CrawlerPolicy {
+      robots: Some("User-agent: *\nDisallow: /".to_string()),
+      max_age_secs: Some(600),
+      ..Default::default()
+    };
+    let shop = CrawlerPolicy {
+      sitemap: Some("<urlset/>".to_string()),
+      ..Default::default()
+    };
+    assert_eq!(resolve(&defaults, &shop, "/robots.txt").unwrap().content, "User-agent: *\nDisallow: /");
+    let sitemap = resolve(&defaults, &shop, "/sitemap.xml").unwrap();
+    assert_eq!((sitemap.content.as_str(), sitemap.max_age_secs), ("<urlset/>", 600));
+    let blog = CrawlerPolicy {
+      passthrough: true,
+      ..Default::default()
+    };
+    assert_eq!(resolve(&defaults, &blog, "/robots.txt"), None);
+    assert_eq!(resolve(&defaults, &shop, "/index.html"), None);
+  }
+}
@@ -0,0 +1,201 @@
+use crate::config::{product_entry, ProductConfig};
+use crate::worker_util::{run_tool, tool_flags};
+use awc::Client;
+use deno_core::error::{generic_error, AnyError};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use service::tools::deps::{collect_dependencies, DependencyInfo};
+
+const OSV_QUERY_BATCH_URL: &str = "https://api.osv.dev/v1/querybatch";
+const OSV_VULN_URL: &str = "https://api.osv.dev/v1/vulns";
+
+///漏洞等级 顺序即严重程度
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+  Unknown,
+  Low,
+  Moderate,
+  High,
+  Critical,
+}
+
+impl Severity {
+  fn from_advisory(value: &str) -> Self {
+    match value.to_ascii_lowercase().as_str() {
+      "low" => Severity::Low,
+      "moderate" | "medium" => Severity::Moderate,
+      "high" => Severity::High,
+      "critical" => Severity::Critical,
+      _ => Severity::Unknown,
+    }
+  }
+}
+
+///依赖审计策略 cool.json 中的 audit 配置
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuditPolicy {
+  pub block_severity: Option<Severity>, //达到该等级时阻止生产部署 不配置则只报告
+  pub ignore: Vec<String>,              //忽略的漏洞编号
+}
+
+///一条漏洞记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Finding {
+  pub registry: String,
+  pub package: String,
+  pub version: String,
+  pub advisory: String,
+  pub aliases: Vec<String>,
+  pub summary: Option<String>,
+  pub severity: Severity,
+}
+
+///审计结果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditReport {
+  pub product_code: String,
+  pub findings: Vec<Finding>,
+  pub unaudited: Vec<String>, //没有漏洞库可以查询的依赖 例如 deno.land/x
+  pub max_severity: Option<Severity>,
+  pub blocked: bool,
+}
+
+#[derive(Deserialize)]
+struct OsvBatchResponse {
+  #[serde(default)]
+  results: Vec<OsvBatchResult>,
+}
+
+#[derive(Deserialize)]
+struct OsvBatchResult {
+  #[serde(default)]
+  vulns: Vec<OsvVulnRef>,
+}
+
+#[derive(Deserialize)]
+struct OsvVulnRef {
+  id: String,
+}
+
+#[derive(Deserialize)]
+struct OsvVuln {
+  id: String,
+  #[serde(default)]
+  summary: Option<String>,
+  #[serde(default)]
+  aliases: Vec<String>,
+  #[serde(default)]
+  database_specific: Option<serde_json::Value>,
+}
+
+///依赖对应的 OSV 生态 没有对应生态的返回 None
+fn osv_ecosystem(dep: &DependencyInfo) -> Option<&'static str> {
+  match dep.registry.as_str() {
+    "npm" => Some("npm"),
+    _ => None,
+  }
+}
+
+///分析产品模块图并到 OSV 查询所有依赖的漏洞
+pub async fn audit_product(product_code: &str) -> Result<AuditReport, AnyError> {
+  let config = ProductConfig::load(product_code)?;
+  let entry = product_entry(product_code);
+  let deps = run_tool(format!("product-{}-audit", product_code), move || async move {
+    collect_dependencies(tool_flags("run", &entry)?).await
+  })
+  .await?;
+
+  let mut queries = vec![];
+  let mut audited = vec![];
+  let mut unaudited = vec![];
+  for dep in deps.iter() {
+    match (osv_ecosystem(dep), &dep.version) {
+      (Some(ecosystem), Some(version)) => {
+        queries.push(json!({ "package": { "name": dep.name, "ecosystem": ecosystem }, "version": version }));
+        audited.push(dep);
+      }
+      (None, _) if dep.registry == "node" => {}
+      _ => unaudited.push(format!("{}:{}@{}", dep.registry, dep.name, dep.version.clone().unwrap_or_default())),
+    }
+  }
+
+  let client = Client::default();
+  let mut findings = vec![];
+  if !queries.is_empty() {
+    let batch: OsvBatchResponse = client
+      .post(OSV_QUERY_BATCH_URL)
+      .send_json(&json!({ "queries": queries }))
+      .await
+      .map_err(|err| generic_error(format!("osv request failed: {}", err)))?
+      .json()
+      .limit(16 * 1024 * 1024)
+      .await
+      .map_err(|err| generic_error(format!("osv response invalid: {}", err)))?;
+    for (dep, result) in audited.into_iter().zip(batch.results) {
+      for vuln in result.vulns {
+        if config.audit.ignore.contains(&vuln.id) {
+          continue;
+        }
+        let detail: OsvVuln = client
+          .get(format!("{}/{}", OSV_VULN_URL, vuln.id))
+          .send()
+          .await
+          .map_err(|err| generic_error(format!("osv request failed: {}", err)))?
+          .json()
+          .limit(4 * 1024 * 1024)
+          .await
+          .map_err(|err| generic_error(format!("osv response invalid: {}", err)))?;
+        let severity = detail
+          .database_specific
+          .as_ref()
+          .and_then(|d| d.get("severity"))
+          .and_then(|s| s.as_str())
+          .map(Severity::from_advisory)
+          .unwrap_or(Severity::Unknown);
+        findings.push(Finding {
+          registry: dep.registry.clone(),
+          package: dep.name.clone(),
+          version: dep.version.clone().unwrap_or_default(),
+          advisory: detail.id,
+          aliases: detail.aliases,
+          summary: detail.summary,
+          severity,
+        });
+      }
+    }
+  }
+  findings.sort_by(|a, b| b.severity.cmp(&a.severity));
+  let max_severity = findings.first().map(|f| f.severity);
+  let blocked = match (config.audit.block_severity, max_severity) {
+    (Some(threshold), Some(max)) => max >= threshold,
+    _ => false,
+  };
+  Ok(AuditReport {
+    product_code: product_code.to_string(),
+    findings,
+    unaudited,
+    max_severity,
+    blocked,
+  })
+}
+
+///生产部署前的审计门禁 只有配置了 block_severity 才会执行
+pub async fn ensure_deployable(product_code: &str) -> Result<(), AnyError> {
+  let config = ProductConfig::load(product_code)?;
+  if config.audit.block_severity.is_none() {
+    return Ok(());
+  }
+  let report = audit_product(product_code).await?;
+  if report.blocked {
+    let advisories: Vec<String> = report
+      .findings
+      .iter()
+      .filter(|f| Some(f.severity) >= config.audit.block_severity)
+      .map(|f| format!("{}@{} {}", f.package, f.version, f.advisory))
+      .collect();
+    return Err(generic_error(format!("deploy blocked by dependency audit: {}", advisories.join(", "))));
+  }
+  Ok(())
+}
@@ -0,0 +1,303 @@
+use crate::config::{module_pins_path, product_dir, product_entry};
+use crate::versions;
+use crate::worker_util::{run_tool, tool_flags};
+use awc::Client;
+use deno_core::error::{custom_error, generic_error, AnyError};
+use deno_runtime::at_rest;
+use serde::{Deserialize, Serialize};
+use service::tools::deps::{collect_dependencies, DependencyInfo, DependencyKind};
+use std::collections::BTreeMap;
+use walkdir::WalkDir;
+
+const NPM_REGISTRY_URL: &str = "https://registry.npmjs.org";
+const DENO_LAND_CDN_URL: &str = "https://cdn.deno.land";
+///直接分发 npm 包的 CDN 与 service::tools::deps 中的识别规则一致
+const NPM_CDN_HOSTS: [&str; 4] = ["esm.sh/", "cdn.skypack.dev/", "unpkg.com/", "cdn.jsdelivr.net/"];
+///会写导入说明符的文件
+const SOURCE_EXTENSIONS: [&str; 10] = ["ts", "tsx", "mts", "cts", "js", "jsx", "mjs", "cjs", "json", "jsonc"];
+
+///产品的一个依赖和可以升级到的版本
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutdatedDependency {
+  pub kind: DependencyKind,
+  pub registry: String,
+  pub name: String,
+  pub current: Option<String>,
+  pub latest: Option<String>, //查询不到时为空
+  pub outdated: bool,
+  pub declared: bool, //产品代码中直接写了版本 只有这些依赖可以通过接口升级 其余为传递依赖
+  pub specifiers: Vec<String>,
+}
+
+///选择升级的依赖
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateSelection {
+  pub registry: String,
+  pub name: String,
+  pub version: String, //写入说明符的版本 deno.land 的包需要带上 v 前缀时由调用方决定
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateRequest {
+  pub updates: Vec<UpdateSelection>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdatedFile {
+  pub path: String,
+  pub replacements: usize,
+}
+
+///升级结果 base_version 到 version 之间只有依赖版本的修改
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateReport {
+  pub product_code: String,
+  pub applied: bool,
+  pub base_version: Option<String>, //升级前的代码
+  pub version: Option<String>,      //升级后的代码 可以直接部署
+  pub files: Vec<UpdatedFile>,
+  pub pins_removed: usize, //锁定表中不再使用的旧版本 新版本在下次加载时锁定
+}
+
+#[derive(Deserialize)]
+struct NpmLatest {
+  version: String,
+}
+
+#[derive(Deserialize)]
+struct DenoLandVersions {
+  latest: Option<String>,
+}
+
+///包是否由这个说明符前缀导入 prefix 为同一个说明符中包名之前的部分
+fn matches_prefix(registry: &str, prefix: &str) -> bool {
+  match registry {
+    "deno.land/x" => prefix.ends_with("deno.land/x/"),
+    "deno.land/std" => prefix.ends_with("deno.land/"),
+    "npm" => prefix.ends_with("npm:") || (prefix.ends_with('/') && NPM_CDN_HOSTS.iter().any(|host| prefix.contains(host))),
+    host => prefix.ends_with(&format!("{}/", host)),
+  }
+}
+
+fn is_delimiter(c: char) -> bool {
+  c.is_whitespace() || matches!(c, '/' | '"' | '\'' | '`' | '?' | '#' | ')' | ',')
+}
+
+///把文本中导入这个包的说明符改为新版本 返回新文本和替换次数<br>
+/// 版本写成范围时同样替换 例如 npm:preact@^10
+pub fn rewrite_specifiers(text: &str, registry: &str, name: &str, version: &str) -> (String, usize) {
+  let needle = format!("{}@", name);
+  let mut result = String::with_capacity(text.len());
+  let mut count = 0;
+  let mut rest = text;
+  while let Some(index) = rest.find(&needle) {
+    let (before, after) = rest.split_at(index);
+    let start = before
+      .rfind(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '`' | '('))
+      .map(|i| i + 1)
+      .unwrap_or(0);
+    let after = &after[needle.len()..];
+    let end = after.find(is_delimiter).unwrap_or(after.len());
+    result.push_str(before);
+    result.push_str(&needle);
+    if matches_prefix(registry, &before[start..]) && end > 0 {
+      result.push_str(version);
+      count += 1;
+    } else {
+      result.push_str(&after[..end]);
+    }
+    rest = &after[end..];
+  }
+  result.push_str(rest);
+  (result, count)
+}
+
+///产品代码中可能写了导入说明符的文件 解密后读取
+fn read_sources(product_code: &str) -> Result<Vec<(String, String)>, AnyError> {
+  let dir = product_dir(product_code);
+  if !dir.is_dir() {
+    return Err(custom_error("NotFound", format!("product {} not found", product_code)));
+  }
+  let mut sources = vec![];
+  let entries = WalkDir::new(&dir).sort_by_file_name().into_iter().filter_map(|e| e.ok());
+  for entry in entries.filter(|e| e.file_type().is_file()) {
+    let ext = entry.path().extension().and_then(|e| e.to_str()).unwrap_or_default();
+    if !SOURCE_EXTENSIONS.contains(&ext) {
+      continue;
+    }
+    let bytes = at_rest::decrypt(std::fs::read(entry.path())?)?;
+    if let Ok(text) = String::from_utf8(bytes) {
+      let rel = entry.path().strip_prefix(&dir).unwrap().to_string_lossy().replace('\\', "/");
+      sources.push((rel, text));
+    }
+  }
+  Ok(sources)
+}
+
+async fn latest_version(client: &Client, dep: &DependencyInfo) -> Result<Option<String>, AnyError> {
+  let url = match dep.registry.as_str() {
+    "npm" => format!("{}/{}/latest", NPM_REGISTRY_URL, dep.name.replace('/', "%2F")),
+    "deno.land/x" | "deno.land/std" => format!("{}/{}/meta/versions.json", DENO_LAND_CDN_URL, dep.name),
+    _ => return Ok(None),
+  };
+  let mut res = client.get(url).send().await.map_err(|err| generic_error(err.to_string()))?;
+  if !res.status().is_success() {
+    return Ok(None);
+  }
+  let latest = match dep.registry.as_str() {
+    "npm" => Some(res.json::<NpmLatest>().await.map_err(|err| generic_error(err.to_string()))?.version),
+    _ => {
+      let versions: DenoLandVersions = res.json().limit(8 * 1024 * 1024).await.map_err(|err| generic_error(err.to_string()))?;
+      versions.latest
+    }
+  };
+  Ok(latest)
+}
+
+///列出产品的远程 deno.land 和 npm 依赖 当前版本与最新版本
+pub async fn outdated(product_code: &str) -> Result<Vec<OutdatedDependency>, AnyError> {
+  let code = product_code.to_string();
+  let sources = tokio::task::spawn_blocking(move || read_sources(&code)).await??;
+  let entry = product_entry(product_code);
+  let deps = run_tool(format!("product-{}-outdated", product_code), move || async move {
+    collect_dependencies(tool_flags("run", &entry)?).await
+  })
+  .await?;
+  let client = Client::default();
+  let mut result = vec![];
+  for dep in deps.into_iter().filter(|d| d.kind != DependencyKind::Node) {
+    let latest = match latest_version(&client, &dep).await {
+      Ok(latest) => latest,
+      Err(err) => {
+        log::warn!("failed to query latest version of {}: {}", dep.name, err);
+        None
+      }
+    };
+    let outdated = match (&dep.version, &latest) {
+      (Some(current), Some(latest)) => current.as_str() != latest.trim_start_matches('v'),
+      _ => false,
+    };
+    let declared = sources
+      .iter()
+      .any(|(_, text)| rewrite_specifiers(text, &dep.registry, &dep.name, "").1 > 0);
+    result.push(OutdatedDependency {
+      kind: dep.kind,
+      registry: dep.registry,
+      name: dep.name,
+      current: dep.version,
+      latest,
+      outdated,
+      declared,
+      specifiers: dep.specifiers,
+    });
+  }
+  Ok(result)
+}
+
+fn validate(selection: &UpdateSelection) -> Result<(), AnyError> {
+  let valid = !selection.version.is_empty()
+    && selection
+      .version
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+' | '_' | '^' | '~'));
+  if !valid {
+    return Err(generic_error(format!("invalid version {} for {}", selection.version, selection.name)));
+  }
+  Ok(())
+}
+
+///锁定表中属于被升级包的旧地址 新地址在下次加载时重新锁定
+fn prune_pins(product_code: &str, updates: &[UpdateSelection]) -> Result<usize, AnyError> {
+  let path = module_pins_path(product_code);
+  let mut pins: BTreeMap<String, String> = match std::fs::read_to_string(&path) {
+    Ok(text) => serde_json::from_str(&text)?,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+    Err(err) => return Err(err.into()),
+  };
+  let before = pins.len();
+  pins.retain(|url, _| !updates.iter().any(|u| rewrite_specifiers(url, &u.registry, &u.name, &u.version).1 > 0));
+  let removed = before - pins.len();
+  if removed > 0 {
+    std::fs::write(&path, serde_json::to_string_pretty(&pins)?)?;
+  }
+  Ok(removed)
+}
+
+///把选中的依赖改为新版本 <br>
+/// 修改前后各保存一个版本 两个版本之间只有依赖版本的修改 之后按正常流程部署新版本<br>
+/// dry_run 时只返回会修改的文件
+pub async fn apply(product_code: &str, request: &UpdateRequest, dry_run: bool) -> Result<UpdateReport, AnyError> {
+  if request.updates.is_empty() {
+    return Err(generic_error("no dependency selected"));
+  }
+  for selection in &request.updates {
+    validate(selection)?;
+  }
+  let code = product_code.to_string();
+  let sources = tokio::task::spawn_blocking(move || read_sources(&code)).await??;
+  let mut updated = vec![];
+  let mut files = vec![];
+  let mut found = vec![false; request.updates.len()];
+  for (path, text) in sources {
+    let mut text = text;
+    let mut replacements = 0;
+    for (i, selection) in request.updates.iter().enumerate() {
+      let (rewritten, count) = rewrite_specifiers(&text, &selection.registry, &selection.name, &selection.version);
+      found[i] |= count > 0;
+      replacements += count;
+      text = rewritten;
+    }
+    if replacements > 0 {
+      files.push(UpdatedFile {
+        path: path.clone(),
+        replacements,
+      });
+      updated.push((path, text));
+    }
+  }
+  if let Some(i) = found.iter().position(|f| !f) {
+    let name = &request.updates[i].name;
+    return Err(generic_error(format!("{} is not imported with a version by product code", name)));
+  }
+  let mut report = UpdateReport {
+    product_code: product_code.to_string(),
+    applied: false,
+    base_version: None,
+    version: None,
+    files,
+    pins_removed: 0,
+  };
+  if dry_run {
+    return Ok(report);
+  }
+  report.base_version = Some(versions::snapshot(product_code).await?);
+  let dir = product_dir(product_code);
+  for (path, text) in updated {
+    tokio::fs::write(dir.join(path), at_rest::encrypt(text.as_bytes())?).await?;
+  }
+  report.pins_removed = prune_pins(product_code, &request.updates)?;
+  report.version = Some(versions::snapshot(product_code).await?);
+  report.applied = true;
+  Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn rewrites_versioned_specifiers() {
+    let text = r#"import { h } from "npm:preact@^10.5.0";
+import { useState } from "https://esm.sh/v125/preact@10.5.0/hooks";
+import { Application } from "https://deno.land/x/oak@v12.1.0/mod.ts";
+import "https://deno.land/x/react@1.0.0/mod.ts";"#;
+    let (rewritten, count) = rewrite_specifiers(text, "npm", "preact", "10.19.2");
+    assert_eq!(count, 2);
+    assert!(rewritten.contains("npm:preact@10.19.2\""));
+    assert!(rewritten.contains("esm.sh/v125/preact@10.19.2/hooks"));
+    let (rewritten, count) = rewrite_specifiers(&rewritten, "deno.land/x", "oak", "v12.6.1");
+    assert_eq!(count, 1);
+    assert!(rewritten.contains("deno.land/x/oak@v12.6.1/mod.ts"));
+    assert_eq!(rewrite_specifiers(text, "npm", "react", "18.0.0").1, 0);
+  }
+}
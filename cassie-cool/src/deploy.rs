@@ -0,0 +1,290 @@
+use crate::config::{data_dir, ProductConfig};
+use crate::list_query::ListSpec;
+use crate::pipeline::{run_pipeline, PipelineResult, Step, StepStatus};
+use crate::roles::{self, Role};
+use crate::smoke::{run_smoke_tests, SmokeResult};
+use crate::util::now_millis;
+use crate::versions;
+use crate::worker_util::{Project, ScriptWorkerId, ScriptWorkerThread, WORKER_TABLE};
+use crate::{bench, dep_audit, licenses, node_compat, offline, routes, size_budget};
+use deno_core::error::AnyError;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use tokio::io::AsyncWriteExt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployStatus {
+  Deployed,   //部署成功
+  Blocked,    //流水线必需步骤失败 未切换
+  RolledBack, //冒烟测试失败 已回滚到上一个版本
+  Aborted,    //批量部署中其他产品失败 没有切换
+}
+
+///一次部署的记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeployRecord {
+  pub product_code: String,
+  pub author: Option<String>,
+  pub version: Option<String>,
+  pub previous_version: Option<String>,
+  pub created_at: u64,
+  pub status: DeployStatus,
+  pub pipeline: PipelineResult,
+  pub smoke_tests: Vec<SmokeResult>,
+}
+
+///部署记录列表的排序和过滤 owner 按部署的作者过滤
+pub const LIST_SPEC: ListSpec = ListSpec {
+  id: "created_at",
+  sort: &["created_at", "version", "status"],
+  default_sort: "created_at",
+  status: Some("status"),
+  tag: None,
+  owner: Some("author"),
+};
+
+///部署历史 data/history/{product_code}.jsonl 每行一条记录
+pub fn history_path(product_code: &str) -> PathBuf {
+  let mut path = data_dir();
+  path.push("history");
+  path.push(format!("{}.jsonl", product_code));
+  path
+}
+
+pub async fn append_history(record: &DeployRecord) -> Result<(), AnyError> {
+  let path = history_path(&record.product_code);
+  tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+  let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+  let mut line = serde_json::to_vec(record)?;
+  line.push(b'\n');
+  file.write_all(&line).await?;
+  Ok(())
+}
+
+///读取部署历史 按时间先后
+pub async fn read_history(product_code: &str) -> Result<Vec<DeployRecord>, AnyError> {
+  let text = match tokio::fs::read_to_string(history_path(product_code)).await {
+    Ok(text) => text,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+    Err(err) => return Err(err.into()),
+  };
+  Ok(text.lines().filter(|l| !l.is_empty()).filter_map(|l| serde_json::from_str(l).ok()).collect())
+}
+
+///产品当前运行的部署 id 为部署记录的 created_at 跟随主实例的副本没有部署记录
+#[derive(Debug, Clone, Default)]
+pub struct Deployment {
+  pub id: Option<String>,
+  pub version: Option<String>,
+}
+
+lazy_static! {
+  //切换 runtime 时持有 WORKER_TABLE 的锁并等待 并发执行的切换需要先排队
+  static ref SWITCH: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+  static ref CURRENT: RwLock<HashMap<String, Deployment>> = RwLock::new(HashMap::new());
+}
+
+///当前运行的部署 网关启动后第一次使用时从部署历史读取
+pub async fn current_deployment(product_code: &str) -> Deployment {
+  if let Some(deployment) = CURRENT.read().unwrap().get(product_code) {
+    return deployment.clone();
+  }
+  let deployment = match read_history(product_code).await {
+    Ok(history) => history
+      .into_iter()
+      .rev()
+      .find(|r| r.status == DeployStatus::Deployed)
+      .map(|r| Deployment {
+        id: Some(r.created_at.to_string()),
+        version: r.version,
+      })
+      .unwrap_or_default(),
+    Err(err) => {
+      log::warn!("failed to read deploy history of {}: {}", product_code, err);
+      return Deployment::default();
+    }
+  };
+  //读取期间切换的部署优先
+  CURRENT.write().unwrap().entry(product_code.to_string()).or_insert(deployment).clone()
+}
+
+///切换 runtime 后记录当前运行的部署 None 表示下次使用时从部署历史读取
+pub fn set_current_deployment(product_code: &str, deployment: Option<Deployment>) {
+  let mut current = CURRENT.write().unwrap();
+  match deployment {
+    Some(deployment) => current.insert(product_code.to_string(), deployment),
+    None => current.remove(product_code),
+  };
+}
+
+///切换生产 runtime 没有 worker 时新建
+pub async fn swap_product_runtime(product_code: &str) {
+  let _switch = SWITCH.lock().await;
+  let mut script_table = WORKER_TABLE.lock().unwrap();
+  match script_table.get_mut(&ScriptWorkerId(product_code.to_string())) {
+    Some(w) => {
+      w.swap_runtime().await;
+    }
+    None => {
+      let mut worker = ScriptWorkerThread::new(Project::from_product(product_code));
+      worker.start_runtime().await;
+      script_table.insert(worker.id.clone(), worker);
+    }
+  }
+}
+
+///启动生产 runtime 已有 runtime 时再启动一个
+pub async fn start_product_runtime(product_code: &str) {
+  let _switch = SWITCH.lock().await;
+  let mut script_table = WORKER_TABLE.lock().unwrap();
+  match script_table.get_mut(&ScriptWorkerId(product_code.to_string())) {
+    Some(w) => {
+      w.start_runtime().await;
+    }
+    None => {
+      let mut worker = ScriptWorkerThread::new(Project::from_product(product_code));
+      worker.start_runtime().await;
+      script_table.insert(worker.id.clone(), worker);
+    }
+  }
+}
+
+///平滑替换一个生产 runtime 先启动新的再停止 notify 对应的 runtime 它已经停止时返回 false
+pub async fn replace_product_runtime(product_code: &str, notify: &async_channel::Sender<u8>) -> bool {
+  let _switch = SWITCH.lock().await;
+  let mut script_table = WORKER_TABLE.lock().unwrap();
+  match script_table.get_mut(&ScriptWorkerId(product_code.to_string())) {
+    Some(w) => w.replace_runtime(notify).await,
+    None => false,
+  }
+}
+
+///停止产品的所有生产 runtime 返回停止的个数
+pub async fn stop_product_runtime(product_code: &str) -> usize {
+  let _switch = SWITCH.lock().await;
+  let mut script_table = WORKER_TABLE.lock().unwrap();
+  let mut stopped = 0;
+  if let Some(w) = script_table.get_mut(&ScriptWorkerId(product_code.to_string())) {
+    while w.stop_runtime() {
+      stopped += 1;
+    }
+  }
+  stopped
+}
+
+///校验所有角色入口 声明了路由清单时校验清单 声明了 http 入口时不再使用 runtime.entry
+fn validate_entry(config: &ProductConfig, product_code: &str) -> Result<(), AnyError> {
+  roles::validate(product_code, &config.entries)?;
+  if !config.routes.is_empty() {
+    config.runtime.validate_cwd(product_code)?;
+    return routes::validate(product_code, &config.routes);
+  }
+  match config.entries.iter().any(|e| e.role == Role::Http) {
+    true => config.runtime.validate_cwd(product_code),
+    false => config.runtime.validate(product_code),
+  }
+}
+
+///生产部署前的门禁 启动参数 离线 vendor 依赖漏洞 许可证 npm 安装脚本 体积预算 只执行产品配置了的检查
+pub async fn ensure_deployable(product_code: &str) -> Result<(), AnyError> {
+  validate_entry(&ProductConfig::load(product_code)?, product_code)?;
+  offline::ensure_vendored(product_code).await?;
+  dep_audit::ensure_deployable(product_code).await?;
+  licenses::ensure_deployable(product_code).await?;
+  node_compat::ensure_deployable(product_code).await?;
+  size_budget::ensure_deployable(product_code).await?;
+  Ok(())
+}
+
+///部署的第一步 执行流水线 必需步骤失败时记录的状态为 Blocked
+pub async fn prepare(product_code: &str, author: Option<String>) -> Result<(ProductConfig, DeployRecord), AnyError> {
+  let config = ProductConfig::load(product_code)?;
+  validate_entry(&config, product_code)?;
+  config.node.validate()?;
+  offline::ensure_vendored(product_code).await?;
+  let pipeline = run_pipeline(product_code).await?;
+  //回滚到上一次成功的部署 回滚过的版本快照仍然保留 不能按最新快照回滚
+  let previous_version = current_deployment(product_code).await.version;
+  let record = DeployRecord {
+    product_code: product_code.to_string(),
+    author,
+    version: None,
+    previous_version,
+    created_at: now_millis(),
+    status: DeployStatus::Blocked,
+    pipeline,
+    smoke_tests: vec![],
+  };
+  Ok((config, record))
+}
+
+///保存版本并切换 runtime 流水线运行了基准测试时结果记为这个版本的结果 供之后的部署对比
+pub async fn switch(record: &mut DeployRecord) -> Result<(), AnyError> {
+  let version = versions::snapshot(&record.product_code).await?;
+  let benched = record
+    .pipeline
+    .steps
+    .iter()
+    .any(|s| s.step == Step::Bench && s.status != StepStatus::Skipped);
+  if benched {
+    if let Err(err) = bench::keep_for_version(&record.product_code, &version).await {
+      log::warn!("{} failed to keep bench results: {}", record.product_code, err);
+    }
+  }
+  record.version = Some(version);
+  swap_product_runtime(&record.product_code).await;
+  record.status = DeployStatus::Deployed;
+  let deployment = Deployment {
+    id: Some(record.created_at.to_string()),
+    version: record.version.clone(),
+  };
+  set_current_deployment(&record.product_code, Some(deployment));
+  Ok(())
+}
+
+///切换后执行冒烟测试 没有配置时视为通过
+pub async fn verify(config: &ProductConfig, record: &mut DeployRecord) -> bool {
+  if config.smoke_tests.is_empty() {
+    return true;
+  }
+  record.smoke_tests = run_smoke_tests(&record.product_code, &config.smoke_tests, &config.smoke).await;
+  record.smoke_tests.iter().all(|r| r.passed)
+}
+
+///恢复上一个版本的代码并再次切换 没有上一个版本时保持当前版本
+pub async fn rollback(record: &mut DeployRecord) -> Result<(), AnyError> {
+  match &record.previous_version {
+    Some(previous) => {
+      log::warn!("rolling back {} to {}", record.product_code, previous);
+      versions::restore(&record.product_code, previous).await?;
+      swap_product_runtime(&record.product_code).await;
+      record.status = DeployStatus::RolledBack;
+      //回滚后运行的是上一次成功的部署
+      set_current_deployment(&record.product_code, None);
+    }
+    None => log::warn!("{} has no previous version to roll back to", record.product_code),
+  }
+  Ok(())
+}
+
+///部署产品 <br>
+/// 流水线通过后保存版本并切换 runtime 然后执行冒烟测试<br>
+/// 冒烟测试失败时恢复上一个版本的代码并再次切换
+pub async fn deploy_product(product_code: &str, author: Option<String>) -> Result<DeployRecord, AnyError> {
+  let (config, mut record) = prepare(product_code, author).await?;
+  if !record.pipeline.success {
+    append_history(&record).await?;
+    return Ok(record);
+  }
+  switch(&mut record).await?;
+  if !verify(&config, &mut record).await {
+    log::warn!("{} smoke tests failed", product_code);
+    rollback(&mut record).await?;
+  }
+  append_history(&record).await?;
+  Ok(record)
+}
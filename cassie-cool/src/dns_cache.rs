@@ -0,0 +1,268 @@
+use crate::config::ProductConfig;
+use crate::metrics;
+use deno_runtime::deno_fetch::reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use deno_runtime::deno_fetch::{set_dns_resolver, DnsResolver};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+///runtime fetch 的 DNS 缓存 cool.json 中的 dns<br>
+/// 同一产品的 runtime 共用一个缓存 上游 DNS 解析失败时在 stale_secs 内继续使用过期的结果
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DnsCacheConfig {
+  pub enabled: bool,
+  pub ttl_secs: u64,          //解析结果的缓存时间
+  pub negative_ttl_secs: u64, //解析失败的缓存时间 为 0 时不缓存失败
+  pub stale_secs: u64,        //过期后解析失败时继续使用旧结果的时间
+}
+
+impl Default for DnsCacheConfig {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      ttl_secs: 60,
+      negative_ttl_secs: 5,
+      stale_secs: 300,
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+enum Cached {
+  Found(Vec<IpAddr>),
+  Failed(String),
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+  cached: Cached,
+  resolved_at: Instant,
+}
+
+///缓存的状态 计数从网关启动或配置变化时开始
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DnsCacheStats {
+  pub product_code: String,
+  pub config: DnsCacheConfig,
+  pub entries: usize,
+  pub hits: u64,
+  pub misses: u64,
+  pub negative_hits: u64, //命中缓存的解析失败
+  pub stale_hits: u64,    //解析失败时使用了过期的结果
+}
+
+pub struct DnsCache {
+  product_code: String,
+  config: DnsCacheConfig,
+  entries: Mutex<HashMap<String, Entry>>,
+  hits: AtomicU64,
+  misses: AtomicU64,
+  negative_hits: AtomicU64,
+  stale_hits: AtomicU64,
+}
+
+lazy_static! {
+  static ref CACHES: Mutex<HashMap<String, Arc<DnsCache>>> = Mutex::new(HashMap::new());
+}
+
+impl DnsCache {
+  fn new(product_code: &str, config: DnsCacheConfig) -> Self {
+    Self {
+      product_code: product_code.to_string(),
+      config,
+      entries: Mutex::new(HashMap::new()),
+      hits: AtomicU64::new(0),
+      misses: AtomicU64::new(0),
+      negative_hits: AtomicU64::new(0),
+      stale_hits: AtomicU64::new(0),
+    }
+  }
+
+  fn count(&self, counter: &AtomicU64, result: &str) {
+    counter.fetch_add(1, Ordering::Relaxed);
+    metrics::inc_counter(
+      "runtime_dns_cache_lookups_total",
+      "DNS lookups of runtime fetch by cache result",
+      &[("product", &self.product_code), ("result", result)],
+      1,
+    );
+  }
+
+  ///没有过期的结果 包括缓存的解析失败
+  fn get(&self, host: &str, now: Instant) -> Option<Result<Vec<IpAddr>, String>> {
+    let entries = self.entries.lock().unwrap();
+    let entry = entries.get(host)?;
+    let age = now.saturating_duration_since(entry.resolved_at);
+    match &entry.cached {
+      Cached::Found(ips) if age < Duration::from_secs(self.config.ttl_secs) => Some(Ok(ips.clone())),
+      Cached::Failed(err) if age < Duration::from_secs(self.config.negative_ttl_secs) => Some(Err(err.clone())),
+      _ => None,
+    }
+  }
+
+  ///保存解析结果 失败时如果有没超过 stale_secs 的旧结果就继续使用
+  fn update(&self, host: &str, result: Result<Vec<IpAddr>, String>, now: Instant) -> Result<Vec<IpAddr>, String> {
+    let mut entries = self.entries.lock().unwrap();
+    let err = match result {
+      Ok(ips) => {
+        let entry = Entry {
+          cached: Cached::Found(ips.clone()),
+          resolved_at: now,
+        };
+        entries.insert(host.to_string(), entry);
+        return Ok(ips);
+      }
+      Err(err) => err,
+    };
+    if let Some(Entry {
+      cached: Cached::Found(ips),
+      resolved_at,
+    }) = entries.get(host)
+    {
+      let stale_until = Duration::from_secs(self.config.ttl_secs + self.config.stale_secs);
+      if now.saturating_duration_since(*resolved_at) < stale_until {
+        self.count(&self.stale_hits, "stale");
+        return Ok(ips.clone());
+      }
+    }
+    match self.config.negative_ttl_secs {
+      0 => {
+        entries.remove(host);
+      }
+      _ => {
+        let entry = Entry {
+          cached: Cached::Failed(err.clone()),
+          resolved_at: now,
+        };
+        entries.insert(host.to_string(), entry);
+      }
+    }
+    Err(err)
+  }
+
+  async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>, String> {
+    match self.get(host, Instant::now()) {
+      Some(Ok(ips)) => {
+        self.count(&self.hits, "hit");
+        return Ok(ips);
+      }
+      Some(Err(err)) => {
+        self.count(&self.negative_hits, "negative");
+        return Err(err);
+      }
+      None => self.count(&self.misses, "miss"),
+    }
+    let result = match tokio::net::lookup_host((host, 0)).await {
+      Ok(addrs) => Ok(addrs.map(|addr| addr.ip()).collect::<Vec<_>>()),
+      Err(err) => Err(err.to_string()),
+    };
+    let result = self.update(host, result, Instant::now());
+    self.set_entries_gauge();
+    result
+  }
+
+  fn set_entries_gauge(&self) {
+    let entries = self.entries.lock().unwrap().len();
+    metrics::set_gauge(
+      "runtime_dns_cache_entries",
+      "Hosts in the DNS cache of runtime fetch",
+      &[("product", &self.product_code)],
+      entries as f64,
+    );
+  }
+
+  fn stats(&self) -> DnsCacheStats {
+    DnsCacheStats {
+      product_code: self.product_code.clone(),
+      config: self.config.clone(),
+      entries: self.entries.lock().unwrap().len(),
+      hits: self.hits.load(Ordering::Relaxed),
+      misses: self.misses.load(Ordering::Relaxed),
+      negative_hits: self.negative_hits.load(Ordering::Relaxed),
+      stale_hits: self.stale_hits.load(Ordering::Relaxed),
+    }
+  }
+}
+
+struct CacheResolver(Arc<DnsCache>);
+
+impl Resolve for CacheResolver {
+  fn resolve(&self, name: Name) -> Resolving {
+    let cache = self.0.clone();
+    let host = name.as_str().to_ascii_lowercase();
+    Box::pin(async move {
+      let ips = cache.lookup(&host).await.map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+      let addrs: Addrs = Box::new(ips.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+      Ok::<_, Box<dyn std::error::Error + Send + Sync>>(addrs)
+    })
+  }
+}
+
+///在 runtime 线程里调用 开启时这个线程创建的 fetch 客户端使用产品的缓存<br>
+/// 配置变化后重新创建缓存
+pub fn apply(product_code: &str) {
+  let config = ProductConfig::load(product_code).map(|c| c.dns).unwrap_or_default();
+  if !config.enabled {
+    CACHES.lock().unwrap().remove(product_code);
+    set_dns_resolver(None);
+    return;
+  }
+  let cache = {
+    let mut caches = CACHES.lock().unwrap();
+    match caches.get(product_code) {
+      Some(cache) if cache.config == config => cache.clone(),
+      _ => {
+        let cache = Arc::new(DnsCache::new(product_code, config));
+        caches.insert(product_code.to_string(), cache.clone());
+        cache
+      }
+    }
+  };
+  set_dns_resolver(Some(DnsResolver(Arc::new(CacheResolver(cache)))));
+}
+
+///产品缓存的状态 没有开启时为 None
+pub fn stats(product_code: &str) -> Option<DnsCacheStats> {
+  CACHES.lock().unwrap().get(product_code).map(|cache| cache.stats())
+}
+
+///清空产品的缓存 返回清除的主机数
+pub fn flush(product_code: &str) -> usize {
+  let cache = match CACHES.lock().unwrap().get(product_code) {
+    Some(cache) => cache.clone(),
+    None => return 0,
+  };
+  let flushed = std::mem::take(&mut *cache.entries.lock().unwrap()).len();
+  cache.set_entries_gauge();
+  flushed
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn caches_with_negative_and_stale_entries() {
+    let cache = DnsCache::new("dns-test", DnsCacheConfig::default());
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+    let start = Instant::now();
+    assert_eq!(cache.get("api.internal", start), None);
+    assert_eq!(cache.update("api.internal", Ok(vec![ip]), start), Ok(vec![ip]));
+    assert_eq!(cache.get("api.internal", start + Duration::from_secs(30)), Some(Ok(vec![ip])));
+    let expired = start + Duration::from_secs(90);
+    assert_eq!(cache.get("api.internal", expired), None);
+    //上游 DNS 抖动时继续使用旧结果
+    assert_eq!(cache.update("api.internal", Err("timeout".to_string()), expired), Ok(vec![ip]));
+    assert_eq!(cache.stats().stale_hits, 1);
+    let gone = start + Duration::from_secs(400);
+    assert_eq!(cache.update("api.internal", Err("timeout".to_string()), gone), Err("timeout".to_string()));
+    assert_eq!(cache.get("api.internal", gone + Duration::from_secs(1)), Some(Err("timeout".to_string())));
+    assert_eq!(cache.get("api.internal", gone + Duration::from_secs(10)), None);
+  }
+}
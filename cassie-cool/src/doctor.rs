@@ -0,0 +1,378 @@
+use crate::config::{data_dir, product_dir, GatewayConfig, ProductConfig, HTTP_BIND};
+use crate::util::{list_dir, read_json, write_json};
+use crate::{artifacts, audit_log, catalog, encryption, mtls, state_snapshot, storage, versions};
+use deno_core::error::{generic_error, AnyError};
+use deno_runtime::at_rest;
+use deno_runtime::deno_fetch::reqwest;
+use serde::{Deserialize, Serialize};
+use std::net::TcpListener;
+use std::time::Duration;
+use walkdir::WalkDir;
+
+///data 目录中持久化数据的格式 不兼容的修改时加一
+pub const STATE_FORMAT: u32 = 1;
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+///自检 gateway.json 中的 doctor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DoctorConfig {
+  pub time_url: String,               //用响应的 Date 头检查时钟偏差
+  pub max_clock_skew_secs: u64,       //超过时报告错误
+  pub acme_directory: Option<String>, //申请证书的 ACME 目录 配置后检查能否访问
+}
+
+impl Default for DoctorConfig {
+  fn default() -> Self {
+    Self {
+      time_url: "https://registry.npmjs.org".to_string(),
+      max_clock_skew_secs: 30,
+      acme_directory: None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+  Ok,
+  Warning,
+  Error,
+  Skipped,
+}
+
+///一项检查的结果 hint 为处理建议
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Finding {
+  pub check: String,
+  pub status: Status,
+  pub message: String,
+  pub hint: Option<String>,
+}
+
+impl Finding {
+  fn new(check: &str, status: Status, message: impl Into<String>) -> Self {
+    Self {
+      check: check.to_string(),
+      status,
+      message: message.into(),
+      hint: None,
+    }
+  }
+
+  fn hint(mut self, hint: impl Into<String>) -> Self {
+    self.hint = Some(hint.into());
+    self
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DoctorReport {
+  pub healthy: bool, //没有错误 警告不影响
+  pub findings: Vec<Finding>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FormatFile {
+  format: u32,
+}
+
+fn format_path() -> std::path::PathBuf {
+  data_dir().join("format.json")
+}
+
+///监听地址能否绑定 网关运行中时不检查
+fn check_ports(config: &GatewayConfig) -> Vec<Finding> {
+  let mut binds = vec![HTTP_BIND.to_string()];
+  if let Some(tls) = &config.tls {
+    binds.push(tls.bind.clone());
+  }
+  binds
+    .into_iter()
+    .map(|bind| match TcpListener::bind(&bind) {
+      Ok(_) => Finding::new("port", Status::Ok, format!("{} is available", bind)),
+      Err(err) => Finding::new("port", Status::Error, format!("cannot bind {}: {}", bind, err))
+        .hint("stop the process using the port or change the listen address"),
+    })
+    .collect()
+}
+
+///产品代码目录和存储能否读写
+async fn check_storage() -> Vec<Finding> {
+  let mut findings = vec![];
+  let probe = product_dir("").join(format!(".doctor-{}", uuid::Uuid::new_v4()));
+  let code = std::fs::create_dir_all(product_dir(""))
+    .and_then(|_| std::fs::write(&probe, b"ok"))
+    .and_then(|_| std::fs::remove_file(&probe));
+  findings.push(match code {
+    Ok(_) => Finding::new("storage", Status::Ok, "code directory is writable"),
+    Err(err) => Finding::new("storage", Status::Error, format!("code directory is not writable: {}", err))
+      .hint("give the gateway user write access to the code directory"),
+  });
+  let key = format!(".doctor/probe-{}", uuid::Uuid::new_v4());
+  let storage = storage::storage();
+  let result: Result<(), AnyError> = async {
+    storage.put(&key, b"ok".to_vec()).await?;
+    let read = storage.get(&key).await?;
+    storage.delete(&key).await?;
+    match read.as_deref() {
+      Some(b"ok") => Ok(()),
+      _ => Err(generic_error("probe object read back different content")),
+    }
+  }
+  .await;
+  findings.push(match result {
+    Ok(_) => Finding::new("storage", Status::Ok, "storage backend is readable and writable"),
+    Err(err) => Finding::new("storage", Status::Error, format!("storage backend check failed: {}", err))
+      .hint("check storage credentials, bucket or table permissions and network access"),
+  });
+  findings
+}
+
+///最新版本的文件能否解密 审计日志链是否完整 最新的状态快照能否解压
+async fn check_snapshots() -> Result<Vec<Finding>, AnyError> {
+  let mut findings = vec![];
+  for (product_code, _) in list_dir(data_dir().join("versions"))? {
+    let version = match versions::latest_version(&product_code)? {
+      Some(version) => version,
+      None => continue,
+    };
+    let dir = versions::version_dir(&product_code, &version)?;
+    let unreadable = WalkDir::new(&dir)
+      .into_iter()
+      .filter_map(|e| e.ok())
+      .filter(|e| e.file_type().is_file())
+      .filter(|e| std::fs::read(e.path()).map_err(AnyError::from).and_then(at_rest::decrypt).is_err())
+      .count();
+    if unreadable > 0 {
+      findings.push(
+        Finding::new(
+          "snapshot",
+          Status::Error,
+          format!("{} files of {} version {} cannot be read", unreadable, product_code, version),
+        )
+        .hint("check that encryption at rest uses the master key the version was written with"),
+      );
+    }
+  }
+  for product_code in audit_log::products()? {
+    let report = audit_log::verify(&product_code)?;
+    if !report.valid {
+      findings.push(
+        Finding::new(
+          "snapshot",
+          Status::Error,
+          format!("audit log of {} failed verification: {}", product_code, report.error.unwrap_or_default()),
+        )
+        .hint("restore the audit log from the latest checkpoint export"),
+      );
+    }
+  }
+  for product_code in catalog::product_codes()? {
+    let latest = match state_snapshot::list(&product_code).await?.into_iter().next() {
+      Some(latest) => latest,
+      None => continue,
+    };
+    let bytes = artifacts::read_artifact(state_snapshot::ARTIFACT_KIND, &latest.name).await?;
+    let valid = tar::Archive::new(flate2::read::GzDecoder::new(bytes.as_slice()))
+      .entries()
+      .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+      .is_ok();
+    if !valid {
+      findings
+        .push(Finding::new("snapshot", Status::Error, format!("state snapshot {} is corrupted", latest.name)).hint("take a new state snapshot"));
+    }
+  }
+  if findings.is_empty() {
+    findings.push(Finding::new(
+      "snapshot",
+      Status::Ok,
+      "versions, audit logs and state snapshots are intact",
+    ));
+  }
+  Ok(findings)
+}
+
+async fn fetch(url: &str, method: reqwest::Method) -> Result<reqwest::Response, AnyError> {
+  let client = reqwest::Client::builder().timeout(CHECK_TIMEOUT).build()?;
+  Ok(client.request(method, url).send().await?)
+}
+
+///用远端响应的 Date 头估计本机时钟偏差 精度为秒
+async fn check_clock(config: &GatewayConfig) -> Finding {
+  if config.offline.enabled {
+    return Finding::new("clock", Status::Skipped, "offline mode is enabled");
+  }
+  let doctor = &config.doctor;
+  let res = match fetch(&doctor.time_url, reqwest::Method::HEAD).await {
+    Ok(res) => res,
+    Err(err) => return Finding::new("clock", Status::Warning, format!("cannot reach {}: {}", doctor.time_url, err)),
+  };
+  let remote = res
+    .headers()
+    .get(reqwest::header::DATE)
+    .and_then(|d| d.to_str().ok())
+    .and_then(|d| chrono::DateTime::parse_from_rfc2822(d).ok());
+  let remote = match remote {
+    Some(remote) => remote,
+    None => return Finding::new("clock", Status::Warning, format!("{} returned no Date header", doctor.time_url)),
+  };
+  let skew = (chrono::Utc::now().timestamp() - remote.timestamp()).unsigned_abs();
+  match skew > doctor.max_clock_skew_secs {
+    true => Finding::new("clock", Status::Error, format!("clock is off by {}s", skew))
+      .hint("enable NTP, TOTP codes, session expiry and signatures depend on the clock"),
+    false => Finding::new("clock", Status::Ok, format!("clock skew {}s", skew)),
+  }
+}
+
+///证书能否加载 配置了 ACME 目录时检查能否访问
+async fn check_acme(config: &GatewayConfig) -> Vec<Finding> {
+  let mut findings = vec![];
+  if let Some(tls) = &config.tls {
+    findings.push(match mtls::server_config(tls) {
+      Ok(_) => Finding::new("acme", Status::Ok, "tls certificate and key load"),
+      Err(err) => {
+        Finding::new("acme", Status::Error, format!("cannot load tls certificate: {}", err)).hint("check the cert and key paths in gateway.json tls")
+      }
+    });
+  }
+  let directory = match &config.doctor.acme_directory {
+    Some(directory) if !config.offline.enabled => directory,
+    _ => {
+      findings.push(Finding::new("acme", Status::Skipped, "no acme directory configured"));
+      return findings;
+    }
+  };
+  findings.push(match fetch(directory, reqwest::Method::GET).await {
+    Ok(res) if res.status().is_success() => Finding::new("acme", Status::Ok, format!("{} is reachable", directory)),
+    Ok(res) => Finding::new("acme", Status::Error, format!("{} returned {}", directory, res.status())),
+    Err(err) => Finding::new("acme", Status::Error, format!("cannot reach {}: {}", directory, err))
+      .hint("allow outbound https to the acme server or renew certificates manually"),
+  });
+  findings
+}
+
+///持久化数据的格式和配置文件能否被当前版本读取
+fn check_state_version() -> Result<Vec<Finding>, AnyError> {
+  let mut findings = vec![];
+  let format = read_json::<FormatFile>(format_path())?.format;
+  findings.push(match format {
+    0 => Finding::new("state_version", Status::Ok, "no persisted state yet"),
+    f if f > STATE_FORMAT => Finding::new(
+      "state_version",
+      Status::Error,
+      format!("data was written by a newer gateway (format {}, supported {})", f, STATE_FORMAT),
+    )
+    .hint("upgrade the gateway or restore data from a backup taken before the upgrade"),
+    f if f < STATE_FORMAT => Finding::new("state_version", Status::Warning, format!("data uses older format {}", f)),
+    _ => Finding::new("state_version", Status::Ok, format!("data format {}", format)),
+  });
+  if let Err(err) = GatewayConfig::load() {
+    findings.push(Finding::new(
+      "state_version",
+      Status::Error,
+      format!("gateway.json cannot be parsed: {}", err),
+    ));
+  }
+  for product_code in catalog::product_codes()? {
+    if let Err(err) = ProductConfig::load(&product_code) {
+      findings.push(
+        Finding::new(
+          "state_version",
+          Status::Error,
+          format!("cool.json of {} cannot be parsed: {}", product_code, err),
+        )
+        .hint("fix the product config before deploying"),
+      );
+    }
+  }
+  Ok(findings)
+}
+
+///运行所有检查 startup 为 true 时网关还没有监听 检查端口
+pub async fn run(startup: bool) -> DoctorReport {
+  let config = GatewayConfig::load().unwrap_or_default();
+  let mut findings = vec![];
+  match startup {
+    true => findings.extend(check_ports(&config)),
+    false => findings.push(Finding::new("port", Status::Skipped, "the gateway is already listening")),
+  }
+  findings.extend(check_storage().await);
+  match check_snapshots().await {
+    Ok(found) => findings.extend(found),
+    Err(err) => findings.push(Finding::new("snapshot", Status::Error, err.to_string())),
+  }
+  findings.push(check_clock(&config).await);
+  findings.extend(check_acme(&config).await);
+  match check_state_version() {
+    Ok(found) => findings.extend(found),
+    Err(err) => findings.push(Finding::new("state_version", Status::Error, err.to_string())),
+  }
+  DoctorReport {
+    healthy: findings.iter().all(|f| f.status != Status::Error),
+    findings,
+  }
+}
+
+fn log_report(report: &DoctorReport) {
+  for finding in &report.findings {
+    let hint = finding.hint.as_deref().map(|h| format!(" ({})", h)).unwrap_or_default();
+    match finding.status {
+      Status::Error => log::error!("doctor {}: {}{}", finding.check, finding.message, hint),
+      Status::Warning => log::warn!("doctor {}: {}{}", finding.check, finding.message, hint),
+      _ => log::debug!("doctor {}: {}", finding.check, finding.message),
+    }
+  }
+}
+
+///启动时在监听之前自检 只记录日志 第一次启动时写入数据格式
+pub async fn startup() {
+  let report = run(true).await;
+  log_report(&report);
+  if read_json::<FormatFile>(format_path()).map(|f| f.format == 0).unwrap_or(false) {
+    if let Err(err) = write_json(format_path(), &FormatFile { format: STATE_FORMAT }) {
+      log::error!("failed to write data format: {}", err);
+    }
+  }
+}
+
+///--doctor 只运行自检 输出报告后退出 有错误时退出码为 1
+pub async fn run_cli() -> ! {
+  encryption::start();
+  let config = GatewayConfig::load().unwrap_or_default();
+  if let Err(err) = storage::init(&config.storage) {
+    log::error!("failed to initialize storage: {}", err);
+  }
+  let report = run(true).await;
+  for finding in &report.findings {
+    let status = format!("{:?}", finding.status).to_uppercase();
+    println!("[{:<7}] {:<13} {}", status, finding.check, finding.message);
+    if let Some(hint) = &finding.hint {
+      println!("{:23}{}", "", hint);
+    }
+  }
+  std::process::exit(if report.healthy { 0 } else { 1 })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn reports_unavailable_port() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let bind = listener.local_addr().unwrap().to_string();
+    let config = GatewayConfig {
+      tls: Some(crate::config::TlsConfig {
+        bind: bind.clone(),
+        cert: String::new(),
+        key: String::new(),
+        client_ca: None,
+      }),
+      ..Default::default()
+    };
+    let findings = check_ports(&config);
+    assert_eq!(findings.last().unwrap().status, Status::Error);
+    assert!(findings.last().unwrap().message.contains(&bind));
+  }
+}
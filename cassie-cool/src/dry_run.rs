@@ -0,0 +1,97 @@
+use crate::auth::error_response;
+use crate::Res;
+use actix_web::HttpResponse;
+use deno_core::error::AnyError;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+///修改类接口的查询参数 dry_run=true 时只校验并返回修改后的结果 不写入
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DryRunQuery {
+  #[serde(default)]
+  pub dry_run: bool,
+}
+
+///一个变化的字段 path 为 json 路径 例如 catalog.tags
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+  pub path: String,
+  pub before: Value,
+  pub after: Value,
+}
+
+///预览结果 before 为修改前 新建时为 null after 为修改后 删除时为 null
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DryRun {
+  pub dry_run: bool,
+  pub before: Value,
+  pub after: Value,
+  pub changes: Vec<Change>,
+}
+
+///对象逐个字段比较 数组和其他值整体比较
+fn diff(path: &str, before: &Value, after: &Value, out: &mut Vec<Change>) {
+  match (before, after) {
+    (Value::Object(a), Value::Object(b)) => {
+      let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+      keys.sort();
+      keys.dedup();
+      for key in keys {
+        let child = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+        diff(&child, a.get(key).unwrap_or(&Value::Null), b.get(key).unwrap_or(&Value::Null), out);
+      }
+    }
+    _ if before != after => out.push(Change {
+      path: path.to_string(),
+      before: before.clone(),
+      after: after.clone(),
+    }),
+    _ => {}
+  }
+}
+
+///修改前后的值和变化的字段
+pub fn preview<B: Serialize, A: Serialize>(before: &B, after: &A) -> Result<DryRun, AnyError> {
+  let before = serde_json::to_value(before)?;
+  let after = serde_json::to_value(after)?;
+  let mut changes = vec![];
+  diff("", &before, &after, &mut changes);
+  Ok(DryRun {
+    dry_run: true,
+    before,
+    after,
+    changes,
+  })
+}
+
+pub fn respond(result: Result<DryRun, AnyError>) -> HttpResponse {
+  match result {
+    Ok(preview) => Res { code: 0, data: preview }.respond_to(),
+    Err(err) => error_response(err),
+  }
+}
+
+///不支持预览的修改接口收到 dry_run 时拒绝执行 避免自动化流程误以为没有生效
+pub fn unsupported() -> HttpResponse {
+  Res {
+    code: -1,
+    data: "dry_run is not supported by this endpoint".to_string(),
+  }
+  .respond_to()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn diffs_nested_fields() {
+    let before = json!({"name": "a", "catalog": {"tags": ["x"], "owner": "bob"}});
+    let after = json!({"name": "a", "catalog": {"tags": ["x", "y"]}, "description": "d"});
+    let result = preview(&before, &after).unwrap();
+    let paths: Vec<&str> = result.changes.iter().map(|c| c.path.as_str()).collect();
+    assert_eq!(paths, vec!["catalog.owner", "catalog.tags", "description"]);
+    assert!(preview(&Value::Null, &json!({"a": 1})).unwrap().changes[0].path.is_empty());
+  }
+}
@@ -0,0 +1,168 @@
+use crate::config::ProductConfig;
+use crate::list_query::ListSpec;
+use crate::util::now_millis;
+use deno_runtime::deno_fetch::{set_egress_hook, EgressRecord};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+///每个产品默认保留的记录数
+const DEFAULT_MAX_RECORDS: usize = 1000;
+
+///记录 runtime 通过 fetch 发出的请求 cool.json 中的 egress<br>
+/// 只记录 http 和 https 请求 记录保存在内存中 网关重启后清空
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EgressLog {
+  pub enabled: bool,
+  pub max_records: Option<usize>, //保留的记录数 默认 1000
+}
+
+///一次外部请求 响应体读取完或请求失败时记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EgressEntry {
+  pub id: u64,
+  pub runtime: String, //runtime 线程名
+  pub method: String,
+  pub host: String,
+  pub status: Option<u16>, //请求失败时为空
+  pub duration_ms: u64,    //包括读取响应体的时间
+  pub request_bytes: Option<u64>,
+  pub response_bytes: u64, //脚本实际读取的响应体大小
+  pub error: Option<String>,
+  pub created_at: u64, //发出请求的时间
+}
+
+///按主机汇总 用于找出频繁调用外部 API 的产品
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostSummary {
+  pub host: String,
+  pub requests: u64,
+  pub errors: u64, //请求失败或状态码 >= 500
+  pub duration_ms: u64,
+  pub response_bytes: u64,
+}
+
+///外部请求列表的排序和过滤 status 按状态码过滤 tag 按主机过滤
+pub const LIST_SPEC: ListSpec = ListSpec {
+  id: "id",
+  sort: &["created_at", "duration_ms", "response_bytes"],
+  default_sort: "-created_at",
+  status: Some("status"),
+  tag: Some("host"),
+  owner: None,
+};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+  static ref RECORDS: Mutex<HashMap<String, VecDeque<EgressEntry>>> = Mutex::new(HashMap::new());
+}
+
+fn push(product_code: &str, entry: EgressEntry, max_records: usize) {
+  let mut records = RECORDS.lock().unwrap();
+  let entries = records.entry(product_code.to_string()).or_default();
+  entries.push_back(entry);
+  while entries.len() > max_records {
+    entries.pop_front();
+  }
+}
+
+///在 runtime 线程里调用 开启时记录这个线程发出的请求<br>
+/// 产品代码创建的 Worker 在其他线程中运行 它们发出的请求不会记录
+pub fn apply(product_code: &str) {
+  let config = ProductConfig::load(product_code).map(|c| c.egress).unwrap_or_default();
+  if !config.enabled {
+    set_egress_hook(None);
+    return;
+  }
+  let product_code = product_code.to_string();
+  let runtime = std::thread::current().name().unwrap_or_default().to_string();
+  let max_records = config.max_records.unwrap_or(DEFAULT_MAX_RECORDS).max(1);
+  set_egress_hook(Some(Box::new(move |record: EgressRecord| {
+    let duration_ms = record.duration.as_millis() as u64;
+    let entry = EgressEntry {
+      id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+      runtime: runtime.clone(),
+      method: record.method,
+      host: record.host,
+      status: record.status,
+      duration_ms,
+      request_bytes: record.request_bytes,
+      response_bytes: record.response_bytes,
+      error: record.error,
+      created_at: now_millis().saturating_sub(duration_ms),
+    };
+    push(&product_code, entry, max_records);
+  })));
+}
+
+///产品最近的外部请求
+pub fn list(product_code: &str) -> Vec<EgressEntry> {
+  RECORDS
+    .lock()
+    .unwrap()
+    .get(product_code)
+    .map(|entries| entries.iter().cloned().collect())
+    .unwrap_or_default()
+}
+
+fn summarize(entries: &[EgressEntry]) -> Vec<HostSummary> {
+  let mut hosts: BTreeMap<&str, HostSummary> = BTreeMap::new();
+  for entry in entries {
+    let summary = hosts.entry(&entry.host).or_insert_with(|| HostSummary {
+      host: entry.host.clone(),
+      ..Default::default()
+    });
+    summary.requests += 1;
+    if entry.error.is_some() || entry.status.map(|s| s >= 500).unwrap_or(true) {
+      summary.errors += 1;
+    }
+    summary.duration_ms += entry.duration_ms;
+    summary.response_bytes += entry.response_bytes;
+  }
+  let mut summaries: Vec<HostSummary> = hosts.into_values().collect();
+  summaries.sort_by(|a, b| b.requests.cmp(&a.requests));
+  summaries
+}
+
+///按主机汇总产品最近的外部请求 请求数多的在前
+pub fn summary(product_code: &str) -> Vec<HostSummary> {
+  summarize(&list(product_code))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn summarizes_by_host() {
+    let entry = |id: u64, host: &str, status: Option<u16>| EgressEntry {
+      id,
+      runtime: "product-shop-0".to_string(),
+      method: "GET".to_string(),
+      host: host.to_string(),
+      status,
+      duration_ms: 10,
+      request_bytes: Some(0),
+      response_bytes: 100,
+      error: status.is_none().then(|| "connection refused".to_string()),
+      created_at: id,
+    };
+    for id in 0..3 {
+      push("egress-test", entry(id, "api.example.com", Some(200)), 2);
+    }
+    assert_eq!(list("egress-test").iter().map(|e| e.id).collect::<Vec<_>>(), vec![1, 2]);
+    let entries = vec![
+      entry(0, "maps.example.com", Some(200)),
+      entry(1, "api.example.com", Some(503)),
+      entry(2, "api.example.com", None),
+    ];
+    let summaries = summarize(&entries);
+    assert_eq!(summaries[0].host, "api.example.com");
+    assert_eq!((summaries[0].requests, summaries[0].errors, summaries[0].response_bytes), (2, 2, 200));
+    assert_eq!((summaries[1].requests, summaries[1].errors), (1, 0));
+  }
+}
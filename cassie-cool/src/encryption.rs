@@ -0,0 +1,250 @@
+use crate::config::{data_dir, GatewayConfig};
+use crate::util::{now_millis, read_json, write_json};
+use deno_core::error::{custom_error, generic_error, AnyError};
+use deno_runtime::at_rest::{self, Keyring};
+use lazy_static::lazy_static;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use walkdir::WalkDir;
+
+///静态加密 gateway.json 中的 encryption<br>
+/// 主密钥只用来加密数据密钥 数据密钥加密产品代码 版本快照和 KV 的值
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+  pub enabled: bool,                    //开启后新写入的数据会加密 已有的明文在轮换数据密钥时加密
+  pub master_key_env: String,           //保存主密钥的环境变量 64 位 hex
+  pub master_key_file: Option<String>,  //保存主密钥的文件 优先于环境变量
+  pub kms_command: Option<Vec<String>>, //从 KMS 取主密钥的命令 标准输出为 hex 主密钥 优先于文件
+}
+
+impl Default for EncryptionConfig {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      master_key_env: "COOL_MASTER_KEY".to_string(),
+      master_key_file: None,
+      kms_command: None,
+    }
+  }
+}
+
+///用主密钥加密后的数据密钥
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WrappedKey {
+  id: u32,
+  created_at: u64,
+  wrapped: String, //hex
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct KeyFile {
+  current: u32,
+  keys: Vec<WrappedKey>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataKeyInfo {
+  pub id: u32,
+  pub created_at: u64,
+  pub current: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionStatus {
+  pub enabled: bool,
+  pub keys: Vec<DataKeyInfo>,
+}
+
+///轮换数据密钥的结果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RotateReport {
+  pub key_id: u32,
+  pub files: u64, //重新加密的文件数
+  pub bytes: u64,
+}
+
+lazy_static! {
+  //当前使用的主密钥 轮换和重新包装时使用
+  static ref MASTER_KEY: Mutex<Option<[u8; 32]>> = Mutex::new(None);
+}
+
+///数据密钥表 data/keys.json
+fn keys_path() -> PathBuf {
+  data_dir().join("keys.json")
+}
+
+fn parse_key(text: &str) -> Result<[u8; 32], AnyError> {
+  let bytes = hex::decode(text.trim()).map_err(|_| generic_error("master key must be hex encoded"))?;
+  bytes
+    .try_into()
+    .map_err(|_| generic_error("master key must be 32 bytes (64 hex characters)"))
+}
+
+fn read_env_key(name: &str) -> Result<[u8; 32], AnyError> {
+  let text = std::env::var(name).map_err(|_| generic_error(format!("environment variable {} is not set", name)))?;
+  parse_key(&text)
+}
+
+///按 kms_command master_key_file master_key_env 的顺序读取主密钥
+fn master_key(config: &EncryptionConfig) -> Result<[u8; 32], AnyError> {
+  if let Some(command) = config.kms_command.as_ref().filter(|c| !c.is_empty()) {
+    let output = Command::new(&command[0]).args(&command[1..]).output()?;
+    if !output.status.success() {
+      return Err(generic_error(format!("kms command exited with {}", output.status)));
+    }
+    return parse_key(&String::from_utf8_lossy(&output.stdout));
+  }
+  if let Some(file) = &config.master_key_file {
+    return parse_key(&std::fs::read_to_string(file)?);
+  }
+  read_env_key(&config.master_key_env)
+}
+
+fn random_key() -> Result<[u8; 32], AnyError> {
+  let mut key = [0u8; 32];
+  SystemRandom::new()
+    .fill(&mut key)
+    .map_err(|_| generic_error("failed to generate data key"))?;
+  Ok(key)
+}
+
+fn wrap(master: &[u8; 32], id: u32, key: &[u8; 32]) -> Result<WrappedKey, AnyError> {
+  Ok(WrappedKey {
+    id,
+    created_at: now_millis(),
+    wrapped: hex::encode(at_rest::seal_with(id, master, key)?),
+  })
+}
+
+fn unwrap(master: &[u8; 32], key: &WrappedKey) -> Result<[u8; 32], AnyError> {
+  let bytes = at_rest::open_with(master, &hex::decode(&key.wrapped)?)
+    .map_err(|_| generic_error(format!("data key {} can not be unwrapped, wrong master key", key.id)))?;
+  bytes.try_into().map_err(|_| generic_error(format!("data key {} is corrupted", key.id)))
+}
+
+fn keyring(master: &[u8; 32], file: &KeyFile) -> Result<Keyring, AnyError> {
+  let mut keys = HashMap::new();
+  for key in &file.keys {
+    keys.insert(key.id, unwrap(master, key)?);
+  }
+  Keyring::new(file.current, keys)
+}
+
+fn add_data_key(master: &[u8; 32], file: &mut KeyFile) -> Result<u32, AnyError> {
+  let id = file.keys.iter().map(|k| k.id).max().unwrap_or(0) + 1;
+  file.keys.push(wrap(master, id, &random_key()?)?);
+  file.current = id;
+  Ok(id)
+}
+
+fn master() -> Result<[u8; 32], AnyError> {
+  MASTER_KEY
+    .lock()
+    .unwrap()
+    .ok_or_else(|| custom_error("NotFound", "encryption at rest is not enabled"))
+}
+
+///读取主密钥并安装数据密钥 第一次启动时生成数据密钥
+pub fn init(config: &EncryptionConfig) -> Result<(), AnyError> {
+  let master = master_key(config)?;
+  let mut file: KeyFile = read_json(keys_path())?;
+  if file.keys.is_empty() {
+    add_data_key(&master, &mut file)?;
+    write_json(keys_path(), &file)?;
+  }
+  at_rest::install(Some(keyring(&master, &file)?));
+  *MASTER_KEY.lock().unwrap() = Some(master);
+  Ok(())
+}
+
+///产品代码和版本快照 KV 的值在读取时使用旧密钥解密 不需要重新加密
+fn encrypted_roots() -> Vec<PathBuf> {
+  vec![std::env::current_dir().unwrap().join("code"), data_dir().join("versions")]
+}
+
+fn reencrypt(path: &Path) -> Result<u64, AnyError> {
+  let plain = at_rest::decrypt(std::fs::read(path)?)?;
+  let sealed = at_rest::encrypt(&plain)?;
+  let tmp = path.with_extension("cool-reencrypt");
+  std::fs::write(&tmp, &sealed)?;
+  std::fs::rename(tmp, path)?;
+  Ok(sealed.len() as u64)
+}
+
+///生成新的数据密钥并用它重新加密产品代码和版本快照 旧的数据密钥保留给 KV 使用
+pub fn rotate_data_key() -> Result<RotateReport, AnyError> {
+  let master = master()?;
+  let mut file: KeyFile = read_json(keys_path())?;
+  let key_id = add_data_key(&master, &mut file)?;
+  let keyring = keyring(&master, &file)?;
+  write_json(keys_path(), &file)?;
+  at_rest::install(Some(keyring));
+  let (mut files, mut bytes) = (0, 0);
+  for root in encrypted_roots() {
+    for entry in WalkDir::new(root).into_iter().filter_map(|e| e.ok()).filter(|e| e.file_type().is_file()) {
+      bytes += reencrypt(entry.path())?;
+      files += 1;
+    }
+  }
+  Ok(RotateReport { key_id, files, bytes })
+}
+
+///用环境变量 env 中的新主密钥重新包装所有数据密钥 数据本身不变<br>
+/// 完成后需要把 gateway.json 的主密钥配置改为新的主密钥
+pub fn rewrap(env: &str) -> Result<EncryptionStatus, AnyError> {
+  let master = master()?;
+  let new_master = read_env_key(env)?;
+  let mut file: KeyFile = read_json(keys_path())?;
+  for key in file.keys.iter_mut() {
+    let plain = unwrap(&master, key)?;
+    key.wrapped = hex::encode(at_rest::seal_with(key.id, &new_master, &plain)?);
+  }
+  write_json(keys_path(), &file)?;
+  *MASTER_KEY.lock().unwrap() = Some(new_master);
+  status()
+}
+
+pub fn status() -> Result<EncryptionStatus, AnyError> {
+  let file: KeyFile = read_json(keys_path())?;
+  Ok(EncryptionStatus {
+    enabled: at_rest::enabled(),
+    keys: file
+      .keys
+      .iter()
+      .map(|k| DataKeyInfo {
+        id: k.id,
+        created_at: k.created_at,
+        current: k.id == file.current,
+      })
+      .collect(),
+  })
+}
+
+///读取可能加密的文本文件
+pub async fn read_to_string(path: impl AsRef<Path>) -> Result<String, AnyError> {
+  let bytes = at_rest::decrypt(tokio::fs::read(path).await?)?;
+  Ok(String::from_utf8(bytes)?)
+}
+
+///开启加密时加密后写入
+pub async fn write(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> Result<(), AnyError> {
+  tokio::fs::write(path, at_rest::encrypt(contents.as_ref())?).await?;
+  Ok(())
+}
+
+///按配置开启静态加密 读取不到主密钥时不会加密新数据 已加密的数据无法读取
+pub fn start() {
+  let config = GatewayConfig::load().map(|c| c.encryption).unwrap_or_default();
+  if !config.enabled {
+    return;
+  }
+  match init(&config) {
+    Ok(_) => log::info!("encryption at rest enabled"),
+    Err(err) => log::error!("failed to enable encryption at rest: {}", err),
+  }
+}
@@ -0,0 +1,225 @@
+use crate::metrics;
+use crate::util::now_millis;
+use actix_web::http::header::{
+  HeaderMap, HeaderName, HeaderValue, HttpDate, AUTHORIZATION, CACHE_CONTROL, CONTENT_RANGE, COOKIE, ETAG, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+  LAST_MODIFIED, SET_COOKIE, VARY,
+};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+///校验信息表超过这个数量时清理已经过期的
+const MAX_VALIDATORS: usize = 10_000;
+///304 中除 ETag 外带上的响应头
+const VALIDATOR_HEADERS: [HeaderName; 4] = [LAST_MODIFIED, CACHE_CONTROL, EXPIRES, VARY];
+
+///网关生成 ETag cool.json 中的 etag<br>
+/// runtime 没有设置 ETag 的 GET 200 响应 长度已知且不超过 max_body_bytes 时缓冲响应体 以内容的 sha256 作为强校验的 ETag<br>
+/// 条件请求命中时返回 304 不带响应体 响应按 Cache-Control 仍然新鲜且可以共享时 记住校验信息 之后的条件请求不再转发给 runtime
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EtagPolicy {
+  pub enabled: bool,
+  pub max_body_bytes: u64,
+}
+
+impl Default for EtagPolicy {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      max_body_bytes: 1024 * 1024,
+    }
+  }
+}
+
+///一个地址最近的校验信息 在 expires_at 之前有效
+struct Validator {
+  etag: String,
+  last_modified: Option<SystemTime>,
+  headers: Vec<(HeaderName, HeaderValue)>,
+  expires_at: u64,
+}
+
+lazy_static! {
+  //(产品, 路径和查询参数)
+  static ref VALIDATORS: Mutex<HashMap<(String, String), Validator>> = Mutex::new(HashMap::new());
+}
+
+///内容的 sha256 前 32 位
+fn strong_etag(body: &[u8]) -> String {
+  let digest = ring::digest::digest(&ring::digest::SHA256, body);
+  format!("\"{}\"", &hex::encode(digest.as_ref())[..32])
+}
+
+fn http_date(value: Option<&HeaderValue>) -> Option<SystemTime> {
+  let value = value?.to_str().ok()?;
+  HttpDate::from_str(value).ok().map(SystemTime::from)
+}
+
+///条件请求是否命中 有 If-None-Match 时忽略 If-Modified-Since<br>
+/// If-None-Match 使用弱比较 W/ 前缀不影响结果
+fn not_modified(headers: &HeaderMap, etag: &str, last_modified: Option<SystemTime>) -> bool {
+  let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+  if let Some(tags) = headers.get(IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+    return tags.split(',').any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag));
+  }
+  match (http_date(headers.get(IF_MODIFIED_SINCE)), last_modified) {
+    (Some(since), Some(last_modified)) => last_modified <= since,
+    _ => false,
+  }
+}
+
+///响应可以共享时的新鲜时间 秒<br>
+/// 需要 max-age 或 s-maxage 没有 private no-cache no-store 没有 Set-Cookie Vary 只能是 Accept-Encoding
+fn shared_max_age(headers: &HeaderMap) -> Option<u64> {
+  if headers.contains_key(SET_COOKIE) {
+    return None;
+  }
+  let vary_ok = headers
+    .get_all(VARY)
+    .filter_map(|v| v.to_str().ok())
+    .flat_map(|v| v.split(','))
+    .all(|v| v.trim().eq_ignore_ascii_case("accept-encoding"));
+  if !vary_ok {
+    return None;
+  }
+  let mut max_age = None;
+  let mut shared_max_age = None;
+  for directive in headers.get_all(CACHE_CONTROL).filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(',')) {
+    let directive = directive.trim().to_ascii_lowercase();
+    match directive.split_once('=') {
+      Some(("max-age", secs)) => max_age = secs.trim_matches('"').parse::<u64>().ok(),
+      Some(("s-maxage", secs)) => shared_max_age = secs.trim_matches('"').parse::<u64>().ok(),
+      None if matches!(directive.as_str(), "private" | "no-cache" | "no-store") => return None,
+      _ => {}
+    }
+  }
+  shared_max_age.or(max_age).filter(|secs| *secs > 0)
+}
+
+///带凭据的请求的响应可能因人而异 不使用也不记录共享的校验信息
+fn is_shared_request(req: &HttpRequest) -> bool {
+  req.method() == Method::GET && !req.headers().contains_key(AUTHORIZATION) && !req.headers().contains_key(COOKIE)
+}
+
+fn cache_key(product_code: &str, req: &HttpRequest) -> (String, String) {
+  let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+  (product_code.to_string(), path.to_string())
+}
+
+fn count(product_code: &str, source: &str) {
+  metrics::inc_counter(
+    "gateway_not_modified_total",
+    "Conditional requests answered with 304 by the gateway",
+    &[("product", product_code), ("source", source)],
+    1,
+  );
+}
+
+///转发之前检查 记住的校验信息仍然有效且条件请求命中时直接返回 304
+pub fn respond_cached(product_code: &str, req: &HttpRequest, policy: &EtagPolicy) -> Option<HttpResponse> {
+  if !policy.enabled || !is_shared_request(req) {
+    return None;
+  }
+  if !req.headers().contains_key(IF_NONE_MATCH) && !req.headers().contains_key(IF_MODIFIED_SINCE) {
+    return None;
+  }
+  let key = cache_key(product_code, req);
+  let mut validators = VALIDATORS.lock().unwrap();
+  let validator = validators.get(&key)?;
+  if validator.expires_at <= now_millis() {
+    validators.remove(&key);
+    return None;
+  }
+  if !not_modified(req.headers(), &validator.etag, validator.last_modified) {
+    return None;
+  }
+  count(product_code, "cache");
+  let mut res = HttpResponse::NotModified();
+  for (name, value) in validator.headers.iter() {
+    res.append_header((name.clone(), value.clone()));
+  }
+  Some(res.finish())
+}
+
+///是否缓冲这个响应计算 ETag headers 为整理后的响应头 length 为响应体长度
+pub fn applies(req: &HttpRequest, status: StatusCode, headers: &HeaderMap, length: Option<u64>, policy: &EtagPolicy) -> bool {
+  policy.enabled
+    && req.method() == Method::GET
+    && status == StatusCode::OK
+    && !headers.contains_key(ETAG)
+    && !headers.contains_key(CONTENT_RANGE)
+    && length.map(|l| l <= policy.max_body_bytes).unwrap_or(false)
+}
+
+///给缓冲的响应加上 ETag 条件请求命中时返回 304<br>
+/// res 为已经带上 runtime 响应头的响应 headers 为整理后的响应头
+pub fn respond(product_code: &str, req: &HttpRequest, mut res: HttpResponseBuilder, headers: &HeaderMap, body: Bytes) -> HttpResponse {
+  let etag = strong_etag(&body);
+  res.insert_header((ETAG, etag.clone()));
+  let last_modified = http_date(headers.get(LAST_MODIFIED));
+  if let Some(max_age) = shared_max_age(headers).filter(|_| is_shared_request(req)) {
+    let mut validator_headers = vec![(ETAG, HeaderValue::from_str(&etag).unwrap())];
+    for name in VALIDATOR_HEADERS.iter() {
+      validator_headers.extend(headers.get_all(name).map(|v| (name.clone(), v.clone())));
+    }
+    let now = now_millis();
+    let mut validators = VALIDATORS.lock().unwrap();
+    if validators.len() > MAX_VALIDATORS {
+      validators.retain(|_, v| v.expires_at > now);
+    }
+    validators.insert(
+      cache_key(product_code, req),
+      Validator {
+        etag: etag.clone(),
+        last_modified,
+        headers: validator_headers,
+        expires_at: now + max_age * 1000,
+      },
+    );
+  }
+  if not_modified(req.headers(), &etag, last_modified) {
+    count(product_code, "runtime");
+    return res.status(StatusCode::NOT_MODIFIED).finish();
+  }
+  res.body(body)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn answers_conditional_requests_of_shared_responses() {
+    let etag = strong_etag(b"hello");
+    let mut headers = HeaderMap::new();
+    headers.insert(IF_NONE_MATCH, HeaderValue::from_str(&format!("\"other\", W/{}", etag)).unwrap());
+    assert!(not_modified(&headers, &etag, None));
+    headers.insert(IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+    headers.insert(IF_MODIFIED_SINCE, HeaderValue::from_static("Sun, 06 Nov 1994 08:49:37 GMT"));
+    let last_modified = http_date(Some(&HeaderValue::from_static("Sun, 06 Nov 1994 08:00:00 GMT")));
+    //有 If-None-Match 时不看 If-Modified-Since
+    assert!(!not_modified(&headers, &etag, last_modified));
+    headers.remove(IF_NONE_MATCH);
+    assert!(not_modified(&headers, &etag, last_modified));
+    assert!(!not_modified(&headers, &etag, None));
+
+    let mut headers = HeaderMap::new();
+    assert_eq!(shared_max_age(&headers), None);
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("public, max-age=60, s-maxage=30"));
+    assert_eq!(shared_max_age(&headers), Some(30));
+    headers.insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+    assert_eq!(shared_max_age(&headers), Some(30));
+    headers.insert(VARY, HeaderValue::from_static("Cookie"));
+    assert_eq!(shared_max_age(&headers), None);
+    headers.remove(VARY);
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("private, max-age=60"));
+    assert_eq!(shared_max_age(&headers), None);
+  }
+}
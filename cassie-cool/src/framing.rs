@@ -0,0 +1,250 @@
+use actix_web::body::SizedStream;
+use actix_web::http::header::{
+  HeaderMap, HeaderName, HeaderValue, ACCEPT_RANGES, CONNECTION, CONTENT_ENCODING, CONTENT_LENGTH, ETAG, EXPECT, TRANSFER_ENCODING, VARY,
+};
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use awc::{ClientRequest, SendClientRequest};
+use futures_util::Stream;
+
+///逐跳的头 只对一段连接有效 网关不转发
+const HOP_BY_HOP: [&str; 9] = [
+  "connection",
+  "keep-alive",
+  "proxy-authenticate",
+  "proxy-authorization",
+  "proxy-connection",
+  "te",
+  "trailer",
+  "transfer-encoding",
+  "upgrade",
+];
+
+///网关对响应体的改动 有改动时不能再使用 runtime 声明的长度
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BodyChange {
+  #[default]
+  Unchanged,
+  Rewritten,             //内容被改写 例如缓存替换或注入内容
+  Encoded(&'static str), //网关压缩 值为新的 Content-Encoding
+  Decoded,               //网关解压了 runtime 压缩的内容
+}
+
+///请求体的分帧方式 转发时保持不变
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+  Empty,      //content-length 为 0
+  Sized(u64), //content-length
+  Chunked,    //chunked 或 HTTP/2 中没有声明长度
+}
+
+///按请求头判断 同时存在时 chunked 优先
+pub fn request_framing(headers: &HeaderMap) -> Framing {
+  let chunked = headers
+    .get_all(TRANSFER_ENCODING)
+    .filter_map(|v| v.to_str().ok())
+    .any(|v| v.to_ascii_lowercase().contains("chunked"));
+  if chunked {
+    return Framing::Chunked;
+  }
+  match headers
+    .get(CONTENT_LENGTH)
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.trim().parse::<u64>().ok())
+  {
+    Some(0) => Framing::Empty,
+    Some(len) => Framing::Sized(len),
+    None => Framing::Chunked,
+  }
+}
+
+///需要去掉的逐跳头 包括 Connection 中列出的头
+pub fn hop_by_hop(headers: &HeaderMap) -> Vec<HeaderName> {
+  let mut names: Vec<HeaderName> = HOP_BY_HOP.into_iter().map(HeaderName::from_static).collect();
+  for value in headers.get_all(CONNECTION).filter_map(|v| v.to_str().ok()) {
+    names.extend(value.split(',').filter_map(|token| HeaderName::from_bytes(token.trim().as_bytes()).ok()));
+  }
+  names
+}
+
+fn is_chunked(headers: &HeaderMap) -> bool {
+  headers
+    .get_all(TRANSFER_ENCODING)
+    .filter_map(|v| v.to_str().ok())
+    .any(|v| v.to_ascii_lowercase().contains("chunked"))
+}
+
+///所有 Content-Length 一致时的长度 不一致或无法解析时为 None
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+  let mut lengths = headers
+    .get_all(CONTENT_LENGTH)
+    .map(|v| v.to_str().ok().and_then(|v| v.trim().parse::<u64>().ok()));
+  let first = lengths.next()??;
+  lengths.all(|l| l == Some(first)).then_some(first)
+}
+
+///整理转发给客户端的响应头 返回响应体的长度 为 None 时使用 chunked<br>
+/// 去掉逐跳的头 chunked 和 Content-Length 同时存在时以 chunked 为准 没有响应体的状态码不带长度<br>
+/// 响应体有改动时去掉长度和 Accept-Ranges 强校验的 ETag 改为弱校验
+pub fn normalize_response(status: StatusCode, headers: &mut HeaderMap, change: BodyChange) -> Option<u64> {
+  let chunked = is_chunked(headers);
+  for name in hop_by_hop(headers) {
+    headers.remove(name);
+  }
+  let no_body = status.is_informational() || status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED;
+  let length = content_length(headers).filter(|_| !chunked && !no_body && change == BodyChange::Unchanged);
+  headers.remove(CONTENT_LENGTH);
+  if change != BodyChange::Unchanged {
+    headers.remove(ACCEPT_RANGES);
+    headers.remove("content-md5");
+    let weak = headers
+      .get(ETAG)
+      .and_then(|v| v.to_str().ok())
+      .filter(|v| !v.starts_with("W/"))
+      .and_then(|v| HeaderValue::from_str(&format!("W/{}", v)).ok());
+    if let Some(weak) = weak {
+      headers.insert(ETAG, weak);
+    }
+  }
+  match change {
+    BodyChange::Encoded(encoding) => {
+      headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+      headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    BodyChange::Decoded => {
+      headers.remove(CONTENT_ENCODING);
+    }
+    BodyChange::Unchanged | BodyChange::Rewritten => {}
+  }
+  length
+}
+
+///按客户端的分帧方式转发请求体<br>
+/// 带 Expect: 100-continue 时先只发送请求头 runtime 开始读取请求体 发出 100 后才发送请求体<br>
+/// runtime 在这之前返回的响应就是最终响应 请求体不会被读取
+pub fn send<S, E>(mut req: ClientRequest, framing: Framing, body: S) -> SendClientRequest
+where
+  S: Stream<Item = Result<Bytes, E>> + 'static,
+  E: Into<Box<dyn std::error::Error>> + 'static,
+{
+  match framing {
+    //awc 不允许没有请求体的请求带 Expect
+    Framing::Empty => {
+      req.headers_mut().remove(EXPECT);
+      req.send()
+    }
+    Framing::Sized(len) => req.send_body(SizedStream::new(len, body)),
+    Framing::Chunked => req.send_stream(body),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use actix_web::http::header::HeaderValue;
+  use futures_util::stream;
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::sync::Arc;
+  use std::time::Duration;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  use tokio::net::{TcpListener, TcpStream};
+
+  ///读取到 end 为止 返回读到的全部内容
+  async fn read_until(conn: &mut TcpStream, end: &str) -> String {
+    let mut received = vec![];
+    let mut buf = [0u8; 1024];
+    while !String::from_utf8_lossy(&received).contains(end) {
+      let n = conn.read(&mut buf).await.unwrap();
+      assert!(n > 0, "connection closed before {:?}", end);
+      received.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8_lossy(&received).to_lowercase()
+  }
+
+  #[test]
+  fn detects_framing_and_hop_by_hop_headers() {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_LENGTH, HeaderValue::from_static("12"));
+    assert_eq!(request_framing(&headers), Framing::Sized(12));
+    headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("gzip, chunked"));
+    assert_eq!(request_framing(&headers), Framing::Chunked);
+    headers.insert(CONNECTION, HeaderValue::from_static("keep-alive, X-Trace-Hop"));
+    assert!(hop_by_hop(&headers).contains(&HeaderName::from_static("x-trace-hop")));
+  }
+
+  #[test]
+  fn normalizes_length_and_encoding() {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_LENGTH, HeaderValue::from_static("5"));
+    headers.insert(CONNECTION, HeaderValue::from_static("close"));
+    assert_eq!(normalize_response(StatusCode::OK, &mut headers.clone(), BodyChange::Unchanged), Some(5));
+    assert_eq!(
+      normalize_response(StatusCode::NOT_MODIFIED, &mut headers.clone(), BodyChange::Unchanged),
+      None
+    );
+    headers.append(CONTENT_LENGTH, HeaderValue::from_static("6"));
+    assert_eq!(normalize_response(StatusCode::OK, &mut headers.clone(), BodyChange::Unchanged), None);
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_LENGTH, HeaderValue::from_static("5"));
+    headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+    headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    assert_eq!(normalize_response(StatusCode::OK, &mut headers, BodyChange::Encoded("gzip")), None);
+    assert!(!headers.contains_key(CONTENT_LENGTH) && !headers.contains_key(TRANSFER_ENCODING) && !headers.contains_key(ACCEPT_RANGES));
+    assert_eq!(headers.get(ETAG).unwrap(), "W/\"v1\"");
+    assert_eq!(headers.get(CONTENT_ENCODING).unwrap(), "gzip");
+  }
+
+  #[actix_web::test]
+  async fn streams_slow_uploads_chunked() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let upstream = tokio::spawn(async move {
+      let (mut conn, _) = listener.accept().await.unwrap();
+      let received = read_until(&mut conn, "\r\n0\r\n\r\n").await;
+      conn.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok").await.unwrap();
+      received
+    });
+    let chunks = stream::unfold(0, |i| async move {
+      if i == 3 {
+        return None;
+      }
+      tokio::time::sleep(Duration::from_millis(50)).await;
+      Some((Ok::<_, std::io::Error>(Bytes::from(format!("part{}", i))), i + 1))
+    });
+    let req = awc::Client::default().post(format!("http://{}/upload", addr));
+    let res = send(req, Framing::Chunked, chunks).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let received = upstream.await.unwrap();
+    assert!(received.contains("transfer-encoding: chunked"));
+    assert!(received.contains("part0") && received.contains("part2"));
+  }
+
+  #[actix_web::test]
+  async fn early_rejection_skips_the_body() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let upstream = tokio::spawn(async move {
+      let (mut conn, _) = listener.accept().await.unwrap();
+      let head = read_until(&mut conn, "\r\n\r\n").await;
+      conn
+        .write_all(b"HTTP/1.1 413 Payload Too Large\r\ncontent-length: 0\r\n\r\n")
+        .await
+        .unwrap();
+      head
+    });
+    let polled = Arc::new(AtomicBool::new(false));
+    let flag = polled.clone();
+    let body = stream::once(async move {
+      flag.store(true, Ordering::SeqCst);
+      Ok::<_, std::io::Error>(Bytes::from_static(b"never sent"))
+    });
+    let req = awc::Client::default()
+      .post(format!("http://{}/upload", addr))
+      .insert_header((EXPECT, "100-continue"));
+    let res = send(req, Framing::Sized(10), body).await.unwrap();
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(upstream.await.unwrap().contains("expect: 100-continue"));
+    assert!(!polled.load(Ordering::SeqCst));
+  }
+}
@@ -0,0 +1,127 @@
+use crate::config::GatewayConfig;
+use lazy_static::lazy_static;
+use maxminddb::geoip2;
+use maxminddb::Reader;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+///转发给 worker 的国家代码
+pub const GEO_COUNTRY_HEADER: &str = "x-geo-country";
+///转发给 worker 的地区代码
+pub const GEO_REGION_HEADER: &str = "x-geo-region";
+
+///GeoIP 数据库 gateway.json 中的 geoip
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeoIpConfig {
+  pub database: String, //mmdb 文件路径 例如 GeoLite2-City.mmdb
+  #[serde(default = "default_refresh_secs")]
+  pub refresh_secs: u64, //重新加载数据库的间隔
+}
+
+fn default_refresh_secs() -> u64 {
+  24 * 60 * 60
+}
+
+///地区访问策略 cool.json 中的 geo 国家代码为 ISO 3166-1
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GeoPolicy {
+  pub allow_countries: Vec<String>, //不为空时只允许这些国家
+  pub deny_countries: Vec<String>,  //拒绝的国家
+  pub block_unknown: bool,          //无法识别国家的请求是否拒绝 例如内网地址
+}
+
+impl GeoPolicy {
+  pub fn is_allowed(&self, country: Option<&str>) -> bool {
+    match country {
+      Some(country) => {
+        let matches = |list: &Vec<String>| list.iter().any(|c| c.eq_ignore_ascii_case(country));
+        !matches(&self.deny_countries) && (self.allow_countries.is_empty() || matches(&self.allow_countries))
+      }
+      None => !self.block_unknown,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GeoInfo {
+  pub country: Option<String>,
+  pub region: Option<String>,
+}
+
+lazy_static! {
+  static ref READER: RwLock<Option<Reader<Vec<u8>>>> = RwLock::new(None);
+}
+
+fn load(config: &GeoIpConfig) {
+  match Reader::open_readfile(&config.database) {
+    Ok(reader) => {
+      *READER.write().unwrap() = Some(reader);
+      log::info!("loaded GeoIP database {}", config.database);
+    }
+    Err(err) => log::error!("failed to load GeoIP database {}: {}", config.database, err),
+  }
+}
+
+///按网关配置加载数据库 并定时重新加载以获取更新
+pub fn start() {
+  let config = match GatewayConfig::load().ok().and_then(|c| c.geoip) {
+    Some(config) => config,
+    None => return,
+  };
+  load(&config);
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.refresh_secs.max(60)));
+    interval.tick().await;
+    loop {
+      interval.tick().await;
+      load(&config);
+    }
+  });
+}
+
+///查询 ip 所在的国家和地区 没有加载数据库时返回空
+pub fn lookup(ip: IpAddr) -> GeoInfo {
+  let reader = READER.read().unwrap();
+  let city: geoip2::City = match reader.as_ref().and_then(|r| r.lookup(ip).ok()) {
+    Some(city) => city,
+    None => return GeoInfo::default(),
+  };
+  GeoInfo {
+    country: city.country.and_then(|c| c.iso_code).map(|c| c.to_string()),
+    region: city
+      .subdivisions
+      .and_then(|s| s.into_iter().next())
+      .and_then(|s| s.iso_code)
+      .map(|s| s.to_string()),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn geo_policy() {
+    let policy = GeoPolicy {
+      allow_countries: vec!["CN".to_string(), "sg".to_string()],
+      deny_countries: vec![],
+      block_unknown: false,
+    };
+    assert!(policy.is_allowed(Some("CN")));
+    assert!(policy.is_allowed(Some("SG")));
+    assert!(!policy.is_allowed(Some("US")));
+    assert!(policy.is_allowed(None));
+
+    let policy = GeoPolicy {
+      allow_countries: vec![],
+      deny_countries: vec!["US".to_string()],
+      block_unknown: true,
+    };
+    assert!(!policy.is_allowed(Some("US")));
+    assert!(policy.is_allowed(Some("CN")));
+    assert!(!policy.is_allowed(None));
+  }
+}
@@ -0,0 +1,361 @@
+use crate::config::{data_dir, product_dir, GatewayConfig};
+use crate::deploy::{self, DeployRecord, DeployStatus};
+use crate::secrets;
+use crate::signature::verify_with_keys;
+use crate::util::{now_millis, read_json, write_json};
+use actix_web::HttpRequest;
+use deno_core::error::{custom_error, generic_error, AnyError};
+use deno_runtime::at_rest;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use walkdir::WalkDir;
+
+///每个钩子保留的投递记录数
+const MAX_DELIVERIES: usize = 200;
+
+///git 推送钩子 gateway.json 中的 git_hooks<br>
+/// 仓库的 branch 分支有推送时拉取代码并部署到 product_code
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GitHook {
+  pub id: String,   //钩子名称 投递记录按钩子保存
+  pub repo: String, //仓库全名 GitHub 的 owner/name 或 GitLab 的 group/project
+  #[serde(default = "default_branch")]
+  pub branch: String,
+  pub product_code: String,
+  #[serde(default = "default_secret")]
+  pub secret: String, //产品密钥库中的密钥名 GitHub 用来校验签名 GitLab 与 token 比较
+  #[serde(default)]
+  pub clone_url: Option<String>, //拉取地址 默认使用推送事件中的地址
+  #[serde(default)]
+  pub subdir: Option<String>, //产品代码在仓库中的子目录
+}
+
+fn default_branch() -> String {
+  "main".to_string()
+}
+
+fn default_secret() -> String {
+  "git-webhook".to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+  Github,
+  Gitlab,
+}
+
+///推送事件中部署需要的信息
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Push {
+  pub repo: String,
+  pub branch: String,
+  pub commit: String,
+  pub clone_url: String,
+  pub pusher: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+  Running,
+  Deployed,
+  Blocked,    //流水线没有通过
+  RolledBack, //冒烟测试失败 已回滚
+  Failed,     //拉取代码或部署出错
+}
+
+///一次投递 重放时使用记录中的提交重新部署
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delivery {
+  pub id: String,
+  pub hook: String,
+  pub provider: Provider,
+  pub push: Push,
+  pub received_at: u64,
+  pub finished_at: Option<u64>,
+  pub status: DeliveryStatus,
+  pub version: Option<String>, //部署生成的版本
+  pub error: Option<String>,
+  pub replay_of: Option<String>, //重放的原投递
+}
+
+lazy_static! {
+  //读改写投递记录文件
+  static ref STORE_LOCK: Mutex<()> = Mutex::new(());
+  //同一时间只执行一次拉取和部署 避免两次推送交错写入产品目录 GitOps 同步也使用这个锁
+  pub static ref DEPLOY_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+///钩子的投递记录 data/hooks/{hook}.json
+fn deliveries_path(hook: &str) -> PathBuf {
+  data_dir().join("hooks").join(format!("{}.json", hook))
+}
+
+///仓库的本地检出 data/git/{product_code}
+pub fn checkout_dir(product_code: &str) -> PathBuf {
+  data_dir().join("git").join(product_code)
+}
+
+fn hooks() -> Vec<GitHook> {
+  GatewayConfig::load().map(|c| c.git_hooks).unwrap_or_default()
+}
+
+fn find_hook(id: &str) -> Result<GitHook, AnyError> {
+  hooks()
+    .into_iter()
+    .find(|h| h.id == id)
+    .ok_or_else(|| custom_error("NotFound", format!("git hook {} not found", id)))
+}
+
+///按事件类型请求头识别来源 不是推送事件时为空
+pub fn detect(req: &HttpRequest) -> Result<Option<Provider>, AnyError> {
+  let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+  match (header("x-github-event"), header("x-gitlab-event")) {
+    (Some("push"), _) => Ok(Some(Provider::Github)),
+    (_, Some("Push Hook")) => Ok(Some(Provider::Gitlab)),
+    (Some(_), _) | (_, Some(_)) => Ok(None),
+    _ => Err(generic_error("not a GitHub or GitLab webhook")),
+  }
+}
+
+///解析推送事件 删除分支的推送没有可部署的提交
+pub fn parse_push(provider: Provider, body: &[u8]) -> Result<Push, AnyError> {
+  let event: serde_json::Value = serde_json::from_slice(body)?;
+  let text = |pointer: &str| event.pointer(pointer).and_then(|v| v.as_str()).map(|s| s.to_string());
+  let (repo, clone_url, commit, pusher) = match provider {
+    Provider::Github => (
+      text("/repository/full_name"),
+      text("/repository/clone_url"),
+      text("/after"),
+      text("/pusher/name"),
+    ),
+    Provider::Gitlab => (
+      text("/project/path_with_namespace"),
+      text("/project/git_http_url"),
+      text("/checkout_sha").or_else(|| text("/after")),
+      text("/user_username"),
+    ),
+  };
+  let branch = text("/ref").and_then(|r| r.strip_prefix("refs/heads/").map(|b| b.to_string()));
+  match (repo, branch, commit, clone_url) {
+    (Some(repo), Some(branch), Some(commit), Some(clone_url)) if commit.chars().any(|c| c != '0') => Ok(Push {
+      repo,
+      branch,
+      commit,
+      clone_url,
+      pusher,
+    }),
+    _ => Err(generic_error("push event without a branch commit")),
+  }
+}
+
+fn verify(hook: &GitHook, provider: Provider, req: &HttpRequest, body: &[u8]) -> Result<(), AnyError> {
+  let keys = secrets::active_values(&hook.product_code, &hook.secret)?;
+  if keys.is_empty() {
+    return Err(custom_error("Forbidden", format!("webhook secret {} not configured", hook.secret)));
+  }
+  let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).unwrap_or_default();
+  let valid = match provider {
+    Provider::Github => verify_with_keys(&keys, body, header("x-hub-signature-256")),
+    Provider::Gitlab => {
+      let token = header("x-gitlab-token");
+      keys
+        .iter()
+        .any(|k| ring::constant_time::verify_slices_are_equal(k.as_bytes(), token.as_bytes()).is_ok())
+    }
+  };
+  match valid {
+    true => Ok(()),
+    false => Err(custom_error("Unauthorized", "invalid webhook signature")),
+  }
+}
+
+fn save(delivery: &Delivery) -> Result<(), AnyError> {
+  let _lock = STORE_LOCK.lock().unwrap();
+  let path = deliveries_path(&delivery.hook);
+  let mut deliveries: Vec<Delivery> = read_json(path.clone())?;
+  match deliveries.iter_mut().find(|d| d.id == delivery.id) {
+    Some(d) => *d = delivery.clone(),
+    None => deliveries.push(delivery.clone()),
+  }
+  if deliveries.len() > MAX_DELIVERIES {
+    deliveries.drain(..deliveries.len() - MAX_DELIVERIES);
+  }
+  write_json(path, &deliveries)
+}
+
+///钩子的投递记录 新的在前
+pub fn deliveries(hook: &str) -> Result<Vec<Delivery>, AnyError> {
+  find_hook(hook)?;
+  let mut deliveries: Vec<Delivery> = read_json(deliveries_path(hook))?;
+  deliveries.reverse();
+  Ok(deliveries)
+}
+
+///执行 git 命令 返回标准输出
+pub fn git(dir: &Path, args: &[&str]) -> Result<String, AnyError> {
+  let output = Command::new("git").arg("-C").arg(dir).args(args).output()?;
+  if !output.status.success() {
+    return Err(generic_error(format!(
+      "git {} failed: {}",
+      args.join(" "),
+      String::from_utf8_lossy(&output.stderr).trim()
+    )));
+  }
+  Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+///把仓库的提交或分支浅拉取到 dir 返回检出的提交
+pub fn checkout(dir: &Path, url: &str, rev: &str) -> Result<String, AnyError> {
+  if !dir.join(".git").is_dir() {
+    std::fs::create_dir_all(dir)?;
+    git(dir, &["init", "--quiet"])?;
+  }
+  git(dir, &["fetch", "--quiet", "--depth", "1", url, rev])?;
+  git(dir, &["checkout", "--quiet", "--force", "--detach", "FETCH_HEAD"])?;
+  git(dir, &["rev-parse", "HEAD"])
+}
+
+///用检出目录 dir 中的 subdir 替换产品代码 开启静态加密时加密写入
+pub fn replace_code(dir: &Path, subdir: Option<&str>, product_code: &str) -> Result<(), AnyError> {
+  let source = match subdir {
+    Some(subdir) => dir.join(subdir),
+    None => dir.to_path_buf(),
+  };
+  if !source.is_dir() {
+    return Err(generic_error(format!("directory {} not found in the repository", subdir.unwrap_or(""))));
+  }
+  let target = product_dir(product_code);
+  if target.exists() {
+    std::fs::remove_dir_all(&target)?;
+  }
+  let entries = WalkDir::new(&source)
+    .into_iter()
+    .filter_entry(|e| e.file_name() != ".git")
+    .filter_map(|e| e.ok());
+  for entry in entries {
+    let to = target.join(entry.path().strip_prefix(&source).unwrap());
+    if entry.file_type().is_dir() {
+      std::fs::create_dir_all(&to)?;
+    } else if entry.file_type().is_file() {
+      std::fs::write(&to, at_rest::encrypt(&std::fs::read(entry.path())?)?)?;
+    }
+  }
+  Ok(())
+}
+
+///拉取推送的提交并替换产品代码
+fn pull(hook: &GitHook, push: &Push) -> Result<(), AnyError> {
+  let dir = checkout_dir(&hook.product_code);
+  let url = hook.clone_url.as_deref().unwrap_or(&push.clone_url);
+  checkout(&dir, url, &push.commit)?;
+  replace_code(&dir, hook.subdir.as_deref(), &hook.product_code)
+}
+
+async fn pull_and_deploy(hook: &GitHook, push: &Push) -> Result<DeployRecord, AnyError> {
+  let (h, p) = (hook.clone(), push.clone());
+  tokio::task::spawn_blocking(move || pull(&h, &p)).await??;
+  let author = push.pusher.clone().or_else(|| Some(format!("git hook {}", hook.id)));
+  deploy::deploy_product(&hook.product_code, author).await
+}
+
+async fn run(mut delivery: Delivery, hook: GitHook) {
+  let result = {
+    let _lock = DEPLOY_LOCK.lock().await;
+    pull_and_deploy(&hook, &delivery.push).await
+  };
+  delivery.finished_at = Some(now_millis());
+  match result {
+    Ok(record) => {
+      delivery.version = record.version;
+      delivery.status = match record.status {
+        DeployStatus::Deployed => DeliveryStatus::Deployed,
+        DeployStatus::Blocked | DeployStatus::Aborted => DeliveryStatus::Blocked,
+        DeployStatus::RolledBack => DeliveryStatus::RolledBack,
+      };
+    }
+    Err(err) => {
+      log::error!("git hook {} failed to deploy {}: {}", hook.id, delivery.push.commit, err);
+      delivery.status = DeliveryStatus::Failed;
+      delivery.error = Some(err.to_string());
+    }
+  }
+  if let Err(err) = save(&delivery) {
+    log::error!("failed to save git hook delivery {}: {}", delivery.id, err);
+  }
+}
+
+fn start(hook: GitHook, provider: Provider, push: Push, replay_of: Option<String>) -> Result<Delivery, AnyError> {
+  let delivery = Delivery {
+    id: uuid::Uuid::new_v4().to_string(),
+    hook: hook.id.clone(),
+    provider,
+    push,
+    received_at: now_millis(),
+    finished_at: None,
+    status: DeliveryStatus::Running,
+    version: None,
+    error: None,
+    replay_of,
+  };
+  save(&delivery)?;
+  tokio::spawn(run(delivery.clone(), hook));
+  Ok(delivery)
+}
+
+///接收推送事件 校验签名后在后台拉取代码并部署 返回投递记录<br>
+/// 不是推送事件 例如 GitHub 的 ping 时返回空
+pub fn receive(req: &HttpRequest, body: &[u8]) -> Result<Option<Delivery>, AnyError> {
+  let provider = match detect(req)? {
+    Some(provider) => provider,
+    None => return Ok(None),
+  };
+  let push = parse_push(provider, body)?;
+  let hook = hooks()
+    .into_iter()
+    .find(|h| h.repo.eq_ignore_ascii_case(&push.repo) && h.branch == push.branch)
+    .ok_or_else(|| custom_error("NotFound", format!("no git hook for {} {}", push.repo, push.branch)))?;
+  verify(&hook, provider, req, body)?;
+  start(hook, provider, push, None).map(Some)
+}
+
+///用原投递的提交重新拉取和部署
+pub fn replay(hook: &str, id: &str) -> Result<Delivery, AnyError> {
+  let hook = find_hook(hook)?;
+  let deliveries: Vec<Delivery> = read_json(deliveries_path(&hook.id))?;
+  let original = deliveries
+    .into_iter()
+    .find(|d| d.id == id)
+    .ok_or_else(|| custom_error("NotFound", format!("delivery {} not found", id)))?;
+  start(hook, original.provider, original.push, Some(original.id))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn parses_push_events() {
+    let github = br#"{"ref":"refs/heads/main","after":"a1b2","repository":{"full_name":"acme/shop","clone_url":"https://github.com/acme/shop.git"},"pusher":{"name":"alice"}}"#;
+    let push = parse_push(Provider::Github, github).unwrap();
+    assert_eq!(push.repo, "acme/shop");
+    assert_eq!(push.branch, "main");
+    assert_eq!(push.commit, "a1b2");
+    assert_eq!(push.pusher.as_deref(), Some("alice"));
+
+    let gitlab = br#"{"ref":"refs/heads/dev","checkout_sha":"c3d4","project":{"path_with_namespace":"acme/shop","git_http_url":"https://gitlab.com/acme/shop.git"}}"#;
+    let push = parse_push(Provider::Gitlab, gitlab).unwrap();
+    assert_eq!(push.branch, "dev");
+    assert_eq!(push.commit, "c3d4");
+
+    //删除分支和推送标签都不部署
+    let deleted = br#"{"ref":"refs/heads/main","after":"0000000000","repository":{"full_name":"acme/shop","clone_url":"x"}}"#;
+    assert!(parse_push(Provider::Github, deleted).is_err());
+    let tag = br#"{"ref":"refs/tags/v1","after":"a1b2","repository":{"full_name":"acme/shop","clone_url":"x"}}"#;
+    assert!(parse_push(Provider::Github, tag).is_err());
+  }
+}
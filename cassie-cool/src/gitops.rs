@@ -0,0 +1,552 @@
+use crate::catalog;
+use crate::config::{data_dir, product_dir, GatewayConfig, ProductConfig, PRODUCT_CONFIG_FILE};
+use crate::deploy::{self, DeployStatus};
+use crate::dry_run::{self, Change};
+use crate::encryption;
+use crate::git_hooks::{self, DEPLOY_LOCK};
+use crate::notifier::{self, Notification, Severity};
+use crate::replica;
+use crate::tenants;
+use crate::util::{list_dir, now_millis, read_json, write_json};
+use deno_core::error::{custom_error, generic_error, AnyError};
+use deno_runtime::at_rest;
+use lazy_static::lazy_static;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use walkdir::WalkDir;
+
+///GitOps 同步 gateway.json 中的 gitops<br>
+/// 配置仓库 path 目录下的每个 {product_code}.json 声明一个产品 定时拉取并把平台调整为与清单一致
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GitOpsConfig {
+  pub repo: Option<String>, //配置仓库的拉取地址 不配置时不同步
+  pub branch: String,
+  pub path: String,       //清单所在目录
+  pub interval_secs: u64, //拉取间隔
+  pub approval: bool,     //true 时只生成同步计划 管理员批准后才执行
+}
+
+impl Default for GitOpsConfig {
+  fn default() -> Self {
+    Self {
+      repo: None,
+      branch: "main".to_string(),
+      path: "products".to_string(),
+      interval_secs: 60,
+      approval: false,
+    }
+  }
+}
+
+///产品代码所在的仓库
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Source {
+  pub url: String,
+  #[serde(default = "default_rev")]
+  pub rev: String, //分支 标签或提交
+  #[serde(default)]
+  pub subdir: Option<String>, //产品代码在仓库中的子目录
+}
+
+fn default_rev() -> String {
+  "main".to_string()
+}
+
+///产品清单 没有声明的部分不由 GitOps 管理
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+  #[serde(default)]
+  pub owner: Option<String>, //归属的租户 id
+  #[serde(default)]
+  pub source: Option<Source>,
+  #[serde(default)]
+  pub config: Option<Value>, //完整的 cool.json 包括入口 路由和其他产品配置 覆盖代码仓库中的 cool.json
+}
+
+///与清单不一致的地方和同步时执行的操作
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+  CreateProduct,
+  SetOwner {
+    before: Option<String>,
+    after: String,
+  },
+  UpdateConfig {
+    changes: Vec<Change>,
+  },
+  DeployCode {
+    before: Option<String>, //上次同步的提交
+    after: String,
+    modified: bool, //产品代码在同步之后被其他途径修改过
+  },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProductPlan {
+  pub product_code: String,
+  pub actions: Vec<Action>,
+}
+
+///同步计划 也是偏差报告 id 由内容计算 内容不变时 id 不变
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Plan {
+  pub id: String,
+  pub commit: String, //配置仓库的提交
+  pub products: Vec<ProductPlan>,
+  pub created_at: u64,
+}
+
+///产品上次同步的状态
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncedProduct {
+  pub commit: Option<String>,
+  pub tree_hash: String, //同步后产品代码的摘要 不包括 cool.json
+  pub synced_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProductResult {
+  pub product_code: String,
+  pub status: Option<DeployStatus>,
+  pub version: Option<String>,
+  pub error: Option<String>,
+}
+
+///一次计划的执行结果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApplyReport {
+  pub plan: String,
+  pub commit: String,
+  pub approved_by: Option<String>, //自动同步时为空
+  pub results: Vec<ProductResult>,
+  pub finished_at: u64,
+}
+
+///同步状态 plan 为当前的偏差 为空表示平台与清单一致
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncStatus {
+  pub enabled: bool,
+  pub approval: bool,
+  pub commit: Option<String>,
+  pub checked_at: Option<u64>,
+  pub plan: Option<Plan>,
+  pub unmanaged: Vec<String>, //平台上有但没有清单的产品 只报告不删除
+  pub last_applied: Option<ApplyReport>,
+  pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct SyncState {
+  products: BTreeMap<String, SyncedProduct>,
+  status: SyncStatus,
+}
+
+///计算偏差时产品的当前状态
+#[derive(Debug, Clone, Default)]
+struct Current {
+  exists: bool,
+  owner: Option<String>,
+  config: Value,
+  tree_hash: String,
+  synced: Option<SyncedProduct>,
+}
+
+lazy_static! {
+  //拉取 计算计划和执行计划同一时间只进行一次 批准时计划不会被替换
+  static ref SYNC_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+///同步状态 data/gitops/state.json
+fn state_path() -> PathBuf {
+  data_dir().join("gitops").join("state.json")
+}
+
+///配置仓库的本地检出 data/gitops/repo
+fn repo_dir() -> PathBuf {
+  data_dir().join("gitops").join("repo")
+}
+
+fn config() -> GitOpsConfig {
+  GatewayConfig::load().map(|c| c.gitops).unwrap_or_default()
+}
+
+///产品代码的摘要 cool.json 由清单单独比较 解密后计算 不受静态加密影响
+fn tree_hash(product_code: &str) -> Result<String, AnyError> {
+  let dir = product_dir(product_code);
+  if !dir.is_dir() {
+    return Ok(String::new());
+  }
+  let mut ctx = digest::Context::new(&digest::SHA256);
+  let entries = WalkDir::new(&dir).sort_by_file_name().into_iter().filter_map(|e| e.ok());
+  for entry in entries.filter(|e| e.file_type().is_file()) {
+    let rel = entry.path().strip_prefix(&dir).unwrap().to_string_lossy().replace('\\', "/");
+    if rel == PRODUCT_CONFIG_FILE {
+      continue;
+    }
+    let bytes = at_rest::decrypt(std::fs::read(entry.path())?)?;
+    ctx.update(rel.as_bytes());
+    ctx.update(&[0]);
+    ctx.update(&(bytes.len() as u64).to_le_bytes());
+    ctx.update(&bytes);
+  }
+  Ok(hex::encode(ctx.finish()))
+}
+
+fn read_product_config(product_code: &str) -> Result<Value, AnyError> {
+  match std::fs::read(product_dir(product_code).join(PRODUCT_CONFIG_FILE)) {
+    Ok(bytes) => Ok(serde_json::from_slice(&at_rest::decrypt(bytes)?)?),
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(serde_json::json!({})),
+    Err(err) => Err(err.into()),
+  }
+}
+
+///读取检出中的清单 文件名为产品编码 清单中的 cool.json 必须能按产品配置解析
+fn load_manifests(path: &str) -> Result<BTreeMap<String, Manifest>, AnyError> {
+  let mut manifests = BTreeMap::new();
+  for (name, file) in list_dir(repo_dir().join(path))? {
+    let product_code = match name.strip_suffix(".json") {
+      Some(code) if file.is_file() => code.to_string(),
+      _ => continue,
+    };
+    if !tenants::valid_code(&product_code) {
+      return Err(generic_error(format!("invalid product code in manifest {}", name)));
+    }
+    let manifest: Manifest =
+      serde_json::from_slice(&std::fs::read(&file)?).map_err(|err| generic_error(format!("invalid manifest {}: {}", name, err)))?;
+    if let Some(config) = &manifest.config {
+      serde_json::from_value::<ProductConfig>(config.clone())
+        .map_err(|err| generic_error(format!("invalid config in manifest {}: {}", name, err)))?;
+    }
+    manifests.insert(product_code, manifest);
+  }
+  Ok(manifests)
+}
+
+///分支或标签解析为提交 已经是完整提交时直接使用
+fn resolve(source: &Source) -> Result<String, AnyError> {
+  if source.rev.len() == 40 && source.rev.chars().all(|c| c.is_ascii_hexdigit()) {
+    return Ok(source.rev.clone());
+  }
+  let output = git_hooks::git(&repo_dir(), &["ls-remote", &source.url, &source.rev])?;
+  output
+    .split_whitespace()
+    .next()
+    .map(|commit| commit.to_string())
+    .ok_or_else(|| generic_error(format!("{} not found in {}", source.rev, source.url)))
+}
+
+///单个产品与清单的偏差
+fn diff_product(manifest: &Manifest, current: &Current, commit: Option<&str>) -> Result<Vec<Action>, AnyError> {
+  let mut actions = vec![];
+  if !current.exists {
+    actions.push(Action::CreateProduct);
+  }
+  if let Some(owner) = &manifest.owner {
+    if current.owner.as_ref() != Some(owner) {
+      actions.push(Action::SetOwner {
+        before: current.owner.clone(),
+        after: owner.clone(),
+      });
+    }
+  }
+  if let Some(commit) = commit {
+    let synced = current.synced.as_ref();
+    let modified = current.exists && synced.map(|s| s.tree_hash != current.tree_hash).unwrap_or(false);
+    let before = synced.and_then(|s| s.commit.clone());
+    if modified || before.as_deref() != Some(commit) {
+      actions.push(Action::DeployCode {
+        before,
+        after: commit.to_string(),
+        modified,
+      });
+    }
+  }
+  if let Some(config) = &manifest.config {
+    let changes = dry_run::preview(&current.config, config)?.changes;
+    if !changes.is_empty() {
+      actions.push(Action::UpdateConfig { changes });
+    }
+  }
+  Ok(actions)
+}
+
+fn plan_id(commit: &str, products: &[ProductPlan]) -> Result<String, AnyError> {
+  let mut ctx = digest::Context::new(&digest::SHA256);
+  ctx.update(commit.as_bytes());
+  ctx.update(&serde_json::to_vec(products)?);
+  Ok(hex::encode(ctx.finish())[..16].to_string())
+}
+
+///拉取配置仓库并计算偏差 返回计划 清单和没有清单的产品
+fn check(config: &GitOpsConfig, repo: &str, state: &SyncState) -> Result<(Plan, BTreeMap<String, Manifest>, Vec<String>), AnyError> {
+  let commit = git_hooks::checkout(&repo_dir(), repo, &config.branch)?;
+  let manifests = load_manifests(&config.path)?;
+  let mut products = vec![];
+  for (product_code, manifest) in &manifests {
+    let exists = product_dir(product_code).is_dir();
+    let current = Current {
+      exists,
+      owner: tenants::product_meta(product_code)?.map(|m| m.owner),
+      config: match exists {
+        true => read_product_config(product_code)?,
+        false => serde_json::json!({}),
+      },
+      tree_hash: tree_hash(product_code)?,
+      synced: state.products.get(product_code).cloned(),
+    };
+    let source_commit = manifest.source.as_ref().map(resolve).transpose()?;
+    let actions = diff_product(manifest, &current, source_commit.as_deref())?;
+    if !actions.is_empty() {
+      products.push(ProductPlan {
+        product_code: product_code.clone(),
+        actions,
+      });
+    }
+  }
+  let unmanaged = catalog::product_codes()?
+    .into_iter()
+    .filter(|code| !manifests.contains_key(code))
+    .collect();
+  let plan = Plan {
+    id: plan_id(&commit, &products)?,
+    commit,
+    products,
+    created_at: now_millis(),
+  };
+  Ok((plan, manifests, unmanaged))
+}
+
+///按计划调整一个产品 代码或配置有变化时部署
+async fn apply_product(product: &ProductPlan, manifest: &Manifest, commit: &str, state: &mut SyncState) -> Result<ProductResult, AnyError> {
+  let product_code = product.product_code.clone();
+  let mut deploy = false;
+  for action in &product.actions {
+    match action {
+      Action::CreateProduct => {
+        std::fs::create_dir_all(product_dir(&product_code))?;
+        deploy = true;
+      }
+      Action::SetOwner { after, .. } => {
+        tenants::assign_owner(&product_code, after, false)?;
+      }
+      Action::DeployCode { after, .. } => {
+        let source = manifest.source.clone().ok_or_else(|| generic_error("manifest has no source"))?;
+        let (code, rev) = (product_code.clone(), after.clone());
+        tokio::task::spawn_blocking(move || {
+          let dir = git_hooks::checkout_dir(&code);
+          git_hooks::checkout(&dir, &source.url, &rev)?;
+          git_hooks::replace_code(&dir, source.subdir.as_deref(), &code)
+        })
+        .await??;
+        deploy = true;
+      }
+      Action::UpdateConfig { .. } => deploy = true,
+    }
+  }
+  //替换代码时也会覆盖 cool.json 清单中有配置时总是重新写入
+  if let (true, Some(config)) = (deploy, &manifest.config) {
+    let path = product_dir(&product_code).join(PRODUCT_CONFIG_FILE);
+    encryption::write(&path, serde_json::to_vec_pretty(config)?).await?;
+  }
+  let mut result = ProductResult {
+    product_code: product_code.clone(),
+    status: None,
+    version: None,
+    error: None,
+  };
+  if deploy {
+    let record = deploy::deploy_product(&product_code, Some(format!("gitops {}", &commit[..commit.len().min(7)]))).await?;
+    result.status = Some(record.status);
+    result.version = record.version;
+  }
+  let source_commit = product.actions.iter().find_map(|a| match a {
+    Action::DeployCode { after, .. } => Some(after.clone()),
+    _ => None,
+  });
+  let synced = state.products.get(&product_code).and_then(|s| s.commit.clone());
+  state.products.insert(
+    product_code.clone(),
+    SyncedProduct {
+      commit: source_commit.or(synced),
+      tree_hash: tree_hash(&product_code)?,
+      synced_at: now_millis(),
+    },
+  );
+  Ok(result)
+}
+
+async fn apply(plan: &Plan, manifests: &BTreeMap<String, Manifest>, approved_by: Option<String>, state: &mut SyncState) -> ApplyReport {
+  let _lock = DEPLOY_LOCK.lock().await;
+  let mut results = vec![];
+  for product in &plan.products {
+    let manifest = manifests.get(&product.product_code).cloned().unwrap_or_default();
+    let result = match apply_product(product, &manifest, &plan.commit, state).await {
+      Ok(result) => result,
+      Err(err) => {
+        log::error!("gitops failed to sync {}: {}", product.product_code, err);
+        ProductResult {
+          product_code: product.product_code.clone(),
+          status: None,
+          version: None,
+          error: Some(err.to_string()),
+        }
+      }
+    };
+    results.push(result);
+  }
+  ApplyReport {
+    plan: plan.id.clone(),
+    commit: plan.commit.clone(),
+    approved_by,
+    results,
+    finished_at: now_millis(),
+  }
+}
+
+///拉取并计算偏差 有新的偏差时发送通知 自动模式下直接执行<br>
+/// dry_run 时只返回偏差 不保存 不通知也不执行
+pub async fn sync(dry_run: bool) -> Result<SyncStatus, AnyError> {
+  let config = config();
+  let repo = config.repo.clone().ok_or_else(|| generic_error("gitops repository not configured"))?;
+  let _lock = SYNC_LOCK.lock().await;
+  let mut state: SyncState = read_json(state_path())?;
+  state.status.enabled = true;
+  state.status.approval = config.approval;
+  state.status.checked_at = Some(now_millis());
+  let snapshot = state.clone();
+  let (plan, manifests, unmanaged) = match tokio::task::spawn_blocking(move || check(&config, &repo, &snapshot)).await? {
+    Ok(checked) => checked,
+    Err(err) if dry_run => return Err(err),
+    Err(err) => {
+      state.status.error = Some(err.to_string());
+      write_json(state_path(), &state)?;
+      return Err(err);
+    }
+  };
+  state.status.error = None;
+  state.status.commit = Some(plan.commit.clone());
+  state.status.unmanaged = unmanaged;
+  let previous = state.status.plan.take().map(|p| p.id);
+  if plan.products.is_empty() {
+    state.status.plan = None;
+  } else if dry_run {
+    state.status.plan = Some(plan);
+    return Ok(state.status);
+  } else {
+    if previous.as_ref() != Some(&plan.id) {
+      notifier::notify(Notification::new(
+        "gitops.drift",
+        "",
+        Severity::Warning,
+        format!("{} products differ from gitops commit {}", plan.products.len(), plan.commit),
+        serde_json::to_value(&plan)?,
+      ));
+    }
+    match state.status.approval {
+      true => state.status.plan = Some(plan),
+      false => {
+        let report = apply(&plan, &manifests, None, &mut state).await;
+        state.status.last_applied = Some(report);
+      }
+    }
+  }
+  if !dry_run {
+    write_json(state_path(), &state)?;
+  }
+  Ok(state.status)
+}
+
+///批准并执行待批准的计划 计划已被新的提交或偏差替换时拒绝
+pub async fn approve(id: &str, approved_by: Option<String>) -> Result<ApplyReport, AnyError> {
+  let config = config();
+  let _lock = SYNC_LOCK.lock().await;
+  let mut state: SyncState = read_json(state_path())?;
+  let plan = match state.status.plan.clone() {
+    Some(plan) if plan.id == id => plan,
+    Some(plan) => return Err(generic_error(format!("plan {} has been replaced by {}", id, plan.id))),
+    None => return Err(custom_error("NotFound", format!("plan {} not found", id))),
+  };
+  let path = config.path.clone();
+  let manifests = tokio::task::spawn_blocking(move || load_manifests(&path)).await??;
+  let report = apply(&plan, &manifests, approved_by, &mut state).await;
+  state.status.plan = None;
+  state.status.last_applied = Some(report.clone());
+  write_json(state_path(), &state)?;
+  Ok(report)
+}
+
+///同步状态和当前的偏差
+pub fn status() -> Result<SyncStatus, AnyError> {
+  let config = config();
+  let state: SyncState = read_json(state_path())?;
+  Ok(SyncStatus {
+    enabled: config.repo.is_some(),
+    approval: config.approval,
+    ..state.status
+  })
+}
+
+///配置了仓库时定时同步
+pub fn start() {
+  let config = config();
+  //只读副本跟随主实例 不自己同步
+  if config.repo.is_none() || config.interval_secs == 0 || replica::enabled() {
+    return;
+  }
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+      interval.tick().await;
+      if let Err(err) = sync(false).await {
+        log::error!("gitops sync failed: {}", err);
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn detects_drift() {
+    let manifest = Manifest {
+      owner: Some("t1".to_string()),
+      source: None,
+      config: Some(json!({"runtime": {"entry": "main.ts"}})),
+    };
+    let mut current = Current {
+      exists: true,
+      owner: Some("t1".to_string()),
+      config: json!({"runtime": {"entry": "main.ts"}}),
+      tree_hash: "a".to_string(),
+      synced: Some(SyncedProduct {
+        commit: Some("c1".to_string()),
+        tree_hash: "a".to_string(),
+        synced_at: 0,
+      }),
+    };
+    assert!(diff_product(&manifest, &current, Some("c1")).unwrap().is_empty());
+    current.tree_hash = "b".to_string();
+    current.config = json!({"runtime": {"entry": "old.ts"}});
+    let actions = diff_product(&manifest, &current, Some("c1")).unwrap();
+    assert_eq!(
+      actions[0],
+      Action::DeployCode {
+        before: Some("c1".to_string()),
+        after: "c1".to_string(),
+        modified: true,
+      }
+    );
+    assert!(matches!(&actions[1], Action::UpdateConfig { changes } if changes[0].path == "runtime.entry"));
+    let created = diff_product(&manifest, &Current::default(), None).unwrap();
+    assert_eq!(created[0], Action::CreateProduct);
+  }
+}
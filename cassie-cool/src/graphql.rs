@@ -0,0 +1,1001 @@
+use crate::bandwidth::{self, Direction};
+use crate::catalog;
+use crate::check_policies;
+use crate::config::{product_dir, GatewayConfig, ProductConfig};
+use crate::crash;
+use crate::metrics;
+use crate::signature;
+use crate::worker_util::{self, PortUnavailable, ScriptWorkerId};
+use actix_web::http::header::{AUTHORIZATION, CONTENT_TYPE, COOKIE};
+use actix_web::http::Method;
+use actix_web::{guard, web, HttpRequest, HttpResponse};
+use awc::Client;
+use futures_util::future::join_all;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::RwLock;
+use std::time::Duration;
+
+///重新读取产品 schema 的间隔
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+///产品 GraphQL 响应的大小上限
+const MAX_RESPONSE_BYTES: usize = 10 * 1024 * 1024;
+///SDL 中开始一个定义的关键字
+const DEFINITION_KEYWORDS: [&str; 9] = ["schema", "scalar", "type", "interface", "union", "enum", "input", "directive", "extend"];
+
+///网关的 GraphQL 入口 gateway.json 中的 graphql<br>
+/// 按根字段把查询拆分给注册了 schema 的产品 同时转发后合并结果 mutation 按字段顺序依次转发
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GraphqlConfig {
+  pub enabled: bool,
+  pub path: String,
+  pub timeout_ms: u64, //单个产品的超时
+}
+
+impl Default for GraphqlConfig {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      path: "/graphql".to_string(),
+      timeout_ms: 10_000,
+    }
+  }
+}
+
+///产品注册的 GraphQL schema cool.json 中的 graphql<br>
+/// schema 为产品目录下的 SDL 文件 其中 Query 和 Mutation 的字段由网关转发到 endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GraphqlSchema {
+  pub schema: Option<String>,
+  pub endpoint: String,
+}
+
+impl Default for GraphqlSchema {
+  fn default() -> Self {
+    Self {
+      schema: None,
+      endpoint: "/graphql".to_string(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum OperationKind {
+  Query,
+  Mutation,
+  Subscription,
+}
+
+impl OperationKind {
+  fn keyword(&self) -> &'static str {
+    match self {
+      Self::Query => "query",
+      Self::Mutation => "mutation",
+      Self::Subscription => "subscription",
+    }
+  }
+
+  fn root_type(&self) -> &'static str {
+    match self {
+      Self::Query => "Query",
+      Self::Mutation => "Mutation",
+      Self::Subscription => "Subscription",
+    }
+  }
+}
+
+///注册的产品和它提供的根字段
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisteredSchema {
+  pub product_code: String,
+  pub endpoint: String,
+  pub query: Vec<String>,
+  pub mutation: Vec<String>,
+  pub error: Option<String>, //SDL 读取或解析失败的原因
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GraphqlReport {
+  pub config: GraphqlConfig,
+  pub products: Vec<RegisteredSchema>,
+  pub conflicts: Vec<String>, //多个产品提供的同名根字段 按产品编码排序后第一个生效
+}
+
+lazy_static! {
+  static ref CONFIG: RwLock<GraphqlConfig> = RwLock::new(GraphqlConfig::default());
+  static ref REGISTRY: RwLock<Registry> = RwLock::new(Registry::default());
+}
+
+#[derive(Default)]
+struct Registry {
+  products: Vec<RegisteredSchema>,
+  conflicts: Vec<String>,
+  owners: HashMap<(OperationKind, String), (String, String)>, //(操作类型, 根字段) -> (产品, endpoint)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+  Punct(u8),
+  Spread,
+  Name,
+  Value, //数字和字符串
+}
+
+#[derive(Debug, Clone)]
+struct Token<'a> {
+  kind: Kind,
+  text: &'a str,
+  start: usize,
+  end: usize,
+}
+
+///GraphQL 的词法分析 逗号和注释忽略
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+  let bytes = src.as_bytes();
+  let mut tokens = vec![];
+  let mut i = 0;
+  while i < bytes.len() {
+    let start = i;
+    let kind = match bytes[i] {
+      b' ' | b'\t' | b'\n' | b'\r' | b',' => {
+        i += 1;
+        continue;
+      }
+      b'#' => {
+        while i < bytes.len() && bytes[i] != b'\n' && bytes[i] != b'\r' {
+          i += 1;
+        }
+        continue;
+      }
+      _ if src[i..].starts_with('\u{feff}') => {
+        i += 3;
+        continue;
+      }
+      b'.' if src[i..].starts_with("...") => {
+        i += 3;
+        Kind::Spread
+      }
+      c @ (b'!' | b'$' | b'&' | b'(' | b')' | b':' | b'=' | b'@' | b'[' | b']' | b'{' | b'|' | b'}') => {
+        i += 1;
+        Kind::Punct(c)
+      }
+      b'"' if src[i..].starts_with("\"\"\"") => {
+        i += 3;
+        loop {
+          match src[i..].find("\"\"\"") {
+            Some(offset) if bytes[i + offset - 1] == b'\\' => i += offset + 3,
+            Some(offset) => {
+              i += offset + 3;
+              break;
+            }
+            None => return Err("unterminated block string".to_string()),
+          }
+        }
+        Kind::Value
+      }
+      b'"' => {
+        i += 1;
+        loop {
+          match bytes.get(i) {
+            Some(b'\\') => i += 2,
+            Some(b'"') => break,
+            Some(b'\n' | b'\r') | None => return Err("unterminated string".to_string()),
+            Some(_) => i += 1,
+          }
+        }
+        i += 1;
+        Kind::Value
+      }
+      c if c == b'_' || c.is_ascii_alphabetic() => {
+        while i < bytes.len() && (bytes[i] == b'_' || bytes[i].is_ascii_alphanumeric()) {
+          i += 1;
+        }
+        Kind::Name
+      }
+      c if c == b'-' || c.is_ascii_digit() => {
+        i += 1;
+        while i < bytes.len()
+          && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'.' || (matches!(bytes[i], b'+' | b'-') && matches!(bytes[i - 1], b'e' | b'E')))
+        {
+          i += 1;
+        }
+        Kind::Value
+      }
+      _ => {
+        let c = src[i..].chars().next().unwrap_or_default();
+        return Err(format!("unexpected character {:?} at {}", c, i));
+      }
+    };
+    tokens.push(Token {
+      kind,
+      text: &src[start..i],
+      start,
+      end: i,
+    });
+  }
+  Ok(tokens)
+}
+
+fn is_punct(tokens: &[Token], i: usize, c: u8) -> bool {
+  tokens.get(i).map(|t| t.kind == Kind::Punct(c)).unwrap_or(false)
+}
+
+fn name_at<'a>(tokens: &[Token<'a>], i: usize) -> Result<&'a str, String> {
+  match tokens.get(i) {
+    Some(t) if t.kind == Kind::Name => Ok(t.text),
+    Some(t) => Err(format!("expected a name, found {:?}", t.text)),
+    None => Err("expected a name, found the end of the document".to_string()),
+  }
+}
+
+///i 为开始的括号 返回匹配的括号之后的位置
+fn skip_balanced(tokens: &[Token], i: usize) -> Result<usize, String> {
+  let Kind::Punct(open) = tokens[i].kind else {
+    return Ok(i + 1);
+  };
+  let close = match open {
+    b'(' => b')',
+    b'[' => b']',
+    _ => b'}',
+  };
+  let mut depth = 0;
+  for (j, token) in tokens.iter().enumerate().skip(i) {
+    match token.kind {
+      Kind::Punct(c) if c == open => depth += 1,
+      Kind::Punct(c) if c == close => {
+        depth -= 1;
+        if depth == 0 {
+          return Ok(j + 1);
+        }
+      }
+      _ => {}
+    }
+  }
+  Err(format!("unclosed {:?}", open as char))
+}
+
+///跳过指令 @name(args)
+fn skip_directives(tokens: &[Token], mut i: usize) -> Result<usize, String> {
+  while is_punct(tokens, i, b'@') {
+    name_at(tokens, i + 1)?;
+    i += 2;
+    if is_punct(tokens, i, b'(') {
+      i = skip_balanced(tokens, i)?;
+    }
+  }
+  Ok(i)
+}
+
+///跳过类型引用 例如 [Int!]!
+fn skip_type(tokens: &[Token], mut i: usize) -> Result<usize, String> {
+  while is_punct(tokens, i, b'[') {
+    i += 1;
+  }
+  name_at(tokens, i)?;
+  i += 1;
+  if is_punct(tokens, i, b'!') {
+    i += 1;
+  }
+  while is_punct(tokens, i, b']') {
+    i += 1;
+    if is_punct(tokens, i, b'!') {
+      i += 1;
+    }
+  }
+  Ok(i)
+}
+
+///SDL 中根类型的字段 返回 (query 字段, mutation 字段)<br>
+/// schema { query: ... } 可以重新指定根类型的名称 extend type 的字段合并到原来的类型
+fn root_fields(sdl: &str) -> Result<(Vec<String>, Vec<String>), String> {
+  let tokens = tokenize(sdl)?;
+  let mut roots: HashMap<String, String> = HashMap::new();
+  let mut types: HashMap<String, Vec<String>> = HashMap::new();
+  let mut i = 0;
+  while i < tokens.len() {
+    let token = &tokens[i];
+    match (token.kind, token.text) {
+      (Kind::Value, _) | (Kind::Name, "extend") => i += 1,
+      (Kind::Name, "schema") => {
+        i = skip_directives(&tokens, i + 1)?;
+        if is_punct(&tokens, i, b'{') {
+          i += 1;
+          while !is_punct(&tokens, i, b'}') {
+            let operation = name_at(&tokens, i)?;
+            if !is_punct(&tokens, i + 1, b':') {
+              return Err(format!("expected \":\" after {}", operation));
+            }
+            roots.insert(operation.to_string(), name_at(&tokens, i + 2)?.to_string());
+            i += 3;
+          }
+          i += 1;
+        }
+      }
+      (Kind::Name, "type") => {
+        let type_name = name_at(&tokens, i + 1)?;
+        i += 2;
+        if tokens.get(i).map(|t| t.text == "implements").unwrap_or(false) {
+          i += 1;
+          while is_punct(&tokens, i, b'&') || tokens.get(i).map(|t| t.kind == Kind::Name).unwrap_or(false) {
+            i += 1;
+          }
+        }
+        i = skip_directives(&tokens, i)?;
+        if !is_punct(&tokens, i, b'{') {
+          continue;
+        }
+        let fields = types.entry(type_name.to_string()).or_default();
+        i += 1;
+        while !is_punct(&tokens, i, b'}') {
+          if tokens.get(i).map(|t| t.kind == Kind::Value).unwrap_or(false) {
+            i += 1;
+            continue;
+          }
+          fields.push(name_at(&tokens, i)?.to_string());
+          i += 1;
+          if is_punct(&tokens, i, b'(') {
+            i = skip_balanced(&tokens, i)?;
+          }
+          if !is_punct(&tokens, i, b':') {
+            return Err(format!("expected \":\" after {}.{}", type_name, fields.last().unwrap()));
+          }
+          i = skip_directives(&tokens, skip_type(&tokens, i + 1)?)?;
+        }
+        i += 1;
+      }
+      //其他定义连同名称跳过 直到下一个定义开始
+      _ => {
+        i += 2;
+        while let Some(token) = tokens.get(i) {
+          match token.kind {
+            Kind::Punct(b'{' | b'(' | b'[') => i = skip_balanced(&tokens, i)?,
+            Kind::Value => break,
+            Kind::Name if DEFINITION_KEYWORDS.contains(&token.text) && !is_punct(&tokens, i - 1, b'@') => break,
+            _ => i += 1,
+          }
+        }
+      }
+    }
+  }
+  let mut fields = |operation: &str, default: &str| {
+    let type_name = roots.get(operation).map(|t| t.as_str()).unwrap_or(default);
+    types.remove(type_name).unwrap_or_default()
+  };
+  Ok((fields("query", "Query"), fields("mutation", "Mutation")))
+}
+
+///一段请求中引用的变量和片段
+#[derive(Debug, Default)]
+struct References {
+  variables: BTreeSet<String>,
+  fragments: BTreeSet<String>,
+}
+
+fn references(tokens: &[Token]) -> References {
+  let mut refs = References::default();
+  for pair in tokens.windows(2) {
+    match (pair[0].kind, pair[1].kind) {
+      (Kind::Punct(b'$'), Kind::Name) => {
+        refs.variables.insert(pair[1].text.to_string());
+      }
+      (Kind::Spread, Kind::Name) if pair[1].text != "on" => {
+        refs.fragments.insert(pair[1].text.to_string());
+      }
+      _ => {}
+    }
+  }
+  refs
+}
+
+///根选择集中的一个字段 span 为它在请求中的原文
+#[derive(Debug)]
+struct Selection {
+  key: String, //响应中的名称 有别名时为别名
+  field: String,
+  span: (usize, usize),
+  refs: References,
+}
+
+#[derive(Debug)]
+struct Operation {
+  kind: OperationKind,
+  name: Option<String>,
+  variables: Vec<(String, (usize, usize))>, //变量名和定义的原文
+  selections: Vec<Selection>,
+}
+
+#[derive(Debug)]
+struct Fragment {
+  span: (usize, usize),
+  refs: References,
+}
+
+#[derive(Debug)]
+struct Document<'a> {
+  src: &'a str,
+  operations: Vec<Operation>,
+  fragments: HashMap<String, Fragment>,
+}
+
+///解析变量定义 i 为左括号 返回右括号之后的位置
+fn parse_variables(tokens: &[Token], mut i: usize, variables: &mut Vec<(String, (usize, usize))>) -> Result<usize, String> {
+  let end = skip_balanced(tokens, i)? - 1;
+  i += 1;
+  while i < end {
+    if !is_punct(tokens, i, b'$') {
+      return Err(format!("expected a variable, found {:?}", tokens[i].text));
+    }
+    let name = name_at(tokens, i + 1)?.to_string();
+    let mut j = i + 2;
+    while j < end && !is_punct(tokens, j, b'$') {
+      j = match tokens[j].kind {
+        Kind::Punct(b'{' | b'(' | b'[') => skip_balanced(tokens, j)?,
+        _ => j + 1,
+      };
+    }
+    variables.push((name, (tokens[i].start, tokens[j - 1].end)));
+    i = j;
+  }
+  Ok(end + 1)
+}
+
+///解析根选择集 i 为左花括号 返回右花括号之后的位置
+fn parse_root_selections(tokens: &[Token], mut i: usize, selections: &mut Vec<Selection>) -> Result<usize, String> {
+  i += 1;
+  while !is_punct(tokens, i, b'}') {
+    if tokens.get(i).map(|t| t.kind == Kind::Spread).unwrap_or(false) {
+      return Err("fragments on the root selection set are not supported by the gateway".to_string());
+    }
+    let start = i;
+    let key = name_at(tokens, i)?;
+    let mut field = key;
+    i += 1;
+    if is_punct(tokens, i, b':') {
+      field = name_at(tokens, i + 1)?;
+      i += 2;
+    }
+    if is_punct(tokens, i, b'(') {
+      i = skip_balanced(tokens, i)?;
+    }
+    i = skip_directives(tokens, i)?;
+    if is_punct(tokens, i, b'{') {
+      i = skip_balanced(tokens, i)?;
+    }
+    selections.push(Selection {
+      key: key.to_string(),
+      field: field.to_string(),
+      span: (tokens[start].start, tokens[i - 1].end),
+      refs: references(&tokens[start..i]),
+    });
+  }
+  Ok(i + 1)
+}
+
+fn parse_document(src: &str) -> Result<Document, String> {
+  let tokens = tokenize(src)?;
+  let mut document = Document {
+    src,
+    operations: vec![],
+    fragments: HashMap::new(),
+  };
+  let mut i = 0;
+  while i < tokens.len() {
+    let token = &tokens[i];
+    let kind = match (token.kind, token.text) {
+      (Kind::Name, "fragment") => {
+        let name = name_at(&tokens, i + 1)?;
+        let mut j = skip_directives(&tokens, i + 4)?;
+        if !is_punct(&tokens, j, b'{') {
+          return Err(format!("fragment {} has no selection set", name));
+        }
+        j = skip_balanced(&tokens, j)?;
+        let fragment = Fragment {
+          span: (token.start, tokens[j - 1].end),
+          refs: references(&tokens[i..j]),
+        };
+        document.fragments.insert(name.to_string(), fragment);
+        i = j;
+        continue;
+      }
+      (Kind::Punct(b'{'), _) | (Kind::Name, "query") => OperationKind::Query,
+      (Kind::Name, "mutation") => OperationKind::Mutation,
+      (Kind::Name, "subscription") => OperationKind::Subscription,
+      _ => return Err(format!("unexpected {:?}", token.text)),
+    };
+    let mut operation = Operation {
+      kind,
+      name: None,
+      variables: vec![],
+      selections: vec![],
+    };
+    if token.kind == Kind::Name {
+      i += 1;
+      if let Ok(name) = name_at(&tokens, i) {
+        operation.name = Some(name.to_string());
+        i += 1;
+      }
+      if is_punct(&tokens, i, b'(') {
+        i = parse_variables(&tokens, i, &mut operation.variables)?;
+      }
+      i = skip_directives(&tokens, i)?;
+    }
+    if !is_punct(&tokens, i, b'{') {
+      return Err("expected a selection set".to_string());
+    }
+    i = parse_root_selections(&tokens, i, &mut operation.selections)?;
+    document.operations.push(operation);
+  }
+  Ok(document)
+}
+
+///转发给一个产品的查询
+#[derive(Debug)]
+struct SubQuery {
+  product_code: String,
+  endpoint: String,
+  keys: Vec<String>, //这个产品负责的响应字段
+  query: String,
+  variables: Map<String, Value>,
+}
+
+///按 operationName 选择操作 只有一个操作时可以不指定
+fn select_operation<'d>(document: &'d Document, operation_name: Option<&str>) -> Result<&'d Operation, String> {
+  match operation_name {
+    Some(name) => document
+      .operations
+      .iter()
+      .find(|o| o.name.as_deref() == Some(name))
+      .ok_or_else(|| format!("unknown operation {}", name)),
+    None if document.operations.len() == 1 => Ok(&document.operations[0]),
+    None => Err("operationName is required when the document has several operations".to_string()),
+  }
+}
+
+///按根字段的所属产品拆分操作 每个产品只带上用到的变量和片段<br>
+/// 返回拆分的查询和由网关直接回答的 __typename
+fn plan(
+  document: &Document,
+  operation: &Operation,
+  registry: &Registry,
+  variables: &Map<String, Value>,
+) -> Result<(Vec<SubQuery>, Vec<String>), Vec<String>> {
+  let mut groups: Vec<((String, String), Vec<&Selection>)> = vec![];
+  let mut typenames = vec![];
+  let mut errors = vec![];
+  for selection in operation.selections.iter() {
+    if selection.field == "__typename" {
+      typenames.push(selection.key.clone());
+      continue;
+    }
+    if selection.field.starts_with("__") {
+      errors.push(format!("{} is not supported by the gateway", selection.field));
+      continue;
+    }
+    let Some(owner) = registry.owners.get(&(operation.kind, selection.field.clone())) else {
+      errors.push(format!(
+        "Cannot query field \"{}\" on type \"{}\"",
+        selection.field,
+        operation.kind.root_type()
+      ));
+      continue;
+    };
+    match groups.iter_mut().find(|(o, _)| o == owner) {
+      Some((_, selections)) => selections.push(selection),
+      None => groups.push((owner.clone(), vec![selection])),
+    }
+  }
+  let text = |(start, end): (usize, usize)| &document.src[start..end];
+  let mut queries = vec![];
+  for ((product_code, endpoint), selections) in groups {
+    //片段可以引用其他片段 一直找到不再有新的片段
+    let mut fragments = BTreeSet::new();
+    let mut used_variables = BTreeSet::new();
+    let mut pending: Vec<&String> = vec![];
+    for selection in selections.iter() {
+      used_variables.extend(selection.refs.variables.iter().cloned());
+      pending.extend(selection.refs.fragments.iter());
+    }
+    while let Some(name) = pending.pop() {
+      if !fragments.insert(name.clone()) {
+        continue;
+      }
+      match document.fragments.get(name) {
+        Some(fragment) => {
+          used_variables.extend(fragment.refs.variables.iter().cloned());
+          pending.extend(fragment.refs.fragments.iter());
+        }
+        None => errors.push(format!("unknown fragment {}", name)),
+      }
+    }
+    let definitions: Vec<&str> = operation
+      .variables
+      .iter()
+      .filter(|(name, _)| used_variables.contains(name))
+      .map(|(_, span)| text(*span))
+      .collect();
+    let mut query = operation.kind.keyword().to_string();
+    if let Some(name) = &operation.name {
+      query.push(' ');
+      query.push_str(name);
+    }
+    if !definitions.is_empty() {
+      query.push_str(&format!("({})", definitions.join(", ")));
+    }
+    let fields: Vec<&str> = selections.iter().map(|s| text(s.span)).collect();
+    query.push_str(&format!(" {{ {} }}", fields.join(" ")));
+    for fragment in fragments.iter().filter_map(|name| document.fragments.get(name)) {
+      query.push('\n');
+      query.push_str(text(fragment.span));
+    }
+    let mut keys: Vec<String> = vec![];
+    for selection in selections {
+      if !keys.contains(&selection.key) {
+        keys.push(selection.key.clone());
+      }
+    }
+    queries.push(SubQuery {
+      product_code,
+      endpoint,
+      keys,
+      query,
+      variables: variables
+        .iter()
+        .filter(|(name, _)| used_variables.contains(*name))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect(),
+    });
+  }
+  match errors.is_empty() {
+    true => Ok((queries, typenames)),
+    false => Err(errors),
+  }
+}
+
+///转发给产品 产品没有运行或正在停止时返回错误<br>
+/// 和直接转发一样先检查产品的访问策略 需要签名时校验调用方对整个 GraphQL 请求的签名 body 为收到的请求体
+async fn execute(
+  client: &Client,
+  req: &HttpRequest,
+  body: &[u8],
+  query: &SubQuery,
+  operation_name: Option<&str>,
+  timeout: Duration,
+) -> Result<Value, String> {
+  let product_code = query.product_code.as_str();
+  let config = match ProductConfig::cached(product_code).await {
+    Ok(config) => config.unwrap_or_default(),
+    Err(err) => return Err(format!("config unavailable: {}", err)),
+  };
+  let _admitted = match check_policies(req, product_code, &query.endpoint, &config) {
+    Ok((admitted, _)) => admitted,
+    Err(res) => return Err(format!("rejected by the product access policy: {}", res.status())),
+  };
+  if config.signature.applies_to(&query.endpoint) {
+    signature::verify_request(product_code, &config.signature, req, body)?;
+  }
+  let lease = match worker_util::lease_port(&ScriptWorkerId(query.product_code.clone()), config.balance) {
+    Ok(lease) => lease,
+    Err(PortUnavailable::Draining) => return Err("service is shutting down".to_string()),
+    Err(PortUnavailable::Unhealthy(reason)) => return Err(format!("service is unhealthy: {}", reason)),
+    Err(PortUnavailable::NotFound) => return Err("service not found".to_string()),
+  };
+  let port = lease.0;
+  let _inflight = crash::track(product_code, "POST", &query.endpoint);
+  let mut request = client.post(format!("http://127.0.0.1:{}{}", port.0, query.endpoint)).timeout(timeout);
+  //调用方的凭据原样带给产品
+  for name in [AUTHORIZATION, COOKIE] {
+    for value in req.headers().get_all(&name) {
+      request = request.append_header((name.clone(), value.clone()));
+    }
+  }
+  let body = json!({
+    "query": query.query,
+    "variables": query.variables,
+    "operationName": operation_name,
+  });
+  let body = serde_json::to_vec(&body).map_err(|e| e.to_string())?;
+  if let Some(bucket) = bandwidth::bucket(product_code, Direction::Upload, &config.bandwidth) {
+    bucket.acquire(body.len()).await;
+  }
+  bandwidth::record(product_code, Direction::Upload, body.len());
+  let mut res = request
+    .insert_header((CONTENT_TYPE, "application/json"))
+    .send_body(body)
+    .await
+    .map_err(|e| e.to_string())?;
+  let body = res.body().limit(MAX_RESPONSE_BYTES).await.map_err(|e| e.to_string())?;
+  if let Some(bucket) = bandwidth::bucket(product_code, Direction::Download, &config.bandwidth) {
+    bucket.acquire(body.len()).await;
+  }
+  bandwidth::record(product_code, Direction::Download, body.len());
+  match serde_json::from_slice::<Value>(&body) {
+    Ok(value) if value.is_object() => Ok(value),
+    _ => Err(format!("{} returned {} without a GraphQL response", query.endpoint, res.status())),
+  }
+}
+
+fn count(product_code: &str, result: &str) {
+  metrics::inc_counter(
+    "gateway_graphql_subqueries_total",
+    "GraphQL operations forwarded to products by the gateway",
+    &[("product", product_code), ("result", result)],
+    1,
+  );
+}
+
+#[derive(Debug, Deserialize)]
+struct GraphqlRequest {
+  query: String,
+  #[serde(default)]
+  variables: Option<Map<String, Value>>,
+  #[serde(default, rename = "operationName")]
+  operation_name: Option<String>,
+}
+
+fn errors_response(messages: Vec<String>) -> HttpResponse {
+  let errors: Vec<Value> = messages.into_iter().map(|message| json!({ "message": message })).collect();
+  HttpResponse::BadRequest().json(json!({ "errors": errors }))
+}
+
+///GET 从查询参数读取请求 variables 为 JSON 字符串
+fn parse_request(req: &HttpRequest, body: &[u8]) -> Result<GraphqlRequest, String> {
+  if req.method() == Method::POST {
+    return serde_json::from_slice(body).map_err(|e| format!("invalid request body: {}", e));
+  }
+  let params: HashMap<String, String> = url::form_urlencoded::parse(req.query_string().as_bytes()).into_owned().collect();
+  let variables = match params.get("variables") {
+    Some(variables) => Some(serde_json::from_str(variables).map_err(|e| format!("invalid variables: {}", e))?),
+    None => None,
+  };
+  Ok(GraphqlRequest {
+    query: params.get("query").cloned().ok_or("query is required")?,
+    variables,
+    operation_name: params.get("operationName").cloned(),
+  })
+}
+
+///GraphQL 入口 拆分查询 转发给各个产品后合并结果
+pub async fn handle(req: HttpRequest, body: web::Bytes, client: web::Data<Client>) -> HttpResponse {
+  let request = match parse_request(&req, &body) {
+    Ok(request) => request,
+    Err(err) => return errors_response(vec![err]),
+  };
+  let document = match parse_document(&request.query) {
+    Ok(document) => document,
+    Err(err) => return errors_response(vec![format!("syntax error: {}", err)]),
+  };
+  let operation = match select_operation(&document, request.operation_name.as_deref()) {
+    Ok(operation) => operation,
+    Err(err) => return errors_response(vec![err]),
+  };
+  match operation.kind {
+    OperationKind::Subscription => return errors_response(vec!["subscriptions are not supported by the gateway".to_string()]),
+    OperationKind::Mutation if req.method() == Method::GET => return errors_response(vec!["mutations must use POST".to_string()]),
+    _ => {}
+  }
+  let variables = request.variables.unwrap_or_default();
+  let planned = plan(&document, operation, &REGISTRY.read().unwrap(), &variables);
+  let (queries, typenames) = match planned {
+    Ok(planned) => planned,
+    Err(errors) => return errors_response(errors),
+  };
+  let timeout = Duration::from_millis(CONFIG.read().unwrap().timeout_ms);
+  let operation_name = operation.name.as_deref();
+  let results = match operation.kind {
+    OperationKind::Mutation => {
+      let mut results = vec![];
+      for query in queries.iter() {
+        results.push(execute(&client, &req, &body, query, operation_name, timeout).await);
+      }
+      results
+    }
+    _ => join_all(queries.iter().map(|query| execute(&client, &req, &body, query, operation_name, timeout))).await,
+  };
+  let mut values: HashMap<String, Value> = typenames.into_iter().map(|key| (key, Value::from(operation.kind.root_type()))).collect();
+  let mut errors = vec![];
+  for (query, result) in queries.iter().zip(results) {
+    match result {
+      Ok(mut response) => {
+        count(&query.product_code, "ok");
+        let data = response
+          .get_mut("data")
+          .and_then(|d| d.as_object_mut())
+          .map(std::mem::take)
+          .unwrap_or_default();
+        errors.extend(
+          response
+            .get_mut("errors")
+            .and_then(|e| e.as_array_mut())
+            .map(std::mem::take)
+            .unwrap_or_default(),
+        );
+        for key in query.keys.iter() {
+          values.insert(key.clone(), data.get(key).cloned().unwrap_or(Value::Null));
+        }
+      }
+      Err(err) => {
+        count(&query.product_code, "error");
+        log::warn!("GraphQL operation forwarded to {} failed: {}", query.product_code, err);
+        for key in query.keys.iter() {
+          values.insert(key.clone(), Value::Null);
+          errors.push(json!({
+            "message": format!("{}: {}", query.product_code, err),
+            "path": [key],
+            "extensions": { "product": query.product_code },
+          }));
+        }
+      }
+    }
+  }
+  //按请求中的字段顺序返回
+  let mut data = Map::new();
+  for selection in operation.selections.iter() {
+    if let Some(value) = values.remove(&selection.key) {
+      data.insert(selection.key.clone(), value);
+    }
+  }
+  let mut response = json!({ "data": data });
+  if !errors.is_empty() {
+    response["errors"] = Value::Array(errors);
+  }
+  HttpResponse::Ok().json(response)
+}
+
+///读取产品的 SDL 失败时记录原因
+fn register(product_code: &str, config: &GraphqlSchema) -> Option<RegisteredSchema> {
+  let schema = config.schema.as_ref()?;
+  let mut registered = RegisteredSchema {
+    product_code: product_code.to_string(),
+    endpoint: config.endpoint.clone(),
+    query: vec![],
+    mutation: vec![],
+    error: None,
+  };
+  let fields = std::fs::read_to_string(product_dir(product_code).join(schema))
+    .map_err(|e| e.to_string())
+    .and_then(|sdl| root_fields(&sdl));
+  match fields {
+    Ok((query, mutation)) => {
+      registered.query = query;
+      registered.mutation = mutation;
+    }
+    Err(err) => {
+      log::warn!("failed to load the {} GraphQL schema: {}", product_code, err);
+      registered.error = Some(err);
+    }
+  }
+  Some(registered)
+}
+
+///重新读取所有产品注册的 schema
+fn refresh() {
+  let product_codes = catalog::product_codes().unwrap_or_else(|err| {
+    log::warn!("failed to list products for GraphQL: {}", err);
+    vec![]
+  });
+  let mut registry = Registry::default();
+  for product_code in product_codes {
+    let config = ProductConfig::load(&product_code).map(|c| c.graphql).unwrap_or_default();
+    let Some(registered) = register(&product_code, &config) else {
+      continue;
+    };
+    let fields = [(OperationKind::Query, &registered.query), (OperationKind::Mutation, &registered.mutation)];
+    for (kind, field) in fields.into_iter().flat_map(|(kind, fields)| fields.iter().map(move |f| (kind, f))) {
+      match registry.owners.get(&(kind, field.clone())) {
+        Some((first, _)) => registry.conflicts.push(format!(
+          "{}.{} is provided by {} and {}, using {}",
+          kind.root_type(),
+          field,
+          first,
+          product_code,
+          first
+        )),
+        None => {
+          let owner = (product_code.clone(), registered.endpoint.clone());
+          registry.owners.insert((kind, field.clone()), owner);
+        }
+      }
+    }
+    registry.products.push(registered);
+  }
+  *REGISTRY.write().unwrap() = registry;
+}
+
+///注册的产品 根字段和冲突
+pub fn report() -> GraphqlReport {
+  let registry = REGISTRY.read().unwrap();
+  GraphqlReport {
+    config: CONFIG.read().unwrap().clone(),
+    products: registry.products.clone(),
+    conflicts: registry.conflicts.clone(),
+  }
+}
+
+///开启时挂载 GraphQL 入口 带 product_code 请求头的请求仍然转发给产品
+pub fn routes(cfg: &mut web::ServiceConfig) {
+  let config = CONFIG.read().unwrap().clone();
+  if !config.enabled {
+    return;
+  }
+  cfg.service(
+    web::resource(config.path)
+      .guard(guard::fn_guard(|ctx| !ctx.head().headers().contains_key("product_code")))
+      .route(web::get().to(handle))
+      .route(web::post().to(handle)),
+  );
+}
+
+///读取 GraphQL 配置 开启时定时重新读取产品的 schema
+pub fn start() {
+  let config = GatewayConfig::load().map(|c| c.graphql).unwrap_or_default();
+  *CONFIG.write().unwrap() = config.clone();
+  if !config.enabled {
+    return;
+  }
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+    loop {
+      interval.tick().await;
+      tokio::task::spawn_blocking(refresh).await.ok();
+    }
+  });
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn splits_operations_by_root_field() {
+    let sdl = r#"
+      "Products"
+      type Query implements Node @key(fields: "id") {
+        "one product"
+        product(id: ID!, filter: Filter = {tag: "a"}): Product @deprecated(reason: "use products")
+        products: [Product!]!
+      }
+      enum type { query mutation }
+      schema { query: Query mutation: Writes }
+      extend type Writes { addProduct(name: String): Product }
+    "#;
+    assert_eq!(
+      root_fields(sdl).unwrap(),
+      (vec!["product".to_string(), "products".to_string()], vec!["addProduct".to_string()])
+    );
+    let mut registry = Registry::default();
+    for (field, product_code) in [("product", "shop"), ("user", "accounts")] {
+      let owner = (product_code.to_string(), "/graphql".to_string());
+      registry.owners.insert((OperationKind::Query, field.to_string()), owner);
+    }
+    let document = parse_document(
+      r#"query Page($id: ID!, $uid: ID!, $unused: Int) {
+        __typename
+        p: product(id: $id) { ...ProductFields }
+        user(id: $uid) @include(if: true) { name }
+      }
+      fragment ProductFields on Product { name ...Price }
+      fragment Price on Product { price }"#,
+    )
+    .unwrap();
+    let operation = select_operation(&document, None).unwrap();
+    let variables = json!({ "id": "1", "uid": "2" }).as_object().unwrap().clone();
+    let (queries, typenames) = plan(&document, operation, &registry, &variables).unwrap();
+    assert_eq!(typenames, vec!["__typename".to_string()]);
+    assert_eq!(queries.len(), 2);
+    assert_eq!(queries[0].keys, vec!["p".to_string()]);
+    assert_eq!(
+      queries[0].query,
+      "query Page($id: ID!) { p: product(id: $id) { ...ProductFields } }\nfragment Price on Product { price }\nfragment ProductFields on Product { name ...Price }"
+    );
+    assert_eq!(queries[0].variables, json!({ "id": "1" }).as_object().unwrap().clone());
+    assert_eq!(queries[1].query, "query Page($uid: ID!) { user(id: $uid) @include(if: true) { name } }");
+    let document = parse_document("{ orders { id } }").unwrap();
+    let errors = plan(&document, &document.operations[0], &registry, &Map::new()).unwrap_err();
+    assert_eq!(errors, vec!["Cannot query field \"orders\" on type \"Query\"".to_string()]);
+  }
+}
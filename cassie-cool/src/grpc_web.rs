@@ -0,0 +1,305 @@
+use crate::metrics;
+use actix_web::http::header::{
+  HeaderMap, HeaderValue, ACCEPT, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+  ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, CONTENT_LENGTH, CONTENT_TYPE,
+  HOST, ORIGIN, TE, VARY,
+};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use deno_core::error::AnyError;
+use futures_util::stream;
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+const GRPC_STATUS: &str = "grpc-status";
+const GRPC_MESSAGE: &str = "grpc-message";
+///预检时默认允许的请求头 与常见的 gRPC-web 客户端发送的一致
+const ALLOW_HEADERS: &str =
+  "content-type, x-grpc-web, x-user-agent, grpc-timeout, authorization, x-accept-content-transfer-encoding, x-accept-response-streaming";
+///浏览器需要读取的响应头
+const EXPOSE_HEADERS: &str = "grpc-status, grpc-message, grpc-status-details-bin";
+///trailer 帧的标志位
+const TRAILER_FLAG: u8 = 0x80;
+
+///gRPC-web 转换 cool.json 中的 grpc_web<br>
+/// 浏览器发来的 gRPC-web 请求由网关转成 gRPC 通过 HTTP/2 转发给 runtime 响应的 trailer 编码成最后一帧<br>
+/// gRPC-web 不支持客户端流 请求体先完整读取 服务端流的响应逐帧返回
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GrpcWebPolicy {
+  pub enabled: bool,
+  pub cors_origins: Vec<String>,  //允许跨域调用的来源 * 表示所有来源 为空时不返回 CORS 头
+  pub allow_headers: Vec<String>, //预检时额外允许的请求头 例如自定义的 metadata
+  pub max_message_bytes: u64,     //请求体上限
+}
+
+impl Default for GrpcWebPolicy {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      cors_origins: vec![],
+      allow_headers: vec![],
+      max_message_bytes: 4 * 1024 * 1024,
+    }
+  }
+}
+
+lazy_static! {
+  //runtime 的 Deno.serve 在明文连接上自动识别 HTTP/2
+  static ref CLIENT: hyper::Client<HttpConnector> = hyper::Client::builder().http2_only(true).build_http();
+}
+
+fn media_type(headers: &HeaderMap) -> String {
+  let value = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or_default();
+  value.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+///application/grpc-web-text 的请求和响应体为 base64
+fn is_text(headers: &HeaderMap) -> bool {
+  media_type(headers).starts_with("application/grpc-web-text")
+}
+
+pub fn is_grpc_web(headers: &HeaderMap, policy: &GrpcWebPolicy) -> bool {
+  policy.enabled && media_type(headers).starts_with("application/grpc-web")
+}
+
+///gRPC 方法的路径 /包名.服务名/方法名
+fn is_method_path(path: &str) -> bool {
+  match path.trim_start_matches('/').split_once('/') {
+    Some((service, method)) => service.contains('.') && !method.is_empty() && !method.contains('/'),
+    None => false,
+  }
+}
+
+///允许的来源 没有 Origin 的请求不需要 CORS 头
+fn allowed_origin<'a>(headers: &'a HeaderMap, policy: &GrpcWebPolicy) -> Option<&'a HeaderValue> {
+  let origin = headers.get(ORIGIN)?;
+  let text = origin.to_str().ok()?;
+  policy
+    .cors_origins
+    .iter()
+    .any(|o| o == "*" || o.eq_ignore_ascii_case(text))
+    .then_some(origin)
+}
+
+fn cors_headers(res: &mut HttpResponseBuilder, origin: Option<&HeaderValue>) {
+  if let Some(origin) = origin {
+    res
+      .insert_header((ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone()))
+      .insert_header((ACCESS_CONTROL_EXPOSE_HEADERS, EXPOSE_HEADERS))
+      .append_header((VARY, "origin"));
+  }
+}
+
+///gRPC-web 的跨域预检 由网关直接回答<br>
+/// 请求的头中有 x-grpc-web 或者路径是 gRPC 方法时处理 来源不在 cors_origins 中时拒绝
+pub fn preflight(req: &HttpRequest, path: &str, policy: &GrpcWebPolicy) -> Option<HttpResponse> {
+  if !policy.enabled || req.method() != Method::OPTIONS || !req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD) {
+    return None;
+  }
+  let requested = req
+    .headers()
+    .get(ACCESS_CONTROL_REQUEST_HEADERS)
+    .and_then(|v| v.to_str().ok())
+    .unwrap_or_default();
+  let grpc_web = requested.split(',').any(|h| h.trim().eq_ignore_ascii_case("x-grpc-web"));
+  if !grpc_web && !is_method_path(path) {
+    return None;
+  }
+  let origin = match allowed_origin(req.headers(), policy) {
+    Some(origin) => origin,
+    None => return Some(HttpResponse::Forbidden().body("origin not allowed")),
+  };
+  let allow_headers = std::iter::once(ALLOW_HEADERS.to_string())
+    .chain(policy.allow_headers.iter().cloned())
+    .collect::<Vec<_>>()
+    .join(", ");
+  let mut res = HttpResponse::NoContent();
+  cors_headers(&mut res, Some(origin));
+  Some(
+    res
+      .insert_header((ACCESS_CONTROL_ALLOW_METHODS, "POST, OPTIONS"))
+      .insert_header((ACCESS_CONTROL_ALLOW_HEADERS, allow_headers))
+      .insert_header((ACCESS_CONTROL_MAX_AGE, 86400))
+      .finish(),
+  )
+}
+
+///base64 的请求体 客户端可能分段编码 每段都带填充 所以按 4 个字符一组解码
+fn decode_text(body: &[u8]) -> Result<Vec<u8>, AnyError> {
+  let text: Vec<u8> = body.iter().copied().filter(|b| !b.is_ascii_whitespace()).collect();
+  let mut out = Vec::with_capacity(text.len() / 4 * 3);
+  for quad in text.chunks(4) {
+    out.extend(base64::decode(quad)?);
+  }
+  Ok(out)
+}
+
+///trailer 编码成 gRPC-web 的最后一帧 1 字节标志 4 字节长度 然后是 HTTP/1 格式的头
+fn trailer_frame(trailers: &[(String, String)]) -> Vec<u8> {
+  let block: String = trailers
+    .iter()
+    .map(|(name, value)| format!("{}:{}\r\n", name.to_ascii_lowercase(), value))
+    .collect();
+  let mut frame = vec![TRAILER_FLAG];
+  frame.extend((block.len() as u32).to_be_bytes());
+  frame.extend(block.as_bytes());
+  frame
+}
+
+///runtime 没有按 gRPC 响应时 HTTP 状态码对应的 gRPC 状态
+fn status_from_http(status: StatusCode) -> u16 {
+  match status.as_u16() {
+    400 => 13,             //INTERNAL
+    401 => 16,             //UNAUTHENTICATED
+    403 => 7,              //PERMISSION_DENIED
+    404 => 12,             //UNIMPLEMENTED
+    429 | 502..=504 => 14, //UNAVAILABLE
+    _ => 2,                //UNKNOWN
+  }
+}
+
+fn record(product_code: &str, grpc_status: &str) {
+  metrics::inc_counter(
+    "gateway_grpc_web_requests_total",
+    "gRPC-web calls translated by the gateway by gRPC status",
+    &[("product", product_code), ("grpc_status", grpc_status)],
+    1,
+  );
+}
+
+///只有状态的响应 gRPC-web 中状态放在响应头里
+fn status_only(product_code: &str, content_type: &str, origin: Option<&HeaderValue>, code: u16, message: &str) -> HttpResponse {
+  record(product_code, &code.to_string());
+  let mut res = HttpResponse::Ok();
+  cors_headers(&mut res, origin);
+  res
+    .insert_header((CONTENT_TYPE, content_type))
+    .insert_header((GRPC_STATUS, code))
+    .insert_header((GRPC_MESSAGE, message.replace(['\r', '\n'], " ")))
+    .finish()
+}
+
+fn encode(chunk: Bytes, text: bool) -> Bytes {
+  match text {
+    true => Bytes::from(base64::encode(&chunk)),
+    false => chunk,
+  }
+}
+
+///响应结束时的 trailer 帧 同时按状态计数
+fn last_frame(product_code: &str, mut trailers: Vec<(String, String)>, text: bool) -> Bytes {
+  if !trailers.iter().any(|(name, _)| name == GRPC_STATUS) {
+    trailers.push((GRPC_STATUS.to_string(), "2".to_string()));
+    trailers.push((GRPC_MESSAGE.to_string(), "runtime response has no grpc-status".to_string()));
+  }
+  let status = trailers
+    .iter()
+    .find(|(name, _)| name == GRPC_STATUS)
+    .map(|(_, v)| v.as_str())
+    .unwrap_or_default();
+  record(product_code, status);
+  encode(Bytes::from(trailer_frame(&trailers)), text)
+}
+
+///把 gRPC-web 请求转成 gRPC 发给 runtime<br>
+/// url 为 runtime 的地址 headers 为网关整理好的转发请求头 body 为客户端发送的完整请求体
+pub async fn forward(product_code: &str, req: &HttpRequest, url: &str, headers: &HeaderMap, body: Bytes, policy: &GrpcWebPolicy) -> HttpResponse {
+  let text = is_text(req.headers());
+  let media_type = media_type(req.headers());
+  //+proto +json 等后缀原样保留
+  let suffix = media_type
+    .trim_start_matches("application/grpc-web-text")
+    .trim_start_matches("application/grpc-web");
+  let content_type = format!("application/grpc-web{}{}", if text { "-text" } else { "" }, suffix);
+  let origin = allowed_origin(req.headers(), policy).cloned();
+  let body = match text {
+    true => match decode_text(&body) {
+      Ok(body) => Bytes::from(body),
+      Err(err) => return status_only(product_code, &content_type, origin.as_ref(), 13, &format!("invalid base64 body: {}", err)),
+    },
+    false => body,
+  };
+  let mut upstream = hyper::Request::builder().method(Method::POST).uri(url);
+  for (name, value) in headers.iter() {
+    if [CONTENT_LENGTH, CONTENT_TYPE, HOST, ACCEPT, TE].contains(name) || name == "x-grpc-web" {
+      continue;
+    }
+    upstream = upstream.header(name.clone(), value.clone());
+  }
+  let upstream = upstream
+    .header(CONTENT_TYPE, format!("application/grpc{}", suffix))
+    .header(TE, "trailers")
+    .body(hyper::Body::from(body));
+  let res = match upstream {
+    Ok(upstream) => CLIENT.request(upstream).await,
+    Err(err) => return status_only(product_code, &content_type, origin.as_ref(), 13, &err.to_string()),
+  };
+  let (parts, body) = match res {
+    Ok(res) => res.into_parts(),
+    Err(err) => return status_only(product_code, &content_type, origin.as_ref(), 14, &format!("runtime unavailable: {}", err)),
+  };
+  if parts.status != StatusCode::OK {
+    let message = format!("runtime responded {}", parts.status);
+    return status_only(product_code, &content_type, origin.as_ref(), status_from_http(parts.status), &message);
+  }
+  let mut client_resp = HttpResponse::Ok();
+  cors_headers(&mut client_resp, origin.as_ref());
+  for (name, value) in parts.headers.iter().filter(|(name, _)| *name != CONTENT_LENGTH && *name != CONTENT_TYPE) {
+    client_resp.append_header((name.clone(), value.clone()));
+  }
+  client_resp.insert_header((CONTENT_TYPE, content_type));
+  //只有状态的响应 状态已经在响应头中
+  if let Some(status) = parts.headers.get(GRPC_STATUS) {
+    record(product_code, status.to_str().unwrap_or_default());
+    return client_resp.finish();
+  }
+  //数据帧原样返回 最后把 trailer 编码成一帧
+  let product_code = product_code.to_string();
+  let frames = stream::unfold(Some(body), move |state| {
+    let product_code = product_code.clone();
+    async move {
+      let mut body = state?;
+      let trailers = match body.data().await {
+        Some(Ok(chunk)) => return Some((Ok::<_, std::io::Error>(encode(chunk, text)), Some(body))),
+        Some(Err(err)) => vec![(GRPC_STATUS.to_string(), "14".to_string()), (GRPC_MESSAGE.to_string(), err.to_string())],
+        None => match body.trailers().await {
+          Ok(trailers) => trailers
+            .iter()
+            .flatten()
+            .filter_map(|(name, value)| value.to_str().ok().map(|value| (name.to_string(), value.to_string())))
+            .collect(),
+          Err(err) => vec![(GRPC_STATUS.to_string(), "14".to_string()), (GRPC_MESSAGE.to_string(), err.to_string())],
+        },
+      };
+      Some((Ok(last_frame(&product_code, trailers, text)), None))
+    }
+  });
+  client_resp.streaming(frames)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn frames_trailers_and_decodes_text() {
+    let frame = trailer_frame(&[
+      ("Grpc-Status".to_string(), "0".to_string()),
+      ("grpc-message".to_string(), "ok".to_string()),
+    ]);
+    assert_eq!(frame[0], TRAILER_FLAG);
+    assert_eq!(u32::from_be_bytes(frame[1..5].try_into().unwrap()) as usize, frame.len() - 5);
+    assert_eq!(&frame[5..], b"grpc-status:0\r\ngrpc-message:ok\r\n");
+    //两段分别编码的 base64 拼在一起
+    let body = format!("{}{}", base64::encode([0u8, 0, 0, 0, 1]), base64::encode([7u8]));
+    assert_eq!(decode_text(body.as_bytes()).unwrap(), vec![0, 0, 0, 0, 1, 7]);
+    assert!(decode_text(b"AAA").is_err());
+    assert!(is_method_path("/helloworld.Greeter/SayHello"));
+    assert!(!is_method_path("/api/users"));
+    assert_eq!(status_from_http(StatusCode::SERVICE_UNAVAILABLE), 14);
+  }
+}
@@ -0,0 +1,311 @@
+use crate::artifacts;
+use crate::config::ProductConfig;
+use crate::deploy;
+use crate::metrics;
+use crate::notifier::{self, Notification, Severity};
+use crate::util::now_millis;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use service::tools::heap_watch::{HeapControl, HeapSample, SnapshotReply};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+///堆快照保存的 artifact 类型
+pub const HEAP_SNAPSHOT_ARTIFACTS: &str = "heap-snapshots";
+///内存中保留的增长事件数
+const MAX_EVENTS: usize = 500;
+
+///堆内存增长检测 cool.json 中的 heap<br>
+/// 每个生产 runtime 定时采样已用堆大小 最近 window 个样本的线性回归斜率超过阈值且拟合度足够时视为持续增长
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeapPolicy {
+  pub interval_secs: u64,    //采样间隔 0 表示关闭检测
+  pub window: usize,         //参与趋势计算的样本数 默认 20 个 即 10 分钟
+  pub slope_kb_per_min: f64, //已用堆每分钟增长超过这个值视为增长
+  pub min_fit: f64,          //线性拟合的 R² 低于这个值时视为波动而不是持续增长
+  pub snapshot: bool,        //检测到增长时保存堆快照 快照期间 runtime 暂停
+  pub restart: bool,         //检测到增长时平滑重启这个 runtime 先启动新的再停止旧的
+}
+
+impl Default for HeapPolicy {
+  fn default() -> Self {
+    Self {
+      interval_secs: 30,
+      window: 20,
+      slope_kb_per_min: 1024.0,
+      min_fit: 0.8,
+      snapshot: false,
+      restart: false,
+    }
+  }
+}
+
+///一个 runtime 最近的样本和趋势 slope 单位为 KB 每分钟
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuntimeHeap {
+  pub product_code: String,
+  pub runtime: String,
+  pub samples: Vec<HeapSample>,
+  pub slope_kb_per_min: Option<f64>,
+  pub fit: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeapGrowthEvent {
+  pub product_code: String,
+  pub runtime: String,
+  pub used: u64, //检测到时的已用堆大小
+  pub slope_kb_per_min: f64,
+  pub fit: f64,
+  pub snapshot_url: Option<String>,
+  pub restarted: bool,
+  pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeapReport {
+  pub runtimes: Vec<RuntimeHeap>,
+  pub events: Vec<HeapGrowthEvent>, //最近的事件 新的在前
+}
+
+lazy_static! {
+  static ref NEXT_ID: AtomicU64 = AtomicU64::new(0);
+  static ref RUNTIMES: Mutex<HashMap<u64, RuntimeHeap>> = Mutex::new(HashMap::new());
+  static ref EVENTS: Mutex<VecDeque<HeapGrowthEvent>> = Mutex::new(VecDeque::new());
+}
+
+///最小二乘拟合已用堆大小 返回 (每分钟增长的 KB, R²) 样本不足或时间相同时为空
+fn trend(samples: &[HeapSample]) -> Option<(f64, f64)> {
+  let first = samples.first()?.at;
+  let points: Vec<(f64, f64)> = samples
+    .iter()
+    .map(|s| (s.at.saturating_sub(first) as f64 / 60_000.0, s.used as f64 / 1024.0))
+    .collect();
+  let n = points.len() as f64;
+  let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+  let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+  let (mut sxx, mut sxy, mut syy) = (0.0, 0.0, 0.0);
+  for (x, y) in &points {
+    sxx += (x - mean_x) * (x - mean_x);
+    sxy += (x - mean_x) * (y - mean_y);
+    syy += (y - mean_y) * (y - mean_y);
+  }
+  if sxx == 0.0 {
+    return None;
+  }
+  let fit = if syy == 0.0 { 0.0 } else { sxy * sxy / (sxx * syy) };
+  Some((sxy / sxx, fit))
+}
+
+///样本满一个窗口后才判断 返回 (斜率, R²)
+fn growing(samples: &[HeapSample], policy: &HeapPolicy) -> Option<(f64, f64)> {
+  if samples.len() < policy.window.max(2) {
+    return None;
+  }
+  let (slope, fit) = trend(samples)?;
+  (slope >= policy.slope_kb_per_min && fit >= policy.min_fit).then_some((slope, fit))
+}
+
+///加入一个样本 持续增长时清空样本 下一次判断需要重新攒满一个窗口
+fn record(id: u64, sample: HeapSample, policy: &HeapPolicy) -> Option<(f64, f64)> {
+  let mut runtimes = RUNTIMES.lock().unwrap();
+  let state = runtimes.get_mut(&id)?;
+  state.samples.push(sample);
+  if state.samples.len() > policy.window.max(2) {
+    state.samples.remove(0);
+  }
+  let trend = trend(&state.samples);
+  state.slope_kb_per_min = trend.map(|t| t.0);
+  state.fit = trend.map(|t| t.1);
+  let found = growing(&state.samples, policy);
+  if found.is_some() {
+    state.samples.clear();
+  }
+  found
+}
+
+async fn capture_snapshot(product_code: &str, runtime: &str, snapshots: &mpsc::UnboundedSender<SnapshotReply>) -> Option<String> {
+  let (reply, rx) = oneshot::channel();
+  snapshots.send(reply).ok()?;
+  let result = match rx.await {
+    Ok(Ok(bytes)) => artifacts::put_artifact(HEAP_SNAPSHOT_ARTIFACTS, &format!("{}-{}.heapsnapshot", runtime, now_millis()), bytes).await,
+    Ok(Err(err)) => Err(err),
+    Err(_) => return None,
+  };
+  match result {
+    Ok(url) => Some(url),
+    Err(err) => {
+      log::warn!("failed to capture {} heap snapshot: {}", product_code, err);
+      None
+    }
+  }
+}
+
+fn raise(event: HeapGrowthEvent) {
+  log::warn!(
+    "{} heap of {} grows {:.0} KB/min (R² {:.2}), {} KB used",
+    event.product_code,
+    event.runtime,
+    event.slope_kb_per_min,
+    event.fit,
+    event.used / 1024
+  );
+  metrics::inc_counter(
+    "runtime_heap_growth_total",
+    "Sustained heap growth detected per product",
+    &[("product", &event.product_code)],
+    1,
+  );
+  notifier::notify(Notification::new(
+    "heap.growth",
+    &event.product_code,
+    Severity::Warning,
+    format!(
+      "heap of {} grows {:.0} KB per minute, {} MB used",
+      event.runtime,
+      event.slope_kb_per_min,
+      event.used / 1024 / 1024
+    ),
+    serde_json::to_value(&event).unwrap_or_default(),
+  ));
+  let mut events = EVENTS.lock().unwrap();
+  if events.len() >= MAX_EVENTS {
+    events.pop_front();
+  }
+  events.push_back(event);
+}
+
+///接收一个 runtime 的样本 runtime 结束后样本通道关闭
+async fn watch_runtime(
+  id: u64,
+  product_code: String,
+  runtime: String,
+  policy: HeapPolicy,
+  mut samples: mpsc::UnboundedReceiver<HeapSample>,
+  snapshots: mpsc::UnboundedSender<SnapshotReply>,
+  notify: async_channel::Sender<u8>,
+) {
+  while let Some(sample) = samples.recv().await {
+    let Some((slope_kb_per_min, fit)) = record(id, sample, &policy) else {
+      continue;
+    };
+    let snapshot_url = match policy.snapshot {
+      true => capture_snapshot(&product_code, &runtime, &snapshots).await,
+      false => None,
+    };
+    let restarted = policy.restart && deploy::replace_product_runtime(&product_code, &notify).await;
+    raise(HeapGrowthEvent {
+      product_code: product_code.clone(),
+      runtime: runtime.clone(),
+      used: sample.used,
+      slope_kb_per_min,
+      fit,
+      snapshot_url,
+      restarted,
+      created_at: now_millis(),
+    });
+  }
+  RUNTIMES.lock().unwrap().remove(&id);
+}
+
+///生产 runtime 启动时获取 cool.json 中关闭检测时为空<br>
+/// notify 为停止这个 runtime 的通道 重启时用来找到它
+pub fn control(product_code: &str, runtime: &str, notify: async_channel::Sender<u8>) -> Option<HeapControl> {
+  let policy = ProductConfig::load(product_code).map(|c| c.heap).unwrap_or_default();
+  if policy.interval_secs == 0 {
+    return None;
+  }
+  let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+  RUNTIMES.lock().unwrap().insert(
+    id,
+    RuntimeHeap {
+      product_code: product_code.to_string(),
+      runtime: runtime.to_string(),
+      samples: vec![],
+      slope_kb_per_min: None,
+      fit: None,
+    },
+  );
+  let (samples_tx, samples_rx) = mpsc::unbounded_channel();
+  let (snapshots_tx, snapshots_rx) = mpsc::unbounded_channel();
+  let interval = Duration::from_secs(policy.interval_secs);
+  tokio::spawn(watch_runtime(
+    id,
+    product_code.to_string(),
+    runtime.to_string(),
+    policy,
+    samples_rx,
+    snapshots_tx,
+    notify,
+  ));
+  Some(HeapControl {
+    interval,
+    samples: samples_tx,
+    snapshots: snapshots_rx,
+  })
+}
+
+///runtime 最近一次采样的已用堆大小 没有开启采样时为空
+pub fn latest_used(product_code: &str, runtime: &str) -> Option<u64> {
+  let runtimes = RUNTIMES.lock().unwrap();
+  let state = runtimes.values().find(|r| r.product_code == product_code && r.runtime == runtime)?;
+  state.samples.last().map(|s| s.used)
+}
+
+///产品每个生产 runtime 的堆趋势 以及最近的增长事件
+pub fn report(product_code: &str) -> HeapReport {
+  let mut runtimes: Vec<RuntimeHeap> = RUNTIMES
+    .lock()
+    .unwrap()
+    .values()
+    .filter(|r| r.product_code == product_code)
+    .cloned()
+    .collect();
+  runtimes.sort_by(|a, b| a.runtime.cmp(&b.runtime));
+  HeapReport {
+    runtimes,
+    events: EVENTS
+      .lock()
+      .unwrap()
+      .iter()
+      .rev()
+      .filter(|e| e.product_code == product_code)
+      .cloned()
+      .collect(),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn samples(used: impl Fn(u64) -> u64) -> Vec<HeapSample> {
+    (0..20)
+      .map(|i| HeapSample {
+        at: i * 30_000,
+        used: used(i),
+        total: 64 << 20,
+      })
+      .collect()
+  }
+
+  #[test]
+  fn detects_sustained_growth() {
+    let policy = HeapPolicy::default();
+    //每 30 秒增长 1 MB 即每分钟 2048 KB
+    let leaking = samples(|i| (20 << 20) + (i << 20));
+    let (slope, fit) = growing(&leaking, &policy).unwrap();
+    assert!((slope - 2048.0).abs() < 0.001);
+    assert!(fit > 0.99);
+    //窗口未满时不判断
+    assert!(growing(&leaking[..10], &policy).is_none());
+    //回收后回落的锯齿不算持续增长
+    let sawtooth = samples(|i| (20 << 20) + ((i % 4) << 22));
+    assert!(growing(&sawtooth, &policy).is_none());
+    assert!(growing(&samples(|_| 20 << 20), &policy).is_none());
+  }
+}
@@ -0,0 +1,113 @@
+use crate::config::product_dir;
+use crate::worker_util::{Project, ScriptWorkerId, ScriptWorkerThread, WORKER_TABLE};
+use deno_core::error::AnyError;
+use lazy_static::lazy_static;
+use notify::event::{Event, EventKind};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Component, Path};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+///编辑器保存时会连续产生多个事件 最后一个事件之后等待这么久再重启
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+lazy_static! {
+  //释放后停止监听
+  static ref WATCHERS: Mutex<HashMap<String, RecommendedWatcher>> = Mutex::new(HashMap::new());
+}
+
+///隐藏目录 node_modules 和编辑器的临时文件不触发重启
+fn ignored(root: &Path, path: &Path) -> bool {
+  let Ok(relative) = path.strip_prefix(root) else {
+    return true;
+  };
+  let name = relative.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+  name.ends_with('~')
+    || relative.components().any(|c| match c {
+      Component::Normal(part) => {
+        let part = part.to_string_lossy();
+        part.starts_with('.') || part == "node_modules"
+      }
+      _ => false,
+    })
+}
+
+///重启产品的开发 runtime<br>
+/// 没有生产 runtime 时重建 worker 入口配置和 PORT_TABLE 中的端口一起刷新
+async fn reload(product_code: &str) {
+  let id = ScriptWorkerId(product_code.to_string());
+  let mut script_table = WORKER_TABLE.lock().unwrap();
+  let Some(worker) = script_table.get_mut(&id) else {
+    return;
+  };
+  if worker.watch_tx.is_none() {
+    return;
+  }
+  log::info!("{} code changed, restarting the development runtime", product_code);
+  if worker.worker_handlers.lock().unwrap().is_empty() {
+    //先释放旧的 worker 它会从 PORT_TABLE 中移除自己的端口
+    drop(script_table.remove(&id));
+    let mut worker = ScriptWorkerThread::new(Project::from_product(product_code));
+    worker.start_watch_runtime().await;
+    script_table.insert(id, worker);
+  } else {
+    worker.project = Project::from_product(product_code);
+    worker.stop_watch_runtime();
+    worker.start_watch_runtime().await;
+  }
+}
+
+///开发 runtime 启动后监听产品代码目录 文件变化后自动重启<br>
+/// 已在监听时不做任何事
+pub fn watch(product_code: &str) -> Result<(), AnyError> {
+  let mut watchers = WATCHERS.lock().unwrap();
+  if watchers.contains_key(product_code) {
+    return Ok(());
+  }
+  let root = product_dir(product_code).canonicalize()?;
+  let (tx, mut rx) = mpsc::unbounded_channel::<()>();
+  let filter_root = root.clone();
+  let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+    if let Ok(event) = res {
+      let changed = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_));
+      if changed && event.paths.iter().any(|p| !ignored(&filter_root, p)) {
+        let _ = tx.send(());
+      }
+    }
+  })?;
+  watcher.watch(&root, RecursiveMode::Recursive)?;
+  let code = product_code.to_string();
+  //监听释放后发送端关闭 任务随之结束
+  actix_web::rt::spawn(async move {
+    while rx.recv().await.is_some() {
+      while let Ok(Some(())) = tokio::time::timeout(DEBOUNCE, rx.recv()).await {}
+      reload(&code).await;
+    }
+  });
+  watchers.insert(product_code.to_string(), watcher);
+  Ok(())
+}
+
+///停止监听 开发 runtime 停止或产品退出时调用
+pub fn unwatch(product_code: &str) {
+  WATCHERS.lock().unwrap().remove(product_code);
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn ignores_hidden_and_dependency_paths() {
+    let root = Path::new("/srv/code/shop");
+    assert!(!ignored(root, &root.join("main.ts")));
+    assert!(!ignored(root, &root.join("api/cart.ts")));
+    assert!(ignored(root, &root.join(".git/index")));
+    assert!(ignored(root, &root.join("api/.cart.ts.swp")));
+    assert!(ignored(root, &root.join("node_modules/lodash/index.js")));
+    assert!(ignored(root, &root.join("main.ts~")));
+    assert!(ignored(root, Path::new("/srv/code/other/main.ts")));
+  }
+}
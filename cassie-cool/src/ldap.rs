@@ -0,0 +1,214 @@
+use crate::auth::Role;
+use deno_core::error::{custom_error, generic_error, AnyError};
+use lazy_static::lazy_static;
+use ldap3::{ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+///LDAP / Active Directory 认证 gateway.json 中的 auth.ldap
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LdapConfig {
+  pub url: String,                         //ldap://host:389 或 ldaps://host:636
+  pub starttls: bool,                      //ldap:// 连接上启用 StartTLS
+  pub bind_dn: String,                     //查找用户使用的服务账号
+  pub bind_password: String,               //服务账号密码
+  pub base_dn: String,                     //查找用户的起点
+  pub user_filter: String,                 //{login} 替换为转义后的登录名
+  pub mail_attribute: String,              //作为控制台用户邮箱的属性 没有时使用登录名
+  pub group_attribute: String,             //用户所属组的属性 AD 为 memberOf
+  pub group_roles: BTreeMap<String, Role>, //组 DN 对应的角色 属于多个组时取最高的角色
+  pub default_role: Option<Role>,          //不属于任何映射的组时的角色 不配置时拒绝登录
+  pub pool_size: usize,                    //服务账号的连接池大小
+  pub timeout_secs: u64,                   //连接和每次操作的超时
+}
+
+impl Default for LdapConfig {
+  fn default() -> Self {
+    Self {
+      url: "ldap://127.0.0.1:389".to_string(),
+      starttls: false,
+      bind_dn: String::new(),
+      bind_password: String::new(),
+      base_dn: String::new(),
+      user_filter: "(&(objectClass=person)(|(sAMAccountName={login})(uid={login})(mail={login})))".to_string(),
+      mail_attribute: "mail".to_string(),
+      group_attribute: "memberOf".to_string(),
+      group_roles: BTreeMap::new(),
+      default_role: None,
+      pool_size: 4,
+      timeout_secs: 5,
+    }
+  }
+}
+
+///认证通过的目录用户
+#[derive(Debug, Clone)]
+pub struct LdapIdentity {
+  pub dn: String,
+  pub email: String,
+  pub role: Role,
+}
+
+///服务账号的连接池 连接在使用后重新绑定服务账号再放回
+struct Pool {
+  config: LdapConfig,
+  idle: Mutex<Vec<Ldap>>,
+  permits: Arc<Semaphore>,
+}
+
+struct PooledConn {
+  ldap: Ldap,
+  _permit: OwnedSemaphorePermit,
+}
+
+lazy_static! {
+  static ref POOL: RwLock<Option<Arc<Pool>>> = RwLock::new(None);
+}
+
+impl Pool {
+  fn timeout(&self) -> Duration {
+    Duration::from_secs(self.config.timeout_secs.max(1))
+  }
+
+  async fn connect(&self) -> Result<Ldap, AnyError> {
+    let settings = LdapConnSettings::new()
+      .set_conn_timeout(self.timeout())
+      .set_starttls(self.config.starttls);
+    let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url).await?;
+    ldap3::drive!(conn);
+    self.bind_service(&mut ldap).await?;
+    Ok(ldap)
+  }
+
+  async fn bind_service(&self, ldap: &mut Ldap) -> Result<(), AnyError> {
+    ldap
+      .with_timeout(self.timeout())
+      .simple_bind(&self.config.bind_dn, &self.config.bind_password)
+      .await?
+      .success()?;
+    Ok(())
+  }
+
+  async fn acquire(&self) -> Result<PooledConn, AnyError> {
+    let permit = self.permits.clone().acquire_owned().await?;
+    let idle = self.idle.lock().unwrap().pop();
+    let ldap = match idle {
+      Some(mut ldap) if !ldap.is_closed() => ldap,
+      _ => self.connect().await?,
+    };
+    Ok(PooledConn { ldap, _permit: permit })
+  }
+
+  ///只有操作成功的连接才放回连接池
+  fn release(&self, conn: PooledConn) {
+    self.idle.lock().unwrap().push(conn.ldap);
+  }
+
+  async fn find_user(&self, conn: &mut PooledConn, login: &str) -> Result<Option<SearchEntry>, AnyError> {
+    let filter = self.config.user_filter.replace("{login}", &ldap_escape(login));
+    let attrs = vec![self.config.mail_attribute.as_str(), self.config.group_attribute.as_str()];
+    let (entries, _) = conn
+      .ldap
+      .with_timeout(self.timeout())
+      .search(&self.config.base_dn, Scope::Subtree, &filter, attrs)
+      .await?
+      .success()?;
+    match entries.len() {
+      0 => Ok(None),
+      1 => Ok(Some(SearchEntry::construct(entries.into_iter().next().unwrap()))),
+      n => Err(generic_error(format!("login {} matches {} directory entries", login, n))),
+    }
+  }
+
+  async fn verify(&self, login: &str, password: &str) -> Result<LdapIdentity, AnyError> {
+    let mut conn = self.acquire().await?;
+    let entry = self
+      .find_user(&mut conn, login)
+      .await?
+      .ok_or_else(|| custom_error("Unauthorized", "invalid email or password"))?;
+    //用用户自己的 DN 和密码绑定来校验密码 之后换回服务账号
+    let bound = conn
+      .ldap
+      .with_timeout(self.timeout())
+      .simple_bind(&entry.dn, password)
+      .await?
+      .success()
+      .is_ok();
+    self.bind_service(&mut conn.ldap).await?;
+    self.release(conn);
+    if !bound {
+      return Err(custom_error("Unauthorized", "invalid email or password"));
+    }
+    let groups = entry.attrs.get(&self.config.group_attribute).cloned().unwrap_or_default();
+    let role = map_role(&groups, &self.config.group_roles, self.config.default_role)
+      .ok_or_else(|| custom_error("Forbidden", "user is not in any group with dashboard access"))?;
+    let email = entry
+      .attrs
+      .get(&self.config.mail_attribute)
+      .and_then(|values| values.first().cloned())
+      .unwrap_or_else(|| login.to_string());
+    Ok(LdapIdentity { dn: entry.dn, email, role })
+  }
+}
+
+///按组映射角色 组 DN 不区分大小写 取最高的角色
+pub fn map_role(groups: &[String], group_roles: &BTreeMap<String, Role>, default_role: Option<Role>) -> Option<Role> {
+  groups
+    .iter()
+    .filter_map(|group| {
+      group_roles
+        .iter()
+        .find(|(dn, _)| dn.trim().eq_ignore_ascii_case(group.trim()))
+        .map(|(_, role)| *role)
+    })
+    .max()
+    .or(default_role)
+}
+
+///使用配置创建连接池 连接在第一次登录时建立
+pub fn init(config: LdapConfig) {
+  let pool = Pool {
+    permits: Arc::new(Semaphore::new(config.pool_size.max(1))),
+    config,
+    idle: Mutex::new(vec![]),
+  };
+  *POOL.write().unwrap() = Some(Arc::new(pool));
+}
+
+///用目录服务校验登录名和密码 返回用户的邮箱和映射的角色
+pub async fn verify(login: &str, password: &str) -> Result<LdapIdentity, AnyError> {
+  //空密码会被当作匿名绑定 直接拒绝
+  if login.trim().is_empty() || password.is_empty() {
+    return Err(custom_error("Unauthorized", "invalid email or password"));
+  }
+  let pool = POOL
+    .read()
+    .unwrap()
+    .clone()
+    .ok_or_else(|| generic_error("ldap authentication is not configured"))?;
+  pool.verify(login.trim(), password).await
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn maps_groups_to_highest_role() {
+    let mut group_roles = BTreeMap::new();
+    group_roles.insert("CN=Cool Admins,OU=Groups,DC=corp".to_string(), Role::Admin);
+    group_roles.insert("CN=Developers,OU=Groups,DC=corp".to_string(), Role::Developer);
+    let groups = vec![
+      "cn=developers,ou=groups,dc=corp".to_string(),
+      "CN=Cool Admins,OU=Groups,DC=corp".to_string(),
+    ];
+    assert_eq!(map_role(&groups, &group_roles, None), Some(Role::Admin));
+    let groups = vec!["CN=Other,DC=corp".to_string()];
+    assert_eq!(map_role(&groups, &group_roles, None), None);
+    assert_eq!(map_role(&groups, &group_roles, Some(Role::Viewer)), Some(Role::Viewer));
+  }
+}
@@ -1,43 +1,571 @@
+pub mod access_log;
+pub mod admission;
+pub mod amqp;
+pub mod anomaly;
 pub mod api;
+pub mod api_version;
+pub mod artifacts;
+pub mod audit_log;
+pub mod auth;
+pub mod bandwidth;
+pub mod bench;
+pub mod billing;
+pub mod bridge;
+pub mod build_cache;
+pub mod bulk;
+pub mod catalog;
+pub mod collab;
+pub mod config;
+pub mod cookies;
+pub mod crash;
+pub mod crawler;
+pub mod dep_audit;
+pub mod dep_update;
+pub mod geoip;
+pub mod git_hooks;
+pub mod gitops;
+pub mod graphql;
+pub mod grpc_web;
+pub mod deploy;
+pub mod dns_cache;
+pub mod doctor;
+pub mod dry_run;
+pub mod egress;
+pub mod encryption;
+pub mod etag;
+pub mod framing;
+pub mod heap_trend;
+pub mod hot_reload;
+pub mod ldap;
+pub mod licenses;
+pub mod list_query;
+pub mod log_shipping;
+pub mod logs;
+pub mod media;
+pub mod metrics;
+pub mod mqtt;
+pub mod msgpack;
+pub mod mtls;
+pub mod node_compat;
+pub mod notifier;
+pub mod offline;
+pub mod otel;
+pub mod outbound;
+pub mod overload;
+pub mod panics;
+pub mod patch;
+pub mod pipeline;
+pub mod preview;
+pub mod product_package;
+pub mod profiler;
+pub mod rate_limit;
+pub mod recycle;
+pub mod replica;
+pub mod retention;
+pub mod roles;
+pub mod routes;
+pub mod sandbox;
+pub mod search;
+pub mod secrets;
+pub mod security_headers;
+pub mod shared;
+pub mod signature;
+pub mod size_budget;
+pub mod smoke;
+pub mod staging;
+pub mod state_snapshot;
+pub mod storage;
+pub mod templates;
+pub mod tenants;
+pub mod test_watch;
+pub mod trash;
+pub mod tree_index;
+pub mod trusted_cas;
+pub mod upstream;
+pub mod usage;
+pub mod users;
+pub mod util;
+pub mod versions;
+pub mod websocket;
 pub mod worker_util;
 
-use worker_util::{ScriptWorkerId, WorkerPort, PORT_TABLE};
+use bandwidth::Direction;
+use config::ProductConfig;
+use framing::BodyChange;
+use futures_util::StreamExt;
+use geoip::{GeoInfo, GEO_COUNTRY_HEADER, GEO_REGION_HEADER};
+use mtls::{ClientCert, CLIENT_CERT_FINGERPRINT_HEADER, CLIENT_CERT_SUBJECT_HEADER};
+use overload::Admitted;
+use panics::{RequestId, REQUEST_ID_HEADER};
+use routes::{FORWARDED_PREFIX_HEADER, PRODUCT_PREFIX};
+use usage::{USAGE_CPU_HEADER, USAGE_HEAP_HEADER, USAGE_SAMPLE_HEADER};
+use worker_util::{PortUnavailable, ScriptWorkerId, WorkerPort};
 
+use actix_web::body::SizedStream;
+use actix_web::http::header::{HeaderValue, ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, EXPECT, RETRY_AFTER, SET_COOKIE, VARY};
 use actix_web::{dev::PeerAddr, error, web, Error, HttpRequest, HttpResponse};
 use awc::Client;
+use deno_core::error::AnyError;
+use deno_runtime::ops::context::{self as request_context, RequestContext, REQUEST_CONTEXT_HEADER};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Instant;
 use url::Url;
+
+///需要校验签名的请求体大小上限
+const MAX_SIGNED_BODY_BYTES: usize = 10 * 1024 * 1024;
+
 ///路由转发
-pub async fn forward(req: HttpRequest, payload: web::Payload, peer_addr: Option<PeerAddr>, client: web::Data<Client>) -> Result<HttpResponse, Error> {
-  let product_code = match req.headers().get("product_code") {
-    Some(p) => p.to_str().unwrap(),
-    None => {
-      return Ok(HttpResponse::NotFound().body("product_code not found"));
+pub async fn forward(req: HttpRequest, mut payload: web::Payload, peer_addr: Option<PeerAddr>, client: web::Data<Client>) -> Result<HttpResponse, Error> {
+  //路径前缀指向存在的产品时按前缀转发 去掉前缀后的路径交给 runtime 否则使用 product_code 请求头
+  let prefixed = match routes::product_prefix(req.uri().path()) {
+    Some((code, path)) => match ProductConfig::cached(code).await {
+      Ok(config) => config.map(|config| (code, path, config)),
+      Err(err) => return Ok(config_unavailable(code, err)),
+    },
+    None => None,
+  };
+  let prefix = prefixed.as_ref().map(|(code, _, _)| format!("{}{}", PRODUCT_PREFIX, code));
+  let (product_code, path, mut config) = match prefixed {
+    Some(prefixed) => prefixed,
+    None => match req.headers().get("product_code") {
+      Some(p) => {
+        let product_code = p.to_str().unwrap();
+        let config = match ProductConfig::cached(product_code).await {
+          Ok(config) => config.unwrap_or_default(),
+          Err(err) => return Ok(config_unavailable(product_code, err)),
+        };
+        (product_code, req.uri().path(), config)
+      }
+      None => {
+        return Ok(HttpResponse::NotFound().body("product_code not found"));
+      }
+    },
+  };
+  //预览请求之后都按预览产品处理 包括它自己的访问策略
+  let preview = config.preview.route(&req).map(|p| p.to_string());
+  let product_code = match &preview {
+    Some(preview) => {
+      config = match ProductConfig::cached(preview).await {
+        Ok(config) => config.unwrap_or_default(),
+        Err(err) => return Ok(config_unavailable(preview, err)),
+      };
+      preview.as_str()
     }
+    None => product_code,
+  };
+  access_log::tag(&req, product_code);
+  if let Some(res) = crawler::respond(&req, &config.crawler) {
+    return Ok(res);
+  }
+  if let Some(res) = grpc_web::preflight(&req, path, &config.grpc_web) {
+    return Ok(res);
+  }
+  let (_admitted, geo) = match check_policies(&req, product_code, path, &config) {
+    Ok(admitted) => admitted,
+    Err(res) => return Ok(res),
   };
   let id = ScriptWorkerId(product_code.to_string());
-  let hand_port = PORT_TABLE.read().unwrap();
-  let WorkerPort(port) = match hand_port.get(&id) {
-    Some(p) => p,
-    None => {
+  //有多个端口副本时按 balance 选择 转发完成前计入端口的连接数
+  let lease = match worker_util::lease_port(&id, config.balance) {
+    Ok(lease) => lease,
+    Err(PortUnavailable::Draining) => {
+      return Ok(
+        HttpResponse::ServiceUnavailable()
+          .insert_header((RETRY_AFTER, 1))
+          .body(format!("{} service is shutting down", product_code)),
+      );
+    }
+    Err(PortUnavailable::Unhealthy(reason)) => {
+      return Ok(
+        HttpResponse::ServiceUnavailable()
+          .insert_header((RETRY_AFTER, 5))
+          .body(format!("{} service is unhealthy: {}", product_code, reason)),
+      );
+    }
+    Err(PortUnavailable::NotFound) => {
       return Ok(HttpResponse::NotFound().body(format!("{} service not found", product_code)));
     }
   };
+  let WorkerPort(port) = lease.0;
+  let client_cert = req.conn_data::<ClientCert>().cloned();
+  if websocket::is_upgrade(&req) {
+    if let Err(reason) = signature::verify_handshake(product_code, &config.signature, path, &req) {
+      return Ok(HttpResponse::Unauthorized().body(reason));
+    }
+    let ip = peer_addr.as_ref().map(|PeerAddr(addr)| addr.ip());
+    let path_and_query = match req.uri().query() {
+      Some(query) => format!("{}?{}", path, query),
+      None => path.to_string(),
+    };
+    return websocket::proxy(&req, payload, product_code, port, &path_and_query, &config.websocket, ip).await;
+  }
+  //记住的校验信息仍然有效时 条件请求不再转发给 runtime
+  if !config.signature.applies_to(path) {
+    if let Some(res) = etag::respond_cached(product_code, &req, &config.etag) {
+      return Ok(res);
+    }
+  }
   let mut new_url = Url::parse(&format!("http://127.0.0.1:{}", port)).unwrap();
-  new_url.set_path(req.uri().path());
+  new_url.set_path(path);
   new_url.set_query(req.uri().query());
+  let client_ip = peer_addr.as_ref().map(|PeerAddr(addr)| addr.ip().to_string());
   let forwarded_req = client.request_from(new_url.as_str(), req.head()).no_decompress();
-  let forwarded_req = match peer_addr {
+  let mut forwarded_req = match peer_addr {
     Some(PeerAddr(addr)) => forwarded_req.insert_header(("x-forwarded-for", addr.ip().to_string())),
     None => forwarded_req,
   };
-  let res = forwarded_req.send_stream(payload).await.map_err(error::ErrorInternalServerError)?;
+  //逐跳的头只对客户端到网关的连接有效 请求体按原来的分帧方式转发
+  for name in framing::hop_by_hop(req.headers()) {
+    forwarded_req.headers_mut().remove(name);
+  }
+  //去掉客户端伪造的前缀 按前缀转发时告诉 runtime 原来的路径前缀
+  forwarded_req.headers_mut().remove(FORWARDED_PREFIX_HEADER);
+  if let Some(prefix) = prefix {
+    forwarded_req = forwarded_req.insert_header((FORWARDED_PREFIX_HEADER, prefix));
+  }
+  //HTTP/2 中 Cookie 可能分成多个头
+  let cookie = req
+    .headers()
+    .get_all(COOKIE)
+    .filter_map(|v| v.to_str().ok())
+    .collect::<Vec<_>>()
+    .join("; ");
+  if let Some(cookie) = cookies::rewrite_cookie_header(&config.cookies, &cookie) {
+    forwarded_req.headers_mut().remove(COOKIE);
+    if !cookie.is_empty() {
+      forwarded_req = forwarded_req.insert_header((COOKIE, cookie));
+    }
+  }
+  //runtime 中 Platform.context(request) 读取的请求信息 转发完成前保留
+  let request_id = req
+    .extensions()
+    .get::<RequestId>()
+    .map(|r| r.0.clone())
+    .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+  let deployment = deploy::current_deployment(product_code).await;
+  let signed_with = config.signature.applies_to(path).then_some(config.signature.secret.as_str());
+  let context = request_context::register(
+    product_code,
+    RequestContext {
+      request_id: request_id.clone(),
+      principal: principal_claims(&req, client_cert.as_ref(), signed_with),
+      client_ip,
+      country: geo.country.clone(),
+      region: geo.region.clone(),
+      version: deployment.version,
+      deployment_id: deployment.id,
+    },
+  );
+  forwarded_req = forwarded_req
+    .insert_header((REQUEST_ID_HEADER, request_id))
+    .insert_header((REQUEST_CONTEXT_HEADER, context.key()));
+  //客户端证书信息只能由网关设置
+  forwarded_req.headers_mut().remove(CLIENT_CERT_SUBJECT_HEADER);
+  forwarded_req.headers_mut().remove(CLIENT_CERT_FINGERPRINT_HEADER);
+  if let Some(cert) = client_cert {
+    forwarded_req = forwarded_req
+      .insert_header((CLIENT_CERT_SUBJECT_HEADER, cert.subject))
+      .insert_header((CLIENT_CERT_FINGERPRINT_HEADER, cert.fingerprint));
+  }
+  //地区信息同样只能由网关设置
+  forwarded_req.headers_mut().remove(GEO_COUNTRY_HEADER);
+  forwarded_req.headers_mut().remove(GEO_REGION_HEADER);
+  if let Some(country) = geo.country {
+    forwarded_req = forwarded_req.insert_header((GEO_COUNTRY_HEADER, country));
+  }
+  if let Some(region) = geo.region {
+    forwarded_req = forwarded_req.insert_header((GEO_REGION_HEADER, region));
+  }
+  //按间隔采样请求的 CPU 和内存 由 runtime 在响应头中报告
+  forwarded_req.headers_mut().remove(USAGE_SAMPLE_HEADER);
+  if usage::should_sample() {
+    forwarded_req = forwarded_req.insert_header((USAGE_SAMPLE_HEADER, "1"));
+  }
+  //runtime 崩溃时报告中列出未完成的请求
+  let _inflight = crash::track(product_code, req.method().as_str(), path);
+  //开启 trace 导出时 runtime 收到的 traceparent 指向网关的 span
+  let span = otel::start_span(&req, product_code);
+  if let Some(span) = &span {
+    forwarded_req = forwarded_req.insert_header(("traceparent", span.traceparent()));
+  }
+  //gRPC-web 请求由网关转成 gRPC 通过 HTTP/2 转发 客户端只会发送一条消息 请求体先完整读取
+  if grpc_web::is_grpc_web(req.headers(), &config.grpc_web) {
+    let body = match read_body(&mut payload, config.grpc_web.max_message_bytes as usize).await? {
+      Some(body) => body,
+      None => return Ok(HttpResponse::PayloadTooLarge().finish()),
+    };
+    if config.signature.applies_to(path) {
+      if let Err(reason) = signature::verify_request(product_code, &config.signature, &req, &body) {
+        return Ok(HttpResponse::Unauthorized().body(reason));
+      }
+    }
+    bandwidth::record(product_code, Direction::Upload, body.len());
+    let res = grpc_web::forward(
+      product_code,
+      &req,
+      new_url.as_str(),
+      forwarded_req.headers(),
+      body.freeze(),
+      &config.grpc_web,
+    )
+    .await;
+    usage::record_request(product_code, &usage::endpoint_label(req.method().as_str(), path));
+    recycle::record_request(product_code);
+    if let Some(span) = span {
+      span.finish(res.status().as_u16());
+    }
+    return Ok(res);
+  }
+  //runtime 只处理 JSON 客户端接受 MessagePack 时由网关转换响应
+  let accepts_msgpack = msgpack::accepts_msgpack(req.headers(), &config.msgpack);
+  if accepts_msgpack {
+    forwarded_req = forwarded_req.insert_header((ACCEPT, "application/json"));
+  }
+  let msgpack_request = msgpack::is_msgpack_request(req.headers(), &config.msgpack);
+  let started = Instant::now();
+  //需要签名或转换的请求先读取完整请求体 签名按客户端发送的内容校验 校验失败不转发
+  let mut res = if config.signature.applies_to(path) || msgpack_request {
+    let max_body_bytes = match config.signature.applies_to(path) {
+      true => MAX_SIGNED_BODY_BYTES,
+      false => config.msgpack.max_body_bytes as usize,
+    };
+    let body = match read_body(&mut payload, max_body_bytes).await? {
+      Some(body) => body,
+      None => return Ok(HttpResponse::PayloadTooLarge().finish()),
+    };
+    if config.signature.applies_to(path) {
+      if let Err(reason) = signature::verify_request(product_code, &config.signature, &req, &body) {
+        return Ok(HttpResponse::Unauthorized().body(reason));
+      }
+    }
+    if let Some(bucket) = bandwidth::bucket(product_code, Direction::Upload, &config.bandwidth) {
+      bucket.acquire(body.len()).await;
+    }
+    bandwidth::record(product_code, Direction::Upload, body.len());
+    let body = match msgpack_request {
+      true => match msgpack::to_json(&body) {
+        Ok(json) => {
+          msgpack::record(product_code, "request");
+          forwarded_req.headers_mut().remove(CONTENT_LENGTH);
+          forwarded_req = forwarded_req.insert_header((CONTENT_TYPE, "application/json"));
+          web::Bytes::from(json)
+        }
+        Err(err) => {
+          return Ok(HttpResponse::BadRequest().body(format!("invalid MessagePack body: {}", err)));
+        }
+      },
+      false => body.freeze(),
+    };
+    //请求体已经读完 不再需要 runtime 确认
+    forwarded_req.headers_mut().remove(EXPECT);
+    watch_disconnect(product_code, forwarded_req.send_body(body)).await
+  } else {
+    let body = bandwidth::throttle(payload, product_code, Direction::Upload, &config.bandwidth);
+    let request_framing = framing::request_framing(req.headers());
+    watch_disconnect(product_code, framing::send(forwarded_req, request_framing, body)).await
+  }
+  .map_err(error::ErrorInternalServerError)?;
+  let endpoint = usage::endpoint_label(req.method().as_str(), path);
+  usage::record_request(product_code, &endpoint);
+  //声明了路由清单时按路由统计
+  if let Some(route) = routes::match_route(&config.routes, req.method().as_str(), path) {
+    let status = format!("{}xx", res.status().as_u16() / 100);
+    let labels = [("product", product_code), ("route", route.as_str()), ("status", status.as_str())];
+    metrics::inc_counter("gateway_route_requests_total", "Requests per manifest route", &labels, 1);
+    metrics::inc_counter(
+      "gateway_route_duration_milliseconds_total",
+      "Time until the runtime responded per manifest route",
+      &labels,
+      started.elapsed().as_millis() as u64,
+    );
+  }
+  anomaly::record(product_code, res.status().is_server_error());
+  recycle::record_request(product_code);
+  if let Some(span) = span {
+    span.finish(res.status().as_u16());
+  }
+  let header_u64 = |name: &str| res.headers().get(name).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok());
+  if let (Some(cpu_micros), Some(heap_bytes)) = (header_u64(USAGE_CPU_HEADER), header_u64(USAGE_HEAP_HEADER)) {
+    usage::record_sample(product_code, &endpoint, cpu_micros, heap_bytes);
+  }
   let mut client_resp = HttpResponse::build(res.status());
-  for (header_name, header_value) in res.headers().iter().filter(|(h, _)| *h != "connection") {
-    client_resp.insert_header((header_name.clone(), header_value.clone()));
+  let internal_headers = [USAGE_CPU_HEADER, USAGE_HEAP_HEADER];
+  //网关改动响应体时在这里声明 长度和编码相关的头随之调整
+  let mut headers = res.headers().clone();
+  let length = framing::normalize_response(res.status(), &mut headers, BodyChange::Unchanged);
+  //转换成 MessagePack 时改写响应体 length 作为读取上限
+  let msgpack_limit = length.filter(|_| accepts_msgpack && msgpack::applies(req.method(), res.status(), &headers, length, &config.msgpack));
+  if msgpack_limit.is_some() {
+    framing::normalize_response(res.status(), &mut headers, BodyChange::Rewritten);
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(msgpack::MSGPACK_CONTENT_TYPE));
+    headers.append(VARY, HeaderValue::from_static("accept"));
+  }
+  for (header_name, header_value) in headers.iter().filter(|(h, _)| !internal_headers.contains(&h.as_str())) {
+    if header_name == SET_COOKIE {
+      let value = cookies::rewrite_set_cookie(&config.cookies, header_value.to_str().unwrap_or_default());
+      client_resp.append_header((SET_COOKIE, value));
+      continue;
+    }
+    //同名的头可能有多个 例如 Set-Cookie
+    client_resp.append_header((header_name.clone(), header_value.clone()));
   }
-  Ok(client_resp.streaming(res))
+  //runtime 没有设置的安全头由网关注入
+  let https = req.connection_info().scheme() == "https";
+  for (header_name, header_value) in security_headers::headers_for(product_code, &config.security_headers, https) {
+    if !res.headers().contains_key(&header_name) {
+      client_resp.insert_header((header_name, header_value));
+    }
+  }
+  if let Some(limit) = msgpack_limit {
+    let body = res.body().limit(limit as usize).await.map_err(error::ErrorBadGateway)?;
+    bandwidth::record(product_code, Direction::Download, body.len());
+    //runtime 返回的不是合法的 JSON 时原样返回
+    let body = match msgpack::from_json(&body) {
+      Ok(packed) => {
+        msgpack::record(product_code, "response");
+        web::Bytes::from(packed)
+      }
+      Err(err) => {
+        log::warn!("failed to translate {} response to MessagePack: {}", product_code, err);
+        client_resp.insert_header((CONTENT_TYPE, "application/json"));
+        body
+      }
+    };
+    if etag::applies(&req, res.status(), &headers, Some(body.len() as u64), &config.etag) {
+      return Ok(etag::respond(product_code, &req, client_resp, &headers, body));
+    }
+    return Ok(client_resp.body(body));
+  }
+  //开启 etag 时缓冲较小的响应体计算 ETag
+  if etag::applies(&req, res.status(), &headers, length, &config.etag) {
+    let body = res.body().limit(length.unwrap_or(0) as usize).await.map_err(error::ErrorBadGateway)?;
+    bandwidth::record(product_code, Direction::Download, body.len());
+    return Ok(etag::respond(product_code, &req, client_resp, &headers, body));
+  }
+  //runtime 声明了长度时客户端同样收到 content-length 否则使用 chunked
+  let body = bandwidth::throttle(res, product_code, Direction::Download, &config.bandwidth);
+  match length {
+    Some(length) => Ok(client_resp.body(SizedStream::new(length, body))),
+    None => Ok(client_resp.streaming(body)),
+  }
+}
+
+///转发给产品前的访问策略 过载 runtime 压力 客户端证书和地区 GraphQL 入口转发子查询时同样检查<br>
+/// 通过时返回转发中的请求和客户端的地区 拒绝时返回响应
+pub fn check_policies(req: &HttpRequest, product_code: &str, path: &str, config: &ProductConfig) -> Result<(Admitted, GeoInfo), HttpResponse> {
+  //网关过载时先拒绝低优先级的产品
+  let admitted = match overload::admit(product_code, config.priority) {
+    Ok(admitted) => admitted,
+    Err(secs) => {
+      return Err(
+        HttpResponse::TooManyRequests()
+          .insert_header((RETRY_AFTER, secs))
+          .body("gateway overloaded, retry later"),
+      );
+    }
+  };
+  //runtime 压力较高时按比例减少接受的请求
+  if !admission::admit(product_code, &config.admission) {
+    return Err(
+      HttpResponse::ServiceUnavailable()
+        .insert_header((RETRY_AFTER, 1))
+        .body("service under pressure, retry later"),
+    );
+  }
+  if req.conn_data::<ClientCert>().is_none() && config.mtls.applies_to(path) {
+    return Err(HttpResponse::Forbidden().body("client certificate required"));
+  }
+  let geo = req.peer_addr().map(|addr| geoip::lookup(addr.ip())).unwrap_or_default();
+  let country = geo.country.as_deref().unwrap_or("unknown");
+  metrics::inc_counter(
+    "gateway_requests_by_country_total",
+    "Requests received by the gateway by client country",
+    &[("product", product_code), ("country", country)],
+    1,
+  );
+  if !config.geo.is_allowed(geo.country.as_deref()) {
+    metrics::inc_counter(
+      "gateway_geo_blocked_total",
+      "Requests rejected by the product geo policy",
+      &[("product", product_code), ("country", country)],
+      1,
+    );
+    return Err(HttpResponse::Forbidden().body("access from your region is not allowed"));
+  }
+  Ok((admitted, geo))
+}
+
+///产品配置读取失败时拒绝转发 不能按默认配置放开签名 地区等访问策略
+fn config_unavailable(product_code: &str, err: AnyError) -> HttpResponse {
+  HttpResponse::ServiceUnavailable().body(format!("{} config unavailable: {}", product_code, err))
+}
+
+///请求的调用方 控制台会话 客户端证书和请求签名都可能有<br>
+/// 签名校验失败的请求不会转发 所以需要签名时直接记录使用的密钥
+fn principal_claims(req: &HttpRequest, client_cert: Option<&ClientCert>, signed_with: Option<&str>) -> Option<BTreeMap<String, String>> {
+  let mut claims = BTreeMap::new();
+  if let Some(principal) = auth::enabled().then(|| auth::authenticate(req).ok()).flatten() {
+    let role = serde_json::to_value(principal.role).ok().and_then(|r| r.as_str().map(|r| r.to_string()));
+    claims.insert("user_id".to_string(), principal.user_id);
+    claims.insert("email".to_string(), principal.email);
+    claims.extend(role.map(|r| ("role".to_string(), r)));
+    claims.extend(principal.tenant_id.map(|t| ("tenant_id".to_string(), t)));
+  }
+  if let Some(cert) = client_cert {
+    claims.insert("cert_subject".to_string(), cert.subject.clone());
+    claims.insert("cert_fingerprint".to_string(), cert.fingerprint.clone());
+  }
+  if let Some(secret) = signed_with {
+    claims.insert("signature_secret".to_string(), secret.to_string());
+  }
+  (!claims.is_empty()).then_some(claims)
+}
+
+///客户端在 runtime 响应前断开时记录指标
+struct DisconnectGuard<'a> {
+  product_code: &'a str,
+  responded: bool,
+}
+
+impl Drop for DisconnectGuard<'_> {
+  fn drop(&mut self) {
+    if !self.responded {
+      metrics::inc_counter(
+        "gateway_client_disconnects_total",
+        "Requests whose client disconnected before the runtime responded",
+        &[("product", self.product_code)],
+        1,
+      );
+    }
+  }
+}
+
+///读取完整的请求体 超过 limit 时返回 None
+async fn read_body(payload: &mut web::Payload, limit: usize) -> Result<Option<web::BytesMut>, Error> {
+  let mut body = web::BytesMut::new();
+  while let Some(chunk) = payload.next().await {
+    let chunk = chunk?;
+    if body.len() + chunk.len() > limit {
+      return Ok(None);
+    }
+    body.extend_from_slice(&chunk);
+  }
+  Ok(Some(body))
+}
+
+///等待 runtime 响应 <br>
+/// 客户端断开时 actix 会丢弃 handler 连同这里的请求 到 runtime 的连接随之关闭<br>
+/// runtime 中该请求的 request.signal 因此被 abort 脚本可以停止无用的计算
+async fn watch_disconnect<F: std::future::Future>(product_code: &str, fut: F) -> F::Output {
+  let mut guard = DisconnectGuard {
+    product_code,
+    responded: false,
+  };
+  let output = fut.await;
+  guard.responded = true;
+  output
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
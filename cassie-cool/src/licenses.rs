@@ -0,0 +1,355 @@
+use crate::config::{product_entry, ProductConfig};
+use crate::worker_util::{run_tool, tool_flags};
+use awc::Client;
+use deno_core::error::{generic_error, AnyError};
+use serde::{Deserialize, Serialize};
+use service::tools::deps::{collect_dependencies, DependencyInfo};
+
+const NPM_REGISTRY_URL: &str = "https://registry.npmjs.org";
+
+///许可证分类
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LicenseCategory {
+  Permissive,
+  WeakCopyleft,
+  Copyleft,
+  Unknown,
+}
+
+///许可证策略 cool.json 中的 licenses 配置
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LicensePolicy {
+  pub allow: Vec<String>,                    //允许的许可证 为空时不限制
+  pub deny: Vec<String>,                     //禁止的许可证
+  pub deny_categories: Vec<LicenseCategory>, //禁止的分类 例如 copyleft
+  pub block_deploy: bool,                    //存在不允许的许可证时阻止生产部署
+}
+
+impl LicensePolicy {
+  fn is_allowed(&self, license: &str, category: LicenseCategory) -> bool {
+    if self.deny.iter().any(|d| d.eq_ignore_ascii_case(license)) || self.deny_categories.contains(&category) {
+      return false;
+    }
+    self.allow.is_empty() || self.allow.iter().any(|a| a.eq_ignore_ascii_case(license))
+  }
+}
+
+///单个依赖的许可证信息
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DependencyLicense {
+  pub registry: String,
+  pub package: String,
+  pub version: Option<String>,
+  pub license: Option<String>,
+  pub category: LicenseCategory,
+  pub allowed: bool,
+}
+
+///许可证扫描结果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LicenseReport {
+  pub product_code: String,
+  pub dependencies: Vec<DependencyLicense>,
+  pub disallowed: Vec<String>,
+  pub blocked: bool,
+}
+
+impl LicenseCategory {
+  ///限制程度 未知的许可证按最严格处理
+  fn restrictiveness(self) -> u8 {
+    match self {
+      Self::Permissive => 0,
+      Self::WeakCopyleft => 1,
+      Self::Copyleft => 2,
+      Self::Unknown => 3,
+    }
+  }
+}
+
+///按单个 SPDX 标识归类
+fn classify_id(id: &str) -> LicenseCategory {
+  let upper = id.to_ascii_uppercase();
+  if upper.starts_with("AGPL") || upper.starts_with("GPL") || upper.starts_with("SSPL") {
+    LicenseCategory::Copyleft
+  } else if upper.starts_with("LGPL") || upper.starts_with("MPL") || upper.starts_with("EPL") || upper.starts_with("CDDL") {
+    LicenseCategory::WeakCopyleft
+  } else if ["MIT", "ISC", "BSD", "APACHE", "0BSD", "UNLICENSE", "CC0", "ZLIB", "BLUEOAK", "PYTHON"]
+    .iter()
+    .any(|p| upper.starts_with(p))
+  {
+    LicenseCategory::Permissive
+  } else {
+    LicenseCategory::Unknown
+  }
+}
+
+///SPDX 表达式的解析 OR 取限制最少的一项 AND 取限制最多的一项 WITH 后面的例外不影响分类
+struct Expression<'a> {
+  tokens: Vec<&'a str>,
+  pos: usize,
+}
+
+impl<'a> Expression<'a> {
+  fn new(license: &'a str) -> Self {
+    let mut tokens = vec![];
+    for word in license.split_whitespace() {
+      let mut rest = word;
+      while !rest.is_empty() {
+        match rest.find(['(', ')']) {
+          Some(0) => {
+            tokens.push(&rest[..1]);
+            rest = &rest[1..];
+          }
+          Some(i) => {
+            tokens.push(&rest[..i]);
+            rest = &rest[i..];
+          }
+          None => {
+            tokens.push(rest);
+            rest = "";
+          }
+        }
+      }
+    }
+    Self { tokens, pos: 0 }
+  }
+
+  fn peek(&self) -> Option<&'a str> {
+    self.tokens.get(self.pos).copied()
+  }
+
+  fn next(&mut self) -> Option<&'a str> {
+    let token = self.peek();
+    self.pos += 1;
+    token
+  }
+
+  fn operator(&mut self, op: &str) -> bool {
+    match self.peek() {
+      Some(token) if token.eq_ignore_ascii_case(op) => {
+        self.pos += 1;
+        true
+      }
+      _ => false,
+    }
+  }
+
+  fn or(&mut self) -> Option<LicenseCategory> {
+    let mut category = self.and()?;
+    while self.operator("OR") {
+      category = [category, self.and()?].into_iter().min_by_key(|c| c.restrictiveness())?;
+    }
+    Some(category)
+  }
+
+  fn and(&mut self) -> Option<LicenseCategory> {
+    let mut category = self.atom()?;
+    while self.operator("AND") {
+      category = [category, self.atom()?].into_iter().max_by_key(|c| c.restrictiveness())?;
+    }
+    Some(category)
+  }
+
+  fn atom(&mut self) -> Option<LicenseCategory> {
+    match self.next()? {
+      "(" => {
+        let category = self.or()?;
+        (self.next()? == ")").then_some(category)
+      }
+      ")" => None,
+      id => {
+        if self.operator("WITH") {
+          self.next()?;
+        }
+        Some(classify_id(id))
+      }
+    }
+  }
+}
+
+///按 SPDX 表达式归类 例如 (MIT OR Apache-2.0) 无法解析时为 Unknown
+pub fn classify(license: &str) -> LicenseCategory {
+  let mut expression = Expression::new(license);
+  match expression.or() {
+    Some(category) if expression.peek().is_none() => category,
+    _ => LicenseCategory::Unknown,
+  }
+}
+
+///从 LICENSE 文件内容识别 SPDX 标识
+pub fn detect_license_text(text: &str) -> Option<String> {
+  let id = if text.contains("GNU AFFERO GENERAL PUBLIC LICENSE") {
+    "AGPL-3.0"
+  } else if text.contains("GNU LESSER GENERAL PUBLIC LICENSE") {
+    "LGPL-3.0"
+  } else if text.contains("GNU GENERAL PUBLIC LICENSE") {
+    if text.contains("Version 2") {
+      "GPL-2.0"
+    } else {
+      "GPL-3.0"
+    }
+  } else if text.contains("Mozilla Public License") {
+    "MPL-2.0"
+  } else if text.contains("Apache License") {
+    "Apache-2.0"
+  } else if text.contains("Permission is hereby granted, free of charge") {
+    "MIT"
+  } else if text.contains("Permission to use, copy, modify, and/or distribute") {
+    "ISC"
+  } else if text.contains("Redistribution and use in source and binary forms") {
+    if text.contains("Neither the name") {
+      "BSD-3-Clause"
+    } else {
+      "BSD-2-Clause"
+    }
+  } else if text.contains("This is free and unencumbered software") {
+    "Unlicense"
+  } else {
+    return None;
+  };
+  Some(id.to_string())
+}
+
+#[derive(Deserialize)]
+struct NpmVersionInfo {
+  #[serde(default)]
+  license: Option<serde_json::Value>,
+}
+
+async fn npm_license(client: &Client, dep: &DependencyInfo) -> Option<String> {
+  let url = format!("{}/{}/{}", NPM_REGISTRY_URL, dep.name.replace('/', "%2F"), dep.version.as_ref()?);
+  let info: NpmVersionInfo = client.get(url).send().await.ok()?.json().limit(8 * 1024 * 1024).await.ok()?;
+  match info.license? {
+    serde_json::Value::String(license) => Some(license),
+    serde_json::Value::Object(map) => map.get("type").and_then(|t| t.as_str()).map(|t| t.to_string()),
+    _ => None,
+  }
+}
+
+async fn deno_land_license(client: &Client, dep: &DependencyInfo) -> Option<String> {
+  let version = dep.version.as_ref()?;
+  for file in ["LICENSE", "LICENSE.md", "LICENSE.txt"] {
+    let url = format!("https://deno.land/x/{}@{}/{}", dep.name, version, file);
+    let mut res = match client.get(url).send().await {
+      Ok(res) if res.status().is_success() => res,
+      _ => continue,
+    };
+    if let Ok(body) = res.body().limit(1024 * 1024).await {
+      return detect_license_text(&String::from_utf8_lossy(&body));
+    }
+  }
+  None
+}
+
+///扫描产品模块图中所有依赖的许可证
+pub async fn scan_product(product_code: &str) -> Result<LicenseReport, AnyError> {
+  let config = ProductConfig::load(product_code)?;
+  let entry = product_entry(product_code);
+  let deps = run_tool(format!("product-{}-licenses", product_code), move || async move {
+    collect_dependencies(tool_flags("run", &entry)?).await
+  })
+  .await?;
+
+  let client = Client::default();
+  let mut dependencies = vec![];
+  for dep in deps.iter().filter(|d| d.registry != "node") {
+    let license = match dep.registry.as_str() {
+      "npm" => npm_license(&client, dep).await,
+      "deno.land/std" => Some("MIT".to_string()),
+      "deno.land/x" => deno_land_license(&client, dep).await,
+      _ => None,
+    };
+    let category = license.as_deref().map(classify).unwrap_or(LicenseCategory::Unknown);
+    let allowed = match &license {
+      Some(license) => config.licenses.is_allowed(license, category),
+      None => config.licenses.allow.is_empty() && !config.licenses.deny_categories.contains(&LicenseCategory::Unknown),
+    };
+    dependencies.push(DependencyLicense {
+      registry: dep.registry.clone(),
+      package: dep.name.clone(),
+      version: dep.version.clone(),
+      license,
+      category,
+      allowed,
+    });
+  }
+  let disallowed: Vec<String> = dependencies
+    .iter()
+    .filter(|d| !d.allowed)
+    .map(|d| {
+      format!(
+        "{}@{} ({})",
+        d.package,
+        d.version.clone().unwrap_or_default(),
+        d.license.clone().unwrap_or_else(|| "unknown".to_string())
+      )
+    })
+    .collect();
+  let blocked = config.licenses.block_deploy && !disallowed.is_empty();
+  Ok(LicenseReport {
+    product_code: product_code.to_string(),
+    dependencies,
+    disallowed,
+    blocked,
+  })
+}
+
+///生产部署前的许可证检查 配置了策略才会执行
+pub async fn ensure_deployable(product_code: &str) -> Result<(), AnyError> {
+  let config = ProductConfig::load(product_code)?;
+  let policy = &config.licenses;
+  if policy.allow.is_empty() && policy.deny.is_empty() && policy.deny_categories.is_empty() {
+    return Ok(());
+  }
+  let report = scan_product(product_code).await?;
+  if report.blocked {
+    return Err(generic_error(format!("deploy blocked by license policy: {}", report.disallowed.join(", "))));
+  }
+  if !report.disallowed.is_empty() {
+    log::warn!("{} uses disallowed licenses: {}", product_code, report.disallowed.join(", "));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_classify() {
+    assert_eq!(classify("MIT"), LicenseCategory::Permissive);
+    assert_eq!(classify("Apache-2.0"), LicenseCategory::Permissive);
+    assert_eq!(classify("LGPL-2.1-only"), LicenseCategory::WeakCopyleft);
+    assert_eq!(classify("AGPL-3.0"), LicenseCategory::Copyleft);
+    assert_eq!(classify("SEE LICENSE IN LICENSE.md"), LicenseCategory::Unknown);
+    assert_eq!(classify("(MIT OR Apache-2.0)"), LicenseCategory::Permissive);
+    assert_eq!(classify("MIT AND GPL-3.0"), LicenseCategory::Copyleft);
+    assert_eq!(classify("(LGPL-2.1 OR GPL-3.0) AND MIT"), LicenseCategory::WeakCopyleft);
+    assert_eq!(classify("GPL-2.0 WITH Classpath-exception-2.0 OR MIT"), LicenseCategory::Permissive);
+    assert_eq!(classify("(MIT OR Apache-2.0"), LicenseCategory::Unknown);
+  }
+
+  #[test]
+  fn test_policy() {
+    let policy = LicensePolicy {
+      deny_categories: vec![LicenseCategory::Copyleft],
+      ..Default::default()
+    };
+    assert!(policy.is_allowed("MIT", LicenseCategory::Permissive));
+    assert!(!policy.is_allowed("GPL-3.0", LicenseCategory::Copyleft));
+    let policy = LicensePolicy {
+      allow: vec!["MIT".to_string()],
+      ..Default::default()
+    };
+    assert!(!policy.is_allowed("ISC", LicenseCategory::Permissive));
+  }
+
+  #[test]
+  fn test_detect_license_text() {
+    let mit = "MIT License\n\nPermission is hereby granted, free of charge, to any person";
+    assert_eq!(detect_license_text(mit), Some("MIT".to_string()));
+    assert_eq!(detect_license_text("all rights reserved"), None);
+  }
+}
@@ -0,0 +1,221 @@
+use crate::Res;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::HttpResponse;
+use deno_core::error::{generic_error, AnyError};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+
+///每页条数上限
+const MAX_LIMIT: usize = 1000;
+///下一页的游标 没有下一页时不返回
+pub const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
+///过滤后的总条数
+pub const TOTAL_COUNT_HEADER: &str = "x-total-count";
+
+///列表接口共用的查询参数<br>
+/// 不传 limit 时返回全部 响应体仍然是数组 分页信息在响应头中
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListQuery {
+  #[serde(default)]
+  pub cursor: Option<String>, //上一页响应头中的 x-next-cursor
+  #[serde(default)]
+  pub limit: Option<usize>,
+  #[serde(default)]
+  pub sort: Option<String>, //字段名 前面加 - 为倒序 例如 -created_at
+  #[serde(default)]
+  pub status: Option<String>,
+  #[serde(default)]
+  pub tag: Option<String>,
+  #[serde(default)]
+  pub owner: Option<String>,
+}
+
+///列表接口支持的排序字段和过滤条件 过滤条件对应的字段为 None 时不支持
+pub struct ListSpec {
+  pub id: &'static str, //唯一的字段 排序值相同时按它排序 游标中记录
+  pub sort: &'static [&'static str],
+  pub default_sort: &'static str,
+  pub status: Option<&'static str>,
+  pub tag: Option<&'static str>,
+  pub owner: Option<&'static str>,
+}
+
+///一页数据
+#[derive(Debug, Clone)]
+pub struct Page<T> {
+  pub items: Vec<T>,
+  pub next_cursor: Option<String>,
+  pub total: usize,
+}
+
+impl<T> Page<T>
+where
+  T: Serialize + DeserializeOwned + Clone,
+{
+  pub fn respond_to(self) -> HttpResponse {
+    let mut res = Res { code: 0, data: self.items }.respond_to();
+    self.headers(&mut res);
+    res
+  }
+
+  ///把分页信息写入响应头 用于数据不直接是列表的接口
+  pub fn headers(&self, res: &mut HttpResponse) {
+    let headers = res.headers_mut();
+    headers.insert(HeaderName::from_static(TOTAL_COUNT_HEADER), HeaderValue::from(self.total));
+    if let Some(cursor) = self.next_cursor.as_ref().and_then(|c| HeaderValue::from_str(c).ok()) {
+      headers.insert(HeaderName::from_static(NEXT_CURSOR_HEADER), cursor);
+    }
+  }
+}
+
+///游标记录最后一条的排序值和 id 列表变化时从原来的位置继续
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+  sort: String,
+  key: Value,
+  id: Value,
+}
+
+fn compare(a: &Value, b: &Value) -> Ordering {
+  match (a, b) {
+    (Value::Number(a), Value::Number(b)) => a.as_f64().unwrap_or_default().total_cmp(&b.as_f64().unwrap_or_default()),
+    (Value::String(a), Value::String(b)) => a.cmp(b),
+    (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+    (Value::Null, Value::Null) => Ordering::Equal,
+    (Value::Null, _) => Ordering::Less,
+    (_, Value::Null) => Ordering::Greater,
+    _ => a.to_string().cmp(&b.to_string()),
+  }
+}
+
+///字段等于过滤值 数组字段包含过滤值 不区分大小写
+fn matches(value: &Value, expected: &str) -> bool {
+  match value {
+    Value::String(s) => s.eq_ignore_ascii_case(expected),
+    Value::Array(values) => values.iter().any(|v| matches(v, expected)),
+    Value::Bool(b) => b.to_string() == expected,
+    Value::Number(n) => n.to_string() == expected,
+    _ => false,
+  }
+}
+
+fn decode_cursor(cursor: &str) -> Result<Cursor, AnyError> {
+  let bytes = hex::decode(cursor).map_err(|_| generic_error("invalid cursor"))?;
+  serde_json::from_slice(&bytes).map_err(|_| generic_error("invalid cursor"))
+}
+
+///按查询参数过滤 排序并取一页
+pub fn apply<T: Serialize>(items: Vec<T>, query: &ListQuery, spec: &ListSpec) -> Result<Page<T>, AnyError> {
+  let sort = query.sort.clone().unwrap_or_else(|| spec.default_sort.to_string());
+  let (field, desc) = match sort.strip_prefix('-') {
+    Some(field) => (field, true),
+    None => (sort.as_str(), false),
+  };
+  if !spec.sort.contains(&field) {
+    return Err(generic_error(format!(
+      "cannot sort by {}, supported fields: {}",
+      field,
+      spec.sort.join(", ")
+    )));
+  }
+  let mut filters = vec![];
+  for (name, expected, column) in [
+    ("status", &query.status, spec.status),
+    ("tag", &query.tag, spec.tag),
+    ("owner", &query.owner, spec.owner),
+  ] {
+    match (expected, column) {
+      (Some(expected), Some(column)) => filters.push((column, expected.as_str())),
+      (Some(_), None) => return Err(generic_error(format!("filtering by {} is not supported", name))),
+      (None, _) => {}
+    }
+  }
+  let mut rows = vec![];
+  for item in items {
+    let value = serde_json::to_value(&item)?;
+    if filters.iter().all(|(column, expected)| matches(&value[*column], expected)) {
+      rows.push((value[field].clone(), value[spec.id].clone(), item));
+    }
+  }
+  let order = |a: (&Value, &Value), b: (&Value, &Value)| {
+    let ordering = compare(a.0, b.0).then_with(|| compare(a.1, b.1));
+    if desc {
+      ordering.reverse()
+    } else {
+      ordering
+    }
+  };
+  rows.sort_by(|a, b| order((&a.0, &a.1), (&b.0, &b.1)));
+  let total = rows.len();
+  if let Some(cursor) = &query.cursor {
+    let cursor = decode_cursor(cursor)?;
+    if cursor.sort != sort {
+      return Err(generic_error("the cursor was created with a different sort"));
+    }
+    rows.retain(|(key, id, _)| order((key, id), (&cursor.key, &cursor.id)) == Ordering::Greater);
+  }
+  let limit = match query.limit {
+    Some(limit) => limit.clamp(1, MAX_LIMIT),
+    None => rows.len(),
+  };
+  let next_cursor = match rows.get(limit.saturating_sub(1)) {
+    Some((key, id, _)) if rows.len() > limit => Some(hex::encode(serde_json::to_vec(&Cursor {
+      sort: sort.clone(),
+      key: key.clone(),
+      id: id.clone(),
+    })?)),
+    _ => None,
+  };
+  Ok(Page {
+    items: rows.into_iter().take(limit).map(|(_, _, item)| item).collect(),
+    next_cursor,
+    total,
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn pages_with_cursor() {
+    let spec = ListSpec {
+      id: "id",
+      sort: &["id", "created_at"],
+      default_sort: "id",
+      status: Some("status"),
+      tag: Some("tags"),
+      owner: None,
+    };
+    let items: Vec<Value> = (1..=5)
+      .map(|i| json!({"id": i, "created_at": 100 - i, "status": if i % 2 == 0 { "ok" } else { "failed" }, "tags": ["a"]}))
+      .collect();
+    let query = ListQuery {
+      limit: Some(2),
+      sort: Some("-created_at".to_string()),
+      ..Default::default()
+    };
+    let page = apply(items.clone(), &query, &spec).unwrap();
+    assert_eq!(page.items, vec![items[0].clone(), items[1].clone()]);
+    assert_eq!(page.total, 5);
+    let query = ListQuery {
+      cursor: page.next_cursor,
+      ..query
+    };
+    let page = apply(items.clone(), &query, &spec).unwrap();
+    assert_eq!(page.items, vec![items[2].clone(), items[3].clone()]);
+    let query = ListQuery {
+      status: Some("ok".to_string()),
+      ..Default::default()
+    };
+    assert_eq!(apply(items.clone(), &query, &spec).unwrap().items.len(), 2);
+    let query = ListQuery {
+      owner: Some("team".to_string()),
+      ..Default::default()
+    };
+    assert!(apply(items, &query, &spec).is_err());
+  }
+}
@@ -0,0 +1,404 @@
+use crate::config::GatewayConfig;
+use crate::metrics;
+use crate::util::now_millis;
+use chrono::{TimeZone, Utc};
+use deno_core::error::{generic_error, AnyError};
+use deno_runtime::deno_fetch::reqwest;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+
+///发送失败后的重试次数 仍然失败时丢弃这一批
+const MAX_ATTEMPTS: u32 = 5;
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogProtocol {
+  Udp,
+  Tcp,
+}
+
+///日志的目的地
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SinkTarget {
+  ///RFC 5424 格式 tcp 使用 octet counting 分帧
+  Syslog {
+    address: String, //host:port
+    #[serde(default = "default_protocol")]
+    protocol: SyslogProtocol,
+    #[serde(default = "default_facility")]
+    facility: u8, //默认 16 即 local0
+  },
+  ///Grafana Loki 的 push 接口 按产品和输出流分组为不同的 stream
+  Loki {
+    url: String, //例如 http://loki:3100
+    #[serde(default)]
+    labels: BTreeMap<String, String>, //附加在所有 stream 上的标签
+    #[serde(default)]
+    headers: BTreeMap<String, String>, //例如 Authorization 或 X-Scope-OrgID
+  },
+  ///Elasticsearch 的 _bulk 接口
+  Elasticsearch {
+    url: String,
+    #[serde(default = "default_index")]
+    index: String, //{date} 替换为 UTC 日期 例如 cool-logs-2023.06.01
+    #[serde(default)]
+    headers: BTreeMap<String, String>, //例如 Authorization: ApiKey ...
+  },
+}
+
+fn default_protocol() -> SyslogProtocol {
+  SyslogProtocol::Udp
+}
+
+fn default_facility() -> u8 {
+  16
+}
+
+fn default_index() -> String {
+  "cool-logs-{date}".to_string()
+}
+
+///日志投递 gateway.json 中的 log_sinks<br>
+/// 每个目的地有自己的缓冲队列 队列满时丢弃新的日志并计入 gateway_log_sink_dropped_total
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogSink {
+  pub name: String,
+  #[serde(flatten)]
+  pub target: SinkTarget,
+  #[serde(default = "default_batch_size")]
+  pub batch_size: usize, //攒够这么多条立即发送
+  #[serde(default = "default_flush_secs")]
+  pub flush_secs: u64, //不满一批时最多等待这么久
+  #[serde(default = "default_max_buffer")]
+  pub max_buffer: usize, //队列长度上限 目的地不可用时缓冲的日志数
+  #[serde(default)]
+  pub products: Vec<String>, //只投递这些产品的日志 为空时全部投递
+  #[serde(default = "default_gateway")]
+  pub gateway: bool, //是否投递网关自己的日志
+}
+
+fn default_batch_size() -> usize {
+  500
+}
+
+fn default_flush_secs() -> u64 {
+  5
+}
+
+fn default_max_buffer() -> usize {
+  10_000
+}
+
+fn default_gateway() -> bool {
+  true
+}
+
+///一条日志 product_code 为空时是网关自己的日志
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogRecord {
+  pub product_code: Option<String>,
+  pub stream: String, //runtime 的 stdout stderr 或网关日志的级别
+  pub message: String,
+  pub timestamp: u64, //毫秒
+}
+
+impl LogRecord {
+  fn app(&self) -> &str {
+    self.product_code.as_deref().unwrap_or("gateway")
+  }
+
+  ///syslog 的 severity
+  fn severity(&self) -> u8 {
+    match self.stream.as_str() {
+      "error" | "stderr" => 3,
+      "warn" => 4,
+      "debug" | "trace" => 7,
+      _ => 6,
+    }
+  }
+}
+
+struct SinkHandle {
+  config: LogSink,
+  tx: mpsc::Sender<LogRecord>,
+}
+
+lazy_static! {
+  static ref SINKS: RwLock<Vec<SinkHandle>> = RwLock::new(vec![]);
+}
+
+impl LogSink {
+  fn accepts(&self, record: &LogRecord) -> bool {
+    match &record.product_code {
+      Some(product_code) => self.products.is_empty() || self.products.contains(product_code),
+      None => self.gateway,
+    }
+  }
+}
+
+///投递一条日志 不阻塞 队列满时丢弃
+pub fn ship(record: LogRecord) {
+  let sinks = SINKS.read().unwrap();
+  for sink in sinks.iter().filter(|s| s.config.accepts(&record)) {
+    if sink.tx.try_send(record.clone()).is_err() {
+      metrics::inc_counter(
+        "gateway_log_sink_dropped_total",
+        "Log records dropped because a sink queue was full",
+        &[("sink", &sink.config.name)],
+        1,
+      );
+    }
+  }
+}
+
+fn timestamp(millis: u64) -> chrono::DateTime<Utc> {
+  Utc.timestamp_millis_opt(millis as i64).single().unwrap_or_else(Utc::now)
+}
+
+///RFC 5424 格式的一条消息
+fn syslog_line(record: &LogRecord, facility: u8, hostname: &str) -> String {
+  format!(
+    "<{}>1 {} {} {} - {} - {}",
+    facility as u32 * 8 + record.severity() as u32,
+    timestamp(record.timestamp).format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+    hostname,
+    record.app(),
+    record.stream,
+    record.message
+  )
+}
+
+fn loki_body(records: &[LogRecord], labels: &BTreeMap<String, String>) -> serde_json::Value {
+  let mut streams: BTreeMap<(String, String), Vec<[String; 2]>> = BTreeMap::new();
+  for record in records {
+    streams
+      .entry((record.app().to_string(), record.stream.clone()))
+      .or_default()
+      .push([(record.timestamp as u128 * 1_000_000).to_string(), record.message.clone()]);
+  }
+  let streams: Vec<_> = streams
+    .into_iter()
+    .map(|((product, stream), values)| {
+      let mut labels = labels.clone();
+      labels.insert("product".to_string(), product);
+      labels.insert("stream".to_string(), stream);
+      serde_json::json!({ "stream": labels, "values": values })
+    })
+    .collect();
+  serde_json::json!({ "streams": streams })
+}
+
+fn bulk_body(records: &[LogRecord], index: &str) -> Result<String, AnyError> {
+  let mut body = String::new();
+  for record in records {
+    let at = timestamp(record.timestamp);
+    let index = index.replace("{date}", &at.format("%Y.%m.%d").to_string());
+    body.push_str(&serde_json::to_string(&serde_json::json!({ "index": { "_index": index } }))?);
+    body.push('\n');
+    body.push_str(&serde_json::to_string(&serde_json::json!({
+      "@timestamp": at.to_rfc3339(),
+      "product": record.app(),
+      "stream": record.stream,
+      "message": record.message,
+    }))?);
+    body.push('\n');
+  }
+  Ok(body)
+}
+
+async fn post(url: &str, headers: &BTreeMap<String, String>, content_type: &str, body: String) -> Result<String, AnyError> {
+  let client = reqwest::Client::builder().timeout(SEND_TIMEOUT).build()?;
+  let mut req = client.post(url).header("content-type", content_type).body(body);
+  for (name, value) in headers {
+    req = req.header(name.as_str(), value.as_str());
+  }
+  let res = req.send().await?;
+  let status = res.status();
+  let text = res.text().await.unwrap_or_default();
+  match status.is_success() {
+    true => Ok(text),
+    false => Err(generic_error(format!(
+      "responded {}: {}",
+      status,
+      text.chars().take(200).collect::<String>()
+    ))),
+  }
+}
+
+async fn send(target: &SinkTarget, records: &[LogRecord]) -> Result<(), AnyError> {
+  match target {
+    SinkTarget::Syslog { address, protocol, facility } => {
+      let hostname = std::env::var("HOSTNAME").unwrap_or_else(|_| "-".to_string());
+      let lines = records.iter().map(|r| syslog_line(r, *facility, &hostname));
+      match protocol {
+        SyslogProtocol::Udp => {
+          let socket = UdpSocket::bind("0.0.0.0:0").await?;
+          socket.connect(address.as_str()).await?;
+          for line in lines {
+            socket.send(line.as_bytes()).await?;
+          }
+        }
+        SyslogProtocol::Tcp => {
+          let mut stream = tokio::time::timeout(SEND_TIMEOUT, TcpStream::connect(address.as_str())).await??;
+          let mut frames = String::new();
+          for line in lines {
+            frames.push_str(&format!("{} {}", line.len(), line));
+          }
+          stream.write_all(frames.as_bytes()).await?;
+          stream.shutdown().await?;
+        }
+      }
+      Ok(())
+    }
+    SinkTarget::Loki { url, labels, headers } => {
+      let url = format!("{}/loki/api/v1/push", url.trim_end_matches('/'));
+      post(&url, headers, "application/json", loki_body(records, labels).to_string()).await?;
+      Ok(())
+    }
+    SinkTarget::Elasticsearch { url, index, headers } => {
+      let url = format!("{}/_bulk", url.trim_end_matches('/'));
+      let text = post(&url, headers, "application/x-ndjson", bulk_body(records, index)?).await?;
+      //部分文档失败时不整批重试 避免重复写入
+      if serde_json::from_str::<serde_json::Value>(&text).ok().and_then(|v| v["errors"].as_bool()) == Some(true) {
+        log::warn!("elasticsearch rejected part of a batch of {} log records", records.len());
+      }
+      Ok(())
+    }
+  }
+}
+
+///发送一批 失败时指数退避重试 重试期间新日志继续进入队列 队列满后丢弃
+async fn deliver(config: &LogSink, batch: &[LogRecord]) {
+  for attempt in 1..=MAX_ATTEMPTS {
+    match send(&config.target, batch).await {
+      Ok(()) => return,
+      Err(err) if attempt == MAX_ATTEMPTS => {
+        metrics::inc_counter(
+          "gateway_log_sink_failed_total",
+          "Log records dropped after a sink kept failing",
+          &[("sink", &config.name)],
+          batch.len() as u64,
+        );
+        //这里的日志不会再投递到日志目的地 见 ShippingLogger
+        log::error!("log sink {} dropped {} records: {}", config.name, batch.len(), err);
+      }
+      Err(_) => tokio::time::sleep(Duration::from_secs(2u64.pow(attempt).min(60))).await,
+    }
+  }
+}
+
+///收到第一条日志后 在 flush_secs 内攒够一批或到时间后发送
+async fn run(config: LogSink, mut rx: mpsc::Receiver<LogRecord>) {
+  let batch_size = config.batch_size.max(1);
+  let mut batch = Vec::with_capacity(batch_size);
+  loop {
+    match rx.recv().await {
+      Some(record) => batch.push(record),
+      None => return,
+    }
+    let deadline = tokio::time::sleep(Duration::from_secs(config.flush_secs.max(1)));
+    tokio::pin!(deadline);
+    let mut closed = false;
+    while batch.len() < batch_size {
+      tokio::select! {
+        record = rx.recv() => match record {
+          Some(record) => batch.push(record),
+          None => {
+            closed = true;
+            break;
+          }
+        },
+        _ = &mut deadline => break,
+      }
+    }
+    deliver(&config, &batch).await;
+    batch.clear();
+    if closed {
+      return;
+    }
+  }
+}
+
+///网关日志同时输出到终端和日志目的地<br>
+/// 本模块自己的日志只输出到终端 避免目的地不可用时循环投递
+pub struct ShippingLogger {
+  inner: env_logger::Logger,
+}
+
+impl log::Log for ShippingLogger {
+  fn enabled(&self, metadata: &log::Metadata) -> bool {
+    self.inner.enabled(metadata)
+  }
+
+  fn log(&self, record: &log::Record) {
+    if !self.inner.matches(record) {
+      return;
+    }
+    self.inner.log(record);
+    if !record.target().starts_with(module_path!()) {
+      ship(LogRecord {
+        product_code: None,
+        stream: record.level().as_str().to_ascii_lowercase(),
+        message: format!("{}: {}", record.target(), record.args()),
+        timestamp: now_millis(),
+      });
+    }
+  }
+
+  fn flush(&self) {
+    self.inner.flush()
+  }
+}
+
+///替代 env_logger::init 网关日志经过 ShippingLogger
+pub fn init_logger(env: env_logger::Env) {
+  let inner = env_logger::Builder::from_env(env).build();
+  let max_level = inner.filter();
+  if log::set_boxed_logger(Box::new(ShippingLogger { inner })).is_ok() {
+    log::set_max_level(max_level);
+  }
+}
+
+///按网关配置为每个日志目的地启动发送任务
+pub fn start() {
+  let sinks = match GatewayConfig::load() {
+    Ok(config) => config.log_sinks,
+    Err(err) => {
+      log::error!("failed to load log sinks: {}", err);
+      return;
+    }
+  };
+  let mut handles = SINKS.write().unwrap();
+  for config in sinks {
+    let (tx, rx) = mpsc::channel(config.max_buffer.max(1));
+    tokio::spawn(run(config.clone(), rx));
+    handles.push(SinkHandle { config, tx });
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn formats_rfc5424() {
+    let record = LogRecord {
+      product_code: Some("shop".to_string()),
+      stream: "stderr".to_string(),
+      message: "boom".to_string(),
+      timestamp: 1685620800000,
+    };
+    assert_eq!(
+      syslog_line(&record, 16, "gw1"),
+      "<131>1 2023-06-01T12:00:00.000Z gw1 shop - stderr - boom"
+    );
+  }
+}
@@ -0,0 +1,357 @@
+use crate::config::{data_dir, GatewayConfig, ProductConfig};
+use crate::list_query::ListSpec;
+use crate::log_shipping::{self, LogRecord};
+use crate::tenants;
+use crate::util::now_millis;
+use chrono::Utc;
+use deno_core::error::{custom_error, AnyError};
+use deno_runtime::deno_io::{Stdio, StdioPipe};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+///正在写入的日志文件名 轮转后的文件按时间命名
+pub const CURRENT_LOG: &str = "current.log";
+///读取最近日志时最多读取的字节数
+const TAIL_BYTES: u64 = 64 * 1024;
+
+///日志轮转 gateway.json 中的 logs 对所有产品生效 cool.json 中的 logs 覆盖单个产品
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogRotation {
+  pub max_size_mb: u64,   //当前文件超过这个大小时轮转 0 表示不限
+  pub max_age_hours: u64, //当前文件写入超过这么久时轮转 0 表示不限
+  pub max_files: usize,   //保留的轮转文件数 更早的删除
+  pub compress: bool,     //轮转后的文件用 gzip 压缩
+}
+
+impl Default for LogRotation {
+  fn default() -> Self {
+    Self {
+      max_size_mb: 10,
+      max_age_hours: 24,
+      max_files: 10,
+      compress: true,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+  Stdout,
+  Stderr,
+}
+
+impl Stream {
+  fn as_str(&self) -> &'static str {
+    match self {
+      Self::Stdout => "stdout",
+      Self::Stderr => "stderr",
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogFile {
+  pub name: String,
+  pub size: u64,
+  pub modified: u64,
+  pub compressed: bool,
+  pub current: bool, //正在写入的文件
+}
+
+///日志文件列表的排序 默认最新的在前
+pub const LIST_SPEC: ListSpec = ListSpec {
+  id: "name",
+  sort: &["name", "modified", "size"],
+  default_sort: "-modified",
+  status: None,
+  tag: None,
+  owner: None,
+};
+
+///目录中正在写入的日志 按设置轮转 产品日志的同一产品的所有 runtime 共用 写入时加锁
+pub struct RotatingLog {
+  dir: PathBuf,
+  file: File,
+  size: u64,
+  opened_at: u64,
+  policy: LogRotation,
+}
+
+lazy_static! {
+  static ref WRITERS: Mutex<HashMap<String, Arc<Mutex<RotatingLog>>>> = Mutex::new(HashMap::new());
+}
+
+///产品的日志目录 data/logs/{product_code}
+pub fn log_dir(product_code: &str) -> PathBuf {
+  data_dir().join("logs").join(product_code)
+}
+
+///产品的轮转设置 cool.json 没有配置时使用网关的设置
+fn policy(product_code: &str) -> LogRotation {
+  match ProductConfig::load(product_code).ok().and_then(|c| c.logs) {
+    Some(policy) => policy,
+    None => GatewayConfig::load().map(|c| c.logs).unwrap_or_default(),
+  }
+}
+
+impl RotatingLog {
+  pub fn open(dir: PathBuf, policy: LogRotation) -> std::io::Result<Self> {
+    std::fs::create_dir_all(&dir)?;
+    let file = OpenOptions::new().create(true).append(true).open(dir.join(CURRENT_LOG))?;
+    let meta = file.metadata()?;
+    //网关重启后继续写入已有的文件 按文件的创建时间计算轮转
+    let opened_at = match meta.len() {
+      0 => now_millis(),
+      _ => meta
+        .created()
+        .or_else(|_| meta.modified())
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_else(now_millis),
+    };
+    Ok(Self {
+      dir,
+      file,
+      size: meta.len(),
+      opened_at,
+      policy,
+    })
+  }
+
+  fn should_rotate(&self) -> bool {
+    let too_big = self.policy.max_size_mb > 0 && self.size >= self.policy.max_size_mb * 1024 * 1024;
+    let too_old = self.policy.max_age_hours > 0 && now_millis().saturating_sub(self.opened_at) >= self.policy.max_age_hours * 3600 * 1000;
+    self.size > 0 && (too_big || too_old)
+  }
+
+  ///当前文件改名为按时间命名的文件 然后重新打开 压缩和清理在后台线程执行
+  fn rotate(&mut self) -> std::io::Result<()> {
+    self.file.flush()?;
+    let rotated = self.dir.join(format!("{}.log", Utc::now().format("%Y%m%d-%H%M%S%.3f")));
+    std::fs::rename(self.dir.join(CURRENT_LOG), &rotated)?;
+    *self = Self::open(self.dir.clone(), self.policy.clone())?;
+    let (dir, policy) = (self.dir.clone(), self.policy.clone());
+    std::thread::spawn(move || {
+      if policy.compress {
+        if let Err(err) = compress(&rotated) {
+          log::error!("failed to compress {}: {}", rotated.display(), err);
+        }
+      }
+      if let Err(err) = prune(&dir, policy.max_files) {
+        log::error!("failed to prune logs in {}: {}", dir.display(), err);
+      }
+    });
+    Ok(())
+  }
+
+  ///追加一段内容 返回是否发生了轮转
+  pub fn write(&mut self, text: &str) -> std::io::Result<bool> {
+    self.file.write_all(text.as_bytes())?;
+    self.size += text.len() as u64;
+    if !self.should_rotate() {
+      return Ok(false);
+    }
+    self.rotate()?;
+    Ok(true)
+  }
+}
+
+///压缩为 .log.gz 先写临时文件 完成后再删除原文件
+fn compress(path: &Path) -> std::io::Result<()> {
+  let target = path.with_extension("log.gz");
+  let part = path.with_extension("log.gz.part");
+  let mut encoder = GzEncoder::new(File::create(&part)?, Compression::default());
+  std::io::copy(&mut File::open(path)?, &mut encoder)?;
+  encoder.finish()?.sync_all()?;
+  std::fs::rename(&part, &target)?;
+  std::fs::remove_file(path)
+}
+
+///只保留最新的 max_files 个轮转文件
+fn prune(dir: &Path, max_files: usize) -> std::io::Result<()> {
+  let mut rotated: Vec<PathBuf> = std::fs::read_dir(dir)?
+    .filter_map(|e| e.ok())
+    .map(|e| e.path())
+    .filter(|p| p.file_name().map(|n| is_rotated(&n.to_string_lossy())).unwrap_or(false))
+    .collect();
+  //文件名以时间开头 按名称排序即按时间排序 同一时间的 .log 排在 .log.gz 前面
+  rotated.sort_by_key(|p| p.file_name().map(|n| n.to_string_lossy().replace(".gz", "")).unwrap_or_default());
+  let excess = rotated.len().saturating_sub(max_files);
+  for path in rotated.into_iter().take(excess) {
+    std::fs::remove_file(path)?;
+  }
+  Ok(())
+}
+
+fn is_rotated(name: &str) -> bool {
+  name != CURRENT_LOG && (name.ends_with(".log") || name.ends_with(".log.gz"))
+}
+
+fn writer(product_code: &str) -> std::io::Result<Arc<Mutex<RotatingLog>>> {
+  let mut writers = WRITERS.lock().unwrap();
+  if let Some(writer) = writers.get(product_code) {
+    return Ok(writer.clone());
+  }
+  let writer = Arc::new(Mutex::new(RotatingLog::open(log_dir(product_code), policy(product_code))?));
+  writers.insert(product_code.to_string(), writer.clone());
+  Ok(writer)
+}
+
+///追加一行 每行带上时间和输出流 写入后按设置轮转 同时投递到配置的日志目的地
+pub fn append(product_code: &str, stream: Stream, line: &str) -> std::io::Result<()> {
+  log_shipping::ship(LogRecord {
+    product_code: Some(product_code.to_string()),
+    stream: stream.as_str().to_string(),
+    message: line.to_string(),
+    timestamp: now_millis(),
+  });
+  let writer = writer(product_code)?;
+  let mut log = writer.lock().unwrap();
+  let text = format!("{} {} {}\n", Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"), stream.as_str(), line);
+  if log.write(&text)? {
+    //轮转后按 cool.json 中最新的设置
+    log.policy = policy(product_code);
+  }
+  Ok(())
+}
+
+///正在写入的日志的最后 lines 行 只读取文件末尾的一段
+pub fn tail(product_code: &str, lines: usize) -> std::io::Result<Vec<String>> {
+  tail_file(&log_dir(product_code).join(CURRENT_LOG), TAIL_BYTES, lines)
+}
+
+///文件的最后 lines 行 最多读取末尾的 max_bytes 字节
+pub fn tail_file(path: &Path, max_bytes: u64, lines: usize) -> std::io::Result<Vec<String>> {
+  let mut file = match File::open(path) {
+    Ok(file) => file,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+    Err(err) => return Err(err),
+  };
+  let start = file.metadata()?.len().saturating_sub(max_bytes);
+  file.seek(SeekFrom::Start(start))?;
+  let mut bytes = vec![];
+  file.read_to_end(&mut bytes)?;
+  let text = String::from_utf8_lossy(&bytes);
+  //从文件中间开始读时 第一行可能不完整
+  let all: Vec<&str> = text.lines().skip(if start > 0 { 1 } else { 0 }).collect();
+  Ok(all[all.len().saturating_sub(lines)..].iter().map(|l| l.to_string()).collect())
+}
+
+#[cfg(windows)]
+fn pipe_writer_to_file(writer: os_pipe::PipeWriter) -> File {
+  use std::os::windows::prelude::{FromRawHandle, IntoRawHandle};
+  // SAFETY: 取得 handle 的所有权
+  unsafe { File::from_raw_handle(writer.into_raw_handle()) }
+}
+
+#[cfg(unix)]
+fn pipe_writer_to_file(writer: os_pipe::PipeWriter) -> File {
+  use std::os::unix::io::{FromRawFd, IntoRawFd};
+  // SAFETY: 取得 fd 的所有权
+  unsafe { File::from_raw_fd(writer.into_raw_fd()) }
+}
+
+///管道的读取端在线程中按行写入日志 runtime 结束 写入端全部关闭后线程退出
+fn pipe(product_code: &str, stream: Stream) -> std::io::Result<StdioPipe> {
+  let (reader, writer) = os_pipe::pipe()?;
+  let product_code = product_code.to_string();
+  std::thread::Builder::new()
+    .name(format!("product-{}-{}", product_code, stream.as_str()))
+    .spawn(move || {
+      let mut reader = BufReader::new(reader);
+      let mut line = vec![];
+      loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+          Ok(0) | Err(_) => break,
+          Ok(_) => {
+            let text = String::from_utf8_lossy(&line);
+            if let Err(err) = append(&product_code, stream, text.trim_end_matches(['\r', '\n'])) {
+              log::error!("failed to write {} log: {}", product_code, err);
+            }
+          }
+        }
+      }
+    })?;
+  Ok(StdioPipe::File(pipe_writer_to_file(writer)))
+}
+
+///runtime 的标准输出和错误输出 console 和 Deno.stdout 的内容写入产品日志<br>
+/// 创建管道失败时输出到网关的终端
+pub fn capture(product_code: &str) -> Stdio {
+  match (pipe(product_code, Stream::Stdout), pipe(product_code, Stream::Stderr)) {
+    (Ok(stdout), Ok(stderr)) => Stdio {
+      stdin: StdioPipe::Inherit,
+      stdout,
+      stderr,
+    },
+    (Err(err), _) | (_, Err(err)) => {
+      log::error!("failed to capture {} logs: {}", product_code, err);
+      Stdio::default()
+    }
+  }
+}
+
+///产品的日志文件 正在写入的在前 轮转文件新的在前
+pub fn list(product_code: &str) -> Result<Vec<LogFile>, AnyError> {
+  let entries = match std::fs::read_dir(log_dir(product_code)) {
+    Ok(entries) => entries,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+    Err(err) => return Err(err.into()),
+  };
+  let mut files = vec![];
+  for entry in entries {
+    let entry = entry?;
+    let name = entry.file_name().to_string_lossy().to_string();
+    if name != CURRENT_LOG && !is_rotated(&name) {
+      continue;
+    }
+    let meta = entry.metadata()?;
+    files.push(LogFile {
+      compressed: name.ends_with(".gz"),
+      current: name == CURRENT_LOG,
+      size: meta.len(),
+      modified: meta
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default(),
+      name,
+    });
+  }
+  files.sort_by(|a, b| b.current.cmp(&a.current).then(b.name.cmp(&a.name)));
+  Ok(files)
+}
+
+///日志文件的路径 name 必须是 list 返回的文件名
+pub fn file_path(product_code: &str, name: &str) -> Result<PathBuf, AnyError> {
+  let path = log_dir(product_code).join(name);
+  let valid = tenants::valid_code(product_code) && (name == CURRENT_LOG || is_rotated(name)) && !name.contains(['/', '\\']) && !name.starts_with('.');
+  match valid && path.is_file() {
+    true => Ok(path),
+    false => Err(custom_error("NotFound", format!("log file {} not found", name))),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn recognizes_rotated_files() {
+    assert!(is_rotated("20230601-120000.000.log"));
+    assert!(is_rotated("20230601-120000.000.log.gz"));
+    assert!(!is_rotated(CURRENT_LOG));
+    assert!(!is_rotated("20230601-120000.000.log.gz.part"));
+  }
+}
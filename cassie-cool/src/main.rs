@@ -1,31 +1,85 @@
 use std::{collections::HashMap, sync::Mutex};
 
-use actix_governor::{GovernorConfigBuilder, Governor};
 use actix_web::{middleware, web, App, HttpServer};
 use awc::Client;
-use cassie_cool::{api::api_routers, forward};
+use cassie_cool::config::{GatewayConfig, HTTP_BIND};
+use cassie_cool::rate_limit::RateLimit;
+use cassie_cool::{
+  access_log, admission, anomaly, api::api_routers, api_version, audit_log, auth, bridge, crash, crawler, doctor, encryption, forward, geoip, gitops,
+  graphql, log_shipping, mtls, otel, overload, panics, rate_limit, recycle, replica, retention, sandbox, security_headers, storage, usage,
+  worker_util,
+};
 ///网关入口0
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-  env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+  log_shipping::init_logger(env_logger::Env::new().default_filter_or("info"));
   //在这里写 是所有线程共享
   let file_table: web::Data<Mutex<HashMap<String, String>>> = web::Data::new(Mutex::new(HashMap::new()));
   bannder();
-  let  governor_conf  = GovernorConfigBuilder::default().per_second(2).burst_size(5).finish().unwrap();
-  log::info!("starting main HTTP server at http://127.0.0.1:9999");
-  HttpServer::new(move || {
+  //--doctor 只做自检 不启动网关
+  if std::env::args().any(|arg| arg == "--doctor") {
+    doctor::run_cli().await;
+  }
+  crash::start();
+  encryption::start();
+  storage::start();
+  replica::start();
+  sandbox::start();
+  worker_util::start();
+  geoip::start();
+  usage::start();
+  auth::start();
+  audit_log::start();
+  retention::start();
+  anomaly::start();
+  admission::start();
+  recycle::start();
+  log_shipping::start();
+  access_log::start();
+  otel::start();
+  gitops::start();
+  api_version::start();
+  rate_limit::start();
+  overload::start();
+  security_headers::start();
+  crawler::start();
+  graphql::start();
+  bridge::start();
+  let gateway_config = GatewayConfig::load().map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
+  doctor::startup().await;
+  log::info!("starting main HTTP server at http://{}", HTTP_BIND);
+  let server = HttpServer::new(move || {
     //在这里写  是有问题的  只会在当前线程里有效
     App::new()
-      .wrap(Governor::new(&governor_conf))
+      .wrap(RateLimit)
       .configure(api_routers)
+      .configure(graphql::routes)
       .app_data(file_table.clone())
       .app_data(web::Data::new(Client::default()))
+      .wrap_fn(replica::guard)
+      .wrap_fn(api_version::negotiate)
+      .wrap_fn(panics::guard)
+      .wrap_fn(access_log::record)
       .wrap(middleware::Logger::default())
       .default_service(web::to(forward))
   })
-  .bind(("127.0.0.1", 9999))?
-  .run()
-  .await
+  .on_connect(mtls::on_connect)
+  .bind(HTTP_BIND)?;
+  //配置了证书时同时监听 https 可以要求客户端证书
+  let server = match &gateway_config.tls {
+    Some(tls) => {
+      log::info!("starting main HTTPS server at https://{}", tls.bind);
+      server.bind_rustls_021(tls.bind.as_str(), mtls::server_config(tls)?)?
+    }
+    None => server,
+  };
+  let server = server.run();
+  panics::start(server.handle());
+  server.await?;
+  if panics::restart_requested() {
+    return Err(panics::reexec());
+  }
+  Ok(())
 }
 fn bannder() {
   eprintln!(
@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+
+///判断是否为二进制时检查的字节数
+const SNIFF_LEN: usize = 8192;
+
+///文件的类型信息 编辑器据此决定用文本编辑器还是预览
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMeta {
+  pub size: u64,
+  pub content_type: String,
+  pub binary: bool,             //二进制文件不能按文本读取 对比和修改
+  pub image: Option<ImageInfo>, //可以预览的图片
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageInfo {
+  pub width: u32,
+  pub height: u32,
+}
+
+///含有 NUL 或不是合法 UTF-8 时视为二进制 只检查开头部分
+pub fn is_binary(bytes: &[u8]) -> bool {
+  let head = &bytes[..bytes.len().min(SNIFF_LEN)];
+  if head.contains(&0) {
+    return true;
+  }
+  match std::str::from_utf8(head) {
+    Ok(_) => false,
+    //截断处可能落在多字节字符中间
+    Err(err) => err.error_len().is_some() || head.len() == bytes.len(),
+  }
+}
+
+///文件头 按顺序匹配
+const MAGIC: &[(&[u8], &str)] = &[
+  (b"\x89PNG\r\n\x1a\n", "image/png"),
+  (b"\xff\xd8\xff", "image/jpeg"),
+  (b"GIF87a", "image/gif"),
+  (b"GIF89a", "image/gif"),
+  (b"\x00\x00\x01\x00", "image/x-icon"),
+  (b"%PDF-", "application/pdf"),
+  (b"wOFF", "font/woff"),
+  (b"wOF2", "font/woff2"),
+  (b"\x00\x01\x00\x00", "font/ttf"),
+  (b"OTTO", "font/otf"),
+  (b"\x00asm", "application/wasm"),
+  (b"PK\x03\x04", "application/zip"),
+];
+
+fn sniff(bytes: &[u8]) -> Option<&'static str> {
+  if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+    return Some("image/webp");
+  }
+  MAGIC.iter().find(|(magic, _)| bytes.starts_with(magic)).map(|(_, t)| *t)
+}
+
+fn by_extension(name: &str) -> Option<&'static str> {
+  let ext = name.rsplit_once('.').map(|(_, e)| e.to_ascii_lowercase())?;
+  Some(match ext.as_str() {
+    "js" | "mjs" | "jsx" => "text/javascript",
+    "ts" | "mts" | "tsx" => "text/typescript",
+    "json" => "application/json",
+    "html" | "htm" => "text/html",
+    "css" => "text/css",
+    "md" => "text/markdown",
+    "svg" => "image/svg+xml",
+    "txt" => "text/plain",
+    _ => return None,
+  })
+}
+
+///先按文件头识别 再按扩展名 都不能识别时按是否二进制区分
+pub fn content_type(name: &str, bytes: &[u8]) -> &'static str {
+  sniff(bytes).or_else(|| by_extension(name)).unwrap_or_else(|| match is_binary(bytes) {
+    true => "application/octet-stream",
+    false => "text/plain",
+  })
+}
+
+fn be_u16(bytes: &[u8], at: usize) -> Option<u32> {
+  bytes.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]) as u32)
+}
+
+fn le_u16(bytes: &[u8], at: usize) -> Option<u32> {
+  bytes.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as u32)
+}
+
+fn le_u24(bytes: &[u8], at: usize) -> Option<u32> {
+  bytes.get(at..at + 3).map(|b| u32::from_le_bytes([b[0], b[1], b[2], 0]))
+}
+
+fn jpeg_size(bytes: &[u8]) -> Option<(u32, u32)> {
+  let mut at = 2;
+  while at + 4 <= bytes.len() {
+    if bytes[at] != 0xff {
+      return None;
+    }
+    let marker = bytes[at + 1];
+    //SOF0 到 SOF15 中除了 DHT DAC JPG 都带有图片尺寸
+    if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+      return Some((be_u16(bytes, at + 7)?, be_u16(bytes, at + 5)?));
+    }
+    at += 2 + be_u16(bytes, at + 2)? as usize;
+  }
+  None
+}
+
+fn webp_size(bytes: &[u8]) -> Option<(u32, u32)> {
+  match bytes.get(12..16)? {
+    b"VP8X" => Some((le_u24(bytes, 24)? + 1, le_u24(bytes, 27)? + 1)),
+    b"VP8L" => {
+      let b = bytes.get(21..25)?;
+      let bits = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+      Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+    }
+    b"VP8 " => Some((le_u16(bytes, 26)? & 0x3fff, le_u16(bytes, 28)? & 0x3fff)),
+    _ => None,
+  }
+}
+
+///图片的宽高 支持 png jpeg gif webp
+pub fn image_size(bytes: &[u8]) -> Option<ImageInfo> {
+  let (width, height) = match sniff(bytes)? {
+    "image/png" => {
+      let b = bytes.get(16..24)?;
+      (u32::from_be_bytes([b[0], b[1], b[2], b[3]]), u32::from_be_bytes([b[4], b[5], b[6], b[7]]))
+    }
+    "image/gif" => (le_u16(bytes, 6)?, le_u16(bytes, 8)?),
+    "image/jpeg" => jpeg_size(bytes)?,
+    "image/webp" => webp_size(bytes)?,
+    _ => return None,
+  };
+  Some(ImageInfo { width, height })
+}
+
+pub fn file_meta(name: &str, bytes: &[u8]) -> FileMeta {
+  let content_type = content_type(name, bytes);
+  FileMeta {
+    size: bytes.len() as u64,
+    content_type: content_type.to_string(),
+    binary: sniff(bytes).is_some() || is_binary(bytes),
+    image: image_size(bytes),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn detects_binary_and_images() {
+    assert!(!is_binary("export const 名称 = 1;".as_bytes()));
+    assert!(is_binary(b"\x00\x01\x02"));
+    assert!(is_binary(b"\xff\xfe\xfd"));
+
+    let mut png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+    png.extend_from_slice(&640u32.to_be_bytes());
+    png.extend_from_slice(&480u32.to_be_bytes());
+    let meta = file_meta("logo.png", &png);
+    assert_eq!(meta.content_type, "image/png");
+    assert!(meta.binary);
+    assert_eq!(meta.image, Some(ImageInfo { width: 640, height: 480 }));
+
+    let gif = b"GIF89a\x20\x00\x10\x00";
+    assert_eq!(image_size(gif), Some(ImageInfo { width: 32, height: 16 }));
+
+    let meta = file_meta("app.ts", b"console.log(1)");
+    assert_eq!(meta.content_type, "text/typescript");
+    assert!(!meta.binary);
+    assert_eq!(meta.image, None);
+  }
+}
@@ -0,0 +1,171 @@
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MetricType {
+  Counter,
+  Gauge,
+}
+
+#[derive(Default)]
+struct Registry {
+  types: BTreeMap<String, (MetricType, String)>, //指标名 -> 类型 说明
+  values: BTreeMap<(String, String), f64>,        //(指标名, 标签) -> 值
+}
+
+lazy_static! {
+  static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+fn escape(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+  labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, escape(v))).collect::<Vec<_>>().join(",")
+}
+
+fn update(kind: MetricType, name: &str, help: &str, labels: &[(&str, &str)], f: impl FnOnce(&mut f64)) {
+  let mut registry = REGISTRY.lock().unwrap();
+  registry.types.entry(name.to_string()).or_insert_with(|| (kind, help.to_string()));
+  f(registry.values.entry((name.to_string(), render_labels(labels))).or_insert(0.0));
+}
+
+///计数器累加
+pub fn inc_counter(name: &str, help: &str, labels: &[(&str, &str)], value: u64) {
+  update(MetricType::Counter, name, help, labels, |v| *v += value as f64);
+}
+
+///设置瞬时值
+pub fn set_gauge(name: &str, help: &str, labels: &[(&str, &str)], value: f64) {
+  update(MetricType::Gauge, name, help, labels, |v| *v = value);
+}
+
+///瞬时值增减 例如当前连接数
+pub fn add_gauge(name: &str, help: &str, labels: &[(&str, &str)], delta: f64) {
+  update(MetricType::Gauge, name, help, labels, |v| *v += delta);
+}
+
+///按 Prometheus 文本格式输出所有指标
+pub fn render() -> String {
+  render_filtered(|_| true)
+}
+
+///只输出某个产品的指标 供租户查看自己的数据
+pub fn render_product(product_code: &str) -> String {
+  let label = format!("product=\"{}\"", escape(product_code));
+  render_filtered(|labels| labels.split(',').any(|l| l == label))
+}
+
+fn render_filtered(filter: impl Fn(&str) -> bool) -> String {
+  let registry = REGISTRY.lock().unwrap();
+  let mut out = String::new();
+  for (name, (kind, help)) in registry.types.iter() {
+    let kind = match kind {
+      MetricType::Counter => "counter",
+      MetricType::Gauge => "gauge",
+    };
+    let values: Vec<_> = registry
+      .values
+      .range((name.clone(), String::new())..)
+      .take_while(|((n, _), _)| n == name)
+      .filter(|((_, labels), _)| filter(labels))
+      .collect();
+    if values.is_empty() {
+      continue;
+    }
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for ((_, labels), value) in values {
+      match labels.is_empty() {
+        true => writeln!(out, "{} {}", name, value),
+        false => writeln!(out, "{}{{{}}} {}", name, labels, value),
+      }
+      .unwrap();
+    }
+  }
+  out
+}
+
+///指标的一个值 供 OTLP 导出使用
+#[derive(Debug, Clone)]
+pub struct MetricPoint {
+  pub name: String,
+  pub help: String,
+  pub counter: bool,
+  pub labels: Vec<(String, String)>,
+  pub value: f64,
+}
+
+///把 render_labels 的结果还原为标签
+fn parse_labels(rendered: &str) -> Vec<(String, String)> {
+  let mut labels = vec![];
+  let mut chars = rendered.chars();
+  loop {
+    let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+    if key.is_empty() || chars.next() != Some('"') {
+      return labels;
+    }
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+      match c {
+        '"' => break,
+        '\\' => match chars.next() {
+          Some('n') => value.push('\n'),
+          Some(c) => value.push(c),
+          None => break,
+        },
+        c => value.push(c),
+      }
+    }
+    labels.push((key, value));
+    chars.next(); //跳过逗号
+  }
+}
+
+///所有指标的当前值
+pub fn snapshot() -> Vec<MetricPoint> {
+  let registry = REGISTRY.lock().unwrap();
+  registry
+    .values
+    .iter()
+    .filter_map(|((name, labels), value)| {
+      let (kind, help) = registry.types.get(name)?;
+      Some(MetricPoint {
+        name: name.clone(),
+        help: help.clone(),
+        counter: *kind == MetricType::Counter,
+        labels: parse_labels(labels),
+        value: *value,
+      })
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn renders_prometheus_text() {
+    inc_counter("test_requests_total", "Requests", &[("product", "demo")], 2);
+    inc_counter("test_requests_total", "Requests", &[("product", "demo")], 1);
+    set_gauge("test_connections", "Connections", &[("product", "a\"b")], 4.0);
+    let text = render();
+    assert!(text.contains("# TYPE test_requests_total counter\n"));
+    assert!(text.contains("test_requests_total{product=\"demo\"} 3\n"));
+    assert!(text.contains("test_connections{product=\"a\\\"b\"} 4\n"));
+
+    let text = render_product("demo");
+    assert!(text.contains("test_requests_total{product=\"demo\"} 3\n"));
+    assert!(!text.contains("test_connections"));
+
+    let labels = parse_labels(&render_labels(&[("product", "a\"b"), ("kind", "x\ny")]));
+    assert_eq!(
+      labels,
+      vec![("product".to_string(), "a\"b".to_string()), ("kind".to_string(), "x\ny".to_string())]
+    );
+  }
+}
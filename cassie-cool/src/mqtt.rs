@@ -0,0 +1,211 @@
+use crate::upstream::Conn;
+use deno_core::error::{generic_error, AnyError};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const CONNECT: u8 = 0x10;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const PINGREQ: [u8; 2] = [0xc0, 0x00];
+const SUBSCRIBE_ID: u16 = 1;
+
+///收到的 PUBLISH QoS 0 的消息没有 packet_id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Publish {
+  pub packet_id: Option<u16>,
+  pub topic: String,
+  pub payload: Vec<u8>,
+  pub dup: bool, //broker 重发的消息
+}
+
+///MQTT 3.1.1 的订阅端 只实现消费消息需要的报文<br>
+/// 使用持久会话 没有 PUBACK 的消息在重连后由 broker 重发
+pub struct MqttSession {
+  conn: Box<dyn Conn>,
+  buf: Vec<u8>,
+  keep_alive: Duration,
+  last_sent: Instant,
+}
+
+fn encode_len(out: &mut Vec<u8>, mut len: usize) {
+  loop {
+    let mut byte = (len % 128) as u8;
+    len /= 128;
+    if len > 0 {
+      byte |= 0x80;
+    }
+    out.push(byte);
+    if len == 0 {
+      return;
+    }
+  }
+}
+
+fn string(out: &mut Vec<u8>, value: &[u8]) {
+  out.extend((value.len() as u16).to_be_bytes());
+  out.extend(value);
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+  let mut out = vec![header];
+  encode_len(&mut out, body.len());
+  out.extend(body);
+  out
+}
+
+///从缓冲中解析一个完整的报文 返回首字节 报文体和占用的字节数 数据不够时返回 None
+fn parse(buf: &[u8]) -> Result<Option<(u8, Vec<u8>, usize)>, AnyError> {
+  let mut len = 0usize;
+  for i in 1..=4 {
+    let Some(byte) = buf.get(i) else {
+      return Ok(None);
+    };
+    len += ((byte & 0x7f) as usize) << (7 * (i - 1));
+    if byte & 0x80 == 0 {
+      let start = i + 1;
+      return Ok(buf.get(start..start + len).map(|body| (buf[0], body.to_vec(), start + len)));
+    }
+  }
+  Err(generic_error("malformed mqtt remaining length"))
+}
+
+fn parse_publish(header: u8, body: &[u8]) -> Result<Publish, AnyError> {
+  let truncated = || generic_error("truncated mqtt publish");
+  let topic_len = u16::from_be_bytes(body.get(..2).ok_or_else(truncated)?.try_into()?) as usize;
+  let topic = std::str::from_utf8(body.get(2..2 + topic_len).ok_or_else(truncated)?)?.to_string();
+  let mut rest = 2 + topic_len;
+  let packet_id = match (header >> 1) & 0x03 {
+    0 => None,
+    _ => {
+      let id = u16::from_be_bytes(body.get(rest..rest + 2).ok_or_else(truncated)?.try_into()?);
+      rest += 2;
+      Some(id)
+    }
+  };
+  Ok(Publish {
+    packet_id,
+    topic,
+    payload: body[rest..].to_vec(),
+    dup: header & 0x08 != 0,
+  })
+}
+
+impl MqttSession {
+  ///发送 CONNECT 等待 CONNACK clean session 为 0
+  pub async fn connect(conn: Box<dyn Conn>, client_id: &str, credentials: Option<(&str, &str)>, keep_alive: Duration) -> Result<Self, AnyError> {
+    let mut session = Self {
+      conn,
+      buf: vec![],
+      keep_alive,
+      last_sent: Instant::now(),
+    };
+    let mut flags = 0u8;
+    let mut payload = vec![];
+    string(&mut payload, client_id.as_bytes());
+    if let Some((username, password)) = credentials {
+      flags |= 0xc0;
+      string(&mut payload, username.as_bytes());
+      string(&mut payload, password.as_bytes());
+    }
+    let mut body = vec![];
+    string(&mut body, b"MQTT");
+    body.extend([4, flags]);
+    body.extend((keep_alive.as_secs() as u16).to_be_bytes());
+    body.extend(payload);
+    session.send(&packet(CONNECT, &body)).await?;
+    let (header, body) = session.read_packet().await?;
+    match (header >> 4, body.get(1)) {
+      (2, Some(0)) => Ok(session),
+      (2, Some(code)) => Err(generic_error(format!("mqtt connection refused with code {}", code))),
+      _ => Err(generic_error("expected mqtt connack")),
+    }
+  }
+
+  ///以 QoS 1 订阅 topic filter
+  pub async fn subscribe(&mut self, filter: &str) -> Result<(), AnyError> {
+    let mut body = SUBSCRIBE_ID.to_be_bytes().to_vec();
+    string(&mut body, filter.as_bytes());
+    body.push(1);
+    self.send(&packet(SUBSCRIBE, &body)).await?;
+    loop {
+      let (header, body) = self.read_packet().await?;
+      if header >> 4 != 9 {
+        continue;
+      }
+      return match body.get(2) {
+        Some(0x80) | None => Err(generic_error(format!("mqtt subscription to {} was rejected", filter))),
+        Some(_) => Ok(()),
+      };
+    }
+  }
+
+  ///下一条消息 期间按 keep_alive 发送 PINGREQ
+  pub async fn next(&mut self) -> Result<Publish, AnyError> {
+    loop {
+      let (header, body) = self.read_packet().await?;
+      if header >> 4 == PUBLISH {
+        return parse_publish(header, &body);
+      }
+    }
+  }
+
+  ///处理完成后确认 broker 之后不再重发
+  pub async fn ack(&mut self, packet_id: u16) -> Result<(), AnyError> {
+    self.send(&packet(PUBACK, &packet_id.to_be_bytes())).await
+  }
+
+  async fn send(&mut self, bytes: &[u8]) -> Result<(), AnyError> {
+    self.conn.write_all(bytes).await?;
+    self.conn.flush().await?;
+    self.last_sent = Instant::now();
+    Ok(())
+  }
+
+  async fn read_packet(&mut self) -> Result<(u8, Vec<u8>), AnyError> {
+    loop {
+      if let Some((header, body, used)) = parse(&self.buf)? {
+        self.buf.drain(..used);
+        return Ok((header, body));
+      }
+      let mut chunk = [0u8; 8192];
+      let ping_at = tokio::time::Instant::from_std(self.last_sent + self.keep_alive / 2);
+      let read = tokio::select! {
+        read = self.conn.read(&mut chunk) => Some(read),
+        _ = tokio::time::sleep_until(ping_at) => None,
+      };
+      match read {
+        Some(read) => match read? {
+          0 => return Err(generic_error("mqtt connection closed by broker")),
+          n => self.buf.extend_from_slice(&chunk[..n]),
+        },
+        None => self.send(&PINGREQ).await?,
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn parses_publish_packets() {
+    let mut body = vec![];
+    string(&mut body, b"orders/created");
+    body.extend(7u16.to_be_bytes());
+    body.extend(vec![b'x'; 200]);
+    //QoS 1 重发
+    let bytes = packet(0x3a, &body);
+    assert_eq!(&bytes[1..3], &[0xda, 0x01]);
+    assert_eq!(parse(&bytes[..bytes.len() - 1]).unwrap(), None);
+    let (header, body, used) = parse(&bytes).unwrap().unwrap();
+    assert_eq!(used, bytes.len());
+    let publish = parse_publish(header, &body).unwrap();
+    assert_eq!(publish.topic, "orders/created");
+    assert_eq!(publish.packet_id, Some(7));
+    assert_eq!(publish.payload.len(), 200);
+    assert!(publish.dup);
+    assert!(parse(&[0x30, 0xff, 0xff, 0xff, 0xff, 0x01]).is_err());
+  }
+}
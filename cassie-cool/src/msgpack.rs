@@ -0,0 +1,313 @@
+use crate::metrics;
+use actix_web::http::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_ENCODING, CONTENT_TYPE};
+use actix_web::http::{Method, StatusCode};
+use deno_core::error::{generic_error, AnyError};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+///嵌套层数上限 避免恶意的请求体耗尽栈
+const MAX_DEPTH: usize = 128;
+
+///JSON 与 MessagePack 互转 cool.json 中的 msgpack<br>
+/// 请求体为 MessagePack 时转成 JSON 交给 runtime 客户端 Accept 中有 MessagePack 时把 runtime 的 JSON 响应转成 MessagePack<br>
+/// 只转换长度已知且不超过 max_body_bytes 的响应 其他响应照常返回 JSON
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MsgpackPolicy {
+  pub enabled: bool,
+  pub max_body_bytes: u64,
+}
+
+impl Default for MsgpackPolicy {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      max_body_bytes: 4 * 1024 * 1024,
+    }
+  }
+}
+
+fn media_type(value: Option<&HeaderValue>) -> String {
+  let value = value.and_then(|v| v.to_str().ok()).unwrap_or_default();
+  value.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+fn is_msgpack_type(media_type: &str) -> bool {
+  media_type == MSGPACK_CONTENT_TYPE || media_type == "application/x-msgpack"
+}
+
+///请求体是否需要转成 JSON
+pub fn is_msgpack_request(headers: &HeaderMap, policy: &MsgpackPolicy) -> bool {
+  policy.enabled && is_msgpack_type(&media_type(headers.get(CONTENT_TYPE)))
+}
+
+///客户端是否接受 MessagePack q=0 表示不接受
+pub fn accepts_msgpack(headers: &HeaderMap, policy: &MsgpackPolicy) -> bool {
+  policy.enabled
+    && headers
+      .get_all(ACCEPT)
+      .filter_map(|v| v.to_str().ok())
+      .flat_map(|v| v.split(','))
+      .any(|range| {
+        let mut parts = range.split(';');
+        let media_type = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let refused = parts.any(|p| matches!(p.trim().split_once('='), Some(("q", q)) if q.trim().parse::<f32>().map(|q| q == 0.0).unwrap_or(false)));
+        is_msgpack_type(&media_type) && !refused
+      })
+}
+
+///是否把这个响应转成 MessagePack length 为 runtime 声明的长度
+pub fn applies(method: &Method, status: StatusCode, headers: &HeaderMap, length: Option<u64>, policy: &MsgpackPolicy) -> bool {
+  method != Method::HEAD
+    && status != StatusCode::NO_CONTENT
+    && status != StatusCode::NOT_MODIFIED
+    && !headers.contains_key(CONTENT_ENCODING)
+    && media_type(headers.get(CONTENT_TYPE)) == "application/json"
+    && length.map(|l| l <= policy.max_body_bytes).unwrap_or(false)
+}
+
+pub fn record(product_code: &str, direction: &str) {
+  metrics::inc_counter(
+    "gateway_msgpack_translations_total",
+    "Bodies translated between JSON and MessagePack by the gateway",
+    &[("product", product_code), ("direction", direction)],
+    1,
+  );
+}
+
+fn write_len(out: &mut Vec<u8>, len: usize, fix: u8, fix_max: usize, markers: [u8; 3]) {
+  match len {
+    len if len <= fix_max => out.push(fix | len as u8),
+    len if len <= u8::MAX as usize && markers[0] != 0 => out.extend([markers[0], len as u8]),
+    len if len <= u16::MAX as usize => {
+      out.push(markers[1]);
+      out.extend((len as u16).to_be_bytes());
+    }
+    len => {
+      out.push(markers[2]);
+      out.extend((len as u32).to_be_bytes());
+    }
+  }
+}
+
+fn encode_number(out: &mut Vec<u8>, n: &Number) {
+  if let Some(n) = n.as_u64() {
+    match n {
+      0..=0x7f => out.push(n as u8),
+      n if n <= u8::MAX as u64 => out.extend([0xcc, n as u8]),
+      n if n <= u16::MAX as u64 => {
+        out.push(0xcd);
+        out.extend((n as u16).to_be_bytes());
+      }
+      n if n <= u32::MAX as u64 => {
+        out.push(0xce);
+        out.extend((n as u32).to_be_bytes());
+      }
+      n => {
+        out.push(0xcf);
+        out.extend(n.to_be_bytes());
+      }
+    }
+  } else if let Some(n) = n.as_i64() {
+    match n {
+      -32..=-1 => out.push(n as i8 as u8),
+      n if n >= i8::MIN as i64 => out.extend([0xd0, n as i8 as u8]),
+      n if n >= i16::MIN as i64 => {
+        out.push(0xd1);
+        out.extend((n as i16).to_be_bytes());
+      }
+      n if n >= i32::MIN as i64 => {
+        out.push(0xd2);
+        out.extend((n as i32).to_be_bytes());
+      }
+      n => {
+        out.push(0xd3);
+        out.extend(n.to_be_bytes());
+      }
+    }
+  } else {
+    out.push(0xcb);
+    out.extend(n.as_f64().unwrap_or_default().to_be_bytes());
+  }
+}
+
+fn encode(out: &mut Vec<u8>, value: &Value) {
+  match value {
+    Value::Null => out.push(0xc0),
+    Value::Bool(false) => out.push(0xc2),
+    Value::Bool(true) => out.push(0xc3),
+    Value::Number(n) => encode_number(out, n),
+    Value::String(s) => {
+      write_len(out, s.len(), 0xa0, 31, [0xd9, 0xda, 0xdb]);
+      out.extend(s.as_bytes());
+    }
+    Value::Array(items) => {
+      write_len(out, items.len(), 0x90, 15, [0, 0xdc, 0xdd]);
+      items.iter().for_each(|item| encode(out, item));
+    }
+    Value::Object(map) => {
+      write_len(out, map.len(), 0x80, 15, [0, 0xde, 0xdf]);
+      for (key, value) in map {
+        encode(out, &Value::String(key.clone()));
+        encode(out, value);
+      }
+    }
+  }
+}
+
+///JSON 转成 MessagePack
+pub fn from_json(json: &[u8]) -> Result<Vec<u8>, AnyError> {
+  let value: Value = serde_json::from_slice(json)?;
+  let mut out = Vec::with_capacity(json.len());
+  encode(&mut out, &value);
+  Ok(out)
+}
+
+struct Decoder<'a> {
+  bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+  fn take(&mut self, n: usize) -> Result<&'a [u8], AnyError> {
+    if n > self.bytes.len() {
+      return Err(generic_error("truncated MessagePack data"));
+    }
+    let (head, rest) = self.bytes.split_at(n);
+    self.bytes = rest;
+    Ok(head)
+  }
+
+  fn uint(&mut self, n: usize) -> Result<u64, AnyError> {
+    Ok(self.take(n)?.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64))
+  }
+
+  fn int(&mut self, n: usize) -> Result<i64, AnyError> {
+    let shift = 64 - n as u32 * 8;
+    Ok(((self.uint(n)? << shift) as i64) >> shift)
+  }
+
+  fn string(&mut self, len: usize) -> Result<String, AnyError> {
+    Ok(std::str::from_utf8(self.take(len)?)?.to_string())
+  }
+
+  fn float(value: f64) -> Result<Value, AnyError> {
+    Number::from_f64(value)
+      .map(Value::Number)
+      .ok_or_else(|| generic_error("NaN and Infinity have no JSON form"))
+  }
+
+  fn array(&mut self, len: usize, depth: usize) -> Result<Value, AnyError> {
+    let mut items = Vec::with_capacity(len.min(self.bytes.len()));
+    for _ in 0..len {
+      items.push(self.value(depth + 1)?);
+    }
+    Ok(Value::Array(items))
+  }
+
+  fn map(&mut self, len: usize, depth: usize) -> Result<Value, AnyError> {
+    let mut map = Map::new();
+    for _ in 0..len {
+      let key = match self.value(depth + 1)? {
+        Value::String(key) => key,
+        _ => return Err(generic_error("MessagePack map keys must be strings")),
+      };
+      map.insert(key, self.value(depth + 1)?);
+    }
+    Ok(Value::Object(map))
+  }
+
+  fn value(&mut self, depth: usize) -> Result<Value, AnyError> {
+    if depth > MAX_DEPTH {
+      return Err(generic_error("MessagePack data is nested too deeply"));
+    }
+    let marker = self.take(1)?[0];
+    let value = match marker {
+      0x00..=0x7f => Value::from(marker),
+      0x80..=0x8f => self.map((marker & 0x0f) as usize, depth)?,
+      0x90..=0x9f => self.array((marker & 0x0f) as usize, depth)?,
+      0xa0..=0xbf => Value::String(self.string((marker & 0x1f) as usize)?),
+      0xc0 => Value::Null,
+      0xc2 => Value::Bool(false),
+      0xc3 => Value::Bool(true),
+      0xca => Self::float(f32::from_bits(self.uint(4)? as u32) as f64)?,
+      0xcb => Self::float(f64::from_bits(self.uint(8)?))?,
+      0xcc => Value::from(self.uint(1)?),
+      0xcd => Value::from(self.uint(2)?),
+      0xce => Value::from(self.uint(4)?),
+      0xcf => Value::from(self.uint(8)?),
+      0xd0 => Value::from(self.int(1)?),
+      0xd1 => Value::from(self.int(2)?),
+      0xd2 => Value::from(self.int(4)?),
+      0xd3 => Value::from(self.int(8)?),
+      0xd9 => {
+        let len = self.uint(1)? as usize;
+        Value::String(self.string(len)?)
+      }
+      0xda => {
+        let len = self.uint(2)? as usize;
+        Value::String(self.string(len)?)
+      }
+      0xdb => {
+        let len = self.uint(4)? as usize;
+        Value::String(self.string(len)?)
+      }
+      0xdc => {
+        let len = self.uint(2)? as usize;
+        self.array(len, depth)?
+      }
+      0xdd => {
+        let len = self.uint(4)? as usize;
+        self.array(len, depth)?
+      }
+      0xde => {
+        let len = self.uint(2)? as usize;
+        self.map(len, depth)?
+      }
+      0xdf => {
+        let len = self.uint(4)? as usize;
+        self.map(len, depth)?
+      }
+      0xe0..=0xff => Value::from(marker as i8),
+      _ => return Err(generic_error("MessagePack binary and extension types have no JSON form")),
+    };
+    Ok(value)
+  }
+}
+
+///MessagePack 转成 JSON
+pub fn to_json(msgpack: &[u8]) -> Result<Vec<u8>, AnyError> {
+  let mut decoder = Decoder { bytes: msgpack };
+  let value = decoder.value(0)?;
+  if !decoder.bytes.is_empty() {
+    return Err(generic_error("trailing bytes after MessagePack value"));
+  }
+  Ok(serde_json::to_vec(&value)?)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn round_trips_json_values() {
+    let json = serde_json::json!({
+      "id": 300,
+      "delta": -70000,
+      "small": -5,
+      "price": 9.5,
+      "name": "cassie",
+      "tags": ["a", "b"],
+      "long": "x".repeat(40),
+      "nested": { "ok": true, "none": null },
+    });
+    let bytes = serde_json::to_vec(&json).unwrap();
+    let packed = from_json(&bytes).unwrap();
+    assert!(packed.len() < bytes.len());
+    let back: Value = serde_json::from_slice(&to_json(&packed).unwrap()).unwrap();
+    assert_eq!(back, json);
+    assert_eq!(from_json(b"{\"a\":[1,-1]}").unwrap(), vec![0x81, 0xa1, b'a', 0x92, 0x01, 0xff]);
+    assert!(to_json(&[0x92, 0x01]).is_err());
+    assert!(to_json(&[0xc4, 0x01, 0x00]).is_err());
+  }
+}
@@ -0,0 +1,99 @@
+use crate::config::TlsConfig;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use ring::digest;
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, ServerConfig};
+use rustls::{Certificate, PrivateKey, RootCertStore};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::fs::File;
+use std::io::{self, BufReader};
+use tokio_rustls::server::TlsStream;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+///转发给 worker 的客户端证书主题
+pub const CLIENT_CERT_SUBJECT_HEADER: &str = "x-client-cert-subject";
+///转发给 worker 的客户端证书指纹 sha256 十六进制
+pub const CLIENT_CERT_FINGERPRINT_HEADER: &str = "x-client-cert-fingerprint";
+
+///客户端证书要求 cool.json 中的 mtls
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MtlsPolicy {
+  pub required: bool,
+  pub paths: Vec<String>, //需要客户端证书的路径前缀 为空时所有请求都需要
+}
+
+impl MtlsPolicy {
+  pub fn applies_to(&self, path: &str) -> bool {
+    self.required && (self.paths.is_empty() || self.paths.iter().any(|p| path.starts_with(p.as_str())))
+  }
+}
+
+///已通过 CA 校验的客户端证书 连接建立时放入连接数据
+#[derive(Debug, Clone)]
+pub struct ClientCert {
+  pub subject: String,
+  pub fingerprint: String,
+}
+
+impl ClientCert {
+  pub fn from_der(der: &[u8]) -> Self {
+    let subject = match X509Certificate::from_der(der) {
+      Ok((_, cert)) => cert.subject().to_string(),
+      Err(_) => String::new(),
+    };
+    Self {
+      subject,
+      fingerprint: hex::encode(digest::digest(&digest::SHA256, der)),
+    }
+  }
+}
+
+///HttpServer::on_connect 回调 记录 tls 连接的客户端证书
+pub fn on_connect(conn: &dyn Any, ext: &mut Extensions) {
+  if let Some(tls) = conn.downcast_ref::<TlsStream<TcpStream>>() {
+    let (_, session) = tls.get_ref();
+    if let Some(cert) = session.peer_certificates().and_then(|certs| certs.first()) {
+      ext.insert(ClientCert::from_der(&cert.0));
+    }
+  }
+}
+
+fn invalid_data(err: impl ToString) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
+  let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+  Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> io::Result<PrivateKey> {
+  for item in rustls_pemfile::read_all(&mut BufReader::new(File::open(path)?))? {
+    match item {
+      rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => {
+        return Ok(PrivateKey(key));
+      }
+      _ => {}
+    }
+  }
+  Err(invalid_data(format!("no private key found in {}", path)))
+}
+
+///tls 监听配置 配置了 client_ca 时校验客户端证书<br>
+/// 没有证书的连接仍然可以建立 是否必须由产品的 mtls 配置决定
+pub fn server_config(tls: &TlsConfig) -> io::Result<ServerConfig> {
+  let builder = ServerConfig::builder().with_safe_defaults();
+  let builder = match &tls.client_ca {
+    Some(ca) => {
+      let mut roots = RootCertStore::empty();
+      for cert in load_certs(ca)? {
+        roots.add(&cert).map_err(invalid_data)?;
+      }
+      builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed())
+    }
+    None => builder.with_no_client_auth(),
+  };
+  builder.with_single_cert(load_certs(&tls.cert)?, load_key(&tls.key)?).map_err(invalid_data)
+}
@@ -0,0 +1,293 @@
+use crate::config::{product_entry, ProductConfig};
+use crate::worker_util::{run_tool, tool_flags};
+use awc::Client;
+use deno_core::error::{generic_error, AnyError};
+use deno_runtime::deno_node::is_builtin_node_module;
+use serde::{Deserialize, Serialize};
+use service::args::Flags;
+use service::tools::deps::{collect_dependencies, DependencyInfo, DependencyKind};
+use std::collections::BTreeMap;
+
+const NPM_REGISTRY_URL: &str = "https://registry.npmjs.org";
+///安装时执行的 npm 生命周期脚本
+const INSTALL_SCRIPTS: [&str; 3] = ["preinstall", "install", "postinstall"];
+
+///npm 包安装脚本的处理方式 runtime 安装 npm 包时从不执行这些脚本
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallScripts {
+  #[default]
+  Ignore, //忽略 可以通过接口查看哪些包有安装脚本
+  Deny, //有安装脚本且不在 allow_scripts 中的包阻止生产部署
+}
+
+///Node.js 兼容模式 cool.json 中的 node<br>
+/// 默认关闭 产品代码不能导入 node: 内置模块 npm 包内部不受影响<br>
+/// 从 Node.js 迁移的产品打开后可以少改代码
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NodeCompat {
+  pub enabled: bool,                   //允许导入 node: 内置模块
+  pub globals: bool,                   //注入 process Buffer global setImmediate clearImmediate 需要 enabled
+  pub install_scripts: InstallScripts, //npm 包安装脚本的处理
+  pub allow_scripts: Vec<String>,      //确认过不执行安装脚本也能正常使用的包
+}
+
+impl NodeCompat {
+  pub fn validate(&self) -> Result<(), AnyError> {
+    if self.globals && !self.enabled {
+      return Err(generic_error("node.globals requires node.enabled"));
+    }
+    Ok(())
+  }
+}
+
+///有安装脚本的 npm 包
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptPackage {
+  pub package: String,
+  pub version: Option<String>,
+  pub scripts: BTreeMap<String, String>,
+  pub allowed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstallScriptReport {
+  pub product_code: String,
+  pub packages: Vec<ScriptPackage>,
+  pub blocked: bool,
+}
+
+///在 runtime 线程里调用 按产品配置限制 node: 内置模块和注入 Node.js 全局变量
+pub fn apply(flags: &mut Flags, product_code: &str) {
+  let node = ProductConfig::load(product_code).unwrap_or_default().node;
+  flags.deny_node_builtins = !node.enabled;
+  flags.node_globals = node.enabled && node.globals;
+}
+
+#[derive(Deserialize)]
+struct NpmVersionInfo {
+  #[serde(default)]
+  scripts: BTreeMap<String, String>,
+  #[serde(default)]
+  gypfile: bool,
+  #[serde(default)]
+  binary: Option<serde_json::Value>,
+  #[serde(default)]
+  dependencies: BTreeMap<String, String>,
+}
+
+impl NpmVersionInfo {
+  fn install_scripts(&self) -> BTreeMap<String, String> {
+    self
+      .scripts
+      .iter()
+      .filter(|(name, _)| INSTALL_SCRIPTS.contains(&name.as_str()))
+      .map(|(name, script)| (name.clone(), script.clone()))
+      .collect()
+  }
+}
+
+async fn npm_version_info(client: &Client, dep: &DependencyInfo) -> Result<NpmVersionInfo, AnyError> {
+  let version = dep
+    .version
+    .as_ref()
+    .ok_or_else(|| generic_error(format!("{} has no resolved version", dep.name)))?;
+  let url = format!("{}/{}/{}", NPM_REGISTRY_URL, dep.name.replace('/', "%2F"), version);
+  let mut res = client.get(url).send().await.map_err(|err| generic_error(err.to_string()))?;
+  res.json().limit(8 * 1024 * 1024).await.map_err(|err| generic_error(err.to_string()))
+}
+
+///扫描产品依赖的 npm 包中声明了安装脚本的包
+pub async fn scan_install_scripts(product_code: &str) -> Result<InstallScriptReport, AnyError> {
+  let config = ProductConfig::load(product_code)?.node;
+  let entry = product_entry(product_code);
+  let deps = run_tool(format!("product-{}-install-scripts", product_code), move || async move {
+    collect_dependencies(tool_flags("run", &entry)?).await
+  })
+  .await?;
+  let client = Client::default();
+  let mut packages = vec![];
+  for dep in deps.iter().filter(|d| d.registry == "npm") {
+    let scripts = npm_version_info(&client, dep).await?.install_scripts();
+    if scripts.is_empty() {
+      continue;
+    }
+    packages.push(ScriptPackage {
+      package: dep.name.clone(),
+      version: dep.version.clone(),
+      allowed: config.allow_scripts.contains(&dep.name),
+      scripts,
+    });
+  }
+  let blocked = config.install_scripts == InstallScripts::Deny && packages.iter().any(|p| !p.allowed);
+  Ok(InstallScriptReport {
+    product_code: product_code.to_string(),
+    packages,
+    blocked,
+  })
+}
+
+///生产部署前的检查 拒绝安装脚本时才需要查询 npm
+pub async fn ensure_deployable(product_code: &str) -> Result<(), AnyError> {
+  let config = ProductConfig::load(product_code)?.node;
+  config.validate()?;
+  if config.install_scripts != InstallScripts::Deny {
+    return Ok(());
+  }
+  let report = scan_install_scripts(product_code).await?;
+  if report.blocked {
+    let packages: Vec<String> = report
+      .packages
+      .iter()
+      .filter(|p| !p.allowed)
+      .map(|p| format!("{}@{}", p.package, p.version.as_deref().unwrap_or("?")))
+      .collect();
+    return Err(generic_error(format!("deploy blocked by npm install scripts: {}", packages.join(", "))));
+  }
+  Ok(())
+}
+
+///兼容层中只实现了一部分的内置模块和缺失的部分
+const PARTIAL_BUILTINS: [(&str, &str); 10] = [
+  ("child_process", "runtime 权限限制下不能创建子进程"),
+  ("cluster", "不支持多进程 调用会抛出异常"),
+  ("dgram", "只有部分 UDP 接口"),
+  ("http2", "只支持客户端 没有服务端"),
+  ("inspector", "只有接口定义 没有实现"),
+  ("repl", "只有接口定义 没有实现"),
+  ("tls", "不支持 createSecureContext 等底层接口"),
+  ("v8", "只有堆统计等少量接口"),
+  ("vm", "上下文不隔离"),
+  ("worker_threads", "不支持 resourceLimits 和 transferList"),
+];
+///依赖这些包说明需要编译原生扩展
+const NATIVE_ADDON_PACKAGES: [&str; 5] = ["bindings", "nan", "node-addon-api", "node-gyp-build", "prebuild-install"];
+
+///兼容层的支持程度
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Support {
+  Supported,
+  Partial,
+  Unsupported,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoverageItem {
+  pub name: String,
+  pub version: Option<String>,
+  pub support: Support,
+  pub notes: Vec<String>,
+}
+
+///产品迁移到 runtime 的可行性
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoverageReport {
+  pub product_code: String,
+  pub node_enabled: bool,          //没有打开兼容模式时产品代码不能导入 node: 内置模块
+  pub builtins: Vec<CoverageItem>, //产品代码直接导入的 node: 内置模块
+  pub packages: Vec<CoverageItem>, //npm 包 包括传递依赖
+  pub feasible: bool,              //没有不支持的内置模块和包
+}
+
+///内置模块在兼容层中的支持程度
+pub fn builtin_support(name: &str) -> (Support, Vec<String>) {
+  let name = name.strip_prefix("node:").unwrap_or(name);
+  if !is_builtin_node_module(name) {
+    return (Support::Unsupported, vec!["兼容层中没有这个内置模块".to_string()]);
+  }
+  let base = name.split('/').next().unwrap_or(name);
+  match PARTIAL_BUILTINS.iter().find(|(module, _)| *module == base) {
+    Some((_, note)) => (Support::Partial, vec![note.to_string()]),
+    None => (Support::Supported, vec![]),
+  }
+}
+
+///npm 包的支持程度 原生扩展不能加载 安装脚本不会执行
+fn package_support(info: &NpmVersionInfo) -> (Support, Vec<String>) {
+  let mut notes = vec![];
+  if info.gypfile || info.binary.is_some() {
+    notes.push("包含原生扩展".to_string());
+  }
+  for dep in NATIVE_ADDON_PACKAGES.iter().filter(|d| info.dependencies.contains_key(**d)) {
+    notes.push(format!("依赖原生扩展加载包 {}", dep));
+  }
+  if !notes.is_empty() {
+    return (Support::Unsupported, notes);
+  }
+  let scripts = info.install_scripts();
+  if !scripts.is_empty() {
+    let names: Vec<&str> = scripts.keys().map(|name| name.as_str()).collect();
+    return (Support::Partial, vec![format!("安装脚本不会执行: {}", names.join(", "))]);
+  }
+  (Support::Supported, vec![])
+}
+
+///分析产品模块图中的 node: 内置模块和 npm 包 对照兼容层给出支持程度 部署前评估迁移可行性
+pub async fn coverage(product_code: &str) -> Result<CoverageReport, AnyError> {
+  let config = ProductConfig::load(product_code)?.node;
+  let entry = product_entry(product_code);
+  let deps = run_tool(format!("product-{}-node-coverage", product_code), move || async move {
+    collect_dependencies(tool_flags("run", &entry)?).await
+  })
+  .await?;
+  let client = Client::default();
+  let mut builtins = vec![];
+  let mut packages = vec![];
+  for dep in deps {
+    match dep.kind {
+      DependencyKind::Node => {
+        let (support, notes) = builtin_support(&dep.name);
+        builtins.push(CoverageItem {
+          name: dep.name,
+          version: None,
+          support,
+          notes,
+        });
+      }
+      DependencyKind::Npm => {
+        let (support, notes) = match npm_version_info(&client, &dep).await {
+          Ok(info) => package_support(&info),
+          Err(err) => (Support::Partial, vec![format!("无法获取包信息: {}", err)]),
+        };
+        packages.push(CoverageItem {
+          name: dep.name,
+          version: dep.version,
+          support,
+          notes,
+        });
+      }
+      DependencyKind::Remote => {}
+    }
+  }
+  let feasible = builtins.iter().chain(packages.iter()).all(|item| item.support != Support::Unsupported);
+  Ok(CoverageReport {
+    product_code: product_code.to_string(),
+    node_enabled: config.enabled,
+    builtins,
+    packages,
+    feasible,
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn globals_require_node_builtins() {
+    let config: NodeCompat = serde_json::from_str(r#"{"globals": true, "install_scripts": "deny"}"#).unwrap();
+    assert_eq!(config.install_scripts, InstallScripts::Deny);
+    assert!(config.validate().is_err());
+    let config = NodeCompat { enabled: true, ..config };
+    assert!(config.validate().is_ok());
+  }
+
+  #[test]
+  fn classifies_builtins() {
+    assert_eq!(builtin_support("node:fs/promises").0, Support::Supported);
+    assert_eq!(builtin_support("vm").0, Support::Partial);
+    assert_eq!(builtin_support("node:missing").0, Support::Unsupported);
+  }
+}
@@ -0,0 +1,116 @@
+use crate::config::GatewayConfig;
+use crate::signature;
+use crate::util::now_millis;
+use deno_core::error::{generic_error, AnyError};
+use deno_runtime::deno_fetch::reqwest;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+///投递失败后的重试次数
+const MAX_ATTEMPTS: u32 = 3;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+///告警通知 gateway.json 中的 notifiers<br>
+/// 事件以 json POST 到 url 配置了 secret 时带上 HMAC-SHA256 签名 x-cool-signature: sha256=...
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notifier {
+  pub name: String,
+  pub url: String,
+  #[serde(default)]
+  pub secret: Option<String>,
+  #[serde(default)]
+  pub events: Vec<String>, //只投递这些事件 支持 anomaly.* 前缀匹配 为空时全部投递
+  #[serde(default)]
+  pub products: Vec<String>, //只投递这些产品的事件 为空时全部投递
+}
+
+impl Notifier {
+  fn accepts(&self, notification: &Notification) -> bool {
+    let event_ok = self.events.is_empty()
+      || self.events.iter().any(|e| match e.strip_suffix('*') {
+        Some(prefix) => notification.event.starts_with(prefix),
+        None => *e == notification.event,
+      });
+    let product_ok = self.products.is_empty() || self.products.contains(&notification.product_code);
+    event_ok && product_ok
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+  Info,
+  Warning,
+  Critical,
+}
+
+///投递给通知接收方的事件
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+  pub event: String, //事件类型 例如 anomaly.error_spike
+  pub product_code: String,
+  pub severity: Severity,
+  pub message: String,
+  pub detail: serde_json::Value,
+  pub created_at: u64,
+}
+
+impl Notification {
+  pub fn new(event: &str, product_code: &str, severity: Severity, message: String, detail: serde_json::Value) -> Self {
+    Self {
+      event: event.to_string(),
+      product_code: product_code.to_string(),
+      severity,
+      message,
+      detail,
+      created_at: now_millis(),
+    }
+  }
+}
+
+async fn deliver(notifier: &Notifier, body: &str, event: &str) -> Result<(), AnyError> {
+  let client = reqwest::Client::builder().timeout(DELIVERY_TIMEOUT).build()?;
+  let mut req = client
+    .post(&notifier.url)
+    .header("content-type", "application/json")
+    .header("x-cool-event", event)
+    .body(body.to_string());
+  if let Some(secret) = &notifier.secret {
+    req = req.header("x-cool-signature", format!("sha256={}", signature::sign(secret, body)));
+  }
+  let status = req.send().await?.status();
+  match status.is_success() {
+    true => Ok(()),
+    false => Err(generic_error(format!("responded {}", status))),
+  }
+}
+
+///在后台投递给所有匹配的接收方 失败按指数退避重试 不阻塞调用方
+pub fn notify(notification: Notification) {
+  let notifiers = match GatewayConfig::load() {
+    Ok(config) => config.notifiers,
+    Err(err) => {
+      log::error!("failed to load notifiers: {}", err);
+      return;
+    }
+  };
+  let body = match serde_json::to_string(&notification) {
+    Ok(body) => body,
+    Err(err) => {
+      log::error!("failed to encode notification: {}", err);
+      return;
+    }
+  };
+  for notifier in notifiers.into_iter().filter(|n| n.accepts(&notification)) {
+    let (body, event) = (body.clone(), notification.event.clone());
+    tokio::spawn(async move {
+      for attempt in 1..=MAX_ATTEMPTS {
+        match deliver(&notifier, &body, &event).await {
+          Ok(()) => return,
+          Err(err) if attempt == MAX_ATTEMPTS => log::warn!("notifier {} gave up on {}: {}", notifier.name, event, err),
+          Err(_) => tokio::time::sleep(Duration::from_secs(2u64.pow(attempt))).await,
+        }
+      }
+    });
+  }
+}
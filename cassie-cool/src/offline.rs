@@ -0,0 +1,73 @@
+use crate::audit_log::{self, AuditEvent, AuditKind};
+use crate::config::{product_entry, GatewayConfig, ProductConfig};
+use crate::worker_util::{run_tool, tool_flags};
+use deno_core::error::{generic_error, AnyError};
+use deno_runtime::permissions::set_permission_denied_hook;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use service::args::Flags;
+use service::tools::deps::find_remote_modules;
+
+///离线模式 gateway.json 和 cool.json 中的 offline<br>
+/// 网关开启后产品无法关闭 产品可以单独开启
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OfflineConfig {
+  pub enabled: bool,
+  pub allow_hosts: Vec<String>, //允许访问的主机 host 或 host:port 网关和产品的白名单合并生效
+}
+
+///产品生效的网络白名单 未开启离线模式时返回 None
+pub fn net_allowlist(product_code: &str) -> Option<Vec<String>> {
+  let gateway = GatewayConfig::load().unwrap_or_default().offline;
+  let product = ProductConfig::load(product_code).unwrap_or_default().offline;
+  if !gateway.enabled && !product.enabled {
+    return None;
+  }
+  let mut hosts = gateway.allow_hosts;
+  hosts.extend(product.allow_hosts);
+  hosts.sort();
+  hosts.dedup();
+  Some(hosts)
+}
+
+///在 runtime 线程里调用 离线模式下限制网络并把违规访问记录为审计事件
+pub fn apply(flags: &mut Flags, product_code: &str) {
+  flags.net_allowlist = net_allowlist(product_code);
+  if flags.net_allowlist.is_none() {
+    set_permission_denied_hook(None);
+    return;
+  }
+  let product_code = product_code.to_string();
+  set_permission_denied_hook(Some(Box::new(move |permission, api, target| {
+    let event = AuditEvent::new(
+      AuditKind::OfflineViolation,
+      &product_code,
+      json!({ "permission": permission, "api": api, "target": target }),
+    );
+    if let Err(err) = audit_log::record(&event) {
+      log::error!("failed to record audit event: {}", err);
+    }
+  })));
+}
+
+///离线模式下的部署检查 产品必须完全 vendor 不能依赖白名单以外的远程模块
+pub async fn ensure_vendored(product_code: &str) -> Result<(), AnyError> {
+  let allowlist = match net_allowlist(product_code) {
+    Some(allowlist) => allowlist,
+    None => return Ok(()),
+  };
+  let entry = product_entry(product_code);
+  let remote = run_tool(format!("product-{}-vendored", product_code), move || async move {
+    let mut flags = tool_flags("run", &entry)?;
+    flags.net_allowlist = Some(allowlist);
+    find_remote_modules(flags).await
+  })
+  .await?;
+  if remote.is_empty() {
+    return Ok(());
+  }
+  let event = AuditEvent::new(AuditKind::OfflineViolation, product_code, json!({ "stage": "deploy", "modules": remote }));
+  audit_log::record(&event)?;
+  Err(generic_error(format!("deploy blocked in offline mode, modules not vendored: {}", remote.join(", "))))
+}
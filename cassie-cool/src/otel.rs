@@ -0,0 +1,464 @@
+use crate::config::GatewayConfig;
+use crate::deploy::{self, DeployStatus};
+use crate::metrics::{self, MetricPoint};
+use crate::usage;
+use actix_web::HttpRequest;
+use deno_core::error::{generic_error, AnyError};
+use deno_runtime::deno_fetch::reqwest;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SCOPE_NAME: &str = "cassie-cool";
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OtlpProtocol {
+  Http, //OTLP/HTTP protobuf 发送到 {endpoint}/v1/metrics 和 /v1/traces
+  Grpc, //OTLP/gRPC http 地址使用 h2c
+}
+
+///OTLP 导出 gateway.json 中的 otel<br>
+/// 每个产品是一个 resource 带上 product product.version 和网关实例的属性
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OtelConfig {
+  pub endpoint: Option<String>, //collector 地址 例如 http://collector:4318 为空时不导出
+  pub protocol: OtlpProtocol,
+  pub headers: BTreeMap<String, String>, //例如认证头
+  pub interval_secs: u64,                //导出间隔
+  pub instance: Option<String>,          //service.instance.id 默认使用 HOSTNAME
+  pub traces: bool,                      //为转发的请求记录 span
+  pub sample_ratio: f64,                 //没有上游 traceparent 时的采样比例
+  pub max_spans: usize,                  //两次导出之间缓冲的 span 上限 超出丢弃
+}
+
+impl Default for OtelConfig {
+  fn default() -> Self {
+    Self {
+      endpoint: None,
+      protocol: OtlpProtocol::Http,
+      headers: BTreeMap::new(),
+      interval_secs: 15,
+      instance: None,
+      traces: true,
+      sample_ratio: 1.0,
+      max_spans: 10_000,
+    }
+  }
+}
+
+///一个完成的 span
+#[derive(Debug, Clone)]
+struct SpanData {
+  trace_id: [u8; 16],
+  span_id: [u8; 8],
+  parent_span_id: Option<[u8; 8]>,
+  name: String,
+  product_code: String,
+  start_nanos: u64,
+  end_nanos: u64,
+  attributes: Vec<(String, String)>,
+  status: Option<u16>,
+}
+
+lazy_static! {
+  static ref CONFIG: RwLock<Option<OtelConfig>> = RwLock::new(None);
+  static ref SPANS: Mutex<VecDeque<SpanData>> = Mutex::new(VecDeque::new());
+}
+
+fn now_nanos() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+  let mut out = [0u8; N];
+  out.copy_from_slice(&uuid::Uuid::new_v4().as_bytes()[..N]);
+  out
+}
+
+///解析 W3C traceparent 00-{trace_id}-{parent_id}-{flags}
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8], bool)> {
+  let parts: Vec<&str> = value.trim().split('-').collect();
+  if parts.len() != 4 || parts[0] != "00" {
+    return None;
+  }
+  let trace_id: [u8; 16] = hex::decode(parts[1]).ok()?.try_into().ok()?;
+  let parent_id: [u8; 8] = hex::decode(parts[2]).ok()?.try_into().ok()?;
+  let flags = u8::from_str_radix(parts[3], 16).ok()?;
+  match trace_id == [0; 16] || parent_id == [0; 8] {
+    true => None,
+    false => Some((trace_id, parent_id, flags & 1 == 1)),
+  }
+}
+
+///网关转发请求的 span 结束时放入导出缓冲 没有调用 finish 的按未完成记录
+pub struct ServerSpan {
+  data: SpanData,
+  sampled: bool,
+}
+
+impl ServerSpan {
+  ///转发给 runtime 的 traceparent
+  pub fn traceparent(&self) -> String {
+    format!(
+      "00-{}-{}-{}",
+      hex::encode(self.data.trace_id),
+      hex::encode(self.data.span_id),
+      if self.sampled { "01" } else { "00" }
+    )
+  }
+
+  pub fn finish(mut self, status: u16) {
+    self.data.status = Some(status);
+  }
+}
+
+impl Drop for ServerSpan {
+  fn drop(&mut self) {
+    if !self.sampled {
+      return;
+    }
+    let max_spans = match CONFIG.read().unwrap().as_ref() {
+      Some(config) => config.max_spans,
+      None => return,
+    };
+    self.data.end_nanos = now_nanos();
+    let mut spans = SPANS.lock().unwrap();
+    if spans.len() < max_spans {
+      spans.push_back(self.data.clone());
+    }
+  }
+}
+
+///开始一个请求的 span 没有开启导出或 traces 时返回 None<br>
+/// 请求带有 traceparent 时继续上游的 trace 并沿用它的采样决定
+pub fn start_span(req: &HttpRequest, product_code: &str) -> Option<ServerSpan> {
+  let sample_ratio = match CONFIG.read().unwrap().as_ref() {
+    Some(config) if config.traces => config.sample_ratio,
+    _ => return None,
+  };
+  let upstream = req.headers().get("traceparent").and_then(|v| v.to_str().ok()).and_then(parse_traceparent);
+  let (trace_id, parent_span_id, sampled) = match upstream {
+    Some((trace_id, parent, sampled)) => (trace_id, Some(parent), sampled),
+    None => {
+      let roll = u32::from_le_bytes(random_bytes::<4>()) as f64 / u32::MAX as f64;
+      (random_bytes::<16>(), None, roll < sample_ratio)
+    }
+  };
+  let method = req.method().as_str();
+  Some(ServerSpan {
+    data: SpanData {
+      trace_id,
+      span_id: random_bytes::<8>(),
+      parent_span_id,
+      name: usage::endpoint_label(method, req.uri().path()),
+      product_code: product_code.to_string(),
+      start_nanos: now_nanos(),
+      end_nanos: 0,
+      attributes: vec![
+        ("http.method".to_string(), method.to_string()),
+        ("http.target".to_string(), req.uri().path().to_string()),
+      ],
+      status: None,
+    },
+    sampled,
+  })
+}
+
+///protobuf 编码 只包含 OTLP 用到的字段类型
+#[derive(Default)]
+struct Proto(Vec<u8>);
+
+impl Proto {
+  fn varint(&mut self, mut value: u64) {
+    while value >= 0x80 {
+      self.0.push((value as u8) | 0x80);
+      value >>= 7;
+    }
+    self.0.push(value as u8);
+  }
+
+  fn key(&mut self, field: u32, wire: u8) {
+    self.varint(((field as u64) << 3) | wire as u64);
+  }
+
+  fn uint(&mut self, field: u32, value: u64) {
+    self.key(field, 0);
+    self.varint(value);
+  }
+
+  fn fixed64(&mut self, field: u32, value: u64) {
+    self.key(field, 1);
+    self.0.extend_from_slice(&value.to_le_bytes());
+  }
+
+  fn double(&mut self, field: u32, value: f64) {
+    self.fixed64(field, value.to_bits());
+  }
+
+  fn bytes(&mut self, field: u32, value: &[u8]) {
+    self.key(field, 2);
+    self.varint(value.len() as u64);
+    self.0.extend_from_slice(value);
+  }
+
+  fn string(&mut self, field: u32, value: &str) {
+    self.bytes(field, value.as_bytes());
+  }
+
+  fn message(&mut self, field: u32, f: impl FnOnce(&mut Proto)) {
+    let mut inner = Proto::default();
+    f(&mut inner);
+    self.bytes(field, &inner.0);
+  }
+
+  ///KeyValue { key = 1, value = 2 { string_value = 1 } }
+  fn attribute(&mut self, field: u32, key: &str, value: &str) {
+    self.message(field, |kv| {
+      kv.string(1, key);
+      kv.message(2, |any| any.string(1, value));
+    });
+  }
+
+  fn resource(&mut self, field: u32, attributes: &[(String, String)]) {
+    self.message(field, |resource| {
+      for (key, value) in attributes {
+        resource.attribute(1, key, value);
+      }
+    });
+  }
+
+  fn scope(&mut self, field: u32) {
+    self.message(field, |scope| {
+      scope.string(1, SCOPE_NAME);
+      scope.string(2, env!("CARGO_PKG_VERSION"));
+    });
+  }
+}
+
+///网关实例的 resource 属性 加上产品和它当前的版本
+fn resource_attributes(instance: &str, product_code: Option<&str>, versions: &BTreeMap<String, String>) -> Vec<(String, String)> {
+  let mut attributes = vec![
+    ("service.name".to_string(), SCOPE_NAME.to_string()),
+    ("service.instance.id".to_string(), instance.to_string()),
+    ("service.version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+  ];
+  if let Some(product_code) = product_code {
+    attributes.push(("product".to_string(), product_code.to_string()));
+    if let Some(version) = versions.get(product_code) {
+      attributes.push(("product.version".to_string(), version.clone()));
+    }
+  }
+  attributes
+}
+
+///ExportMetricsServiceRequest 按 product 标签分为不同的 resource 没有 product 标签的属于网关
+fn encode_metrics(points: &[MetricPoint], start_nanos: u64, instance: &str, versions: &BTreeMap<String, String>) -> Vec<u8> {
+  let now = now_nanos();
+  let mut by_product: BTreeMap<Option<String>, BTreeMap<&str, Vec<&MetricPoint>>> = BTreeMap::new();
+  for point in points {
+    let product = point.labels.iter().find(|(k, _)| k == "product").map(|(_, v)| v.clone());
+    by_product.entry(product).or_default().entry(point.name.as_str()).or_default().push(point);
+  }
+  let mut req = Proto::default();
+  for (product, metrics) in by_product {
+    req.message(1, |rm| {
+      rm.resource(1, &resource_attributes(instance, product.as_deref(), versions));
+      rm.message(2, |sm| {
+        sm.scope(1);
+        for (name, points) in metrics {
+          sm.message(2, |metric| {
+            metric.string(1, name);
+            metric.string(2, &points[0].help);
+            let data_points = |data: &mut Proto| {
+              for point in &points {
+                data.message(1, |dp| {
+                  if point.counter {
+                    dp.fixed64(2, start_nanos);
+                  }
+                  dp.fixed64(3, now);
+                  dp.double(4, point.value);
+                  for (key, value) in point.labels.iter().filter(|(k, _)| k != "product") {
+                    dp.attribute(7, key, value);
+                  }
+                });
+              }
+            };
+            match points[0].counter {
+              //Sum 累计值 单调递增
+              true => metric.message(7, |sum| {
+                data_points(sum);
+                sum.uint(2, 2);
+                sum.uint(3, 1);
+              }),
+              false => metric.message(5, data_points),
+            }
+          });
+        }
+      });
+    });
+  }
+  req.0
+}
+
+///ExportTraceServiceRequest 每个产品一个 resource
+fn encode_spans(spans: &[SpanData], instance: &str, versions: &BTreeMap<String, String>) -> Vec<u8> {
+  let mut by_product: BTreeMap<&str, Vec<&SpanData>> = BTreeMap::new();
+  for span in spans {
+    by_product.entry(span.product_code.as_str()).or_default().push(span);
+  }
+  let mut req = Proto::default();
+  for (product_code, spans) in by_product {
+    req.message(1, |rs| {
+      rs.resource(1, &resource_attributes(instance, Some(product_code), versions));
+      rs.message(2, |ss| {
+        ss.scope(1);
+        for span in spans {
+          ss.message(2, |s| {
+            s.bytes(1, &span.trace_id);
+            s.bytes(2, &span.span_id);
+            if let Some(parent) = &span.parent_span_id {
+              s.bytes(4, parent);
+            }
+            s.string(5, &span.name);
+            s.uint(6, 2); //SPAN_KIND_SERVER
+            s.fixed64(7, span.start_nanos);
+            s.fixed64(8, span.end_nanos);
+            for (key, value) in &span.attributes {
+              s.attribute(9, key, value);
+            }
+            if let Some(status) = span.status {
+              s.attribute(9, "http.status_code", &status.to_string());
+            }
+            s.message(15, |st| match span.status {
+              Some(status) if status < 500 => st.uint(3, 1),
+              Some(_) => st.uint(3, 2),
+              None => {
+                st.string(2, "request was not completed");
+                st.uint(3, 2);
+              }
+            });
+          });
+        }
+      });
+    });
+  }
+  req.0
+}
+
+async fn export(config: &OtelConfig, signal: &str, body: Vec<u8>) -> Result<(), AnyError> {
+  let endpoint = config.endpoint.as_deref().unwrap_or_default().trim_end_matches('/');
+  let mut builder = reqwest::Client::builder().timeout(EXPORT_TIMEOUT);
+  let (url, content_type, body) = match config.protocol {
+    OtlpProtocol::Http => (format!("{}/v1/{}", endpoint, signal), "application/x-protobuf", body),
+    OtlpProtocol::Grpc => {
+      if endpoint.starts_with("http://") {
+        builder = builder.http2_prior_knowledge();
+      }
+      let service = match signal {
+        "metrics" => "opentelemetry.proto.collector.metrics.v1.MetricsService",
+        _ => "opentelemetry.proto.collector.trace.v1.TraceService",
+      };
+      //gRPC 消息前缀 1 字节压缩标志和 4 字节长度
+      let mut framed = vec![0u8];
+      framed.extend_from_slice(&(body.len() as u32).to_be_bytes());
+      framed.extend_from_slice(&body);
+      (format!("{}/{}/Export", endpoint, service), "application/grpc", framed)
+    }
+  };
+  let mut req = builder.build()?.post(url).header("content-type", content_type).body(body);
+  if config.protocol == OtlpProtocol::Grpc {
+    req = req.header("te", "trailers");
+  }
+  for (name, value) in &config.headers {
+    req = req.header(name.as_str(), value.as_str());
+  }
+  let res = req.send().await?;
+  let grpc_status = res.headers().get("grpc-status").and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+  match (res.status().is_success(), grpc_status.as_deref()) {
+    (true, None | Some("0")) => Ok(()),
+    (true, Some(code)) => Err(generic_error(format!("grpc status {}", code))),
+    (false, _) => Err(generic_error(format!("responded {}", res.status()))),
+  }
+}
+
+///产品当前线上的版本 取最近一次部署记录
+async fn current_versions(products: impl Iterator<Item = &str>) -> BTreeMap<String, String> {
+  let mut versions = BTreeMap::new();
+  for product_code in products {
+    let record = deploy::read_history(product_code).await.ok().and_then(|h| h.into_iter().last());
+    let version = record.and_then(|r| match r.status {
+      DeployStatus::Deployed => r.version,
+      _ => r.previous_version,
+    });
+    if let Some(version) = version {
+      versions.insert(product_code.to_string(), version);
+    }
+  }
+  versions
+}
+
+async fn export_once(config: &OtelConfig, start_nanos: u64, instance: &str) {
+  let points = metrics::snapshot();
+  let spans: Vec<SpanData> = SPANS.lock().unwrap().drain(..).collect();
+  let mut products: Vec<&str> = points
+    .iter()
+    .flat_map(|p| p.labels.iter().filter(|(k, _)| k == "product").map(|(_, v)| v.as_str()))
+    .chain(spans.iter().map(|s| s.product_code.as_str()))
+    .collect();
+  products.sort_unstable();
+  products.dedup();
+  let versions = current_versions(products.into_iter()).await;
+  if !points.is_empty() {
+    if let Err(err) = export(config, "metrics", encode_metrics(&points, start_nanos, instance, &versions)).await {
+      log::warn!("otlp metrics export failed: {}", err);
+    }
+  }
+  if !spans.is_empty() {
+    if let Err(err) = export(config, "traces", encode_spans(&spans, instance, &versions)).await {
+      log::warn!("otlp traces export dropped {} spans: {}", spans.len(), err);
+    }
+  }
+}
+
+///配置了 endpoint 时按间隔导出指标和 span
+pub fn start() {
+  let config = GatewayConfig::load().map(|c| c.otel).unwrap_or_default();
+  if config.endpoint.is_none() {
+    return;
+  }
+  *CONFIG.write().unwrap() = Some(config.clone());
+  let start_nanos = now_nanos();
+  let instance = config
+    .instance
+    .clone()
+    .or_else(|| std::env::var("HOSTNAME").ok())
+    .unwrap_or_else(|| "cassie-cool".to_string());
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+    interval.tick().await;
+    loop {
+      interval.tick().await;
+      export_once(&config, start_nanos, &instance).await;
+    }
+  });
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn parses_traceparent() {
+    let (trace_id, parent, sampled) = parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+    assert_eq!(hex::encode(trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(hex::encode(parent), "00f067aa0ba902b7");
+    assert!(sampled);
+    assert!(parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+    assert!(parse_traceparent("garbage").is_none());
+  }
+}
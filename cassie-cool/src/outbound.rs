@@ -0,0 +1,112 @@
+use crate::config::{GatewayConfig, ProductConfig};
+use deno_runtime::deno_fetch::{
+  set_connect_options, set_connection_limiter, AddressFamily, ConnectOptions, ConnectionLimiter, ConnectionLimits, LimitPolicy,
+};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+///runtime fetch 建立连接的方式和并发上限 gateway.json 和 cool.json 中的 outbound<br>
+/// 产品没有配置的项使用网关的设置 部署网络的 IPv6 不通时在网关设置 v4_only 或 prefer_v4<br>
+/// hyper 在先尝试的地址族 300ms 内没连上时并行尝试另一个地址族 这个等待时间不能配置<br>
+/// 并发上限按产品计算 同一产品的 runtime 共用 请求从发出到响应体读取完都占用名额
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboundConfig {
+  pub address_family: Option<AddressFamily>, //auto prefer_v4 prefer_v6 v4_only v6_only 默认 auto 按 DNS 返回的顺序
+  pub connect_timeout_ms: Option<u64>,       //建立连接的超时 由解析到的地址平分 一个地址卡住时尽快尝试下一个
+  pub max_connections: Option<usize>,        //同时进行的外部请求上限
+  pub max_per_host: Option<usize>,           //同一主机同时进行的请求上限
+  pub host_limits: BTreeMap<String, usize>,  //指定主机的上限 覆盖 max_per_host 和网关中同一主机的设置
+  pub on_limit: Option<LimitPolicy>,         //queue 排队等待 reject 立即失败 默认 queue
+  pub queue_timeout_ms: Option<u64>,         //排队超过这个时间后失败 不配置时一直等待
+}
+
+lazy_static! {
+  static ref LIMITERS: Mutex<HashMap<String, Arc<ConnectionLimiter>>> = Mutex::new(HashMap::new());
+}
+
+fn resolve(gateway: &OutboundConfig, product: &OutboundConfig) -> ConnectOptions {
+  ConnectOptions {
+    address_family: product.address_family.or(gateway.address_family).unwrap_or_default(),
+    connect_timeout: product.connect_timeout_ms.or(gateway.connect_timeout_ms).map(Duration::from_millis),
+  }
+}
+
+///没有配置任何上限时为 None
+fn resolve_limits(gateway: &OutboundConfig, product: &OutboundConfig) -> Option<ConnectionLimits> {
+  let mut hosts = gateway.host_limits.clone();
+  hosts.extend(product.host_limits.clone());
+  let limits = ConnectionLimits {
+    max_connections: product.max_connections.or(gateway.max_connections),
+    max_per_host: product.max_per_host.or(gateway.max_per_host),
+    hosts,
+    policy: product.on_limit.or(gateway.on_limit).unwrap_or_default(),
+    queue_timeout: product.queue_timeout_ms.or(gateway.queue_timeout_ms).map(Duration::from_millis),
+  };
+  match limits.max_connections.is_some() || limits.max_per_host.is_some() || !limits.hosts.is_empty() {
+    true => Some(limits),
+    false => None,
+  }
+}
+
+///在 runtime 线程里调用 这个线程之后创建的 fetch 客户端使用这些设置<br>
+/// 上限变化后重新创建 已经发出的请求仍占用旧的名额
+pub fn apply(product_code: &str) {
+  let gateway = GatewayConfig::load().map(|c| c.outbound).unwrap_or_default();
+  let product = ProductConfig::load(product_code).map(|c| c.outbound).unwrap_or_default();
+  set_connect_options(resolve(&gateway, &product));
+  let Some(limits) = resolve_limits(&gateway, &product) else {
+    LIMITERS.lock().unwrap().remove(product_code);
+    set_connection_limiter(None);
+    return;
+  };
+  let limiter = {
+    let mut limiters = LIMITERS.lock().unwrap();
+    match limiters.get(product_code) {
+      Some(limiter) if *limiter.limits() == limits => limiter.clone(),
+      _ => {
+        let limiter = Arc::new(ConnectionLimiter::new(limits));
+        limiters.insert(product_code.to_string(), limiter.clone());
+        limiter
+      }
+    }
+  };
+  set_connection_limiter(Some(limiter));
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn product_overrides_gateway_setting() {
+    let gateway = OutboundConfig {
+      address_family: Some(AddressFamily::V4Only),
+      connect_timeout_ms: Some(3000),
+      max_per_host: Some(20),
+      host_limits: BTreeMap::from([("db.internal".to_string(), 5)]),
+      ..Default::default()
+    };
+    let product = OutboundConfig {
+      address_family: Some(AddressFamily::PreferV6),
+      on_limit: Some(LimitPolicy::Reject),
+      host_limits: BTreeMap::from([("db.internal".to_string(), 2)]),
+      ..Default::default()
+    };
+    let options = resolve(&gateway, &product);
+    assert_eq!(options.address_family, AddressFamily::PreferV6);
+    assert_eq!(options.connect_timeout, Some(Duration::from_secs(3)));
+    assert_eq!(resolve(&OutboundConfig::default(), &OutboundConfig::default()), ConnectOptions::default());
+    let limits = resolve_limits(&gateway, &product).unwrap();
+    assert_eq!((limits.max_per_host, limits.policy), (Some(20), LimitPolicy::Reject));
+    assert_eq!(limits.hosts.get("db.internal"), Some(&2));
+    let reject_only = OutboundConfig {
+      on_limit: Some(LimitPolicy::Reject),
+      ..Default::default()
+    };
+    assert_eq!(resolve_limits(&OutboundConfig::default(), &reject_only), None);
+  }
+}
@@ -0,0 +1,316 @@
+use crate::config::GatewayConfig;
+use crate::metrics;
+use crate::notifier::{self, Notification, Severity};
+use crate::util::now_millis;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+
+///内存中保留的过载事件数
+const MAX_EVENTS: usize = 500;
+///事件循环延迟的采样间隔
+const LAG_INTERVAL: Duration = Duration::from_millis(100);
+///最高的拒绝级别 critical 的产品始终不拒绝
+const MAX_LEVEL: u8 = 3;
+
+///产品的优先级 cool.json 中的 priority<br>
+/// 过载时从 low 开始依次拒绝 critical 不拒绝
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+  Low,
+  #[default]
+  Normal,
+  High,
+  Critical,
+}
+
+impl Priority {
+  const ALL: [Priority; 4] = [Self::Low, Self::Normal, Self::High, Self::Critical];
+
+  fn as_str(&self) -> &'static str {
+    match self {
+      Self::Low => "low",
+      Self::Normal => "normal",
+      Self::High => "high",
+      Self::Critical => "critical",
+    }
+  }
+
+  ///拒绝级别达到 level 时是否拒绝
+  fn shed(&self, level: u8) -> bool {
+    (*self as u8) < level
+  }
+}
+
+///过载保护 gateway.json 中的 overload<br>
+/// 负载为转发中的请求数和事件循环延迟相对上限的较大比例 达到 1 时拒绝 low 之后每超过 step 多拒绝一个优先级
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OverloadConfig {
+  pub max_inflight: usize,    //网关转发中的请求数上限 0 表示不按请求数判断
+  pub max_lag_ms: u64,        //网关事件循环的延迟上限 0 表示不按延迟判断
+  pub step: f64,              //负载每多超过这个比例 多拒绝一个优先级
+  pub recover: f64,           //负载降到这一级阈值的这个比例以下才降级 避免来回切换
+  pub check_interval_ms: u64, //重新计算负载的间隔
+  pub retry_after_secs: u64,  //拒绝时的 Retry-After
+}
+
+impl Default for OverloadConfig {
+  fn default() -> Self {
+    Self {
+      max_inflight: 0,
+      max_lag_ms: 0,
+      step: 0.25,
+      recover: 0.8,
+      check_interval_ms: 1000,
+      retry_after_secs: 5,
+    }
+  }
+}
+
+impl OverloadConfig {
+  fn is_enabled(&self) -> bool {
+    self.max_inflight > 0 || self.max_lag_ms > 0
+  }
+
+  fn pressure(&self, inflight: usize, lag_ms: u64) -> f64 {
+    let mut pressure: f64 = 0.0;
+    if self.max_inflight > 0 {
+      pressure = pressure.max(inflight as f64 / self.max_inflight as f64);
+    }
+    if self.max_lag_ms > 0 {
+      pressure = pressure.max(lag_ms as f64 / self.max_lag_ms as f64);
+    }
+    pressure
+  }
+
+  ///进入 level 需要的负载
+  fn threshold(&self, level: u8) -> f64 {
+    1.0 + self.step * level.saturating_sub(1) as f64
+  }
+
+  ///从当前级别出发 负载超过更高一级的阈值时升级 低于这一级阈值的 recover 比例时降级
+  fn level(&self, current: u8, pressure: f64) -> u8 {
+    let mut level = current.min(MAX_LEVEL);
+    while level < MAX_LEVEL && pressure >= self.threshold(level + 1) {
+      level += 1;
+    }
+    while level > 0 && pressure < self.threshold(level) * self.recover {
+      level -= 1;
+    }
+    level
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OverloadEvent {
+  pub level: u8,
+  pub previous: u8,
+  pub shedding: Vec<Priority>, //这一级拒绝的优先级
+  pub inflight: usize,
+  pub lag_ms: u64,
+  pub pressure: f64,
+  pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OverloadReport {
+  pub config: OverloadConfig,
+  pub level: u8,
+  pub shedding: Vec<Priority>,
+  pub inflight: usize,
+  pub lag_ms: u64,
+  pub events: Vec<OverloadEvent>, //最近的事件 新的在前
+}
+
+lazy_static! {
+  static ref CONFIG: RwLock<OverloadConfig> = RwLock::new(OverloadConfig::default());
+  static ref LAGS: Mutex<HashMap<ThreadId, u64>> = Mutex::new(HashMap::new()); //每个 worker 线程最近的事件循环延迟
+  static ref EVENTS: Mutex<VecDeque<OverloadEvent>> = Mutex::new(VecDeque::new());
+}
+static LEVEL: AtomicU8 = AtomicU8::new(0);
+static INFLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+  static SAMPLING: Cell<bool> = Cell::new(false);
+}
+
+///转发中的请求 drop 时减少计数
+pub struct Admitted;
+
+impl Drop for Admitted {
+  fn drop(&mut self) {
+    INFLIGHT.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+fn shedding(level: u8) -> Vec<Priority> {
+  Priority::ALL.into_iter().filter(|p| p.shed(level)).collect()
+}
+
+///在当前 worker 线程的事件循环中定时休眠 实际唤醒比预期晚的时间即为延迟
+async fn sample_lag() {
+  let thread = std::thread::current().id();
+  loop {
+    let started = Instant::now();
+    actix_web::rt::time::sleep(LAG_INTERVAL).await;
+    let lag = started.elapsed().saturating_sub(LAG_INTERVAL).as_millis() as u64;
+    LAGS.lock().unwrap().insert(thread, lag);
+  }
+}
+
+///过载时拒绝低优先级产品的请求 返回 Retry-After 的秒数<br>
+/// 允许时返回转发中的请求 在 worker 线程中第一次调用时开始采样这个线程的事件循环延迟
+pub fn admit(product_code: &str, priority: Priority) -> Result<Admitted, u64> {
+  let (enabled, sample, retry_after_secs) = {
+    let config = CONFIG.read().unwrap();
+    (config.is_enabled(), config.max_lag_ms > 0, config.retry_after_secs)
+  };
+  if enabled && sample && !SAMPLING.with(|s| s.replace(true)) {
+    actix_web::rt::spawn(sample_lag());
+  }
+  if enabled && priority.shed(LEVEL.load(Ordering::Relaxed)) {
+    metrics::inc_counter(
+      "gateway_shed_requests_total",
+      "Requests rejected because the gateway is overloaded",
+      &[("product", product_code), ("priority", priority.as_str())],
+      1,
+    );
+    return Err(retry_after_secs);
+  }
+  INFLIGHT.fetch_add(1, Ordering::Relaxed);
+  Ok(Admitted)
+}
+
+fn raise(event: OverloadEvent) {
+  let shedding: Vec<&str> = event.shedding.iter().map(|p| p.as_str()).collect();
+  let message = match event.level {
+    0 => format!(
+      "gateway recovered, {} requests in flight, {} ms event loop lag",
+      event.inflight, event.lag_ms
+    ),
+    _ => format!(
+      "gateway overloaded, shedding {} priority, {} requests in flight, {} ms event loop lag",
+      shedding.join(", "),
+      event.inflight,
+      event.lag_ms
+    ),
+  };
+  log::warn!("{}", message);
+  metrics::inc_counter(
+    "gateway_overload_changes_total",
+    "Changes of the gateway overload level",
+    &[("level", &event.level.to_string())],
+    1,
+  );
+  let severity = match event.level > event.previous {
+    true => Severity::Warning,
+    false => Severity::Info,
+  };
+  notifier::notify(Notification::new(
+    "gateway.overload",
+    "",
+    severity,
+    message,
+    serde_json::to_value(&event).unwrap_or_default(),
+  ));
+  let mut events = EVENTS.lock().unwrap();
+  if events.len() >= MAX_EVENTS {
+    events.pop_front();
+  }
+  events.push_back(event);
+}
+
+fn current_lag() -> u64 {
+  LAGS.lock().unwrap().values().copied().max().unwrap_or(0)
+}
+
+fn tick(config: &OverloadConfig) {
+  let inflight = INFLIGHT.load(Ordering::Relaxed);
+  let lag_ms = current_lag();
+  let pressure = config.pressure(inflight, lag_ms);
+  let previous = LEVEL.load(Ordering::Relaxed);
+  let level = config.level(previous, pressure);
+  metrics::set_gauge(
+    "gateway_overload_level",
+    "Priority classes currently shed by the gateway",
+    &[],
+    level as f64,
+  );
+  if level == previous {
+    return;
+  }
+  LEVEL.store(level, Ordering::Relaxed);
+  raise(OverloadEvent {
+    level,
+    previous,
+    shedding: shedding(level),
+    inflight,
+    lag_ms,
+    pressure,
+    created_at: now_millis(),
+  });
+}
+
+///当前的负载 拒绝的优先级和最近的级别变化
+pub fn report() -> OverloadReport {
+  let level = LEVEL.load(Ordering::Relaxed);
+  OverloadReport {
+    config: CONFIG.read().unwrap().clone(),
+    level,
+    shedding: shedding(level),
+    inflight: INFLIGHT.load(Ordering::Relaxed),
+    lag_ms: current_lag(),
+    events: EVENTS.lock().unwrap().iter().rev().cloned().collect(),
+  }
+}
+
+///读取过载保护配置 开启时定时计算负载
+pub fn start() {
+  let config = GatewayConfig::load().map(|c| c.overload).unwrap_or_default();
+  *CONFIG.write().unwrap() = config.clone();
+  if !config.is_enabled() {
+    return;
+  }
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_millis(config.check_interval_ms.max(100)));
+    loop {
+      interval.tick().await;
+      tick(&config);
+    }
+  });
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn sheds_lower_priorities_first() {
+    let config = OverloadConfig {
+      max_inflight: 100,
+      max_lag_ms: 200,
+      ..OverloadConfig::default()
+    };
+    assert_eq!(config.level(0, config.pressure(90, 50)), 0);
+    assert_eq!(config.level(0, config.pressure(100, 50)), 1);
+    //延迟和请求数取较大的负载
+    assert_eq!(config.level(0, config.pressure(10, 260)), 2);
+    assert_eq!(config.level(0, config.pressure(200, 0)), 3);
+    //降到阈值的 recover 比例以下才降级
+    assert_eq!(config.level(1, 0.9), 1);
+    assert_eq!(config.level(1, 0.7), 0);
+    assert_eq!(config.level(3, 1.3), 3);
+    assert_eq!(config.level(3, 1.1), 2);
+    assert_eq!(config.level(3, 0.5), 0);
+    assert_eq!(shedding(0), vec![]);
+    assert_eq!(shedding(2), vec![Priority::Low, Priority::Normal]);
+    assert_eq!(shedding(MAX_LEVEL), vec![Priority::Low, Priority::Normal, Priority::High]);
+  }
+}
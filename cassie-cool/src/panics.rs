@@ -0,0 +1,211 @@
+use crate::config::GatewayConfig;
+use crate::list_query::ListSpec;
+use crate::metrics;
+use crate::util::now_millis;
+use actix_web::dev::{ServerHandle, Service, ServiceRequest, ServiceResponse};
+use actix_web::{Error, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use futures_util::FutureExt;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+///请求 id 请求中带有时沿用 否则生成
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+///内存中保留的 panic 记录数
+const MAX_RECORDS: usize = 200;
+
+///请求 id 放在请求的 extensions 里 转发给 runtime 时使用同一个
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+///请求处理中的 panic gateway.json 中的 panics
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PanicConfig {
+  pub restart_threshold: u32, //窗口内 panic 次数达到这个值时重启网关 0 表示不重启
+  pub window_secs: u64,       //统计窗口
+}
+
+impl Default for PanicConfig {
+  fn default() -> Self {
+    Self {
+      restart_threshold: 0,
+      window_secs: 300,
+    }
+  }
+}
+
+///一次 panic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PanicRecord {
+  pub request_id: String,
+  pub method: String,
+  pub path: String,
+  pub message: String,
+  pub created_at: u64,
+}
+
+///panic 列表的排序
+pub const LIST_SPEC: ListSpec = ListSpec {
+  id: "request_id",
+  sort: &["created_at", "path"],
+  default_sort: "-created_at",
+  status: None,
+  tag: None,
+  owner: None,
+};
+
+lazy_static! {
+  static ref CONFIG: Mutex<PanicConfig> = Mutex::new(PanicConfig::default());
+  static ref RECORDS: Mutex<VecDeque<PanicRecord>> = Mutex::new(VecDeque::new());
+  static ref SERVER: Mutex<Option<ServerHandle>> = Mutex::new(None);
+}
+
+static RESTART: AtomicBool = AtomicBool::new(false);
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+  match payload.downcast_ref::<&str>() {
+    Some(s) => s.to_string(),
+    None => payload.downcast_ref::<String>().cloned().unwrap_or_else(|| "unknown panic".to_string()),
+  }
+}
+
+///窗口内的 panic 次数
+fn recent_count(records: &VecDeque<PanicRecord>, window_secs: u64, now: u64) -> usize {
+  records.iter().filter(|r| now.saturating_sub(r.created_at) < window_secs * 1000).count()
+}
+
+fn record(record: PanicRecord) {
+  log::error!(
+    "request {} {} {} panicked: {}",
+    record.request_id,
+    record.method,
+    record.path,
+    record.message
+  );
+  metrics::inc_counter("gateway_panics_total", "Requests that panicked in the gateway", &[], 1);
+  let config = CONFIG.lock().unwrap().clone();
+  let mut records = RECORDS.lock().unwrap();
+  if records.len() >= MAX_RECORDS {
+    records.pop_front();
+  }
+  records.push_back(record);
+  if config.restart_threshold == 0 || recent_count(&records, config.window_secs, now_millis()) < config.restart_threshold as usize {
+    return;
+  }
+  //只触发一次 处理完正在进行的请求后由 main 重新启动
+  if !RESTART.swap(true, Ordering::SeqCst) {
+    log::error!("{} panics within {}s, restarting gateway", config.restart_threshold, config.window_secs);
+    if let Some(handle) = SERVER.lock().unwrap().clone() {
+      actix_web::rt::spawn(async move { handle.stop(true).await });
+    }
+  }
+}
+
+fn panic_response(req: actix_web::HttpRequest, request_id: &str) -> ServiceResponse {
+  let res = HttpResponse::InternalServerError()
+    .insert_header((REQUEST_ID_HEADER, request_id))
+    .body(format!("internal error, request id {}", request_id));
+  ServiceResponse::new(req, res)
+}
+
+///捕获请求处理中的 panic 返回带请求 id 的 500 不影响 actix 的工作线程<br>
+/// 用法 .wrap_fn(panics::guard)
+pub fn guard<S>(req: ServiceRequest, srv: &S) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+  S::Future: 'static,
+{
+  let request_id = req
+    .headers()
+    .get(REQUEST_ID_HEADER)
+    .and_then(|v| v.to_str().ok())
+    .map(|v| v.to_string())
+    .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+  req.extensions_mut().insert(RequestId(request_id.clone()));
+  let http_req = req.request().clone();
+  let record_panic = move |payload: Box<dyn Any + Send>, http_req: actix_web::HttpRequest| {
+    record(PanicRecord {
+      request_id: request_id.clone(),
+      method: http_req.method().to_string(),
+      path: http_req.uri().path().to_string(),
+      message: panic_message(payload.as_ref()),
+      created_at: now_millis(),
+    });
+    Ok(panic_response(http_req, &request_id))
+  };
+  //服务本身在创建 future 时也可能 panic
+  let fut = match std::panic::catch_unwind(AssertUnwindSafe(|| srv.call(req))) {
+    Ok(fut) => fut,
+    Err(payload) => return Box::pin(async move { record_panic(payload, http_req) }),
+  };
+  Box::pin(async move {
+    match AssertUnwindSafe(fut).catch_unwind().await {
+      Ok(res) => res,
+      Err(payload) => record_panic(payload, http_req),
+    }
+  })
+}
+
+///最近的 panic 新的在前
+pub fn list() -> Vec<PanicRecord> {
+  RECORDS.lock().unwrap().iter().rev().cloned().collect()
+}
+
+///panic 次数超过阈值后 网关停止并需要重新启动
+pub fn restart_requested() -> bool {
+  RESTART.load(Ordering::SeqCst)
+}
+
+///重新执行当前程序 unix 下替换当前进程 其他平台启动新进程后退出
+pub fn reexec() -> std::io::Error {
+  let exe = match std::env::current_exe() {
+    Ok(exe) => exe,
+    Err(err) => return err,
+  };
+  let mut cmd = std::process::Command::new(exe);
+  cmd.args(std::env::args_os().skip(1));
+  #[cfg(unix)]
+  {
+    use std::os::unix::process::CommandExt;
+    cmd.exec()
+  }
+  #[cfg(not(unix))]
+  {
+    match cmd.spawn() {
+      Ok(_) => std::process::exit(0),
+      Err(err) => err,
+    }
+  }
+}
+
+///读取网关配置 server 用于超过阈值时停止服务
+pub fn start(server: ServerHandle) {
+  *CONFIG.lock().unwrap() = GatewayConfig::load().map(|c| c.panics).unwrap_or_default();
+  *SERVER.lock().unwrap() = Some(server);
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn counts_panics_within_window() {
+    let record = |created_at| PanicRecord {
+      request_id: "id".to_string(),
+      method: "GET".to_string(),
+      path: "/".to_string(),
+      message: "boom".to_string(),
+      created_at,
+    };
+    let records: VecDeque<PanicRecord> = vec![record(1_000), record(51_000), record(59_000)].into();
+    assert_eq!(recent_count(&records, 10, 60_000), 2);
+    assert_eq!(recent_count(&records, 60, 60_000), 3);
+    assert_eq!(panic_message(&"boom"), "boom");
+  }
+}
@@ -0,0 +1,444 @@
+use crate::config::product_dir;
+use crate::media;
+use crate::versions::FileChange;
+use deno_core::error::{custom_error, generic_error, AnyError};
+use deno_runtime::at_rest;
+use lazy_static::lazy_static;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+lazy_static! {
+  ///同一时间只应用一个补丁 检查和写入之间文件不会被其他补丁修改
+  static ref PATCH_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+///按文件的编辑操作
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum FileOp {
+  Replace { search: String, replace: String }, //search 必须在文件中恰好出现一次
+  Write { contents: String },                  //写入整个文件 文件不存在时创建
+  Delete,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileEdit {
+  pub path: String,              //相对产品目录 用 / 分隔
+  pub base_hash: Option<String>, //编辑基于的文件内容 sha256 与当前内容不同时视为冲突
+  pub ops: Vec<FileOp>,
+}
+
+///补丁请求 diff 为 unified diff 可以包含多个文件 files 为按文件的编辑操作 两者可以同时使用
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PatchRequest {
+  pub diff: Option<String>,
+  pub files: Vec<FileEdit>,
+  pub dry_run: bool, //只检查能否应用 不写入
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PatchedFile {
+  pub path: String,
+  pub change: FileChange,
+  pub hash: Option<String>, //应用后的内容 sha256 删除时为空
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conflict {
+  pub path: String,
+  pub message: String,
+}
+
+///有冲突时所有文件都不会修改
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PatchReport {
+  pub applied: bool,
+  pub files: Vec<PatchedFile>,
+  pub conflicts: Vec<Conflict>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineKind {
+  Context,
+  Remove,
+  Add,
+}
+
+#[derive(Debug, Clone)]
+struct Line {
+  kind: LineKind,
+  text: String,
+  eol: bool, //后面跟着 \ No newline at end of file 时为 false
+}
+
+#[derive(Debug, Clone)]
+struct Hunk {
+  old_start: usize,
+  old_len: usize,
+  lines: Vec<Line>,
+}
+
+///unified diff 中的一个文件 old new 为空表示 /dev/null
+#[derive(Debug, Clone)]
+struct FilePatch {
+  old: Option<String>,
+  new: Option<String>,
+  hunks: Vec<Hunk>,
+}
+
+fn diff_path(value: &str) -> Option<String> {
+  let value = value.split('\t').next().unwrap_or_default().trim();
+  if value == "/dev/null" {
+    return None;
+  }
+  let value = value.strip_prefix("a/").or_else(|| value.strip_prefix("b/")).unwrap_or(value);
+  Some(value.to_string())
+}
+
+///@@ -1,3 +1,4 @@ 长度省略时为 1
+fn hunk_range(header: &str) -> Option<(usize, usize)> {
+  let old = header.strip_prefix("@@ -")?.split(' ').next()?;
+  let (start, len) = old.split_once(',').unwrap_or((old, "1"));
+  Some((start.parse().ok()?, len.parse().ok()?))
+}
+
+fn parse(diff: &str) -> Result<Vec<FilePatch>, AnyError> {
+  let mut patches: Vec<FilePatch> = vec![];
+  let mut old = None;
+  let mut lines = diff.lines().peekable();
+  while let Some(line) = lines.next() {
+    if let Some(path) = line.strip_prefix("--- ") {
+      old = Some(diff_path(path));
+      continue;
+    }
+    if let Some(path) = line.strip_prefix("+++ ") {
+      let old = old.take().ok_or_else(|| generic_error("+++ without ---"))?;
+      patches.push(FilePatch {
+        old,
+        new: diff_path(path),
+        hunks: vec![],
+      });
+      continue;
+    }
+    if !line.starts_with("@@ ") {
+      //diff --git index 等行忽略
+      continue;
+    }
+    let patch = patches.last_mut().ok_or_else(|| generic_error("hunk without file header"))?;
+    let (old_start, old_len) = hunk_range(line).ok_or_else(|| generic_error(format!("invalid hunk header {}", line)))?;
+    let mut hunk = Hunk {
+      old_start,
+      old_len,
+      lines: vec![],
+    };
+    let mut remaining = old_len;
+    while let Some(next) = lines.peek() {
+      let kind = match next.chars().next() {
+        Some(' ') => LineKind::Context,
+        Some('-') if !next.starts_with("--- ") || remaining > 0 => LineKind::Remove,
+        Some('+') if !next.starts_with("+++ ") => LineKind::Add,
+        Some('\\') => {
+          if let Some(last) = hunk.lines.last_mut() {
+            last.eol = false;
+          }
+          lines.next();
+          continue;
+        }
+        //空行是去掉了行尾空格的上下文
+        None if remaining > 0 => LineKind::Context,
+        _ => break,
+      };
+      if kind != LineKind::Add {
+        remaining = remaining.saturating_sub(1);
+      }
+      hunk.lines.push(Line {
+        kind,
+        text: next.get(1..).unwrap_or_default().to_string(),
+        eol: true,
+      });
+      lines.next();
+    }
+    patch.hunks.push(hunk);
+  }
+  if old.is_some() {
+    return Err(generic_error("--- without +++"));
+  }
+  Ok(patches)
+}
+
+fn same_line(original: &str, text: &str) -> bool {
+  original.trim_end_matches(['\n', '\r']) == text
+}
+
+///依次应用 hunk 行号不准时在附近查找上下文 找不到时为冲突
+fn apply_hunks(original: &str, hunks: &[Hunk]) -> Result<String, String> {
+  let lines: Vec<&str> = original.split_inclusive('\n').collect();
+  let mut out = String::new();
+  let mut cursor = 0;
+  for (i, hunk) in hunks.iter().enumerate() {
+    let old: Vec<&str> = hunk.lines.iter().filter(|l| l.kind != LineKind::Add).map(|l| l.text.as_str()).collect();
+    if old.len() != hunk.old_len {
+      return Err(format!("hunk {} has {} old lines, header says {}", i + 1, old.len(), hunk.old_len));
+    }
+    let matches_at = |at: usize| at >= cursor && at + old.len() <= lines.len() && old.iter().enumerate().all(|(k, t)| same_line(lines[at + k], t));
+    //新增文件内容的 hunk 起始行号为 0
+    let expected = match hunk.old_len {
+      0 => hunk.old_start,
+      _ => hunk.old_start.saturating_sub(1),
+    };
+    let at = (0..=lines.len())
+      .flat_map(|offset| [expected.checked_add(offset), expected.checked_sub(offset)])
+      .flatten()
+      .find(|at| matches_at(*at))
+      .ok_or_else(|| format!("hunk {} does not apply at line {}", i + 1, hunk.old_start))?;
+    out.push_str(&lines[cursor..at].concat());
+    let mut k = at;
+    for line in &hunk.lines {
+      match line.kind {
+        LineKind::Context => {
+          out.push_str(lines[k]);
+          k += 1;
+        }
+        LineKind::Remove => k += 1,
+        LineKind::Add => {
+          out.push_str(&line.text);
+          if line.eol {
+            out.push('\n');
+          }
+        }
+      }
+    }
+    cursor = k;
+  }
+  out.push_str(&lines[cursor..].concat());
+  Ok(out)
+}
+
+fn apply_ops(original: &str, ops: &[FileOp]) -> Result<Option<String>, String> {
+  let mut contents = original.to_string();
+  for (i, op) in ops.iter().enumerate() {
+    match op {
+      FileOp::Replace { search, replace } => match contents.matches(search.as_str()).count() {
+        1 => contents = contents.replacen(search.as_str(), replace, 1),
+        0 => return Err(format!("op {}: search text not found", i + 1)),
+        n => return Err(format!("op {}: search text found {} times", i + 1, n)),
+      },
+      FileOp::Write { contents: new } => contents = new.clone(),
+      FileOp::Delete => return Ok(None),
+    }
+  }
+  Ok(Some(contents))
+}
+
+fn sha256(bytes: &[u8]) -> String {
+  hex::encode(digest::digest(&digest::SHA256, bytes))
+}
+
+///补丁中的路径 不能跳出产品目录
+fn resolve(root: &Path, path: &str) -> Result<PathBuf, String> {
+  let relative = Path::new(path);
+  if path.is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+    return Err(format!("invalid path {}", path));
+  }
+  Ok(root.join(relative))
+}
+
+///补丁涉及的文件 原内容和新内容 None 表示不存在
+struct Staged {
+  root: PathBuf,
+  original: BTreeMap<String, Option<Vec<u8>>>,
+  updated: BTreeMap<String, Option<Vec<u8>>>,
+}
+
+impl Staged {
+  ///当前内容 之前的编辑已修改过时返回修改后的内容
+  async fn current(&mut self, path: &str) -> Result<Option<String>, String> {
+    if let Some(contents) = self.updated.get(path) {
+      return Ok(contents.as_ref().map(|c| String::from_utf8_lossy(c).to_string()));
+    }
+    let file = resolve(&self.root, path)?;
+    let bytes = match tokio::fs::read(&file).await {
+      Ok(bytes) => Some(at_rest::decrypt(bytes).map_err(|err| err.to_string())?),
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+      Err(err) => return Err(err.to_string()),
+    };
+    self.original.insert(path.to_string(), bytes.clone());
+    match bytes {
+      Some(bytes) if media::is_binary(&bytes) => Err("binary file can not be patched".to_string()),
+      Some(bytes) => Ok(Some(String::from_utf8(bytes).map_err(|err| err.to_string())?)),
+      None => Ok(None),
+    }
+  }
+
+  fn stage(&mut self, path: &str, contents: Option<String>) {
+    self.updated.insert(path.to_string(), contents.map(String::into_bytes));
+  }
+
+  async fn apply_file_patch(&mut self, patch: &FilePatch) -> Result<(), String> {
+    let path = patch.new.as_ref().or(patch.old.as_ref()).ok_or("both sides are /dev/null")?.clone();
+    let current = self.current(&path).await?;
+    let original = match (&patch.old, current) {
+      (None, Some(_)) => return Err("file already exists".to_string()),
+      (Some(_), None) => return Err("file not found".to_string()),
+      (_, current) => current.unwrap_or_default(),
+    };
+    let contents = apply_hunks(&original, &patch.hunks)?;
+    if patch.new.is_none() {
+      if !contents.is_empty() {
+        return Err("file to delete has more content than the patch".to_string());
+      }
+      self.stage(&path, None);
+      return Ok(());
+    }
+    self.stage(&path, Some(contents));
+    Ok(())
+  }
+
+  async fn apply_edit(&mut self, edit: &FileEdit) -> Result<(), String> {
+    let current = self.current(&edit.path).await?;
+    if let Some(base_hash) = &edit.base_hash {
+      let hash = current.as_ref().map(|c| sha256(c.as_bytes()));
+      if hash.as_ref() != Some(base_hash) {
+        return Err("file changed since base_hash".to_string());
+      }
+    }
+    let contents = apply_ops(&current.unwrap_or_default(), &edit.ops)?;
+    self.stage(&edit.path, contents);
+    Ok(())
+  }
+
+  fn report(&self) -> Vec<PatchedFile> {
+    self
+      .updated
+      .iter()
+      .filter_map(|(path, updated)| {
+        let change = match (self.original.get(path).cloned().flatten(), updated) {
+          (None, Some(_)) => FileChange::Added,
+          (Some(_), None) => FileChange::Removed,
+          (Some(old), Some(new)) if &old != new => FileChange::Modified,
+          _ => return None,
+        };
+        Some(PatchedFile {
+          path: path.clone(),
+          change,
+          hash: updated.as_ref().map(|c| sha256(c)),
+        })
+      })
+      .collect()
+  }
+
+  async fn write(root: &Path, path: &str, contents: &Option<Vec<u8>>) -> Result<(), AnyError> {
+    let file = root.join(path);
+    match contents {
+      Some(contents) => {
+        tokio::fs::create_dir_all(file.parent().unwrap()).await?;
+        tokio::fs::write(&file, at_rest::encrypt(contents)?).await?;
+      }
+      None => {
+        if let Err(err) = tokio::fs::remove_file(&file).await {
+          if err.kind() != std::io::ErrorKind::NotFound {
+            return Err(err.into());
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  ///写入所有修改 中途失败时恢复已写入的文件
+  async fn commit(&self) -> Result<(), AnyError> {
+    let mut written = vec![];
+    for (path, contents) in &self.updated {
+      if let Err(err) = Self::write(&self.root, path, contents).await {
+        for path in written {
+          let _ = Self::write(&self.root, path, &self.original[path]).await;
+        }
+        return Err(err);
+      }
+      written.push(path);
+    }
+    Ok(())
+  }
+}
+
+///应用补丁 所有文件都没有冲突时才写入
+pub async fn apply(product_code: &str, request: &PatchRequest) -> Result<PatchReport, AnyError> {
+  let patches = match &request.diff {
+    Some(diff) => parse(diff)?,
+    None => vec![],
+  };
+  if patches.is_empty() && request.files.is_empty() {
+    return Err(generic_error("empty patch"));
+  }
+  let root = product_dir(product_code);
+  if !root.is_dir() {
+    return Err(custom_error("NotFound", format!("product {} not found", product_code)));
+  }
+  let _lock = PATCH_LOCK.lock().await;
+  let mut staged = Staged {
+    root,
+    original: BTreeMap::new(),
+    updated: BTreeMap::new(),
+  };
+  let mut conflicts = vec![];
+  for patch in &patches {
+    if let Err(message) = staged.apply_file_patch(patch).await {
+      let path = patch.new.clone().or_else(|| patch.old.clone()).unwrap_or_default();
+      conflicts.push(Conflict { path, message });
+    }
+  }
+  for edit in &request.files {
+    if let Err(message) = staged.apply_edit(edit).await {
+      conflicts.push(Conflict {
+        path: edit.path.clone(),
+        message,
+      });
+    }
+  }
+  let files = staged.report();
+  if !conflicts.is_empty() || request.dry_run {
+    return Ok(PatchReport {
+      applied: false,
+      files,
+      conflicts,
+    });
+  }
+  staged.commit().await?;
+  Ok(PatchReport {
+    applied: true,
+    files,
+    conflicts,
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  const DIFF: &str = "--- a/src/main.ts\n+++ b/src/main.ts\n@@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n";
+
+  #[test]
+  fn applies_unified_diff() {
+    let patches = parse(DIFF).unwrap();
+    assert_eq!(patches.len(), 1);
+    assert_eq!(patches[0].new.as_deref(), Some("src/main.ts"));
+    assert_eq!(apply_hunks("a\nb\nc\nd\n", &patches[0].hunks).unwrap(), "a\nb\nC\nd\n");
+    //行号偏移时按上下文查找
+    assert_eq!(apply_hunks("x\ny\na\nb\nc\nd\n", &patches[0].hunks).unwrap(), "x\ny\na\nb\nC\nd\n");
+    assert!(apply_hunks("a\nb\nX\nd\n", &patches[0].hunks).is_err());
+  }
+
+  #[test]
+  fn applies_ops() {
+    let ops = vec![FileOp::Replace {
+      search: "b".to_string(),
+      replace: "B".to_string(),
+    }];
+    assert_eq!(apply_ops("a b c", &ops).unwrap(), Some("a B c".to_string()));
+    assert!(apply_ops("b b", &ops).is_err());
+    assert!(apply_ops("a", &ops).is_err());
+  }
+}
@@ -0,0 +1,401 @@
+use crate::build_cache::{self, CacheStatus};
+use crate::config::{data_dir, product_dir, product_entry, ProductConfig};
+use crate::worker_util::{run_tool, tool_flags};
+use crate::{bench, dep_audit, licenses, size_budget};
+use deno_core::error::{generic_error, AnyError};
+use deno_core::ModuleSpecifier;
+use deno_runtime::deno_fetch::{CassetteConfig, CassetteMode, FetchMocks, MatchRule};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use service::args::{CliOptions, CoverageFlags, DenoSubcommand, FileFlags, Flags, TestFlags};
+use service::factory::CliFactory;
+use service::tools::coverage::{self, CoverageSummary, CoverageThreshold};
+use service::tools::test::{SlowestTests, TestEvent, TestRunListener, TestSummary};
+use service::tools::{lint, test};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+///部署流水线的步骤
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Step {
+  Check,    //类型检查
+  Lint,     //代码规范
+  Test,     //单元测试
+  Bundle,   //打包及体积预算
+  Audit,    //依赖漏洞审计
+  Licenses, //依赖许可证
+  Bench,    //基准测试 与对比版本相比出现性能退化时失败
+}
+
+///步骤配置 required 的步骤失败会阻止部署
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepConfig {
+  pub step: Step,
+  #[serde(default = "default_required")]
+  pub required: bool,
+}
+
+fn default_required() -> bool {
+  true
+}
+
+///录制的响应保存在产品目录下 每个测试文件一个 需要和代码一起提交
+pub const CASSETTE_DIR: &str = "__cassettes__";
+
+///测试中外部请求的录制和回放 cool.json 中的 cassettes<br>
+/// record 时请求真实服务并保存响应 replay 时只使用保存的响应 没有匹配的请求失败
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TestCassettes {
+  pub mode: Option<CassetteMode>,  //为空时不录制也不回放
+  pub match_on: Vec<MatchRule>,    //匹配规则 为空时按 method 和 url 匹配
+  pub redact_headers: Vec<String>, //额外需要隐藏的头 authorization cookie 等默认隐藏
+}
+
+impl TestCassettes {
+  fn config(&self, product_code: &str) -> Option<CassetteConfig> {
+    let mut redact_headers = CassetteConfig::default_redact_headers();
+    redact_headers.extend(self.redact_headers.iter().map(|h| h.to_ascii_lowercase()));
+    Some(CassetteConfig {
+      mode: self.mode?,
+      dir: product_dir(product_code).join(CASSETTE_DIR),
+      match_on: match self.match_on.is_empty() {
+        true => CassetteConfig::default_match_on(),
+        false => self.match_on.clone(),
+      },
+      redact_headers,
+    })
+  }
+}
+
+///流水线配置 cool.json 中的 pipeline
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PipelineConfig {
+  pub steps: Vec<StepConfig>,
+  pub coverage: Option<CoverageThreshold>,  //测试覆盖率下限 配置后测试步骤收集覆盖率 低于下限时失败
+  pub report_slowest: Option<NonZeroUsize>, //测试结果中列出最慢的 N 个测试和测试文件
+  pub isolate_per_test: bool,               //每个测试使用新的 isolate 测试之间不共享全局变量和资源 测试会变慢
+}
+
+impl Default for PipelineConfig {
+  fn default() -> Self {
+    Self {
+      steps: vec![
+        StepConfig { step: Step::Check, required: true },
+        StepConfig { step: Step::Lint, required: false },
+        StepConfig { step: Step::Test, required: true },
+        StepConfig { step: Step::Bundle, required: false },
+      ],
+      coverage: None,
+      report_slowest: None,
+      isolate_per_test: false,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StepStatus {
+  Passed,
+  Failed,
+  Skipped, //前面的必需步骤失败后 后续步骤不再执行
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepResult {
+  pub step: Step,
+  pub required: bool,
+  pub status: StepStatus,
+  pub message: Option<String>,
+  pub duration_ms: u128,
+  #[serde(default)]
+  pub cache: Option<CacheStatus>, //不能缓存的步骤为空
+  #[serde(default)]
+  pub coverage: Option<CoverageSummary>, //配置了覆盖率下限的测试步骤 每个文件和总计的行及分支覆盖
+  #[serde(default)]
+  pub slowest: Option<SlowestTests>, //配置了 report_slowest 的测试步骤 最慢的测试和测试文件
+}
+
+///流水线结果 success 为 false 时不会切换 runtime
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PipelineResult {
+  pub product_code: String,
+  pub success: bool,
+  pub steps: Vec<StepResult>,
+}
+
+///最近一次流水线结果 data/reports/{product_code}/pipeline.json
+pub fn result_path(product_code: &str) -> PathBuf {
+  let mut path = data_dir();
+  path.push("reports");
+  path.push(product_code);
+  path.push("pipeline.json");
+  path
+}
+
+///测试步骤的覆盖率数据 data/reports/{product_code}/coverage 目录下是原始数据 coverage.json 是汇总
+fn coverage_dir(product_code: &str) -> PathBuf {
+  let mut path = data_dir();
+  path.push("reports");
+  path.push(product_code);
+  path.push("coverage");
+  path
+}
+
+fn coverage_summary_path(product_code: &str) -> PathBuf {
+  coverage_dir(product_code).with_extension("json")
+}
+
+///测试步骤最慢的测试 data/reports/{product_code}/slowest.json
+fn slowest_path(product_code: &str) -> PathBuf {
+  result_path(product_code).with_file_name("slowest.json")
+}
+
+///最近一次测试步骤写入的报告 每次运行测试前删除 没有配置时不存在 其他步骤为 None
+fn load_report<T: DeserializeOwned>(step: Step, path: PathBuf) -> Option<T> {
+  if step != Step::Test {
+    return None;
+  }
+  let bytes = std::fs::read(path).ok()?;
+  serde_json::from_slice(&bytes).ok()
+}
+
+///测试运行结束时记录最慢的测试
+struct SlowestListener {
+  n: usize,
+  slowest: Mutex<Option<SlowestTests>>,
+}
+
+impl TestRunListener for SlowestListener {
+  fn started(&self, _specifiers: &[ModuleSpecifier]) {}
+
+  fn event(&self, _event: &TestEvent) {}
+
+  fn finished(&self, summary: &TestSummary, _elapsed: &Duration) {
+    *self.slowest.lock().unwrap() = Some(summary.slowest(self.n));
+  }
+
+  fn failed(&self, _error: &AnyError) {}
+}
+
+async fn run_check(product_code: &str) -> Result<(), AnyError> {
+  let entry = product_entry(product_code);
+  run_tool(format!("product-{}-check", product_code), move || async move {
+    let flags = tool_flags("check", &entry)?;
+    let factory = CliFactory::from_flags(flags).await?;
+    let module_load_preparer = factory.module_load_preparer().await?;
+    module_load_preparer.load_and_type_check_files(&[entry]).await
+  })
+  .await
+}
+
+async fn run_lint(product_code: &str) -> Result<(), AnyError> {
+  let dir = product_dir(product_code).to_string_lossy().to_string();
+  run_tool(format!("product-{}-lint", product_code), move || async move {
+    let flags = tool_flags("lint", &dir)?;
+    let lint_flags = match flags.subcommand.clone() {
+      DenoSubcommand::Lint(lint_flags) => lint_flags,
+      _ => unreachable!(),
+    };
+    let cli_options = CliOptions::from_flags(flags)?;
+    let lint_options = cli_options.resolve_lint_options(lint_flags)?;
+    lint::lint(cli_options, lint_options).await
+  })
+  .await
+}
+
+///产品测试的参数 流水线和编辑器的监听测试共用
+pub fn test_flags(product_code: &str) -> Result<(Flags, TestFlags), AnyError> {
+  let dir = product_dir(product_code).to_string_lossy().to_string();
+  let config = ProductConfig::load(product_code)?;
+  let mut flags = tool_flags("test", &dir)?;
+  //测试中的外部请求使用模拟响应 或者录制和回放
+  flags.fetch_mocks = Some(config.fetch_mocks).filter(|m| *m != FetchMocks::default());
+  flags.fetch_cassettes = config.cassettes.config(product_code);
+  let mut test_flags = match flags.subcommand.clone() {
+    DenoSubcommand::Test(test_flags) => test_flags,
+    _ => unreachable!(),
+  };
+  //没有测试文件时视为通过
+  test_flags.allow_none = true;
+  test_flags.report_slowest = config.pipeline.report_slowest;
+  test_flags.isolate_per_test = config.pipeline.isolate_per_test;
+  Ok((flags, test_flags))
+}
+
+///配置了覆盖率下限时收集覆盖率 测试通过后按下限检查 汇总写入 coverage.json<br>
+/// 配置了 report_slowest 时最慢的测试写入 slowest.json 测试失败时也写入
+async fn run_test(product_code: &str, threshold: Option<CoverageThreshold>) -> Result<(), AnyError> {
+  let code = product_code.to_string();
+  let dir = coverage_dir(product_code);
+  //上次的数据会混入这次的结果
+  if dir.exists() {
+    tokio::fs::remove_dir_all(&dir).await?;
+  }
+  for path in [coverage_summary_path(product_code), slowest_path(product_code)] {
+    if path.exists() {
+      tokio::fs::remove_file(path).await?;
+    }
+  }
+  run_tool(format!("product-{}-test", product_code), move || async move {
+    let (mut flags, test_flags) = test_flags(&code)?;
+    if threshold.is_some() {
+      flags.coverage_dir = Some(dir.to_string_lossy().to_string());
+    }
+    let listener = test_flags.report_slowest.map(|n| {
+      Arc::new(SlowestListener {
+        n: n.get(),
+        slowest: Mutex::new(None),
+      })
+    });
+    let cli_options = CliOptions::from_flags(flags.clone())?;
+    let test_options = cli_options.resolve_test_options(test_flags)?;
+    let result = test::run_tests(cli_options, test_options, listener.clone().map(|l| l as Arc<dyn TestRunListener>)).await;
+    if let Some(slowest) = listener.and_then(|l| l.slowest.lock().unwrap().take()) {
+      std::fs::create_dir_all(slowest_path(&code).parent().unwrap())?;
+      std::fs::write(slowest_path(&code), serde_json::to_vec_pretty(&slowest)?)?;
+    }
+    result?;
+    let Some(threshold) = threshold else {
+      return Ok(());
+    };
+    let coverage_flags = CoverageFlags {
+      files: FileFlags {
+        include: vec![dir],
+        ignore: vec![],
+      },
+      output: None,
+      include: vec![r"^file:".to_string()],
+      exclude: vec![r"test\.(js|mjs|ts|jsx|tsx)$".to_string()],
+      lcov: false,
+    };
+    let summary = coverage::summarize(flags, coverage_flags).await?;
+    std::fs::write(coverage_summary_path(&code), serde_json::to_vec_pretty(&summary)?)?;
+    let violations = summary.check(&threshold);
+    match violations.is_empty() {
+      true => Ok(()),
+      false => Err(generic_error(violations.join("; "))),
+    }
+  })
+  .await
+}
+
+async fn run_step(product_code: &str, step: Step, config: &PipelineConfig) -> Result<(), AnyError> {
+  match step {
+    Step::Check => run_check(product_code).await,
+    Step::Lint => run_lint(product_code).await,
+    Step::Test => run_test(product_code, config.coverage).await,
+    Step::Bundle => {
+      let report = size_budget::analyze_product(product_code).await?;
+      match report.violations.is_empty() {
+        true => Ok(()),
+        false => Err(generic_error(report.violations.join("; "))),
+      }
+    }
+    Step::Audit => {
+      let report = dep_audit::audit_product(product_code).await?;
+      match report.blocked {
+        false => Ok(()),
+        true => Err(generic_error(format!("{} advisories, max severity {:?}", report.findings.len(), report.max_severity))),
+      }
+    }
+    Step::Licenses => {
+      let report = licenses::scan_product(product_code).await?;
+      match report.disallowed.is_empty() {
+        true => Ok(()),
+        false => Err(generic_error(report.disallowed.join(", "))),
+      }
+    }
+    Step::Bench => bench::ensure_no_regression(product_code).await,
+  }
+}
+
+///依次执行产品配置的流水线步骤 必需步骤失败后跳过剩余步骤
+pub async fn run_pipeline(product_code: &str) -> Result<PipelineResult, AnyError> {
+  let config = ProductConfig::load(product_code)?;
+  let code = product_code.to_string();
+  //计算失败时不使用缓存 不影响部署
+  let key = match tokio::task::spawn_blocking(move || build_cache::input_key(&code)).await? {
+    Ok(key) => Some(key),
+    Err(err) => {
+      log::warn!("{} build cache disabled: {}", product_code, err);
+      None
+    }
+  };
+  let mut success = true;
+  let mut steps = vec![];
+  let pipeline = config.pipeline;
+  for &StepConfig { step, required } in &pipeline.steps {
+    if !success {
+      steps.push(StepResult {
+        step,
+        required,
+        status: StepStatus::Skipped,
+        message: None,
+        duration_ms: 0,
+        cache: None,
+        coverage: None,
+        slowest: None,
+      });
+      continue;
+    }
+    let key = key.as_deref().filter(|_| build_cache::cacheable(step));
+    if let Some(cached) = key.and_then(|key| build_cache::lookup(step, key)) {
+      log::info!("{} pipeline step {:?} cached", product_code, step);
+      steps.push(StepResult {
+        step,
+        required,
+        status: StepStatus::Passed,
+        message: cached.message,
+        duration_ms: 0,
+        cache: Some(CacheStatus::Hit),
+        coverage: load_report(step, coverage_summary_path(product_code)),
+        slowest: load_report(step, slowest_path(product_code)),
+      });
+      continue;
+    }
+    let started = Instant::now();
+    let result = run_step(product_code, step, &pipeline).await;
+    let duration_ms = started.elapsed().as_millis();
+    let (status, message) = match result {
+      Ok(_) => {
+        if let Some(key) = key {
+          if let Err(err) = build_cache::store(step, key, product_code, None) {
+            log::warn!("{} failed to cache pipeline step {:?}: {}", product_code, step, err);
+          }
+        }
+        (StepStatus::Passed, None)
+      }
+      Err(err) => {
+        if required {
+          success = false;
+        }
+        (StepStatus::Failed, Some(err.to_string()))
+      }
+    };
+    log::info!("{} pipeline step {:?} {:?} in {}ms", product_code, step, status, duration_ms);
+    steps.push(StepResult {
+      step,
+      required,
+      status,
+      message,
+      duration_ms,
+      cache: key.map(|_| CacheStatus::Miss),
+      coverage: load_report(step, coverage_summary_path(product_code)),
+      slowest: load_report(step, slowest_path(product_code)),
+    });
+  }
+  let result = PipelineResult {
+    product_code: product_code.to_string(),
+    success,
+    steps,
+  };
+  let path = result_path(product_code);
+  tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+  tokio::fs::write(&path, serde_json::to_vec_pretty(&result)?).await?;
+  Ok(result)
+}
@@ -0,0 +1,63 @@
+use actix_web::HttpRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+///预览环境路由 cool.json 中的 preview<br>
+/// 请求头或 cookie 的值命中 instances 时转发到对应的预览产品 其余请求访问当前产品<br>
+/// 例如 {"instances": {"pr-42": "shop-pr-42"}} 带 x-preview: pr-42 的请求由 shop-pr-42 处理
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PreviewRouting {
+  pub header: String,                      //携带预览名称的请求头
+  pub cookie: Option<String>,              //同时从这个 cookie 读取 请求头优先
+  pub instances: BTreeMap<String, String>, //预览名称到预览产品编码
+}
+
+impl Default for PreviewRouting {
+  fn default() -> Self {
+    Self {
+      header: "x-preview".to_string(),
+      cookie: None,
+      instances: BTreeMap::new(),
+    }
+  }
+}
+
+impl PreviewRouting {
+  ///按请求头和 cookie 的值选择预览产品 没有命中时为空
+  pub fn select(&self, header: Option<&str>, cookie: Option<&str>) -> Option<&str> {
+    if self.instances.is_empty() {
+      return None;
+    }
+    header
+      .and_then(|name| self.instances.get(name.trim()))
+      .or_else(|| cookie.and_then(|name| self.instances.get(name.trim())))
+      .map(|p| p.as_str())
+  }
+
+  ///请求要访问的预览产品
+  pub fn route(&self, req: &HttpRequest) -> Option<&str> {
+    let header = req.headers().get(self.header.as_str()).and_then(|v| v.to_str().ok());
+    let cookie = self.cookie.as_ref().and_then(|name| req.cookie(name));
+    self.select(header, cookie.as_ref().map(|c| c.value()))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn selects_preview_instance() {
+    let routing = PreviewRouting {
+      cookie: Some("preview".to_string()),
+      instances: BTreeMap::from([("pr-42".to_string(), "shop-pr-42".to_string())]),
+      ..Default::default()
+    };
+    assert_eq!(routing.select(Some("pr-42"), None), Some("shop-pr-42"));
+    assert_eq!(routing.select(None, Some("pr-42")), Some("shop-pr-42"));
+    assert_eq!(routing.select(Some("pr-7"), Some("pr-42")), Some("shop-pr-42"));
+    assert_eq!(routing.select(Some("pr-7"), None), None);
+    assert_eq!(routing.select(None, None), None);
+  }
+}
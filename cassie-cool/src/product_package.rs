@@ -0,0 +1,321 @@
+use crate::audit_log::{self, AuditEvent, AuditKind};
+use crate::config::{module_pins_path, product_dir, GatewayConfig, ProductConfig, PRODUCT_CONFIG_FILE};
+use crate::deploy::{self, DeployStatus};
+use crate::signature::{sign, verify_with_keys};
+use crate::util::now_millis;
+use crate::versions::{self, CURRENT_VERSION};
+use crate::{secrets, tenants};
+use deno_core::error::{custom_error, generic_error, AnyError};
+use deno_runtime::at_rest;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Component, Path};
+use walkdir::WalkDir;
+
+///包格式版本 不兼容的修改时增加
+const FORMAT: u32 = 1;
+const MANIFEST: &str = "package.json";
+const SIGNATURE: &str = "signature";
+const PINS: &str = "pins.json";
+const CODE_PREFIX: &str = "code/";
+///按顺序查找的 import map 所在文件 相对 runtime.cwd
+const IMPORT_MAP_FILES: [&str; 3] = ["deno.json", "deno.jsonc", "import_map.json"];
+
+///产品包的签名密钥 gateway.json 中的 packages<br>
+/// 导出时用 signing_key 签名 导入时 signing_key 和 trusted_keys 中任一密钥校验通过即可<br>
+/// 在两个环境之间迁移时配置相同的密钥
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PackageConfig {
+  pub signing_key: Option<String>,
+  pub trusted_keys: Vec<String>,
+}
+
+///包内容说明 打包在 package.json 中 签名覆盖整个文件 文件内容通过其中的 sha256 校验
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackageManifest {
+  pub format: u32,
+  pub product_code: String,
+  pub version: String, //导出的代码版本 current 为正在编辑的代码
+  pub description: Option<String>,
+  pub entry: String,
+  pub import_map: Option<String>,      //import map 所在的文件 随代码一起打包
+  pub files: BTreeMap<String, String>, //代码文件和 sha256 代码包含 cool.json
+  pub pins: Option<String>,            //远程模块锁定表的 sha256
+  pub secrets: Vec<String>,            //产品用到的密钥名 密钥值不导出
+  pub exported_at: u64,
+}
+
+///导入 /admin/import 的查询参数
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImportRequest {
+  #[serde(default)]
+  pub target: Option<String>, //导入为哪个产品 默认与导出时相同
+  #[serde(default)]
+  pub owner: Option<String>, //归属的租户 id
+  #[serde(default)]
+  pub replace: bool, //产品已存在时替换代码
+  #[serde(default)]
+  pub deploy: bool, //导入后部署
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportReport {
+  pub source: String, //导出时的产品编码
+  pub target: String,
+  pub source_version: String,
+  pub version: Option<String>, //导入后保存的版本 dry_run 时为空
+  pub files: usize,
+  pub replaced: bool,
+  pub owner: Option<String>,
+  pub deploy: Option<DeployStatus>,
+  pub missing_secrets: Vec<String>, //目标产品还没有设置的密钥
+}
+
+fn config() -> PackageConfig {
+  GatewayConfig::load().map(|c| c.packages).unwrap_or_default()
+}
+
+fn sha256(bytes: &[u8]) -> String {
+  hex::encode(digest::digest(&digest::SHA256, bytes))
+}
+
+fn append(tar: &mut tar::Builder<GzEncoder<Vec<u8>>>, path: &str, bytes: &[u8], mtime: u64) -> Result<(), AnyError> {
+  let mut header = tar::Header::new_gnu();
+  header.set_size(bytes.len() as u64);
+  header.set_mode(0o644);
+  header.set_mtime(mtime / 1000);
+  header.set_cksum();
+  tar.append_data(&mut header, path, bytes)?;
+  Ok(())
+}
+
+///打包代码目录 文件解密后写入 导入时按目标环境的静态加密配置重新加密
+fn build(product_code: &str, version: &str, from: &Path, key: &str) -> Result<Vec<u8>, AnyError> {
+  //入口和工作目录以导出版本中的 cool.json 为准
+  let config: ProductConfig = match std::fs::read(from.join(PRODUCT_CONFIG_FILE)) {
+    Ok(bytes) => serde_json::from_slice(&at_rest::decrypt(bytes)?)?,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => ProductConfig::default(),
+    Err(err) => return Err(err.into()),
+  };
+  let mut manifest = PackageManifest {
+    format: FORMAT,
+    product_code: product_code.to_string(),
+    version: version.to_string(),
+    description: tenants::product_meta(product_code)?.and_then(|m| m.description),
+    entry: config.runtime.entry.clone(),
+    import_map: None,
+    files: BTreeMap::new(),
+    pins: None,
+    secrets: secrets::list_secrets(product_code)?.into_iter().map(|s| s.name).collect(),
+    exported_at: now_millis(),
+  };
+  let mut tar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+  let entries = WalkDir::new(from).sort_by_file_name().into_iter().filter_map(|e| e.ok());
+  for entry in entries.filter(|e| e.file_type().is_file()) {
+    let rel = entry.path().strip_prefix(from).unwrap().to_string_lossy().replace('\\', "/");
+    let bytes = at_rest::decrypt(std::fs::read(entry.path())?)?;
+    append(&mut tar, &format!("{}{}", CODE_PREFIX, rel), &bytes, manifest.exported_at)?;
+    manifest.files.insert(rel, sha256(&bytes));
+  }
+  let cwd = config
+    .runtime
+    .cwd
+    .clone()
+    .map(|cwd| format!("{}/", cwd.trim_end_matches('/')))
+    .unwrap_or_default();
+  manifest.import_map = IMPORT_MAP_FILES
+    .iter()
+    .map(|name| format!("{}{}", cwd, name))
+    .find(|path| manifest.files.contains_key(path));
+  match std::fs::read(module_pins_path(product_code)) {
+    Ok(pins) => {
+      append(&mut tar, PINS, &pins, manifest.exported_at)?;
+      manifest.pins = Some(sha256(&pins));
+    }
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+    Err(err) => return Err(err.into()),
+  }
+  let json = serde_json::to_vec_pretty(&manifest)?;
+  append(&mut tar, MANIFEST, &json, manifest.exported_at)?;
+  append(
+    &mut tar,
+    SIGNATURE,
+    sign(key, &String::from_utf8_lossy(&json)).as_bytes(),
+    manifest.exported_at,
+  )?;
+  Ok(tar.into_inner()?.finish()?)
+}
+
+///导出产品为签名的包 返回文件名和内容 version 默认最新部署的版本
+pub async fn export(product_code: &str, version: Option<String>) -> Result<(String, Vec<u8>), AnyError> {
+  if !product_dir(product_code).is_dir() {
+    return Err(custom_error("NotFound", format!("product {} not found", product_code)));
+  }
+  let key = config().signing_key.ok_or_else(|| generic_error("package signing key not configured"))?;
+  let version = match version {
+    Some(version) => version,
+    None => versions::latest_version(product_code)?.unwrap_or_else(|| CURRENT_VERSION.to_string()),
+  };
+  let from = match version.as_str() {
+    CURRENT_VERSION => product_dir(product_code),
+    _ => versions::version_dir(product_code, &version)?,
+  };
+  if !from.is_dir() {
+    return Err(custom_error("NotFound", format!("version {} not found", version)));
+  }
+  let (code, v) = (product_code.to_string(), version.clone());
+  let bytes = tokio::task::spawn_blocking(move || build(&code, &v, &from, &key)).await??;
+  audit_log::record(&AuditEvent::new(
+    AuditKind::ProductExported,
+    product_code,
+    json!({"version": version, "bytes": bytes.len()}),
+  ))?;
+  Ok((format!("{}-{}.cool.tar.gz", product_code, version), bytes))
+}
+
+///包中的路径只能是普通的相对路径 不允许跳出产品目录
+fn safe_path(path: &Path) -> bool {
+  path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+///解包并校验签名和每个文件的 sha256 返回说明和代码文件
+fn open(bytes: &[u8], keys: &[String]) -> Result<(PackageManifest, BTreeMap<String, Vec<u8>>, Option<Vec<u8>>), AnyError> {
+  let mut entries = BTreeMap::new();
+  let mut archive = tar::Archive::new(GzDecoder::new(bytes));
+  for entry in archive.entries()? {
+    let mut entry = entry?;
+    if !entry.header().entry_type().is_file() {
+      continue;
+    }
+    let path = entry.path()?.into_owned();
+    if !safe_path(&path) {
+      return Err(generic_error(format!("invalid path {} in package", path.display())));
+    }
+    let mut content = vec![];
+    entry.read_to_end(&mut content)?;
+    entries.insert(path.to_string_lossy().replace('\\', "/"), content);
+  }
+  let json = entries
+    .remove(MANIFEST)
+    .ok_or_else(|| generic_error("package.json missing from package"))?;
+  let signature = entries.remove(SIGNATURE).ok_or_else(|| generic_error("package is not signed"))?;
+  if !verify_with_keys(keys, &json, &String::from_utf8_lossy(&signature)) {
+    return Err(custom_error("Forbidden", "package signature does not match any trusted key"));
+  }
+  let manifest: PackageManifest = serde_json::from_slice(&json)?;
+  if manifest.format != FORMAT {
+    return Err(generic_error(format!("unsupported package format {}", manifest.format)));
+  }
+  let pins = entries.remove(PINS);
+  if pins.as_deref().map(sha256) != manifest.pins {
+    return Err(generic_error("module pins do not match the package manifest"));
+  }
+  let mut files = BTreeMap::new();
+  for (path, content) in entries {
+    let rel = path
+      .strip_prefix(CODE_PREFIX)
+      .ok_or_else(|| generic_error(format!("unexpected file {} in package", path)))?;
+    if manifest.files.get(rel) != Some(&sha256(&content)) {
+      return Err(generic_error(format!("{} does not match the package manifest", rel)));
+    }
+    files.insert(rel.to_string(), content);
+  }
+  if let Some(missing) = manifest.files.keys().find(|path| !files.contains_key(*path)) {
+    return Err(generic_error(format!("{} missing from package", missing)));
+  }
+  Ok((manifest, files, pins))
+}
+
+///写入代码和锁定表 原有代码整体替换
+fn write(target: &str, files: &BTreeMap<String, Vec<u8>>, pins: Option<&[u8]>) -> Result<(), AnyError> {
+  let dir = product_dir(target);
+  if dir.exists() {
+    std::fs::remove_dir_all(&dir)?;
+  }
+  std::fs::create_dir_all(&dir)?;
+  for (path, content) in files {
+    let to = dir.join(path);
+    std::fs::create_dir_all(to.parent().unwrap())?;
+    std::fs::write(&to, at_rest::encrypt(content)?)?;
+  }
+  match pins {
+    Some(pins) => {
+      std::fs::create_dir_all(module_pins_path(target).parent().unwrap())?;
+      std::fs::write(module_pins_path(target), pins)?;
+    }
+    None => match std::fs::remove_file(module_pins_path(target)) {
+      Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+      _ => {}
+    },
+  }
+  Ok(())
+}
+
+///校验并导入其他网关导出的包 保存为新版本 dry_run 时只校验<br>
+/// 密钥不在包中 需要在目标环境单独设置
+pub async fn import(bytes: Vec<u8>, request: &ImportRequest, dry_run: bool) -> Result<ImportReport, AnyError> {
+  let config = config();
+  let keys: Vec<String> = config.signing_key.into_iter().chain(config.trusted_keys).collect();
+  if keys.is_empty() {
+    return Err(generic_error("no package signing keys configured"));
+  }
+  let (manifest, files, pins) = tokio::task::spawn_blocking(move || open(&bytes, &keys)).await??;
+  let target = request.target.clone().unwrap_or_else(|| manifest.product_code.clone());
+  if !tenants::valid_code(&target) {
+    return Err(generic_error("product code may only contain lowercase letters, digits, - and _"));
+  }
+  let replaced = product_dir(&target).exists();
+  if replaced && !request.replace {
+    return Err(generic_error(format!("product {} already exists", target)));
+  }
+  let existing: Vec<String> = secrets::list_secrets(&target)?.into_iter().map(|s| s.name).collect();
+  let mut report = ImportReport {
+    source: manifest.product_code.clone(),
+    target: target.clone(),
+    source_version: manifest.version.clone(),
+    version: None,
+    files: files.len(),
+    replaced,
+    owner: request.owner.clone(),
+    deploy: None,
+    missing_secrets: manifest.secrets.iter().filter(|s| !existing.contains(s)).cloned().collect(),
+  };
+  if dry_run {
+    return Ok(report);
+  }
+  let code = target.clone();
+  tokio::task::spawn_blocking(move || write(&code, &files, pins.as_deref())).await??;
+  report.version = Some(versions::snapshot(&target).await?);
+  if let Some(owner) = &request.owner {
+    tenants::assign_owner(&target, owner, false)?;
+  }
+  audit_log::record(&AuditEvent::new(
+    AuditKind::ProductImported,
+    &target,
+    json!({"source": report.source, "source_version": report.source_version, "version": report.version, "replaced": replaced}),
+  ))?;
+  if request.deploy {
+    report.deploy = Some(deploy::deploy_product(&target, Some("import".to_string())).await?.status);
+  }
+  Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn rejects_unsafe_paths() {
+    assert!(safe_path(Path::new("code/src/app.ts")));
+    assert!(!safe_path(Path::new("code/../../etc/passwd")));
+    assert!(!safe_path(Path::new("/etc/passwd")));
+    assert!(!safe_path(Path::new("./code/app.ts")));
+  }
+}
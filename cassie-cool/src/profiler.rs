@@ -0,0 +1,201 @@
+use crate::artifacts;
+use crate::util::now_millis;
+use crate::worker_util::{ScriptWorkerId, WORKER_TABLE};
+use deno_core::error::{custom_error, AnyError};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use service::tools::cpu_profile::{to_folded, ProfileControl, ProfileSettings, ProfileWindow};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+
+///采样结果保存的 artifact 类型
+pub const PROFILE_ARTIFACTS: &str = "profiles";
+///状态中保留的窗口数 更早的窗口仍在 artifacts 中
+const MAX_WINDOWS: usize = 500;
+
+///开始采样的参数
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProfileRequest {
+  pub interval_us: Option<u32>, //采样间隔 默认 10000 微秒 间隔越短开销越大
+  pub window_secs: Option<u64>, //时间窗口的长度 默认 60 秒 每个 runtime 每个窗口导出一个文件
+}
+
+///一个时间窗口的采样 url 为 folded stacks 文件的下载链接
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WindowSummary {
+  pub runtime: String,
+  pub started_at: u64,
+  pub ended_at: u64,
+  pub samples: u64,
+  pub idle_samples: u64,
+  pub url: Option<String>,
+}
+
+///产品的采样状态 id 为开始采样的时间 total_url 为所有窗口合并后的文件
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProfileStatus {
+  pub product_code: String,
+  pub running: bool,
+  pub id: Option<String>,
+  pub interval_us: Option<u32>,
+  pub window_secs: Option<u64>,
+  pub started_at: Option<u64>,
+  pub stopped_at: Option<u64>,
+  pub windows: Vec<WindowSummary>,
+  pub total_url: Option<String>,
+}
+
+struct Profiling {
+  settings: watch::Sender<Option<ProfileSettings>>,
+  windows: mpsc::UnboundedSender<ProfileWindow>,
+  status: ProfileStatus,
+  total: BTreeMap<String, u64>,
+}
+
+lazy_static! {
+  static ref PROFILES: Mutex<HashMap<String, Profiling>> = Mutex::new(HashMap::new());
+}
+
+///合并到这次采样的总计 返回窗口和总计的文件名
+fn record(product_code: &str, window: &ProfileWindow) -> Option<(String, String, String)> {
+  let mut profiles = PROFILES.lock().unwrap();
+  let profiling = profiles.get_mut(product_code)?;
+  let id = profiling.status.id.clone()?;
+  for (stack, count) in &window.stacks {
+    *profiling.total.entry(stack.clone()).or_insert(0) += count;
+  }
+  let n = profiling.status.windows.len();
+  Some((
+    format!("{}-{}-{}.folded", product_code, id, n),
+    format!("{}-{}.folded", product_code, id),
+    to_folded(&profiling.total),
+  ))
+}
+
+///保存窗口的文件 并更新合并后的文件
+async fn collect(product_code: String, mut rx: mpsc::UnboundedReceiver<ProfileWindow>) {
+  while let Some(window) = rx.recv().await {
+    let Some((name, total_name, total)) = record(&product_code, &window) else {
+      continue;
+    };
+    let url = match artifacts::put_artifact(PROFILE_ARTIFACTS, &name, window.to_folded().into_bytes()).await {
+      Ok(url) => Some(url),
+      Err(err) => {
+        log::warn!("failed to save {} profile: {}", product_code, err);
+        None
+      }
+    };
+    let total_url = match artifacts::put_artifact(PROFILE_ARTIFACTS, &total_name, total.into_bytes()).await {
+      Ok(url) => Some(url),
+      Err(err) => {
+        log::warn!("failed to save {} profile: {}", product_code, err);
+        None
+      }
+    };
+    let mut profiles = PROFILES.lock().unwrap();
+    let Some(status) = profiles.get_mut(&product_code).map(|p| &mut p.status) else {
+      continue;
+    };
+    if status.windows.len() >= MAX_WINDOWS {
+      status.windows.remove(0);
+    }
+    status.windows.push(WindowSummary {
+      runtime: window.runtime,
+      started_at: window.started_at,
+      ended_at: window.ended_at,
+      samples: window.samples,
+      idle_samples: window.idle_samples,
+      url,
+    });
+    status.total_url = total_url.or(status.total_url.take());
+  }
+}
+
+fn profiling<'a>(profiles: &'a mut HashMap<String, Profiling>, product_code: &str) -> &'a mut Profiling {
+  profiles.entry(product_code.to_string()).or_insert_with(|| {
+    let (settings, _) = watch::channel(None);
+    let (windows, rx) = mpsc::unbounded_channel();
+    tokio::spawn(collect(product_code.to_string(), rx));
+    Profiling {
+      settings,
+      windows,
+      status: ProfileStatus {
+        product_code: product_code.to_string(),
+        ..Default::default()
+      },
+      total: BTreeMap::new(),
+    }
+  })
+}
+
+///生产 runtime 启动时获取 采样中启动的 runtime 立即开始采样<br>
+/// 需要在网关的运行时中调用 热加载模式的 runtime 不采样
+pub fn control(product_code: &str) -> ProfileControl {
+  let mut profiles = PROFILES.lock().unwrap();
+  let profiling = profiling(&mut profiles, product_code);
+  ProfileControl {
+    settings: profiling.settings.subscribe(),
+    windows: profiling.windows.clone(),
+  }
+}
+
+fn running_runtimes(product_code: &str) -> usize {
+  let table = WORKER_TABLE.lock().unwrap();
+  table
+    .get(&ScriptWorkerId(product_code.to_string()))
+    .map(|w| w.worker_handlers.lock().unwrap().len())
+    .unwrap_or(0)
+}
+
+///开始持续采样 产品的所有生产 runtime 按时间窗口导出 folded stacks 文件
+pub fn start(product_code: &str, request: ProfileRequest) -> Result<ProfileStatus, AnyError> {
+  if running_runtimes(product_code) == 0 {
+    return Err(custom_error("NotFound", format!("product {} has no running runtime", product_code)));
+  }
+  let interval_us = request.interval_us.unwrap_or(10_000).max(100);
+  let window_secs = request.window_secs.unwrap_or(60).max(1);
+  let mut profiles = PROFILES.lock().unwrap();
+  let profiling = profiling(&mut profiles, product_code);
+  let started_at = now_millis();
+  profiling.total.clear();
+  profiling.status = ProfileStatus {
+    product_code: product_code.to_string(),
+    running: true,
+    id: Some(started_at.to_string()),
+    interval_us: Some(interval_us),
+    window_secs: Some(window_secs),
+    started_at: Some(started_at),
+    ..Default::default()
+  };
+  profiling.settings.send_replace(Some(ProfileSettings {
+    interval_us,
+    window: Duration::from_secs(window_secs),
+  }));
+  Ok(profiling.status.clone())
+}
+
+///停止采样 正在进行的窗口提前结束并导出
+pub fn stop(product_code: &str) -> ProfileStatus {
+  let mut profiles = PROFILES.lock().unwrap();
+  let profiling = profiling(&mut profiles, product_code);
+  if profiling.status.running {
+    profiling.settings.send_replace(None);
+    profiling.status.running = false;
+    profiling.status.stopped_at = Some(now_millis());
+  }
+  profiling.status.clone()
+}
+
+pub fn status(product_code: &str) -> ProfileStatus {
+  let profiles = PROFILES.lock().unwrap();
+  match profiles.get(product_code) {
+    Some(profiling) => profiling.status.clone(),
+    None => ProfileStatus {
+      product_code: product_code.to_string(),
+      ..Default::default()
+    },
+  }
+}
@@ -0,0 +1,276 @@
+use crate::config::GatewayConfig;
+use crate::metrics;
+use crate::util::now_millis;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::{Error, HttpResponse};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use lazy_static::lazy_static;
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+///Redis 出错后使用本地限流的时间 之后再尝试 Redis
+const FALLBACK_MS: u64 = 5000;
+///本地限流表超过这个数量时清理已经恢复的客户端
+const MAX_LOCAL_KEYS: usize = 10_000;
+
+///GCRA 在 Redis 中原子执行 使用 Redis 的时间 各节点的时钟不需要一致<br>
+/// 返回 {是否允许, 需要等待的毫秒数}
+const GCRA_SCRIPT: &str = r#"
+redis.replicate_commands()
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local interval = tonumber(ARGV[1])
+local limit = interval * tonumber(ARGV[2])
+local tat = tonumber(redis.call('GET', KEYS[1]) or now)
+if tat < now then tat = now end
+local new_tat = tat + interval
+if new_tat - now > limit then
+  return {0, new_tat - now - limit}
+end
+redis.call('SET', KEYS[1], new_tat, 'PX', new_tat - now)
+return {1, 0}
+"#;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimiterBackend {
+  #[default]
+  Local, //每个网关进程单独计数
+  Redis, //多个网关节点共用计数
+}
+
+///请求限流 gateway.json 中的 rate_limit 按客户端 ip 计数
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+  pub backend: LimiterBackend,
+  pub replenish_ms: u64,         //每隔多久恢复一次请求额度
+  pub burst: u64,                //额度上限 即允许的突发请求数
+  pub redis_url: Option<String>, //redis://host:6379/0
+  pub key_prefix: String,
+  pub timeout_ms: u64, //Redis 超过这个时间没有响应时使用本地限流
+}
+
+impl Default for RateLimitConfig {
+  fn default() -> Self {
+    Self {
+      backend: LimiterBackend::Local,
+      replenish_ms: 2000,
+      burst: 5,
+      redis_url: None,
+      key_prefix: "cool:ratelimit:".to_string(),
+      timeout_ms: 200,
+    }
+  }
+}
+
+///一次限流判断 不允许时 retry_after_ms 为需要等待的时间
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+  pub allowed: bool,
+  pub retry_after_ms: u64,
+}
+
+lazy_static! {
+  static ref CONFIG: RwLock<RateLimitConfig> = RwLock::new(RateLimitConfig::default());
+  static ref REDIS: RwLock<Option<ConnectionManager>> = RwLock::new(None);
+  static ref LOCAL: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+  static ref SCRIPT: redis::Script = redis::Script::new(GCRA_SCRIPT);
+}
+static REDIS_DOWN_UNTIL: AtomicU64 = AtomicU64::new(0);
+
+///GCRA 算法 tat 为额度全部恢复的时间 返回判断结果和新的 tat
+fn gcra(tat: Option<u64>, now: u64, interval: u64, burst: u64) -> (Decision, Option<u64>) {
+  let new_tat = tat.unwrap_or(now).max(now) + interval;
+  let limit = interval * burst;
+  if new_tat - now > limit {
+    let decision = Decision {
+      allowed: false,
+      retry_after_ms: new_tat - now - limit,
+    };
+    return (decision, None);
+  }
+  let decision = Decision {
+    allowed: true,
+    retry_after_ms: 0,
+  };
+  (decision, Some(new_tat))
+}
+
+fn local_acquire(key: &str, interval: u64, burst: u64) -> Decision {
+  let now = now_millis();
+  let mut table = LOCAL.lock().unwrap();
+  if table.len() > MAX_LOCAL_KEYS {
+    table.retain(|_, tat| *tat > now);
+  }
+  let (decision, new_tat) = gcra(table.get(key).copied(), now, interval, burst);
+  if let Some(new_tat) = new_tat {
+    table.insert(key.to_string(), new_tat);
+  }
+  decision
+}
+
+async fn redis_acquire(mut conn: ConnectionManager, key: &str, interval: u64, burst: u64) -> redis::RedisResult<Decision> {
+  let (allowed, retry_after_ms): (u8, u64) = SCRIPT.key(key).arg(interval).arg(burst).invoke_async(&mut conn).await?;
+  Ok(Decision {
+    allowed: allowed == 1,
+    retry_after_ms,
+  })
+}
+
+///Redis 不可用时 暂时改用本地限流
+fn fall_back(reason: &str) {
+  if REDIS_DOWN_UNTIL.swap(now_millis() + FALLBACK_MS, Ordering::Relaxed) < now_millis() {
+    log::warn!("rate limiter falls back to local counting: {}", reason);
+  }
+  metrics::inc_counter(
+    "gateway_rate_limit_fallback_total",
+    "Rate limit checks counted locally because Redis was unavailable",
+    &[],
+    1,
+  );
+}
+
+fn redis_connection() -> Option<ConnectionManager> {
+  if REDIS_DOWN_UNTIL.load(Ordering::Relaxed) > now_millis() {
+    return None;
+  }
+  REDIS.read().unwrap().clone()
+}
+
+///按 key 计数 配置了 Redis 时所有节点共用额度<br>
+/// interval 毫秒恢复一次额度 最多 burst 个
+pub async fn acquire(key: &str, interval: u64, burst: u64) -> Decision {
+  let interval = interval.max(1);
+  let (prefix, timeout_ms) = {
+    let config = CONFIG.read().unwrap();
+    (config.key_prefix.clone(), config.timeout_ms)
+  };
+  if let Some(conn) = redis_connection() {
+    let redis_key = format!("{}{}", prefix, key);
+    match tokio::time::timeout(Duration::from_millis(timeout_ms), redis_acquire(conn, &redis_key, interval, burst)).await {
+      Ok(Ok(decision)) => return decision,
+      Ok(Err(err)) => fall_back(&err.to_string()),
+      Err(_) => fall_back("timed out"),
+    }
+  }
+  local_acquire(key, interval, burst)
+}
+
+///网关的请求限流中间件 用法 .wrap(RateLimit)
+pub struct RateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+  B: 'static,
+{
+  type Response = ServiceResponse<EitherBody<B>>;
+  type Error = Error;
+  type Transform = RateLimitMiddleware<S>;
+  type InitError = ();
+  type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+  fn new_transform(&self, service: S) -> Self::Future {
+    ready(Ok(RateLimitMiddleware { service: Rc::new(service) }))
+  }
+}
+
+pub struct RateLimitMiddleware<S> {
+  service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+  B: 'static,
+{
+  type Response = ServiceResponse<EitherBody<B>>;
+  type Error = Error;
+  type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+  forward_ready!(service);
+
+  fn call(&self, req: ServiceRequest) -> Self::Future {
+    let service = self.service.clone();
+    Box::pin(async move {
+      if let Some(ip) = req.peer_addr().map(|addr| addr.ip()) {
+        let (interval, burst) = {
+          let config = CONFIG.read().unwrap();
+          (config.replenish_ms, config.burst)
+        };
+        let decision = acquire(&ip.to_string(), interval, burst).await;
+        if !decision.allowed {
+          metrics::inc_counter("gateway_rate_limited_total", "Requests rejected by the gateway rate limiter", &[], 1);
+          let secs = (decision.retry_after_ms + 999) / 1000;
+          let res = HttpResponse::TooManyRequests()
+            .insert_header((RETRY_AFTER, secs))
+            .body(format!("Too many requests, retry in {}s", secs));
+          return Ok(req.into_response(res).map_into_right_body());
+        }
+      }
+      service.call(req).await.map(ServiceResponse::map_into_left_body)
+    })
+  }
+}
+
+///读取限流配置 使用 Redis 时在后台连接 连接成功之前使用本地限流
+pub fn start() {
+  let config = GatewayConfig::load().map(|c| c.rate_limit).unwrap_or_default();
+  *CONFIG.write().unwrap() = config.clone();
+  if config.backend != LimiterBackend::Redis {
+    return;
+  }
+  let url = match &config.redis_url {
+    Some(url) => url.clone(),
+    None => {
+      log::error!("rate_limit.backend is redis but redis_url is not set, counting locally");
+      return;
+    }
+  };
+  tokio::spawn(async move {
+    loop {
+      let conn = match redis::Client::open(url.as_str()) {
+        Ok(client) => ConnectionManager::new(client).await,
+        Err(err) => Err(err),
+      };
+      match conn {
+        Ok(conn) => {
+          log::info!("rate limiter connected to redis");
+          *REDIS.write().unwrap() = Some(conn);
+          return;
+        }
+        Err(err) => {
+          log::warn!("failed to connect to redis for rate limiting: {}", err);
+          tokio::time::sleep(Duration::from_millis(FALLBACK_MS)).await;
+        }
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn allows_burst_then_waits() {
+    let mut tat = None;
+    for _ in 0..5 {
+      let (decision, new_tat) = gcra(tat, 1000, 500, 5);
+      assert!(decision.allowed);
+      tat = new_tat;
+    }
+    let (decision, new_tat) = gcra(tat, 1000, 500, 5);
+    assert_eq!(decision.retry_after_ms, 500);
+    assert!(new_tat.is_none());
+    assert!(gcra(tat, 1500, 500, 5).0.allowed);
+  }
+}
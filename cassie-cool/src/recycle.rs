@@ -0,0 +1,250 @@
+use crate::config::ProductConfig;
+use crate::util::now_millis;
+use crate::{deploy, heap_trend, metrics};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+///检查的间隔 每次每个产品最多替换一个 runtime 新的 runtime 有时间启动后再替换下一个
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+///内存中保留的回收事件数
+const MAX_EVENTS: usize = 500;
+
+///生产 runtime 的定期回收 cool.json 中的 recycle<br>
+/// 任一上限达到后平滑替换这个 runtime 先启动新的再停止旧的 缓解长时间运行的脚本中缓慢的泄漏
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecyclePolicy {
+  pub max_requests: Option<u64>,    //处理的请求数 runtime 共用一个端口 按启动后产品转发的请求数平均到每个 runtime
+  pub max_uptime_secs: Option<u64>, //运行时间
+  pub max_heap_mb: Option<u64>,     //已用堆大小 runtime 是网关进程中的线程 没有单独的 RSS 需要开启 heap 采样
+}
+
+impl RecyclePolicy {
+  fn is_enabled(&self) -> bool {
+    self.max_requests.is_some() || self.max_uptime_secs.is_some() || self.max_heap_mb.is_some()
+  }
+
+  ///返回第一个达到的上限 (原因, 值, 上限)
+  fn exceeded(&self, usage: &RuntimeUsage, now: u64) -> Option<(RecycleReason, u64, u64)> {
+    let uptime_secs = now.saturating_sub(usage.started_at) / 1000;
+    let heap_mb = usage.heap_used.map(|used| used / 1024 / 1024);
+    let checks = [
+      (RecycleReason::Requests, Some(usage.requests), self.max_requests),
+      (RecycleReason::Uptime, Some(uptime_secs), self.max_uptime_secs),
+      (RecycleReason::Heap, heap_mb, self.max_heap_mb),
+    ];
+    checks.into_iter().find_map(|(reason, value, limit)| match (value, limit) {
+      (Some(value), Some(limit)) if value >= limit => Some((reason, value, limit)),
+      _ => None,
+    })
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecycleReason {
+  Requests,
+  Uptime,
+  Heap,
+}
+
+impl RecycleReason {
+  fn as_str(&self) -> &'static str {
+    match self {
+      Self::Requests => "requests",
+      Self::Uptime => "uptime",
+      Self::Heap => "heap",
+    }
+  }
+}
+
+///一个生产 runtime 的用量 requests 为估算值
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuntimeUsage {
+  pub runtime: String,
+  pub started_at: u64,
+  pub requests: u64,
+  pub heap_used: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecycleEvent {
+  pub product_code: String,
+  pub runtime: String,
+  pub reason: RecycleReason,
+  pub value: u64, //请求数 秒 或 MB
+  pub limit: u64,
+  pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecycleReport {
+  pub policy: RecyclePolicy,
+  pub runtimes: Vec<RuntimeUsage>,
+  pub events: Vec<RecycleEvent>, //最近的事件 新的在前
+}
+
+struct Tracked {
+  product_code: String,
+  runtime: String,
+  started_at: u64,
+  requests_at_start: u64,
+  notify: async_channel::Sender<u8>, //runtime 停止后关闭
+}
+
+lazy_static! {
+  static ref REQUESTS: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new()); //网关启动后每个产品转发的请求数
+  static ref RUNTIMES: Mutex<Vec<Tracked>> = Mutex::new(Vec::new());
+  static ref EVENTS: Mutex<VecDeque<RecycleEvent>> = Mutex::new(VecDeque::new());
+}
+
+///记录一次转发的请求
+pub fn record_request(product_code: &str) {
+  *REQUESTS.lock().unwrap().entry(product_code.to_string()).or_default() += 1;
+}
+
+///生产 runtime 启动时登记 notify 为停止这个 runtime 的通道 替换时用来找到它
+pub fn register(product_code: &str, runtime: &str, notify: async_channel::Sender<u8>) {
+  let requests_at_start = REQUESTS.lock().unwrap().get(product_code).copied().unwrap_or(0);
+  RUNTIMES.lock().unwrap().push(Tracked {
+    product_code: product_code.to_string(),
+    runtime: runtime.to_string(),
+    started_at: now_millis(),
+    requests_at_start,
+    notify,
+  });
+}
+
+///产品正在运行的 runtime 按启动时间排序 已停止的不再跟踪
+fn usage(product_code: &str) -> Vec<(RuntimeUsage, async_channel::Sender<u8>)> {
+  let total = REQUESTS.lock().unwrap().get(product_code).copied().unwrap_or(0);
+  let mut runtimes = RUNTIMES.lock().unwrap();
+  runtimes.retain(|t| !t.notify.is_closed());
+  let running: Vec<&Tracked> = runtimes.iter().filter(|t| t.product_code == product_code).collect();
+  let count = running.len() as u64;
+  running
+    .into_iter()
+    .map(|t| {
+      let usage = RuntimeUsage {
+        runtime: t.runtime.clone(),
+        started_at: t.started_at,
+        requests: total.saturating_sub(t.requests_at_start) / count,
+        heap_used: heap_trend::latest_used(product_code, &t.runtime),
+      };
+      (usage, t.notify.clone())
+    })
+    .collect()
+}
+
+fn raise(event: RecycleEvent) {
+  let reason = event.reason.as_str();
+  log::info!(
+    "recycling {} of {}: {} {} reached the limit of {}",
+    event.runtime,
+    event.product_code,
+    reason,
+    event.value,
+    event.limit
+  );
+  metrics::inc_counter(
+    "runtime_recycles_total",
+    "Production runtimes replaced by the recycling policy",
+    &[("product", &event.product_code), ("reason", reason)],
+    1,
+  );
+  let mut events = EVENTS.lock().unwrap();
+  if events.len() >= MAX_EVENTS {
+    events.pop_front();
+  }
+  events.push_back(event);
+}
+
+///每个产品替换最早达到上限的 runtime
+async fn tick() {
+  let mut products: Vec<String> = RUNTIMES.lock().unwrap().iter().map(|t| t.product_code.clone()).collect();
+  products.sort();
+  products.dedup();
+  let now = now_millis();
+  for product_code in products {
+    let policy = ProductConfig::load(&product_code).map(|c| c.recycle).unwrap_or_default();
+    if !policy.is_enabled() {
+      continue;
+    }
+    let found = usage(&product_code)
+      .into_iter()
+      .find_map(|(usage, notify)| policy.exceeded(&usage, now).map(|exceeded| (usage.runtime, notify, exceeded)));
+    let Some((runtime, notify, (reason, value, limit))) = found else {
+      continue;
+    };
+    if deploy::replace_product_runtime(&product_code, &notify).await {
+      raise(RecycleEvent {
+        product_code,
+        runtime,
+        reason,
+        value,
+        limit,
+        created_at: now_millis(),
+      });
+    }
+  }
+}
+
+///产品的回收策略 runtime 的用量和最近的回收
+pub fn report(product_code: &str) -> RecycleReport {
+  RecycleReport {
+    policy: ProductConfig::load(product_code).map(|c| c.recycle).unwrap_or_default(),
+    runtimes: usage(product_code).into_iter().map(|(usage, _)| usage).collect(),
+    events: EVENTS
+      .lock()
+      .unwrap()
+      .iter()
+      .rev()
+      .filter(|e| e.product_code == product_code)
+      .cloned()
+      .collect(),
+  }
+}
+
+///定时检查所有产品的生产 runtime
+pub fn start() {
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+      interval.tick().await;
+      tick().await;
+    }
+  });
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn finds_first_exceeded_limit() {
+    let usage = RuntimeUsage {
+      runtime: "product-shop-0".to_string(),
+      started_at: 0,
+      requests: 5000,
+      heap_used: None,
+    };
+    let now = 7200 * 1000;
+    assert_eq!(RecyclePolicy::default().exceeded(&usage, now), None);
+    let policy = RecyclePolicy {
+      max_requests: Some(10_000),
+      max_uptime_secs: Some(3600),
+      max_heap_mb: Some(256),
+    };
+    assert_eq!(policy.exceeded(&usage, now), Some((RecycleReason::Uptime, 7200, 3600)));
+    //没有堆采样时不按堆大小回收
+    assert_eq!(policy.exceeded(&usage, 1000), None);
+    let heavy = RuntimeUsage {
+      heap_used: Some(300 << 20),
+      ..usage
+    };
+    assert_eq!(policy.exceeded(&heavy, 1000), Some((RecycleReason::Heap, 300, 256)));
+  }
+}
@@ -0,0 +1,184 @@
+use crate::api_version;
+use crate::auth::error_response;
+use crate::config::{data_dir, GatewayConfig};
+use crate::deploy;
+use crate::util::list_dir;
+use crate::versions;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::Error;
+use deno_core::error::{custom_error, AnyError};
+use futures_util::future::LocalBoxFuture;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+///只读实例上可以调用的修改类接口 登录只在本实例创建会话
+const ALLOWED_MUTATIONS: [&str; 2] = ["/auth/login", "/auth/logout"];
+///接受 dry_run 查询参数的修改类接口 dry_run 时只预览不写入 {} 匹配一个路径段<br>
+/// 其他接口会忽略 dry_run 不能按查询参数放行
+const DRY_RUN_ROUTES: [&str; 27] = [
+  "/runtime/bulk",
+  "/code/meta/{}",
+  "/code/outdated/{}/apply",
+  "/code/secrets/{}/{}",
+  "/code/secrets/{}/{}/{}/retire",
+  "/admin/tenants",
+  "/admin/products/{}/owner",
+  "/admin/usage/export",
+  "/admin/users",
+  "/admin/users/{}",
+  "/admin/users/{}/delete",
+  "/admin/users/{}/totp/reset",
+  "/admin/audit/{}/checkpoint",
+  "/admin/retention/run",
+  "/admin/encryption/rotate",
+  "/admin/encryption/rewrap",
+  "/admin/state/{}/snapshot",
+  "/admin/state/restore",
+  "/admin/clone",
+  "/admin/hooks/{}/deliveries/{}/replay",
+  "/admin/gitops/sync",
+  "/admin/gitops/plans/{}/approve",
+  "/admin/import",
+  "/admin/cas",
+  "/admin/cas/{}/delete",
+  "/tenant/products",
+  "/tenant/products/{}",
+];
+///用 GET 调用但会改变 runtime 的接口
+const RUNTIME_CONTROLS: [&str; 5] = ["/start", "/stop", "/restart", "/start_debugger", "/exit"];
+
+///只读副本 gateway.json 中的 replica<br>
+/// 副本处理产品请求和只读的管理接口 拒绝部署和配置修改<br>
+/// 定时从共享存储拉取主实例部署的版本 需要与主实例使用同一个存储后端
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReplicaConfig {
+  pub enabled: bool,
+  pub poll_secs: u64, //拉取新版本的间隔
+}
+
+impl Default for ReplicaConfig {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      poll_secs: 10,
+    }
+  }
+}
+
+lazy_static! {
+  static ref ENABLED: AtomicBool = AtomicBool::new(false);
+}
+
+pub fn enabled() -> bool {
+  ENABLED.load(Ordering::Relaxed)
+}
+
+///按路径段匹配 DRY_RUN_ROUTES 中的路由
+fn matches_route(pattern: &str, path: &str) -> bool {
+  let pattern: Vec<&str> = pattern.split('/').collect();
+  let path: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+  pattern.len() == path.len() && pattern.iter().zip(&path).all(|(p, s)| p == s || (*p == "{}" && !s.is_empty()))
+}
+
+///请求是否会修改平台状态 产品请求和支持 dry_run 的接口的预览不算
+pub fn is_mutation(method: &Method, path: &str, query: &str) -> bool {
+  let path = match api_version::management_path(path) {
+    Some((_, path)) => path,
+    None => return false,
+  };
+  if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+    let runtime_control =
+      path.starts_with("/runtime/") && (path.starts_with("/runtime/deploy/") || RUNTIME_CONTROLS.iter().any(|control| path.ends_with(control)));
+    //协同编辑通过 websocket 写入代码
+    return runtime_control || path.starts_with("/code/collab/");
+  }
+  let dry_run =
+    query.split('&').any(|pair| pair == "dry_run=true" || pair == "dry_run=1") && DRY_RUN_ROUTES.iter().any(|route| matches_route(route, path));
+  !dry_run && !ALLOWED_MUTATIONS.contains(&path)
+}
+
+///拒绝修改类请求的中间件 用法 .wrap_fn(replica::guard)
+pub fn guard<S>(req: ServiceRequest, srv: &S) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>>
+where
+  S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+  S::Future: 'static,
+{
+  if !enabled() || !is_mutation(req.method(), req.path(), req.query_string()) {
+    return Box::pin(srv.call(req));
+  }
+  let err = custom_error("Forbidden", "this gateway is a read-only replica, send changes to the primary");
+  let res = req.into_response(error_response(err));
+  Box::pin(async move { Ok(res) })
+}
+
+///下载新版本 最新版本有变化的产品切换到新版本 返回切换的产品
+async fn follow(applied: &mut HashMap<String, String>) -> Result<Vec<String>, AnyError> {
+  versions::hydrate().await?;
+  let mut switched = vec![];
+  for (product_code, _) in list_dir(data_dir().join("versions"))? {
+    let latest = match versions::latest_version(&product_code)? {
+      Some(latest) => latest,
+      None => continue,
+    };
+    if applied.get(&product_code) == Some(&latest) {
+      continue;
+    }
+    versions::restore(&product_code, &latest).await?;
+    deploy::swap_product_runtime(&product_code).await;
+    let deployment = deploy::Deployment {
+      id: None,
+      version: Some(latest.clone()),
+    };
+    deploy::set_current_deployment(&product_code, Some(deployment));
+    log::info!("replica switched {} to version {}", product_code, latest);
+    applied.insert(product_code.clone(), latest);
+    switched.push(product_code);
+  }
+  Ok(switched)
+}
+
+///按配置进入只读模式 并定时跟随主实例部署的版本
+pub fn start() {
+  let config = GatewayConfig::load().map(|c| c.replica).unwrap_or_default();
+  ENABLED.store(config.enabled, Ordering::Relaxed);
+  if !config.enabled {
+    return;
+  }
+  log::info!("running as a read-only replica");
+  tokio::spawn(async move {
+    let mut applied = HashMap::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(config.poll_secs.max(1)));
+    loop {
+      interval.tick().await;
+      if let Err(err) = follow(&mut applied).await {
+        log::error!("replica failed to follow the primary: {}", err);
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn classifies_mutations() {
+    assert!(is_mutation(&Method::POST, "/admin/users", ""));
+    assert!(is_mutation(&Method::POST, "/api/v1/admin/users", ""));
+    assert!(!is_mutation(&Method::POST, "/admin/users", "dry_run=true"));
+    assert!(!is_mutation(&Method::POST, "/api/v1/admin/users/u1/delete", "dry_run=1"));
+    assert!(is_mutation(&Method::POST, "/code/patch/x", "dry_run=true"));
+    assert!(is_mutation(&Method::POST, "/admin/users/u1/unknown", "dry_run=true"));
+    assert!(!is_mutation(&Method::GET, "/admin/users", ""));
+    assert!(is_mutation(&Method::GET, "/runtime/deploy/shop", ""));
+    assert!(is_mutation(&Method::GET, "/runtime/pro/shop/stop", ""));
+    assert!(!is_mutation(&Method::GET, "/runtime/shop/logs", ""));
+    assert!(!is_mutation(&Method::POST, "/auth/login", ""));
+    assert!(!is_mutation(&Method::POST, "/orders", ""));
+  }
+}
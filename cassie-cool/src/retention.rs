@@ -0,0 +1,307 @@
+use crate::artifacts;
+use crate::audit_log::{self, AuditEvent, AuditKind};
+use crate::config::{data_dir, GatewayConfig};
+use crate::deploy::history_path;
+use crate::replica;
+use crate::trash;
+use crate::usage::usage_path;
+use crate::util::{list_dir, now_millis};
+use chrono::{Duration as ChronoDuration, NaiveDate, Utc};
+use deno_core::error::AnyError;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+///不属于某个产品的清理记录在这个审计日志里
+pub const PLATFORM_AUDIT: &str = "@platform";
+
+const DAY_MILLIS: u64 = 24 * 3600 * 1000;
+
+///可以设置保留期的数据
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+  Logs,      //部署历史 data/history
+  Metrics,   //每天的用量 data/usage
+  Artifacts, //导出的文件 data/artifacts 不区分产品
+  Audit,     //审计事件 data/audit
+  Trash,     //删除的代码文件 data/trash 没有配置时保留 30 天
+}
+
+///数据保留 gateway.json 中的 retention 没有配置的类别永久保留
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+  pub interval_secs: u64,                                  //自动清理的间隔 0 表示只手动清理
+  pub days: BTreeMap<Category, u64>,                       //各类别的保留天数
+  pub products: BTreeMap<String, BTreeMap<Category, u64>>, //按产品覆盖保留天数
+}
+
+impl Default for RetentionConfig {
+  fn default() -> Self {
+    Self {
+      interval_secs: 6 * 3600,
+      days: BTreeMap::new(),
+      products: BTreeMap::new(),
+    }
+  }
+}
+
+impl RetentionConfig {
+  ///产品的保留天数 产品没有覆盖时使用类别的配置
+  pub fn days_for(&self, category: Category, product_code: Option<&str>) -> Option<u64> {
+    product_code
+      .and_then(|p| self.products.get(p))
+      .and_then(|days| days.get(&category))
+      .or_else(|| self.days.get(&category))
+      .copied()
+  }
+}
+
+///会被删除或已删除的一项数据
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PruneItem {
+  pub category: Category,
+  pub product_code: Option<String>,
+  pub target: String, //文件路径 相对 data 目录
+  pub entries: u64,   //删除的记录数 整个文件删除时为 1
+  pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PruneReport {
+  pub dry_run: bool,
+  pub created_at: u64,
+  pub items: Vec<PruneItem>,
+  pub total_bytes: u64,
+}
+
+fn relative(path: &Path) -> String {
+  path.strip_prefix(data_dir()).unwrap_or(path).to_string_lossy().to_string()
+}
+
+fn cutoff_millis(days: u64) -> u64 {
+  now_millis().saturating_sub(days * DAY_MILLIS)
+}
+
+///删除 jsonl 中 created_at 早于 cutoff 的行
+fn prune_jsonl(path: &Path, cutoff: u64, dry_run: bool) -> Result<(u64, u64), AnyError> {
+  let text = match std::fs::read_to_string(path) {
+    Ok(text) => text,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok((0, 0)),
+    Err(err) => return Err(err.into()),
+  };
+  let (mut entries, mut bytes) = (0, 0);
+  let mut kept = String::new();
+  for line in text.lines().filter(|l| !l.is_empty()) {
+    let created_at = serde_json::from_str::<serde_json::Value>(line)
+      .ok()
+      .and_then(|v| v.get("created_at").and_then(|c| c.as_u64()));
+    match created_at {
+      Some(created_at) if created_at < cutoff => {
+        entries += 1;
+        bytes += line.len() as u64 + 1;
+      }
+      _ => {
+        kept.push_str(line);
+        kept.push('\n');
+      }
+    }
+  }
+  if !dry_run && entries > 0 {
+    let tmp = path.with_extension("jsonl.tmp");
+    std::fs::write(&tmp, kept)?;
+    std::fs::rename(tmp, path)?;
+  }
+  Ok((entries, bytes))
+}
+
+fn prune_logs(config: &RetentionConfig, dry_run: bool, items: &mut Vec<PruneItem>) -> Result<(), AnyError> {
+  for (name, _) in list_dir(data_dir().join("history"))? {
+    let product_code = match name.strip_suffix(".jsonl") {
+      Some(product_code) => product_code,
+      None => continue,
+    };
+    let days = match config.days_for(Category::Logs, Some(product_code)) {
+      Some(days) => days,
+      None => continue,
+    };
+    let path = history_path(product_code);
+    let (entries, bytes) = prune_jsonl(&path, cutoff_millis(days), dry_run)?;
+    if entries > 0 {
+      items.push(PruneItem {
+        category: Category::Logs,
+        product_code: Some(product_code.to_string()),
+        target: relative(&path),
+        entries,
+        bytes,
+      });
+    }
+  }
+  Ok(())
+}
+
+fn prune_metrics(config: &RetentionConfig, dry_run: bool, items: &mut Vec<PruneItem>) -> Result<(), AnyError> {
+  for (product_code, _) in list_dir(data_dir().join("usage"))? {
+    let days = match config.days_for(Category::Metrics, Some(&product_code)) {
+      Some(days) => days,
+      None => continue,
+    };
+    let cutoff = Utc::now().date_naive() - ChronoDuration::days(days as i64);
+    for (name, _) in list_dir(data_dir().join("usage").join(&product_code))? {
+      let date = match name.strip_suffix(".json") {
+        Some(date) => date,
+        None => continue,
+      };
+      match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        Ok(day) if day < cutoff => {}
+        _ => continue,
+      }
+      let path = usage_path(&product_code, date);
+      let bytes = std::fs::metadata(&path)?.len();
+      if !dry_run {
+        std::fs::remove_file(&path)?;
+      }
+      items.push(PruneItem {
+        category: Category::Metrics,
+        product_code: Some(product_code.clone()),
+        target: relative(&path),
+        entries: 1,
+        bytes,
+      });
+    }
+  }
+  Ok(())
+}
+
+fn prune_artifacts(config: &RetentionConfig, dry_run: bool, items: &mut Vec<PruneItem>) -> Result<(), AnyError> {
+  let days = match config.days_for(Category::Artifacts, None) {
+    Some(days) => days,
+    None => return Ok(()),
+  };
+  let cutoff = cutoff_millis(days);
+  //在 spawn_blocking 的线程中运行 可以等待存储的异步接口
+  let runtime = tokio::runtime::Handle::current();
+  for object in runtime.block_on(artifacts::list_artifacts(None))? {
+    if object.modified >= cutoff {
+      continue;
+    }
+    let (kind, name) = object.key.split_once('/').unwrap_or_default();
+    if !dry_run {
+      runtime.block_on(artifacts::delete_artifact(kind, name))?;
+    }
+    items.push(PruneItem {
+      category: Category::Artifacts,
+      product_code: None,
+      target: format!("artifacts/{}", object.key),
+      entries: 1,
+      bytes: object.size,
+    });
+  }
+  Ok(())
+}
+
+fn prune_audit(config: &RetentionConfig, dry_run: bool, items: &mut Vec<PruneItem>) -> Result<(), AnyError> {
+  for product_code in audit_log::products()? {
+    let days = match config.days_for(Category::Audit, Some(&product_code)) {
+      Some(days) => days,
+      None => continue,
+    };
+    let (entries, bytes) = audit_log::prune(&product_code, cutoff_millis(days), dry_run)?;
+    if entries > 0 {
+      items.push(PruneItem {
+        category: Category::Audit,
+        product_code: Some(product_code.clone()),
+        target: relative(&audit_log::audit_path(&product_code)),
+        entries,
+        bytes,
+      });
+    }
+  }
+  Ok(())
+}
+
+fn prune_trash(config: &RetentionConfig, dry_run: bool, items: &mut Vec<PruneItem>) -> Result<(), AnyError> {
+  for product_code in trash::products()? {
+    let days = config.days_for(Category::Trash, Some(&product_code)).unwrap_or(trash::DEFAULT_TRASH_DAYS);
+    for entry in trash::prune(&product_code, cutoff_millis(days), dry_run)? {
+      items.push(PruneItem {
+        category: Category::Trash,
+        product_code: Some(product_code.clone()),
+        target: relative(&trash::trash_dir(&product_code).join(&entry.id)),
+        entries: 1,
+        bytes: entry.bytes,
+      });
+    }
+  }
+  Ok(())
+}
+
+///按保留期清理数据 dry_run 时只返回会删除的内容<br>
+/// 实际删除后每项都记一条审计事件 不属于产品的记在 @platform 下
+pub fn run(config: &RetentionConfig, dry_run: bool) -> Result<PruneReport, AnyError> {
+  let mut items = vec![];
+  prune_logs(config, dry_run, &mut items)?;
+  prune_metrics(config, dry_run, &mut items)?;
+  prune_artifacts(config, dry_run, &mut items)?;
+  prune_audit(config, dry_run, &mut items)?;
+  prune_trash(config, dry_run, &mut items)?;
+  if !dry_run {
+    for item in &items {
+      let product_code = item.product_code.as_deref().unwrap_or(PLATFORM_AUDIT);
+      let detail = json!({ "category": item.category, "target": item.target, "entries": item.entries, "bytes": item.bytes });
+      audit_log::record(&AuditEvent::new(AuditKind::RetentionPruned, product_code, detail))?;
+    }
+  }
+  Ok(PruneReport {
+    dry_run,
+    created_at: now_millis(),
+    total_bytes: items.iter().map(|i| i.bytes).sum(),
+    items,
+  })
+}
+
+///读取网关配置后清理 在阻塞线程上执行
+pub async fn run_with_gateway_config(dry_run: bool) -> Result<PruneReport, AnyError> {
+  let config = GatewayConfig::load()?.retention;
+  tokio::task::spawn_blocking(move || run(&config, dry_run)).await?
+}
+
+///按配置的间隔定时清理
+pub fn start() {
+  let interval_secs = GatewayConfig::load().map(|c| c.retention.interval_secs).unwrap_or_default();
+  //共享存储中的数据由主实例清理
+  if interval_secs == 0 || replica::enabled() {
+    return;
+  }
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+    loop {
+      interval.tick().await;
+      match run_with_gateway_config(false).await {
+        Ok(report) if !report.items.is_empty() => {
+          log::info!("retention pruned {} items, {} bytes", report.items.len(), report.total_bytes)
+        }
+        Ok(_) => {}
+        Err(err) => log::error!("retention pruning failed: {}", err),
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn product_overrides_category_days() {
+    let mut config = RetentionConfig::default();
+    config.days.insert(Category::Audit, 365);
+    config.products.insert("shop".to_string(), BTreeMap::from([(Category::Audit, 730)]));
+    assert_eq!(config.days_for(Category::Audit, Some("shop")), Some(730));
+    assert_eq!(config.days_for(Category::Audit, Some("blog")), Some(365));
+    assert_eq!(config.days_for(Category::Logs, Some("shop")), None);
+  }
+}
@@ -0,0 +1,352 @@
+use crate::config::{module_pins_path, product_dir, storage_dir, validate_module, ProductConfig};
+use crate::crash;
+use crate::dns_cache;
+use crate::egress;
+use crate::logs;
+use crate::node_compat;
+use crate::offline;
+use crate::outbound;
+use crate::sandbox;
+use crate::trusted_cas;
+use crate::util::now_millis;
+use crate::worker_util::tool_flags;
+use chrono::Utc;
+use cron::Schedule;
+use deno_core::error::{generic_error, AnyError};
+use deno_runtime::tokio_util::create_and_run_current_thread;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use service::tools::run::run_script;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::select;
+
+///入口角色
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+  Http,               //接收网关转发的请求
+  BackgroundConsumer, //常驻后台 退出后自动重启
+  CronHandler,        //按 schedule 定时执行 每次运行结束即退出
+}
+
+///入口配置 cool.json 中的 entries
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntryConfig {
+  pub name: String,
+  pub role: Role,
+  pub entry: String, //相对产品目录
+  #[serde(default)]
+  pub args: Vec<String>,
+  #[serde(default)]
+  pub schedule: Option<String>, //cron 表达式 带秒 例如 0 */5 * * * *
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoleState {
+  Running,
+  Idle, //定时任务等待下一次执行
+  Failed,
+  Stopped,
+}
+
+///角色运行状态
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoleStatus {
+  pub name: String,
+  pub role: Role,
+  pub entry: String,
+  pub state: RoleState,
+  pub runs: u32,
+  pub last_started_at: Option<u64>,
+  pub last_exit_at: Option<u64>,
+  pub last_error: Option<String>,
+  pub next_run_at: Option<u64>,
+}
+
+struct RoleHandle {
+  status: Arc<Mutex<RoleStatus>>,
+  stop_tx: async_channel::Sender<u8>,
+}
+
+lazy_static! {
+  static ref ROLE_TABLE: Mutex<HashMap<String, Vec<RoleHandle>>> = Mutex::new(HashMap::new());
+}
+
+///后台角色退出后的重启间隔
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+
+///在独立线程里运行一个入口 结束时通过返回的通道通知
+fn spawn_entry(
+  product_code: String,
+  thread_name: String,
+  script: String,
+  script_args: Vec<String>,
+  cwd: Option<PathBuf>,
+  stream_rx: async_channel::Receiver<TcpStream>,
+  notify_rx: async_channel::Receiver<u8>,
+) -> tokio::sync::oneshot::Receiver<Result<i32, String>> {
+  let (exit_tx, exit_rx) = tokio::sync::oneshot::channel();
+  let build = thread::Builder::new().name(thread_name);
+  let _ = build.spawn(move || {
+    let fut = async move {
+      let result = match tool_flags("run", &script) {
+        Ok(mut flags) => {
+          flags.argv = script_args;
+          flags.cwd = cwd;
+          flags.module_pins = Some(module_pins_path(&product_code));
+          flags.storage_dir = Some(storage_dir(&product_code));
+          flags.product_code = Some(product_code.clone());
+          offline::apply(&mut flags, &product_code);
+          egress::apply(&product_code);
+          dns_cache::apply(&product_code);
+          outbound::apply(&product_code);
+          node_compat::apply(&mut flags, &product_code);
+          trusted_cas::apply(&mut flags, &product_code);
+          let _scratch = sandbox::apply(&mut flags, &product_code, &uuid::Uuid::new_v4().to_string());
+          run_script(
+            flags,
+            stream_rx,
+            notify_rx,
+            logs::capture(&product_code),
+            crash::hook(&product_code),
+            None,
+            None,
+          )
+          .await
+          .map_err(|e| e.to_string())
+        }
+        Err(err) => Err(err.to_string()),
+      };
+      let _ = exit_tx.send(result);
+    };
+    create_and_run_current_thread(fut);
+  });
+  exit_rx
+}
+
+fn update(status: &Arc<Mutex<RoleStatus>>, f: impl FnOnce(&mut RoleStatus)) {
+  f(&mut status.lock().unwrap());
+}
+
+///常驻角色 退出后按间隔重启 直到收到停止信号
+async fn supervise_background(product_code: String, config: EntryConfig, cwd: Option<PathBuf>, status: Arc<Mutex<RoleStatus>>, stop_rx: async_channel::Receiver<u8>) {
+  //非 http 角色不接收请求 保留发送端避免通道关闭
+  let (_stream_tx, stream_rx) = async_channel::unbounded::<TcpStream>();
+  let script = product_dir(&product_code).join(&config.entry).to_string_lossy().to_string();
+  loop {
+    let (notify_tx, notify_rx) = async_channel::bounded::<u8>(1);
+    update(&status, |s| {
+      s.state = RoleState::Running;
+      s.runs += 1;
+      s.last_started_at = Some(now_millis());
+    });
+    let exit_rx = spawn_entry(
+      product_code.clone(),
+      format!("product-{}-{}", product_code, config.name),
+      script.clone(),
+      config.args.clone(),
+      cwd.clone(),
+      stream_rx.clone(),
+      notify_rx,
+    );
+    select! {
+      result = exit_rx => {
+        let error = match result {
+          Ok(Ok(_)) => None,
+          Ok(Err(err)) => Some(err),
+          Err(_) => Some("worker thread panicked".to_string()),
+        };
+        update(&status, |s| {
+          s.state = if error.is_some() { RoleState::Failed } else { RoleState::Stopped };
+          s.last_exit_at = Some(now_millis());
+          s.last_error = error;
+        });
+        log::warn!("{} role {} exited, restarting", product_code, config.name);
+      }
+      _ = stop_rx.recv() => {
+        let _ = notify_tx.send(1).await;
+        update(&status, |s| s.state = RoleState::Stopped);
+        return;
+      }
+    }
+    select! {
+      _ = tokio::time::sleep(RESTART_BACKOFF) => {}
+      _ = stop_rx.recv() => {
+        update(&status, |s| s.state = RoleState::Stopped);
+        return;
+      }
+    }
+  }
+}
+
+///定时角色 每次到点启动一个 runtime 运行结束后等待下一次
+///cron 入口必须配置合法的 schedule
+fn parse_schedule(config: &EntryConfig) -> Result<Schedule, AnyError> {
+  config
+    .schedule
+    .as_deref()
+    .and_then(|schedule| Schedule::from_str(schedule).ok())
+    .ok_or_else(|| generic_error(format!("entry {} has invalid schedule {:?}", config.name, config.schedule)))
+}
+
+///部署前校验 每个入口模块必须是产品目录内的文件 cron 入口的 schedule 必须合法
+pub fn validate(product_code: &str, entries: &[EntryConfig]) -> Result<(), AnyError> {
+  for entry in entries {
+    validate_module(product_code, &entry.entry)?;
+    if entry.role == Role::CronHandler {
+      parse_schedule(entry)?;
+    }
+  }
+  Ok(())
+}
+
+async fn supervise_cron(product_code: String, config: EntryConfig, cwd: Option<PathBuf>, status: Arc<Mutex<RoleStatus>>, stop_rx: async_channel::Receiver<u8>) {
+  let schedule = match parse_schedule(&config) {
+    Ok(schedule) => schedule,
+    Err(err) => {
+      update(&status, |s| {
+        s.state = RoleState::Failed;
+        s.last_error = Some(err.to_string());
+      });
+      return;
+    }
+  };
+  let (_stream_tx, stream_rx) = async_channel::unbounded::<TcpStream>();
+  let script = product_dir(&product_code).join(&config.entry).to_string_lossy().to_string();
+  loop {
+    let next = match schedule.upcoming(Utc).next() {
+      Some(next) => next,
+      None => {
+        update(&status, |s| s.state = RoleState::Stopped);
+        return;
+      }
+    };
+    let wait = (next - Utc::now()).to_std().unwrap_or_default();
+    update(&status, |s| {
+      s.state = RoleState::Idle;
+      s.next_run_at = Some(next.timestamp_millis() as u64);
+    });
+    select! {
+      _ = tokio::time::sleep(wait) => {}
+      _ = stop_rx.recv() => {
+        update(&status, |s| s.state = RoleState::Stopped);
+        return;
+      }
+    }
+    let (notify_tx, notify_rx) = async_channel::bounded::<u8>(1);
+    update(&status, |s| {
+      s.state = RoleState::Running;
+      s.runs += 1;
+      s.last_started_at = Some(now_millis());
+    });
+    let exit_rx = spawn_entry(
+      product_code.clone(),
+      format!("product-{}-{}", product_code, config.name),
+      script.clone(),
+      config.args.clone(),
+      cwd.clone(),
+      stream_rx.clone(),
+      notify_rx,
+    );
+    select! {
+      result = exit_rx => {
+        let error = match result {
+          Ok(Ok(_)) => None,
+          Ok(Err(err)) => Some(err),
+          Err(_) => Some("worker thread panicked".to_string()),
+        };
+        update(&status, |s| {
+          s.last_exit_at = Some(now_millis());
+          s.last_error = error;
+        });
+      }
+      _ = stop_rx.recv() => {
+        let _ = notify_tx.send(1).await;
+        update(&status, |s| s.state = RoleState::Stopped);
+        return;
+      }
+    }
+  }
+}
+
+///启动产品配置的非 http 角色 已经在运行的会先停止
+pub fn start_roles(product_code: &str) {
+  stop_roles(product_code);
+  let config = ProductConfig::load(product_code).unwrap_or_default();
+  let cwd = config.runtime.cwd_path(product_code);
+  let mut handles = vec![];
+  for entry in config.entries.into_iter().filter(|e| e.role != Role::Http) {
+    let (stop_tx, stop_rx) = async_channel::bounded::<u8>(1);
+    let status = Arc::new(Mutex::new(RoleStatus {
+      name: entry.name.clone(),
+      role: entry.role,
+      entry: entry.entry.clone(),
+      state: RoleState::Idle,
+      runs: 0,
+      last_started_at: None,
+      last_exit_at: None,
+      last_error: None,
+      next_run_at: None,
+    }));
+    let product_code = product_code.to_string();
+    let task_status = status.clone();
+    let cwd = cwd.clone();
+    match entry.role {
+      Role::BackgroundConsumer => {
+        tokio::spawn(supervise_background(product_code, entry, cwd, task_status, stop_rx));
+      }
+      Role::CronHandler => {
+        tokio::spawn(supervise_cron(product_code, entry, cwd, task_status, stop_rx));
+      }
+      Role::Http => unreachable!(),
+    }
+    handles.push(RoleHandle { status, stop_tx });
+  }
+  ROLE_TABLE.lock().unwrap().insert(product_code.to_string(), handles);
+}
+
+///停止产品所有非 http 角色
+pub fn stop_roles(product_code: &str) {
+  if let Some(handles) = ROLE_TABLE.lock().unwrap().remove(product_code) {
+    for handle in handles {
+      let _ = handle.stop_tx.try_send(1);
+    }
+  }
+}
+
+///产品所有非 http 角色的状态
+pub fn role_status(product_code: &str) -> Vec<RoleStatus> {
+  match ROLE_TABLE.lock().unwrap().get(product_code) {
+    Some(handles) => handles.iter().map(|h| h.status.lock().unwrap().clone()).collect(),
+    None => vec![],
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn cron(schedule: Option<&str>) -> EntryConfig {
+    EntryConfig {
+      name: "cleanup".to_string(),
+      role: Role::CronHandler,
+      entry: "cron.ts".to_string(),
+      args: vec![],
+      schedule: schedule.map(|s| s.to_string()),
+    }
+  }
+
+  #[test]
+  fn requires_a_valid_schedule() {
+    assert!(parse_schedule(&cron(Some("0 */5 * * * *"))).is_ok());
+    assert!(parse_schedule(&cron(Some("every five minutes"))).is_err());
+    assert!(parse_schedule(&cron(None)).is_err());
+  }
+}
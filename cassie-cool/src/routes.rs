@@ -0,0 +1,208 @@
+use crate::config::product_dir;
+use crate::tenants;
+use deno_core::error::{generic_error, AnyError};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+const METHODS: [&str; 7] = ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"];
+///网关按路径前缀转发 /p/{product_code}/... 浏览器和第三方 webhook 不需要设置 product_code 请求头
+pub const PRODUCT_PREFIX: &str = "/p/";
+///按前缀转发时告诉 runtime 去掉的前缀 生成链接时拼在路径前面
+pub const FORWARDED_PREFIX_HEADER: &str = "x-forwarded-prefix";
+
+///runtime 中的路由入口 按清单把请求直接交给处理函数<br>
+/// 处理函数在第一次命中时才导入 签名为 (request, params) => Response
+const ROUTES_HOST: &str = r#"const routes = __ROUTES__.map((route) => ({ ...route, pattern: new URLPattern({ pathname: route.path }) }));
+const handlers = new Map();
+function handlerOf(route) {
+  let handler = handlers.get(route);
+  if (!handler) {
+    handler = route.load().then((mod) => {
+      const fn = mod[route.export];
+      if (typeof fn !== "function") {
+        throw new TypeError(`${route.module} has no exported function ${route.export}`);
+      }
+      return fn;
+    });
+    handler.catch(() => handlers.delete(route));
+    handlers.set(route, handler);
+  }
+  return handler;
+}
+async function dispatch(request) {
+  const url = new URL(request.url);
+  for (const route of routes) {
+    if (route.method && route.method !== request.method) continue;
+    const match = route.pattern.exec({ pathname: url.pathname });
+    if (!match) continue;
+    try {
+      const handler = await handlerOf(route);
+      return await handler(request, match.pathname.groups);
+    } catch (err) {
+      console.error(`route ${route.name} failed:`, err);
+      return new Response("Internal Server Error", { status: 500 });
+    }
+  }
+  return new Response("Not Found", { status: 404 });
+}
+async function serve(conn) {
+  for await (const event of Deno.serveHttp(conn)) {
+    event.respondWith(dispatch(event.request)).catch(() => {});
+  }
+}
+for await (const conn of Deno.listen({ port: 3000 })) {
+  serve(conn).catch(() => {});
+}
+"#;
+
+///路由清单中的一项 cool.json 中的 routes<br>
+/// path 支持 :name 参数和结尾的 * 例如 /users/:id /files/*
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Route {
+  pub path: String,
+  #[serde(default)]
+  pub method: Option<String>, //不配置时匹配所有方法
+  pub module: String, //处理函数所在模块 相对产品目录
+  #[serde(default = "default_export")]
+  pub export: String, //导出的处理函数名 默认 default
+}
+
+fn default_export() -> String {
+  "default".to_string()
+}
+
+impl Route {
+  ///指标中使用的路由名称 例如 GET /users/:id
+  pub fn name(&self) -> String {
+    format!("{} {}", self.method.as_deref().unwrap_or("*"), self.path)
+  }
+
+  ///和 runtime 中 URLPattern 的匹配结果一致 清单只允许两边都支持的写法
+  pub fn matches(&self, method: &str, path: &str) -> bool {
+    if self.method.as_deref().map(|m| m != method).unwrap_or(false) {
+      return false;
+    }
+    let mut patterns = self.path.trim_start_matches('/').split('/');
+    let mut segments = path.trim_start_matches('/').split('/');
+    loop {
+      match (patterns.next(), segments.next()) {
+        (Some("*"), segment) => return segment.is_some(),
+        (Some(pattern), Some(segment)) if pattern.starts_with(':') && !segment.is_empty() => {}
+        (Some(pattern), Some(segment)) if pattern == segment => {}
+        (None, None) => return true,
+        _ => return false,
+      }
+    }
+  }
+
+  fn validate(&self, product_code: &str) -> Result<(), AnyError> {
+    if !self.path.starts_with('/') {
+      return Err(generic_error(format!("route {} must start with /", self.path)));
+    }
+    let segments: Vec<&str> = self.path[1..].split('/').collect();
+    for (i, segment) in segments.iter().enumerate() {
+      let valid = match segment.strip_prefix(':') {
+        Some(name) => !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        None if *segment == "*" => i == segments.len() - 1,
+        None => !segment.contains(|c| ":*(){}?+\\".contains(c)),
+      };
+      if !valid {
+        return Err(generic_error(format!("route {} has an unsupported segment {}", self.path, segment)));
+      }
+    }
+    if let Some(method) = &self.method {
+      if !METHODS.contains(&method.as_str()) {
+        return Err(generic_error(format!("route {} has an unsupported method {}", self.path, method)));
+      }
+    }
+    let base = product_dir(product_code).canonicalize()?;
+    let module = base
+      .join(&self.module)
+      .canonicalize()
+      .map_err(|_| generic_error(format!("route module {} not found", self.module)))?;
+    if !module.starts_with(&base) || !module.is_file() {
+      return Err(generic_error(format!("route module {} must be a file inside the product", self.module)));
+    }
+    Ok(())
+  }
+}
+
+///部署前校验路由清单
+pub fn validate(product_code: &str, routes: &[Route]) -> Result<(), AnyError> {
+  for route in routes {
+    route.validate(product_code)?;
+  }
+  Ok(())
+}
+
+///请求命中的路由名称 没有清单或没有命中时为空
+pub fn match_route(routes: &[Route], method: &str, path: &str) -> Option<String> {
+  routes.iter().find(|r| r.matches(method, path)).map(|r| r.name())
+}
+
+///按路径前缀匹配产品 返回产品编码和去掉前缀后的路径 不是合法的产品编码时不匹配
+pub fn product_prefix(path: &str) -> Option<(&str, &str)> {
+  let rest = path.strip_prefix(PRODUCT_PREFIX)?;
+  let (product_code, path) = match rest.find('/') {
+    Some(i) => rest.split_at(i),
+    None => (rest, "/"),
+  };
+  tenants::valid_code(product_code).then_some((product_code, path))
+}
+
+///生成 runtime 的入口模块 以 data: URL 作为 runtime 的启动模块<br>
+/// 处理函数用字面量 import() 导入 开启 lazy_imports 时第一次命中才加载
+pub fn host_module(product_code: &str, routes: &[Route]) -> String {
+  let base = product_dir(product_code);
+  let mut entries = vec![];
+  for route in routes {
+    //产品目录是绝对路径
+    let module = Url::from_file_path(base.join(&route.module)).unwrap().to_string();
+    let json = |value: &str| serde_json::to_string(value).unwrap();
+    entries.push(format!(
+      "{{ name: {}, path: {}, method: {}, module: {}, export: {}, load: () => import({}) }}",
+      json(&route.name()),
+      json(&route.path),
+      route.method.as_deref().map(json).unwrap_or_else(|| "null".to_string()),
+      json(&route.module),
+      json(&route.export),
+      json(&module),
+    ));
+  }
+  let source = ROUTES_HOST.replace("__ROUTES__", &format!("[\n  {},\n]", entries.join(",\n  ")));
+  format!("data:application/javascript;base64,{}", base64::encode(source))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn matches_like_url_pattern() {
+    let route: Route = serde_json::from_str(r#"{"path": "/users/:id", "method": "GET", "module": "users.ts"}"#).unwrap();
+    assert_eq!(route.export, "default");
+    assert!(route.matches("GET", "/users/42"));
+    assert!(!route.matches("POST", "/users/42"));
+    assert!(!route.matches("GET", "/users/"));
+    assert!(!route.matches("GET", "/users/42/"));
+    let files = Route {
+      path: "/files/*".to_string(),
+      method: None,
+      module: "files.ts".to_string(),
+      export: "get".to_string(),
+    };
+    assert!(files.matches("PUT", "/files/a/b"));
+    assert!(!files.matches("GET", "/files"));
+    assert_eq!(match_route(&[route, files], "GET", "/files/"), Some("* /files/*".to_string()));
+  }
+
+  #[test]
+  fn strips_product_prefix() {
+    assert_eq!(product_prefix("/p/shop/orders/1"), Some(("shop", "/orders/1")));
+    assert_eq!(product_prefix("/p/shop"), Some(("shop", "/")));
+    assert_eq!(product_prefix("/p/shop/"), Some(("shop", "/")));
+    assert_eq!(product_prefix("/p/../etc"), None);
+    assert_eq!(product_prefix("/p//orders"), None);
+    assert_eq!(product_prefix("/shop/orders"), None);
+  }
+}
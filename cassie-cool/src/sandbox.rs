@@ -0,0 +1,102 @@
+use crate::config::{data_dir, product_dir, ProductConfig};
+use crate::util::copy_dir;
+use deno_runtime::ops::scratch;
+use serde::{Deserialize, Serialize};
+use service::args::{Flags, FsSandbox};
+use std::path::PathBuf;
+
+///文件系统沙箱 cool.json 中的 filesystem<br>
+/// 代码目录只读 每个 runtime 只能写入自己的临时目录 停止时删除
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilesystemPolicy {
+  pub sandbox: bool,           //关闭后 runtime 可以读写整个文件系统
+  pub scratch_quota_mb: u64,   //临时目录的大小上限
+  pub allow_read: Vec<String>, //额外可读的路径 相对路径按产品目录解析
+  pub allow_run: Vec<String>,  //允许执行的命令 沙箱中默认不能启动子进程
+}
+
+impl Default for FilesystemPolicy {
+  fn default() -> Self {
+    Self {
+      sandbox: true,
+      scratch_quota_mb: 256,
+      allow_read: vec![],
+      allow_run: vec![],
+    }
+  }
+}
+
+///所有临时目录的根目录 data/scratch
+fn scratch_root() -> PathBuf {
+  data_dir().join("scratch")
+}
+
+///产品所有 runtime 临时目录的父目录 data/scratch/{product_code}
+pub fn product_scratch_dir(product_code: &str) -> PathBuf {
+  scratch_root().join(product_code)
+}
+
+///runtime 的临时目录 data/scratch/{product_code}/{worker}
+pub fn scratch_dir(product_code: &str, worker: &str) -> PathBuf {
+  product_scratch_dir(product_code).join(worker)
+}
+
+///新 runtime 临时目录的初始内容 data/scratch-seed/{product_code} 由状态快照恢复时写入
+pub fn seed_dir(product_code: &str) -> PathBuf {
+  data_dir().join("scratch-seed").join(product_code)
+}
+
+///runtime 的临时目录 释放时删除目录并取消配额
+pub struct Scratch {
+  dir: PathBuf,
+}
+
+impl Drop for Scratch {
+  fn drop(&mut self) {
+    scratch::unregister(&self.dir);
+    if let Err(err) = std::fs::remove_dir_all(&self.dir) {
+      if err.kind() != std::io::ErrorKind::NotFound {
+        log::error!("failed to remove scratch directory {}: {}", self.dir.display(), err);
+      }
+    }
+  }
+}
+
+///在 runtime 线程里调用 开启沙箱时创建临时目录并限制文件系统权限<br>
+/// 返回的 Scratch 需要保留到 runtime 结束
+pub fn apply(flags: &mut Flags, product_code: &str, worker: &str) -> Option<Scratch> {
+  let policy = ProductConfig::load(product_code).unwrap_or_default().filesystem;
+  if !policy.sandbox {
+    return None;
+  }
+  let code_dir = product_dir(product_code);
+  let dir = scratch_dir(product_code, worker);
+  //上次异常退出时可能有残留
+  let _ = std::fs::remove_dir_all(&dir);
+  let seed = seed_dir(product_code);
+  let created = match seed.is_dir() {
+    true => copy_dir(&seed, &dir).map(|_| ()),
+    false => std::fs::create_dir_all(&dir),
+  };
+  if let Err(err) = created {
+    log::error!("failed to create scratch directory {}: {}", dir.display(), err);
+  }
+  scratch::register(&dir, policy.scratch_quota_mb * 1024 * 1024);
+  flags.sandbox = Some(FsSandbox {
+    allow_read: policy.allow_read.iter().map(|p| code_dir.join(p)).collect(),
+    allow_run: policy.allow_run,
+    code_dir,
+    scratch_dir: dir.clone(),
+  });
+  Some(Scratch { dir })
+}
+
+///启动时清理上次运行留下的临时目录
+pub fn start() {
+  if let Err(err) = std::fs::remove_dir_all(scratch_root()) {
+    if err.kind() != std::io::ErrorKind::NotFound {
+      log::error!("failed to clean scratch directories: {}", err);
+    }
+  }
+}
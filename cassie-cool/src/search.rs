@@ -0,0 +1,231 @@
+use crate::audit_log;
+use crate::catalog::{self, CatalogQuery};
+use crate::config::product_dir;
+use crate::deploy::{history_path, DeployRecord};
+use crate::media;
+use crate::util::now_millis;
+use deno_core::error::{generic_error, AnyError};
+use deno_runtime::at_rest;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+///最多收集的结果数 超过后不再继续搜索
+const MAX_HITS: usize = 1000;
+///超过这个大小的代码文件不搜索
+const MAX_FILE_BYTES: u64 = 1024 * 1024;
+///只搜索最近几天的日志
+const LOG_WINDOW_DAYS: u64 = 7;
+const SNIPPET_CHARS: usize = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HitKind {
+  Product, //产品目录信息
+  Code,    //代码文件中的一行
+  Log,     //部署记录和审计事件
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchQuery {
+  pub q: String,
+  pub kinds: Option<String>,   //逗号分隔 例如 code,log 为空时搜索全部
+  pub product: Option<String>, //只搜索这个产品
+  pub page: usize,             //从 1 开始
+  pub per_page: usize,
+}
+
+impl Default for SearchQuery {
+  fn default() -> Self {
+    Self {
+      q: String::new(),
+      kinds: None,
+      product: None,
+      page: 1,
+      per_page: 20,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchHit {
+  pub kind: HitKind,
+  pub product_code: String,
+  pub title: String, //产品名称 文件路径或日志类型
+  pub snippet: String,
+  pub line: Option<usize>,     //代码文件中的行号 从 1 开始
+  pub created_at: Option<u64>, //日志的时间
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchPage {
+  pub total: usize,
+  pub page: usize,
+  pub per_page: usize,
+  pub truncated: bool, //结果超过上限 total 不是全部
+  pub hits: Vec<SearchHit>,
+}
+
+///匹配位置附近的片段
+fn snippet(text: &str, at: usize) -> String {
+  let start = text[..at].char_indices().rev().nth(SNIPPET_CHARS / 2).map(|(i, _)| i).unwrap_or(0);
+  text[start..].chars().take(SNIPPET_CHARS).collect::<String>().trim().to_string()
+}
+
+///不区分大小写查找 返回在原文中的位置
+fn find(text: &str, needle: &str) -> Option<usize> {
+  let lower = text.to_lowercase();
+  let at = lower.find(needle)?;
+  //小写后长度可能变化 这时退回到行首
+  Some(if lower.len() == text.len() { at } else { 0 })
+}
+
+fn search_code(product_code: &str, needle: &str, hits: &mut Vec<SearchHit>) -> Result<(), AnyError> {
+  let root = product_dir(product_code);
+  let files = WalkDir::new(&root)
+    .sort_by_file_name()
+    .into_iter()
+    .filter_map(|e| e.ok())
+    .filter(|e| e.file_type().is_file() && e.metadata().map(|m| m.len() <= MAX_FILE_BYTES).unwrap_or(false));
+  for file in files {
+    let bytes = at_rest::decrypt(std::fs::read(file.path())?)?;
+    if media::is_binary(&bytes) {
+      continue;
+    }
+    let text = String::from_utf8_lossy(&bytes);
+    let path = file.path().strip_prefix(&root).unwrap().to_string_lossy().replace('\\', "/");
+    for (i, line) in text.lines().enumerate() {
+      if let Some(at) = find(line, needle) {
+        hits.push(SearchHit {
+          kind: HitKind::Code,
+          product_code: product_code.to_string(),
+          title: path.clone(),
+          snippet: snippet(line, at),
+          line: Some(i + 1),
+          created_at: None,
+        });
+        if hits.len() >= MAX_HITS {
+          return Ok(());
+        }
+      }
+    }
+  }
+  Ok(())
+}
+
+fn search_logs(product_code: &str, needle: &str, hits: &mut Vec<SearchHit>) -> Result<(), AnyError> {
+  let since = now_millis().saturating_sub(LOG_WINDOW_DAYS * 24 * 3600 * 1000);
+  let mut found = vec![];
+  let history = match std::fs::read_to_string(history_path(product_code)) {
+    Ok(text) => text,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+    Err(err) => return Err(err.into()),
+  };
+  for record in history.lines().filter_map(|l| serde_json::from_str::<DeployRecord>(l).ok()) {
+    if record.created_at < since {
+      continue;
+    }
+    let text = serde_json::to_string(&record)?;
+    if let Some(at) = find(&text, needle) {
+      found.push(SearchHit {
+        kind: HitKind::Log,
+        product_code: product_code.to_string(),
+        title: format!("deploy {}", record.version.as_deref().unwrap_or("-")),
+        snippet: snippet(&text, at),
+        line: None,
+        created_at: Some(record.created_at),
+      });
+    }
+  }
+  for event in audit_log::read_events(product_code)?.into_iter().filter(|e| e.created_at >= since) {
+    let text = serde_json::to_string(&event.detail)?;
+    if let Some(at) = find(&text, needle) {
+      found.push(SearchHit {
+        kind: HitKind::Log,
+        product_code: product_code.to_string(),
+        title: format!("audit {}", serde_json::to_value(event.kind)?.as_str().unwrap_or_default()),
+        snippet: snippet(&text, at),
+        line: None,
+        created_at: Some(event.created_at),
+      });
+    }
+  }
+  found.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+  hits.extend(found.into_iter().take(MAX_HITS.saturating_sub(hits.len())));
+  Ok(())
+}
+
+///在 products 范围内搜索 结果按产品 代码 日志的顺序分页<br>
+/// 调用方负责按权限给出 products 在阻塞线程上执行
+pub fn search(products: &[String], query: &SearchQuery) -> Result<SearchPage, AnyError> {
+  let needle = query.q.trim().to_lowercase();
+  if needle.is_empty() {
+    return Err(generic_error("empty search query"));
+  }
+  let kinds: Vec<HitKind> = match &query.kinds {
+    Some(kinds) => kinds
+      .split(',')
+      .map(|k| serde_json::from_value(serde_json::Value::String(k.trim().to_string())))
+      .collect::<Result<_, _>>()
+      .map_err(|_| generic_error(format!("invalid kinds {}", kinds)))?,
+    None => vec![HitKind::Product, HitKind::Code, HitKind::Log],
+  };
+  let products: Vec<&String> = products
+    .iter()
+    .filter(|p| query.product.as_ref().map(|only| only == *p).unwrap_or(true))
+    .collect();
+  let mut hits = vec![];
+  if kinds.contains(&HitKind::Product) {
+    let catalog_query = CatalogQuery {
+      q: Some(needle.clone()),
+      tag: None,
+    };
+    for entry in catalog::search(&catalog_query)?
+      .into_iter()
+      .filter(|e| products.contains(&&e.product_code))
+    {
+      hits.push(SearchHit {
+        kind: HitKind::Product,
+        title: entry.display_name,
+        snippet: entry.description.unwrap_or_default(),
+        product_code: entry.product_code,
+        line: None,
+        created_at: None,
+      });
+    }
+  }
+  for kind in [HitKind::Code, HitKind::Log].into_iter().filter(|k| kinds.contains(k)) {
+    for product_code in &products {
+      if hits.len() >= MAX_HITS {
+        break;
+      }
+      match kind {
+        HitKind::Code => search_code(product_code, &needle, &mut hits)?,
+        _ => search_logs(product_code, &needle, &mut hits)?,
+      }
+    }
+  }
+  let per_page = query.per_page.clamp(1, 100);
+  let page = query.page.max(1);
+  Ok(SearchPage {
+    total: hits.len(),
+    page,
+    per_page,
+    truncated: hits.len() >= MAX_HITS,
+    hits: hits.into_iter().skip((page - 1) * per_page).take(per_page).collect(),
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn finds_snippet_case_insensitive() {
+    let line = "  const Books = await fetchBooks();";
+    let at = find(line, "fetchbooks").unwrap();
+    assert_eq!(&line[at..at + 10], "fetchBooks");
+    assert_eq!(snippet(line, at), "const Books = await fetchBooks();");
+    assert_eq!(find(line, "missing"), None);
+  }
+}
@@ -0,0 +1,127 @@
+use crate::config::data_dir;
+use crate::util::now_millis;
+use deno_core::error::{generic_error, AnyError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+///密钥的一个版本 轮换时追加新版本 旧版本在停用前继续有效
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecretVersion {
+  pub version: u32,
+  pub value: String,
+  pub created_at: u64,
+  #[serde(default)]
+  pub retired: bool, //停用后不再参与校验
+}
+
+///对外展示的密钥信息 不包含值
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecretInfo {
+  pub name: String,
+  pub versions: Vec<SecretVersionInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecretVersionInfo {
+  pub version: u32,
+  pub created_at: u64,
+  pub retired: bool,
+}
+
+type SecretTable = BTreeMap<String, Vec<SecretVersion>>;
+
+///产品密钥 data/secrets/{product_code}.json
+pub fn secrets_path(product_code: &str) -> PathBuf {
+  let mut path = data_dir();
+  path.push("secrets");
+  path.push(format!("{}.json", product_code));
+  path
+}
+
+fn load(product_code: &str) -> Result<SecretTable, AnyError> {
+  match std::fs::read_to_string(secrets_path(product_code)) {
+    Ok(text) => Ok(serde_json::from_str(&text)?),
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(SecretTable::new()),
+    Err(err) => Err(err.into()),
+  }
+}
+
+fn save(product_code: &str, table: &SecretTable) -> Result<(), AnyError> {
+  let path = secrets_path(product_code);
+  std::fs::create_dir_all(path.parent().unwrap())?;
+  std::fs::write(path, serde_json::to_vec_pretty(table)?)?;
+  Ok(())
+}
+
+fn info(name: &str, versions: &[SecretVersion]) -> SecretInfo {
+  SecretInfo {
+    name: name.to_string(),
+    versions: versions
+      .iter()
+      .map(|v| SecretVersionInfo {
+        version: v.version,
+        created_at: v.created_at,
+        retired: v.retired,
+      })
+      .collect(),
+  }
+}
+
+///所有密钥及版本 不返回值
+pub fn list_secrets(product_code: &str) -> Result<Vec<SecretInfo>, AnyError> {
+  Ok(load(product_code)?.iter().map(|(name, versions)| info(name, versions)).collect())
+}
+
+///一个密钥的版本 不存在时为 None
+pub fn secret_info(product_code: &str, name: &str) -> Result<Option<SecretInfo>, AnyError> {
+  Ok(load(product_code)?.get(name).map(|versions| info(name, versions)))
+}
+
+///写入密钥的新版本 已有的版本保持有效 新版本在最后 dry_run 时不保存
+pub fn put_secret(product_code: &str, name: &str, value: String, dry_run: bool) -> Result<SecretInfo, AnyError> {
+  if name.is_empty() || value.is_empty() {
+    return Err(generic_error("secret name and value are required"));
+  }
+  let mut table = load(product_code)?;
+  let versions = table.entry(name.to_string()).or_default();
+  let version = versions.iter().map(|v| v.version).max().unwrap_or(0) + 1;
+  versions.push(SecretVersion {
+    version,
+    value,
+    created_at: now_millis(),
+    retired: false,
+  });
+  let secret = info(name, versions);
+  if !dry_run {
+    save(product_code, &table)?;
+  }
+  Ok(secret)
+}
+
+///停用密钥的某个版本 轮换完成后停用旧版本
+pub fn retire_secret(product_code: &str, name: &str, version: u32, dry_run: bool) -> Result<SecretInfo, AnyError> {
+  let mut table = load(product_code)?;
+  let secret = table
+    .get_mut(name)
+    .and_then(|versions| versions.iter_mut().find(|v| v.version == version))
+    .ok_or_else(|| generic_error(format!("secret {} version {} not found", name, version)))?;
+  secret.retired = true;
+  let secret = info(name, &table[name]);
+  if !dry_run {
+    save(product_code, &table)?;
+  }
+  Ok(secret)
+}
+
+///密钥所有未停用的值 新版本在前
+pub fn active_values(product_code: &str, name: &str) -> Result<Vec<String>, AnyError> {
+  let mut versions: Vec<SecretVersion> = load(product_code)?
+    .remove(name)
+    .unwrap_or_default()
+    .into_iter()
+    .filter(|v| !v.retired)
+    .collect();
+  versions.sort_by(|a, b| b.version.cmp(&a.version));
+  Ok(versions.into_iter().map(|v| v.value).collect())
+}
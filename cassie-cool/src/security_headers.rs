@@ -0,0 +1,119 @@
+use crate::config::GatewayConfig;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+
+///注入到响应中的安全头 字段为空时使用网关的默认值 设置为空字符串时不注入这个头<br>
+/// runtime 自己设置的同名头不会被覆盖
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SecurityHeaders {
+  pub enabled: Option<bool>,              //产品中配置时覆盖网关的 products
+  pub hsts: Option<String>,               //Strict-Transport-Security 只在 https 请求中注入 例如 max-age=31536000
+  pub content_type_options: Option<bool>, //X-Content-Type-Options: nosniff
+  pub csp: Option<String>,                //Content-Security-Policy
+  pub csp_report_only: Option<bool>,      //只报告不拦截 使用 Content-Security-Policy-Report-Only
+  pub referrer_policy: Option<String>,    //Referrer-Policy 例如 strict-origin-when-cross-origin
+}
+
+///网关的安全头策略 gateway.json 中的 security_headers
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SecurityHeadersConfig {
+  pub products: Vec<String>, //默认注入的产品 * 表示所有产品
+  pub defaults: SecurityHeaders,
+}
+
+lazy_static! {
+  static ref CONFIG: RwLock<SecurityHeadersConfig> = RwLock::new(SecurityHeadersConfig::default());
+}
+
+///产品的配置覆盖网关的默认值
+fn merge(defaults: &SecurityHeaders, product: &SecurityHeaders) -> SecurityHeaders {
+  SecurityHeaders {
+    enabled: product.enabled.or(defaults.enabled),
+    hsts: product.hsts.clone().or_else(|| defaults.hsts.clone()),
+    content_type_options: product.content_type_options.or(defaults.content_type_options),
+    csp: product.csp.clone().or_else(|| defaults.csp.clone()),
+    csp_report_only: product.csp_report_only.or(defaults.csp_report_only),
+    referrer_policy: product.referrer_policy.clone().or_else(|| defaults.referrer_policy.clone()),
+  }
+}
+
+fn resolve(config: &SecurityHeadersConfig, product_code: &str, product: &SecurityHeaders, https: bool) -> Vec<(HeaderName, HeaderValue)> {
+  let selected = config.products.iter().any(|p| p == "*" || p == product_code);
+  let headers = merge(&config.defaults, product);
+  if !headers.enabled.unwrap_or(selected) {
+    return vec![];
+  }
+  let csp_header = match headers.csp_report_only.unwrap_or(false) {
+    true => "content-security-policy-report-only",
+    false => "content-security-policy",
+  };
+  let nosniff = headers.content_type_options.unwrap_or(false).then(|| "nosniff".to_string());
+  let hsts = headers.hsts.filter(|_| https);
+  let mut result = vec![];
+  for (name, value) in [
+    ("strict-transport-security", hsts),
+    ("x-content-type-options", nosniff),
+    (csp_header, headers.csp),
+    ("referrer-policy", headers.referrer_policy),
+  ] {
+    let value = match value {
+      Some(value) if !value.is_empty() => value,
+      _ => continue,
+    };
+    match HeaderValue::from_str(&value) {
+      Ok(value) => result.push((HeaderName::from_static(name), value)),
+      Err(_) => log::warn!("invalid {} value for {}: {}", name, product_code, value),
+    }
+  }
+  result
+}
+
+///产品响应需要注入的安全头
+pub fn headers_for(product_code: &str, product: &SecurityHeaders, https: bool) -> Vec<(HeaderName, HeaderValue)> {
+  resolve(&CONFIG.read().unwrap(), product_code, product, https)
+}
+
+///读取网关的默认值
+pub fn start() {
+  let config = GatewayConfig::load().map(|c| c.security_headers).unwrap_or_default();
+  *CONFIG.write().unwrap() = config;
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn product_overrides_gateway_defaults() {
+    let config = SecurityHeadersConfig {
+      products: vec!["shop".to_string()],
+      defaults: SecurityHeaders {
+        hsts: Some("max-age=31536000".to_string()),
+        content_type_options: Some(true),
+        csp: Some("default-src 'self'".to_string()),
+        ..Default::default()
+      },
+    };
+    let names = |headers: Vec<(HeaderName, HeaderValue)>| headers.into_iter().map(|(n, _)| n.to_string()).collect::<Vec<_>>();
+    let shop = resolve(&config, "shop", &SecurityHeaders::default(), false);
+    assert_eq!(names(shop), vec!["x-content-type-options", "content-security-policy"]);
+    assert!(resolve(&config, "blog", &SecurityHeaders::default(), true).is_empty());
+    let blog = SecurityHeaders {
+      enabled: Some(true),
+      csp: Some("default-src 'none'".to_string()),
+      csp_report_only: Some(true),
+      content_type_options: Some(false),
+      ..Default::default()
+    };
+    let headers = resolve(&config, "blog", &blog, true);
+    assert_eq!(
+      names(headers.clone()),
+      vec!["strict-transport-security", "content-security-policy-report-only"]
+    );
+    assert_eq!(headers[1].1, "default-src 'none'");
+  }
+}
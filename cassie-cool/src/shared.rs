@@ -0,0 +1,62 @@
+use crate::config::product_dir;
+use crate::util::copy_dir;
+use deno_core::error::{generic_error, AnyError};
+use serde::{Deserialize, Serialize};
+use service::resolver::{latest_shared_version, shared_modules_dir};
+use std::path::Component;
+use std::path::Path;
+
+///共享模块 产品通过 platform:{name}@{version}/{path} 引用
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedModule {
+  pub name: String,
+  pub versions: Vec<String>,
+  pub latest: Option<String>, //不带版本号引用时解析到的版本
+}
+
+fn is_valid_segment(value: &str) -> bool {
+  let mut components = Path::new(value).components();
+  matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) && !value.contains('@')
+}
+
+///所有共享模块及其版本
+pub fn list_shared_modules() -> Result<Vec<SharedModule>, AnyError> {
+  let root = shared_modules_dir();
+  if !root.exists() {
+    return Ok(vec![]);
+  }
+  let mut modules = vec![];
+  for entry in std::fs::read_dir(&root)?.filter_map(|e| e.ok()).filter(|e| e.path().is_dir()) {
+    let name = entry.file_name().to_string_lossy().to_string();
+    let mut versions: Vec<String> = std::fs::read_dir(entry.path())?
+      .filter_map(|e| e.ok())
+      .filter(|e| e.path().is_dir())
+      .map(|e| e.file_name().to_string_lossy().to_string())
+      .collect();
+    versions.sort();
+    let latest = latest_shared_version(&root, &name);
+    modules.push(SharedModule { name, versions, latest });
+  }
+  modules.sort_by(|a, b| a.name.cmp(&b.name));
+  Ok(modules)
+}
+
+///把产品目录下的某个子目录发布为共享模块的一个版本<br>
+/// 已发布的版本不可覆盖 避免已部署的产品被静默修改
+pub async fn publish_shared_module(name: &str, version: &str, product_code: &str, path: &str) -> Result<u64, AnyError> {
+  if !is_valid_segment(name) || !is_valid_segment(version) {
+    return Err(generic_error(format!("invalid shared module {}@{}", name, version)));
+  }
+  let product_root = product_dir(product_code).canonicalize()?;
+  let source = product_root.join(path).canonicalize()?;
+  if !source.starts_with(&product_root) || !source.is_dir() {
+    return Err(generic_error(format!("{} is not a directory of product {}", path, product_code)));
+  }
+  let target = shared_modules_dir().join(name).join(version);
+  if target.exists() {
+    return Err(generic_error(format!("shared module {}@{} already exists", name, version)));
+  }
+  let bytes = tokio::task::spawn_blocking(move || copy_dir(&source, &target)).await??;
+  log::info!("published shared module {}@{} from {}/{}", name, version, product_code, path);
+  Ok(bytes)
+}
@@ -0,0 +1,204 @@
+use crate::secrets;
+use actix_web::HttpRequest;
+use lazy_static::lazy_static;
+use ring::{digest, hmac};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+///请求签名校验 cool.json 中的 signature<br>
+/// 签名内容为 {timestamp}\n{METHOD}\n{path?query}\n{hex(sha256(body))}<br>
+/// 使用 HMAC-SHA256 十六进制编码 密钥来自密钥库 所有未停用的版本都可以通过校验<br>
+/// 签名中没有 nonce 开启 reject_replays 时时间窗口内同一个签名只接受一次 重试需要用新的时间戳重新签名<br>
+/// 记录只保存在当前网关进程中 多个网关实例之间不共享
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SignaturePolicy {
+  pub enabled: bool,
+  pub secret: String,           //密钥库中的密钥名
+  pub signature_header: String, //签名请求头
+  pub timestamp_header: String, //时间戳请求头 unix 秒
+  pub max_skew_secs: u64,       //允许的时间偏差 超出视为过期请求
+  pub paths: Vec<String>,       //需要签名的路径前缀 按路径段匹配 为空时所有请求都需要
+  pub reject_replays: bool,     //拒绝时间窗口内重复的签名
+}
+
+impl Default for SignaturePolicy {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      secret: "request-signing".to_string(),
+      signature_header: "x-signature".to_string(),
+      timestamp_header: "x-timestamp".to_string(),
+      max_skew_secs: 300,
+      paths: vec![],
+      reject_replays: true,
+    }
+  }
+}
+
+impl SignaturePolicy {
+  pub fn applies_to(&self, path: &str) -> bool {
+    self.enabled && (self.paths.is_empty() || self.paths.iter().any(|p| under(path, p)))
+  }
+}
+
+///已经接受的签名 -> 过期时间 unix 秒
+#[derive(Default)]
+struct Seen {
+  signatures: HashMap<String, u64>,
+  pruned_at: u64,
+}
+
+lazy_static! {
+  static ref SEEN: Mutex<Seen> = Mutex::new(Seen::default());
+}
+
+///按路径段匹配前缀 /admin 匹配 /admin 和 /admin/users 不匹配 /administrator
+fn under(path: &str, prefix: &str) -> bool {
+  match path.strip_prefix(prefix.trim_end_matches('/')) {
+    Some(rest) => rest.is_empty() || rest.starts_with('/'),
+    None => false,
+  }
+}
+
+///记录通过校验的签名 时间窗口内已经出现过时返回 false 每秒最多清理一次过期的记录
+fn first_use(seen: &mut Seen, signature: &str, now: u64, expires_at: u64) -> bool {
+  if seen.pruned_at < now {
+    seen.signatures.retain(|_, expires| *expires >= now);
+    seen.pruned_at = now;
+  }
+  match seen.signatures.get(signature) {
+    Some(expires) if *expires >= now => false,
+    _ => {
+      seen.signatures.insert(signature.to_string(), expires_at);
+      true
+    }
+  }
+}
+
+///签名内容
+pub fn signing_payload(timestamp: &str, method: &str, path_and_query: &str, body: &[u8]) -> String {
+  let body_hash = hex::encode(digest::digest(&digest::SHA256, body));
+  format!("{}\n{}\n{}\n{}", timestamp, method.to_ascii_uppercase(), path_and_query, body_hash)
+}
+
+///计算签名 十六进制
+pub fn sign(secret: &str, payload: &str) -> String {
+  let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+  hex::encode(hmac::sign(&key, payload.as_bytes()))
+}
+
+///任一密钥的 HMAC-SHA256 签名与 signature 相同时通过 signature 可以带 sha256= 前缀
+pub fn verify_with_keys(keys: &[String], payload: impl AsRef<[u8]>, signature: &str) -> bool {
+  let signature = match hex::decode(signature.trim().trim_start_matches("sha256=")) {
+    Ok(signature) => signature,
+    Err(_) => return false,
+  };
+  keys.iter().any(|secret| {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    hmac::verify(&key, payload.as_ref(), &signature).is_ok()
+  })
+}
+
+fn check_timestamp(timestamp: &str, now: u64, max_skew_secs: u64) -> Result<(), String> {
+  let timestamp: u64 = timestamp.parse().map_err(|_| "invalid timestamp".to_string())?;
+  if timestamp.abs_diff(now) > max_skew_secs {
+    return Err("request timestamp expired".to_string());
+  }
+  Ok(())
+}
+
+///校验请求签名 失败时返回原因 转发前调用
+pub fn verify_request(product_code: &str, policy: &SignaturePolicy, req: &HttpRequest, body: &[u8]) -> Result<(), String> {
+  let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+  let signature = header(&policy.signature_header).ok_or_else(|| format!("missing {} header", policy.signature_header))?;
+  let timestamp = header(&policy.timestamp_header).ok_or_else(|| format!("missing {} header", policy.timestamp_header))?;
+  let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+  check_timestamp(&timestamp, now, policy.max_skew_secs)?;
+  let keys = secrets::active_values(product_code, &policy.secret).map_err(|e| e.to_string())?;
+  if keys.is_empty() {
+    return Err(format!("signing secret {} not configured", policy.secret));
+  }
+  let path_and_query = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+  let payload = signing_payload(&timestamp, req.method().as_str(), path_and_query, body);
+  if !verify_with_keys(&keys, &payload, &signature) {
+    return Err("invalid signature".to_string());
+  }
+  //时间戳在窗口内的请求都可能被重放 记录保留到时间戳超出窗口为止
+  let expires_at = timestamp.parse::<u64>().unwrap_or(now) + policy.max_skew_secs;
+  let key = format!("{}:{}", product_code, signature.trim().trim_start_matches("sha256=").to_ascii_lowercase());
+  if policy.reject_replays && !first_use(&mut SEEN.lock().unwrap(), &key, now, expires_at) {
+    return Err("replayed request".to_string());
+  }
+  Ok(())
+}
+
+///WebSocket 握手没有请求体 需要签名的路径按空请求体校验 校验通过前不升级连接
+pub fn verify_handshake(product_code: &str, policy: &SignaturePolicy, path: &str, req: &HttpRequest) -> Result<(), String> {
+  match policy.applies_to(path) {
+    true => verify_request(product_code, policy, req, &[]),
+    false => Ok(()),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn verifies_with_any_active_key() {
+    let payload = signing_payload("1700000000", "post", "/orders?id=1", b"{\"a\":1}");
+    assert!(payload.starts_with("1700000000\nPOST\n/orders?id=1\n"));
+    let signature = sign("old-key", &payload);
+    let keys = vec!["new-key".to_string(), "old-key".to_string()];
+    assert!(verify_with_keys(&keys, &payload, &signature));
+    assert!(verify_with_keys(&keys, &payload, &format!("sha256={}", signature)));
+    assert!(!verify_with_keys(&keys[..1], &payload, &signature));
+    assert!(!verify_with_keys(&keys, &payload, "not-hex"));
+  }
+
+  #[test]
+  fn rejects_stale_timestamps() {
+    assert!(check_timestamp("1000", 1200, 300).is_ok());
+    assert!(check_timestamp("1000", 1400, 300).is_err());
+    assert!(check_timestamp("1400", 1000, 300).is_err());
+    assert!(check_timestamp("abc", 1000, 300).is_err());
+  }
+
+  #[test]
+  fn matches_path_segments_and_rejects_replays() {
+    let policy = SignaturePolicy {
+      enabled: true,
+      paths: vec!["/admin".to_string(), "/hooks/".to_string()],
+      ..Default::default()
+    };
+    assert!(policy.applies_to("/admin"));
+    assert!(policy.applies_to("/admin/users"));
+    assert!(!policy.applies_to("/administrator"));
+    assert!(policy.applies_to("/hooks/github"));
+    assert!(!policy.applies_to("/hookshot"));
+    let mut seen = Seen::default();
+    assert!(first_use(&mut seen, "abc", 1000, 1300));
+    assert!(!first_use(&mut seen, "abc", 1200, 1500));
+    assert!(first_use(&mut seen, "abc", 1301, 1601));
+  }
+
+  #[test]
+  fn rejects_unsigned_upgrades_to_signed_paths() {
+    let policy = SignaturePolicy {
+      enabled: true,
+      paths: vec!["/live".to_string()],
+      ..Default::default()
+    };
+    let upgrade = |path: &str| {
+      actix_web::test::TestRequest::get()
+        .uri(path)
+        .insert_header((actix_web::http::header::UPGRADE, "websocket"))
+        .to_http_request()
+    };
+    assert!(verify_handshake("shop", &policy, "/live/feed", &upgrade("/live/feed")).is_err());
+    assert!(verify_handshake("shop", &policy, "/chat", &upgrade("/chat")).is_ok());
+  }
+}
@@ -0,0 +1,125 @@
+use crate::config::{data_dir, product_entry, ProductConfig};
+use crate::worker_util::{run_tool, tool_flags};
+use deno_core::error::{generic_error, AnyError};
+use serde::{Deserialize, Serialize};
+use service::tools::bundle::{analyze_bundle, BundleAnalysis};
+use std::path::PathBuf;
+
+///体积预算 cool.json 中的 size_budget 配置
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SizeBudget {
+  pub max_bundle_bytes: Option<u64>, //打包后总大小上限
+  pub max_group_bytes: Option<u64>,  //单个依赖包大小上限
+  pub fail_on_exceed: bool,          //超出预算时阻止生产部署 否则只告警
+}
+
+///体积分析报告
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SizeReport {
+  pub product_code: String,
+  pub budget: SizeBudget,
+  pub analysis: BundleAnalysis,
+  pub violations: Vec<String>,
+  pub blocked: bool,
+}
+
+///最近一次报告的保存位置 data/reports/{product_code}/bundle.json
+pub fn report_path(product_code: &str) -> PathBuf {
+  let mut path = data_dir();
+  path.push("reports");
+  path.push(product_code);
+  path.push("bundle.json");
+  path
+}
+
+fn check_budget(budget: &SizeBudget, analysis: &BundleAnalysis) -> Vec<String> {
+  let mut violations = vec![];
+  let total = analysis.bundle_bytes.unwrap_or(analysis.source_bytes);
+  if let Some(max) = budget.max_bundle_bytes {
+    if total > max {
+      violations.push(format!("bundle is {} bytes, budget is {} bytes", total, max));
+    }
+  }
+  if let Some(max) = budget.max_group_bytes {
+    for group in analysis.groups.iter().filter(|g| g.bytes > max) {
+      violations.push(format!("{} is {} bytes, budget is {} bytes", group.group, group.bytes, max));
+    }
+  }
+  violations
+}
+
+///打包产品并对照预算生成报告 同时保存为 json 供下载
+pub async fn analyze_product(product_code: &str) -> Result<SizeReport, AnyError> {
+  let config = ProductConfig::load(product_code)?;
+  let entry = product_entry(product_code);
+  let analysis = run_tool(format!("product-{}-bundle", product_code), move || async move {
+    analyze_bundle(tool_flags("bundle", &entry)?).await
+  })
+  .await?;
+  let violations = check_budget(&config.size_budget, &analysis);
+  let report = SizeReport {
+    product_code: product_code.to_string(),
+    blocked: config.size_budget.fail_on_exceed && !violations.is_empty(),
+    budget: config.size_budget,
+    analysis,
+    violations,
+  };
+  let path = report_path(product_code);
+  tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+  tokio::fs::write(&path, serde_json::to_vec_pretty(&report)?).await?;
+  Ok(report)
+}
+
+///生产部署前的体积检查 配置了预算才会执行
+pub async fn ensure_deployable(product_code: &str) -> Result<(), AnyError> {
+  let config = ProductConfig::load(product_code)?;
+  let budget = &config.size_budget;
+  if budget.max_bundle_bytes.is_none() && budget.max_group_bytes.is_none() {
+    return Ok(());
+  }
+  let report = analyze_product(product_code).await?;
+  if report.blocked {
+    return Err(generic_error(format!("deploy blocked by size budget: {}", report.violations.join("; "))));
+  }
+  if !report.violations.is_empty() {
+    log::warn!("{} exceeds size budget: {}", product_code, report.violations.join("; "));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use service::tools::bundle::GroupSize;
+
+  #[test]
+  fn reports_budget_violations() {
+    let group = |group: &str, bytes: u64| GroupSize {
+      group: group.to_string(),
+      bytes,
+      modules: 1,
+    };
+    let analysis = BundleAnalysis {
+      bundle_bytes: Some(900),
+      source_bytes: 1500,
+      modules: vec![],
+      groups: vec![group("npm:lodash", 600), group("local", 300)],
+    };
+    let budget = SizeBudget {
+      max_bundle_bytes: Some(1000),
+      max_group_bytes: Some(500),
+      fail_on_exceed: true,
+    };
+    assert_eq!(
+      check_budget(&budget, &analysis),
+      vec!["npm:lodash is 600 bytes, budget is 500 bytes".to_string()]
+    );
+    let analysis = BundleAnalysis {
+      bundle_bytes: None,
+      ..analysis
+    };
+    assert_eq!(check_budget(&budget, &analysis).len(), 2);
+    assert!(check_budget(&SizeBudget::default(), &analysis).is_empty());
+  }
+}
@@ -0,0 +1,138 @@
+use crate::worker_util::{ScriptWorkerId, WorkerPort, PORT_TABLE};
+use awc::http::Method;
+use awc::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+///冒烟测试 cool.json 中的 smoke_tests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmokeTest {
+  pub name: String,
+  #[serde(default = "default_method")]
+  pub method: String,
+  pub path: String,
+  #[serde(default)]
+  pub headers: HashMap<String, String>,
+  #[serde(default)]
+  pub body: Option<String>,
+  #[serde(default = "default_status")]
+  pub expect_status: u16,
+  #[serde(default)]
+  pub expect_body_contains: Option<String>,
+}
+
+fn default_method() -> String {
+  "GET".to_string()
+}
+
+fn default_status() -> u16 {
+  200
+}
+
+///冒烟测试的执行参数
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SmokeOptions {
+  pub startup_grace_ms: u64, //新 runtime 启动后等待多久再开始测试
+  pub timeout_ms: u64,       //单个请求超时
+  pub retries: u32,          //失败重试次数 新 runtime 可能还在加载模块
+}
+
+impl Default for SmokeOptions {
+  fn default() -> Self {
+    Self {
+      startup_grace_ms: 1000,
+      timeout_ms: 5000,
+      retries: 3,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmokeResult {
+  pub name: String,
+  pub passed: bool,
+  pub status: Option<u16>,
+  pub message: Option<String>,
+}
+
+async fn run_once(client: &Client, port: u16, test: &SmokeTest) -> Result<(), (Option<u16>, String)> {
+  let method = Method::from_str(&test.method.to_ascii_uppercase()).map_err(|e| (None, e.to_string()))?;
+  let mut req = client.request(method, format!("http://127.0.0.1:{}{}", port, test.path));
+  for (name, value) in test.headers.iter() {
+    req = req.insert_header((name.as_str(), value.as_str()));
+  }
+  let mut res = match &test.body {
+    Some(body) => req.send_body(body.clone()).await,
+    None => req.send().await,
+  }
+  .map_err(|e| (None, e.to_string()))?;
+  let status = res.status().as_u16();
+  if status != test.expect_status {
+    return Err((Some(status), format!("expected status {}, got {}", test.expect_status, status)));
+  }
+  if let Some(expected) = &test.expect_body_contains {
+    let body = res.body().limit(4 * 1024 * 1024).await.map_err(|e| (Some(status), e.to_string()))?;
+    if !String::from_utf8_lossy(&body).contains(expected.as_str()) {
+      return Err((Some(status), format!("body does not contain {:?}", expected)));
+    }
+  }
+  Ok(())
+}
+
+///对产品当前的端口执行冒烟测试
+pub async fn run_smoke_tests(product_code: &str, tests: &[SmokeTest], options: &SmokeOptions) -> Vec<SmokeResult> {
+  let port = PORT_TABLE
+    .read()
+    .unwrap()
+    .get(&ScriptWorkerId(product_code.to_string()))
+    .and_then(|ports| ports.first())
+    .map(|e| e.port);
+  let WorkerPort(port) = match port {
+    Some(port) => port,
+    None => {
+      return tests
+        .iter()
+        .map(|t| SmokeResult {
+          name: t.name.clone(),
+          passed: false,
+          status: None,
+          message: Some("service not found".to_string()),
+        })
+        .collect();
+    }
+  };
+  tokio::time::sleep(Duration::from_millis(options.startup_grace_ms)).await;
+  let client = Client::builder().timeout(Duration::from_millis(options.timeout_ms)).finish();
+  let mut results = vec![];
+  for test in tests {
+    let mut attempt = 0;
+    let result = loop {
+      match run_once(&client, port, test).await {
+        Ok(_) => break Ok(()),
+        Err(err) if attempt >= options.retries => break Err(err),
+        Err(_) => {
+          attempt += 1;
+          tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+      }
+    };
+    results.push(match result {
+      Ok(_) => SmokeResult {
+        name: test.name.clone(),
+        passed: true,
+        status: Some(test.expect_status),
+        message: None,
+      },
+      Err((status, message)) => SmokeResult {
+        name: test.name.clone(),
+        passed: false,
+        status,
+        message: Some(message),
+      },
+    });
+  }
+  results
+}
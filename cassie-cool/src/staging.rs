@@ -0,0 +1,104 @@
+use crate::audit_log::{self, AuditEvent, AuditKind};
+use crate::config::{module_pins_path, product_dir, storage_dir};
+use crate::util::copy_dir;
+use crate::versions::{self, CURRENT_VERSION};
+use crate::{secrets, state_snapshot, tenants};
+use deno_core::error::{custom_error, generic_error, AnyError};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+///复制产品 /admin/clone 的请求体
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloneRequest {
+  pub source: String,
+  #[serde(default)]
+  pub target: Option<String>, //新产品编码 默认为 prefix 加源产品编码
+  #[serde(default = "default_prefix")]
+  pub prefix: String,
+  #[serde(default)]
+  pub version: Option<String>, //复制哪个版本的代码 默认最新部署的版本 current 为正在编辑的代码
+  #[serde(default)]
+  pub include_data: bool, //同时复制 KV 存储
+}
+
+fn default_prefix() -> String {
+  "staging-".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CloneReport {
+  pub source: String,
+  pub target: String,
+  pub version: String,              //复制的源产品版本
+  pub storage: Vec<String>,         //复制的存储文件 不复制数据时为空
+  pub owner: Option<String>,        //与源产品相同的租户
+  pub missing_secrets: Vec<String>, //密钥不会复制 需要为新产品单独设置
+}
+
+impl CloneRequest {
+  pub fn target(&self) -> String {
+    self.target.clone().unwrap_or_else(|| format!("{}{}", self.prefix, self.source))
+  }
+}
+
+///复制产品的代码 配置和可选的数据到新的产品编码下 用于从生产环境快速创建预发环境<br>
+/// 密钥和部署记录不会复制 新产品不会自动启动
+pub async fn clone_product(request: &CloneRequest) -> Result<CloneReport, AnyError> {
+  let source = request.source.as_str();
+  let target = request.target();
+  //源和目标都来自请求体 访问目录前确认不会跳出产品目录 版本号由 version_dir 校验
+  if !tenants::valid_code(source) || !tenants::valid_code(&target) {
+    return Err(generic_error("product code may only contain lowercase letters, digits, - and _"));
+  }
+  if !product_dir(source).is_dir() {
+    return Err(custom_error("NotFound", format!("product {} not found", source)));
+  }
+  if product_dir(&target).exists() || tenants::product_meta(&target)?.is_some() {
+    return Err(generic_error(format!("product {} already exists", target)));
+  }
+  let version = match &request.version {
+    Some(version) => version.clone(),
+    None => versions::latest_version(source)?.unwrap_or_else(|| CURRENT_VERSION.to_string()),
+  };
+  let from = match version.as_str() {
+    CURRENT_VERSION => product_dir(source),
+    _ => versions::version_dir(source, &version)?,
+  };
+  if !from.is_dir() {
+    return Err(custom_error("NotFound", format!("version {} not found", version)));
+  }
+  //cool.json 在代码目录中 随代码一起复制 加密的文件原样复制
+  let to = product_dir(&target);
+  tokio::task::spawn_blocking(move || copy_dir(&from, &to)).await??;
+  if module_pins_path(source).exists() {
+    tokio::fs::create_dir_all(module_pins_path(&target).parent().unwrap()).await?;
+    tokio::fs::copy(module_pins_path(source), module_pins_path(&target)).await?;
+  }
+  versions::snapshot(&target).await?;
+  let storage = match request.include_data {
+    true => {
+      let (code, dir) = (source.to_string(), storage_dir(&target));
+      tokio::task::spawn_blocking(move || state_snapshot::copy_storage(&code, &dir)).await??
+    }
+    false => vec![],
+  };
+  let owner = match tenants::product_meta(source)? {
+    Some(meta) => Some(tenants::assign_owner(&target, &meta.owner, false)?.owner),
+    None => None,
+  };
+  let missing_secrets = secrets::list_secrets(source)?.into_iter().map(|s| s.name).collect();
+  let report = CloneReport {
+    source: source.to_string(),
+    target,
+    version,
+    storage,
+    owner,
+    missing_secrets,
+  };
+  audit_log::record(&AuditEvent::new(
+    AuditKind::ProductCloned,
+    &report.target,
+    json!({"source": report.source, "version": report.version, "storage": report.storage}),
+  ))?;
+  Ok(report)
+}
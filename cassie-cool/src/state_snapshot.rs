@@ -0,0 +1,255 @@
+use crate::artifacts::{self, download_url};
+use crate::audit_log::{self, AuditEvent, AuditKind};
+use crate::config::{data_dir, product_dir, storage_dir};
+use crate::sandbox::{product_scratch_dir, seed_dir};
+use crate::tenants;
+use crate::util::{copy_dir, list_dir, now_millis};
+use crate::worker_util::{ScriptWorkerId, WORKER_TABLE};
+use deno_core::error::{custom_error, generic_error, AnyError};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rusqlite::{Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+///快照在 artifacts 中的类型 data/artifacts/state/{product_code}-{created_at}.tar.gz
+pub const ARTIFACT_KIND: &str = "state";
+const MANIFEST: &str = "manifest.json";
+const SQLITE_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+///快照内容说明 打包在 manifest.json 中
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateManifest {
+  pub product_code: String,
+  pub created_at: u64,
+  pub storage: Vec<String>, //storage/ 下的文件 KV 数据库等
+  pub workers: Vec<String>, //scratch/ 下各 runtime 的临时目录
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateSnapshot {
+  pub name: String,
+  pub url: String, //下载链接
+  pub size: u64,
+  pub manifest: StateManifest,
+}
+
+///快照列表中的一项
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotFile {
+  pub name: String,
+  pub url: String,
+  pub size: u64,
+  pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestoreReport {
+  pub snapshot: String,
+  pub source: String, //快照来自的产品
+  pub target: String,
+  pub storage: Vec<String>,
+  pub worker: Option<String>, //作为新 runtime 临时目录初始内容的 runtime
+}
+
+///快照和恢复过程中的临时目录 data/tmp/{name}
+fn work_dir(name: &str) -> PathBuf {
+  data_dir().join("tmp").join(format!("{}-{}", name, uuid::Uuid::new_v4()))
+}
+
+fn is_sqlite(path: &Path) -> bool {
+  let mut header = [0u8; 16];
+  std::fs::File::open(path).and_then(|mut f| f.read_exact(&mut header)).is_ok() && &header == SQLITE_HEADER
+}
+
+///运行中的数据库不能直接复制 用 VACUUM INTO 得到一致的副本 包含 WAL 中已提交的数据
+fn copy_sqlite(from: &Path, to: &Path) -> Result<(), AnyError> {
+  let conn = Connection::open_with_flags(from, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+  conn.busy_timeout(Duration::from_secs(5))?;
+  conn.execute("VACUUM INTO ?1", [to.to_string_lossy().to_string()])?;
+  Ok(())
+}
+
+///复制产品的持久化存储到 to 目录 返回复制的文件名<br>
+/// 产品不需要停止 数据库通过只读连接复制
+pub fn copy_storage(product_code: &str, to: &Path) -> Result<Vec<String>, AnyError> {
+  std::fs::create_dir_all(to)?;
+  let mut files = vec![];
+  for (name, path) in list_dir(storage_dir(product_code))? {
+    //WAL 和回滚日志已经包含在 VACUUM INTO 的副本中
+    if !path.is_file() || ["-wal", "-shm", "-journal"].iter().any(|s| name.ends_with(s)) {
+      continue;
+    }
+    match is_sqlite(&path) {
+      true => copy_sqlite(&path, &to.join(&name))?,
+      false => {
+        std::fs::copy(&path, to.join(&name))?;
+      }
+    }
+    files.push(name);
+  }
+  Ok(files)
+}
+
+fn build(product_code: &str, work: &Path) -> Result<(Vec<u8>, StateManifest), AnyError> {
+  let storage = work.join("storage");
+  let mut manifest = StateManifest {
+    product_code: product_code.to_string(),
+    created_at: now_millis(),
+    storage: copy_storage(product_code, &storage)?,
+    workers: vec![],
+  };
+  let mut tar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+  tar.append_dir_all("storage", &storage)?;
+  for (worker, path) in list_dir(product_scratch_dir(product_code))? {
+    if !path.is_dir() {
+      continue;
+    }
+    tar.append_dir_all(format!("scratch/{}", worker), &path)?;
+    manifest.workers.push(worker);
+  }
+  let json = serde_json::to_vec_pretty(&manifest)?;
+  let mut header = tar::Header::new_gnu();
+  header.set_size(json.len() as u64);
+  header.set_mode(0o644);
+  header.set_mtime(manifest.created_at / 1000);
+  header.set_cksum();
+  tar.append_data(&mut header, MANIFEST, json.as_slice())?;
+  let bytes = tar.into_inner()?.finish()?;
+  Ok((bytes, manifest))
+}
+
+///把产品的 KV 存储和各 runtime 的临时目录打包为 artifact 产品不需要停止
+pub async fn snapshot(product_code: &str) -> Result<StateSnapshot, AnyError> {
+  if !tenants::valid_code(product_code) {
+    return Err(generic_error("product code may only contain lowercase letters, digits, - and _"));
+  }
+  if !product_dir(product_code).is_dir() {
+    return Err(custom_error("NotFound", format!("product {} not found", product_code)));
+  }
+  let code = product_code.to_string();
+  let work = work_dir("snapshot");
+  let dir = work.clone();
+  let built = tokio::task::spawn_blocking(move || build(&code, &dir)).await?;
+  let _ = tokio::fs::remove_dir_all(&work).await;
+  let (bytes, manifest) = built?;
+  let name = format!("{}-{}.tar.gz", product_code, manifest.created_at);
+  let size = bytes.len() as u64;
+  let url = artifacts::put_artifact(ARTIFACT_KIND, &name, bytes).await?;
+  audit_log::record(&AuditEvent::new(
+    AuditKind::StateSnapshot,
+    product_code,
+    json!({"snapshot": name, "storage": manifest.storage, "workers": manifest.workers, "bytes": size}),
+  ))?;
+  Ok(StateSnapshot { name, url, size, manifest })
+}
+
+///产品的状态快照 新的在前
+pub async fn list(product_code: &str) -> Result<Vec<SnapshotFile>, AnyError> {
+  let prefix = format!("{}-", product_code);
+  let mut files = vec![];
+  for object in artifacts::list_artifacts(Some(ARTIFACT_KIND)).await? {
+    let name = object.key.rsplit('/').next().unwrap_or_default().to_string();
+    //名称中剩下的部分只有时间戳 避免 shop 匹配到 shop-eu 的快照
+    let created_at = match name
+      .strip_prefix(&prefix)
+      .and_then(|rest| rest.strip_suffix(".tar.gz"))
+      .and_then(|ts| ts.parse().ok())
+    {
+      Some(created_at) => created_at,
+      None => continue,
+    };
+    files.push(SnapshotFile {
+      url: download_url(ARTIFACT_KIND, &name),
+      size: object.size,
+      name,
+      created_at,
+    });
+  }
+  files.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+  Ok(files)
+}
+
+///用 from 替换 to 目录 from 不存在时 to 为空目录
+fn replace_dir(from: &Path, to: &Path) -> Result<(), AnyError> {
+  match std::fs::remove_dir_all(to) {
+    Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+    _ => {}
+  }
+  match from.is_dir() {
+    true => copy_dir(from, to).map(|_| ())?,
+    false => std::fs::create_dir_all(to)?,
+  }
+  Ok(())
+}
+
+fn unpack(bytes: &[u8], work: &Path, name: &str, target: &str, worker: Option<String>) -> Result<RestoreReport, AnyError> {
+  tar::Archive::new(GzDecoder::new(bytes)).unpack(work)?;
+  let manifest: StateManifest = serde_json::from_slice(&std::fs::read(work.join(MANIFEST))?)?;
+  if manifest.product_code == target {
+    return Err(custom_error(
+      "Forbidden",
+      format!("state of {} can only be restored into another (staging) product", target),
+    ));
+  }
+  let worker = match worker {
+    Some(worker) if manifest.workers.contains(&worker) => Some(worker),
+    Some(worker) => {
+      return Err(custom_error(
+        "NotFound",
+        format!("snapshot {} has no scratch directory of {}", name, worker),
+      ))
+    }
+    None => manifest.workers.first().cloned(),
+  };
+  replace_dir(&work.join("storage"), &storage_dir(target))?;
+  //临时目录属于单个 runtime 作为目标产品之后启动的每个 runtime 的初始内容
+  match &worker {
+    Some(worker) => replace_dir(&work.join("scratch").join(worker), &seed_dir(target))?,
+    None => match std::fs::remove_dir_all(seed_dir(target)) {
+      Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+      _ => {}
+    },
+  }
+  Ok(RestoreReport {
+    snapshot: name.to_string(),
+    source: manifest.product_code,
+    target: target.to_string(),
+    storage: manifest.storage,
+    worker,
+  })
+}
+
+///把快照恢复到另一个已停止的产品 用于在预发环境复现和状态有关的问题<br>
+/// 目标产品原有的 KV 存储会被替换 worker 为空时使用快照中的第一个临时目录
+pub async fn restore(name: &str, target: &str, worker: Option<String>) -> Result<RestoreReport, AnyError> {
+  //target 来自请求体 替换目录前确认不会跳出产品目录
+  if !tenants::valid_code(target) {
+    return Err(generic_error("product code may only contain lowercase letters, digits, - and _"));
+  }
+  if !product_dir(target).is_dir() {
+    return Err(custom_error("NotFound", format!("product {} not found", target)));
+  }
+  //运行中的 runtime 持有数据库连接 替换文件会损坏数据
+  if WORKER_TABLE.lock().unwrap().contains_key(&ScriptWorkerId(target.to_string())) {
+    return Err(generic_error(format!("stop product {} before restoring state into it", target)));
+  }
+  let bytes = artifacts::read_artifact(ARTIFACT_KIND, name)
+    .await
+    .map_err(|_| custom_error("NotFound", format!("state snapshot {} not found", name)))?;
+  let work = work_dir("restore");
+  let (dir, snapshot, product) = (work.clone(), name.to_string(), target.to_string());
+  let report = tokio::task::spawn_blocking(move || unpack(&bytes, &dir, &snapshot, &product, worker)).await;
+  let _ = tokio::fs::remove_dir_all(&work).await;
+  let report = report??;
+  audit_log::record(&AuditEvent::new(
+    AuditKind::StateRestored,
+    target,
+    json!({"snapshot": report.snapshot, "source": report.source, "worker": report.worker}),
+  ))?;
+  Ok(report)
+}
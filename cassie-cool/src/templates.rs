@@ -0,0 +1,175 @@
+use crate::config::product_dir;
+use crate::encryption;
+use deno_core::error::{custom_error, generic_error, AnyError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Component, Path};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateVar {
+  pub name: String,
+  pub description: String,
+  pub default: Option<String>, //没有默认值的变量必须传入
+}
+
+///文件模板 path 和 content 中的 {{变量}} 在插入时替换
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileTemplate {
+  pub id: String,
+  pub name: String,
+  pub description: String,
+  pub path: String, //默认的文件路径 相对产品目录 用 / 分隔
+  pub variables: Vec<TemplateVar>,
+  pub content: String,
+}
+
+///按模板新建文件
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InsertTemplate {
+  pub template: String,
+  pub path: Option<String>, //为空时使用模板的默认路径
+  #[serde(default)]
+  pub variables: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InsertedFile {
+  pub path: String,
+  pub content: String,
+}
+
+const ROUTE_HANDLER: &str = r#"import { Router } from "https://deno.land/x/oak@v12.5.0/mod.ts";
+
+export const {{name}}Router = new Router();
+
+{{name}}Router.get("{{route}}", (ctx) => {
+  ctx.response.body = { ok: true };
+});
+
+{{name}}Router.post("{{route}}", async (ctx) => {
+  const body = await ctx.request.body({ type: "json" }).value;
+  ctx.response.status = 201;
+  ctx.response.body = body;
+});
+"#;
+
+const CRON_JOB: &str = r#"// 在 cool.json 的 entries 中添加:
+// { "name": "{{name}}", "role": "cron-handler", "entry": "jobs/{{name}}.ts", "schedule": "{{schedule}}" }
+// 每次执行结束后进程退出 不要在这里启动常驻服务
+
+async function run() {
+  console.log(`{{name}} started at ${new Date().toISOString()}`);
+}
+
+await run();
+"#;
+
+const TEST_FILE: &str = r#"import { assertEquals } from "https://deno.land/std@0.190.0/testing/asserts.ts";
+
+Deno.test("{{name}}", async (t) => {
+  await t.step("works", () => {
+    assertEquals(1 + 1, 2);
+  });
+});
+"#;
+
+fn var(name: &str, description: &str, default: Option<&str>) -> TemplateVar {
+  TemplateVar {
+    name: name.to_string(),
+    description: description.to_string(),
+    default: default.map(|d| d.to_string()),
+  }
+}
+
+///内置的模板
+pub fn list() -> Vec<FileTemplate> {
+  vec![
+    FileTemplate {
+      id: "route-handler".to_string(),
+      name: "Route handler".to_string(),
+      description: "oak router with GET and POST handlers".to_string(),
+      path: "routes/{{name}}.ts".to_string(),
+      variables: vec![var("name", "router name", None), var("route", "request path", Some("/"))],
+      content: ROUTE_HANDLER.to_string(),
+    },
+    FileTemplate {
+      id: "cron-job".to_string(),
+      name: "Cron job".to_string(),
+      description: "entry for a cron-handler role".to_string(),
+      path: "jobs/{{name}}.ts".to_string(),
+      variables: vec![
+        var("name", "job name", None),
+        var("schedule", "cron expression with seconds", Some("0 */5 * * * *")),
+      ],
+      content: CRON_JOB.to_string(),
+    },
+    FileTemplate {
+      id: "test-file".to_string(),
+      name: "Test file".to_string(),
+      description: "Deno.test with a first step".to_string(),
+      path: "tests/{{name}}_test.ts".to_string(),
+      variables: vec![var("name", "test name", None)],
+      content: TEST_FILE.to_string(),
+    },
+  ]
+}
+
+///替换 {{变量}} 没有传入也没有默认值的变量返回错误
+pub fn render(text: &str, variables: &BTreeMap<String, String>) -> Result<String, AnyError> {
+  let mut out = String::new();
+  let mut rest = text;
+  while let Some(start) = rest.find("{{") {
+    let end = rest[start..].find("}}").ok_or_else(|| generic_error("unclosed {{ in template"))? + start;
+    let name = rest[start + 2..end].trim();
+    let value = variables.get(name).ok_or_else(|| generic_error(format!("missing variable {}", name)))?;
+    out.push_str(&rest[..start]);
+    out.push_str(value);
+    rest = &rest[end + 2..];
+  }
+  out.push_str(rest);
+  Ok(out)
+}
+
+///按模板在产品目录下新建文件 文件已存在时不覆盖
+pub async fn insert(product_code: &str, request: &InsertTemplate) -> Result<InsertedFile, AnyError> {
+  let template = list()
+    .into_iter()
+    .find(|t| t.id == request.template)
+    .ok_or_else(|| custom_error("NotFound", format!("template {} not found", request.template)))?;
+  let mut variables = request.variables.clone();
+  for v in &template.variables {
+    if let Some(default) = &v.default {
+      variables.entry(v.name.clone()).or_insert_with(|| default.clone());
+    }
+  }
+  let path = render(request.path.as_ref().unwrap_or(&template.path), &variables)?;
+  if path.is_empty() || !Path::new(&path).components().all(|c| matches!(c, Component::Normal(_))) {
+    return Err(generic_error(format!("invalid path {}", path)));
+  }
+  let content = render(&template.content, &variables)?;
+  let root = product_dir(product_code);
+  if !root.is_dir() {
+    return Err(custom_error("NotFound", format!("product {} not found", product_code)));
+  }
+  let target = root.join(&path);
+  if target.exists() {
+    return Err(generic_error(format!("{} already exists", path)));
+  }
+  tokio::fs::create_dir_all(target.parent().unwrap()).await?;
+  encryption::write(&target, &content).await?;
+  Ok(InsertedFile { path, content })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn renders_variables() {
+    let variables = BTreeMap::from([("name".to_string(), "books".to_string())]);
+    assert_eq!(render("routes/{{name}}.ts", &variables).unwrap(), "routes/books.ts");
+    assert_eq!(render("{{ name }}Router", &variables).unwrap(), "booksRouter");
+    assert!(render("{{route}}", &variables).is_err());
+    assert!(render("{{name", &variables).is_err());
+  }
+}
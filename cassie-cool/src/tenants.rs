@@ -0,0 +1,270 @@
+use crate::auth;
+use crate::config::{data_dir, product_dir};
+use crate::list_query::ListSpec;
+use crate::util::{now_millis, read_json, write_json};
+use actix_web::HttpRequest;
+use deno_core::error::{custom_error, generic_error, get_custom_error_class, AnyError};
+use lazy_static::lazy_static;
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+///租户配额
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TenantQuota {
+  pub max_products: usize, //最多可以创建的产品数
+}
+
+impl Default for TenantQuota {
+  fn default() -> Self {
+    Self { max_products: 3 }
+  }
+}
+
+///租户 通过 api key 访问 /tenant 下的接口 只保存 key 的 sha256
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tenant {
+  pub id: String,
+  pub name: String,
+  #[serde(default)]
+  pub quota: TenantQuota,
+  pub created_at: u64,
+  #[serde(skip_serializing_if = "String::is_empty", default)]
+  api_key_hash: String,
+}
+
+impl Tenant {
+  ///对外展示时不带 key 的摘要
+  pub fn public(&self) -> Self {
+    Self {
+      api_key_hash: String::new(),
+      ..self.clone()
+    }
+  }
+}
+
+///产品归属 data/products.json
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProductMeta {
+  pub product_code: String,
+  pub owner: String, //租户 id
+  #[serde(default)]
+  pub description: Option<String>,
+  pub created_at: u64,
+}
+
+///租户产品列表的排序
+pub const LIST_SPEC: ListSpec = ListSpec {
+  id: "product_code",
+  sort: &["product_code", "created_at"],
+  default_sort: "product_code",
+  status: None,
+  tag: None,
+  owner: None,
+};
+
+lazy_static! {
+  //读改写整个文件 同一时间只允许一个修改
+  static ref STORE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+fn tenants_path() -> PathBuf {
+  data_dir().join("tenants.json")
+}
+
+fn products_path() -> PathBuf {
+  data_dir().join("products.json")
+}
+
+fn hash_key(api_key: &str) -> String {
+  hex::encode(digest::digest(&digest::SHA256, api_key.as_bytes()))
+}
+
+///产品编码只能包含小写字母 数字 - 和 _
+pub fn valid_code(code: &str) -> bool {
+  !code.is_empty() && code.len() <= 64 && code.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+pub fn list_tenants() -> Result<Vec<Tenant>, AnyError> {
+  let tenants: Vec<Tenant> = read_json(tenants_path())?;
+  Ok(tenants.iter().map(Tenant::public).collect())
+}
+
+///创建租户 返回租户和 api key key 只在这里返回一次 dry_run 时不保存
+pub fn create_tenant(name: &str, quota: TenantQuota, dry_run: bool) -> Result<(Tenant, String), AnyError> {
+  if name.trim().is_empty() {
+    return Err(generic_error("tenant name is required"));
+  }
+  let mut bytes = [0u8; 32];
+  SystemRandom::new()
+    .fill(&mut bytes)
+    .map_err(|_| generic_error("failed to generate api key"))?;
+  let api_key = format!("cool_{}", hex::encode(bytes));
+  let tenant = Tenant {
+    id: uuid::Uuid::new_v4().to_string(),
+    name: name.trim().to_string(),
+    quota,
+    created_at: now_millis(),
+    api_key_hash: hash_key(&api_key),
+  };
+  let _lock = STORE_LOCK.lock().unwrap();
+  let mut tenants: Vec<Tenant> = read_json(tenants_path())?;
+  tenants.push(tenant.clone());
+  if !dry_run {
+    write_json(tenants_path(), &tenants)?;
+  }
+  Ok((tenant.public(), api_key))
+}
+
+///按请求头 Authorization: Bearer {api_key} 识别租户 没有 api key 时使用绑定了租户的控制台用户的会话
+pub fn authenticate(req: &HttpRequest) -> Result<Tenant, AnyError> {
+  let tenants: Vec<Tenant> = read_json(tenants_path())?;
+  let api_key = req
+    .headers()
+    .get("authorization")
+    .and_then(|v| v.to_str().ok())
+    .and_then(|v| v.strip_prefix("Bearer "));
+  match api_key {
+    Some(api_key) => {
+      let hash = hash_key(api_key.trim());
+      tenants
+        .into_iter()
+        .find(|t| t.api_key_hash == hash)
+        .ok_or_else(|| custom_error("Unauthorized", "invalid api key"))
+    }
+    None => {
+      let principal = auth::authenticate(req).map_err(|err| match get_custom_error_class(&err) {
+        Some("Unauthorized") => custom_error("Unauthorized", "missing api key"),
+        _ => err,
+      })?;
+      let tenant_id = principal
+        .tenant_id
+        .ok_or_else(|| custom_error("Forbidden", "user is not bound to a tenant"))?;
+      tenants
+        .into_iter()
+        .find(|t| t.id == tenant_id)
+        .ok_or_else(|| custom_error("Unauthorized", "tenant no longer exists"))
+    }
+  }
+}
+
+pub fn product_meta(product_code: &str) -> Result<Option<ProductMeta>, AnyError> {
+  let products: BTreeMap<String, ProductMeta> = read_json(products_path())?;
+  Ok(products.get(product_code).cloned())
+}
+
+///租户自己的产品
+pub fn owned_products(tenant_id: &str) -> Result<Vec<ProductMeta>, AnyError> {
+  let products: BTreeMap<String, ProductMeta> = read_json(products_path())?;
+  Ok(products.into_values().filter(|p| p.owner == tenant_id).collect())
+}
+
+///产品属于该租户时返回归属信息 不属于时与不存在返回相同的错误 避免探测其他租户的产品
+pub fn ensure_owner(tenant: &Tenant, product_code: &str) -> Result<ProductMeta, AnyError> {
+  match product_meta(product_code)? {
+    Some(meta) if meta.owner == tenant.id => Ok(meta),
+    _ => Err(custom_error("NotFound", format!("product {} not found", product_code))),
+  }
+}
+
+///在配额内创建产品 同时创建产品代码目录 dry_run 时只校验
+pub fn create_product(tenant: &Tenant, product_code: &str, description: Option<String>, dry_run: bool) -> Result<ProductMeta, AnyError> {
+  if !valid_code(product_code) {
+    return Err(generic_error("product code may only contain lowercase letters, digits, - and _"));
+  }
+  let _lock = STORE_LOCK.lock().unwrap();
+  let mut products: BTreeMap<String, ProductMeta> = read_json(products_path())?;
+  if products.contains_key(product_code) || product_dir(product_code).exists() {
+    return Err(generic_error(format!("product {} already exists", product_code)));
+  }
+  let owned = products.values().filter(|p| p.owner == tenant.id).count();
+  if owned >= tenant.quota.max_products {
+    return Err(generic_error(format!("product quota of {} reached", tenant.quota.max_products)));
+  }
+  let meta = ProductMeta {
+    product_code: product_code.to_string(),
+    owner: tenant.id.clone(),
+    description,
+    created_at: now_millis(),
+  };
+  if dry_run {
+    return Ok(meta);
+  }
+  std::fs::create_dir_all(product_dir(product_code))?;
+  products.insert(product_code.to_string(), meta.clone());
+  write_json(products_path(), &products)?;
+  Ok(meta)
+}
+
+pub fn update_product(tenant: &Tenant, product_code: &str, description: Option<String>, dry_run: bool) -> Result<ProductMeta, AnyError> {
+  let _lock = STORE_LOCK.lock().unwrap();
+  let mut products: BTreeMap<String, ProductMeta> = read_json(products_path())?;
+  match products.get_mut(product_code) {
+    Some(meta) if meta.owner == tenant.id => {
+      meta.description = description;
+      let meta = meta.clone();
+      if !dry_run {
+        write_json(products_path(), &products)?;
+      }
+      Ok(meta)
+    }
+    _ => Err(custom_error("NotFound", format!("product {} not found", product_code))),
+  }
+}
+
+///删除产品的归属信息和代码 调用方负责先停止 runtime
+pub fn delete_product(tenant: &Tenant, product_code: &str) -> Result<(), AnyError> {
+  let _lock = STORE_LOCK.lock().unwrap();
+  let mut products: BTreeMap<String, ProductMeta> = read_json(products_path())?;
+  match products.get(product_code) {
+    Some(meta) if meta.owner == tenant.id => {
+      products.remove(product_code);
+      write_json(products_path(), &products)?;
+      let dir = product_dir(product_code);
+      if dir.exists() {
+        std::fs::remove_dir_all(dir)?;
+      }
+      Ok(())
+    }
+    _ => Err(custom_error("NotFound", format!("product {} not found", product_code))),
+  }
+}
+
+///管理员指定已有产品的归属 用于迁移租户功能之前创建的产品
+pub fn assign_owner(product_code: &str, tenant_id: &str, dry_run: bool) -> Result<ProductMeta, AnyError> {
+  let tenants: Vec<Tenant> = read_json(tenants_path())?;
+  if !tenants.iter().any(|t| t.id == tenant_id) {
+    return Err(generic_error(format!("tenant {} not found", tenant_id)));
+  }
+  let _lock = STORE_LOCK.lock().unwrap();
+  let mut products: BTreeMap<String, ProductMeta> = read_json(products_path())?;
+  let meta = products.entry(product_code.to_string()).or_insert_with(|| ProductMeta {
+    product_code: product_code.to_string(),
+    owner: tenant_id.to_string(),
+    description: None,
+    created_at: now_millis(),
+  });
+  meta.owner = tenant_id.to_string();
+  let meta = meta.clone();
+  if !dry_run {
+    write_json(products_path(), &products)?;
+  }
+  Ok(meta)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn validates_product_codes() {
+    assert!(valid_code("shop-api_2"));
+    assert!(!valid_code(""));
+    assert!(!valid_code("../etc"));
+    assert!(!valid_code("Shop"));
+  }
+}
@@ -0,0 +1,376 @@
+use crate::auth;
+use crate::config::{product_dir, ProductConfig};
+use crate::pipeline;
+use crate::worker_util::run_tool;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_ws::Message;
+use deno_core::error::{custom_error, AnyError};
+use deno_core::serde_json;
+use deno_core::ModuleSpecifier;
+use futures_util::StreamExt;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use service::args::CliOptions;
+use service::tools::test::{self, format_test_error, SlowestTests, TestEvent, TestResult, TestRunListener, TestStepResult, TestSummary};
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::{broadcast, oneshot};
+
+///一次运行中保留给新连接的消息数 超出后不再保留输出
+const MAX_RUN_MESSAGES: usize = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TestStatus {
+  Ok,
+  Ignored,
+  Failed,
+  Cancelled,
+}
+
+///推送给编辑器的消息 每次运行以 started 开始 以 finished 结束<br>
+/// 类型检查或模块图有错误时只有 error 这次没有运行测试
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum WatchMsg {
+  Started {
+    modules: Vec<String>, //这次运行的测试文件 文件变化后只运行受影响的
+  },
+  Result {
+    module: String,
+    name: String,
+    step_of: Option<String>, //测试步骤所属的测试
+    status: TestStatus,
+    duration_ms: u64,
+    failure: Option<String>,
+  },
+  Output {
+    text: String,
+  },
+  Uncaught {
+    module: String,
+    error: String,
+  },
+  Finished {
+    passed: usize,
+    failed: usize,
+    ignored: usize,
+    filtered_out: usize,
+    duration_ms: u64,
+    slowest: Option<SlowestTests>, //配置了 pipeline.report_slowest 时这次运行最慢的测试
+  },
+  Error {
+    message: String,
+  },
+}
+
+///产品的监听测试 所有连接共享 最后一个连接断开时停止
+struct Watch {
+  clients: usize,
+  run: Vec<String>, //当前这次运行已推送的消息 新连接先收到这些
+  tx: broadcast::Sender<String>,
+  stop: Option<oneshot::Sender<()>>,
+}
+
+lazy_static! {
+  static ref WATCHES: Mutex<HashMap<String, Arc<Mutex<Watch>>>> = Mutex::new(HashMap::new());
+}
+
+fn encode(msg: &WatchMsg) -> String {
+  serde_json::to_string(msg).unwrap()
+}
+
+///产品目录下的相对路径 其他模块保持原样
+pub(crate) fn module_path(root: &Path, specifier: &str) -> String {
+  ModuleSpecifier::parse(specifier)
+    .ok()
+    .and_then(|s| s.to_file_path().ok())
+    .and_then(|p| p.strip_prefix(root).ok().map(|p| p.to_string_lossy().replace('\\', "/")))
+    .unwrap_or_else(|| specifier.to_string())
+}
+
+///把测试事件转换成消息 测试名称在注册时记下
+struct Listener {
+  root: PathBuf,
+  watch: Arc<Mutex<Watch>>,
+  names: Mutex<HashMap<usize, (String, String, Option<String>)>>, //id -> (模块, 名称, 所属的测试)
+  report_slowest: Option<NonZeroUsize>,
+}
+
+impl Listener {
+  fn send(&self, msg: WatchMsg) {
+    let text = encode(&msg);
+    let mut watch = self.watch.lock().unwrap();
+    if matches!(msg, WatchMsg::Started { .. } | WatchMsg::Error { .. }) {
+      watch.run.clear();
+    }
+    if watch.run.len() < MAX_RUN_MESSAGES || !matches!(msg, WatchMsg::Output { .. }) {
+      watch.run.push(text.clone());
+    }
+    let _ = watch.tx.send(text);
+  }
+
+  fn result(&self, id: usize, status: TestStatus, failure: Option<String>, elapsed: u64) -> Option<WatchMsg> {
+    let (module, name, step_of) = self.names.lock().unwrap().get(&id)?.clone();
+    Some(WatchMsg::Result {
+      module,
+      name,
+      step_of,
+      status,
+      duration_ms: elapsed,
+      failure,
+    })
+  }
+
+  fn convert(&self, event: &TestEvent) -> Option<WatchMsg> {
+    match event {
+      TestEvent::Register(description) => {
+        let module = module_path(&self.root, &description.origin);
+        self
+          .names
+          .lock()
+          .unwrap()
+          .insert(description.id, (module, description.name.clone(), None));
+        None
+      }
+      TestEvent::StepRegister(description) => {
+        let module = module_path(&self.root, &description.origin);
+        let entry = (module, description.name.clone(), Some(description.root_name.clone()));
+        self.names.lock().unwrap().insert(description.id, entry);
+        None
+      }
+      TestEvent::Result(id, result, elapsed) => {
+        let (status, failure) = match result {
+          TestResult::Ok => (TestStatus::Ok, None),
+          TestResult::Ignored => (TestStatus::Ignored, None),
+          TestResult::Failed(failure) => (TestStatus::Failed, Some(failure.to_string())),
+          TestResult::Cancelled => (TestStatus::Cancelled, None),
+        };
+        self.result(*id, status, failure, *elapsed)
+      }
+      TestEvent::StepResult(id, result, elapsed) => {
+        let (status, failure) = match result {
+          TestStepResult::Ok => (TestStatus::Ok, None),
+          TestStepResult::Ignored => (TestStatus::Ignored, None),
+          TestStepResult::Failed(failure) => (TestStatus::Failed, Some(failure.to_string())),
+        };
+        self.result(*id, status, failure, *elapsed)
+      }
+      TestEvent::Output(output) => Some(WatchMsg::Output {
+        text: String::from_utf8_lossy(output).into_owned(),
+      }),
+      TestEvent::UncaughtError(origin, error) => Some(WatchMsg::Uncaught {
+        module: module_path(&self.root, origin),
+        error: format_test_error(error),
+      }),
+      TestEvent::Plan(_) | TestEvent::Wait(_) | TestEvent::StepWait(_) | TestEvent::Sigint => None,
+    }
+  }
+}
+
+impl TestRunListener for Listener {
+  fn started(&self, specifiers: &[ModuleSpecifier]) {
+    self.names.lock().unwrap().clear();
+    let modules = specifiers.iter().map(|s| module_path(&self.root, s.as_str())).collect();
+    self.send(WatchMsg::Started { modules });
+  }
+
+  fn event(&self, event: &TestEvent) {
+    if let Some(msg) = self.convert(event) {
+      self.send(msg);
+    }
+  }
+
+  fn finished(&self, summary: &TestSummary, elapsed: &Duration) {
+    self.send(WatchMsg::Finished {
+      passed: summary.passed,
+      failed: summary.failed,
+      ignored: summary.ignored,
+      filtered_out: summary.filtered_out,
+      duration_ms: elapsed.as_millis() as u64,
+      slowest: self.report_slowest.map(|n| summary.slowest(n.get())),
+    });
+  }
+
+  fn failed(&self, error: &AnyError) {
+    self.send(WatchMsg::Error { message: error.to_string() });
+  }
+}
+
+///第一个连接时启动监听 文件变化后重新运行受影响的测试
+fn start(product_code: &str) -> Arc<Mutex<Watch>> {
+  let (tx, _) = broadcast::channel(256);
+  let (stop, stopped) = oneshot::channel();
+  let watch = Arc::new(Mutex::new(Watch {
+    clients: 0,
+    run: vec![],
+    tx,
+    stop: Some(stop),
+  }));
+  let listener = Arc::new(Listener {
+    root: product_dir(product_code),
+    watch: watch.clone(),
+    names: Mutex::new(HashMap::new()),
+    report_slowest: ProductConfig::load(product_code).ok().and_then(|c| c.pipeline.report_slowest),
+  });
+  let code = product_code.to_string();
+  actix_web::rt::spawn(async move {
+    let name = format!("product-{}-test-watch", code);
+    let result = run_tool(name, move || async move {
+      let (mut flags, test_flags) = pipeline::test_flags(&code)?;
+      flags.watch = Some(vec![]);
+      flags.no_clear_screen = true;
+      let cli_options = CliOptions::from_flags(flags)?;
+      let test_options = cli_options.resolve_test_options(test_flags)?;
+      select! {
+        result = test::run_tests_with_watch(cli_options, test_options, Some(listener.clone())) => {
+          if let Err(err) = &result {
+            listener.failed(err);
+          }
+          result
+        }
+        _ = stopped => Ok(()),
+      }
+    })
+    .await;
+    if let Err(err) = result {
+      log::warn!("test watch stopped: {}", err);
+    }
+  });
+  watch
+}
+
+fn join_watch(product_code: &str) -> (Arc<Mutex<Watch>>, Vec<String>, broadcast::Receiver<String>) {
+  let mut watches = WATCHES.lock().unwrap();
+  let watch = watches.entry(product_code.to_string()).or_insert_with(|| start(product_code)).clone();
+  let (run, rx) = {
+    let mut locked = watch.lock().unwrap();
+    locked.clients += 1;
+    (locked.run.clone(), locked.tx.subscribe())
+  };
+  (watch, run, rx)
+}
+
+fn leave(product_code: &str, watch: &Arc<Mutex<Watch>>) {
+  let mut watches = WATCHES.lock().unwrap();
+  let mut locked = watch.lock().unwrap();
+  locked.clients -= 1;
+  if locked.clients > 0 {
+    return;
+  }
+  if let Some(stop) = locked.stop.take() {
+    let _ = stop.send(());
+  }
+  watches.remove(product_code);
+}
+
+///编辑器的监听测试通道 WebSocket<br>
+/// 连接后先收到当前这次运行已有的消息 之后每次运行推送 started result output finished 等消息<br>
+/// 同一产品的连接共用一个监听 最后一个连接断开时停止
+pub async fn join(req: &HttpRequest, payload: web::Payload, product_code: &str) -> Result<HttpResponse, Error> {
+  if !product_dir(product_code).is_dir() {
+    return Ok(auth::error_response(custom_error(
+      "NotFound",
+      format!("product {} not found", product_code),
+    )));
+  }
+  let (response, mut session, mut stream) = actix_ws::handle(req, payload)?;
+  let code = product_code.to_string();
+  let (watch, run, mut rx) = join_watch(&code);
+  actix_web::rt::spawn(async move {
+    for text in run {
+      if session.text(text).await.is_err() {
+        leave(&code, &watch);
+        return;
+      }
+    }
+    loop {
+      select! {
+        msg = stream.next() => {
+          let sent = match msg {
+            Some(Ok(Message::Ping(bytes))) => session.pong(&bytes).await,
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+            Some(Ok(_)) => Ok(()),
+          };
+          if sent.is_err() {
+            break;
+          }
+        }
+        msg = rx.recv() => match msg {
+          Ok(text) => {
+            if session.text(text).await.is_err() {
+              break;
+            }
+          }
+          //输出太多时跳过落后的部分
+          Err(broadcast::error::RecvError::Lagged(_)) => {}
+          Err(broadcast::error::RecvError::Closed) => break,
+        }
+      }
+    }
+    leave(&code, &watch);
+    let _ = session.close(None).await;
+  });
+  Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use service::tools::test::{TestDescription, TestFailure, TestLocation};
+
+  #[test]
+  fn converts_events_for_the_editor() {
+    let root = PathBuf::from("/code/shop");
+    let (tx, _) = broadcast::channel(16);
+    let watch = Arc::new(Mutex::new(Watch {
+      clients: 1,
+      run: vec![encode(&WatchMsg::Error { message: "old".to_string() })],
+      tx,
+      stop: None,
+    }));
+    let listener = Listener {
+      root: root.clone(),
+      watch: watch.clone(),
+      names: Mutex::new(HashMap::new()),
+      report_slowest: None,
+    };
+    let specifier = ModuleSpecifier::from_file_path(root.join("tests/cart_test.ts")).unwrap();
+    listener.started(&[specifier.clone()]);
+    listener.event(&TestEvent::Register(TestDescription {
+      id: 7,
+      name: "adds items".to_string(),
+      ignore: false,
+      only: false,
+      origin: specifier.to_string(),
+      location: TestLocation {
+        file_name: specifier.to_string(),
+        line_number: 1,
+        column_number: 1,
+      },
+    }));
+    listener.event(&TestEvent::Result(7, TestResult::Failed(TestFailure::FailedSteps(1)), 12));
+    let run = watch.lock().unwrap().run.clone();
+    assert_eq!(run.len(), 2);
+    assert_eq!(
+      run[0],
+      encode(&WatchMsg::Started {
+        modules: vec!["tests/cart_test.ts".to_string()]
+      })
+    );
+    let result = WatchMsg::Result {
+      module: "tests/cart_test.ts".to_string(),
+      name: "adds items".to_string(),
+      step_of: None,
+      status: TestStatus::Failed,
+      duration_ms: 12,
+      failure: Some("1 test step failed.".to_string()),
+    };
+    assert_eq!(run[1], encode(&result));
+    assert_eq!(module_path(&root, "https://deno.land/std/assert.ts"), "https://deno.land/std/assert.ts");
+  }
+}
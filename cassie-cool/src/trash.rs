@@ -0,0 +1,161 @@
+use crate::audit_log::{self, AuditEvent, AuditKind};
+use crate::config::{data_dir, product_dir};
+use crate::util::{copy_dir, list_dir, now_millis, read_json, write_json};
+use deno_core::error::{custom_error, generic_error, AnyError};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+
+///没有配置保留期时回收站保留的天数
+pub const DEFAULT_TRASH_DAYS: u64 = 30;
+
+///回收站中的一项 删除的文件或目录 以及重命名时被覆盖的文件
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrashEntry {
+  pub id: String,
+  pub path: String, //原来相对产品目录的路径 用 / 分隔
+  pub is_dir: bool,
+  pub bytes: u64,
+  pub reason: String, //delete 或 rename
+  pub deleted_at: u64,
+}
+
+///产品的回收站 data/trash/{product_code}/{id}/ 下为 entry.json 和移入的 item
+pub fn trash_dir(product_code: &str) -> PathBuf {
+  data_dir().join("trash").join(product_code)
+}
+
+fn entry_dir(product_code: &str, id: &str) -> Result<PathBuf, AnyError> {
+  let dir = trash_dir(product_code).join(id);
+  if uuid::Uuid::parse_str(id).is_err() || !dir.join("entry.json").exists() {
+    return Err(custom_error("NotFound", format!("trash entry {} not found", id)));
+  }
+  Ok(dir)
+}
+
+fn size(path: &Path) -> u64 {
+  WalkDir::new(path)
+    .into_iter()
+    .filter_map(|e| e.ok())
+    .filter_map(|e| e.metadata().ok())
+    .filter(|m| m.is_file())
+    .map(|m| m.len())
+    .sum()
+}
+
+///跨文件系统不能 rename 时复制后删除
+fn move_path(from: &Path, to: &Path) -> Result<(), AnyError> {
+  if std::fs::rename(from, to).is_ok() {
+    return Ok(());
+  }
+  if from.is_dir() {
+    copy_dir(from, to)?;
+    std::fs::remove_dir_all(from)?;
+  } else {
+    std::fs::copy(from, to)?;
+    std::fs::remove_file(from)?;
+  }
+  Ok(())
+}
+
+///把产品目录下的 path 移到回收站 path 不存在时返回空
+pub fn discard(product_code: &str, path: &Path, reason: &str) -> Result<Option<TrashEntry>, AnyError> {
+  let root = product_dir(product_code);
+  let relative = path.strip_prefix(&root).map_err(|_| generic_error("path is outside the product"))?;
+  if relative.as_os_str().is_empty() || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+    return Err(generic_error(format!("invalid path {}", relative.display())));
+  }
+  let metadata = match std::fs::symlink_metadata(path) {
+    Ok(metadata) => metadata,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+    Err(err) => return Err(err.into()),
+  };
+  let entry = TrashEntry {
+    id: uuid::Uuid::new_v4().to_string(),
+    path: relative.iter().map(|p| p.to_string_lossy()).collect::<Vec<_>>().join("/"),
+    is_dir: metadata.is_dir(),
+    bytes: size(path),
+    reason: reason.to_string(),
+    deleted_at: now_millis(),
+  };
+  let dir = trash_dir(product_code).join(&entry.id);
+  std::fs::create_dir_all(&dir)?;
+  move_path(path, &dir.join("item"))?;
+  write_json(dir.join("entry.json"), &entry)?;
+  let detail = json!({ "id": entry.id, "path": entry.path, "reason": entry.reason, "bytes": entry.bytes });
+  audit_log::record(&AuditEvent::new(AuditKind::FileTrashed, product_code, detail))?;
+  Ok(Some(entry))
+}
+
+///回收站中的所有项 最近删除的在前
+pub fn list(product_code: &str) -> Result<Vec<TrashEntry>, AnyError> {
+  let mut entries = vec![];
+  for (_, dir) in list_dir(trash_dir(product_code))? {
+    let path = dir.join("entry.json");
+    if path.exists() {
+      entries.push(read_json::<TrashEntry>(path)?);
+    }
+  }
+  entries.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at));
+  Ok(entries)
+}
+
+///恢复到原来的位置或 to 指定的位置 目标已存在时不覆盖
+pub fn restore(product_code: &str, id: &str, to: Option<&str>) -> Result<TrashEntry, AnyError> {
+  let dir = entry_dir(product_code, id)?;
+  let entry: TrashEntry = read_json(dir.join("entry.json"))?;
+  let target = to.unwrap_or(&entry.path);
+  if target.is_empty() || !Path::new(target).components().all(|c| matches!(c, Component::Normal(_))) {
+    return Err(generic_error(format!("invalid path {}", target)));
+  }
+  let target = product_dir(product_code).join(target);
+  if target.exists() {
+    return Err(generic_error(format!("{} already exists", target.display())));
+  }
+  std::fs::create_dir_all(target.parent().unwrap())?;
+  move_path(&dir.join("item"), &target)?;
+  std::fs::remove_dir_all(&dir)?;
+  let detail = json!({ "id": entry.id, "path": entry.path, "to": to });
+  audit_log::record(&AuditEvent::new(AuditKind::TrashRestored, product_code, detail))?;
+  Ok(entry)
+}
+
+fn remove(product_code: &str, entry: &TrashEntry) -> Result<(), AnyError> {
+  match std::fs::remove_dir_all(trash_dir(product_code).join(&entry.id)) {
+    Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+    _ => Ok(()),
+  }
+}
+
+///永久删除 id 为空时清空回收站
+pub fn purge(product_code: &str, id: Option<&str>) -> Result<Vec<TrashEntry>, AnyError> {
+  let entries = match id {
+    Some(id) => vec![read_json::<TrashEntry>(entry_dir(product_code, id)?.join("entry.json"))?],
+    None => list(product_code)?,
+  };
+  for entry in &entries {
+    remove(product_code, entry)?;
+  }
+  if !entries.is_empty() {
+    let detail = json!({ "ids": entries.iter().map(|e| e.id.clone()).collect::<Vec<_>>() });
+    audit_log::record(&AuditEvent::new(AuditKind::TrashPurged, product_code, detail))?;
+  }
+  Ok(entries)
+}
+
+///有回收站的产品
+pub fn products() -> Result<Vec<String>, AnyError> {
+  Ok(list_dir(data_dir().join("trash"))?.into_iter().map(|(name, _)| name).collect())
+}
+
+///删除早于 cutoff 的项 返回删除的项
+pub fn prune(product_code: &str, cutoff: u64, dry_run: bool) -> Result<Vec<TrashEntry>, AnyError> {
+  let expired: Vec<TrashEntry> = list(product_code)?.into_iter().filter(|e| e.deleted_at < cutoff).collect();
+  if !dry_run {
+    for entry in &expired {
+      remove(product_code, entry)?;
+    }
+  }
+  Ok(expired)
+}
@@ -0,0 +1,206 @@
+use crate::config::product_dir;
+use deno_core::error::AnyError;
+use lazy_static::lazy_static;
+use notify::event::{Event, EventKind};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+use walkdir::WalkDir;
+
+///每个线程一次处理的文件数
+const STAT_BATCH: usize = 512;
+
+///目录树中的一项 key 为相对产品目录的路径 各级用 | 连接
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TreeEntry {
+  pub id: String, //在索引的生命周期内保持不变
+  pub is_dir: bool,
+  pub size: u64,
+  pub created_at: u64,
+  pub modified_at: u64,
+}
+
+///产品目录树的快照 generation 在每次变化后递增
+#[derive(Debug, Clone)]
+pub struct TreeSnapshot {
+  pub generation: u64,
+  pub root: PathBuf,
+  pub entries: Vec<(String, TreeEntry)>,
+}
+
+struct TreeIndex {
+  root: PathBuf,
+  generation: u64,
+  entries: BTreeMap<String, TreeEntry>,
+}
+
+struct Indexed {
+  index: Arc<Mutex<TreeIndex>>,
+  _watcher: RecommendedWatcher, //释放后停止监听
+}
+
+lazy_static! {
+  static ref INDEXES: Mutex<HashMap<String, Indexed>> = Mutex::new(HashMap::new());
+}
+
+fn millis(time: std::io::Result<std::time::SystemTime>) -> u64 {
+  time
+    .ok()
+    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+    .map(|d| d.as_millis() as u64)
+    .unwrap_or(0)
+}
+
+fn key(root: &Path, path: &Path) -> Option<String> {
+  let relative = path.strip_prefix(root).ok()?;
+  let parts: Vec<String> = relative.iter().map(|p| p.to_string_lossy().to_string()).collect();
+  match parts.is_empty() {
+    true => None,
+    false => Some(parts.join("|")),
+  }
+}
+
+fn stat(path: &Path, id: Option<String>) -> Option<TreeEntry> {
+  let metadata = std::fs::metadata(path).ok()?;
+  Some(TreeEntry {
+    id: id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+    is_dir: metadata.is_dir(),
+    size: if metadata.is_dir() { 0 } else { metadata.len() },
+    created_at: millis(metadata.created()),
+    modified_at: millis(metadata.modified()),
+  })
+}
+
+///分批并行执行 f 结果顺序与输入相同 大目录树的 stat 和读取都用它
+pub fn batched<T, R, F>(items: Vec<T>, f: F) -> Vec<R>
+where
+  T: Send,
+  R: Send,
+  F: Fn(T) -> R + Sync,
+{
+  if items.len() <= STAT_BATCH {
+    return items.into_iter().map(f).collect();
+  }
+  let mut batches = vec![];
+  let mut items = items.into_iter().peekable();
+  while items.peek().is_some() {
+    batches.push(items.by_ref().take(STAT_BATCH).collect::<Vec<T>>());
+  }
+  let f = &f;
+  std::thread::scope(|scope| {
+    let handles: Vec<_> = batches
+      .into_iter()
+      .map(|batch| scope.spawn(move || batch.into_iter().map(f).collect::<Vec<R>>()))
+      .collect();
+    handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+  })
+}
+
+///遍历 dir 并分批 stat
+fn scan(root: &Path, dir: &Path) -> Vec<(String, TreeEntry)> {
+  let paths: Vec<PathBuf> = WalkDir::new(dir)
+    .follow_links(true)
+    .into_iter()
+    .filter_map(|e| e.ok())
+    .map(|e| e.into_path())
+    .collect();
+  batched(paths, |path| key(root, &path).zip(stat(&path, None)))
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+impl TreeIndex {
+  ///按文件变化更新索引 已有的项保留 id
+  fn apply(&mut self, paths: &[PathBuf]) {
+    for path in paths {
+      let key = match key(&self.root, path) {
+        Some(key) => key,
+        None => continue,
+      };
+      match stat(path, self.entries.get(&key).map(|e| e.id.clone())) {
+        Some(entry) => {
+          //移动进来的目录 下面的文件不会逐个产生事件
+          if entry.is_dir {
+            for (k, e) in scan(&self.root, path) {
+              self.entries.entry(k).or_insert(e);
+            }
+          }
+          self.entries.insert(key, entry);
+        }
+        None => {
+          let prefix = format!("{}|", key);
+          self.entries.retain(|k, _| k != &key && !k.starts_with(&prefix));
+        }
+      }
+    }
+    self.generation += 1;
+  }
+}
+
+fn build(product_code: &str) -> Result<Indexed, AnyError> {
+  let dir = product_dir(product_code);
+  std::fs::create_dir_all(&dir)?;
+  let root = dir.canonicalize()?;
+  let index = Arc::new(Mutex::new(TreeIndex {
+    root: root.clone(),
+    generation: 1,
+    entries: BTreeMap::new(),
+  }));
+  let target = index.clone();
+  let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+    if let Ok(event) = res {
+      if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)) {
+        target.lock().unwrap().apply(&event.paths);
+      }
+    }
+  })?;
+  //先监听再遍历 遍历期间的变化不会丢失
+  watcher.watch(&root, RecursiveMode::Recursive)?;
+  let entries = scan(&root, &root);
+  let mut locked = index.lock().unwrap();
+  for (k, e) in entries {
+    locked.entries.entry(k).or_insert(e);
+  }
+  drop(locked);
+  Ok(Indexed { index, _watcher: watcher })
+}
+
+///产品的目录树 第一次访问时建立索引 之后由文件监听更新 会阻塞
+pub fn snapshot(product_code: &str) -> Result<TreeSnapshot, AnyError> {
+  let existing = INDEXES.lock().unwrap().get(product_code).map(|i| i.index.clone());
+  let index = match existing {
+    Some(index) => index,
+    None => {
+      let built = build(product_code)?;
+      let mut indexes = INDEXES.lock().unwrap();
+      indexes.entry(product_code.to_string()).or_insert(built).index.clone()
+    }
+  };
+  let index = index.lock().unwrap();
+  Ok(TreeSnapshot {
+    generation: index.generation,
+    root: index.root.clone(),
+    entries: index.entries.iter().map(|(k, e)| (k.clone(), e.clone())).collect(),
+  })
+}
+
+///产品删除后释放索引和文件监听
+pub fn forget(product_code: &str) {
+  INDEXES.lock().unwrap().remove(product_code);
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn batched_keeps_order() {
+    let items: Vec<usize> = (0..STAT_BATCH * 3 + 7).collect();
+    let doubled = batched(items.clone(), |i| i * 2);
+    assert_eq!(doubled, items.iter().map(|i| i * 2).collect::<Vec<_>>());
+  }
+}
@@ -0,0 +1,181 @@
+use crate::config::data_dir;
+use crate::util::now_millis;
+use deno_core::error::{custom_error, generic_error, AnyError};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use service::args::{CaData, Flags};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+///runtime fetch 额外信任的根证书 用于访问使用私有 CA 的内部服务<br>
+/// 修改后在 runtime 下次启动时生效
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrustedCa {
+  pub id: String,            //第一个证书 DER 的 sha256
+  pub name: String,          //名称 只用于展示
+  pub pem: String,           //PEM 可以包含多个证书
+  pub products: Vec<String>, //生效的产品 为空时对所有产品生效
+  pub subject: String,       //第一个证书的 subject
+  pub not_after: i64,        //第一个证书的过期时间 秒
+  pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AddTrustedCa {
+  pub name: String,
+  pub pem: String,
+  #[serde(default)]
+  pub products: Vec<String>,
+}
+
+lazy_static! {
+  static ref STORE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+///data/trusted_cas.json
+fn store_path() -> PathBuf {
+  let mut path = data_dir();
+  path.push("trusted_cas.json");
+  path
+}
+
+fn load() -> Result<Vec<TrustedCa>, AnyError> {
+  match std::fs::read_to_string(store_path()) {
+    Ok(text) => Ok(serde_json::from_str(&text)?),
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+    Err(err) => Err(err.into()),
+  }
+}
+
+fn save(cas: &[TrustedCa]) -> Result<(), AnyError> {
+  let path = store_path();
+  std::fs::create_dir_all(path.parent().unwrap())?;
+  std::fs::write(path, serde_json::to_vec_pretty(cas)?)?;
+  Ok(())
+}
+
+///解析并校验 PEM 至少包含一个证书 每个证书都必须是合法的 X.509
+fn parse(info: AddTrustedCa) -> Result<TrustedCa, AnyError> {
+  let certs = rustls_pemfile::certs(&mut info.pem.as_bytes()).map_err(|_| generic_error("invalid PEM"))?;
+  let first = certs.first().ok_or_else(|| generic_error("no certificate found in PEM"))?;
+  for der in &certs {
+    X509Certificate::from_der(der).map_err(|err| generic_error(format!("invalid certificate: {}", err)))?;
+  }
+  let (_, cert) = X509Certificate::from_der(first).map_err(|err| generic_error(format!("invalid certificate: {}", err)))?;
+  let digest = ring::digest::digest(&ring::digest::SHA256, first);
+  Ok(TrustedCa {
+    id: hex::encode(digest.as_ref()),
+    name: info.name,
+    pem: info.pem,
+    products: info.products,
+    subject: cert.subject().to_string(),
+    not_after: cert.validity().not_after.timestamp(),
+    created_at: now_millis(),
+  })
+}
+
+pub fn list() -> Result<Vec<TrustedCa>, AnyError> {
+  load()
+}
+
+///添加证书 同一个证书再次添加时替换原来的名称和产品
+pub fn add(info: AddTrustedCa, dry_run: bool) -> Result<TrustedCa, AnyError> {
+  let ca = parse(info)?;
+  let _lock = STORE_LOCK.lock().unwrap();
+  let mut cas = load()?;
+  cas.retain(|c| c.id != ca.id);
+  cas.push(ca.clone());
+  if !dry_run {
+    save(&cas)?;
+  }
+  Ok(ca)
+}
+
+pub fn remove(id: &str, dry_run: bool) -> Result<TrustedCa, AnyError> {
+  let _lock = STORE_LOCK.lock().unwrap();
+  let mut cas = load()?;
+  let index = cas
+    .iter()
+    .position(|c| c.id == id)
+    .ok_or_else(|| custom_error("NotFound", format!("trusted ca {} not found", id)))?;
+  let ca = cas.remove(index);
+  if !dry_run {
+    save(&cas)?;
+  }
+  Ok(ca)
+}
+
+///产品信任的证书拼接成一个 PEM 没有时为 None
+fn bundle_for(cas: &[TrustedCa], product_code: &str) -> Option<Vec<u8>> {
+  let pems: Vec<&str> = cas
+    .iter()
+    .filter(|c| c.products.is_empty() || c.products.iter().any(|p| p == product_code))
+    .map(|c| c.pem.trim())
+    .collect();
+  if pems.is_empty() {
+    return None;
+  }
+  Some(format!("{}\n", pems.join("\n")).into_bytes())
+}
+
+///把产品信任的证书加入 runtime 的根证书<br>
+/// 原来通过 ca_data 或 DENO_CERT 指定的证书文件继续有效
+pub fn apply(flags: &mut Flags, product_code: &str) {
+  let cas = match load() {
+    Ok(cas) => cas,
+    Err(err) => {
+      log::error!("failed to load trusted cas: {}", err);
+      return;
+    }
+  };
+  let mut bundle = match bundle_for(&cas, product_code) {
+    Some(bundle) => bundle,
+    None => return,
+  };
+  let existing = match flags.ca_data.take() {
+    Some(CaData::File(path)) => Some(path),
+    Some(CaData::Bytes(bytes)) => {
+      bundle.extend_from_slice(&bytes);
+      None
+    }
+    None => std::env::var("DENO_CERT").ok(),
+  };
+  if let Some(path) = existing {
+    match std::fs::read(&path) {
+      Ok(bytes) => bundle.extend_from_slice(&bytes),
+      Err(err) => log::error!("failed to read ca file {}: {}", path, err),
+    }
+  }
+  flags.ca_data = Some(CaData::Bytes(bundle));
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn bundles_global_and_selected_product_cas() {
+    let ca = |id: &str, products: &[&str]| TrustedCa {
+      id: id.to_string(),
+      name: id.to_string(),
+      pem: format!("-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n", id),
+      products: products.iter().map(|p| p.to_string()).collect(),
+      subject: String::new(),
+      not_after: 0,
+      created_at: 0,
+    };
+    let cas = vec![ca("global", &[]), ca("internal", &["shop"])];
+    let shop = String::from_utf8(bundle_for(&cas, "shop").unwrap()).unwrap();
+    assert!(shop.contains("global") && shop.contains("internal"));
+    let blog = String::from_utf8(bundle_for(&cas, "blog").unwrap()).unwrap();
+    assert!(blog.contains("global") && !blog.contains("internal"));
+    assert_eq!(bundle_for(&cas[1..], "blog"), None);
+    assert!(parse(AddTrustedCa {
+      name: "empty".to_string(),
+      pem: "not a certificate".to_string(),
+      products: vec![],
+    })
+    .is_err());
+  }
+}
@@ -0,0 +1,352 @@
+use crate::config::ProductConfig;
+use crate::{offline, secrets};
+use deno_core::error::{generic_error, AnyError};
+use deno_runtime::deno_fetch::reqwest;
+use deno_runtime::deno_tls::create_default_root_cert_store;
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use url::Url;
+
+///每一步检查的超时
+const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamKind {
+  Tcp,
+  Http, //对 url 发 GET 请求 状态码小于 400 为正常
+  Redis,
+  Postgres,
+  Mysql,
+}
+
+impl UpstreamKind {
+  fn default_port(&self) -> Option<u16> {
+    match self {
+      Self::Tcp => None,
+      Self::Http => Some(80),
+      Self::Redis => Some(6379),
+      Self::Postgres => Some(5432),
+      Self::Mysql => Some(3306),
+    }
+  }
+}
+
+///产品依赖的外部服务 cool.json 中的 upstreams
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Upstream {
+  pub name: String,
+  pub kind: UpstreamKind,
+  #[serde(default)]
+  pub host: String, //http 类型使用 url
+  #[serde(default)]
+  pub port: Option<u16>, //默认为各类型的标准端口
+  #[serde(default)]
+  pub url: Option<String>,
+  #[serde(default)]
+  pub tls: bool,
+  #[serde(default)]
+  pub secret: Option<String>, //凭据所在的产品密钥 http 作为 bearer token redis 作为密码
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StepState {
+  Ok,
+  Failed,
+  Skipped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckStep {
+  pub step: String, //dns tcp tls protocol auth
+  pub state: StepState,
+  pub detail: Option<String>,
+  pub millis: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpstreamStatus {
+  pub name: String,
+  pub kind: UpstreamKind,
+  pub target: String,
+  pub ok: bool,
+  pub blocked: bool, //离线模式的白名单不允许访问 没有发起连接
+  pub steps: Vec<CheckStep>,
+}
+
+pub trait Conn: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Conn for T {}
+
+struct Checker {
+  steps: Vec<CheckStep>,
+}
+
+impl Checker {
+  ///执行一步检查 超时视为失败
+  async fn step<T, F>(&mut self, step: &str, f: F) -> Option<T>
+  where
+    F: Future<Output = Result<(T, Option<String>), AnyError>>,
+  {
+    let started = Instant::now();
+    let res = match tokio::time::timeout(STEP_TIMEOUT, f).await {
+      Ok(res) => res,
+      Err(_) => Err(generic_error("timed out")),
+    };
+    let millis = started.elapsed().as_millis() as u64;
+    let (state, detail, value) = match res {
+      Ok((value, detail)) => (StepState::Ok, detail, Some(value)),
+      Err(err) => (StepState::Failed, Some(err.to_string()), None),
+    };
+    self.steps.push(CheckStep {
+      step: step.to_string(),
+      state,
+      detail,
+      millis,
+    });
+    value
+  }
+
+  fn skip(&mut self, step: &str, detail: &str) {
+    self.steps.push(CheckStep {
+      step: step.to_string(),
+      state: StepState::Skipped,
+      detail: Some(detail.to_string()),
+      millis: 0,
+    });
+  }
+}
+
+pub async fn tls_connect(host: &str, tcp: TcpStream) -> Result<Box<dyn Conn>, AnyError> {
+  let config = rustls::ClientConfig::builder()
+    .with_safe_defaults()
+    .with_root_certificates(create_default_root_cert_store())
+    .with_no_client_auth();
+  let name = rustls::ServerName::try_from(host).map_err(|_| generic_error(format!("invalid tls server name {}", host)))?;
+  let stream = tokio_rustls::TlsConnector::from(Arc::new(config)).connect(name, tcp).await?;
+  Ok(Box::new(stream))
+}
+
+///postgres 先发送 SSLRequest 服务端回复 S 后再握手
+async fn postgres_tls(host: &str, mut tcp: TcpStream) -> Result<Box<dyn Conn>, AnyError> {
+  tcp.write_all(&[0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f]).await?;
+  let mut reply = [0u8; 1];
+  tcp.read_exact(&mut reply).await?;
+  if reply[0] != b'S' {
+    return Err(generic_error("server does not accept ssl"));
+  }
+  tls_connect(host, tcp).await
+}
+
+async fn read_line(conn: &mut Box<dyn Conn>) -> Result<String, AnyError> {
+  let mut line = vec![];
+  let mut byte = [0u8; 1];
+  while line.len() < 512 {
+    conn.read_exact(&mut byte).await?;
+    if byte[0] == b'\n' {
+      break;
+    }
+    line.push(byte[0]);
+  }
+  Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+}
+
+///redis 可选 AUTH 然后 PING
+async fn redis_ping(conn: &mut Box<dyn Conn>, password: Option<&str>) -> Result<((), Option<String>), AnyError> {
+  if let Some(password) = password {
+    conn
+      .write_all(format!("*2\r\n$4\r\nAUTH\r\n${}\r\n{}\r\n", password.len(), password).as_bytes())
+      .await?;
+    let reply = read_line(conn).await?;
+    if !reply.starts_with('+') {
+      return Err(generic_error(format!("auth failed: {}", reply)));
+    }
+  }
+  conn.write_all(b"*1\r\n$4\r\nPING\r\n").await?;
+  match read_line(conn).await? {
+    reply if reply == "+PONG" => Ok(((), None)),
+    reply => Err(generic_error(reply)),
+  }
+}
+
+///读取 mysql 的握手包 确认是 mysql 并返回服务端版本
+async fn mysql_greeting(conn: &mut Box<dyn Conn>) -> Result<((), Option<String>), AnyError> {
+  let mut header = [0u8; 4];
+  conn.read_exact(&mut header).await?;
+  let len = u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize;
+  let mut packet = vec![0u8; len.min(1024)];
+  conn.read_exact(&mut packet).await?;
+  match packet.first() {
+    Some(10) => {
+      let version = packet[1..].split(|b| *b == 0).next().unwrap_or_default();
+      Ok(((), Some(format!("server {}", String::from_utf8_lossy(version)))))
+    }
+    Some(0xff) => Err(generic_error(String::from_utf8_lossy(packet.get(3..).unwrap_or_default()).to_string())),
+    _ => Err(generic_error("not a mysql server")),
+  }
+}
+
+async fn http_get(url: &str, token: Option<&str>) -> Result<((), Option<String>), AnyError> {
+  let client = reqwest::Client::builder().timeout(STEP_TIMEOUT).build()?;
+  let mut req = client.get(url);
+  if let Some(token) = token {
+    req = req.bearer_auth(token);
+  }
+  let status = req.send().await?.status();
+  match status.as_u16() {
+    401 | 403 => Err(generic_error(format!("credentials rejected with {}", status))),
+    code if code >= 400 => Err(generic_error(format!("responded {}", status))),
+    _ => Ok(((), Some(status.to_string()))),
+  }
+}
+
+pub fn allowed(allowlist: &Option<Vec<String>>, host: &str, port: u16) -> bool {
+  match allowlist {
+    Some(hosts) => hosts.iter().any(|h| h == host || *h == format!("{}:{}", host, port)),
+    None => true,
+  }
+}
+
+///要连接的主机 端口和是否使用 tls
+fn target(upstream: &Upstream) -> Result<(String, u16, bool), String> {
+  let (host, port, tls) = match (upstream.kind, &upstream.url) {
+    (UpstreamKind::Http, Some(url)) => {
+      let url = Url::parse(url).map_err(|err| format!("invalid url: {}", err))?;
+      let port = url.port_or_known_default().unwrap_or(80);
+      (url.host_str().unwrap_or_default().to_string(), port, url.scheme() == "https")
+    }
+    (kind, _) => (upstream.host.clone(), upstream.port.or(kind.default_port()).unwrap_or(0), upstream.tls),
+  };
+  if host.is_empty() || port == 0 {
+    return Err("host and port are required".to_string());
+  }
+  Ok((host, port, tls))
+}
+
+///从网关所在的网络检查一个外部依赖 凭据只通过 tls 或发给本机
+async fn check(product_code: &str, upstream: &Upstream, allowlist: &Option<Vec<String>>) -> UpstreamStatus {
+  let mut status = UpstreamStatus {
+    name: upstream.name.clone(),
+    kind: upstream.kind,
+    target: String::new(),
+    ok: false,
+    blocked: false,
+    steps: vec![],
+  };
+  let (host, port, tls) = match target(upstream) {
+    Ok(target) => target,
+    Err(message) => {
+      status.steps.push(CheckStep {
+        step: "config".to_string(),
+        state: StepState::Failed,
+        detail: Some(message),
+        millis: 0,
+      });
+      return status;
+    }
+  };
+  status.target = format!("{}:{}", host, port);
+  if !allowed(allowlist, &host, port) {
+    status.blocked = true;
+    return status;
+  }
+  let mut checker = Checker { steps: vec![] };
+  let addr = checker
+    .step("dns", async {
+      let addr: SocketAddr = tokio::net::lookup_host((host.as_str(), port))
+        .await?
+        .next()
+        .ok_or_else(|| generic_error("no address"))?;
+      Ok::<_, AnyError>((addr, Some(addr.ip().to_string())))
+    })
+    .await;
+  let tcp = match addr {
+    Some(addr) => {
+      checker
+        .step("tcp", async { Ok::<_, AnyError>((TcpStream::connect(addr).await?, None)) })
+        .await
+    }
+    None => None,
+  };
+  let secret = match upstream.secret.as_deref() {
+    Some(name) => secrets::active_values(product_code, name).ok().and_then(|v| v.into_iter().next()),
+    None => None,
+  };
+  let safe = tls || addr.map(|a| a.ip().is_loopback()).unwrap_or(false);
+  if let Some(tcp) = tcp {
+    let mut conn: Option<Box<dyn Conn>> = match (upstream.kind, tls) {
+      (UpstreamKind::Http, _) => {
+        drop(tcp);
+        None
+      }
+      (UpstreamKind::Postgres, true) => {
+        checker
+          .step("tls", async { Ok::<_, AnyError>((postgres_tls(&host, tcp).await?, None)) })
+          .await
+      }
+      (UpstreamKind::Mysql, true) => {
+        checker.skip("tls", "negotiated by the mysql client after the greeting");
+        Some(Box::new(tcp) as Box<dyn Conn>)
+      }
+      (_, true) => {
+        checker
+          .step("tls", async { Ok::<_, AnyError>((tls_connect(&host, tcp).await?, None)) })
+          .await
+      }
+      (_, false) => Some(Box::new(tcp) as Box<dyn Conn>),
+    };
+    match (upstream.kind, conn.as_mut()) {
+      (UpstreamKind::Http, _) => {
+        let scheme = if tls { "https" } else { "http" };
+        let url = upstream.url.clone().unwrap_or_else(|| format!("{}://{}:{}/", scheme, host, port));
+        let token = secret.as_deref().filter(|_| safe);
+        if secret.is_some() && !safe {
+          checker.skip("auth", "credentials are only sent over tls");
+        }
+        checker.step("protocol", http_get(&url, token)).await;
+      }
+      (UpstreamKind::Redis, Some(conn)) => {
+        if secret.is_some() && !safe {
+          checker.skip("auth", "credentials are only sent over tls");
+        }
+        let password = secret.as_deref().filter(|_| safe);
+        checker.step("protocol", redis_ping(conn, password)).await;
+      }
+      (UpstreamKind::Mysql, Some(conn)) => {
+        checker.step("protocol", mysql_greeting(conn)).await;
+      }
+      (UpstreamKind::Postgres, Some(_)) if secret.is_some() => checker.skip("auth", "postgres credentials are not checked"),
+      _ => {}
+    }
+  }
+  status.ok = checker.steps.iter().all(|s| s.state != StepState::Failed);
+  status.steps = checker.steps;
+  status
+}
+
+///检查产品声明的所有外部依赖 并行执行 遵守离线模式的白名单
+pub async fn check_all(product_code: &str) -> Result<Vec<UpstreamStatus>, AnyError> {
+  let upstreams = ProductConfig::load(product_code)?.upstreams;
+  let allowlist = offline::net_allowlist(product_code);
+  Ok(join_all(upstreams.iter().map(|u| check(product_code, u, &allowlist))).await)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn allowlist_matches_host_or_host_port() {
+    let allowlist = Some(vec!["db.internal".to_string(), "cache:6380".to_string()]);
+    assert!(allowed(&allowlist, "db.internal", 5432));
+    assert!(allowed(&allowlist, "cache", 6380));
+    assert!(!allowed(&allowlist, "cache", 6379));
+    assert!(allowed(&None, "anything", 1));
+  }
+}
@@ -0,0 +1,247 @@
+use crate::config::{data_dir, GatewayConfig};
+use crate::metrics;
+use chrono::Utc;
+use deno_core::error::AnyError;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub use deno_runtime::deno_http::usage::{USAGE_CPU_HEADER, USAGE_HEAP_HEADER, USAGE_SAMPLE_HEADER};
+
+///用量采样 gateway.json 中的 usage
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UsageConfig {
+  pub sample_every: u64, //每多少个请求采样一次 CPU 和内存 0 表示不采样
+  pub flush_secs: u64,   //用量写入磁盘的间隔
+}
+
+impl Default for UsageConfig {
+  fn default() -> Self {
+    Self {
+      sample_every: 20,
+      flush_secs: 60,
+    }
+  }
+}
+
+///单个接口的用量 cpu 和内存只包含被采样的请求
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct EndpointUsage {
+  pub requests: u64,
+  pub sampled_requests: u64,
+  pub sampled_cpu_micros: u64,
+  pub sampled_heap_bytes: u64,
+}
+
+impl EndpointUsage {
+  ///按采样结果估算全部请求的 CPU 秒数
+  pub fn estimated_cpu_secs(&self) -> f64 {
+    match self.sampled_requests {
+      0 => 0.0,
+      n => self.sampled_cpu_micros as f64 / n as f64 * self.requests as f64 / 1_000_000.0,
+    }
+  }
+}
+
+///产品一天的用量 data/usage/{product_code}/{date}.json
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DailyUsage {
+  pub product_code: String,
+  pub date: String,
+  pub endpoints: BTreeMap<String, EndpointUsage>,
+  #[serde(default)]
+  pub egress_bytes: u64, //返回给客户端的响应体字节数
+}
+
+struct Entry {
+  usage: DailyUsage,
+  dirty: bool,
+}
+
+lazy_static! {
+  static ref USAGE: Mutex<HashMap<(String, String), Entry>> = Mutex::new(HashMap::new());
+  static ref REQUEST_SEQ: AtomicU64 = AtomicU64::new(0);
+  static ref SAMPLE_EVERY: AtomicU64 = AtomicU64::new(UsageConfig::default().sample_every);
+}
+
+pub fn usage_path(product_code: &str, date: &str) -> PathBuf {
+  let mut path = data_dir();
+  path.push("usage");
+  path.push(product_code);
+  path.push(format!("{}.json", date));
+  path
+}
+
+fn today() -> String {
+  Utc::now().format("%Y-%m-%d").to_string()
+}
+
+fn read_file(product_code: &str, date: &str) -> Result<Option<DailyUsage>, AnyError> {
+  match std::fs::read_to_string(usage_path(product_code, date)) {
+    Ok(text) => Ok(Some(serde_json::from_str(&text)?)),
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+    Err(err) => Err(err.into()),
+  }
+}
+
+///接口标签 路径中的数字 uuid 等 id 段替换为 :id 避免标签无限增长
+pub fn endpoint_label(method: &str, path: &str) -> String {
+  let segments: Vec<&str> = path
+    .split('/')
+    .map(|s| {
+      let is_id = !s.is_empty() && (s.chars().all(|c| c.is_ascii_digit()) || (s.len() >= 16 && s.chars().all(|c| c.is_ascii_hexdigit() || c == '-')));
+      if is_id {
+        ":id"
+      } else {
+        s
+      }
+    })
+    .collect();
+  format!("{} {}", method, segments.join("/"))
+}
+
+fn update_daily(product_code: &str, f: impl FnOnce(&mut DailyUsage)) {
+  let date = today();
+  let mut table = USAGE.lock().unwrap();
+  let entry = table.entry((product_code.to_string(), date.clone())).or_insert_with(|| Entry {
+    //网关重启后在已有的数据上继续累计
+    usage: read_file(product_code, &date).ok().flatten().unwrap_or(DailyUsage {
+      product_code: product_code.to_string(),
+      date,
+      endpoints: BTreeMap::new(),
+      egress_bytes: 0,
+    }),
+    dirty: false,
+  });
+  f(&mut entry.usage);
+  entry.dirty = true;
+}
+
+fn update(product_code: &str, endpoint: &str, f: impl FnOnce(&mut EndpointUsage)) {
+  update_daily(product_code, |usage| f(usage.endpoints.entry(endpoint.to_string()).or_default()));
+}
+
+///是否采样这个请求
+pub fn should_sample() -> bool {
+  match SAMPLE_EVERY.load(Ordering::Relaxed) {
+    0 => false,
+    n => REQUEST_SEQ.fetch_add(1, Ordering::Relaxed) % n == 0,
+  }
+}
+
+pub fn record_request(product_code: &str, endpoint: &str) {
+  update(product_code, endpoint, |u| u.requests += 1);
+}
+
+///记录返回给客户端的流量
+pub fn record_egress(product_code: &str, bytes: usize) {
+  update_daily(product_code, |usage| usage.egress_bytes += bytes as u64);
+}
+
+///记录 runtime 报告的采样结果
+pub fn record_sample(product_code: &str, endpoint: &str, cpu_micros: u64, heap_bytes: u64) {
+  update(product_code, endpoint, |u| {
+    u.sampled_requests += 1;
+    u.sampled_cpu_micros += cpu_micros;
+    u.sampled_heap_bytes += heap_bytes;
+  });
+  let labels = [("product", product_code), ("endpoint", endpoint)];
+  metrics::inc_counter("gateway_usage_samples_total", "Requests sampled for CPU and memory usage", &labels, 1);
+  metrics::inc_counter(
+    "gateway_sampled_cpu_microseconds_total",
+    "Isolate CPU time of sampled requests",
+    &labels,
+    cpu_micros,
+  );
+  metrics::inc_counter("gateway_sampled_heap_bytes_total", "Heap growth of sampled requests", &labels, heap_bytes);
+}
+
+///产品某天的用量 date 为 YYYY-MM-DD
+pub fn daily_usage(product_code: &str, date: &str) -> Result<DailyUsage, AnyError> {
+  if let Some(entry) = USAGE.lock().unwrap().get(&(product_code.to_string(), date.to_string())) {
+    return Ok(entry.usage.clone());
+  }
+  Ok(read_file(product_code, date)?.unwrap_or(DailyUsage {
+    product_code: product_code.to_string(),
+    date: date.to_string(),
+    endpoints: BTreeMap::new(),
+    egress_bytes: 0,
+  }))
+}
+
+///有用量记录的产品
+pub fn products() -> Result<Vec<String>, AnyError> {
+  let mut dir = data_dir();
+  dir.push("usage");
+  let mut products = vec![];
+  let entries = match std::fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(products),
+    Err(err) => return Err(err.into()),
+  };
+  for entry in entries {
+    let entry = entry?;
+    if entry.file_type()?.is_dir() {
+      products.push(entry.file_name().to_string_lossy().to_string());
+    }
+  }
+  products.sort();
+  Ok(products)
+}
+
+///把有变化的用量写入磁盘 之前日期的数据写入后从内存移除
+pub fn flush() -> Result<(), AnyError> {
+  let date = today();
+  let mut table = USAGE.lock().unwrap();
+  for entry in table.values_mut().filter(|e| e.dirty) {
+    let path = usage_path(&entry.usage.product_code, &entry.usage.date);
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(&path, serde_json::to_vec_pretty(&entry.usage)?)?;
+    entry.dirty = false;
+  }
+  table.retain(|(_, d), _| *d == date);
+  Ok(())
+}
+
+///按网关配置设置采样频率 并定时写入用量
+pub fn start() {
+  let config = GatewayConfig::load().map(|c| c.usage).unwrap_or_default();
+  SAMPLE_EVERY.store(config.sample_every, Ordering::Relaxed);
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.flush_secs.max(1)));
+    loop {
+      interval.tick().await;
+      if let Err(err) = flush() {
+        log::error!("failed to flush usage: {}", err);
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn labels_endpoints_without_ids() {
+    assert_eq!(endpoint_label("GET", "/users/42/orders"), "GET /users/:id/orders");
+    assert_eq!(endpoint_label("POST", "/items/3fa85f64-5717-4562-b3fc-2c963f66afa6"), "POST /items/:id");
+    assert_eq!(endpoint_label("GET", "/"), "GET /");
+  }
+
+  #[test]
+  fn estimates_cpu_from_samples() {
+    let usage = EndpointUsage {
+      requests: 100,
+      sampled_requests: 5,
+      sampled_cpu_micros: 50_000,
+      sampled_heap_bytes: 0,
+    };
+    assert_eq!(usage.estimated_cpu_secs(), 1.0);
+  }
+}
@@ -0,0 +1,408 @@
+use crate::auth::{self, Role};
+use crate::config::data_dir;
+use crate::util::{now_millis, read_json, write_json};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use deno_core::error::{custom_error, generic_error, AnyError};
+use lazy_static::lazy_static;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+///totp 的时间步长 秒
+const TOTP_STEP_SECS: u64 = 30;
+const MIN_PASSWORD_LEN: usize = 8;
+
+///控制台的本地用户 data/users.json 密码只保存 argon2 摘要
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+  pub id: String,
+  pub email: String,
+  pub role: Role,
+  #[serde(default)]
+  pub source: UserSource,
+  #[serde(default)]
+  pub tenant_id: Option<String>, //绑定租户的用户只能访问 /tenant 下自己的产品
+  #[serde(default)]
+  pub totp_enabled: bool,
+  pub created_at: u64,
+  #[serde(skip_serializing_if = "String::is_empty", default)]
+  password_hash: String,
+  #[serde(skip_serializing_if = "Option::is_none", default)]
+  totp_secret: Option<String>, //base32
+}
+
+impl User {
+  ///对外展示时不带密码摘要和 totp 密钥
+  pub fn public(&self) -> Self {
+    Self {
+      password_hash: String::new(),
+      totp_secret: None,
+      ..self.clone()
+    }
+  }
+}
+
+///用户来自哪里 目录服务的用户在第一次登录时创建 每次登录按组更新角色
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UserSource {
+  #[default]
+  Local,
+  Ldap,
+}
+
+///修改用户 不传的字段保持不变
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UserUpdate {
+  pub role: Option<Role>,
+  pub password: Option<String>,
+  pub tenant_id: Option<String>, //空字符串表示解除租户绑定
+}
+
+///登录会话 只保存在内存中 网关重启后需要重新登录
+#[derive(Debug, Clone)]
+pub struct Session {
+  pub user_id: String,
+  pub csrf_token: String,
+  pub expires_at: u64,
+}
+
+lazy_static! {
+  static ref STORE_LOCK: Mutex<()> = Mutex::new(());
+  static ref SESSIONS: Mutex<HashMap<String, Session>> = Mutex::new(HashMap::new());
+}
+
+fn users_path() -> PathBuf {
+  data_dir().join("users.json")
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N], AnyError> {
+  let mut bytes = [0u8; N];
+  SystemRandom::new()
+    .fill(&mut bytes)
+    .map_err(|_| generic_error("failed to generate random bytes"))?;
+  Ok(bytes)
+}
+
+fn hash_password(password: &str) -> Result<String, AnyError> {
+  if password.chars().count() < MIN_PASSWORD_LEN {
+    return Err(generic_error(format!("password must be at least {} characters", MIN_PASSWORD_LEN)));
+  }
+  let salt = SaltString::encode_b64(&random_bytes::<16>()?).map_err(|e| generic_error(e.to_string()))?;
+  let hash = Argon2::default()
+    .hash_password(password.as_bytes(), &salt)
+    .map_err(|e| generic_error(e.to_string()))?;
+  Ok(hash.to_string())
+}
+
+fn verify_password(hash: &str, password: &str) -> bool {
+  match PasswordHash::new(hash) {
+    Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+    Err(_) => false,
+  }
+}
+
+///RFC 6238 的 6 位验证码 HMAC-SHA1
+pub fn totp_code(secret: &[u8], unix_secs: u64) -> String {
+  let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+  let counter = unix_secs / TOTP_STEP_SECS;
+  let tag = hmac::sign(&key, &counter.to_be_bytes());
+  let digest = tag.as_ref();
+  let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+  let value = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]]) & 0x7fff_ffff;
+  format!("{:06}", value % 1_000_000)
+}
+
+///允许前后各一个时间步的时钟误差
+fn verify_totp(secret: &str, code: &str) -> bool {
+  let secret = match base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret) {
+    Some(secret) => secret,
+    None => return false,
+  };
+  let now = now_millis() / 1000;
+  [now.saturating_sub(TOTP_STEP_SECS), now, now + TOTP_STEP_SECS]
+    .iter()
+    .any(|t| totp_code(&secret, *t) == code.trim())
+}
+
+fn read_users() -> Result<Vec<User>, AnyError> {
+  read_json(users_path())
+}
+
+fn not_found(id: &str) -> AnyError {
+  custom_error("NotFound", format!("user {} not found", id))
+}
+
+pub fn list_users() -> Result<Vec<User>, AnyError> {
+  Ok(read_users()?.iter().map(User::public).collect())
+}
+
+pub fn get_user(id: &str) -> Result<User, AnyError> {
+  read_users()?
+    .into_iter()
+    .find(|u| u.id == id)
+    .map(|u| u.public())
+    .ok_or_else(|| not_found(id))
+}
+
+pub fn create_user(email: &str, password: &str, role: Role, tenant_id: Option<String>, dry_run: bool) -> Result<User, AnyError> {
+  let email = email.trim().to_lowercase();
+  if !email.contains('@') {
+    return Err(generic_error(format!("invalid email {}", email)));
+  }
+  let user = User {
+    id: uuid::Uuid::new_v4().to_string(),
+    email,
+    role,
+    source: UserSource::Local,
+    tenant_id,
+    totp_enabled: false,
+    created_at: now_millis(),
+    password_hash: hash_password(password)?,
+    totp_secret: None,
+  };
+  let _lock = STORE_LOCK.lock().unwrap();
+  let mut users = read_users()?;
+  if users.iter().any(|u| u.email == user.email) {
+    return Err(generic_error(format!("user {} already exists", user.email)));
+  }
+  users.push(user.clone());
+  if !dry_run {
+    write_json(users_path(), &users)?;
+  }
+  Ok(user.public())
+}
+
+fn modify_user(id: &str, dry_run: bool, f: impl FnOnce(&mut User) -> Result<(), AnyError>) -> Result<User, AnyError> {
+  let _lock = STORE_LOCK.lock().unwrap();
+  let mut users = read_users()?;
+  let user = users.iter_mut().find(|u| u.id == id).ok_or_else(|| not_found(id))?;
+  f(user)?;
+  let user = user.public();
+  if !dry_run {
+    write_json(users_path(), &users)?;
+  }
+  Ok(user)
+}
+
+///管理员修改用户 修改密码后该用户的会话全部失效
+pub fn update_user(id: &str, update: UserUpdate, dry_run: bool) -> Result<User, AnyError> {
+  let password_changed = update.password.is_some();
+  let user = modify_user(id, dry_run, |user| {
+    if let Some(role) = update.role {
+      user.role = role;
+    }
+    if let Some(password) = &update.password {
+      user.password_hash = hash_password(password)?;
+    }
+    if let Some(tenant_id) = update.tenant_id {
+      user.tenant_id = Some(tenant_id).filter(|t| !t.is_empty());
+    }
+    Ok(())
+  })?;
+  if password_changed && !dry_run {
+    revoke_sessions(id);
+  }
+  Ok(user)
+}
+
+///删除用户 返回被删除的用户 dry_run 时不删除
+pub fn delete_user(id: &str, dry_run: bool) -> Result<User, AnyError> {
+  let _lock = STORE_LOCK.lock().unwrap();
+  let mut users = read_users()?;
+  let index = users.iter().position(|u| u.id == id).ok_or_else(|| not_found(id))?;
+  let user = users.remove(index).public();
+  if !dry_run {
+    write_json(users_path(), &users)?;
+    revoke_sessions(id);
+  }
+  Ok(user)
+}
+
+///用户自己修改密码 需要旧密码
+pub fn change_password(id: &str, old_password: &str, new_password: &str) -> Result<User, AnyError> {
+  modify_user(id, false, |user| {
+    if !verify_password(&user.password_hash, old_password) {
+      return Err(generic_error("old password is incorrect"));
+    }
+    user.password_hash = hash_password(new_password)?;
+    Ok(())
+  })
+}
+
+///生成新的 totp 密钥 确认验证码之前不会生效 返回密钥和 otpauth 链接
+pub fn begin_totp(id: &str) -> Result<(String, String), AnyError> {
+  let secret = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &random_bytes::<20>()?);
+  let user = modify_user(id, false, |user| {
+    if user.totp_enabled {
+      return Err(generic_error("two-factor authentication is already enabled"));
+    }
+    user.totp_secret = Some(secret.clone());
+    Ok(())
+  })?;
+  let url = format!(
+    "otpauth://totp/DenoCool:{}?secret={}&issuer=DenoCool&period={}",
+    user.email, secret, TOTP_STEP_SECS
+  );
+  Ok((secret, url))
+}
+
+///用当前验证码确认后启用两步验证
+pub fn enable_totp(id: &str, code: &str) -> Result<User, AnyError> {
+  modify_user(id, false, |user| match &user.totp_secret {
+    Some(secret) if verify_totp(secret, code) => {
+      user.totp_enabled = true;
+      Ok(())
+    }
+    Some(_) => Err(generic_error("invalid verification code")),
+    None => Err(generic_error("two-factor authentication has not been set up")),
+  })
+}
+
+///关闭两步验证 code 为空时不校验 用于管理员重置
+pub fn disable_totp(id: &str, code: Option<&str>, dry_run: bool) -> Result<User, AnyError> {
+  modify_user(id, dry_run, |user| {
+    if let (Some(code), Some(secret)) = (code, &user.totp_secret) {
+      if user.totp_enabled && !verify_totp(secret, code) {
+        return Err(generic_error("invalid verification code"));
+      }
+    }
+    user.totp_enabled = false;
+    user.totp_secret = None;
+    Ok(())
+  })
+}
+
+///校验邮箱密码和验证码 成功后创建会话 返回会话 token
+pub fn login(email: &str, password: &str, totp: Option<&str>) -> Result<(String, Session, User), AnyError> {
+  let email = email.trim().to_lowercase();
+  let user = read_users()?
+    .into_iter()
+    .find(|u| u.email == email && verify_password(&u.password_hash, password))
+    .ok_or_else(|| custom_error("Unauthorized", "invalid email or password"))?;
+  start_session(user, totp)
+}
+
+///目录服务认证通过后登录 没有对应的用户时创建 有时更新角色
+pub fn login_external(email: &str, source: UserSource, role: Role, totp: Option<&str>) -> Result<(String, Session, User), AnyError> {
+  let email = email.trim().to_lowercase();
+  let user = {
+    let _lock = STORE_LOCK.lock().unwrap();
+    let mut users = read_users()?;
+    let user = match users.iter_mut().find(|u| u.email == email) {
+      Some(user) if user.source != source => {
+        return Err(custom_error("Unauthorized", format!("{} uses a different login method", email)));
+      }
+      Some(user) => {
+        user.role = role;
+        user.clone()
+      }
+      None => {
+        let user = User {
+          id: uuid::Uuid::new_v4().to_string(),
+          email,
+          role,
+          source,
+          tenant_id: None,
+          totp_enabled: false,
+          created_at: now_millis(),
+          password_hash: String::new(),
+          totp_secret: None,
+        };
+        users.push(user.clone());
+        user
+      }
+    };
+    write_json(users_path(), &users)?;
+    user
+  };
+  start_session(user, totp)
+}
+
+///校验两步验证后创建会话
+fn start_session(user: User, totp: Option<&str>) -> Result<(String, Session, User), AnyError> {
+  if user.totp_enabled {
+    let code = totp.ok_or_else(|| custom_error("Unauthorized", "verification code required"))?;
+    if !verify_totp(user.totp_secret.as_deref().unwrap_or_default(), code) {
+      return Err(custom_error("Unauthorized", "invalid verification code"));
+    }
+  }
+  let token = hex::encode(random_bytes::<32>()?);
+  let session = Session {
+    user_id: user.id.clone(),
+    csrf_token: hex::encode(random_bytes::<16>()?),
+    expires_at: now_millis() + auth::session_ttl_secs() * 1000,
+  };
+  SESSIONS.lock().unwrap().insert(token.clone(), session.clone());
+  Ok((token, session, user.public()))
+}
+
+///是否为有本地密码的用户 目录认证时本地用户仍然使用本地密码 用于目录服务不可用时登录
+pub fn has_local_password(email: &str) -> Result<bool, AnyError> {
+  let email = email.trim().to_lowercase();
+  Ok(read_users()?.iter().any(|u| u.email == email && !u.password_hash.is_empty()))
+}
+
+pub fn logout(token: &str) {
+  SESSIONS.lock().unwrap().remove(token);
+}
+
+fn revoke_sessions(user_id: &str) {
+  SESSIONS.lock().unwrap().retain(|_, s| s.user_id != user_id);
+}
+
+///会话和对应的用户 过期或用户已删除时返回未认证
+pub fn session(token: &str) -> Result<(Session, User), AnyError> {
+  let session = {
+    let mut sessions = SESSIONS.lock().unwrap();
+    match sessions.get(token) {
+      Some(session) if session.expires_at > now_millis() => session.clone(),
+      Some(_) => {
+        sessions.remove(token);
+        return Err(custom_error("Unauthorized", "session expired"));
+      }
+      None => return Err(custom_error("Unauthorized", "not logged in")),
+    }
+  };
+  let user = get_user(&session.user_id).map_err(|_| custom_error("Unauthorized", "not logged in"))?;
+  Ok((session, user))
+}
+
+///清理过期的会话
+pub fn purge_sessions() {
+  let now = now_millis();
+  SESSIONS.lock().unwrap().retain(|_, s| s.expires_at > now);
+}
+
+///还没有任何用户时创建管理员
+pub fn bootstrap_admin(email: &str, password: &str) -> Result<Option<User>, AnyError> {
+  if !read_users()?.is_empty() {
+    return Ok(None);
+  }
+  create_user(email, password, Role::Admin, None, false).map(Some)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn totp_matches_rfc6238_vectors() {
+    let secret = b"12345678901234567890";
+    assert_eq!(totp_code(secret, 59), "287082");
+    assert_eq!(totp_code(secret, 1111111109), "081804");
+    assert_eq!(totp_code(secret, 1234567890), "005924");
+  }
+
+  #[test]
+  fn hashes_and_verifies_passwords() {
+    let hash = hash_password("correct horse").unwrap();
+    assert!(verify_password(&hash, "correct horse"));
+    assert!(!verify_password(&hash, "wrong horse"));
+    assert!(hash_password("short").is_err());
+  }
+}
@@ -0,0 +1,59 @@
+use deno_core::error::AnyError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+///当前时间戳 毫秒
+pub fn now_millis() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+///读取 json 文件 文件不存在时返回默认值
+pub fn read_json<T: DeserializeOwned + Default>(path: PathBuf) -> Result<T, AnyError> {
+  match std::fs::read_to_string(path) {
+    Ok(text) => Ok(serde_json::from_str(&text)?),
+    Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+    Err(err) => Err(err.into()),
+  }
+}
+
+pub fn write_json<T: Serialize>(path: PathBuf, value: &T) -> Result<(), AnyError> {
+  std::fs::create_dir_all(path.parent().unwrap())?;
+  std::fs::write(path, serde_json::to_vec_pretty(value)?)?;
+  Ok(())
+}
+
+///目录下的文件名 目录不存在时为空
+pub fn list_dir(dir: PathBuf) -> Result<Vec<(String, PathBuf)>, AnyError> {
+  let entries = match std::fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+    Err(err) => return Err(err.into()),
+  };
+  let mut names = vec![];
+  for entry in entries {
+    let entry = entry?;
+    names.push((entry.file_name().to_string_lossy().to_string(), entry.path()));
+  }
+  names.sort();
+  Ok(names)
+}
+
+///递归复制目录 目标目录不存在时创建
+pub fn copy_dir(from: &Path, to: &Path) -> io::Result<u64> {
+  let mut bytes = 0;
+  std::fs::create_dir_all(to)?;
+  for entry in WalkDir::new(from).follow_links(false).into_iter().filter_map(|e| e.ok()) {
+    let relative = entry.path().strip_prefix(from).unwrap();
+    let target = to.join(relative);
+    if entry.file_type().is_dir() {
+      std::fs::create_dir_all(&target)?;
+    } else if entry.file_type().is_file() {
+      bytes += std::fs::copy(entry.path(), &target)?;
+    }
+  }
+  Ok(bytes)
+}
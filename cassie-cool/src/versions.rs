@@ -0,0 +1,251 @@
+use crate::config::{data_dir, product_dir};
+use crate::media;
+use crate::storage;
+use crate::util::{copy_dir, now_millis};
+use deno_core::error::{generic_error, AnyError};
+use deno_runtime::at_rest;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+
+///对比时表示当前正在编辑的代码
+pub const CURRENT_VERSION: &str = "current";
+
+///产品版本目录 data/versions/{product_code}
+pub fn versions_dir(product_code: &str) -> PathBuf {
+  let mut dir = data_dir();
+  dir.push("versions");
+  dir.push(product_code);
+  dir
+}
+
+///版本号只能是版本目录下的一层目录名 版本号可能来自请求参数 不允许跳出版本目录
+fn valid_version(version: &str) -> bool {
+  let mut components = Path::new(version).components();
+  matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) && !version.contains(['/', '\\'])
+}
+
+///某个版本的代码目录
+pub fn version_dir(product_code: &str, version: &str) -> Result<PathBuf, AnyError> {
+  if !valid_version(version) {
+    return Err(generic_error(format!("invalid version {}", version)));
+  }
+  Ok(versions_dir(product_code).join(version))
+}
+
+///所有版本 按时间先后排序
+pub fn list_versions(product_code: &str) -> Result<Vec<String>, AnyError> {
+  let dir = versions_dir(product_code);
+  if !dir.exists() {
+    return Ok(vec![]);
+  }
+  let mut versions: Vec<String> = std::fs::read_dir(dir)?
+    .filter_map(|e| e.ok())
+    .filter(|e| e.path().is_dir())
+    .filter_map(|e| e.file_name().to_str().map(|s| s.to_string()))
+    .collect();
+  //版本号为毫秒时间戳 等长时字符串顺序即时间顺序
+  versions.sort_by(|a, b| a.len().cmp(&b.len()).then(a.cmp(b)));
+  Ok(versions)
+}
+
+///最新的版本
+pub fn latest_version(product_code: &str) -> Result<Option<String>, AnyError> {
+  Ok(list_versions(product_code)?.pop())
+}
+
+///版本在存储中的键 versions/{product_code}/{version}.tar.gz
+fn version_key(product_code: &str, version: &str) -> String {
+  format!("versions/{}/{}.tar.gz", product_code, version)
+}
+
+///打包版本目录 文件保持静态加密后的内容
+fn pack(dir: &Path) -> Result<Vec<u8>, AnyError> {
+  let mut tar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+  tar.append_dir_all(".", dir)?;
+  Ok(tar.into_inner()?.finish()?)
+}
+
+///从存储下载本地没有的版本
+async fn fetch(product_code: &str, version: &str) -> Result<bool, AnyError> {
+  let bytes = match storage::storage().get(&version_key(product_code, version)).await? {
+    Some(bytes) => bytes,
+    None => return Ok(false),
+  };
+  let dir = version_dir(product_code, version)?;
+  tokio::task::spawn_blocking(move || tar::Archive::new(GzDecoder::new(bytes.as_slice())).unpack(dir)).await??;
+  Ok(true)
+}
+
+///把当前代码保存为一个新版本 使用外部存储时同时上传
+pub async fn snapshot(product_code: &str) -> Result<String, AnyError> {
+  let version = now_millis().to_string();
+  let from = product_dir(product_code);
+  let to = version_dir(product_code, &version)?;
+  let dir = to.clone();
+  tokio::task::spawn_blocking(move || copy_dir(&from, &to)).await??;
+  if storage::is_remote() {
+    let bytes = tokio::task::spawn_blocking(move || pack(&dir)).await??;
+    storage::storage().put(&version_key(product_code, &version), bytes).await?;
+  }
+  Ok(version)
+}
+
+///用指定版本覆盖当前代码 本地没有时从存储下载
+pub async fn restore(product_code: &str, version: &str) -> Result<(), AnyError> {
+  let from = version_dir(product_code, version)?;
+  if !from.exists() && !fetch(product_code, version).await? {
+    return Err(generic_error(format!("version {} not found", version)));
+  }
+  let to = product_dir(product_code);
+  tokio::task::spawn_blocking(move || {
+    if to.exists() {
+      std::fs::remove_dir_all(&to)?;
+    }
+    copy_dir(&from, &to)
+  })
+  .await??;
+  Ok(())
+}
+
+///使用外部存储时 下载本地没有的版本 没有代码的产品恢复为最新版本 返回下载的版本数
+pub async fn hydrate() -> Result<usize, AnyError> {
+  let mut products = BTreeSet::new();
+  let mut fetched = 0;
+  for object in storage::storage().list("versions/").await? {
+    let parsed = object
+      .key
+      .strip_prefix("versions/")
+      .and_then(|k| k.strip_suffix(".tar.gz"))
+      .and_then(|k| k.split_once('/'));
+    let (product_code, version) = match parsed {
+      Some((product_code, version)) => (product_code.to_string(), version.to_string()),
+      None => continue,
+    };
+    let Ok(dir) = version_dir(&product_code, &version) else {
+      continue;
+    };
+    if !dir.exists() && fetch(&product_code, &version).await? {
+      fetched += 1;
+    }
+    products.insert(product_code);
+  }
+  for product_code in &products {
+    if product_dir(product_code).exists() {
+      continue;
+    }
+    if let Some(version) = latest_version(product_code)? {
+      restore(product_code, &version).await?;
+    }
+  }
+  Ok(fetched)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileChange {
+  Added,
+  Removed,
+  Modified,
+}
+
+///单个文件的差异 diff 为 unified diff 格式
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileDiff {
+  pub path: String,
+  pub change: FileChange,
+  pub diff: String,
+  #[serde(default)]
+  pub binary: bool, //二进制文件只标记有变化 不生成 diff
+}
+
+fn tree_dir(product_code: &str, version: &str) -> Result<PathBuf, AnyError> {
+  match version {
+    CURRENT_VERSION => Ok(product_dir(product_code)),
+    _ => version_dir(product_code, version),
+  }
+}
+
+///读取目录下所有文件 相对路径统一使用 /
+fn read_tree(dir: &Path) -> Result<BTreeMap<String, Vec<u8>>, AnyError> {
+  let mut files = BTreeMap::new();
+  if !dir.exists() {
+    return Ok(files);
+  }
+  for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()).filter(|e| e.file_type().is_file()) {
+    let relative = entry.path().strip_prefix(dir).unwrap();
+    let relative: Vec<String> = relative.iter().map(|p| p.to_string_lossy().to_string()).collect();
+    files.insert(relative.join("/"), at_rest::decrypt(std::fs::read(entry.path())?)?);
+  }
+  Ok(files)
+}
+
+fn unified_diff(path: &str, before: &[u8], after: &[u8]) -> String {
+  if media::is_binary(before) || media::is_binary(after) {
+    return format!("Binary files a/{} and b/{} differ\n", path, path);
+  }
+  match (std::str::from_utf8(before), std::str::from_utf8(after)) {
+    (Ok(before), Ok(after)) => TextDiff::from_lines(before, after)
+      .unified_diff()
+      .context_radius(3)
+      .header(&format!("a/{}", path), &format!("b/{}", path))
+      .to_string(),
+    _ => format!("Binary files a/{} and b/{} differ\n", path, path),
+  }
+}
+
+///对比两个版本的代码树 version 可以是 current
+pub async fn diff_versions(product_code: &str, from: &str, to: &str) -> Result<Vec<FileDiff>, AnyError> {
+  let from_dir = tree_dir(product_code, from)?;
+  let to_dir = tree_dir(product_code, to)?;
+  for (version, dir) in [(from, &from_dir), (to, &to_dir)] {
+    if !dir.exists() {
+      return Err(generic_error(format!("version {} not found", version)));
+    }
+  }
+  tokio::task::spawn_blocking(move || {
+    let before = read_tree(&from_dir)?;
+    let after = read_tree(&to_dir)?;
+    let empty = vec![];
+    let mut paths: Vec<&String> = before.keys().chain(after.keys()).collect();
+    paths.sort();
+    paths.dedup();
+    let mut diffs = vec![];
+    for path in paths {
+      let (change, old, new) = match (before.get(path), after.get(path)) {
+        (Some(old), Some(new)) if old == new => continue,
+        (Some(old), Some(new)) => (FileChange::Modified, old, new),
+        (Some(old), None) => (FileChange::Removed, old, &empty),
+        (None, Some(new)) => (FileChange::Added, &empty, new),
+        (None, None) => continue,
+      };
+      diffs.push(FileDiff {
+        path: path.clone(),
+        change,
+        diff: unified_diff(path, old, new),
+        binary: media::is_binary(old) || media::is_binary(new),
+      });
+    }
+    Ok(diffs)
+  })
+  .await?
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn rejects_versions_outside_the_versions_dir() {
+    assert!(version_dir("shop", "1700000000000").is_ok());
+    for version in ["", ".", "..", "../../etc", "/etc", "a/b", "a\\b"] {
+      assert!(version_dir("shop", version).is_err(), "{}", version);
+    }
+    assert!(tree_dir("shop", CURRENT_VERSION).is_ok());
+  }
+}
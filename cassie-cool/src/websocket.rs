@@ -0,0 +1,213 @@
+use crate::metrics;
+use actix_web::http::header::SEC_WEBSOCKET_PROTOCOL;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_ws::{CloseCode, CloseReason, Message};
+use awc::ws::{Frame, Message as UpstreamMessage};
+use awc::Client;
+use futures_util::{SinkExt, StreamExt};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use tokio::select;
+
+///WebSocket 连接上限 cool.json 中的 websocket 在升级时检查
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebSocketLimits {
+  pub max_connections: Option<usize>,        //产品同时在线的连接数
+  pub max_connections_per_ip: Option<usize>, //单个客户端 ip 同时在线的连接数
+}
+
+#[derive(Default)]
+struct Counts {
+  total: usize,
+  per_ip: HashMap<IpAddr, usize>,
+}
+
+lazy_static! {
+  static ref CONNECTIONS: Mutex<HashMap<String, Counts>> = Mutex::new(HashMap::new());
+}
+
+const CONNECTIONS_METRIC: &str = "gateway_websocket_connections";
+const CONNECTIONS_HELP: &str = "Open WebSocket connections proxied by the gateway";
+
+///占用一个连接名额 连接结束时释放
+struct ConnectionGuard {
+  product_code: String,
+  ip: Option<IpAddr>,
+}
+
+impl ConnectionGuard {
+  ///超出上限时返回拒绝原因
+  fn acquire(product_code: &str, ip: Option<IpAddr>, limits: &WebSocketLimits) -> Result<Self, &'static str> {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    let counts = connections.entry(product_code.to_string()).or_default();
+    if limits.max_connections.map(|max| counts.total >= max).unwrap_or(false) {
+      return Err("product connection limit reached");
+    }
+    if let (Some(ip), Some(max)) = (ip, limits.max_connections_per_ip) {
+      if counts.per_ip.get(&ip).copied().unwrap_or(0) >= max {
+        return Err("per-ip connection limit reached");
+      }
+    }
+    counts.total += 1;
+    if let Some(ip) = ip {
+      *counts.per_ip.entry(ip).or_default() += 1;
+    }
+    metrics::add_gauge(CONNECTIONS_METRIC, CONNECTIONS_HELP, &[("product", product_code)], 1.0);
+    Ok(Self {
+      product_code: product_code.to_string(),
+      ip,
+    })
+  }
+}
+
+impl Drop for ConnectionGuard {
+  fn drop(&mut self) {
+    let mut connections = CONNECTIONS.lock().unwrap();
+    if let Some(counts) = connections.get_mut(&self.product_code) {
+      counts.total = counts.total.saturating_sub(1);
+      if let Some(ip) = self.ip {
+        if let Some(count) = counts.per_ip.get_mut(&ip) {
+          *count -= 1;
+          if *count == 0 {
+            counts.per_ip.remove(&ip);
+          }
+        }
+      }
+    }
+    metrics::add_gauge(CONNECTIONS_METRIC, CONNECTIONS_HELP, &[("product", &self.product_code)], -1.0);
+  }
+}
+
+///是否为 WebSocket 升级请求
+pub fn is_upgrade(req: &HttpRequest) -> bool {
+  req
+    .headers()
+    .get(actix_web::http::header::UPGRADE)
+    .map(|v| v.as_bytes().eq_ignore_ascii_case(b"websocket"))
+    .unwrap_or(false)
+}
+
+fn to_upstream(msg: Message) -> Option<UpstreamMessage> {
+  Some(match msg {
+    Message::Text(text) => UpstreamMessage::Text(text),
+    Message::Binary(bytes) => UpstreamMessage::Binary(bytes),
+    Message::Continuation(item) => UpstreamMessage::Continuation(item),
+    Message::Ping(bytes) => UpstreamMessage::Ping(bytes),
+    Message::Pong(bytes) => UpstreamMessage::Pong(bytes),
+    Message::Close(reason) => UpstreamMessage::Close(reason),
+    Message::Nop => return None,
+  })
+}
+
+///代理 WebSocket 连接<br>
+/// 超出上限时仍然完成升级 随后发送 1013 关闭帧说明原因 方便客户端稍后重试
+pub async fn proxy(
+  req: &HttpRequest,
+  payload: web::Payload,
+  product_code: &str,
+  port: u16,
+  path_and_query: &str,
+  limits: &WebSocketLimits,
+  ip: Option<IpAddr>,
+) -> Result<HttpResponse, Error> {
+  let (mut response, mut session, mut client_stream) = actix_ws::handle(req, payload)?;
+  let guard = match ConnectionGuard::acquire(product_code, ip, limits) {
+    Ok(guard) => guard,
+    Err(reason) => {
+      metrics::inc_counter(
+        "gateway_websocket_rejected_total",
+        "WebSocket upgrades rejected by connection limits",
+        &[("product", product_code), ("reason", reason)],
+        1,
+      );
+      actix_web::rt::spawn(async move {
+        let _ = session
+          .close(Some(CloseReason {
+            code: CloseCode::Again,
+            description: Some(reason.to_string()),
+          }))
+          .await;
+      });
+      return Ok(response);
+    }
+  };
+  let mut upstream_req = Client::new().ws(format!("ws://127.0.0.1:{}{}", port, path_and_query));
+  //握手相关的头由两段连接各自生成 子协议的候选列表交给 runtime 选择
+  let forwarded =
+    |name: &str| name == "sec-websocket-protocol" || (!name.starts_with("sec-websocket") && !matches!(name, "connection" | "upgrade" | "host"));
+  for (name, value) in req.headers().iter().filter(|(name, _)| forwarded(name.as_str())) {
+    upstream_req = upstream_req.set_header(name.clone(), value.clone());
+  }
+  if let Some(ip) = ip {
+    upstream_req = upstream_req.set_header("x-forwarded-for", ip.to_string());
+  }
+  let mut upstream = match upstream_req.connect().await {
+    Ok((upstream_res, upstream)) => {
+      //runtime 选择的子协议带回给客户端 否则浏览器会因为协议不匹配关闭连接
+      if let Some(protocol) = upstream_res.headers().get(SEC_WEBSOCKET_PROTOCOL) {
+        response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, protocol.clone());
+      }
+      upstream
+    }
+    Err(err) => {
+      log::warn!("{} websocket upstream connect failed: {}", product_code, err);
+      actix_web::rt::spawn(async move {
+        let _ = session
+          .close(Some(CloseReason {
+            code: CloseCode::Error,
+            description: Some("service unavailable".to_string()),
+          }))
+          .await;
+      });
+      return Ok(response);
+    }
+  };
+  actix_web::rt::spawn(async move {
+    let _guard = guard;
+    loop {
+      select! {
+        msg = client_stream.next() => {
+          let msg = match msg {
+            Some(Ok(msg)) => msg,
+            _ => break,
+          };
+          let closing = matches!(msg, Message::Close(_));
+          if let Some(msg) = to_upstream(msg) {
+            if upstream.send(msg).await.is_err() || closing {
+              break;
+            }
+          }
+        }
+        frame = upstream.next() => {
+          let sent = match frame {
+            Some(Ok(Frame::Text(bytes))) => match String::from_utf8(bytes.to_vec()) {
+              Ok(text) => session.text(text).await,
+              Err(_) => session.binary(bytes).await,
+            },
+            Some(Ok(Frame::Binary(bytes))) => session.binary(bytes).await,
+            Some(Ok(Frame::Continuation(item))) => session.continuation(item).await,
+            Some(Ok(Frame::Ping(bytes))) => session.ping(&bytes).await,
+            Some(Ok(Frame::Pong(bytes))) => session.pong(&bytes).await,
+            Some(Ok(Frame::Close(reason))) => {
+              let _ = session.close(reason).await;
+              return;
+            }
+            _ => {
+              let _ = session.close(None).await;
+              return;
+            }
+          };
+          if sent.is_err() {
+            break;
+          }
+        }
+      }
+    }
+    let _ = upstream.close().await;
+  });
+  Ok(response)
+}
@@ -1,29 +1,60 @@
+use deno_core::error::generic_error;
 use deno_core::error::AnyError;
 use deno_core::error::JsError;
 use deno_runtime::colors;
 use deno_runtime::fmt_errors::format_js_error;
+use deno_runtime::ops::tasks;
 use deno_runtime::tokio_util::create_and_run_current_thread;
+use futures_util::future::join_all;
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use service::args;
 use service::args::flags_from_vec;
 use service::args::DenoSubcommand;
+use service::module_loader::{set_lazy_load_hook, LazyLoad};
 use service::tools::run::run_script;
 use service::tools::run::run_with_watch;
 use service::util::v8::get_v8_flags_from_env;
 use service::util::v8::init_v8_flags;
+use crate::config::{module_pins_path, product_dir, storage_dir, ProductConfig};
+use crate::crash;
+use crate::dns_cache;
+use crate::egress;
+use crate::heap_trend;
+use crate::logs;
+use crate::metrics;
+use crate::node_compat;
+use crate::offline;
+use crate::outbound;
+use crate::profiler;
+use crate::recycle;
+use crate::roles::{self, Role};
+use crate::routes;
+use crate::sandbox;
+use crate::trusted_cas;
+use crate::util::now_millis;
+use std::future::Future;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use std::{collections::HashMap, net::SocketAddr};
 use std::{env, thread};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::select;
 pub type WorkerTable = HashMap<ScriptWorkerId, ScriptWorkerThread>;
-pub type PortTable = HashMap<ScriptWorkerId, WorkerPort>;
+pub type PortTable = HashMap<ScriptWorkerId, Vec<PortEntry>>;
+
+///检查哪些端口需要探测的间隔
+const HEALTH_TICK: Duration = Duration::from_secs(1);
+///停止 runtime 前检查转发中的请求是否完成的间隔
+const DRAIN_POLL: Duration = Duration::from_millis(100);
 
 lazy_static! {
   pub static ref WORKER_PORT: Arc<Mutex<WorkerPort>> = Arc::new(Mutex::new(WorkerPort(3000)));
   pub static ref WORKER_TABLE: Arc<Mutex<WorkerTable>> = Arc::new(Mutex::new(WorkerTable::new()));
   pub static ref PORT_TABLE: Arc<RwLock<PortTable>> = Arc::new(RwLock::new(PortTable::new()));
+  static ref CONNECTIONS: Mutex<HashMap<WorkerPort, usize>> = Mutex::new(HashMap::new()); //每个端口转发中的请求数
+  static ref CURSORS: Mutex<HashMap<ScriptWorkerId, usize>> = Mutex::new(HashMap::new()); //轮询到的位置
 }
 
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -34,8 +65,141 @@ impl WorkerPort {
   }
 }
 
+///端口的探测结果 还没有探测过时为 Unknown 照常转发
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PortHealth {
+  #[default]
+  Unknown,
+  Healthy,
+  Unhealthy,
+}
+
+///产品有多个端口副本时的负载均衡 cool.json 中的 balance
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+  #[default]
+  RoundRobin,
+  LeastConnections, //转发中的请求最少的端口
+}
+
+///没有可以转发的端口的原因
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortUnavailable {
+  NotFound,
+  Draining,
+  Unhealthy(String),
+}
+
+///转发中的请求占用的端口 drop 时释放
+pub struct PortLease(pub WorkerPort);
+
+impl Drop for PortLease {
+  fn drop(&mut self) {
+    if let Some(count) = CONNECTIONS.lock().unwrap().get_mut(&self.0) {
+      *count = count.saturating_sub(1);
+    }
+  }
+}
+
+///PORT_TABLE 中的端口和健康状态
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortEntry {
+  pub port: WorkerPort,
+  pub health: PortHealth,
+  pub failures: u32,              //连续失败的次数
+  pub successes: u32,             //连续成功的次数
+  pub checked_at: u64,            //最近一次探测的时间
+  pub last_error: Option<String>, //最近一次失败的原因
+  pub draining: bool,             //正在停止端口上最后一个 runtime 网关不再转发新的请求
+}
+
+impl PortEntry {
+  fn new(port: WorkerPort) -> Self {
+    Self { port, ..Default::default() }
+  }
+
+  ///记录一次探测 状态改变时返回新的状态
+  fn record(&mut self, result: Result<(), String>, check: &HealthCheck) -> Option<PortHealth> {
+    self.checked_at = now_millis();
+    let health = match result {
+      Ok(()) => {
+        self.failures = 0;
+        self.successes += 1;
+        self.last_error = None;
+        match self.successes >= check.healthy_threshold.max(1) {
+          true => PortHealth::Healthy,
+          false => self.health,
+        }
+      }
+      Err(err) => {
+        self.successes = 0;
+        self.failures += 1;
+        self.last_error = Some(err);
+        match self.failures >= check.unhealthy_threshold.max(1) {
+          true => PortHealth::Unhealthy,
+          false => self.health,
+        }
+      }
+    };
+    if health == self.health {
+      return None;
+    }
+    self.health = health;
+    Some(health)
+  }
+}
+
+///端口健康检查 cool.json 中的 health<br>
+/// 定时请求 path 有响应且状态码小于 500 时算成功 没有实现这个路径的脚本返回 404 也算成功<br>
+/// 连接失败 超时或 5xx 连续达到 unhealthy_threshold 次后标记为不健康 网关直接返回 503
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthCheck {
+  pub path: String,
+  pub interval_ms: u64, //探测间隔 0 表示关闭
+  pub timeout_ms: u64,
+  pub unhealthy_threshold: u32,
+  pub healthy_threshold: u32,
+}
+
+impl Default for HealthCheck {
+  fn default() -> Self {
+    Self {
+      path: "/healthz".to_string(),
+      interval_ms: 5000,
+      timeout_ms: 2000,
+      unhealthy_threshold: 3,
+      healthy_threshold: 1,
+    }
+  }
+}
+
+///停止 runtime 的方式 cool.json 中的 shutdown<br>
+/// 停止前等待已经开始的请求完成 超过 drain_timeout_ms 仍未完成时直接停止
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShutdownPolicy {
+  pub drain_timeout_ms: u64,
+}
+
+impl Default for ShutdownPolicy {
+  fn default() -> Self {
+    Self { drain_timeout_ms: 30_000 }
+  }
+}
+
 pub struct Terminate {
   notify_serder: async_channel::Sender<u8>, //结束当前runtime
+  port: WorkerPort,                         //runtime 接收请求的端口
+}
+
+///一个端口的 server 副本的端口各自有一个
+struct Listener {
+  port: WorkerPort,
+  stream_rx: async_channel::Receiver<TcpStream>,
+  server_tx: async_channel::Sender<ServerStatus>,
 }
 ///项目server 的状态
 pub enum ServerStatus {
@@ -51,8 +215,31 @@ pub struct ScriptWorkerId(pub String);
 
 ///项目信息
 pub struct Project {
-  pub name: String, //名称 一般为英文
-  pub path: String, //启动项目代码路径
+  pub name: String,         //名称 一般为英文
+  pub path: String,         //启动项目代码路径
+  pub args: Vec<String>,    //脚本参数
+  pub cwd: Option<PathBuf>, //工作目录
+  pub lazy_imports: bool,   //动态导入延迟到第一次执行时加载
+}
+impl Project {
+  ///按产品配置构建 入口参数和工作目录来自 cool.json
+  pub fn from_product(product_code: &str) -> Self {
+    let config = ProductConfig::load(product_code).unwrap_or_default();
+    //声明了路由清单时由生成的入口模块分发请求 其次是 entries 中的 http 角色
+    let entry_path = |entry: &str| product_dir(product_code).join(entry).to_string_lossy().to_string();
+    let (path, args) = match config.entries.iter().find(|e| e.role == Role::Http) {
+      _ if !config.routes.is_empty() => (routes::host_module(product_code, &config.routes), config.runtime.args.clone()),
+      Some(entry) => (entry_path(&entry.entry), entry.args.clone()),
+      None => (entry_path(&config.runtime.entry), config.runtime.args.clone()),
+    };
+    Self {
+      name: product_code.to_string(),
+      path,
+      args,
+      cwd: config.runtime.cwd_path(product_code),
+      lazy_imports: config.runtime.lazy_imports,
+    }
+  }
 }
 ///项目woker入口
 pub struct ScriptWorkerThread {
@@ -64,47 +251,17 @@ pub struct ScriptWorkerThread {
   stream_rx: async_channel::Receiver<TcpStream>,
   server_tx: async_channel::Sender<ServerStatus>,    // server状态通道 控制服务状态
   pub watch_tx: Option<async_channel::Sender<bool>>, //热加载模式时使用
+  replicas: Vec<Listener>,                           //port 之外的端口副本 每个副本有自己的 runtime
 }
 impl ScriptWorkerThread {
   ///创建一个新的 worker
   /// project项目信息
   pub fn new(project: Project) -> Self {
-    let (server_tx, server_rx) = async_channel::bounded::<ServerStatus>(1);
-    let (stream_tx, stream_rx) = async_channel::unbounded::<TcpStream>();
-    let thread_name = project.name.clone();
-    let port = get_next_port(&project);
-    //异步启动当前worker server
-    tokio::spawn(async move {
-      let addr: SocketAddr = SocketAddr::from(([127, 0, 0, 1], port.0));
-      let tcp_listener = TcpListener::bind(addr).await.unwrap();
-      println!("starting {} HTTP server at http://127.0.0.1:{}", thread_name, port.0);
-      let mut ok = false;
-      loop {
-        select!(
-            Ok((tcp_stream,_add))= tcp_listener.accept() => {
-              if ok {
-                let _ = tcp_stream.try_write(b"\xE5\x81\x9C\xE6\xAD\xA2\xE6\x9C\x8D\xE5\x8A\xA1");
-              }else{
-                let _ = stream_tx.send(tcp_stream).await;
-              }
-            }
-            Ok(item) = server_rx.recv() => {
-               match item{
-                ServerStatus::Start => {
-                  ok=false;
-                },
-                ServerStatus::Wait => {
-                  ok=true;
-                },
-                ServerStatus::Exit => {
-                  println!("stop {} HTTP server at http://127.0.0.1:{}", thread_name, port.0);
-                  break;
-                },
-              }
-            }
-        );
-      }
-    });
+    let port = get_next_port();
+    let mut hand_port = PORT_TABLE.write().unwrap();
+    hand_port.insert(ScriptWorkerId(project.name.clone()), vec![PortEntry::new(port)]);
+    drop(hand_port);
+    let Listener { stream_rx, server_tx, .. } = listen(project.name.clone(), port);
     Self {
       id: ScriptWorkerId(project.name.clone()),
       stream_rx,
@@ -114,6 +271,63 @@ impl ScriptWorkerThread {
       open_debug_server: false,
       watch_tx: None,
       worker_handlers: Mutex::new(Vec::new()),
+      replicas: Vec::new(),
+    }
+  }
+  ///所有端口 第一个为 port
+  fn ports(&self) -> Vec<WorkerPort> {
+    std::iter::once(self.port).chain(self.replicas.iter().map(|l| l.port)).collect()
+  }
+  ///端口的连接通道和状态通道
+  fn listener(&self, port: WorkerPort) -> (async_channel::Receiver<TcpStream>, async_channel::Sender<ServerStatus>) {
+    match self.replicas.iter().find(|l| l.port == port) {
+      Some(l) => (l.stream_rx.clone(), l.server_tx.clone()),
+      None => (self.stream_rx.clone(), self.server_tx.clone()),
+    }
+  }
+  ///runtime 最少的端口
+  fn least_loaded_port(&self) -> WorkerPort {
+    let harr = self.worker_handlers.lock().unwrap();
+    let ports = self.ports();
+    ports.into_iter().min_by_key(|p| harr.iter().filter(|h| h.port == *p).count()).unwrap()
+  }
+  ///停止端口上的所有 runtime 请求完成后关闭副本的 server
+  fn retire_replica(&mut self, port: WorkerPort) {
+    let Some(position) = self.replicas.iter().position(|l| l.port == port) else {
+      return;
+    };
+    let listener = self.replicas.remove(position);
+    set_draining(&self.id, port, true);
+    let mut harr = self.worker_handlers.lock().unwrap();
+    let (hands, rest): (Vec<Terminate>, Vec<Terminate>) = harr.drain(..).partition(|h| h.port == port);
+    *harr = rest;
+    let id = self.id.clone();
+    tokio::task::spawn(async move {
+      join_all(hands.into_iter().map(|hand| drain_and_terminate(&id.0, hand))).await;
+      let _ = listener.server_tx.send(ServerStatus::Exit).await;
+      remove_port(&id, port);
+    });
+  }
+  ///调整端口副本数 每个端口至少有一个 runtime<br>
+  /// CPU 密集的产品可以分散到多个端口和 isolate 多出的副本等转发中的请求完成后关闭
+  pub async fn set_replicas(&mut self, replicas: usize) {
+    let replicas = replicas.max(1);
+    while self.replicas.len() + 1 < replicas {
+      let port = get_next_port();
+      if let Some(ports) = PORT_TABLE.write().unwrap().get_mut(&self.id) {
+        ports.push(PortEntry::new(port));
+      }
+      self.replicas.push(listen(self.id.0.clone(), port));
+    }
+    while self.replicas.len() + 1 > replicas {
+      let port = self.replicas.last().unwrap().port;
+      self.retire_replica(port);
+    }
+    for port in self.ports() {
+      let running = self.worker_handlers.lock().unwrap().iter().any(|h| h.port == port);
+      if !running {
+        self.start_runtime_on(port).await;
+      }
     }
   }
   ///停止开发服务
@@ -139,20 +353,38 @@ impl ScriptWorkerThread {
     args.push("--unstable".to_string());
     args.push("--watch".to_string());
     args.push(self.project.path.clone());
+    args.extend(self.project.args.clone());
+    let cwd = self.project.cwd.clone();
+    let lazy_imports = self.project.lazy_imports;
+    let product_code = self.id.0.clone();
+    let module_pins = module_pins_path(&product_code);
     let build = thread::Builder::new().name(format!("product-{}-debugger", self.id.clone().0));
     let _ = build.spawn(|| {
       let fut = async move {
-        let flags = match flags_from_vec(args) {
+        let mut flags = match flags_from_vec(args) {
           Ok(flags) => flags,
           Err(err) => unwrap_or_exit(Err(AnyError::from(err))),
         };
+        flags.cwd = cwd;
+        flags.lazy_dynamic_imports = lazy_imports;
+        flags.module_pins = Some(module_pins);
+        flags.storage_dir = Some(storage_dir(&product_code));
+        flags.product_code = Some(product_code.clone());
+        offline::apply(&mut flags, &product_code);
+        egress::apply(&product_code);
+        dns_cache::apply(&product_code);
+        outbound::apply(&product_code);
+        node_compat::apply(&mut flags, &product_code);
+        trusted_cas::apply(&mut flags, &product_code);
+        //临时目录在热加载结束后删除
+        let _scratch = sandbox::apply(&mut flags, &product_code, "debugger");
         let default_v8_flags = match flags.subcommand {
           DenoSubcommand::Lsp => vec!["--max-old-space-size=3072".to_string()],
           _ => vec![],
         };
         init_v8_flags(&default_v8_flags, &flags.v8_flags, get_v8_flags_from_env());
         //Script Engine Start
-        let code = run_with_watch(flags, stream_rx, watch_rx).await;
+        let code = run_with_watch(flags, stream_rx, watch_rx, || logs::capture(&product_code), crash::hook(&product_code)).await;
         let handle = thread::current();
         let name = handle.name().unwrap();
         println!("{}  Worker stop info {:?}", name, code);
@@ -171,16 +403,30 @@ impl ScriptWorkerThread {
       self.start_runtime().await;
     }
   }
-  ///生产环境可以启动
+  ///生产环境可以启动 新的 runtime 放在 runtime 最少的端口
   pub async fn start_runtime(&mut self) {
+    self.start_runtime_on(self.least_loaded_port()).await;
+  }
+  ///在指定端口上启动 runtime
+  async fn start_runtime_on(&mut self, port: WorkerPort) {
     let size = self.worker_handlers.lock().unwrap().len();
-    let stream_rx = self.stream_rx.clone();
+    let on_port = self.worker_handlers.lock().unwrap().iter().filter(|h| h.port == port).count();
+    let (stream_rx, server_tx) = self.listener(port);
     let (notify_tx, notify_rx) = async_channel::bounded::<u8>(1);
     let mut args: Vec<String> = env::args().collect();
     args.push("run".to_string());
     args.push(self.project.path.clone());
+    args.extend(self.project.args.clone());
+    let cwd = self.project.cwd.clone();
+    let lazy_imports = self.project.lazy_imports;
+    let product_code = self.id.0.clone();
+    let module_pins = module_pins_path(&product_code);
     let open_debug_server = self.open_debug_server;
-    let build = thread::Builder::new().name(format!("product-{}-{}", self.id.clone().0, size));
+    let profile = profiler::control(&product_code);
+    let runtime_name = format!("product-{}-{}", self.id.clone().0, size);
+    let heap = heap_trend::control(&product_code, &runtime_name, notify_tx.clone());
+    recycle::register(&product_code, &runtime_name, notify_tx.clone());
+    let build = thread::Builder::new().name(runtime_name);
     let _ = build.spawn(move || {
       let fut = async move {
         let mut flags: args::Flags = match flags_from_vec(args) {
@@ -193,12 +439,34 @@ impl ScriptWorkerThread {
         };
         init_v8_flags(&default_v8_flags, &flags.v8_flags, get_v8_flags_from_env());
         flags.unstable = true;
+        flags.cwd = cwd;
+        flags.lazy_dynamic_imports = lazy_imports;
+        flags.module_pins = Some(module_pins);
+        flags.storage_dir = Some(storage_dir(&product_code));
+        flags.product_code = Some(product_code.clone());
+        offline::apply(&mut flags, &product_code);
+        egress::apply(&product_code);
+        dns_cache::apply(&product_code);
+        outbound::apply(&product_code);
+        node_compat::apply(&mut flags, &product_code);
+        trusted_cas::apply(&mut flags, &product_code);
+        //每个 runtime 使用自己的临时目录 runtime 结束后删除
+        let _scratch = sandbox::apply(&mut flags, &product_code, &uuid::Uuid::new_v4().to_string());
         //开启 debugger
         if open_debug_server {
           let default = || "127.0.0.1:9229".parse::<SocketAddr>().unwrap();
           flags.inspect = Some(default());
         }
-        let code = run_script(flags, stream_rx, notify_rx).await;
+        let code = run_script(
+          flags,
+          stream_rx,
+          notify_rx,
+          logs::capture(&product_code),
+          crash::hook(&product_code),
+          Some(profile),
+          heap,
+        )
+        .await;
         let handle = thread::current();
         let name = handle.name().unwrap();
         println!("{}  Worker stop info {:?}", name, code);
@@ -206,24 +474,49 @@ impl ScriptWorkerThread {
       create_and_run_current_thread(fut);
     });
     let mut harr: std::sync::MutexGuard<'_, Vec<Terminate>> = self.worker_handlers.lock().unwrap();
-    harr.push(Terminate { notify_serder: notify_tx });
+    harr.push(Terminate {
+      notify_serder: notify_tx,
+      port,
+    });
+    drop(harr);
+    if on_port == 0 {
+      set_draining(&self.id, port, false);
+      let _ = server_tx.send(ServerStatus::Start).await;
+    }
     if size == 0 {
-      let _ = self.server_tx.send(ServerStatus::Start).await;
+      //第一个生产 runtime 启动时 同时启动后台和定时角色
+      roles::start_roles(&self.id.0);
     }
   }
-  ///停止runtime
+  ///停止runtime 最后启动的先停止
   pub fn stop_runtime(&mut self) -> bool {
     let mut harr = self.worker_handlers.lock().unwrap();
-    if let Some(hand) = &harr.pop() {
+    if let Some(hand) = harr.pop() {
       let len = harr.len();
-      let notify_serder = hand.notify_serder.clone();
-      let server_tx_ref = self.server_tx.clone();
+      let port = hand.port;
+      let port_idle = !harr.iter().any(|h| h.port == port);
+      drop(harr);
+      //副本端口上最后一个runtime停止时 关闭这个副本
+      if port_idle && port != self.port && len > 0 {
+        self.worker_handlers.lock().unwrap().push(hand);
+        self.retire_replica(port);
+        return true;
+      }
+      let id = self.id.0.clone();
+      let (_, server_tx_ref) = self.listener(port);
+      //停止端口上最后一个runtime时 不再转发新的请求
+      if port_idle {
+        set_draining(&self.id, port, true);
+      }
       tokio::task::spawn(async move {
-        //停止runtime
-        let _ = notify_serder.send(1).await;
-        let _ = notify_serder.close();
+        //等待转发中的请求完成后停止runtime
+        drain_and_terminate(&id, hand).await;
         //如果没有runtime在运行 则暂停接收请求
         if len == 0 {
+          roles::stop_roles(&id);
+          tasks::fail_unfinished_tasks(&id, "runtime stopped");
+        }
+        if port_idle {
           let _ = server_tx_ref.send(ServerStatus::Wait).await;
         }
       });
@@ -231,6 +524,38 @@ impl ScriptWorkerThread {
     }
     false
   }
+  ///部署新版本 先启动新runtime 再停止最早的runtime 切换过程中不中断服务
+  pub async fn swap_runtime(&mut self) {
+    let size = self.worker_handlers.lock().unwrap().len();
+    //新的 runtime 放在最早的 runtime 的端口上 端口副本数不变
+    let port = self.worker_handlers.lock().unwrap().first().map(|h| h.port);
+    self.start_runtime_on(port.unwrap_or_else(|| self.least_loaded_port())).await;
+    if size > 0 {
+      let oldest = self.worker_handlers.lock().unwrap().remove(0);
+      let id = self.id.0.clone();
+      tokio::task::spawn(async move { drain_and_terminate(&id, oldest).await });
+      //后台和定时角色同样切换到新代码
+      roles::start_roles(&self.id.0);
+    }
+  }
+  ///平滑重启 notify 对应的 runtime 先启动新的 runtime 再停止它 已经停止时返回 false
+  pub async fn replace_runtime(&mut self, notify: &async_channel::Sender<u8>) -> bool {
+    let position = self
+      .worker_handlers
+      .lock()
+      .unwrap()
+      .iter()
+      .position(|h| h.notify_serder.same_channel(notify));
+    let Some(position) = position else {
+      return false;
+    };
+    let port = self.worker_handlers.lock().unwrap()[position].port;
+    self.start_runtime_on(port).await;
+    let old = self.worker_handlers.lock().unwrap().remove(position);
+    let id = self.id.0.clone();
+    tokio::task::spawn(async move { drain_and_terminate(&id, old).await });
+    true
+  }
   pub fn stop_all_runtime(&mut self) {
     self.stop_watch_runtime();
     loop {
@@ -250,7 +575,134 @@ impl Drop for ScriptWorkerThread {
     self.stop_all_runtime();
     //停止server 服务
     let _ = self.server_tx.send_blocking(ServerStatus::Exit);
+    for replica in self.replicas.iter() {
+      let _ = replica.server_tx.send_blocking(ServerStatus::Exit);
+    }
+  }
+}
+
+///在端口上启动 server 收到的连接交给这个端口上的 runtime
+fn listen(thread_name: String, port: WorkerPort) -> Listener {
+  let (server_tx, server_rx) = async_channel::bounded::<ServerStatus>(1);
+  let (stream_tx, stream_rx) = async_channel::unbounded::<TcpStream>();
+  //异步启动当前worker server
+  tokio::spawn(async move {
+    let addr: SocketAddr = SocketAddr::from(([127, 0, 0, 1], port.0));
+    let tcp_listener = TcpListener::bind(addr).await.unwrap();
+    println!("starting {} HTTP server at http://127.0.0.1:{}", thread_name, port.0);
+    let mut ok = false;
+    loop {
+      select!(
+          Ok((tcp_stream,_add))= tcp_listener.accept() => {
+            if ok {
+              let _ = tcp_stream.try_write(b"\xE5\x81\x9C\xE6\xAD\xA2\xE6\x9C\x8D\xE5\x8A\xA1");
+            }else{
+              let _ = stream_tx.send(tcp_stream).await;
+            }
+          }
+          Ok(item) = server_rx.recv() => {
+             match item{
+              ServerStatus::Start => {
+                ok=false;
+              },
+              ServerStatus::Wait => {
+                ok=true;
+              },
+              ServerStatus::Exit => {
+                println!("stop {} HTTP server at http://127.0.0.1:{}", thread_name, port.0);
+                break;
+              },
+            }
+          }
+      );
+    }
+  });
+  Listener { port, stream_rx, server_tx }
+}
+
+///标记端口是否正在停止
+fn set_draining(id: &ScriptWorkerId, port: WorkerPort, draining: bool) {
+  let mut table = PORT_TABLE.write().unwrap();
+  if let Some(entry) = table.get_mut(id).and_then(|ports| ports.iter_mut().find(|e| e.port == port)) {
+    entry.draining = draining;
+  }
+}
+
+///副本关闭后移除它的端口
+fn remove_port(id: &ScriptWorkerId, port: WorkerPort) {
+  if let Some(ports) = PORT_TABLE.write().unwrap().get_mut(id) {
+    ports.retain(|e| e.port != port);
   }
+  CONNECTIONS.lock().unwrap().remove(&port);
+}
+
+///按策略选择端口 跳过正在停止和不健康的端口<br>
+/// cursor 为轮询的位置 connections 为每个端口转发中的请求数
+fn choose(entries: &[PortEntry], balance: Balance, cursor: usize, connections: &HashMap<WorkerPort, usize>) -> Result<WorkerPort, PortUnavailable> {
+  let available: Vec<&PortEntry> = entries.iter().filter(|e| !e.draining && e.health != PortHealth::Unhealthy).collect();
+  if available.is_empty() {
+    return Err(match entries.iter().find(|e| !e.draining) {
+      Some(entry) => PortUnavailable::Unhealthy(entry.last_error.clone().unwrap_or_default()),
+      None if entries.is_empty() => PortUnavailable::NotFound,
+      None => PortUnavailable::Draining,
+    });
+  }
+  let entry = match balance {
+    Balance::RoundRobin => available[cursor % available.len()],
+    Balance::LeastConnections => available
+      .iter()
+      .copied()
+      .min_by_key(|e| connections.get(&e.port).copied().unwrap_or(0))
+      .unwrap(),
+  };
+  Ok(entry.port)
+}
+
+///为请求选择产品的端口 请求完成前租约计入端口的连接数
+pub fn lease_port(id: &ScriptWorkerId, balance: Balance) -> Result<PortLease, PortUnavailable> {
+  let entries = PORT_TABLE.read().unwrap().get(id).cloned().ok_or(PortUnavailable::NotFound)?;
+  let cursor = {
+    let mut cursors = CURSORS.lock().unwrap();
+    let cursor = cursors.entry(id.clone()).or_default();
+    *cursor = cursor.wrapping_add(1);
+    *cursor
+  };
+  let mut connections = CONNECTIONS.lock().unwrap();
+  let port = choose(&entries, balance, cursor, &connections)?;
+  *connections.entry(port).or_default() += 1;
+  Ok(PortLease(port))
+}
+
+///等待停止前已经开始转发的请求完成 超时后直接停止 runtime<br>
+/// 同一产品的 runtime 共用端口 无法区分请求由哪个 runtime 处理 所以等待产品所有已经开始的请求
+async fn drain_and_terminate(product_code: &str, hand: Terminate) {
+  let policy = ProductConfig::load(product_code).map(|c| c.shutdown).unwrap_or_default();
+  let before = crash::next_request_id();
+  let deadline = tokio::time::Instant::now() + Duration::from_millis(policy.drain_timeout_ms);
+  loop {
+    let pending = crash::inflight_before(product_code, before);
+    if pending == 0 {
+      break;
+    }
+    if tokio::time::Instant::now() >= deadline {
+      log::warn!(
+        "{} still has {} requests in flight after {} ms, stopping the runtime",
+        product_code,
+        pending,
+        policy.drain_timeout_ms
+      );
+      metrics::inc_counter(
+        "runtime_drain_timeouts_total",
+        "Runtimes stopped before their in-flight requests finished",
+        &[("product", product_code)],
+        1,
+      );
+      break;
+    }
+    tokio::time::sleep(DRAIN_POLL).await;
+  }
+  let _ = hand.notify_serder.send(1).await;
+  let _ = hand.notify_serder.close();
 }
 
 fn unwrap_or_exit<T>(result: Result<T, AnyError>) -> T {
@@ -270,8 +722,132 @@ fn unwrap_or_exit<T>(result: Result<T, AnyError>) -> T {
     }
   }
 }
+///构建 deno 工具使用的参数 与 runtime 启动参数保持一致
+pub fn tool_flags(subcommand: &str, path: &str) -> Result<args::Flags, AnyError> {
+  let args = vec!["deno".to_string(), subcommand.to_string(), path.to_string()];
+  let mut flags = flags_from_vec(args).map_err(AnyError::from)?;
+  flags.unstable = true;
+  Ok(flags)
+}
+
+///在独立线程里运行 deno 工具(模块图分析等) 不阻塞网关线程
+pub async fn run_tool<F, Fut, T>(name: String, f: F) -> Result<T, AnyError>
+where
+  F: FnOnce() -> Fut + Send + 'static,
+  Fut: Future<Output = Result<T, AnyError>> + 'static,
+  T: Send + 'static,
+{
+  let (tx, rx) = tokio::sync::oneshot::channel();
+  thread::Builder::new().name(name).spawn(move || {
+    let fut = async move {
+      let _ = tx.send(f().await);
+    };
+    create_and_run_current_thread(fut);
+  })?;
+  rx.await.map_err(|_| generic_error("tool thread exited unexpectedly"))?
+}
+
+///动态导入延迟加载的指标 模块在 runtime 线程中加载 计数写入网关的指标
+fn record_lazy_load(load: &LazyLoad) {
+  let product = load.product_code.as_deref().unwrap_or_default();
+  log::debug!("{} lazily loaded {} ({} modules)", product, load.specifier, load.modules);
+  let labels = [("product", product)];
+  metrics::inc_counter("runtime_lazy_imports_total", "Dynamic imports loaded on first use", &labels, 1);
+  metrics::inc_counter(
+    "runtime_lazy_modules_total",
+    "Modules loaded on first use of a dynamic import",
+    &labels,
+    load.modules as u64,
+  );
+  metrics::inc_counter(
+    "runtime_lazy_import_milliseconds_total",
+    "Time spent preparing dynamic imports on first use",
+    &labels,
+    load.elapsed.as_millis() as u64,
+  );
+}
+
+async fn probe(client: &reqwest::Client, port: WorkerPort, check: &HealthCheck) -> Result<(), String> {
+  let url = format!("http://127.0.0.1:{}{}", port.0, check.path);
+  let res = client
+    .get(&url)
+    .timeout(Duration::from_millis(check.timeout_ms))
+    .send()
+    .await
+    .map_err(|e| e.to_string())?;
+  match res.status().is_server_error() {
+    true => Err(format!("{} returned {}", check.path, res.status())),
+    false => Ok(()),
+  }
+}
+
+///探测到期的端口 结果写回 PORT_TABLE 端口已经换掉时丢弃结果
+async fn check_ports(client: &reqwest::Client) {
+  let entries: Vec<(ScriptWorkerId, WorkerPort, u64)> = PORT_TABLE
+    .read()
+    .unwrap()
+    .iter()
+    .flat_map(|(id, ports)| ports.iter().map(move |entry| (id, entry)))
+    .filter(|(_, entry)| !entry.draining)
+    .map(|(id, entry)| (id.clone(), entry.port, entry.checked_at))
+    .collect();
+  let now = now_millis();
+  let mut due = vec![];
+  for (id, port, checked_at) in entries {
+    let check = ProductConfig::load(&id.0).map(|c| c.health).unwrap_or_default();
+    if check.interval_ms > 0 && now.saturating_sub(checked_at) >= check.interval_ms {
+      due.push((id, port, check));
+    }
+  }
+  let results = join_all(due.iter().map(|(_, port, check)| probe(client, *port, check))).await;
+  let mut table = PORT_TABLE.write().unwrap();
+  for ((id, port, check), result) in due.into_iter().zip(results) {
+    let Some(entry) = table.get_mut(&id).and_then(|ports| ports.iter_mut().find(|e| e.port == port)) else {
+      continue;
+    };
+    let Some(health) = entry.record(result, &check) else {
+      continue;
+    };
+    match health {
+      PortHealth::Unhealthy => log::warn!(
+        "{} port {} is unhealthy: {}",
+        id.0,
+        port.0,
+        entry.last_error.as_deref().unwrap_or_default()
+      ),
+      _ => log::info!("{} port {} is healthy", id.0, port.0),
+    }
+    let healthy = if health == PortHealth::Healthy { 1.0 } else { 0.0 };
+    metrics::set_gauge(
+      "runtime_port_healthy",
+      "Whether the worker port answers health checks",
+      &[("product", &id.0), ("port", &port.0.to_string())],
+      healthy,
+    );
+  }
+}
+
+///注册延迟加载的回调 启动端口健康检查
+pub fn start() {
+  set_lazy_load_hook(Arc::new(record_lazy_load));
+  let client = match reqwest::Client::builder().build() {
+    Ok(client) => client,
+    Err(err) => {
+      log::error!("failed to create the health check client: {}", err);
+      return;
+    }
+  };
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(HEALTH_TICK);
+    loop {
+      interval.tick().await;
+      check_ports(&client).await;
+    }
+  });
+}
+
 use port_selector::{is_free, Port};
-fn get_next_port(project: &Project) -> WorkerPort {
+fn get_next_port() -> WorkerPort {
   let mut curport = WORKER_PORT.lock().unwrap();
   let mut curr_port = curport.next().unwrap();
   //进行端口检测 如果有被占用的情况获取下一个
@@ -283,7 +859,43 @@ fn get_next_port(project: &Project) -> WorkerPort {
     }
   }
   *curport = curr_port.clone();
-  let mut hand_port = PORT_TABLE.write().unwrap();
-  hand_port.insert(ScriptWorkerId(project.name.clone()), curr_port.clone());
   return curr_port;
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn marks_unhealthy_after_consecutive_failures() {
+    let check = HealthCheck::default();
+    let mut entry = PortEntry::new(WorkerPort(3001));
+    assert_eq!(entry.record(Ok(()), &check), Some(PortHealth::Healthy));
+    assert_eq!(entry.record(Err("timed out".to_string()), &check), None);
+    assert_eq!(entry.record(Err("timed out".to_string()), &check), None);
+    assert_eq!(entry.record(Err("connection refused".to_string()), &check), Some(PortHealth::Unhealthy));
+    assert_eq!(entry.last_error.as_deref(), Some("connection refused"));
+    assert_eq!(entry.record(Ok(()), &check), Some(PortHealth::Healthy));
+    assert_eq!(entry.failures, 0);
+  }
+
+  #[test]
+  fn balances_across_available_ports() {
+    let mut entries: Vec<PortEntry> = (3001..=3003).map(|p| PortEntry::new(WorkerPort(p))).collect();
+    entries[1].draining = true;
+    let connections = HashMap::from([(WorkerPort(3001), 4), (WorkerPort(3003), 1)]);
+    let picked: Vec<u16> = (0..4)
+      .map(|cursor| choose(&entries, Balance::RoundRobin, cursor, &connections).unwrap().0)
+      .collect();
+    assert_eq!(picked, vec![3001, 3003, 3001, 3003]);
+    assert_eq!(choose(&entries, Balance::LeastConnections, 0, &connections), Ok(WorkerPort(3003)));
+    entries[2].health = PortHealth::Unhealthy;
+    entries[2].last_error = Some("timed out".to_string());
+    entries[0].draining = true;
+    assert_eq!(
+      choose(&entries, Balance::RoundRobin, 0, &connections),
+      Err(PortUnavailable::Unhealthy("timed out".to_string()))
+    );
+    assert_eq!(choose(&[], Balance::RoundRobin, 0, &connections), Err(PortUnavailable::NotFound));
+  }
+}
@@ -0,0 +1,333 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+
+//! Record and replay of outbound http(s) requests for deterministic test
+//! runs. Every test module gets its own cassette file. In record mode real
+//! responses are saved to the cassette as they arrive; in replay mode
+//! `op_fetch` answers from the cassette and never touches the network.
+
+use std::cell::RefCell;
+use std::path::Path;
+use std::path::PathBuf;
+
+use deno_core::error::type_error;
+use deno_core::error::AnyError;
+use deno_core::serde_json;
+use deno_core::url::Position;
+use deno_core::url::Url;
+use reqwest::header::HeaderMap;
+use reqwest::Method;
+use reqwest::Response;
+use reqwest::ResponseBuilderExt;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Value stored instead of a redacted header.
+const REDACTED: &str = "[REDACTED]";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CassetteMode {
+  Record,
+  Replay,
+}
+
+/// Parts of a request compared when looking up a recorded response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchRule {
+  Method,
+  /// The whole url without the fragment.
+  Url,
+  /// Scheme, host, port and path, ignoring the query.
+  Path,
+  /// Query parameters in any order.
+  Query,
+  /// The request body. Streamed bodies are not recorded and never match.
+  Body,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CassetteConfig {
+  pub mode: CassetteMode,
+  /// Cassettes are named after the test module, relative to the parent of
+  /// this directory when the module is inside it.
+  pub dir: PathBuf,
+  pub match_on: Vec<MatchRule>,
+  /// Header names, request and response headers with these names are saved
+  /// as `[REDACTED]`.
+  pub redact_headers: Vec<String>,
+}
+
+impl CassetteConfig {
+  pub fn default_match_on() -> Vec<MatchRule> {
+    vec![MatchRule::Method, MatchRule::Url]
+  }
+
+  pub fn default_redact_headers() -> Vec<String> {
+    ["authorization", "proxy-authorization", "cookie", "set-cookie", "x-api-key"]
+      .into_iter()
+      .map(String::from)
+      .collect()
+  }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedBody {
+  /// Set when the body is valid utf-8.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub text: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub bytes: Option<Vec<u8>>,
+}
+
+impl RecordedBody {
+  fn new(bytes: Vec<u8>) -> Self {
+    match String::from_utf8(bytes) {
+      Ok(text) => Self {
+        text: Some(text),
+        bytes: None,
+      },
+      Err(err) => Self {
+        text: None,
+        bytes: Some(err.into_bytes()),
+      },
+    }
+  }
+
+  fn to_vec(&self) -> Vec<u8> {
+    match (&self.text, &self.bytes) {
+      (Some(text), _) => text.as_bytes().to_vec(),
+      (None, Some(bytes)) => bytes.clone(),
+      (None, None) => vec![],
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+  pub method: String,
+  pub url: String,
+  pub headers: Vec<(String, String)>,
+  /// `None` when the body was streamed.
+  pub body: Option<RecordedBody>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedResponse {
+  pub status: u16,
+  pub headers: Vec<(String, String)>,
+  pub body: RecordedBody,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Interaction {
+  pub request: RecordedRequest,
+  pub response: RecordedResponse,
+}
+
+/// The cassette of one test module, put into the op state of its worker.
+pub struct Cassette {
+  config: CassetteConfig,
+  path: PathBuf,
+  interactions: RefCell<Vec<Interaction>>,
+  /// Replay mode: interactions already served, in cassette order.
+  used: RefCell<Vec<bool>>,
+}
+
+fn cassette_name(dir: &Path, specifier: &Url) -> String {
+  let path = match specifier.to_file_path() {
+    Ok(path) => {
+      let root = dir.parent().unwrap_or(dir);
+      path.strip_prefix(root).map(Path::to_path_buf).unwrap_or(path)
+    }
+    Err(_) => PathBuf::from(specifier.path()),
+  };
+  let name: String = path
+    .to_string_lossy()
+    .trim_start_matches(['/', '\\'])
+    .chars()
+    .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+    .collect();
+  format!("{name}.json")
+}
+
+fn sorted_query(url: &Url) -> Vec<(String, String)> {
+  let mut pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+  pairs.sort();
+  pairs
+}
+
+impl Cassette {
+  /// Opens the cassette of a test module. Record mode starts from an empty
+  /// cassette. In replay mode a missing cassette is empty, so modules that
+  /// make no requests don't need one.
+  pub fn open(config: CassetteConfig, specifier: &Url) -> Result<Self, AnyError> {
+    let path = config.dir.join(cassette_name(&config.dir, specifier));
+    let interactions: Vec<Interaction> = match config.mode {
+      CassetteMode::Record => vec![],
+      CassetteMode::Replay => match std::fs::read(&path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(err) => return Err(type_error(format!("Cannot read cassette {}: {err}", path.display()))),
+      },
+    };
+    Ok(Self {
+      used: RefCell::new(vec![false; interactions.len()]),
+      interactions: RefCell::new(interactions),
+      config,
+      path,
+    })
+  }
+
+  pub fn mode(&self) -> CassetteMode {
+    self.config.mode
+  }
+
+  fn matches(&self, recorded: &RecordedRequest, method: &Method, url: &Url, body: Option<&[u8]>) -> bool {
+    let Ok(recorded_url) = Url::parse(&recorded.url) else {
+      return false;
+    };
+    self.config.match_on.iter().all(|rule| match rule {
+      MatchRule::Method => recorded.method.eq_ignore_ascii_case(method.as_str()),
+      MatchRule::Url => recorded_url == *url,
+      MatchRule::Path => recorded_url[..Position::AfterPath] == url[..Position::AfterPath],
+      MatchRule::Query => sorted_query(&recorded_url) == sorted_query(url),
+      MatchRule::Body => match (&recorded.body, body) {
+        (Some(recorded), Some(body)) => recorded.to_vec() == body,
+        _ => false,
+      },
+    })
+  }
+
+  /// Replay mode: the first unused interaction matching the request, or the
+  /// last matching one when all of them were served already.
+  pub fn replay(&self, method: &Method, url: &Url, body: Option<&[u8]>) -> Result<Response, AnyError> {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    let interactions = self.interactions.borrow();
+    let mut used = self.used.borrow_mut();
+    let matching: Vec<usize> = (0..interactions.len())
+      .filter(|i| self.matches(&interactions[*i].request, method, &url, body))
+      .collect();
+    let Some(index) = matching.iter().find(|i| !used[**i]).or(matching.last()).copied() else {
+      return Err(type_error(format!("No recorded response for {method} {url} in {}", self.path.display())));
+    };
+    used[index] = true;
+    let recorded = &interactions[index].response;
+    let mut builder = http::Response::builder().status(recorded.status).url(url);
+    for (name, value) in &recorded.headers {
+      builder = builder.header(name.as_str(), value.as_str());
+    }
+    Ok(Response::from(builder.body(reqwest::Body::from(recorded.body.to_vec()))?))
+  }
+
+  fn headers(&self, headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+      .iter()
+      .map(|(name, value)| {
+        let redacted = self.config.redact_headers.iter().any(|r| name.as_str().eq_ignore_ascii_case(r));
+        let value = match redacted {
+          true => REDACTED.to_string(),
+          false => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+        };
+        (name.to_string(), value)
+      })
+      .collect()
+  }
+
+  /// Record mode: reads the whole response, saves the interaction and
+  /// returns an equivalent response for the script.
+  pub async fn record(
+    &self,
+    method: &Method,
+    url: &Url,
+    request_headers: &HeaderMap,
+    request_body: Option<Vec<u8>>,
+    response: Response,
+  ) -> Result<Response, AnyError> {
+    let status = response.status();
+    let response_url = response.url().clone();
+    let response_headers = response.headers().clone();
+    let body = response.bytes().await?;
+    let mut request_url = url.clone();
+    request_url.set_fragment(None);
+    let interaction = Interaction {
+      request: RecordedRequest {
+        method: method.to_string(),
+        url: request_url.to_string(),
+        headers: self.headers(request_headers),
+        body: request_body.map(RecordedBody::new),
+      },
+      response: RecordedResponse {
+        status: status.as_u16(),
+        headers: self.headers(&response_headers),
+        body: RecordedBody::new(body.to_vec()),
+      },
+    };
+    self.interactions.borrow_mut().push(interaction);
+    self.save()?;
+
+    let mut builder = http::Response::builder().status(status).url(response_url);
+    for (name, value) in response_headers.iter() {
+      builder = builder.header(name, value);
+    }
+    Ok(Response::from(builder.body(reqwest::Body::from(body))?))
+  }
+
+  fn save(&self) -> Result<(), AnyError> {
+    std::fs::create_dir_all(&self.config.dir)?;
+    std::fs::write(&self.path, serde_json::to_vec_pretty(&*self.interactions.borrow())?)?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_cassette_matching_and_redaction() {
+    let config = CassetteConfig {
+      mode: CassetteMode::Replay,
+      dir: PathBuf::from("/product/__cassettes__"),
+      match_on: vec![MatchRule::Method, MatchRule::Path, MatchRule::Query],
+      redact_headers: CassetteConfig::default_redact_headers(),
+    };
+    let specifier = Url::parse("file:///product/tests/users_test.ts").unwrap();
+    assert_eq!(cassette_name(&config.dir, &specifier), "tests_users_test.ts.json");
+
+    let interaction = |body: &str| Interaction {
+      request: RecordedRequest {
+        method: "GET".to_string(),
+        url: "https://api.example.com/users?b=2&a=1".to_string(),
+        headers: vec![],
+        body: None,
+      },
+      response: RecordedResponse {
+        status: 200,
+        headers: vec![],
+        body: RecordedBody::new(body.as_bytes().to_vec()),
+      },
+    };
+    let cassette = Cassette {
+      path: config.dir.join("tests_users_test.ts.json"),
+      interactions: RefCell::new(vec![interaction("first"), interaction("second")]),
+      used: RefCell::new(vec![false, false]),
+      config,
+    };
+    let url = Url::parse("https://api.example.com/users?a=1&b=2#top").unwrap();
+    for expected in ["first", "second", "second"] {
+      let response = cassette.replay(&Method::GET, &url, None).unwrap();
+      assert_eq!(response.url().as_str(), "https://api.example.com/users?a=1&b=2");
+      assert_eq!(response.bytes().await.unwrap(), expected.as_bytes());
+    }
+    assert!(cassette.replay(&Method::POST, &url, None).is_err());
+
+    let mut headers = HeaderMap::new();
+    headers.insert("authorization", "Bearer secret".parse().unwrap());
+    headers.insert("accept", "application/json".parse().unwrap());
+    let recorded = cassette.headers(&headers);
+    assert!(recorded.contains(&("authorization".to_string(), REDACTED.to_string())));
+    assert!(recorded.contains(&("accept".to_string(), "application/json".to_string())));
+  }
+}
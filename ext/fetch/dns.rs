@@ -0,0 +1,91 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+
+use std::net::SocketAddr;
+
+use reqwest::dns::Addrs;
+use reqwest::dns::Name;
+use reqwest::dns::Resolve;
+use reqwest::dns::Resolving;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::DnsResolver;
+
+/// Address families used for outbound connections. hyper tries the family of
+/// the first resolved address and falls back to the other one after 300ms,
+/// so the order of the resolved addresses decides which family is preferred.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFamily {
+  /// Keep the order of the resolver.
+  #[default]
+  Auto,
+  PreferV4,
+  PreferV6,
+  V4Only,
+  V6Only,
+}
+
+impl AddressFamily {
+  /// Reorders or filters resolved addresses, keeping the resolver order
+  /// within a family.
+  pub fn arrange(self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    if self == AddressFamily::Auto {
+      return addrs;
+    }
+    let (v4, v6): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.into_iter().partition(|a| a.is_ipv4());
+    match self {
+      AddressFamily::Auto | AddressFamily::PreferV4 => v4.into_iter().chain(v6).collect(),
+      AddressFamily::PreferV6 => v6.into_iter().chain(v4).collect(),
+      AddressFamily::V4Only => v4,
+      AddressFamily::V6Only => v6,
+    }
+  }
+}
+
+/// Applies an [`AddressFamily`] to the addresses of another resolver, or of
+/// the system resolver when there is none.
+pub(crate) struct FamilyResolver {
+  pub family: AddressFamily,
+  pub inner: Option<DnsResolver>,
+}
+
+impl Resolve for FamilyResolver {
+  fn resolve(&self, name: Name) -> Resolving {
+    let family = self.family;
+    let host = name.as_str().to_string();
+    let inner = self.inner.as_ref().map(|inner| inner.resolve(name));
+    Box::pin(async move {
+      let addrs: Vec<SocketAddr> = match inner {
+        Some(resolving) => resolving.await?.collect(),
+        None => tokio::net::lookup_host((host.as_str(), 0)).await?.collect(),
+      };
+      let addrs = family.arrange(addrs);
+      if addrs.is_empty() {
+        let message = format!("No {family:?} address found for {host}");
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, message).into());
+      }
+      let addrs: Addrs = Box::new(addrs.into_iter());
+      Ok::<_, Box<dyn std::error::Error + Send + Sync>>(addrs)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_arrange_addresses() {
+    let addrs: Vec<SocketAddr> = ["[2001:db8::1]:0", "10.0.0.1:0", "[2001:db8::2]:0", "10.0.0.2:0"]
+      .iter()
+      .map(|a| a.parse().unwrap())
+      .collect();
+    let arranged = AddressFamily::PreferV4.arrange(addrs.clone());
+    assert_eq!(arranged[..2], [addrs[1], addrs[3]]);
+    assert_eq!(arranged.len(), 4);
+    assert_eq!(AddressFamily::V4Only.arrange(addrs.clone()), vec![addrs[1], addrs[3]]);
+    assert_eq!(AddressFamily::V6Only.arrange(addrs.clone()), vec![addrs[0], addrs[2]]);
+    assert_eq!(AddressFamily::PreferV6.arrange(addrs.clone())[0], addrs[0]);
+  }
+}
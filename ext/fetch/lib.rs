@@ -1,17 +1,25 @@
 // Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
 
 mod byte_stream;
+mod cassette;
+mod dns;
 mod fs_fetch_handler;
+mod limits;
+mod mock;
 
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::cmp::min;
+use std::collections::HashMap;
 use std::convert::From;
+use std::fmt;
 use std::path::Path;
 use std::path::PathBuf;
 use std::pin::Pin;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 
 use deno_core::error::type_error;
 use deno_core::error::AnyError;
@@ -43,6 +51,9 @@ use deno_tls::RootCertStoreProvider;
 use data_url::DataUrl;
 use http::header::CONTENT_LENGTH;
 use http::Uri;
+use reqwest::dns::Name;
+use reqwest::dns::Resolve;
+use reqwest::dns::Resolving;
 use reqwest::header::HeaderMap;
 use reqwest::header::HeaderName;
 use reqwest::header::HeaderValue;
@@ -64,7 +75,18 @@ use tokio::sync::mpsc;
 pub use data_url;
 pub use reqwest;
 
+pub use cassette::Cassette;
+pub use cassette::CassetteConfig;
+pub use cassette::CassetteMode;
+pub use cassette::MatchRule;
+pub use dns::AddressFamily;
 pub use fs_fetch_handler::FsFetchHandler;
+pub use limits::ConnectionLimiter;
+pub use limits::ConnectionLimits;
+pub use limits::ConnectionPermit;
+pub use limits::LimitPolicy;
+pub use mock::FetchFixture;
+pub use mock::FetchMocks;
 
 pub use crate::byte_stream::MpscByteStream;
 
@@ -127,6 +149,137 @@ deno_core::extension!(deno_fetch,
   },
 );
 
+/// An outbound request made by `fetch()`. Reported when the response body is
+/// dropped, so `duration` and `response_bytes` cover the whole body that the
+/// script read, or when the request fails before a response arrives.
+#[derive(Clone, Debug)]
+pub struct EgressRecord {
+  pub method: String,
+  pub host: String,
+  pub status: Option<u16>,
+  pub duration: Duration,
+  /// `None` when the body was streamed without a known length.
+  pub request_bytes: Option<u64>,
+  pub response_bytes: u64,
+  pub error: Option<String>,
+}
+
+pub type EgressHook = Box<dyn Fn(EgressRecord)>;
+
+thread_local! {
+  static EGRESS_HOOK: RefCell<Option<EgressHook>> = RefCell::new(None);
+}
+
+/// Installs a hook for outbound http(s) requests on the current thread. Every
+/// product runtime runs on its own thread, which lets the embedder attribute
+/// requests to the product that made them.
+pub fn set_egress_hook(hook: Option<EgressHook>) {
+  EGRESS_HOOK.with(|cell| *cell.borrow_mut() = hook);
+}
+
+fn report_egress(record: EgressRecord) {
+  let _ = EGRESS_HOOK.try_with(|cell| {
+    if let Some(hook) = cell.borrow().as_ref() {
+      hook(record);
+    }
+  });
+}
+
+fn egress_enabled() -> bool {
+  EGRESS_HOOK.try_with(|cell| cell.borrow().is_some()).unwrap_or(false)
+}
+
+/// Resolver used by the http clients of `fetch()` instead of the system
+/// resolver.
+#[derive(Clone)]
+pub struct DnsResolver(pub Arc<dyn Resolve>);
+
+impl fmt::Debug for DnsResolver {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("DnsResolver")
+  }
+}
+
+impl Resolve for DnsResolver {
+  fn resolve(&self, name: Name) -> Resolving {
+    self.0.resolve(name)
+  }
+}
+
+thread_local! {
+  static DNS_RESOLVER: RefCell<Option<DnsResolver>> = RefCell::new(None);
+}
+
+/// Installs a resolver for the http clients that `fetch()` creates on the
+/// current thread, which lets the embedder keep a DNS cache per product.
+/// Clients created before the call keep their resolver.
+pub fn set_dns_resolver(resolver: Option<DnsResolver>) {
+  DNS_RESOLVER.with(|cell| *cell.borrow_mut() = resolver);
+}
+
+fn thread_dns_resolver() -> Option<DnsResolver> {
+  DNS_RESOLVER.with(|cell| cell.borrow().clone())
+}
+
+/// Connection settings for the http clients of `fetch()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectOptions {
+  pub address_family: AddressFamily,
+  /// Split between the resolved addresses, so a hanging address doesn't use
+  /// up the whole timeout.
+  pub connect_timeout: Option<Duration>,
+}
+
+thread_local! {
+  static CONNECT_OPTIONS: RefCell<ConnectOptions> = RefCell::new(ConnectOptions::default());
+}
+
+/// Sets the connection settings for the http clients that `fetch()` creates
+/// on the current thread, like [`set_dns_resolver`].
+pub fn set_connect_options(options: ConnectOptions) {
+  CONNECT_OPTIONS.with(|cell| *cell.borrow_mut() = options);
+}
+
+fn thread_connect_options() -> ConnectOptions {
+  CONNECT_OPTIONS.with(|cell| *cell.borrow())
+}
+
+thread_local! {
+  static CONNECTION_LIMITER: RefCell<Option<Arc<ConnectionLimiter>>> = RefCell::new(None);
+}
+
+/// Installs the limiter for outbound http(s) requests made on the current
+/// thread. The embedder shares one limiter between the runtimes of a product.
+pub fn set_connection_limiter(limiter: Option<Arc<ConnectionLimiter>>) {
+  CONNECTION_LIMITER.with(|cell| *cell.borrow_mut() = limiter);
+}
+
+fn thread_connection_limiter() -> Option<Arc<ConnectionLimiter>> {
+  CONNECTION_LIMITER.try_with(|cell| cell.borrow().clone()).ok().flatten()
+}
+
+/// Requests that need a [`ConnectionPermit`] before `op_fetch_send` sends
+/// them, keyed by the request rid.
+#[derive(Default)]
+struct PendingPermits(HashMap<ResourceId, (Arc<ConnectionLimiter>, String, Rc<CancelHandle>)>);
+
+/// Requests waiting for `op_fetch_send`, keyed by the request rid.
+#[derive(Default)]
+struct PendingEgress(HashMap<ResourceId, (EgressRecord, Instant)>);
+
+/// Counts the response body and reports the request when dropped.
+struct EgressGuard {
+  record: EgressRecord,
+  started: Instant,
+}
+
+impl Drop for EgressGuard {
+  fn drop(&mut self) {
+    self.record.duration = self.started.elapsed();
+    report_egress(self.record.clone());
+  }
+}
+
 pub type CancelableResponseFuture = Pin<Box<dyn Future<Output = CancelableResponseResult>>>;
 
 pub trait FetchHandler: dyn_clone::DynClone {
@@ -182,6 +335,8 @@ pub fn get_or_create_client_from_state(state: &mut OpState) -> Result<reqwest::C
         pool_idle_timeout: None,
         http1: true,
         http2: true,
+        dns_resolver: thread_dns_resolver(),
+        connect: thread_connect_options(),
       },
     )?;
     state.put::<reqwest::Client>(client.clone());
@@ -246,6 +401,47 @@ where
         return Err(type_error("Invalid URL"));
       }
 
+      // Fixtures win over the cassette, strict mode doesn't apply while
+      // replaying since nothing reaches the network anyway.
+      let cassette = state.try_borrow::<Rc<Cassette>>().cloned();
+      let replaying = cassette.as_ref().filter(|c| c.mode() == CassetteMode::Replay);
+      let mut canned = match (state.try_borrow::<FetchMocks>(), replaying) {
+        (Some(mocks), None) => mocks.respond(&method, &url)?,
+        (Some(mocks), Some(_)) => mocks.find(&method, &url)?,
+        (None, _) => None,
+      };
+      if let (None, Some(cassette)) = (&canned, replaying) {
+        canned = Some(cassette.replay(&method, &url, data.as_deref())?);
+      }
+      if let Some(response) = canned {
+        let fut = async move { Ok(Ok(response)) };
+        let request_rid = state.resource_table.add(FetchRequestResource(Box::pin(fut)));
+        return Ok(FetchReturn {
+          request_rid,
+          request_body_rid: None,
+          cancel_handle_rid: None,
+        });
+      }
+      let recorder = cassette.filter(|c| c.mode() == CassetteMode::Record).map(|cassette| {
+        let body = data.as_ref().map(|data| data.to_vec());
+        (cassette, method.clone(), url.clone(), body)
+      });
+
+      let host = url.host_str().unwrap_or_default().to_string();
+      let egress = egress_enabled().then(|| EgressRecord {
+        method: method.to_string(),
+        host: host.clone(),
+        status: None,
+        duration: Duration::ZERO,
+        request_bytes: match (&data, has_body) {
+          (Some(data), _) => Some(data.len() as u64),
+          (None, true) => body_length,
+          (None, false) => Some(0),
+        },
+        response_bytes: 0,
+        error: None,
+      });
+
       let mut request = client.request(method.clone(), url);
 
       let request_body_rid = if has_body {
@@ -299,6 +495,7 @@ where
         // If httpRequest’s header list contains `Range`, then append (`Accept-Encoding`, `identity`)
         header_map.insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
       }
+      let recorded_headers = recorder.as_ref().map(|_| header_map.clone());
       request = request.headers(header_map);
 
       let options = state.borrow::<Options>();
@@ -316,8 +513,32 @@ where
           .await
           .map(|res| res.map_err(|err| type_error(err.to_string())))
       };
+      let fut: CancelableResponseFuture = match (recorder, recorded_headers) {
+        (Some((cassette, method, url, body)), Some(headers)) => Box::pin(async move {
+          match fut.await {
+            Ok(Ok(res)) => Ok(cassette.record(&method, &url, &headers, body, res).await),
+            result => result,
+          }
+        }),
+        _ => Box::pin(fut),
+      };
 
-      let request_rid = state.resource_table.add(FetchRequestResource(Box::pin(fut)));
+      let request_rid = state.resource_table.add(FetchRequestResource(fut));
+
+      if let Some(egress) = egress {
+        if !state.has::<PendingEgress>() {
+          state.put(PendingEgress::default());
+        }
+        state.borrow_mut::<PendingEgress>().0.insert(request_rid, (egress, Instant::now()));
+      }
+
+      if let Some(limiter) = thread_connection_limiter() {
+        if !state.has::<PendingPermits>() {
+          state.put(PendingPermits::default());
+        }
+        let pending = (limiter, host, cancel_handle.clone());
+        state.borrow_mut::<PendingPermits>().0.insert(request_rid, pending);
+      }
 
       let cancel_handle_rid = state.resource_table.add(FetchCancelHandle(cancel_handle));
 
@@ -370,11 +591,50 @@ pub async fn op_fetch_send(state: Rc<RefCell<OpState>>, rid: ResourceId) -> Resu
   let request = state.borrow_mut().resource_table.take::<FetchRequestResource>(rid)?;
 
   let request = Rc::try_unwrap(request).ok().expect("multiple op_fetch_send ongoing");
+  let egress = state
+    .borrow_mut()
+    .try_borrow_mut::<PendingEgress>()
+    .and_then(|pending| pending.0.remove(&rid));
+  let pending_permit = state
+    .borrow_mut()
+    .try_borrow_mut::<PendingPermits>()
+    .and_then(|pending| pending.0.remove(&rid));
+
+  // The request future is lazy, nothing is sent before the permit is taken.
+  let permit = match pending_permit {
+    Some((limiter, host, cancel)) => match limiter.acquire(&host).or_cancel(cancel).await {
+      Ok(Ok(permit)) => Some(permit),
+      result => {
+        let err = match result {
+          Ok(Err(err)) => err,
+          _ => type_error("request was cancelled"),
+        };
+        if let Some((mut record, started)) = egress {
+          record.error = Some(err.to_string());
+          drop(EgressGuard { record, started });
+        }
+        return Err(err);
+      }
+    },
+    None => None,
+  };
 
   let res = match request.0.await {
     Ok(Ok(res)) => res,
-    Ok(Err(err)) => return Err(type_error(err.to_string())),
-    Err(_) => return Err(type_error("request was cancelled")),
+    Ok(Err(err)) => {
+      if let Some((mut record, started)) = egress {
+        record.error = Some(err.to_string());
+        drop(EgressGuard { record, started });
+      }
+      return Err(type_error(err.to_string()));
+    }
+    Err(_) => {
+      if let Some((mut record, started)) = egress {
+        record.error = Some("request was cancelled".to_string());
+        drop(EgressGuard { record, started });
+      }
+      return Err(type_error("request was cancelled"));
+    }
   };
 
   //debug!("Fetch response {}", url);
@@ -387,11 +647,18 @@ pub async fn op_fetch_send(state: Rc<RefCell<OpState>>, rid: ResourceId) -> Resu
 
   let content_length = res.content_length();
 
-  let stream: BytesStream = Box::pin(
-    res
-      .bytes_stream()
-      .map(|r| r.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))),
-  );
+  let mut guard = egress.map(|(mut record, started)| {
+    record.status = Some(status.as_u16());
+    EgressGuard { record, started }
+  });
+  let stream: BytesStream = Box::pin(res.bytes_stream().map(move |r| {
+    // The permit is released when the body is dropped.
+    let _ = &permit;
+    if let (Some(guard), Ok(chunk)) = (guard.as_mut(), &r) {
+      guard.record.response_bytes += chunk.len() as u64;
+    }
+    r.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+  }));
   let rid = state.borrow_mut().resource_table.add(FetchResponseBodyResource {
     reader: AsyncRefCell::new(stream.peekable()),
     cancel: CancelHandle::default(),
@@ -616,6 +883,8 @@ where
       }),
       http1: args.http1,
       http2: args.http2,
+      dns_resolver: thread_dns_resolver(),
+      connect: thread_connect_options(),
     },
   )?;
 
@@ -634,6 +903,8 @@ pub struct CreateHttpClientOptions {
   pub pool_idle_timeout: Option<Option<u64>>,
   pub http1: bool,
   pub http2: bool,
+  pub dns_resolver: Option<DnsResolver>,
+  pub connect: ConnectOptions,
 }
 
 impl Default for CreateHttpClientOptions {
@@ -648,6 +919,8 @@ impl Default for CreateHttpClientOptions {
       pool_idle_timeout: None,
       http1: true,
       http2: true,
+      dns_resolver: None,
+      connect: ConnectOptions::default(),
     }
   }
 }
@@ -694,6 +967,16 @@ pub fn create_http_client(user_agent: &str, options: CreateHttpClientOptions) ->
     builder = builder.pool_idle_timeout(pool_idle_timeout.map(std::time::Duration::from_millis));
   }
 
+  match (options.connect.address_family, options.dns_resolver) {
+    (AddressFamily::Auto, Some(dns_resolver)) => builder = builder.dns_resolver(Arc::new(dns_resolver)),
+    (AddressFamily::Auto, None) => {}
+    (family, inner) => builder = builder.dns_resolver(Arc::new(dns::FamilyResolver { family, inner })),
+  }
+
+  if let Some(connect_timeout) = options.connect.connect_timeout {
+    builder = builder.connect_timeout(connect_timeout);
+  }
+
   match (options.http1, options.http2) {
     (true, false) => builder = builder.http1_only(),
     (false, true) => builder = builder.http2_prior_knowledge(),
@@ -0,0 +1,146 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+
+//! Caps on the outbound requests in flight, shared by every runtime of a
+//! product so one tenant cannot exhaust ephemeral ports or overwhelm a shared
+//! dependency. A request holds its permits from sending until the response
+//! body is dropped, which also bounds the connections it can open.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use deno_core::error::type_error;
+use deno_core::error::AnyError;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+
+/// What happens to a request over the limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitPolicy {
+  /// Wait for a permit, up to `queue_timeout` when it is set.
+  #[default]
+  Queue,
+  /// Fail right away.
+  Reject,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+  /// Requests in flight across all hosts.
+  pub max_connections: Option<usize>,
+  /// Requests in flight to a single host.
+  pub max_per_host: Option<usize>,
+  /// Limits for specific hosts, overriding `max_per_host`.
+  pub hosts: BTreeMap<String, usize>,
+  pub policy: LimitPolicy,
+  pub queue_timeout: Option<Duration>,
+}
+
+/// Held while a request is in flight.
+pub struct ConnectionPermit {
+  _host: Option<OwnedSemaphorePermit>,
+  _total: Option<OwnedSemaphorePermit>,
+}
+
+pub struct ConnectionLimiter {
+  limits: ConnectionLimits,
+  total: Option<Arc<Semaphore>>,
+  hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl ConnectionLimiter {
+  pub fn new(limits: ConnectionLimits) -> Self {
+    Self {
+      total: limits.max_connections.map(|max| Arc::new(Semaphore::new(max))),
+      hosts: Mutex::new(HashMap::new()),
+      limits,
+    }
+  }
+
+  pub fn limits(&self) -> &ConnectionLimits {
+    &self.limits
+  }
+
+  fn host_semaphore(&self, host: &str) -> Option<Arc<Semaphore>> {
+    let max = self.limits.hosts.get(host).copied().or(self.limits.max_per_host)?;
+    let mut hosts = self.hosts.lock().unwrap();
+    if let Some(semaphore) = hosts.get(host) {
+      return Some(semaphore.clone());
+    }
+    // Hosts without requests in flight are only referenced by the map.
+    hosts.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+    let semaphore = Arc::new(Semaphore::new(max));
+    hosts.insert(host.to_string(), semaphore.clone());
+    Some(semaphore)
+  }
+
+  async fn acquire_one(&self, semaphore: Arc<Semaphore>, what: &str) -> Result<OwnedSemaphorePermit, AnyError> {
+    let exceeded = || type_error(format!("Too many outbound connections {what}"));
+    match (self.limits.policy, self.limits.queue_timeout) {
+      (LimitPolicy::Reject, _) => semaphore.try_acquire_owned().map_err(|_| exceeded()),
+      (LimitPolicy::Queue, None) => Ok(semaphore.acquire_owned().await?),
+      (LimitPolicy::Queue, Some(timeout)) => match tokio::time::timeout(timeout, semaphore.acquire_owned()).await {
+        Ok(permit) => Ok(permit?),
+        Err(_) => Err(exceeded()),
+      },
+    }
+  }
+
+  /// Waits for or rejects a request to `host` according to the policy. The
+  /// host permit is taken first, so requests queued for a busy host don't
+  /// hold back requests to other hosts.
+  pub async fn acquire(&self, host: &str) -> Result<ConnectionPermit, AnyError> {
+    let host_permit = match self.host_semaphore(host) {
+      Some(semaphore) => Some(self.acquire_one(semaphore, &format!("to {host}")).await?),
+      None => None,
+    };
+    let total_permit = match &self.total {
+      Some(semaphore) => Some(self.acquire_one(semaphore.clone(), "in total").await?),
+      None => None,
+    };
+    Ok(ConnectionPermit {
+      _host: host_permit,
+      _total: total_permit,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_connection_limits() {
+    let limiter = ConnectionLimiter::new(ConnectionLimits {
+      max_connections: Some(3),
+      max_per_host: Some(2),
+      hosts: BTreeMap::from([("db.internal".to_string(), 1)]),
+      policy: LimitPolicy::Reject,
+      queue_timeout: None,
+    });
+    let db = limiter.acquire("db.internal").await.unwrap();
+    assert!(limiter.acquire("db.internal").await.is_err());
+    let _api = limiter.acquire("api.example.com").await.unwrap();
+    let _api2 = limiter.acquire("api.example.com").await.unwrap();
+    assert!(limiter.acquire("api.example.com").await.is_err());
+    // The total is used up, so other hosts fail too.
+    assert!(limiter.acquire("maps.example.com").await.is_err());
+    drop(db);
+    assert!(limiter.acquire("db.internal").await.is_ok());
+
+    let queued = ConnectionLimiter::new(ConnectionLimits {
+      max_connections: Some(1),
+      queue_timeout: Some(Duration::from_millis(10)),
+      ..Default::default()
+    });
+    let permit = queued.acquire("api.example.com").await.unwrap();
+    assert!(queued.acquire("maps.example.com").await.is_err());
+    drop(permit);
+    assert!(queued.acquire("maps.example.com").await.is_ok());
+  }
+}
@@ -0,0 +1,134 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+
+//! Canned responses for `fetch()`, used when the embedder runs product tests
+//! so they don't reach real external services. The embedder puts
+//! [`FetchMocks`] into the op state of the test workers; `op_fetch` then
+//! answers matching http(s) requests without touching the network.
+
+use deno_core::error::type_error;
+use deno_core::error::AnyError;
+use deno_core::url::Url;
+use reqwest::Method;
+use reqwest::Response;
+use reqwest::ResponseBuilderExt;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FetchMocks {
+  /// Fail requests that no fixture matches instead of sending them.
+  pub strict: bool,
+  /// Checked in order, the first match wins.
+  pub fixtures: Vec<FetchFixture>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FetchFixture {
+  /// Matches any method when not set.
+  #[serde(default)]
+  pub method: Option<String>,
+  /// Full url, `*` matches any sequence of characters. The fragment is
+  /// ignored.
+  pub url: String,
+  #[serde(default = "default_status")]
+  pub status: u16,
+  #[serde(default)]
+  pub headers: Vec<(String, String)>,
+  #[serde(default)]
+  pub body: String,
+}
+
+fn default_status() -> u16 {
+  200
+}
+
+/// Matches `pattern` against `text`, `*` matches any sequence.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+  let mut parts = pattern.split('*');
+  let first = parts.next().unwrap_or_default();
+  let Some(mut rest) = text.strip_prefix(first) else {
+    return false;
+  };
+  let parts: Vec<&str> = parts.collect();
+  let Some((last, middle)) = parts.split_last() else {
+    return rest.is_empty();
+  };
+  for part in middle {
+    match rest.find(part) {
+      Some(index) => rest = &rest[index + part.len()..],
+      None => return false,
+    }
+  }
+  rest.len() >= last.len() && rest.ends_with(last)
+}
+
+impl FetchFixture {
+  fn matches(&self, method: &Method, url: &str) -> bool {
+    let method_matches = match &self.method {
+      Some(m) => m.eq_ignore_ascii_case(method.as_str()),
+      None => true,
+    };
+    method_matches && wildcard_match(&self.url, url)
+  }
+
+  fn response(&self, url: Url) -> Result<Response, AnyError> {
+    let mut builder = http::Response::builder().status(self.status).url(url);
+    for (name, value) in &self.headers {
+      builder = builder.header(name.as_str(), value.as_str());
+    }
+    Ok(Response::from(builder.body(reqwest::Body::from(self.body.clone()))?))
+  }
+}
+
+impl FetchMocks {
+  /// The canned response for a request, `None` when the request should be
+  /// sent. Errors for unmatched requests in strict mode.
+  pub fn respond(&self, method: &Method, url: &Url) -> Result<Option<Response>, AnyError> {
+    match self.find(method, url)? {
+      Some(response) => Ok(Some(response)),
+      None if self.strict => Err(type_error(format!("No fetch mock matches {method} {url}"))),
+      None => Ok(None),
+    }
+  }
+
+  /// Like [`FetchMocks::respond`] but ignores strict mode.
+  pub fn find(&self, method: &Method, url: &Url) -> Result<Option<Response>, AnyError> {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    match self.fixtures.iter().find(|f| f.matches(method, url.as_str())) {
+      Some(fixture) => Ok(Some(fixture.response(url)?)),
+      None => Ok(None),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_fetch_mocks() {
+    let mocks = FetchMocks {
+      strict: true,
+      fixtures: vec![FetchFixture {
+        method: Some("get".to_string()),
+        url: "https://api.example.com/users/*".to_string(),
+        status: 404,
+        headers: vec![("content-type".to_string(), "application/json".to_string())],
+        body: "{}".to_string(),
+      }],
+    };
+    let url = Url::parse("https://api.example.com/users/42#top").unwrap();
+    let response = mocks.respond(&Method::GET, &url).unwrap().unwrap();
+    assert_eq!(response.status(), 404);
+    assert_eq!(response.url().as_str(), "https://api.example.com/users/42");
+    assert!(mocks.respond(&Method::POST, &url).is_err());
+    let other = Url::parse("https://api.example.com/orders").unwrap();
+    assert!(mocks.respond(&Method::GET, &other).is_err());
+    let lenient = FetchMocks { strict: false, ..mocks };
+    assert!(lenient.respond(&Method::GET, &other).unwrap().is_none());
+    assert!(wildcard_match("https://*.example.com/*", "https://api.example.com/v1"));
+    assert!(!wildcard_match("https://*.example.com/", "https://api.example.com/v1"));
+  }
+}
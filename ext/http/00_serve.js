@@ -13,7 +13,8 @@ import {
   toInnerResponse,
 } from "ext:deno_fetch/23_response.js";
 import { fromInnerRequest, toInnerRequest } from "ext:deno_fetch/23_request.js";
-import { AbortController } from "ext:deno_web/03_abort_signal.js";
+import { AbortController, follow } from "ext:deno_web/03_abort_signal.js";
+import DOMException from "ext:deno_web/01_dom_exception.js";
 import {
   _eventLoop,
   _idleTimeoutDuration,
@@ -38,6 +39,7 @@ import { TcpConn } from "ext:deno_net/01_net.js";
 const {
   ObjectPrototypeIsPrototypeOf,
   PromisePrototypeCatch,
+  PromisePrototypeThen,
   SafeSet,
   SafeSetIterator,
   SetPrototypeAdd,
@@ -53,6 +55,7 @@ const {
   op_http_get_request_headers,
   op_http_get_request_method_and_url,
   op_http_read_request_body,
+  op_http_request_closed,
   op_http_serve,
   op_http_set_promise_complete,
   op_http_set_response_body_bytes,
@@ -64,11 +67,14 @@ const {
   op_http_set_response_trailers,
   op_http_upgrade_raw,
   op_http_upgrade_websocket_next,
+  op_http_usage_sample_end,
+  op_http_usage_sample_start,
   op_http_wait,
 } = core.generateAsyncOpHandler(
   "op_http_get_request_headers",
   "op_http_get_request_method_and_url",
   "op_http_read_request_body",
+  "op_http_request_closed",
   "op_http_serve",
   "op_http_set_promise_complete",
   "op_http_set_response_body_bytes",
@@ -80,6 +86,8 @@ const {
   "op_http_set_response_trailers",
   "op_http_upgrade_raw",
   "op_http_upgrade_websocket_next",
+  "op_http_usage_sample_end",
+  "op_http_usage_sample_start",
   "op_http_wait",
 );
 const _upgraded = Symbol("_upgraded");
@@ -491,6 +499,29 @@ async function asyncResponse(responseBodies, req, status, stream) {
   }
 }
 
+const promiseIdSymbol = SymbolFor("Deno.core.internalPromiseId");
+
+/**
+ * Creates the signal of a single request. It follows the server signal and
+ * is also aborted when the client disconnects before the response was sent,
+ * so handlers can stop work nobody is waiting for.
+ */
+function requestSignal(req, serverSignal) {
+  const controller = new AbortController();
+  follow(controller.signal, serverSignal);
+  const closed = op_http_request_closed(req);
+  // Must not keep the event loop alive on its own
+  core.unrefOp(closed[promiseIdSymbol]);
+  PromisePrototypeThen(closed, (disconnected) => {
+    if (disconnected) {
+      controller.abort(
+        new DOMException("The client disconnected", "AbortError"),
+      );
+    }
+  });
+  return controller.signal;
+}
+
 /**
  * Maps the incoming request slab ID to a fully-fledged Request object, passes it to the user-provided
  * callback, then extracts the response that was returned from that callback. The response is then pulled
@@ -504,10 +535,15 @@ function mapToCallback(responseBodies, context, signal, callback, onError) {
     // 500 error.
     let innerRequest;
     let response;
+    const sampled = op_http_usage_sample_start(req);
     try {
       if (callback.length > 0) {
         innerRequest = new InnerRequest(req, context);
-        const request = fromInnerRequest(innerRequest, signal, "immutable");
+        const request = fromInnerRequest(
+          innerRequest,
+          requestSignal(req, signal),
+          "immutable",
+        );
         if (callback.length === 1) {
           response = await callback(request);
         } else {
@@ -548,6 +584,10 @@ function mapToCallback(responseBodies, context, signal, callback, onError) {
       return;
     }
 
+    if (sampled) {
+      op_http_usage_sample_end(req);
+    }
+
     const status = inner.status;
     const headers = inner.headerList;
     if (headers && headers.length > 0) {
@@ -663,7 +703,6 @@ function serve(arg1, arg2) {
 
   let ref = true;
   let currentPromise = null;
-  const promiseIdSymbol = SymbolFor("Deno.core.internalPromiseId");
 
   // Run the service
   const finished = (async () => {
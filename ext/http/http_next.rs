@@ -14,6 +14,10 @@ use crate::slab::slab_drop;
 use crate::slab::slab_get;
 use crate::slab::slab_insert;
 use crate::slab::SlabId;
+use crate::usage::UsageSample;
+use crate::usage::USAGE_CPU_HEADER;
+use crate::usage::USAGE_HEAP_HEADER;
+use crate::usage::USAGE_SAMPLE_HEADER;
 use crate::websocket_upgrade::WebSocketUpgrade;
 use crate::LocalExecutor;
 use cache_control::CacheControl;
@@ -22,6 +26,7 @@ use deno_core::futures::TryFutureExt;
 use deno_core::op;
 use deno_core::task::spawn;
 use deno_core::task::JoinHandle;
+use deno_core::v8;
 use deno_core::AsyncRefCell;
 use deno_core::AsyncResult;
 use deno_core::ByteString;
@@ -269,6 +274,30 @@ pub fn op_http_set_response_header(slab_id: SlabId, name: &str, value: &str) {
   resp_headers.append(name, value);
 }
 
+/// Starts measuring the request when the embedder asked for it, returns
+/// whether `op_http_usage_sample_end` needs to be called.
+#[op(v8)]
+pub fn op_http_usage_sample_start(scope: &mut v8::HandleScope, slab_id: SlabId) -> bool {
+  let mut http = slab_get(slab_id);
+  if !http.request_parts().headers.contains_key(USAGE_SAMPLE_HEADER) {
+    return false;
+  }
+  http.set_usage_sample(UsageSample::start(scope));
+  true
+}
+
+/// Reports the cost of a sampled request in its response headers.
+#[op(v8)]
+pub fn op_http_usage_sample_end(scope: &mut v8::HandleScope, slab_id: SlabId) {
+  let mut http = slab_get(slab_id);
+  if let Some(sample) = http.take_usage_sample() {
+    let (cpu_micros, heap_bytes) = sample.finish(scope);
+    let resp_headers = http.response().headers_mut();
+    resp_headers.insert(USAGE_CPU_HEADER, HeaderValue::from(cpu_micros));
+    resp_headers.insert(USAGE_HEAP_HEADER, HeaderValue::from(heap_bytes));
+  }
+}
+
 #[op]
 pub fn op_http_set_response_headers(slab_id: SlabId, headers: Vec<(ByteString, ByteString)>) {
   let mut http = slab_get(slab_id);
@@ -451,6 +480,14 @@ pub fn op_http_set_response_body_bytes(slab_id: SlabId, buffer: &[u8]) {
   };
 }
 
+/// Resolves once hyper is done with the request, with `true` when the client
+/// disconnected before a response was sent.
+#[op]
+pub fn op_http_request_closed(slab_id: SlabId) -> impl Future<Output = Result<bool, AnyError>> {
+  let closed = slab_get(slab_id).closed_promise();
+  async move { Ok(closed.await) }
+}
+
 #[op]
 pub async fn op_http_track(state: Rc<RefCell<OpState>>, slab_id: SlabId, server_rid: ResourceId) -> Result<(), AnyError> {
   let http = slab_get(slab_id);
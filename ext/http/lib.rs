@@ -82,6 +82,7 @@ mod request_body;
 mod request_properties;
 mod response_body;
 mod slab;
+pub mod usage;
 mod websocket_upgrade;
 
 pub use request_properties::DefaultHttpPropertyExtractor;
@@ -107,6 +108,7 @@ deno_core::extension!(
     http_next::op_http_get_request_headers,
     http_next::op_http_get_request_method_and_url<HTTP>,
     http_next::op_http_read_request_body,
+    http_next::op_http_request_closed,
     http_next::op_http_serve_on<HTTP>,
     http_next::op_http_serve<HTTP>,
     http_next::op_http_set_promise_complete,
@@ -120,6 +122,8 @@ deno_core::extension!(
     http_next::op_http_track,
     http_next::op_http_upgrade_websocket_next,
     http_next::op_http_upgrade_raw,
+    http_next::op_http_usage_sample_end,
+    http_next::op_http_usage_sample_start,
     http_next::op_http_wait,
   ],
   esm = ["00_serve.js", "01_http.js"],
@@ -2,6 +2,7 @@
 use crate::request_properties::HttpConnectionProperties;
 use crate::response_body::CompletionHandle;
 use crate::response_body::ResponseBytes;
+use crate::usage::UsageSample;
 use deno_core::error::AnyError;
 use http::request::Parts;
 use http::HeaderMap;
@@ -25,7 +26,10 @@ pub struct HttpSlabRecord {
   // The response may get taken before we tear this down
   response: Option<Response>,
   promise: CompletionHandle,
+  // Completed when hyper drops the request, `false` if a response was sent
+  closed: CompletionHandle,
   trailers: Rc<RefCell<Option<HeaderMap>>>,
+  usage_sample: Option<UsageSample>,
   been_dropped: bool,
   #[cfg(feature = "__zombie_http_tracking")]
   alive: bool,
@@ -84,8 +88,10 @@ fn slab_insert_raw(request_parts: Parts, request_body: Option<Incoming>, request
       request_body,
       response: Some(Response::new(body)),
       trailers,
+      usage_sample: None,
       been_dropped: false,
       promise: CompletionHandle::default(),
+      closed: CompletionHandle::default(),
       #[cfg(feature = "__zombie_http_tracking")]
       alive: true,
     })
@@ -105,6 +111,8 @@ pub fn slab_drop(index: SlabId) {
   let record = entry.self_mut();
   assert!(!record.been_dropped, "HTTP state error: Entry has already been dropped");
   record.been_dropped = true;
+  // Dropped before the response was produced: the client went away
+  record.closed.complete(!record.promise.is_completed());
   if record.promise.is_completed() {
     drop(entry);
     slab_expunge(index);
@@ -181,6 +189,16 @@ impl SlabEntry {
     self.self_mut().response.take().unwrap()
   }
 
+  /// Start measuring the cost of this request.
+  pub fn set_usage_sample(&mut self, sample: UsageSample) {
+    self.self_mut().usage_sample = Some(sample);
+  }
+
+  /// Take the measurement started by `set_usage_sample`, if any.
+  pub fn take_usage_sample(&mut self) -> Option<UsageSample> {
+    self.self_mut().usage_sample.take()
+  }
+
   /// Get a reference to the connection properties.
   pub fn request_info(&self) -> &HttpConnectionProperties {
     &self.self_ref().request_info
@@ -196,6 +214,11 @@ impl SlabEntry {
     self.self_ref().promise.clone()
   }
 
+  /// Get a reference to the handle completed when the request is dropped.
+  pub fn closed_promise(&self) -> CompletionHandle {
+    self.self_ref().closed.clone()
+  }
+
   /// Get a reference to the response body completion handle.
   pub fn body_promise(&self) -> CompletionHandle {
     self.self_ref().response.as_ref().unwrap().body().completion_handle()
@@ -226,4 +249,25 @@ mod tests {
     entry.complete();
     slab_drop(id);
   }
+
+  #[test]
+  fn test_slab_closed_before_response() {
+    let req = Request::builder().body(()).unwrap();
+    let (parts, _) = req.into_parts();
+    let id = slab_insert_raw(
+      parts,
+      None,
+      HttpConnectionProperties {
+        peer_address: "".into(),
+        peer_port: None,
+        local_port: None,
+        stream_type: NetworkStreamType::Tcp,
+      },
+    );
+    let closed = slab_get(id).closed_promise();
+    slab_drop(id);
+    assert!(closed.is_completed());
+    assert!(deno_core::futures::executor::block_on(closed));
+    slab_get(id).complete();
+  }
 }
@@ -0,0 +1,66 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+
+//! Sampled cost of individual requests.
+//!
+//! The embedder marks a request for sampling with [`USAGE_SAMPLE_HEADER`].
+//! For those requests the thread CPU time and the V8 heap growth between
+//! dispatching the request to JS and the handler returning its response are
+//! reported back in response headers. Other requests running concurrently on
+//! the same isolate are included in the numbers, which is why this is only
+//! meant to be used on a sample of the traffic.
+
+use deno_core::v8;
+
+/// Request header asking for the cost of the request to be reported.
+pub const USAGE_SAMPLE_HEADER: &str = "x-cool-usage-sample";
+/// Response header with the thread CPU time spent, in microseconds.
+pub const USAGE_CPU_HEADER: &str = "x-cool-usage-cpu-micros";
+/// Response header with the growth of the used heap, in bytes.
+pub const USAGE_HEAP_HEADER: &str = "x-cool-usage-heap-bytes";
+
+#[derive(Debug, Clone, Copy)]
+pub struct UsageSample {
+  cpu_micros: u64,
+  heap_used: usize,
+}
+
+impl UsageSample {
+  pub fn start(scope: &mut v8::HandleScope) -> Self {
+    Self {
+      cpu_micros: thread_cpu_micros(),
+      heap_used: heap_used(scope),
+    }
+  }
+
+  /// Returns the CPU time and heap growth since `start`.
+  pub fn finish(self, scope: &mut v8::HandleScope) -> (u64, usize) {
+    (
+      thread_cpu_micros().saturating_sub(self.cpu_micros),
+      heap_used(scope).saturating_sub(self.heap_used),
+    )
+  }
+}
+
+fn heap_used(scope: &mut v8::HandleScope) -> usize {
+  let mut stats = v8::HeapStatistics::default();
+  scope.get_heap_statistics(&mut stats);
+  stats.used_heap_size()
+}
+
+/// CPU time consumed by the current thread. Each runtime owns its thread so
+/// this is the time spent by the isolate and its ops.
+#[cfg(unix)]
+fn thread_cpu_micros() -> u64 {
+  let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+  // SAFETY: `ts` is a valid timespec to write into
+  let ret = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+  if ret != 0 {
+    return 0;
+  }
+  ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1_000
+}
+
+#[cfg(not(unix))]
+fn thread_cpu_micros() -> u64 {
+  0
+}
@@ -0,0 +1,276 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+
+//! Optional encryption at rest.
+//!
+//! The embedder installs a keyring of AES-256-GCM data keys once per
+//! process. Every encrypted blob starts with a magic header and the id of the
+//! data key that sealed it, so blobs written under older keys stay readable
+//! after a rotation and plaintext blobs (written before encryption was turned
+//! on) are passed through unchanged.
+//!
+//! Product sources are decrypted by the module loader, KV values by
+//! [`EncryptedDbHandler`], which wraps the database handler of `deno_kv`.
+
+use async_trait::async_trait;
+use deno_core::error::generic_error;
+use deno_core::error::AnyError;
+use deno_core::parking_lot::RwLock;
+use deno_core::OpState;
+use deno_kv::AtomicWrite;
+use deno_kv::CommitResult;
+use deno_kv::Database;
+use deno_kv::DatabaseHandler;
+use deno_kv::MutationKind;
+use deno_kv::QueueMessageHandle;
+use deno_kv::ReadRange;
+use deno_kv::ReadRangeOutput;
+use deno_kv::SnapshotReadOptions;
+use deno_kv::Value;
+use once_cell::sync::Lazy;
+use ring::aead;
+use ring::rand::SecureRandom;
+use ring::rand::SystemRandom;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+
+pub const MAGIC: &[u8; 8] = b"COOLENC1";
+const KEY_ID_LEN: usize = 4;
+const HEADER_LEN: usize = MAGIC.len() + KEY_ID_LEN + aead::NONCE_LEN;
+
+/// Data keys by id. New blobs are sealed with `current`.
+#[derive(Clone)]
+pub struct Keyring {
+  current: u32,
+  keys: HashMap<u32, [u8; 32]>,
+}
+
+impl Keyring {
+  pub fn new(current: u32, keys: HashMap<u32, [u8; 32]>) -> Result<Self, AnyError> {
+    if !keys.contains_key(&current) {
+      return Err(generic_error(format!("current data key {current} is missing from the keyring")));
+    }
+    Ok(Self { current, keys })
+  }
+
+  pub fn current(&self) -> u32 {
+    self.current
+  }
+
+  pub fn seal(&self, plain: &[u8]) -> Result<Vec<u8>, AnyError> {
+    seal_with(self.current, &self.keys[&self.current], plain)
+  }
+
+  pub fn open(&self, data: &[u8]) -> Result<Vec<u8>, AnyError> {
+    let key_id = match key_id(data) {
+      Some(key_id) => key_id,
+      None => return Ok(data.to_vec()),
+    };
+    let key = self
+      .keys
+      .get(&key_id)
+      .ok_or_else(|| generic_error(format!("data key {key_id} is not in the keyring")))?;
+    open_with(key, data)
+  }
+}
+
+static KEYRING: Lazy<RwLock<Option<Arc<Keyring>>>> = Lazy::new(|| RwLock::new(None));
+
+/// Installs the keyring used by [`encrypt`] and [`decrypt`]. `None` turns
+/// encryption off for new writes; existing blobs can then no longer be read.
+pub fn install(keyring: Option<Keyring>) {
+  *KEYRING.write() = keyring.map(Arc::new);
+}
+
+pub fn enabled() -> bool {
+  KEYRING.read().is_some()
+}
+
+pub fn keyring() -> Option<Arc<Keyring>> {
+  KEYRING.read().clone()
+}
+
+/// Id of the data key that sealed `data`, `None` for plaintext.
+pub fn key_id(data: &[u8]) -> Option<u32> {
+  if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+    return None;
+  }
+  let mut id = [0u8; KEY_ID_LEN];
+  id.copy_from_slice(&data[MAGIC.len()..MAGIC.len() + KEY_ID_LEN]);
+  Some(u32::from_be_bytes(id))
+}
+
+/// Seals `plain` with the current data key, or returns it unchanged when no
+/// keyring is installed.
+pub fn encrypt(plain: &[u8]) -> Result<Vec<u8>, AnyError> {
+  match keyring() {
+    Some(keyring) => keyring.seal(plain),
+    None => Ok(plain.to_vec()),
+  }
+}
+
+/// Opens a sealed blob. Plaintext is returned as is.
+pub fn decrypt(data: Vec<u8>) -> Result<Vec<u8>, AnyError> {
+  if key_id(&data).is_none() {
+    return Ok(data);
+  }
+  match keyring() {
+    Some(keyring) => keyring.open(&data),
+    None => Err(generic_error("data is encrypted but no keyring is installed")),
+  }
+}
+
+/// Seals `plain` with an explicit key, used for data keys wrapped by the
+/// master key as well.
+pub fn seal_with(key_id: u32, key: &[u8; 32], plain: &[u8]) -> Result<Vec<u8>, AnyError> {
+  let mut nonce = [0u8; aead::NONCE_LEN];
+  SystemRandom::new()
+    .fill(&mut nonce)
+    .map_err(|_| generic_error("failed to generate nonce"))?;
+  let mut out = Vec::with_capacity(HEADER_LEN + plain.len() + aead::AES_256_GCM.tag_len());
+  out.extend_from_slice(MAGIC);
+  out.extend_from_slice(&key_id.to_be_bytes());
+  out.extend_from_slice(&nonce);
+  let mut in_out = plain.to_vec();
+  sealing_key(key)?
+    .seal_in_place_append_tag(
+      aead::Nonce::assume_unique_for_key(nonce),
+      aead::Aad::from(&out[..MAGIC.len() + KEY_ID_LEN]),
+      &mut in_out,
+    )
+    .map_err(|_| generic_error("encryption failed"))?;
+  out.extend_from_slice(&in_out);
+  Ok(out)
+}
+
+pub fn open_with(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, AnyError> {
+  if key_id(data).is_none() {
+    return Err(generic_error("data is not encrypted"));
+  }
+  let mut nonce = [0u8; aead::NONCE_LEN];
+  nonce.copy_from_slice(&data[MAGIC.len() + KEY_ID_LEN..HEADER_LEN]);
+  let mut in_out = data[HEADER_LEN..].to_vec();
+  let plain = sealing_key(key)?
+    .open_in_place(
+      aead::Nonce::assume_unique_for_key(nonce),
+      aead::Aad::from(&data[..MAGIC.len() + KEY_ID_LEN]),
+      &mut in_out,
+    )
+    .map_err(|_| generic_error("decryption failed, wrong key or corrupted data"))?;
+  Ok(plain.to_vec())
+}
+
+fn sealing_key(key: &[u8; 32]) -> Result<aead::LessSafeKey, AnyError> {
+  let key = aead::UnboundKey::new(&aead::AES_256_GCM, key).map_err(|_| generic_error("invalid data key"))?;
+  Ok(aead::LessSafeKey::new(key))
+}
+
+fn encrypt_value(value: &mut Value) -> Result<(), AnyError> {
+  match value {
+    Value::V8(bytes) | Value::Bytes(bytes) => *bytes = encrypt(bytes)?,
+    // Numbers take part in sum/min/max inside the database and stay plain.
+    Value::U64(_) => {}
+  }
+  Ok(())
+}
+
+fn decrypt_value(value: &mut Value) -> Result<(), AnyError> {
+  if let Value::V8(bytes) | Value::Bytes(bytes) = value {
+    *bytes = decrypt(std::mem::take(bytes))?;
+  }
+  Ok(())
+}
+
+/// Wraps a `deno_kv` database handler so values and queue payloads are
+/// sealed before they reach the database. Keys stay plaintext because the
+/// database orders and ranges over them.
+pub struct EncryptedDbHandler<H>(pub H);
+
+#[async_trait(?Send)]
+impl<H: DatabaseHandler> DatabaseHandler for EncryptedDbHandler<H> {
+  type DB = EncryptedDb<H::DB>;
+
+  async fn open(&self, state: Rc<RefCell<OpState>>, path: Option<String>) -> Result<Self::DB, AnyError> {
+    Ok(EncryptedDb(self.0.open(state, path).await?))
+  }
+}
+
+pub struct EncryptedDb<D>(D);
+
+#[async_trait(?Send)]
+impl<D: Database> Database for EncryptedDb<D> {
+  type QMH = EncryptedMessageHandle<D::QMH>;
+
+  async fn snapshot_read(&self, requests: Vec<ReadRange>, options: SnapshotReadOptions) -> Result<Vec<ReadRangeOutput>, AnyError> {
+    let mut outputs = self.0.snapshot_read(requests, options).await?;
+    for entry in outputs.iter_mut().flat_map(|o| o.entries.iter_mut()) {
+      decrypt_value(&mut entry.value)?;
+    }
+    Ok(outputs)
+  }
+
+  async fn atomic_write(&self, mut write: AtomicWrite) -> Result<Option<CommitResult>, AnyError> {
+    for mutation in write.mutations.iter_mut() {
+      if let MutationKind::Set(value) = &mut mutation.kind {
+        encrypt_value(value)?;
+      }
+    }
+    for enqueue in write.enqueues.iter_mut() {
+      enqueue.payload = encrypt(&enqueue.payload)?;
+    }
+    self.0.atomic_write(write).await
+  }
+
+  async fn dequeue_next_message(&self) -> Result<Self::QMH, AnyError> {
+    Ok(EncryptedMessageHandle(self.0.dequeue_next_message().await?))
+  }
+
+  fn close(&self) {
+    self.0.close()
+  }
+}
+
+pub struct EncryptedMessageHandle<H>(H);
+
+#[async_trait(?Send)]
+impl<H: QueueMessageHandle> QueueMessageHandle for EncryptedMessageHandle<H> {
+  async fn take_payload(&mut self) -> Result<Vec<u8>, AnyError> {
+    decrypt(self.0.take_payload().await?)
+  }
+
+  async fn finish(&self, success: bool) -> Result<(), AnyError> {
+    self.0.finish(success).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn seals_with_current_key_and_opens_older_keys() {
+    let mut keys = HashMap::new();
+    keys.insert(1, [1u8; 32]);
+    let old = Keyring::new(1, keys.clone()).unwrap();
+    let sealed_old = old.seal(b"export default 1").unwrap();
+    assert_eq!(key_id(&sealed_old), Some(1));
+
+    keys.insert(2, [2u8; 32]);
+    let rotated = Keyring::new(2, keys).unwrap();
+    let sealed_new = rotated.seal(b"export default 2").unwrap();
+    assert_eq!(key_id(&sealed_new), Some(2));
+    assert_eq!(rotated.open(&sealed_old).unwrap(), b"export default 1");
+    assert_eq!(rotated.open(&sealed_new).unwrap(), b"export default 2");
+    assert_eq!(rotated.open(b"plain").unwrap(), b"plain");
+  }
+
+  #[test]
+  fn rejects_tampered_blobs() {
+    let keyring = Keyring::new(7, HashMap::from([(7, [7u8; 32])])).unwrap();
+    let mut sealed = keyring.seal(b"secret").unwrap();
+    let last = sealed.len() - 1;
+    sealed[last] ^= 1;
+    assert!(keyring.open(&sealed).is_err());
+  }
+}
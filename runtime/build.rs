@@ -184,10 +184,13 @@ mod startup_snapshot {
       "11_workers.js",
       "13_buffer.js",
       "30_os.js",
+      "40_context.js",
       "40_fs_events.js",
       "40_http.js",
       "40_process.js",
+      "40_scratch.js",
       "40_signals.js",
+      "40_tasks.js",
       "40_tty.js",
       "41_prompt.js",
       "90_deno_ns.js",
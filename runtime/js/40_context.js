@@ -0,0 +1,48 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+const core = globalThis.Deno.core;
+const ops = core.ops;
+const primordials = globalThis.__bootstrap.primordials;
+const {
+  Number,
+  ObjectFreeze,
+  String,
+} = primordials;
+
+/** Set by the platform on forwarded requests, see `ops/context.rs`. */
+const CONTEXT_HEADER = "x-cool-context";
+
+/**
+ * Metadata the platform recorded for `request`: request id, principal
+ * claims, client ip and geo, product version and deployment id. Returns
+ * `null` for requests that didn't come through the platform.
+ */
+function context(request) {
+  const key = request?.headers?.get(CONTEXT_HEADER);
+  if (!key) {
+    return null;
+  }
+  const ctx = ops.op_request_context(String(key));
+  if (ctx === null) {
+    return null;
+  }
+  if (ctx.principal !== null) {
+    ObjectFreeze(ctx.principal);
+  }
+  return ObjectFreeze(ctx);
+}
+
+/**
+ * Reports how loaded the script is, from 0 (idle) to 1 (saturated). The
+ * platform gradually accepts fewer requests as the pressure rises. Reports
+ * expire, so scripts should report periodically.
+ */
+function reportPressure(pressure) {
+  ops.op_report_pressure(Number(pressure));
+}
+
+const platform = ObjectFreeze({
+  context,
+  reportPressure,
+});
+
+export { platform };
@@ -0,0 +1,49 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+const core = globalThis.Deno.core;
+const ops = core.ops;
+import * as fs from "ext:deno_fs/30_fs.js";
+const primordials = globalThis.__bootstrap.primordials;
+const {
+  ObjectAssign,
+} = primordials;
+
+/**
+ * Private scratch directory of the worker, or `null` when the embedder
+ * didn't give it one. The directory is removed when the worker stops.
+ */
+function scratchDir() {
+  return ops.op_scratch_dir();
+}
+
+/** Temp files and directories go to the scratch directory by default. */
+function withScratchDir(options) {
+  const dir = ops.op_scratch_dir();
+  if (dir === null || options?.dir !== undefined) {
+    return options;
+  }
+  return ObjectAssign({}, options, { dir });
+}
+
+function makeTempDirSync(options) {
+  return fs.makeTempDirSync(withScratchDir(options));
+}
+
+function makeTempDir(options) {
+  return fs.makeTempDir(withScratchDir(options));
+}
+
+function makeTempFileSync(options) {
+  return fs.makeTempFileSync(withScratchDir(options));
+}
+
+function makeTempFile(options) {
+  return fs.makeTempFile(withScratchDir(options));
+}
+
+export {
+  makeTempDir,
+  makeTempDirSync,
+  makeTempFile,
+  makeTempFileSync,
+  scratchDir,
+};
@@ -0,0 +1,59 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+const core = globalThis.Deno.core;
+const ops = core.ops;
+const primordials = globalThis.__bootstrap.primordials;
+const {
+  PromisePrototypeThen,
+  PromiseResolve,
+  String,
+} = primordials;
+
+class TaskContext {
+  #id;
+
+  constructor(id) {
+    this.#id = id;
+  }
+
+  get id() {
+    return this.#id;
+  }
+
+  /** Whether the platform asked the task to stop. */
+  get cancelled() {
+    return ops.op_task_cancelled(this.#id);
+  }
+
+  /**
+   * Reports progress (0 to 100) with an optional message, returns `true`
+   * when the task was cancelled and should stop.
+   */
+  progress(percent, message) {
+    return ops.op_task_progress(this.#id, percent, message ?? null);
+  }
+}
+
+/**
+ * Runs `fn` in the background as a task tracked by the platform and
+ * returns the task id right away.
+ */
+function startTask(name, fn) {
+  const id = ops.op_task_start(String(name));
+  const ctx = new TaskContext(id);
+  const running = PromisePrototypeThen(PromiseResolve(null), () => {
+    ops.op_task_progress(id, 0, null);
+    return fn(ctx);
+  });
+  PromisePrototypeThen(
+    running,
+    () => ops.op_task_finish(id, null),
+    (err) => ops.op_task_finish(id, String(err?.stack ?? err)),
+  );
+  return id;
+}
+
+const tasks = {
+  start: startTask,
+};
+
+export { tasks };
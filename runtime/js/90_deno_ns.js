@@ -18,7 +18,9 @@ import * as fs from "ext:deno_fs/30_fs.js";
 import * as os from "ext:runtime/30_os.js";
 import * as fsEvents from "ext:runtime/40_fs_events.js";
 import * as process from "ext:runtime/40_process.js";
+import * as scratch from "ext:runtime/40_scratch.js";
 import * as signals from "ext:runtime/40_signals.js";
+import * as tasks from "ext:runtime/40_tasks.js";
 import * as tty from "ext:runtime/40_tty.js";
 // TODO(bartlomieju): this is funky we have two `http` imports
 import * as httpRuntime from "ext:runtime/40_http.js";
@@ -44,10 +46,10 @@ const denoNs = {
   chownSync: fs.chownSync,
   copyFileSync: fs.copyFileSync,
   cwd: fs.cwd,
-  makeTempDirSync: fs.makeTempDirSync,
-  makeTempDir: fs.makeTempDir,
-  makeTempFileSync: fs.makeTempFileSync,
-  makeTempFile: fs.makeTempFile,
+  makeTempDirSync: scratch.makeTempDirSync,
+  makeTempDir: scratch.makeTempDir,
+  makeTempFileSync: scratch.makeTempFileSync,
+  makeTempFile: scratch.makeTempFile,
   memoryUsage: () => ops.op_runtime_memory_usage(),
   mkdirSync: fs.mkdirSync,
   mkdir: fs.mkdir,
@@ -176,6 +178,8 @@ const denoNsUnstable = {
   Kv: kv.Kv,
   KvU64: kv.KvU64,
   KvListIterator: kv.KvListIterator,
+  tasks: tasks.tasks,
+  scratchDir: scratch.scratchDir,
 };
 
 export { denoNs, denoNsUnstable };
@@ -38,6 +38,7 @@ import DOMException from "ext:deno_web/01_dom_exception.js";
 import * as abortSignal from "ext:deno_web/03_abort_signal.js";
 import * as globalInterfaces from "ext:deno_web/04_global_interfaces.js";
 import * as webStorage from "ext:deno_webstorage/01_webstorage.js";
+import * as context from "ext:runtime/40_context.js";
 import * as prompt from "ext:runtime/41_prompt.js";
 
 // https://developer.mozilla.org/en-US/docs/Web/API/WindowOrWorkerGlobalScope
@@ -130,6 +131,7 @@ const windowOrWorkerGlobalScope = {
   SubtleCrypto: util.nonEnumerable(crypto.SubtleCrypto),
   fetch: util.writable(fetch.fetch),
   performance: util.writable(performance.performance),
+  Platform: util.readOnly(context.platform),
   reportError: util.writable(event.reportError),
   setInterval: util.writable(timers.setInterval),
   setTimeout: util.writable(timers.setTimeout),
@@ -21,6 +21,7 @@ pub use deno_webidl;
 pub use deno_websocket;
 pub use deno_webstorage;
 
+pub mod at_rest;
 pub mod colors;
 pub mod errors;
 pub mod fmt_errors;
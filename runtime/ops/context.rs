@@ -0,0 +1,166 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+
+//! Metadata of the request a script is handling.
+//!
+//! The embedder registers a [`RequestContext`] before forwarding a request
+//! and passes the returned key in the [`REQUEST_CONTEXT_HEADER`] header.
+//! `Platform.context(request)` looks the key up, so scripts don't have to
+//! parse forwarded headers. A context is only visible to workers of the
+//! scope it was registered under and is removed when its [`ContextGuard`]
+//! is dropped.
+//!
+//! `Platform.reportPressure(value)` goes the other way: scripts report how
+//! loaded they are and the embedder reads it with [`reported_pressure`].
+
+use crate::ops::tasks::TaskScope;
+use deno_core::error::type_error;
+use deno_core::error::AnyError;
+use deno_core::op;
+use deno_core::parking_lot::Mutex;
+use deno_core::OpState;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::thread::ThreadId;
+use std::time::Duration;
+use std::time::Instant;
+
+deno_core::extension!(
+  deno_context,
+  ops = [op_request_context, op_report_pressure],
+  customizer = |ext: &mut deno_core::ExtensionBuilder| {
+    ext.force_op_registration();
+  },
+);
+
+/// Header carrying the context key. The embedder must strip it from
+/// incoming requests.
+pub const REQUEST_CONTEXT_HEADER: &str = "x-cool-context";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestContext {
+  pub request_id: String,
+  /// Claims of the authenticated caller, `None` for anonymous requests.
+  pub principal: Option<BTreeMap<String, String>>,
+  pub client_ip: Option<String>,
+  pub country: Option<String>,
+  pub region: Option<String>,
+  /// Product version serving the request.
+  pub version: Option<String>,
+  pub deployment_id: Option<String>,
+}
+
+struct Entry {
+  scope: String,
+  context: RequestContext,
+}
+
+static CONTEXTS: Lazy<Mutex<HashMap<String, Entry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+/// Latest pressure per scope and worker thread, each worker reports its own.
+static PRESSURE: Lazy<Mutex<HashMap<String, HashMap<ThreadId, (f64, Instant)>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Removes the context when dropped.
+pub struct ContextGuard {
+  key: String,
+}
+
+impl ContextGuard {
+  /// Value of the [`REQUEST_CONTEXT_HEADER`] header.
+  pub fn key(&self) -> &str {
+    &self.key
+  }
+}
+
+impl Drop for ContextGuard {
+  fn drop(&mut self) {
+    CONTEXTS.lock().remove(&self.key);
+  }
+}
+
+/// Registers the context of a request for the workers of `scope` (the
+/// product code in the gateway). The key is random so a script can't read
+/// the context of a request it didn't receive.
+pub fn register(scope: &str, context: RequestContext) -> ContextGuard {
+  let key = uuid::Uuid::new_v4().to_string();
+  CONTEXTS.lock().insert(
+    key.clone(),
+    Entry {
+      scope: scope.to_string(),
+      context,
+    },
+  );
+  ContextGuard { key }
+}
+
+pub fn get_context(scope: &str, key: &str) -> Option<RequestContext> {
+  CONTEXTS.lock().get(key).filter(|e| e.scope == scope).map(|e| e.context.clone())
+}
+
+fn report_pressure(scope: &str, pressure: f64) {
+  PRESSURE
+    .lock()
+    .entry(scope.to_string())
+    .or_default()
+    .insert(std::thread::current().id(), (pressure.clamp(0.0, 1.0), Instant::now()));
+}
+
+/// Highest pressure the workers of `scope` reported within `max_age`,
+/// between 0 (idle) and 1 (saturated). Older reports are dropped.
+pub fn reported_pressure(scope: &str, max_age: Duration) -> Option<f64> {
+  let mut pressure = PRESSURE.lock();
+  let reports = pressure.get_mut(scope)?;
+  reports.retain(|_, (_, at)| at.elapsed() <= max_age);
+  reports.values().map(|(value, _)| *value).reduce(f64::max)
+}
+
+fn task_scope(state: &OpState) -> String {
+  state
+    .try_borrow::<TaskScope>()
+    .map(|s| s.0.clone())
+    .unwrap_or_else(|| "default".to_string())
+}
+
+#[op]
+fn op_request_context(state: &mut OpState, key: String) -> Option<RequestContext> {
+  get_context(&task_scope(state), &key)
+}
+
+#[op]
+fn op_report_pressure(state: &mut OpState, pressure: f64) -> Result<(), AnyError> {
+  if !pressure.is_finite() {
+    return Err(type_error("pressure must be a finite number"));
+  }
+  report_pressure(&task_scope(state), pressure);
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_context_is_scoped_and_removed_on_drop() {
+    let context = RequestContext {
+      request_id: "req-1".to_string(),
+      country: Some("DE".to_string()),
+      ..Default::default()
+    };
+    let guard = register("shop", context.clone());
+    let key = guard.key().to_string();
+    assert_eq!(get_context("shop", &key), Some(context));
+    assert_eq!(get_context("blog", &key), None);
+    drop(guard);
+    assert_eq!(get_context("shop", &key), None);
+  }
+
+  #[test]
+  fn test_reported_pressure_is_clamped_and_expires() {
+    report_pressure("pressure-shop", 1.7);
+    assert_eq!(reported_pressure("pressure-shop", Duration::from_secs(5)), Some(1.0));
+    assert_eq!(reported_pressure("pressure-blog", Duration::from_secs(5)), None);
+    assert_eq!(reported_pressure("pressure-shop", Duration::ZERO), None);
+  }
+}
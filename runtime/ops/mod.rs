@@ -1,12 +1,15 @@
 // Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
 
+pub mod context;
 pub mod fs_events;
 pub mod http;
 pub mod os;
 pub mod permissions;
 pub mod process;
 pub mod runtime;
+pub mod scratch;
 pub mod signal;
+pub mod tasks;
 pub mod tty;
 mod utils;
 pub mod web_worker;
@@ -0,0 +1,151 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+
+//! Private scratch directories of workers.
+//!
+//! The embedder gives each worker a scratch directory, puts a [`ScratchDir`]
+//! into the op state and registers a size quota for it. Scripts find the
+//! directory with `Deno.scratchDir()`, and `Deno.makeTempDir()` /
+//! `Deno.makeTempFile()` default to it.
+//!
+//! The quota is checked together with the write permission, so once a
+//! directory is over quota no file inside it can be created, opened for
+//! writing or renamed into it. Usage is measured at most once per
+//! [`MEASURE_INTERVAL`].
+
+use crate::fs_util::resolve_from_cwd;
+use deno_core::error::custom_error;
+use deno_core::error::AnyError;
+use deno_core::op;
+use deno_core::parking_lot::Mutex;
+use deno_core::OpState;
+use once_cell::sync::Lazy;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+deno_core::extension!(
+  deno_scratch,
+  ops = [op_scratch_dir],
+  customizer = |ext: &mut deno_core::ExtensionBuilder| {
+    ext.force_op_registration();
+  },
+);
+
+/// Scratch directory of the worker. Workers without one get `null` from
+/// `Deno.scratchDir()`.
+#[derive(Debug, Clone)]
+pub struct ScratchDir(pub PathBuf);
+
+const MEASURE_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Quota {
+  dir: PathBuf,
+  limit: u64,
+  used: u64,
+  measured_at: Option<Instant>,
+}
+
+static QUOTAS: Lazy<Mutex<Vec<Quota>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Limits the size of `dir` to `limit` bytes. Registering a directory again
+/// replaces its limit.
+pub fn register(dir: &Path, limit: u64) {
+  let mut quotas = QUOTAS.lock();
+  quotas.retain(|q| q.dir != dir);
+  quotas.push(Quota {
+    dir: dir.to_path_buf(),
+    limit,
+    used: 0,
+    measured_at: None,
+  });
+}
+
+pub fn unregister(dir: &Path) {
+  QUOTAS.lock().retain(|q| q.dir != dir);
+}
+
+/// Total size of the files below `dir`, symlinks are not followed.
+pub fn dir_size(dir: &Path) -> u64 {
+  let entries = match std::fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(_) => return 0,
+  };
+  let mut size = 0;
+  for entry in entries.flatten() {
+    match entry.file_type() {
+      Ok(t) if t.is_dir() => size += dir_size(&entry.path()),
+      Ok(t) if t.is_file() => size += entry.metadata().map(|m| m.len()).unwrap_or(0),
+      _ => {}
+    }
+  }
+  size
+}
+
+/// Fails when `path` is inside a scratch directory that is over its quota.
+pub fn check_quota(path: &Path, api_name: &str) -> Result<(), AnyError> {
+  if QUOTAS.lock().is_empty() {
+    return Ok(());
+  }
+  let path = resolve_from_cwd(path)?;
+  let (dir, limit, stale) = {
+    let quotas = QUOTAS.lock();
+    let quota = match quotas.iter().find(|q| path.starts_with(&q.dir)) {
+      Some(quota) => quota,
+      None => return Ok(()),
+    };
+    let stale = quota.measured_at.map(|at| at.elapsed() >= MEASURE_INTERVAL).unwrap_or(true);
+    if !stale && quota.used < quota.limit {
+      return Ok(());
+    }
+    (quota.dir.clone(), quota.limit, stale)
+  };
+  // measure outside of the lock, other workers keep writing meanwhile
+  let used = if stale {
+    let used = dir_size(&dir);
+    if let Some(quota) = QUOTAS.lock().iter_mut().find(|q| q.dir == dir) {
+      quota.used = used;
+      quota.measured_at = Some(Instant::now());
+    }
+    used
+  } else {
+    limit
+  };
+  if used < limit {
+    return Ok(());
+  }
+  crate::permissions::report_denied("write", Some(api_name), Some(path.display().to_string()));
+  Err(custom_error(
+    "PermissionDenied",
+    format!(
+      "Scratch directory quota of {limit} bytes exceeded, can't write \"{}\" (used by {api_name})",
+      path.display()
+    ),
+  ))
+}
+
+#[op]
+fn op_scratch_dir(state: &mut OpState) -> Option<String> {
+  state.try_borrow::<ScratchDir>().map(|d| d.0.to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rejects_writes_once_over_quota() {
+    let dir = std::env::temp_dir().join(format!("deno-scratch-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    register(&dir, 8);
+    assert!(check_quota(&dir.join("a.txt"), "Deno.writeFile()").is_ok());
+    std::fs::write(dir.join("a.txt"), b"0123456789").unwrap();
+    // a fresh registration measures again right away
+    register(&dir, 8);
+    assert!(check_quota(&dir.join("b.txt"), "Deno.writeFile()").is_err());
+    assert!(check_quota(Path::new("/elsewhere/b.txt"), "Deno.writeFile()").is_ok());
+    unregister(&dir);
+    assert!(check_quota(&dir.join("b.txt"), "Deno.writeFile()").is_ok());
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,224 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+
+//! Long running tasks started by scripts.
+//!
+//! Scripts register a task with `Deno.tasks.start()` and report progress
+//! while it runs. The table lives outside of the isolate so the embedder can
+//! list tasks and request cancellation from another thread; tasks are keyed
+//! by the scope the embedder put into the op state (the product code in the
+//! gateway).
+
+use deno_core::error::type_error;
+use deno_core::error::AnyError;
+use deno_core::op;
+use deno_core::parking_lot::Mutex;
+use deno_core::OpState;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+deno_core::extension!(
+  deno_tasks,
+  ops = [op_task_start, op_task_progress, op_task_finish, op_task_cancelled],
+  customizer = |ext: &mut deno_core::ExtensionBuilder| {
+    ext.force_op_registration();
+  },
+);
+
+/// Scope the tasks of a worker are registered under. Workers without a
+/// scope use `"default"`.
+#[derive(Debug, Clone)]
+pub struct TaskScope(pub String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskState {
+  Pending,
+  Running,
+  Succeeded,
+  Failed,
+  Cancelled,
+}
+
+impl TaskState {
+  pub fn is_finished(&self) -> bool {
+    matches!(self, TaskState::Succeeded | TaskState::Failed | TaskState::Cancelled)
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskInfo {
+  pub id: String,
+  pub scope: String,
+  pub name: String,
+  pub state: TaskState,
+  /// 0 to 100.
+  pub progress: f64,
+  pub message: Option<String>,
+  pub error: Option<String>,
+  pub created_at: u64,
+  pub updated_at: u64,
+}
+
+/// Finished tasks are kept this long so their result can still be queried.
+const FINISHED_TASK_TTL_MS: u64 = 24 * 60 * 60 * 1000;
+
+static TASKS: Lazy<Mutex<HashMap<String, TaskInfo>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn now_millis() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_millis() as u64)
+    .unwrap_or_default()
+}
+
+fn scope_of(state: &OpState) -> String {
+  state
+    .try_borrow::<TaskScope>()
+    .map(|s| s.0.clone())
+    .unwrap_or_else(|| "default".to_string())
+}
+
+/// Tasks of a scope, oldest first.
+pub fn list_tasks(scope: &str) -> Vec<TaskInfo> {
+  let mut tasks: Vec<TaskInfo> = TASKS.lock().values().filter(|t| t.scope == scope).cloned().collect();
+  tasks.sort_by_key(|t| t.created_at);
+  tasks
+}
+
+pub fn get_task(scope: &str, id: &str) -> Option<TaskInfo> {
+  TASKS.lock().get(id).filter(|t| t.scope == scope).cloned()
+}
+
+/// Requests cancellation. The script sees it on its next progress report;
+/// returns `false` when the task doesn't exist or already finished.
+pub fn cancel_task(scope: &str, id: &str) -> bool {
+  let mut tasks = TASKS.lock();
+  match tasks.get_mut(id) {
+    Some(task) if task.scope == scope && !task.state.is_finished() => {
+      task.state = TaskState::Cancelled;
+      task.updated_at = now_millis();
+      true
+    }
+    _ => false,
+  }
+}
+
+/// Marks the unfinished tasks of a scope as failed, used when the workers
+/// running them were stopped.
+pub fn fail_unfinished_tasks(scope: &str, reason: &str) {
+  let now = now_millis();
+  for task in TASKS.lock().values_mut() {
+    if task.scope == scope && !task.state.is_finished() {
+      task.state = TaskState::Failed;
+      task.error = Some(reason.to_string());
+      task.updated_at = now;
+    }
+  }
+}
+
+#[op]
+fn op_task_start(state: &mut OpState, name: String) -> String {
+  let now = now_millis();
+  let id = uuid::Uuid::new_v4().to_string();
+  let mut tasks = TASKS.lock();
+  tasks.retain(|_, t| !t.state.is_finished() || now.saturating_sub(t.updated_at) < FINISHED_TASK_TTL_MS);
+  tasks.insert(
+    id.clone(),
+    TaskInfo {
+      id: id.clone(),
+      scope: scope_of(state),
+      name,
+      state: TaskState::Pending,
+      progress: 0.0,
+      message: None,
+      error: None,
+      created_at: now,
+      updated_at: now,
+    },
+  );
+  id
+}
+
+/// Records progress and returns whether the task was cancelled.
+#[op]
+fn op_task_progress(id: String, progress: f64, message: Option<String>) -> Result<bool, AnyError> {
+  let mut tasks = TASKS.lock();
+  let task = tasks.get_mut(&id).ok_or_else(|| type_error(format!("Unknown task {id}")))?;
+  if task.state == TaskState::Cancelled {
+    return Ok(true);
+  }
+  if !task.state.is_finished() {
+    task.state = TaskState::Running;
+    if progress.is_finite() {
+      task.progress = progress.clamp(0.0, 100.0);
+    }
+    if message.is_some() {
+      task.message = message;
+    }
+    task.updated_at = now_millis();
+  }
+  Ok(false)
+}
+
+#[op]
+fn op_task_finish(id: String, error: Option<String>) {
+  let mut tasks = TASKS.lock();
+  if let Some(task) = tasks.get_mut(&id) {
+    // a cancelled task keeps its state whatever the script did afterwards
+    if task.state.is_finished() {
+      return;
+    }
+    match error {
+      Some(error) => {
+        task.state = TaskState::Failed;
+        task.error = Some(error);
+      }
+      None => {
+        task.state = TaskState::Succeeded;
+        task.progress = 100.0;
+      }
+    }
+    task.updated_at = now_millis();
+  }
+}
+
+#[op]
+fn op_task_cancelled(id: String) -> bool {
+  TASKS.lock().get(&id).map(|t| t.state == TaskState::Cancelled).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_cancel_only_unfinished_tasks_in_scope() {
+    let now = now_millis();
+    let task = |id: &str, state| TaskInfo {
+      id: id.to_string(),
+      scope: "demo".to_string(),
+      name: "import".to_string(),
+      state,
+      progress: 0.0,
+      message: None,
+      error: None,
+      created_at: now,
+      updated_at: now,
+    };
+    {
+      let mut tasks = TASKS.lock();
+      tasks.insert("t1".to_string(), task("t1", TaskState::Running));
+      tasks.insert("t2".to_string(), task("t2", TaskState::Succeeded));
+    }
+    assert!(!cancel_task("other", "t1"));
+    assert!(cancel_task("demo", "t1"));
+    assert!(!cancel_task("demo", "t2"));
+    assert_eq!(get_task("demo", "t1").unwrap().state, TaskState::Cancelled);
+    assert_eq!(list_tasks("demo").len(), 2);
+  }
+}
@@ -2,6 +2,7 @@
 
 use crate::colors;
 use crate::fs_util::resolve_from_cwd;
+use crate::ops::scratch;
 use deno_core::error::custom_error;
 use deno_core::error::type_error;
 use deno_core::error::uri_error;
@@ -18,6 +19,7 @@ use deno_core::ModuleSpecifier;
 use deno_core::OpState;
 use log;
 use once_cell::sync::Lazy;
+use std::cell::RefCell;
 use std::collections::HashSet;
 use std::fmt;
 use std::hash::Hash;
@@ -95,10 +97,16 @@ impl PermissionState {
             Self::log_perm_access(name, info);
             (Ok(()), true, true)
           }
-          PromptResponse::Deny => (Err(Self::error(name, info)), true, false),
+          PromptResponse::Deny => {
+            report_denied(name, api_name, info());
+            (Err(Self::error(name, info)), true, false)
+          }
         }
       }
-      _ => (Err(Self::error(name, info)), false, false),
+      _ => {
+        report_denied(name, api_name, info());
+        (Err(Self::error(name, info)), false, false)
+      }
     }
   }
 }
@@ -113,6 +121,30 @@ impl fmt::Display for PermissionState {
   }
 }
 
+/// Called with the permission name, api name and target whenever a check
+/// is denied.
+pub type PermissionDeniedHook = Box<dyn Fn(&str, Option<&str>, Option<String>)>;
+
+thread_local! {
+  static PERMISSION_DENIED_HOOK: RefCell<Option<PermissionDeniedHook>> = RefCell::new(None);
+}
+
+/// Installs a hook for permission denials on the current thread. Every
+/// product runtime runs on its own thread, which lets the embedder attribute
+/// violations to the product that caused them.
+pub fn set_permission_denied_hook(hook: Option<PermissionDeniedHook>) {
+  PERMISSION_DENIED_HOOK.with(|cell| *cell.borrow_mut() = hook);
+}
+
+/// Reports a denial to the hook installed on the current thread, if any.
+pub fn report_denied(name: &str, api_name: Option<&str>, info: Option<String>) {
+  PERMISSION_DENIED_HOOK.with(|cell| {
+    if let Some(hook) = cell.borrow().as_ref() {
+      hook(name, api_name, info);
+    }
+  });
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct UnitPermission {
   pub name: &'static str,
@@ -1522,7 +1554,8 @@ impl PermissionsContainer {
 
   #[inline(always)]
   pub fn check_write(&mut self, path: &Path, api_name: &str) -> Result<(), AnyError> {
-    self.0.lock().write.check(path, Some(api_name))
+    self.0.lock().write.check(path, Some(api_name))?;
+    scratch::check_quota(path, api_name)
   }
 
   #[inline(always)]
@@ -1532,7 +1565,8 @@ impl PermissionsContainer {
 
   #[inline(always)]
   pub fn check_write_blind(&mut self, path: &Path, display: &str, api_name: &str) -> Result<(), AnyError> {
-    self.0.lock().write.check_blind(path, display, api_name)
+    self.0.lock().write.check_blind(path, display, api_name)?;
+    scratch::check_quota(path, api_name)
   }
 
   #[inline(always)]
@@ -1631,11 +1665,13 @@ impl deno_fs::FsPermissions for PermissionsContainer {
   }
 
   fn check_write(&mut self, path: &Path, api_name: &str) -> Result<(), AnyError> {
-    self.0.lock().write.check(path, Some(api_name))
+    self.0.lock().write.check(path, Some(api_name))?;
+    scratch::check_quota(path, api_name)
   }
 
   fn check_write_blind(&mut self, p: &Path, display: &str, api_name: &str) -> Result<(), AnyError> {
-    self.0.lock().write.check_blind(p, display, api_name)
+    self.0.lock().write.check_blind(p, display, api_name)?;
+    scratch::check_quota(p, api_name)
   }
 
   fn check_read_all(&mut self, api_name: &str) -> Result<(), AnyError> {
@@ -1671,7 +1707,8 @@ impl deno_kv::sqlite::SqliteDbHandlerPermissions for PermissionsContainer {
 
   #[inline(always)]
   fn check_write(&mut self, p: &Path, api_name: &str) -> Result<(), AnyError> {
-    self.0.lock().write.check(p, Some(api_name))
+    self.0.lock().write.check(p, Some(api_name))?;
+    scratch::check_quota(p, api_name)
   }
 }
 
@@ -1,4 +1,5 @@
 // Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+use crate::at_rest::EncryptedDbHandler;
 use crate::colors;
 use crate::inspector_server::InspectorServer;
 use crate::ops;
@@ -415,7 +416,7 @@ impl WebWorker {
         options.unsafely_ignore_certificate_errors.clone(),
       ),
       deno_tls::deno_tls::init_ops(),
-      deno_kv::deno_kv::init_ops(SqliteDbHandler::<PermissionsContainer>::new(None), unstable),
+      deno_kv::deno_kv::init_ops(EncryptedDbHandler(SqliteDbHandler::<PermissionsContainer>::new(None)), unstable),
       deno_napi::deno_napi::init_ops::<PermissionsContainer>(),
       deno_http::deno_http::init_ops::<DefaultHttpPropertyExtractor>(),
       deno_io::deno_io::init_ops(Some(options.stdio)),
@@ -430,11 +431,14 @@ impl WebWorker {
         options.pre_execute_module_cb.clone(),
         options.format_js_error_fn.clone(),
       ),
+      ops::context::deno_context::init_ops(),
       ops::fs_events::deno_fs_events::init_ops(),
       ops::os::deno_os_worker::init_ops(),
       ops::permissions::deno_permissions::init_ops(),
       ops::process::deno_process::init_ops(),
+      ops::scratch::deno_scratch::init_ops(),
       ops::signal::deno_signal::init_ops(),
+      ops::tasks::deno_tasks::init_ops(),
       ops::tty::deno_tty::init_ops(),
       ops::http::deno_http_runtime::init_ops(),
       deno_permissions_web_worker::init_ops(permissions, unstable, enable_testing_features),
@@ -38,6 +38,7 @@ use deno_tls::RootCertStoreProvider;
 use deno_web::BlobStore;
 use log::debug;
 
+use crate::at_rest::EncryptedDbHandler;
 use crate::inspector_server::InspectorServer;
 use crate::ops;
 use crate::permissions::PermissionsContainer;
@@ -239,7 +240,10 @@ impl MainWorker {
         options.unsafely_ignore_certificate_errors.clone(),
       ),
       deno_tls::deno_tls::init_ops(),
-      deno_kv::deno_kv::init_ops(SqliteDbHandler::<PermissionsContainer>::new(options.origin_storage_dir.clone()), unstable),
+      deno_kv::deno_kv::init_ops(
+        EncryptedDbHandler(SqliteDbHandler::<PermissionsContainer>::new(options.origin_storage_dir.clone())),
+        unstable,
+      ),
       deno_napi::deno_napi::init_ops::<PermissionsContainer>(),
       deno_http::deno_http::init_ops::<DefaultHttpPropertyExtractor>(),
       deno_io::deno_io::init_ops(Some(options.stdio)),
@@ -253,11 +257,14 @@ impl MainWorker {
         options.web_worker_pre_execute_module_cb.clone(),
         options.format_js_error_fn.clone(),
       ),
+      ops::context::deno_context::init_ops(),
       ops::fs_events::deno_fs_events::init_ops(),
       ops::os::deno_os::init_ops(exit_code.clone()),
       ops::permissions::deno_permissions::init_ops(),
       ops::process::deno_process::init_ops(),
+      ops::scratch::deno_scratch::init_ops(),
       ops::signal::deno_signal::init_ops(),
+      ops::tasks::deno_tasks::init_ops(),
       ops::tty::deno_tty::init_ops(),
       ops::http::deno_http_runtime::init_ops(),
       deno_permissions_worker::init_ops(permissions, unstable, enable_testing_features),
@@ -9,6 +9,8 @@ use clap::Command;
 use clap::ValueHint;
 use deno_core::resolve_url_or_path;
 use deno_core::url::Url;
+use deno_runtime::deno_fetch::CassetteConfig;
+use deno_runtime::deno_fetch::FetchMocks;
 use deno_runtime::permissions::parse_sys_kind;
 use log::debug;
 use log::Level;
@@ -188,6 +190,9 @@ pub struct TestFlags {
   pub shuffle: Option<u64>,
   pub concurrent_jobs: Option<NonZeroUsize>,
   pub trace_ops: bool,
+  pub update_snapshots: bool,
+  pub report_slowest: Option<NonZeroUsize>,
+  pub isolate_per_test: bool,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -333,6 +338,59 @@ pub struct Flags {
   pub version: bool,
   pub watch: Option<Vec<PathBuf>>,
   pub no_clear_screen: bool,
+  /// Working directory used for module resolution and config discovery.
+  /// Not exposed as a CLI option, the gateway sets it per product.
+  pub cwd: Option<PathBuf>,
+  /// Pin map file for remote modules, see `cache::PinMap`.
+  /// Not exposed as a CLI option, the gateway sets it per product.
+  pub module_pins: Option<PathBuf>,
+  /// Offline mode: only these hosts may be reached, both by the module
+  /// loader and by the fetch, net and websocket apis. Not exposed as a CLI
+  /// option, the gateway sets it per product.
+  pub net_allowlist: Option<Vec<String>>,
+  /// Product the runtime belongs to, used to scope platform state such as
+  /// background tasks. Not exposed as a CLI option, the gateway sets it.
+  pub product_code: Option<String>,
+  /// Filesystem sandbox of a product runtime. Not exposed as a CLI option,
+  /// the gateway sets it per worker.
+  pub sandbox: Option<FsSandbox>,
+  /// Directory for the origin storage (KV, localStorage) instead of one
+  /// derived from the main module, so the state of a product stays in one
+  /// place across entries and deployments. Not exposed as a CLI option, the
+  /// gateway sets it per product.
+  pub storage_dir: Option<PathBuf>,
+  /// Reject `node:` imports in product code. Modules inside npm packages are
+  /// not affected. Not exposed as a CLI option, the gateway sets it for
+  /// products that did not enable Node compatibility.
+  pub deny_node_builtins: bool,
+  /// Expose the Node.js globals (`process`, `Buffer`, `global`,
+  /// `setImmediate`, `clearImmediate`) to product code before the main module
+  /// runs. Not exposed as a CLI option, the gateway sets it per product.
+  pub node_globals: bool,
+  /// Leave dynamic imports out of the module graph built at startup and load
+  /// them the first time they run, so products with many rarely used routes
+  /// start faster and hold less in memory. Not exposed as a CLI option, the
+  /// gateway sets it per product.
+  pub lazy_dynamic_imports: bool,
+  /// Canned responses for `fetch()` in test runs. Not exposed as a CLI
+  /// option, the gateway sets it when it runs the tests of a product.
+  pub fetch_mocks: Option<FetchMocks>,
+  /// Record or replay outbound requests of test runs. Not exposed as a CLI
+  /// option, the gateway sets it when it runs the tests of a product.
+  pub fetch_cassettes: Option<CassetteConfig>,
+}
+
+/// Scripts may read the code directory but only write to their private
+/// scratch directory. Subprocesses and FFI could bypass this, so they are
+/// denied unless commands are explicitly allowed.
+#[derive(Clone, Debug, Eq, PartialEq, Default)]
+pub struct FsSandbox {
+  pub code_dir: PathBuf,
+  pub scratch_dir: PathBuf,
+  /// Additional readable paths.
+  pub allow_read: Vec<PathBuf>,
+  /// Commands scripts may run.
+  pub allow_run: Vec<String>,
 }
 
 fn join_paths(allowlist: &[PathBuf], d: &str) -> String {
@@ -1581,6 +1639,26 @@ fn test_subcommand() -> Command {
         .help("Enable tracing of async ops. Useful when debugging leaking ops in test, but impacts test execution time.")
         .action(ArgAction::SetTrue),
     )
+    .arg(
+      Arg::new("isolate-per-test")
+        .long("isolate-per-test")
+        .help("Run every test in a fresh isolate, so tests can't leak globals or resources into each other. Slower than sharing an isolate per module.")
+        .action(ArgAction::SetTrue),
+    )
+    .arg(
+      Arg::new("report-slowest")
+        .long("report-slowest")
+        .help("Print the N slowest tests and test modules after the run")
+        .require_equals(true)
+        .value_name("N")
+        .value_parser(value_parser!(NonZeroUsize)),
+    )
+    .arg(
+      Arg::new("update-snapshots")
+        .long("update-snapshots")
+        .help("Write the values of snapshot assertions to __snapshots__ instead of comparing them")
+        .action(ArgAction::SetTrue),
+    )
     .arg(
       Arg::new("doc")
         .long("doc")
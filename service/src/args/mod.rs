@@ -46,6 +46,8 @@ use deno_core::parking_lot::Mutex;
 use deno_core::serde_json;
 use deno_core::url::Url;
 use deno_runtime::colors;
+use deno_runtime::deno_fetch::CassetteConfig;
+use deno_runtime::deno_fetch::FetchMocks;
 use deno_runtime::deno_node::PackageJson;
 use deno_runtime::deno_tls::rustls;
 use deno_runtime::deno_tls::rustls::RootCertStore;
@@ -53,6 +55,8 @@ use deno_runtime::deno_tls::rustls_native_certs::load_native_certs;
 use deno_runtime::deno_tls::rustls_pemfile;
 use deno_runtime::deno_tls::webpki_roots;
 use deno_runtime::inspector_server::InspectorServer;
+use deno_runtime::permissions::Permissions;
+use deno_runtime::permissions::PermissionsContainer;
 use deno_runtime::permissions::PermissionsOptions;
 use once_cell::sync::Lazy;
 use once_cell::sync::OnceCell;
@@ -220,6 +224,9 @@ pub struct TestOptions {
   pub shuffle: Option<u64>,
   pub concurrent_jobs: NonZeroUsize,
   pub trace_ops: bool,
+  pub update_snapshots: bool,
+  pub report_slowest: Option<NonZeroUsize>,
+  pub isolate_per_test: bool,
 }
 
 impl TestOptions {
@@ -236,6 +243,9 @@ impl TestOptions {
       no_run: test_flags.no_run,
       shuffle: test_flags.shuffle,
       trace_ops: test_flags.trace_ops,
+      update_snapshots: test_flags.update_snapshots,
+      report_slowest: test_flags.report_slowest,
+      isolate_per_test: test_flags.isolate_per_test,
     })
   }
 }
@@ -526,7 +536,10 @@ impl CliOptions {
   }
 
   pub fn from_flags(flags: Flags) -> Result<Self, AnyError> {
-    let initial_cwd = std::env::current_dir().with_context(|| "Failed getting cwd.")?;
+    let initial_cwd = match &flags.cwd {
+      Some(cwd) => cwd.clone(),
+      None => std::env::current_dir().with_context(|| "Failed getting cwd.")?,
+    };
     let maybe_config_file = ConfigFile::discover(&flags, &initial_cwd)?;
 
     let mut maybe_package_json = None;
@@ -923,6 +936,83 @@ impl CliOptions {
     resolve_no_prompt(&self.flags)
   }
 
+  pub fn module_pins_path(&self) -> Option<&PathBuf> {
+    self.flags.module_pins.as_ref()
+  }
+
+  pub fn net_allowlist(&self) -> Option<&Vec<String>> {
+    self.flags.net_allowlist.as_ref()
+  }
+
+  pub fn product_code(&self) -> Option<&String> {
+    self.flags.product_code.as_ref()
+  }
+
+  pub fn sandbox(&self) -> Option<&FsSandbox> {
+    self.flags.sandbox.as_ref()
+  }
+
+  pub fn storage_dir(&self) -> Option<&PathBuf> {
+    self.flags.storage_dir.as_ref()
+  }
+
+  pub fn deny_node_builtins(&self) -> bool {
+    self.flags.deny_node_builtins
+  }
+
+  pub fn node_globals(&self) -> bool {
+    self.flags.node_globals
+  }
+
+  pub fn lazy_dynamic_imports(&self) -> bool {
+    self.flags.lazy_dynamic_imports
+  }
+
+  pub fn fetch_mocks(&self) -> Option<&FetchMocks> {
+    self.flags.fetch_mocks.as_ref()
+  }
+
+  pub fn fetch_cassettes(&self) -> Option<&CassetteConfig> {
+    self.flags.fetch_cassettes.as_ref()
+  }
+
+  /// Permissions for product runtimes: everything is allowed, except network
+  /// access in offline mode which is limited to the allowlisted hosts, and
+  /// the filesystem in the sandbox where the code directory is read-only and
+  /// only the scratch directory is writable.
+  pub fn runtime_permissions(&self) -> Result<PermissionsContainer, AnyError> {
+    if self.flags.net_allowlist.is_none() && self.flags.sandbox.is_none() {
+      return Ok(PermissionsContainer::allow_all());
+    }
+    // an empty list would mean "allow all"
+    let allow_net = match &self.flags.net_allowlist {
+      Some(allowlist) if allowlist.is_empty() => None,
+      Some(allowlist) => Some(allowlist.clone()),
+      None => Some(vec![]),
+    };
+    let (allow_read, allow_write, allow_run, allow_ffi) = match &self.flags.sandbox {
+      Some(sandbox) => {
+        let mut read = vec![sandbox.code_dir.clone(), sandbox.scratch_dir.clone()];
+        read.extend(sandbox.allow_read.iter().cloned());
+        let run = (!sandbox.allow_run.is_empty()).then(|| sandbox.allow_run.clone());
+        (Some(read), Some(vec![sandbox.scratch_dir.clone()]), run, None)
+      }
+      None => (Some(vec![]), Some(vec![]), Some(vec![]), Some(vec![])),
+    };
+    let permissions = Permissions::from_options(&PermissionsOptions {
+      allow_env: Some(vec![]),
+      allow_hrtime: true,
+      allow_net,
+      allow_ffi,
+      allow_read,
+      allow_run,
+      allow_sys: Some(vec![]),
+      allow_write,
+      prompt: false,
+    })?;
+    Ok(PermissionsContainer::new(permissions))
+  }
+
   pub fn no_remote(&self) -> bool {
     self.flags.no_remote
   }
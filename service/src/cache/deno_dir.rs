@@ -116,6 +116,11 @@ impl DenoDir {
     self.root.join("deps")
   }
 
+  /// Path to the content addressed module store shared by all products.
+  pub fn module_store_folder_path(&self) -> PathBuf {
+    self.root.join("modules_v1")
+  }
+
   /// Path to the origin data cache folder.
   pub fn origin_data_folder_path(&self) -> PathBuf {
     // TODO(@crowlKats): change to origin_data for 2.0
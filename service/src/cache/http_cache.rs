@@ -16,8 +16,11 @@ use std::fs::File;
 use std::io;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::SystemTime;
 
+use super::module_store::ModuleStore;
+use super::module_store::PinMap;
 use super::CACHE_PERM;
 
 /// Turn base of url (scheme, hostname, port) into a valid filename.
@@ -105,6 +108,8 @@ impl CachedUrlMetadata {
 #[derive(Debug, Clone, Default)]
 pub struct HttpCache {
   pub location: PathBuf,
+  module_store: Option<ModuleStore>,
+  pins: Option<Arc<PinMap>>,
 }
 
 impl HttpCache {
@@ -115,9 +120,40 @@ impl HttpCache {
     assert!(location.is_absolute());
     Self {
       location: location.to_owned(),
+      module_store: None,
+      pins: None,
     }
   }
 
+  /// Stores module contents in the content addressed `store` and, when a
+  /// pin map is given, serves every pinned url from the content it was
+  /// pinned to.
+  pub fn with_module_store(mut self, store: ModuleStore, pins: Option<Arc<PinMap>>) -> Self {
+    self.module_store = Some(store);
+    self.pins = pins;
+    self
+  }
+
+  /// Returns the stored file a pinned url should be served from. Urls cached
+  /// before they were pinned are moved into the store and pinned on first
+  /// read, so the product keeps running the content it first loaded.
+  fn get_pinned_filename(&self, url: &Url, cache_filename: &Path) -> Result<Option<PathBuf>, AnyError> {
+    let (store, pins) = match (&self.module_store, &self.pins) {
+      (Some(store), Some(pins)) => (store, pins),
+      _ => return Ok(None),
+    };
+    if let Some(hash) = pins.get(url).filter(|hash| store.contains(hash)) {
+      return Ok(Some(store.path(&hash)));
+    }
+    if !cache_filename.is_file() {
+      return Ok(None);
+    }
+    let hash = store.put(&fs::read(cache_filename)?)?;
+    store.link(&hash, cache_filename)?;
+    pins.set(url, hash.clone())?;
+    Ok(Some(store.path(&hash)))
+  }
+
   /// Ensures the location of the cache.
   fn ensure_dir_exists(&self, path: &Path) -> io::Result<()> {
     if path.is_dir() {
@@ -143,7 +179,10 @@ impl HttpCache {
       .location
       .join(url_to_filename(url).ok_or_else(|| generic_error("Can't convert url to filename."))?);
     let metadata_filename = CachedUrlMetadata::filename(&cache_filename);
-    let file = File::open(cache_filename)?;
+    let file = match self.get_pinned_filename(url, &cache_filename)? {
+      Some(pinned_filename) => File::open(pinned_filename)?,
+      None => File::open(cache_filename)?,
+    };
     let metadata = fs::read_to_string(metadata_filename)?;
     let metadata: CachedUrlMetadata = serde_json::from_str(&metadata)?;
     Ok((file, metadata.headers, metadata.now))
@@ -157,7 +196,16 @@ impl HttpCache {
     let parent_filename = cache_filename.parent().expect("Cache filename should have a parent dir");
     self.ensure_dir_exists(parent_filename)?;
     // Cache content
-    util::fs::atomic_write_file(&cache_filename, content, CACHE_PERM)?;
+    match &self.module_store {
+      Some(store) => {
+        let hash = store.put(content)?;
+        store.link(&hash, &cache_filename)?;
+        if let Some(pins) = &self.pins {
+          pins.set(url, hash)?;
+        }
+      }
+      None => util::fs::atomic_write_file(&cache_filename, content, CACHE_PERM)?,
+    }
 
     let metadata = CachedUrlMetadata {
       now: SystemTime::now(),
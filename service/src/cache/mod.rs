@@ -23,6 +23,7 @@ mod disk_cache;
 mod emit;
 mod http_cache;
 mod incremental;
+mod module_store;
 mod node;
 mod parsed_source;
 
@@ -36,6 +37,8 @@ pub use emit::EmitCache;
 pub use http_cache::CachedUrlMetadata;
 pub use http_cache::HttpCache;
 pub use incremental::IncrementalCache;
+pub use module_store::ModuleStore;
+pub use module_store::PinMap;
 pub use node::NodeAnalysisCache;
 pub use parsed_source::ParsedSourceCache;
 
@@ -50,6 +53,7 @@ pub struct FetchCacher {
   file_header_overrides: HashMap<ModuleSpecifier, HashMap<String, String>>,
   permissions: PermissionsContainer,
   cache_info_enabled: bool,
+  defer_dynamic_imports: bool,
   maybe_local_node_modules_url: Option<ModuleSpecifier>,
 }
 
@@ -67,6 +71,7 @@ impl FetchCacher {
       file_header_overrides,
       permissions,
       cache_info_enabled: false,
+      defer_dynamic_imports: false,
       maybe_local_node_modules_url,
     }
   }
@@ -76,6 +81,13 @@ impl FetchCacher {
   pub fn enable_loading_cache_info(&mut self) {
     self.cache_info_enabled = true;
   }
+
+  /// Skips loading dynamic imports. deno_graph records them as missing
+  /// dynamic modules, which don't fail the graph, and the module loader
+  /// prepares them when they are first imported.
+  pub fn defer_dynamic_imports(&mut self) {
+    self.defer_dynamic_imports = true;
+  }
 }
 
 impl Loader for FetchCacher {
@@ -97,7 +109,11 @@ impl Loader for FetchCacher {
     }
   }
 
-  fn load(&mut self, specifier: &ModuleSpecifier, _is_dynamic: bool) -> LoadFuture {
+  fn load(&mut self, specifier: &ModuleSpecifier, is_dynamic: bool) -> LoadFuture {
+    if is_dynamic && self.defer_dynamic_imports {
+      return Box::pin(futures::future::ready(Ok(None)));
+    }
+
     if let Some(node_modules_url) = self.maybe_local_node_modules_url.as_ref() {
       // The specifier might be in a completely different symlinked tree than
       // what the resolved node_modules_url is in (ex. `/my-project-1/node_modules`
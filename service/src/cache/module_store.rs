@@ -0,0 +1,142 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+
+//! Content addressed storage for remote modules.
+//!
+//! Every product running in the gateway shares a single `DENO_DIR`, but the
+//! plain http cache keys files by url, so the same module served from two
+//! urls (or re-fetched with `--reload`) ends up on disk more than once and a
+//! refresh triggered by one product changes the code another product runs.
+//! The module store keeps each distinct source exactly once, keyed by its
+//! sha256, and a per-product pin map records which content a product saw
+//! for every url it loaded.
+
+use crate::util;
+use deno_core::error::AnyError;
+use deno_core::parking_lot::Mutex;
+use deno_core::serde_json;
+use deno_core::url::Url;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use super::CACHE_PERM;
+
+/// Storage of module sources keyed by the sha256 of their bytes.
+#[derive(Debug, Clone)]
+pub struct ModuleStore {
+  location: PathBuf,
+}
+
+impl ModuleStore {
+  pub fn new(location: PathBuf) -> Self {
+    Self { location }
+  }
+
+  /// Ex: $DENO_DIR/modules_v1/b9/b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9
+  pub fn path(&self, hash: &str) -> PathBuf {
+    self.location.join(&hash[..2]).join(hash)
+  }
+
+  pub fn contains(&self, hash: &str) -> bool {
+    hash.len() > 2 && self.path(hash).is_file()
+  }
+
+  /// Stores the content if it's not already present and returns its hash.
+  pub fn put(&self, content: &[u8]) -> Result<String, AnyError> {
+    let hash = util::checksum::gen(&[content]);
+    let path = self.path(&hash);
+    if !path.is_file() {
+      fs::create_dir_all(path.parent().unwrap())?;
+      util::fs::atomic_write_file(&path, content, CACHE_PERM)?;
+    }
+    Ok(hash)
+  }
+
+  /// Makes `target` point at the stored content. Hard links are used so the
+  /// bytes live on disk once; a copy is made when the filesystem refuses.
+  pub fn link(&self, hash: &str, target: &Path) -> io::Result<()> {
+    let source = self.path(hash);
+    if target.exists() {
+      fs::remove_file(target)?;
+    }
+    if fs::hard_link(&source, target).is_err() {
+      fs::copy(&source, target)?;
+    }
+    Ok(())
+  }
+}
+
+/// Url to content hash map of a single product, persisted as json.
+#[derive(Debug, Default)]
+pub struct PinMap {
+  path: PathBuf,
+  pins: Mutex<BTreeMap<String, String>>,
+}
+
+impl PinMap {
+  /// Loads the pin map, starting empty when the file doesn't exist yet.
+  pub fn load(path: PathBuf) -> Result<Self, AnyError> {
+    let pins = match fs::read_to_string(&path) {
+      Ok(text) => serde_json::from_str(&text)?,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+      Err(err) => return Err(err.into()),
+    };
+    Ok(Self {
+      path,
+      pins: Mutex::new(pins),
+    })
+  }
+
+  pub fn get(&self, url: &Url) -> Option<String> {
+    self.pins.lock().get(url.as_str()).cloned()
+  }
+
+  /// Pins the url to the given content, saving the map when it changed.
+  pub fn set(&self, url: &Url, hash: String) -> Result<(), AnyError> {
+    let mut pins = self.pins.lock();
+    if pins.get(url.as_str()) == Some(&hash) {
+      return Ok(());
+    }
+    pins.insert(url.to_string(), hash);
+    if let Some(parent) = self.path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    util::fs::atomic_write_file(&self.path, serde_json::to_string_pretty(&*pins)?, CACHE_PERM)?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_module_store_dedupes_content() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = ModuleStore::new(dir.path().join("modules"));
+    let a = store.put(b"export const a = 1;").unwrap();
+    let b = store.put(b"export const a = 1;").unwrap();
+    assert_eq!(a, b);
+    assert!(store.contains(&a));
+    assert_eq!(fs::read(store.path(&a)).unwrap(), b"export const a = 1;");
+
+    let target = dir.path().join("linked.js");
+    store.link(&a, &target).unwrap();
+    assert_eq!(fs::read(&target).unwrap(), b"export const a = 1;");
+  }
+
+  #[test]
+  fn test_pin_map_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("pins").join("demo.json");
+    let url = Url::parse("https://deno.land/std@0.190.0/http/server.ts").unwrap();
+    let pins = PinMap::load(path.clone()).unwrap();
+    assert_eq!(pins.get(&url), None);
+    pins.set(&url, "abc".to_string()).unwrap();
+
+    let pins = PinMap::load(path).unwrap();
+    assert_eq!(pins.get(&url), Some("abc".to_string()));
+  }
+}
@@ -13,8 +13,10 @@ use crate::cache::DenoDir;
 use crate::cache::DenoDirProvider;
 use crate::cache::EmitCache;
 use crate::cache::HttpCache;
+use crate::cache::ModuleStore;
 use crate::cache::NodeAnalysisCache;
 use crate::cache::ParsedSourceCache;
+use crate::cache::PinMap;
 use crate::emit::Emitter;
 use crate::file_fetcher::FileFetcher;
 use crate::graph_util::ModuleGraphBuilder;
@@ -230,14 +232,22 @@ impl CliFactory {
 
   pub fn file_fetcher(&self) -> Result<&Arc<FileFetcher>, AnyError> {
     self.services.file_fetcher.get_or_try_init(|| {
-      Ok(Arc::new(FileFetcher::new(
-        HttpCache::new(&self.deno_dir()?.deps_folder_path()),
+      let pins = match self.options.module_pins_path() {
+        Some(path) => Some(Arc::new(PinMap::load(path.clone())?)),
+        None => None,
+      };
+      let http_cache = HttpCache::new(&self.deno_dir()?.deps_folder_path())
+        .with_module_store(ModuleStore::new(self.deno_dir()?.module_store_folder_path()), pins);
+      let mut file_fetcher = FileFetcher::new(
+        http_cache,
         self.options.cache_setting(),
         !self.options.no_remote(),
         self.http_client().clone(),
         self.blob_store().clone(),
         Some(self.text_only_progress_bar().clone()),
-      )))
+      );
+      file_fetcher.set_remote_allowlist(self.options.net_allowlist().cloned());
+      Ok(Arc::new(file_fetcher))
     })
   }
 
@@ -360,15 +370,18 @@ impl CliFactory {
       .services
       .resolver
       .get_or_try_init_async(async {
-        Ok(Arc::new(CliGraphResolver::new(
-          self.options.to_maybe_jsx_import_source_config(),
-          self.maybe_import_map().await?.clone(),
-          self.options.no_npm(),
-          self.npm_api()?.clone(),
-          self.npm_resolution().await?.clone(),
-          self.package_json_deps_provider().clone(),
-          self.package_json_deps_installer().await?.clone(),
-        )))
+        Ok(Arc::new(
+          CliGraphResolver::new(
+            self.options.to_maybe_jsx_import_source_config(),
+            self.maybe_import_map().await?.clone(),
+            self.options.no_npm(),
+            self.npm_api()?.clone(),
+            self.npm_resolution().await?.clone(),
+            self.package_json_deps_provider().clone(),
+            self.package_json_deps_installer().await?.clone(),
+          )
+          .with_node_builtins_denied(self.options.deny_node_builtins()),
+        ))
       })
       .await
   }
@@ -621,6 +634,7 @@ impl CliFactory {
       is_inspecting: self.options.is_inspecting(),
       is_npm_main: self.options.is_npm_main(),
       location: self.options.location_flag().clone(),
+      node_globals: self.options.node_globals(),
       maybe_binary_npm_command_name: {
         let mut maybe_binary_command_name = None;
         if let DenoSubcommand::Run(flags) = self.options.sub_command() {
@@ -633,6 +647,7 @@ impl CliFactory {
         maybe_binary_command_name
       },
       origin_data_folder_path: Some(self.deno_dir()?.origin_data_folder_path()),
+      origin_storage_dir: self.options.storage_dir().cloned(),
       seed: self.options.seed(),
       unsafely_ignore_certificate_errors: self.options.unsafely_ignore_certificate_errors().clone(),
       unstable: self.options.unstable(),
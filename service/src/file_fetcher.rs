@@ -25,6 +25,7 @@ use deno_core::futures::future::FutureExt;
 use deno_core::parking_lot::Mutex;
 use deno_core::url::Url;
 use deno_core::ModuleSpecifier;
+use deno_runtime::at_rest;
 use deno_runtime::deno_fetch::reqwest::header::HeaderValue;
 use deno_runtime::deno_fetch::reqwest::header::ACCEPT;
 use deno_runtime::deno_fetch::reqwest::header::AUTHORIZATION;
@@ -89,7 +90,7 @@ fn fetch_local(specifier: &ModuleSpecifier) -> Result<File, AnyError> {
   let local = specifier
     .to_file_path()
     .map_err(|_| uri_error(format!("Invalid file path.\n  Specifier: {specifier}")))?;
-  let bytes = fs::read(&local)?;
+  let bytes = at_rest::decrypt(fs::read(&local)?)?;
   let charset = text_encoding::detect_charset(&bytes).to_string();
   let source = get_source_from_bytes(bytes, Some(charset))?;
   let media_type = MediaType::from_specifier(specifier);
@@ -154,10 +155,20 @@ pub fn map_content_type(specifier: &ModuleSpecifier, maybe_content_type: Option<
 }
 
 /// A structure for resolving, fetching and caching source files.
+fn is_host_allowlisted(allowlist: &[String], specifier: &ModuleSpecifier) -> bool {
+  let host = match specifier.host_str() {
+    Some(host) => host,
+    None => return false,
+  };
+  let host_port = specifier.port_or_known_default().map(|port| format!("{host}:{port}"));
+  allowlist.iter().any(|entry| entry == host || Some(entry) == host_port.as_ref())
+}
+
 #[derive(Debug, Clone)]
 pub struct FileFetcher {
   auth_tokens: AuthTokens,
   allow_remote: bool,
+  remote_allowlist: Option<Vec<String>>,
   cache: FileCache,
   cache_setting: CacheSetting,
   pub http_cache: HttpCache,
@@ -179,6 +190,7 @@ impl FileFetcher {
     Self {
       auth_tokens: AuthTokens::new(env::var("DENO_AUTH_TOKENS").ok()),
       allow_remote,
+      remote_allowlist: None,
       cache: Default::default(),
       cache_setting,
       http_cache,
@@ -189,6 +201,12 @@ impl FileFetcher {
     }
   }
 
+  /// Restricts remote modules to the given hosts (`host` or `host:port`).
+  /// Used by the gateway for products running in offline mode.
+  pub fn set_remote_allowlist(&mut self, allowlist: Option<Vec<String>>) {
+    self.remote_allowlist = allowlist;
+  }
+
   /// Sets the log level to use when outputting the download message.
   pub fn set_download_log_level(&mut self, level: log::Level) {
     self.download_log_level = level;
@@ -355,6 +373,17 @@ impl FileFetcher {
       return futures::future::err(err).boxed();
     }
 
+    if let Some(allowlist) = &self.remote_allowlist {
+      if !is_host_allowlisted(allowlist, specifier) {
+        deno_runtime::permissions::report_denied("import", None, Some(specifier.to_string()));
+        return futures::future::err(custom_error(
+          "NoRemote",
+          format!("A remote specifier was requested: \"{specifier}\", but its host is not in the offline allowlist."),
+        ))
+        .boxed();
+      }
+    }
+
     if self.should_use_cache(specifier) {
       match self.fetch_cached(specifier, redirect_limit) {
         Ok(Some(file)) => {
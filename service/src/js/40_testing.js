@@ -3,29 +3,35 @@
 const core = globalThis.Deno.core;
 const ops = core.ops;
 import { setExitHandler } from "ext:runtime/30_os.js";
-import { Console } from "ext:deno_console/01_console.js";
+import { Console, inspect } from "ext:deno_console/01_console.js";
 import { serializePermissions } from "ext:runtime/10_permissions.js";
-import { assert } from "ext:deno_web/00_infra.js";
+import { assert, AssertionError } from "ext:deno_web/00_infra.js";
 const primordials = globalThis.__bootstrap.primordials;
 const {
+  ArrayIsArray,
   ArrayPrototypeFilter,
-  ArrayPrototypeJoin,
   ArrayPrototypePush,
   ArrayPrototypeShift,
   DateNow,
   Error,
   FunctionPrototype,
   Map,
+  MapPrototypeDelete,
   MapPrototypeGet,
   MapPrototypeHas,
   MapPrototypeSet,
   MathCeil,
+  NumberPOSITIVE_INFINITY,
   ObjectKeys,
   ObjectHasOwn,
+  ObjectPrototypeHasOwnProperty,
   ObjectPrototypeIsPrototypeOf,
   Promise,
+  PromisePrototypeThen,
   SafeArrayIterator,
   Set,
+  StringPrototypeEndsWith,
+  StringPrototypeSlice,
   SymbolToStringTag,
   TypeError,
 } = primordials;
@@ -194,26 +200,21 @@ function assertOps(fn) {
           if (MapPrototypeHas(preTraces, id)) continue;
           ArrayPrototypePush(traces, stack);
         }
-        if (traces.length === 1) {
-          message += " The operation was started here:\n";
-          message += traces[0];
-        } else if (traces.length > 1) {
-          message += " The operations were started here:\n";
-          message += ArrayPrototypeJoin(traces, "\n\n");
-        }
-        ArrayPrototypePush(details, message);
+        ArrayPrototypePush(details, { message, traces });
       } else if (dispatchedDiff < completedDiff) {
         const [name, hint] = OP_DETAILS[key] || [key, null];
         const count = completedDiff - dispatchedDiff;
-        ArrayPrototypePush(
-          details,
-          `${count} async operation${count === 1 ? "" : "s"} to ${name} ${
+        ArrayPrototypePush(details, {
+          message: `${count} async operation${
+            count === 1 ? "" : "s"
+          } to ${name} ${
             count === 1 ? "was" : "were"
           } started before this test, but ${
             count === 1 ? "was" : "were"
           } completed during the test. Async operations should not complete in a test if they were not started in that test.
             ${hint ? `This is often caused by not ${hint}.` : ""}`,
-        );
+          traces: [],
+        });
       }
     }
     return { failed: { leakedOps: [details, core.isOpCallTracingEnabled()] } };
@@ -354,16 +355,86 @@ function resourceCloseHint(name) {
   }
 }
 
+// Stacks where the resources were created, keyed by rid. Only collected
+// with `--trace-ops`, the ops are wrapped on first use so that every rid
+// returned from an op is attributed to the code that called it. Resources
+// that existed before are recorded without a stack.
+const resourceTraces = new Map();
+let resourceTracingEnabled = false;
+
+// Same format as the stacks in `core.opCallTraces`.
+function captureStack() {
+  return StringPrototypeSlice(new Error().stack, 6);
+}
+
+function traceResources(result, stack) {
+  if (typeof result === "number") {
+    const rid = String(result);
+    if (
+      !MapPrototypeHas(resourceTraces, rid) &&
+      ObjectHasOwn(core.resources(), rid)
+    ) {
+      MapPrototypeSet(resourceTraces, rid, stack);
+    }
+  } else if (ArrayIsArray(result)) {
+    for (let i = 0; i < result.length; ++i) {
+      if (typeof result[i] === "number") traceResources(result[i], stack);
+    }
+  } else if (result !== null && typeof result === "object") {
+    for (const key in result) {
+      if (
+        ObjectPrototypeHasOwnProperty(result, key) &&
+        (StringPrototypeEndsWith(key, "rid") ||
+          StringPrototypeEndsWith(key, "Rid"))
+      ) {
+        traceResources(result[key], stack);
+      }
+    }
+  }
+}
+
+function enableResourceTracing() {
+  if (resourceTracingEnabled || !core.isOpCallTracingEnabled()) return;
+  resourceTracingEnabled = true;
+  for (const rid of new SafeArrayIterator(ObjectKeys(core.resources()))) {
+    MapPrototypeSet(resourceTraces, rid, null);
+  }
+  for (const name of new SafeArrayIterator(ObjectKeys(ops))) {
+    if (name === "op_resources") continue;
+    const op = ops[name];
+    ops[name] = function (...args) {
+      const result = op(...new SafeArrayIterator(args));
+      if (result !== undefined) traceResources(result, captureStack());
+      return result;
+    };
+  }
+  const opAsync = core.opAsync;
+  core.opAsync = function (...args) {
+    const stack = captureStack();
+    const promise = opAsync(...new SafeArrayIterator(args));
+    PromisePrototypeThen(
+      promise,
+      (result) => traceResources(result, stack),
+      () => {},
+    );
+    return promise;
+  };
+}
+
 // Wrap test function in additional assertion that makes sure
 // the test case does not "leak" resources - ie. resource table after
 // the test has exactly the same contents as before the test.
 function assertResources(fn) {
   /** @param desc {TestDescription | TestStepDescription} */
   return async function resourceSanitizer(desc) {
+    enableResourceTracing();
     const pre = core.resources();
     const innerResult = await fn(desc);
     if (innerResult) return innerResult;
     const post = core.resources();
+    for (const { 0: rid } of resourceTraces) {
+      if (!ObjectHasOwn(post, rid)) MapPrototypeDelete(resourceTraces, rid);
+    }
 
     const allResources = new Set([
       ...new SafeArrayIterator(ObjectKeys(pre)),
@@ -379,20 +450,25 @@ function assertResources(fn) {
       if (preResource === undefined) {
         const [name, action1, action2] = prettyResourceNames(postResource);
         const hint = resourceCloseHint(postResource);
-        const detail =
+        const message =
           `${name} (rid ${resource}) was ${action1} during the test, but not ${action2} during the test. ${hint}`;
-        ArrayPrototypePush(details, detail);
+        const stack = MapPrototypeGet(resourceTraces, resource);
+        ArrayPrototypePush(details, { message, traces: stack ? [stack] : [] });
       } else {
         const [name, action1, action2] = prettyResourceNames(preResource);
-        const detail =
+        const message =
           `${name} (rid ${resource}) was ${action1} before the test started, but was ${action2} during the test. Do not close resources in a test that were not created during that test.`;
-        ArrayPrototypePush(details, detail);
+        ArrayPrototypePush(details, { message, traces: [] });
       }
     }
     if (details.length == 0) {
       return null;
     }
-    return { failed: { leakedResources: details } };
+    return {
+      failed: {
+        leakedResources: [details, core.isOpCallTracingEnabled()],
+      },
+    };
   };
 }
 
@@ -1088,6 +1164,26 @@ function createTestContext(desc) {
       stepReportResult(stepDesc, result, elapsed);
       return result == "ok";
     },
+    /**
+     * Compares the value with the snapshot stored in `__snapshots__`, or
+     * stores it when the tests run with `--update-snapshots`.
+     * @param value {unknown}
+     */
+    assertSnapshot(value) {
+      const actual = typeof value === "string" ? value : inspect(value, {
+        depth: NumberPOSITIVE_INFINITY,
+        sorted: true,
+        trailingComma: true,
+        compact: false,
+        iterableLimit: NumberPOSITIVE_INFINITY,
+        strAbbreviateSize: NumberPOSITIVE_INFINITY,
+        colors: false,
+      });
+      const message = ops.op_test_snapshot(getFullName(desc), actual);
+      if (message !== null) {
+        throw new AssertionError(message);
+      }
+    },
   };
 }
 
@@ -265,6 +265,10 @@ impl TestRun {
               filter,
               shuffle: None,
               trace_ops: false,
+              fetch_mocks: None,
+              fetch_cassettes: None,
+              update_snapshots: false,
+              isolate_per_test: false,
             },
           ))
         };
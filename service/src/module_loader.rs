@@ -44,6 +44,7 @@ use deno_graph::source::Resolver;
 use deno_graph::EsmModule;
 use deno_graph::JsonModule;
 use deno_graph::Module;
+use deno_graph::ModuleGraph;
 use deno_graph::Resolution;
 use deno_lockfile::Lockfile;
 use deno_runtime::deno_fs;
@@ -53,6 +54,7 @@ use deno_runtime::deno_node::NodeResolver;
 use deno_runtime::permissions::PermissionsContainer;
 use deno_semver::npm::NpmPackageNvReference;
 use deno_semver::npm::NpmPackageReqReference;
+use once_cell::sync::Lazy;
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::HashSet;
@@ -60,6 +62,29 @@ use std::pin::Pin;
 use std::rc::Rc;
 use std::str;
 use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+/// A dynamic import that was left out of the startup graph and prepared the
+/// first time it ran, see `Flags::lazy_dynamic_imports`.
+#[derive(Debug, Clone)]
+pub struct LazyLoad {
+  pub product_code: Option<String>,
+  pub specifier: ModuleSpecifier,
+  /// Modules added to the graph: the import and its static dependencies.
+  pub modules: usize,
+  pub elapsed: Duration,
+}
+
+pub type LazyLoadHook = Arc<dyn Fn(&LazyLoad) + Send + Sync>;
+
+static LAZY_LOAD_HOOK: Lazy<Mutex<Option<LazyLoadHook>>> = Lazy::new(|| Mutex::new(None));
+
+/// Registers the callback told about every lazily loaded import. The
+/// gateway uses it for metrics.
+pub fn set_lazy_load_hook(hook: LazyLoadHook) {
+  *LAZY_LOAD_HOOK.lock() = Some(hook);
+}
 
 pub struct ModuleLoadPreparer {
   options: Arc<CliOptions>,
@@ -114,7 +139,11 @@ impl ModuleLoadPreparer {
     log::debug!("Preparing module load.");
     let _pb_clear_guard = self.progress_bar.clear_guard();
 
+    let lazy = self.options.lazy_dynamic_imports();
     let mut cache = self.module_graph_builder.create_fetch_cacher(permissions);
+    if lazy {
+      cache.defer_dynamic_imports();
+    }
     let maybe_imports = self.options.to_maybe_imports()?;
     let graph_resolver = self.resolver.as_graph_resolver();
     let graph_npm_resolver = self.resolver.as_graph_npm_resolver();
@@ -134,14 +163,40 @@ impl ModuleLoadPreparer {
     // should be skipped.
     let reload_exclusions: HashSet<ModuleSpecifier> = graph.specifiers().map(|(s, _)| s.clone()).collect();
 
+    // A deferred import sits in the graph as a missing module and deno_graph
+    // never loads a specifier twice, so the graph is rebuilt from all of its
+    // roots with the import as a new root.
+    let deferred: Vec<&ModuleSpecifier> = match lazy && is_dynamic {
+      true => roots
+        .iter()
+        .filter(|r| reload_exclusions.contains(*r) && graph.get(r).is_none())
+        .collect(),
+      false => vec![],
+    };
+    let started = Instant::now();
+    let modules_before = graph.modules().count();
+    let mut build_roots = roots.clone();
+    if !deferred.is_empty() {
+      build_roots = graph
+        .roots
+        .iter()
+        .filter(|r| !roots.contains(r))
+        .cloned()
+        .chain(roots.iter().cloned())
+        .collect();
+      *graph = ModuleGraph::default();
+    }
+
+    // In lazy mode everything below a dynamic root would be reported to the
+    // loader as dynamic and skipped, so the roots are built as static ones.
     self
       .module_graph_builder
       .build_graph_with_npm_resolution(
         graph,
-        roots.clone(),
+        build_roots,
         &mut cache,
         deno_graph::BuildOptions {
-          is_dynamic,
+          is_dynamic: is_dynamic && !lazy,
           imports: maybe_imports,
           resolver: Some(graph_resolver),
           npm_resolver: Some(graph_npm_resolver),
@@ -153,6 +208,21 @@ impl ModuleLoadPreparer {
 
     graph_valid_with_cli_options(graph, &roots, &self.options)?;
 
+    if !deferred.is_empty() {
+      let hook = LAZY_LOAD_HOOK.lock().clone();
+      if let Some(hook) = hook {
+        let modules = graph.modules().count().saturating_sub(modules_before);
+        for specifier in deferred {
+          hook(&LazyLoad {
+            product_code: self.options.product_code().cloned(),
+            specifier: specifier.clone(),
+            modules,
+            elapsed: started.elapsed(),
+          });
+        }
+      }
+    }
+
     // If there is a lockfile...
     if let Some(lockfile) = &self.lockfile {
       let mut lockfile = lockfile.lock();
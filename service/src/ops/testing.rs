@@ -1,5 +1,6 @@
 // Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
 
+use crate::tools::snapshot::SnapshotStore;
 use crate::tools::test::TestDescription;
 use crate::tools::test::TestEvent;
 use crate::tools::test::TestEventSender;
@@ -13,12 +14,16 @@ use deno_core::serde_v8;
 use deno_core::v8;
 use deno_core::ModuleSpecifier;
 use deno_core::OpState;
+use deno_runtime::deno_fetch::Cassette;
+use deno_runtime::deno_fetch::FetchMocks;
 use deno_runtime::permissions::create_child_permissions;
 use deno_runtime::permissions::ChildPermissionsArg;
 use deno_runtime::permissions::PermissionsContainer;
 use serde::Deserialize;
 use serde::Deserializer;
 use serde::Serialize;
+use std::collections::VecDeque;
+use std::rc::Rc;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use uuid::Uuid;
@@ -26,6 +31,12 @@ use uuid::Uuid;
 #[derive(Default)]
 pub(crate) struct TestContainer(pub Vec<(TestDescription, v8::Global<v8::Function>)>);
 
+/// Ids of the tests registered by an earlier worker of the same module. A
+/// worker that runs a single test in isolation registers the tests again in
+/// the same order, and reuses these ids instead of reporting new tests.
+#[derive(Default)]
+pub(crate) struct RegisteredIds(pub VecDeque<usize>);
+
 deno_core::extension!(deno_test,
   ops = [
     op_pledge_test_permissions,
@@ -33,13 +44,30 @@ deno_core::extension!(deno_test,
     op_register_test,
     op_register_test_step,
     op_dispatch_test_event,
+    op_test_snapshot,
   ],
   options = {
     sender: TestEventSender,
+    fetch_mocks: Option<FetchMocks>,
+    cassette: Option<Rc<Cassette>>,
+    snapshots: Option<Rc<SnapshotStore>>,
+    registered_ids: Option<Vec<usize>>,
   },
   state = |state, options| {
     state.put(options.sender);
     state.put(TestContainer::default());
+    if let Some(fetch_mocks) = options.fetch_mocks {
+      state.put(fetch_mocks);
+    }
+    if let Some(cassette) = options.cassette {
+      state.put(cassette);
+    }
+    if let Some(snapshots) = options.snapshots {
+      state.put(snapshots);
+    }
+    if let Some(registered_ids) = options.registered_ids {
+      state.put(RegisteredIds(registered_ids.into()));
+    }
   },
   customizer = |ext: &mut deno_core::ExtensionBuilder| {
     ext.force_op_registration();
@@ -110,7 +138,8 @@ static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
 
 #[op(v8)]
 fn op_register_test<'a>(scope: &mut v8::HandleScope<'a>, state: &mut OpState, info: TestInfo<'a>) -> Result<TestRegisterResult, AnyError> {
-  let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+  let registered_id = state.try_borrow_mut::<RegisteredIds>().and_then(|ids| ids.0.pop_front());
+  let id = registered_id.unwrap_or_else(|| NEXT_ID.fetch_add(1, Ordering::SeqCst));
   let origin = state.borrow::<ModuleSpecifier>().to_string();
   let description = TestDescription {
     id,
@@ -123,8 +152,10 @@ fn op_register_test<'a>(scope: &mut v8::HandleScope<'a>, state: &mut OpState, in
   let function: v8::Local<v8::Function> = info.function.v8_value.try_into()?;
   let function = v8::Global::new(scope, function);
   state.borrow_mut::<TestContainer>().0.push((description.clone(), function));
-  let mut sender = state.borrow::<TestEventSender>().clone();
-  sender.send(TestEvent::Register(description)).ok();
+  if registered_id.is_none() {
+    let mut sender = state.borrow::<TestEventSender>().clone();
+    sender.send(TestEvent::Register(description)).ok();
+  }
   Ok(TestRegisterResult { id, origin })
 }
 
@@ -181,3 +212,12 @@ fn op_dispatch_test_event(state: &mut OpState, event: TestEvent) -> Result<(), A
   sender.send(event).ok();
   Ok(())
 }
+
+/// Returns the failure message of a snapshot assertion, `None` when it passes.
+#[op]
+fn op_test_snapshot(state: &mut OpState, test_name: String, actual: String) -> Result<Option<String>, AnyError> {
+  match state.try_borrow::<Rc<SnapshotStore>>() {
+    Some(snapshots) => snapshots.assert(&test_name, actual),
+    None => Err(generic_error("Snapshot assertions are only supported in local test modules")),
+  }
+}
@@ -15,7 +15,10 @@ use deno_graph::source::DEFAULT_JSX_IMPORT_SOURCE_MODULE;
 use deno_npm::registry::NpmRegistryApi;
 use deno_runtime::deno_node::is_builtin_node_module;
 use deno_semver::npm::NpmPackageReq;
+use deno_semver::Version;
 use import_map::ImportMap;
+use std::path::Path;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use crate::args::package_json::PackageJsonDeps;
@@ -97,6 +100,7 @@ pub struct CliGraphResolver {
   package_json_deps_installer: Arc<PackageJsonDepsInstaller>,
   found_package_json_dep_flag: Arc<AtomicFlag>,
   sync_download_queue: Option<Arc<TaskQueue>>,
+  deny_node_builtins: bool,
 }
 
 impl Default for CliGraphResolver {
@@ -118,6 +122,7 @@ impl Default for CliGraphResolver {
       package_json_deps_installer: Default::default(),
       found_package_json_dep_flag: Default::default(),
       sync_download_queue: Self::create_sync_download_queue(),
+      deny_node_builtins: false,
     }
   }
 }
@@ -145,6 +150,30 @@ impl CliGraphResolver {
       package_json_deps_installer,
       found_package_json_dep_flag: Default::default(),
       sync_download_queue: Self::create_sync_download_queue(),
+      deny_node_builtins: false,
+    }
+  }
+
+  /// Makes `node:` imports fail to resolve, see `Flags::deny_node_builtins`.
+  pub fn with_node_builtins_denied(mut self, deny: bool) -> Self {
+    self.deny_node_builtins = deny;
+    self
+  }
+
+  fn resolve_mapped(&self, specifier: &str, referrer: &ModuleSpecifier) -> Result<ModuleSpecifier, AnyError> {
+    use MappedResolution::*;
+    match self.mapped_specifier_resolver.resolve(specifier, referrer)? {
+      ImportMap(specifier) => Ok(specifier),
+      PackageJson(specifier) => {
+        // found a specifier in the package.json, so mark that
+        // we need to do an "npm install" later
+        self.found_package_json_dep_flag.raise();
+        Ok(specifier)
+      }
+      None => match specifier.strip_prefix(PLATFORM_SCHEME) {
+        Some(rest) => resolve_platform_specifier(rest, &shared_modules_dir()),
+        None => deno_graph::resolve_import(specifier, referrer).map_err(|err| err.into()),
+      },
     }
   }
 
@@ -186,20 +215,80 @@ impl Resolver for CliGraphResolver {
   }
 
   fn resolve(&self, specifier: &str, referrer: &ModuleSpecifier) -> Result<ModuleSpecifier, AnyError> {
-    use MappedResolution::*;
-    match self.mapped_specifier_resolver.resolve(specifier, referrer)? {
-      ImportMap(specifier) => Ok(specifier),
-      PackageJson(specifier) => {
-        // found a specifier in the package.json, so mark that
-        // we need to do an "npm install" later
-        self.found_package_json_dep_flag.raise();
-        Ok(specifier)
-      }
-      None => deno_graph::resolve_import(specifier, referrer).map_err(|err| err.into()),
+    let resolved = self.resolve_mapped(specifier, referrer)?;
+    // checked after mapping so an import map can not alias a builtin either
+    if self.deny_node_builtins && resolved.scheme() == "node" {
+      return Err(anyhow!(
+        "Importing \"{}\" is not allowed, Node.js compatibility is disabled for this product",
+        resolved
+      ));
     }
+    Ok(resolved)
   }
 }
 
+/// Prefix of specifiers that point into the platform managed shared modules
+/// area, e.g. `platform:utils@1.2.0/log.ts` or `platform:utils` (latest).
+pub const PLATFORM_SCHEME: &str = "platform:";
+
+/// Entry module used when a `platform:` specifier has no path.
+const PLATFORM_DEFAULT_ENTRY: &str = "mod.ts";
+
+/// Root of the shared modules area, laid out as `{root}/{name}/{version}/...`.
+/// Defaults to `shared` under the current directory and can be overridden
+/// with the `DENO_COOL_SHARED_DIR` environment variable.
+pub fn shared_modules_dir() -> PathBuf {
+  match std::env::var_os("DENO_COOL_SHARED_DIR") {
+    Some(dir) => PathBuf::from(dir),
+    None => std::env::current_dir().unwrap_or_default().join("shared"),
+  }
+}
+
+/// Returns the highest semver version published for a shared module.
+pub fn latest_shared_version(root: &Path, name: &str) -> Option<String> {
+  std::fs::read_dir(root.join(name))
+    .ok()?
+    .filter_map(|entry| entry.ok())
+    .filter(|entry| entry.path().is_dir())
+    .filter_map(|entry| {
+      let dir_name = entry.file_name().to_string_lossy().to_string();
+      Version::parse_from_npm(&dir_name).ok().map(|version| (version, dir_name))
+    })
+    .max_by(|a, b| a.0.cmp(&b.0))
+    .map(|(_, dir_name)| dir_name)
+}
+
+/// Splits `name[@version][/path]` into its parts.
+fn parse_platform_specifier(value: &str) -> Option<(&str, Option<&str>, &str)> {
+  let (package, path) = match value.find('/') {
+    Some(index) => (&value[..index], &value[index + 1..]),
+    None => (value, ""),
+  };
+  let (name, version) = match package.split_once('@') {
+    Some((name, version)) => (name, Some(version)),
+    None => (package, None),
+  };
+  let valid = |s: &str| !s.is_empty() && s != "." && s != "..";
+  if !valid(name) || version.map(|v| !valid(v)).unwrap_or(false) {
+    return None;
+  }
+  if path.split('/').any(|segment| segment == "..") {
+    return None;
+  }
+  Some((name, version, path))
+}
+
+fn resolve_platform_specifier(value: &str, root: &Path) -> Result<ModuleSpecifier, AnyError> {
+  let (name, version, path) = parse_platform_specifier(value).ok_or_else(|| anyhow!("Invalid platform specifier: {}{}", PLATFORM_SCHEME, value))?;
+  let version = match version {
+    Some(version) => version.to_string(),
+    None => latest_shared_version(root, name).ok_or_else(|| anyhow!("Shared module not found: {}", name))?,
+  };
+  let path = if path.is_empty() { PLATFORM_DEFAULT_ENTRY } else { path };
+  let file = root.join(name).join(version).join(path);
+  ModuleSpecifier::from_file_path(&file).map_err(|_| anyhow!("Invalid shared module path: {}", file.display()))
+}
+
 fn resolve_package_json_dep(specifier: &str, deps: &PackageJsonDeps) -> Result<Option<ModuleSpecifier>, AnyError> {
   for (bare_specifier, req_result) in deps {
     if specifier.starts_with(bare_specifier) {
@@ -320,4 +409,37 @@ mod test {
     // non-existent bare specifier
     assert_eq!(resolve("non-existent", &deps).unwrap(), None);
   }
+
+  #[test]
+  fn test_parse_platform_specifier() {
+    assert_eq!(parse_platform_specifier("utils"), Some(("utils", None, "")));
+    assert_eq!(parse_platform_specifier("utils@1.2.0"), Some(("utils", Some("1.2.0"), "")));
+    assert_eq!(
+      parse_platform_specifier("utils@1.2.0/log/mod.ts"),
+      Some(("utils", Some("1.2.0"), "log/mod.ts"))
+    );
+    assert_eq!(parse_platform_specifier("utils/log.ts"), Some(("utils", None, "log.ts")));
+    assert_eq!(parse_platform_specifier(""), None);
+    assert_eq!(parse_platform_specifier("utils@"), None);
+    assert_eq!(parse_platform_specifier("../utils"), None);
+    assert_eq!(parse_platform_specifier("utils@1.0.0/../../secret.ts"), None);
+  }
+
+  #[test]
+  fn test_resolve_platform_specifier() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    for version in ["1.0.0", "1.10.0", "1.2.0", "not-a-version"] {
+      std::fs::create_dir_all(root.join("utils").join(version)).unwrap();
+    }
+
+    assert_eq!(latest_shared_version(root, "utils"), Some("1.10.0".to_string()));
+    assert_eq!(latest_shared_version(root, "missing"), None);
+
+    let resolved = resolve_platform_specifier("utils", root).unwrap();
+    assert_eq!(resolved.to_file_path().unwrap(), root.join("utils").join("1.10.0").join("mod.ts"));
+    let resolved = resolve_platform_specifier("utils@1.0.0/log.ts", root).unwrap();
+    assert_eq!(resolved.to_file_path().unwrap(), root.join("utils").join("1.0.0").join("log.ts"));
+    assert!(resolve_platform_specifier("missing/log.ts", root).is_err());
+  }
 }
@@ -362,10 +362,12 @@ pub async fn run(eszip: eszip::EszipV2, metadata: Metadata) -> Result<(), AnyErr
       is_inspecting: false,
       is_npm_main: main_module.scheme() == "npm",
       location: metadata.location,
+      node_globals: false,
       maybe_binary_npm_command_name: NpmPackageReqReference::from_specifier(main_module)
         .ok()
         .map(|req_ref| npm_pkg_req_ref_to_binary_command(&req_ref)),
       origin_data_folder_path: None,
+      origin_storage_dir: None,
       seed: metadata.seed,
       unsafely_ignore_certificate_errors: metadata.unsafely_ignore_certificate_errors,
       unstable: metadata.unstable,
@@ -83,6 +83,7 @@ pub enum BenchResult {
 pub struct BenchReport {
   pub total: usize,
   pub failed: usize,
+  pub used_only: bool,
   pub failures: Vec<(BenchDescription, Box<JsError>)>,
   pub measurements: Vec<(BenchDescription, BenchStats)>,
 }
@@ -115,10 +116,22 @@ impl BenchReport {
     Self {
       total: 0,
       failed: 0,
+      used_only: false,
       failures: Vec::new(),
       measurements: Vec::new(),
     }
   }
+
+  /// Fails the run when a benchmark failed or the "only" option was used.
+  pub fn check(&self) -> Result<(), AnyError> {
+    if self.used_only {
+      return Err(generic_error("Bench failed because the \"only\" option was used"));
+    }
+    if self.failed > 0 {
+      return Err(generic_error("Bench failed"));
+    }
+    Ok(())
+  }
 }
 
 fn create_reporter(show_output: bool, json: bool) -> Box<dyn BenchReporter + Send> {
@@ -489,7 +502,7 @@ async fn bench_specifiers(
   permissions: &Permissions,
   specifiers: Vec<ModuleSpecifier>,
   options: BenchSpecifierOptions,
-) -> Result<(), AnyError> {
+) -> Result<BenchReport, AnyError> {
   let (sender, mut receiver) = unbounded_channel::<BenchEvent>();
   let log_level = options.log_level;
   let option_for_handles = options.clone();
@@ -512,7 +525,6 @@ async fn bench_specifiers(
 
   let handler = {
     spawn(async move {
-      let mut used_only = false;
       let mut report = BenchReport::new();
       let mut reporter = create_reporter(log_level != Some(Level::Error), options.json);
       let mut benches = IndexMap::new();
@@ -522,7 +534,7 @@ async fn bench_specifiers(
           BenchEvent::Plan(plan) => {
             report.total += plan.total;
             if plan.used_only {
-              used_only = true;
+              report.used_only = true;
             }
 
             reporter.report_plan(&plan);
@@ -559,29 +571,18 @@ async fn bench_specifiers(
       }
 
       reporter.report_end(&report);
-
-      if used_only {
-        return Err(generic_error("Bench failed because the \"only\" option was used"));
-      }
-
-      if report.failed > 0 {
-        return Err(generic_error("Bench failed"));
-      }
-
-      Ok(())
+      report
     })
   };
 
-  let (join_results, result) = future::join(join_stream, handler).await;
+  let (join_results, report) = future::join(join_stream, handler).await;
 
   // propagate any errors
   for join_result in join_results {
     join_result??;
   }
 
-  result??;
-
-  Ok(())
+  Ok(report?)
 }
 
 /// Checks if the path has a basename and extension Deno supports for benches.
@@ -595,6 +596,13 @@ fn is_supported_bench_path(path: &Path) -> bool {
 }
 
 pub async fn run_benchmarks(cli_options: CliOptions, bench_options: BenchOptions) -> Result<(), AnyError> {
+  collect_benchmarks(cli_options, bench_options).await?.check()
+}
+
+/// Runs the benchmarks and returns the measurements and failures, without
+/// failing on failed benchmarks. Used by the platform to compare the results
+/// of a product with the ones of another version.
+pub async fn collect_benchmarks(cli_options: CliOptions, bench_options: BenchOptions) -> Result<BenchReport, AnyError> {
   let factory = CliFactory::from_cli_options(Arc::new(cli_options));
   let cli_options = factory.cli_options();
   // Various bench files should not share the same permissions in terms of
@@ -611,7 +619,7 @@ pub async fn run_benchmarks(cli_options: CliOptions, bench_options: BenchOptions
   check_specifiers(cli_options, factory.module_load_preparer().await?, specifiers.clone()).await?;
 
   if bench_options.no_run {
-    return Ok(());
+    return Ok(BenchReport::new());
   }
 
   let log_level = cli_options.log_level();
@@ -626,8 +634,7 @@ pub async fn run_benchmarks(cli_options: CliOptions, bench_options: BenchOptions
       log_level,
     },
   )
-  .await?;
-  Ok(())
+  .await
 }
 
 // TODO(bartlomieju): heavy duplication of code with `cli/tools/test.rs`
@@ -759,9 +766,8 @@ pub async fn run_benchmarks_with_watch(cli_options: CliOptions, bench_options: B
           log_level,
         },
       )
-      .await?;
-
-      Ok(())
+      .await?
+      .check()
     }
   };
 
@@ -1,5 +1,6 @@
 // Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
 
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::sync::Arc;
 
@@ -7,6 +8,8 @@ use deno_core::error::AnyError;
 use deno_core::futures::FutureExt;
 use deno_graph::Module;
 use deno_runtime::colors;
+use serde::Deserialize;
+use serde::Serialize;
 
 use crate::args::BundleFlags;
 use crate::args::CliOptions;
@@ -19,6 +22,8 @@ use crate::util;
 use crate::util::display;
 use crate::util::file_watcher::ResolutionResult;
 
+use super::deps::parse_remote_package;
+
 pub async fn bundle(flags: Flags, bundle_flags: BundleFlags) -> Result<(), AnyError> {
   let cli_options = Arc::new(CliOptions::from_flags(flags)?);
 
@@ -159,3 +164,101 @@ fn bundle_module_graph(graph: &deno_graph::ModuleGraph, cli_options: &CliOptions
     },
   )
 }
+
+/// Size of a single module in the graph.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModuleSize {
+  pub specifier: String,
+  /// The package (or "local") the module belongs to.
+  pub group: String,
+  pub bytes: u64,
+}
+
+/// Sizes aggregated per package.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupSize {
+  pub group: String,
+  pub bytes: u64,
+  pub modules: usize,
+}
+
+/// Breakdown of a bundle, similar to what bundle analyzers report.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleAnalysis {
+  /// Size of the emitted bundle. `None` when the graph can't be bundled
+  /// (npm specifiers are not supported by deno bundle).
+  pub bundle_bytes: Option<u64>,
+  /// Sum of all source modules and npm packages.
+  pub source_bytes: u64,
+  pub modules: Vec<ModuleSize>,
+  pub groups: Vec<GroupSize>,
+}
+
+/// Bundles the main module and reports bytes per module and per package.
+pub async fn analyze_bundle(flags: Flags) -> Result<BundleAnalysis, AnyError> {
+  let factory = CliFactory::from_flags(flags).await?;
+  let cli_options = factory.cli_options();
+  let main_module = cli_options.resolve_main_module()?;
+  let module_graph_builder = factory.module_graph_builder().await?;
+  let graph = module_graph_builder.create_graph(vec![main_module]).await?;
+  let npm_resolver = factory.npm_resolver().await?;
+
+  let mut modules = Vec::new();
+  for module in graph.modules() {
+    let bytes = match module {
+      Module::Esm(m) => m.source.len() as u64,
+      Module::Json(m) => m.source.len() as u64,
+      Module::Node(_) | Module::Npm(_) | Module::External(_) => continue,
+    };
+    let specifier = module.specifier();
+    let group = match parse_remote_package(specifier) {
+      Some((registry, name, version)) => format!("{}:{}@{}", registry, name, version.unwrap_or_default()),
+      None => "local".to_string(),
+    };
+    modules.push(ModuleSize {
+      specifier: specifier.to_string(),
+      group,
+      bytes,
+    });
+  }
+  let snapshot = npm_resolver.snapshot();
+  for package in snapshot.all_packages_for_every_system() {
+    if let Ok(bytes) = npm_resolver.package_size(&package.id) {
+      modules.push(ModuleSize {
+        specifier: format!("npm:{}@{}", package.id.nv.name, package.id.nv.version),
+        group: format!("npm:{}@{}", package.id.nv.name, package.id.nv.version),
+        bytes,
+      });
+    }
+  }
+  modules.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+
+  let mut groups: BTreeMap<String, GroupSize> = BTreeMap::new();
+  for module in &modules {
+    let group = groups.entry(module.group.clone()).or_insert_with(|| GroupSize {
+      group: module.group.clone(),
+      bytes: 0,
+      modules: 0,
+    });
+    group.bytes += module.bytes;
+    group.modules += 1;
+  }
+  let mut groups: Vec<GroupSize> = groups.into_values().collect();
+  groups.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+
+  let bundle_bytes = if graph.npm_packages.is_empty() {
+    Some(bundle_module_graph(&graph, cli_options)?.code.len() as u64)
+  } else {
+    None
+  };
+
+  Ok(BundleAnalysis {
+    bundle_bytes,
+    source_bytes: modules.iter().map(|m| m.bytes).sum(),
+    modules,
+    groups,
+  })
+}
@@ -22,6 +22,8 @@ use deno_core::url::Url;
 use deno_core::LocalInspectorSession;
 use deno_core::ModuleCode;
 use regex::Regex;
+use serde::Deserialize;
+use serde::Serialize;
 use std::fs;
 use std::fs::File;
 use std::io::BufWriter;
@@ -514,12 +516,13 @@ fn filter_coverages(coverages: Vec<ScriptCoverage>, include: Vec<String>, exclud
     .collect::<Vec<ScriptCoverage>>()
 }
 
-pub async fn cover_files(flags: Flags, coverage_flags: CoverageFlags) -> Result<(), AnyError> {
-  if coverage_flags.files.include.is_empty() {
-    return Err(generic_error("No matching coverage profiles found"));
-  }
-
-  let factory = CliFactory::from_flags(flags).await?;
+/// Builds the coverage report of every file in the profiles, next to the
+/// original source of the file.
+async fn collect_reports(
+  factory: &CliFactory,
+  coverage_flags: CoverageFlags,
+  output: &Option<PathBuf>,
+) -> Result<Vec<(CoverageReport, String)>, AnyError> {
   let root_dir_url = factory.npm_resolver().await?.root_dir_url();
   let file_fetcher = factory.file_fetcher()?;
   let cli_options = factory.cli_options();
@@ -536,24 +539,7 @@ pub async fn cover_files(flags: Flags, coverage_flags: CoverageFlags) -> Result<
     vec![]
   };
 
-  let reporter_kind = if coverage_flags.lcov {
-    CoverageReporterKind::Lcov
-  } else {
-    CoverageReporterKind::Pretty
-  };
-
-  let mut reporter = create_reporter(reporter_kind);
-
-  let out_mode = match coverage_flags.output {
-    Some(ref path) => match File::create(path) {
-      Ok(_) => Some(PathBuf::from(path)),
-      Err(e) => {
-        return Err(anyhow!("Failed to create output file: {}", e));
-      }
-    },
-    None => None,
-  };
-
+  let mut reports = Vec::with_capacity(script_coverages.len());
   for script_coverage in script_coverages {
     let module_specifier = deno_core::resolve_url_or_path(&script_coverage.url, cli_options.initial_cwd())?;
 
@@ -595,14 +581,200 @@ pub async fn cover_files(flags: Flags, coverage_flags: CoverageFlags) -> Result<
     };
 
     let source_map = source_map_from_code(&transpiled_code);
-    let coverage_report = generate_coverage_report(&script_coverage, transpiled_code.as_str().to_owned(), &source_map, &out_mode);
+    let coverage_report = generate_coverage_report(&script_coverage, transpiled_code.as_str().to_owned(), &source_map, output);
 
     if !coverage_report.found_lines.is_empty() {
-      reporter.report(&coverage_report, &original_source)?;
+      reports.push((coverage_report, original_source.to_string()));
     }
   }
 
+  Ok(reports)
+}
+
+pub async fn cover_files(flags: Flags, coverage_flags: CoverageFlags) -> Result<(), AnyError> {
+  if coverage_flags.files.include.is_empty() {
+    return Err(generic_error("No matching coverage profiles found"));
+  }
+
+  let factory = CliFactory::from_flags(flags).await?;
+
+  let reporter_kind = if coverage_flags.lcov {
+    CoverageReporterKind::Lcov
+  } else {
+    CoverageReporterKind::Pretty
+  };
+
+  let mut reporter = create_reporter(reporter_kind);
+
+  let out_mode = match coverage_flags.output {
+    Some(ref path) => match File::create(path) {
+      Ok(_) => Some(PathBuf::from(path)),
+      Err(e) => {
+        return Err(anyhow!("Failed to create output file: {}", e));
+      }
+    },
+    None => None,
+  };
+
+  for (coverage_report, original_source) in collect_reports(&factory, coverage_flags, &out_mode).await? {
+    reporter.report(&coverage_report, &original_source)?;
+  }
+
   reporter.done();
 
   Ok(())
 }
+
+/// Found and hit lines and branches, as in the `LF`/`LH` and `BRF`/`BRH`
+/// records of lcov.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoverageCounts {
+  pub lines_found: usize,
+  pub lines_hit: usize,
+  pub branches_found: usize,
+  pub branches_hit: usize,
+}
+
+fn percent(hit: usize, found: usize) -> f64 {
+  match found {
+    0 => 100.0,
+    found => hit as f64 * 100.0 / found as f64,
+  }
+}
+
+impl CoverageCounts {
+  fn from_report(report: &CoverageReport) -> Self {
+    Self {
+      lines_found: report.found_lines.len(),
+      lines_hit: report.found_lines.iter().filter(|(_, count)| *count != 0).count(),
+      branches_found: report.branches.len(),
+      branches_hit: report.branches.iter().filter(|b| b.is_hit).count(),
+    }
+  }
+
+  fn add(&mut self, other: &CoverageCounts) {
+    self.lines_found += other.lines_found;
+    self.lines_hit += other.lines_hit;
+    self.branches_found += other.branches_found;
+    self.branches_hit += other.branches_hit;
+  }
+
+  /// 100 when there are no lines.
+  pub fn line_percent(&self) -> f64 {
+    percent(self.lines_hit, self.lines_found)
+  }
+
+  /// 100 when there are no branches.
+  pub fn branch_percent(&self) -> f64 {
+    percent(self.branches_hit, self.branches_found)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileCoverage {
+  pub url: String,
+  #[serde(flatten)]
+  pub counts: CoverageCounts,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoverageSummary {
+  pub files: Vec<FileCoverage>,
+  pub total: CoverageCounts,
+}
+
+/// Minimum coverage in percent. The thresholds apply to the total, and to
+/// every file as well when `per_file` is set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CoverageThreshold {
+  pub lines: Option<f64>,
+  pub branches: Option<f64>,
+  pub per_file: bool,
+}
+
+impl CoverageThreshold {
+  fn violations(&self, name: &str, counts: &CoverageCounts) -> Vec<String> {
+    let mut violations = vec![];
+    if let Some(min) = self.lines.filter(|min| counts.line_percent() < *min) {
+      violations.push(format!("{name}: line coverage {:.2}% is below {min}%", counts.line_percent()));
+    }
+    if let Some(min) = self.branches.filter(|min| counts.branch_percent() < *min) {
+      violations.push(format!("{name}: branch coverage {:.2}% is below {min}%", counts.branch_percent()));
+    }
+    violations
+  }
+}
+
+impl CoverageSummary {
+  /// Messages for the total and the files below the threshold, empty when the
+  /// coverage is sufficient.
+  pub fn check(&self, threshold: &CoverageThreshold) -> Vec<String> {
+    let mut violations = threshold.violations("total", &self.total);
+    if threshold.per_file {
+      for file in &self.files {
+        violations.extend(threshold.violations(&file.url, &file.counts));
+      }
+    }
+    violations
+  }
+}
+
+/// Summarizes the coverage profiles in `coverage_flags` per file and in total,
+/// the counterpart of `--coverage-threshold` for embedders that gate on it.
+pub async fn summarize(flags: Flags, coverage_flags: CoverageFlags) -> Result<CoverageSummary, AnyError> {
+  if coverage_flags.files.include.is_empty() {
+    return Err(generic_error("No matching coverage profiles found"));
+  }
+
+  let factory = CliFactory::from_flags(flags).await?;
+  let mut summary = CoverageSummary::default();
+  for (coverage_report, _) in collect_reports(&factory, coverage_flags, &None).await? {
+    let counts = CoverageCounts::from_report(&coverage_report);
+    summary.total.add(&counts);
+    summary.files.push(FileCoverage {
+      url: coverage_report.url.to_string(),
+      counts,
+    });
+  }
+  Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_coverage_threshold() {
+    let counts = |lines_hit, branches_hit| CoverageCounts {
+      lines_found: 10,
+      lines_hit,
+      branches_found: 4,
+      branches_hit,
+    };
+    let mut summary = CoverageSummary::default();
+    for (url, counts) in [("file:///a.ts", counts(10, 4)), ("file:///b.ts", counts(6, 1))] {
+      summary.total.add(&counts);
+      summary.files.push(FileCoverage {
+        url: url.to_string(),
+        counts,
+      });
+    }
+    assert_eq!(summary.total.line_percent(), 80.0);
+    let threshold = CoverageThreshold {
+      lines: Some(80.0),
+      branches: Some(50.0),
+      per_file: false,
+    };
+    assert!(summary.check(&threshold).is_empty());
+    let per_file = CoverageThreshold { per_file: true, ..threshold };
+    assert_eq!(
+      summary.check(&per_file),
+      vec![
+        "file:///b.ts: line coverage 60.00% is below 80%".to_string(),
+        "file:///b.ts: branch coverage 25.00% is below 50%".to_string(),
+      ]
+    );
+    assert_eq!(CoverageCounts::default().branch_percent(), 100.0);
+  }
+}
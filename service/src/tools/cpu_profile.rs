@@ -0,0 +1,212 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+
+//! Continuous CPU profiling of a running worker. The V8 sampling profiler is
+//! run one time window after another through an inspector session, and each
+//! window is reduced to folded stacks (`frame;frame;frame count`), the input
+//! format of flamegraph tools.
+
+use deno_core::error::AnyError;
+use deno_core::serde_json;
+use deno_core::serde_json::json;
+use deno_core::LocalInspectorSession;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::sync::watch;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileSettings {
+  /// Sampling interval of the profiler in microseconds.
+  pub interval_us: u32,
+  /// Length of a window, the samples of a window are sent together.
+  pub window: Duration,
+}
+
+/// Folded stacks of one window of one worker.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProfileWindow {
+  /// Name of the thread running the worker.
+  pub runtime: String,
+  pub started_at: u64,
+  pub ended_at: u64,
+  pub samples: u64,
+  /// Samples taken while the isolate was waiting for work.
+  pub idle_samples: u64,
+  pub stacks: BTreeMap<String, u64>,
+}
+
+impl ProfileWindow {
+  /// One line per stack, frames from the outermost call.
+  pub fn to_folded(&self) -> String {
+    to_folded(&self.stacks)
+  }
+}
+
+pub fn to_folded(stacks: &BTreeMap<String, u64>) -> String {
+  stacks.iter().map(|(stack, count)| format!("{stack} {count}\n")).collect()
+}
+
+/// Profiling is on while `settings` holds `Some`. A change of the settings
+/// ends the current window early, so stopping doesn't lose samples.
+pub struct ProfileControl {
+  pub settings: watch::Receiver<Option<ProfileSettings>>,
+  pub windows: mpsc::UnboundedSender<ProfileWindow>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CallFrame {
+  function_name: String,
+  url: String,
+  line_number: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProfileNode {
+  id: i64,
+  #[serde(rename = "callFrame")]
+  call_frame: CallFrame,
+  #[serde(default)]
+  children: Vec<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CpuProfile {
+  nodes: Vec<ProfileNode>,
+  #[serde(default)]
+  samples: Vec<i64>,
+}
+
+fn frame_name(frame: &CallFrame) -> String {
+  let name = match frame.function_name.is_empty() {
+    true => "(anonymous)",
+    false => &frame.function_name,
+  };
+  let name = match frame.url.is_empty() {
+    true => name.to_string(),
+    false => format!("{} {}:{}", name, frame.url, frame.line_number + 1),
+  };
+  name.replace(';', ":")
+}
+
+/// Counts the samples per stack. The `(root)` node is left out of the
+/// stacks and `(idle)` samples are only counted.
+fn fold(profile: &CpuProfile) -> (BTreeMap<String, u64>, u64) {
+  let nodes: HashMap<i64, &ProfileNode> = profile.nodes.iter().map(|n| (n.id, n)).collect();
+  let mut parents = HashMap::new();
+  for node in &profile.nodes {
+    for child in &node.children {
+      parents.insert(*child, node.id);
+    }
+  }
+  let mut paths: HashMap<i64, Option<String>> = HashMap::new();
+  let mut stacks = BTreeMap::new();
+  let mut idle = 0;
+  for id in &profile.samples {
+    let path = paths.entry(*id).or_insert_with(|| {
+      let mut frames = vec![];
+      let mut current = Some(*id);
+      while let Some(node) = current.and_then(|id| nodes.get(&id)) {
+        if parents.contains_key(&node.id) {
+          frames.push(frame_name(&node.call_frame));
+        }
+        current = parents.get(&node.id).copied();
+      }
+      frames.reverse();
+      match frames.as_slice() {
+        [] => None,
+        [frame] if frame == "(idle)" => None,
+        _ => Some(frames.join(";")),
+      }
+    });
+    match path {
+      Some(path) => *stacks.entry(path.clone()).or_insert(0) += 1,
+      None => idle += 1,
+    }
+  }
+  (stacks, idle)
+}
+
+fn now_millis() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+/// Profiles one window and sends it. Returns false when the sender of the
+/// settings was dropped.
+async fn profile_window(session: &mut LocalInspectorSession, control: &mut ProfileControl, settings: ProfileSettings) -> Result<bool, AnyError> {
+  session.post_message::<()>("Profiler.enable", None).await?;
+  session
+    .post_message("Profiler.setSamplingInterval", Some(json!({ "interval": settings.interval_us })))
+    .await?;
+  session.post_message::<()>("Profiler.start", None).await?;
+  let started_at = now_millis();
+  let open = select! {
+    _ = tokio::time::sleep(settings.window) => true,
+    changed = control.settings.changed() => changed.is_ok(),
+  };
+  let result = session.post_message::<()>("Profiler.stop", None).await?;
+  let profile: CpuProfile = serde_json::from_value(result["profile"].clone())?;
+  let (stacks, idle_samples) = fold(&profile);
+  let window = ProfileWindow {
+    runtime: std::thread::current().name().unwrap_or_default().to_string(),
+    started_at,
+    ended_at: now_millis(),
+    samples: stacks.values().sum::<u64>() + idle_samples,
+    idle_samples,
+    stacks,
+  };
+  let _ = control.windows.send(window);
+  Ok(open)
+}
+
+/// Profiles the worker of `session` whenever the settings ask for it, until
+/// the sender of the settings is dropped. Run it next to the event loop of
+/// the worker, the inspector messages are handled while polling it.
+pub async fn run_profiler(mut session: LocalInspectorSession, mut control: ProfileControl) -> Result<(), AnyError> {
+  loop {
+    let settings = *control.settings.borrow_and_update();
+    match settings {
+      Some(settings) => {
+        if !profile_window(&mut session, &mut control, settings).await? {
+          return Ok(());
+        }
+      }
+      None => {
+        if control.settings.changed().await.is_err() {
+          return Ok(());
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_fold_profile() {
+    let profile: CpuProfile = serde_json::from_value(json!({
+      "nodes": [
+        { "id": 1, "callFrame": { "functionName": "(root)", "url": "", "lineNumber": -1 }, "children": [2, 3] },
+        { "id": 2, "callFrame": { "functionName": "(idle)", "url": "", "lineNumber": -1 } },
+        { "id": 3, "callFrame": { "functionName": "handler", "url": "file:///shop/main.ts", "lineNumber": 9 }, "children": [4] },
+        { "id": 4, "callFrame": { "functionName": "", "url": "file:///shop/cart.ts", "lineNumber": 0 } },
+      ],
+      "samples": [2, 3, 4, 4, 2],
+    }))
+    .unwrap();
+    let (stacks, idle) = fold(&profile);
+    assert_eq!(idle, 2);
+    assert_eq!(
+      to_folded(&stacks),
+      "handler file:///shop/main.ts:10 1\nhandler file:///shop/main.ts:10;(anonymous) file:///shop/cart.ts:1 2\n"
+    );
+  }
+}
@@ -0,0 +1,215 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+
+use deno_core::error::AnyError;
+use deno_core::url::Url;
+use deno_graph::Module;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::args::Flags;
+use crate::factory::CliFactory;
+use crate::npm::CliNpmRegistryApi;
+
+/// 依赖来源
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DependencyKind {
+  /// npm: 说明符解析出的包(包含传递依赖)
+  Npm,
+  /// 远程 http(s) 模块
+  Remote,
+  /// node: 内置模块
+  Node,
+}
+
+/// 产品模块图中的一个外部依赖
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DependencyInfo {
+  pub kind: DependencyKind,
+  /// 包所在的生态 npm / deno.land/x / deno.land/std 等
+  pub registry: String,
+  pub name: String,
+  pub version: Option<String>,
+  /// 该包在图中出现的所有模块说明符
+  pub specifiers: Vec<String>,
+}
+
+/// 构建入口模块的模块图 汇总所有外部依赖
+/// 同一个包的多个模块会被合并为一条记录
+pub async fn collect_dependencies(flags: Flags) -> Result<Vec<DependencyInfo>, AnyError> {
+  let factory = CliFactory::from_flags(flags).await?;
+  let main_module = factory.cli_options().resolve_main_module()?;
+  let module_graph_builder = factory.module_graph_builder().await?;
+  let graph = module_graph_builder.create_graph(vec![main_module]).await?;
+  let npm_resolver = factory.npm_resolver().await?;
+
+  let mut deps: BTreeMap<(DependencyKind, String, String, Option<String>), Vec<String>> = BTreeMap::new();
+  for module in graph.modules() {
+    match module {
+      Module::Esm(_) | Module::Json(_) | Module::External(_) => {
+        let specifier = module.specifier();
+        if let Some((registry, name, version)) = parse_remote_package(specifier) {
+          deps
+            .entry((DependencyKind::Remote, registry, name, version))
+            .or_default()
+            .push(specifier.to_string());
+        }
+      }
+      Module::Node(module) => {
+        deps
+          .entry((DependencyKind::Node, "node".to_string(), module.module_name.to_string(), None))
+          .or_default()
+          .push(module.specifier.to_string());
+      }
+      // npm 模块由下面的快照统一处理 以便包含传递依赖
+      Module::Npm(_) => {}
+    }
+  }
+
+  let snapshot = npm_resolver.snapshot();
+  for package in snapshot.all_packages_for_every_system() {
+    let nv = &package.id.nv;
+    deps
+      .entry((DependencyKind::Npm, "npm".to_string(), nv.name.to_string(), Some(nv.version.to_string())))
+      .or_default()
+      .push(format!("npm:{}@{}", nv.name, nv.version));
+  }
+
+  Ok(
+    deps
+      .into_iter()
+      .map(|((kind, registry, name, version), specifiers)| DependencyInfo {
+        kind,
+        registry,
+        name,
+        version,
+        specifiers,
+      })
+      .collect(),
+  )
+}
+
+/// 构建入口模块的模块图 返回需要联网获取的模块
+/// flags.net_allowlist 中的主机以及 npm 仓库主机在白名单中时除外
+/// 加载失败的模块同样计入 用于离线模式下检查产品是否已完全 vendor
+pub async fn find_remote_modules(flags: Flags) -> Result<Vec<String>, AnyError> {
+  let allowlist = flags.net_allowlist.clone().unwrap_or_default();
+  let factory = CliFactory::from_flags(flags).await?;
+  let main_module = factory.cli_options().resolve_main_module()?;
+  let module_graph_builder = factory.module_graph_builder().await?;
+  let graph = module_graph_builder.create_graph(vec![main_module]).await?;
+  let is_allowed = |url: &Url| {
+    let host = url.host_str().unwrap_or_default();
+    let host_port = url.port_or_known_default().map(|port| format!("{host}:{port}"));
+    allowlist.iter().any(|entry| entry == host || Some(entry) == host_port.as_ref())
+  };
+  let npm_allowed = is_allowed(CliNpmRegistryApi::default_url());
+  let mut remote: Vec<String> = graph
+    .specifiers()
+    .map(|(specifier, _)| specifier)
+    .filter(|specifier| match specifier.scheme() {
+      "http" | "https" => !is_allowed(specifier),
+      "npm" => !npm_allowed,
+      _ => false,
+    })
+    .map(|specifier| specifier.to_string())
+    .collect();
+  remote.sort();
+  remote.dedup();
+  Ok(remote)
+}
+
+/// 从远程模块地址中解析出 (registry, name, version)
+/// 本地文件以及无法识别的地址返回 None
+pub fn parse_remote_package(specifier: &Url) -> Option<(String, String, Option<String>)> {
+  if !matches!(specifier.scheme(), "http" | "https") {
+    return None;
+  }
+  let host = specifier.host_str()?;
+  let segments: Vec<&str> = specifier.path_segments()?.filter(|s| !s.is_empty()).collect();
+  match host {
+    "deno.land" => match segments.first() {
+      Some(&"x") => {
+        let (name, version) = split_name_version(segments.get(1)?);
+        Some(("deno.land/x".to_string(), name, version))
+      }
+      Some(first) if first.starts_with("std") => {
+        let (name, version) = split_name_version(first);
+        Some(("deno.land/std".to_string(), name, version))
+      }
+      _ => None,
+    },
+    // 这些 CDN 直接分发 npm 包 按 npm 生态处理
+    "esm.sh" | "cdn.skypack.dev" | "unpkg.com" | "cdn.jsdelivr.net" => {
+      let mut segments = segments.into_iter().peekable();
+      // esm.sh/v125/... 以及 jsdelivr 的 /npm/ 前缀
+      while let Some(segment) = segments.peek() {
+        let is_build_prefix = segment.len() > 1 && segment.starts_with('v') && segment[1..].chars().all(|c| c.is_ascii_digit());
+        if is_build_prefix || *segment == "npm" || *segment == "stable" {
+          segments.next();
+        } else {
+          break;
+        }
+      }
+      let first = segments.next()?;
+      let package = if first.starts_with('@') {
+        format!("{}/{}", first, segments.next()?)
+      } else {
+        first.to_string()
+      };
+      let (name, version) = split_name_version(&package);
+      Some(("npm".to_string(), name, version))
+    }
+    _ => {
+      let (name, version) = split_name_version(segments.first()?);
+      Some((host.to_string(), name, version))
+    }
+  }
+}
+
+/// `oak@v12.1.0` -> (oak, 12.1.0) `@scope/pkg@1.0.0` -> (@scope/pkg, 1.0.0)
+fn split_name_version(segment: &str) -> (String, Option<String>) {
+  let search_start = if segment.starts_with('@') { 1 } else { 0 };
+  match segment[search_start..].find('@') {
+    Some(index) => {
+      let index = index + search_start;
+      let version = segment[index + 1..].trim_start_matches('v');
+      let version = if version.is_empty() { None } else { Some(version.to_string()) };
+      (segment[..index].to_string(), version)
+    }
+    None => (segment.to_string(), None),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn parse(url: &str) -> Option<(String, String, Option<String>)> {
+    parse_remote_package(&Url::parse(url).unwrap())
+  }
+
+  #[test]
+  fn test_parse_remote_package() {
+    assert_eq!(
+      parse("https://deno.land/x/oak@v12.1.0/mod.ts"),
+      Some(("deno.land/x".to_string(), "oak".to_string(), Some("12.1.0".to_string())))
+    );
+    assert_eq!(
+      parse("https://deno.land/std@0.190.0/http/server.ts"),
+      Some(("deno.land/std".to_string(), "std".to_string(), Some("0.190.0".to_string())))
+    );
+    assert_eq!(
+      parse("https://esm.sh/v125/preact@10.5.0/hooks"),
+      Some(("npm".to_string(), "preact".to_string(), Some("10.5.0".to_string())))
+    );
+    assert_eq!(
+      parse("https://cdn.jsdelivr.net/npm/@scope/pkg@1.2.3/index.js"),
+      Some(("npm".to_string(), "@scope/pkg".to_string(), Some("1.2.3".to_string())))
+    );
+    assert_eq!(parse("https://deno.land/x/oak/mod.ts"), Some(("deno.land/x".to_string(), "oak".to_string(), None)));
+    assert_eq!(parse("file:///code/admin/app.ts"), None);
+  }
+}
@@ -29,6 +29,7 @@ use deno_core::error::AnyError;
 use deno_core::futures;
 use deno_core::parking_lot::Mutex;
 use deno_core::task::spawn_blocking;
+use deno_runtime::at_rest;
 use log::debug;
 use log::info;
 use log::warn;
@@ -483,6 +484,7 @@ struct FileContents {
 
 fn read_file_contents(file_path: &Path) -> Result<FileContents, AnyError> {
   let file_bytes = fs::read(file_path).with_context(|| format!("Error reading {}", file_path.display()))?;
+  let file_bytes = at_rest::decrypt(file_bytes)?;
   let charset = text_encoding::detect_charset(&file_bytes);
   let file_text = text_encoding::convert_to_utf8(&file_bytes, charset).map_err(|_| anyhow!("{} is not a valid UTF-8 file", file_path.display()))?;
   let had_bom = file_text.starts_with(text_encoding::BOM_CHAR);
@@ -503,7 +505,7 @@ fn write_file_contents(file_path: &Path, file_contents: FileContents) -> Result<
     file_contents.text
   };
 
-  Ok(fs::write(file_path, file_text)?)
+  Ok(fs::write(file_path, at_rest::encrypt(file_text.as_bytes())?)?)
 }
 
 pub async fn run_parallelized<F>(file_paths: Vec<PathBuf>, f: F) -> Result<(), AnyError>
@@ -0,0 +1,99 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+
+//! Periodic heap usage sampling of a running worker through an inspector
+//! session. Heap snapshots are taken on request of the receiver of the
+//! samples.
+
+use deno_core::error::AnyError;
+use deno_core::futures::channel::mpsc::UnboundedReceiver;
+use deno_core::serde_json::json;
+use deno_core::serde_json::Value;
+use deno_core::LocalInspectorSession;
+use serde::Deserialize;
+use serde::Serialize;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+
+/// Heap usage of the isolate at one moment, sizes in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeapSample {
+  pub at: u64,
+  pub used: u64,
+  pub total: u64,
+}
+
+/// Receives the serialized snapshot, in the `.heapsnapshot` format of the
+/// Chrome DevTools.
+pub type SnapshotReply = oneshot::Sender<Result<Vec<u8>, AnyError>>;
+
+pub struct HeapControl {
+  pub interval: Duration,
+  pub samples: mpsc::UnboundedSender<HeapSample>,
+  pub snapshots: mpsc::UnboundedReceiver<SnapshotReply>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HeapUsage {
+  used_size: f64,
+  total_size: f64,
+}
+
+fn now_millis() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+async fn sample(session: &mut LocalInspectorSession) -> Result<HeapSample, AnyError> {
+  let result = session.post_message::<()>("Runtime.getHeapUsage", None).await?;
+  let usage: HeapUsage = deno_core::serde_json::from_value(result)?;
+  Ok(HeapSample {
+    at: now_millis(),
+    used: usage.used_size as u64,
+    total: usage.total_size as u64,
+  })
+}
+
+/// The chunks are sent as notifications before the response of
+/// `HeapProfiler.takeHeapSnapshot`, so they are all queued once it returns.
+async fn snapshot(session: &mut LocalInspectorSession, notifications: &mut UnboundedReceiver<Value>) -> Result<Vec<u8>, AnyError> {
+  while let Ok(Some(_)) = notifications.try_next() {}
+  session.post_message::<()>("HeapProfiler.enable", None).await?;
+  session
+    .post_message("HeapProfiler.takeHeapSnapshot", Some(json!({ "reportProgress": false })))
+    .await?;
+  let mut bytes = vec![];
+  while let Ok(Some(notification)) = notifications.try_next() {
+    if notification["method"] == "HeapProfiler.addHeapSnapshotChunk" {
+      if let Some(chunk) = notification["params"]["chunk"].as_str() {
+        bytes.extend_from_slice(chunk.as_bytes());
+      }
+    }
+  }
+  session.post_message::<()>("HeapProfiler.disable", None).await?;
+  Ok(bytes)
+}
+
+/// Samples the heap of the worker of `session` every interval until the
+/// receiver of the samples is dropped. Run it next to the event loop of the
+/// worker, the inspector messages are handled while polling it.
+pub async fn run_heap_watch(mut session: LocalInspectorSession, mut control: HeapControl) -> Result<(), AnyError> {
+  let mut notifications = session.take_notification_rx();
+  let mut interval = tokio::time::interval(control.interval);
+  interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+  loop {
+    select! {
+      _ = interval.tick() => {
+        if control.samples.send(sample(&mut session).await?).is_err() {
+          return Ok(());
+        }
+      }
+      Some(reply) = control.snapshots.recv() => {
+        let _ = reply.send(snapshot(&mut session, &mut notifications).await);
+      }
+    }
+  }
+}
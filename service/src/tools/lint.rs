@@ -29,6 +29,7 @@ use deno_lint::linter::Linter;
 use deno_lint::linter::LinterBuilder;
 use deno_lint::rules;
 use deno_lint::rules::LintRule;
+use deno_runtime::at_rest;
 use deno_runtime::fmt_errors::format_location;
 use log::debug;
 use log::info;
@@ -116,7 +117,7 @@ pub async fn lint(cli_options: CliOptions, lint_options: LintOptions) -> Result<
       let reporter_lock = reporter_lock.clone();
       let incremental_cache = incremental_cache.clone();
       move |file_path| {
-        let file_text = fs::read_to_string(&file_path)?;
+        let file_text = String::from_utf8(at_rest::decrypt(fs::read(&file_path)?)?)?;
 
         // don't bother rechecking this file if it didn't have any diagnostics before
         if incremental_cache.is_file_same(&file_path, &file_text) {
@@ -174,7 +175,8 @@ pub async fn lint(cli_options: CliOptions, lint_options: LintOptions) -> Result<
     };
     let has_error = has_error.load(Ordering::Relaxed);
     if has_error {
-      std::process::exit(1);
+      //在网关进程里运行 不能直接退出进程
+      bail!("Found lint problems");
     }
   }
 
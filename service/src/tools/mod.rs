@@ -5,14 +5,18 @@ pub mod bundle;
 pub mod check;
 pub mod compile;
 pub mod coverage;
+pub mod cpu_profile;
+pub mod deps;
 pub mod doc;
 pub mod fmt;
+pub mod heap_watch;
 pub mod info;
 pub mod init;
 pub mod installer;
 pub mod lint;
 pub mod repl;
 pub mod run;
+pub mod snapshot;
 pub mod task;
 pub mod test;
 pub mod upgrade;
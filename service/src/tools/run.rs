@@ -2,25 +2,75 @@ use crate::util;
 
 use deno_ast::ModuleSpecifier;
 use deno_core::error::AnyError;
+use deno_core::v8;
 use deno_core::Extension;
-use deno_runtime::permissions::PermissionsContainer;
+use deno_runtime::deno_io::Stdio;
+use deno_runtime::ops::scratch::ScratchDir;
+use deno_runtime::ops::tasks::TaskScope;
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::net::TcpStream;
 use tokio::select;
 
 use crate::args::Flags;
 use crate::factory::{CliFactory, CliFactoryBuilder};
+use crate::tools::cpu_profile::{self, ProfileControl};
+use crate::tools::heap_watch::{self, HeapControl};
 
 use crate::worker::CliMainWorker;
 
 deno_core::extension!(cc_deno,
   options = {
-      stream_rx:  async_channel::Receiver<TcpStream>
+      stream_rx:  async_channel::Receiver<TcpStream>,
+      product_code: Option<String>,
+      scratch_dir: Option<PathBuf>,
   },
   state = |state, options| {
     state.put(options.stream_rx);
+    //后台任务按产品归属
+    if let Some(product_code) = options.product_code {
+      state.put(TaskScope(product_code));
+    }
+    //沙箱中唯一可写的目录
+    if let Some(scratch_dir) = options.scratch_dir {
+      state.put(ScratchDir(scratch_dir));
+    }
   },
 );
 
+/// Heap statistics of the isolate at the moment a runtime died.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct IsolateStats {
+  pub used_heap_size: usize,
+  pub total_heap_size: usize,
+  pub heap_size_limit: usize,
+  pub external_memory: usize,
+  pub malloced_memory: usize,
+  pub number_of_native_contexts: usize,
+  pub number_of_detached_contexts: usize,
+}
+
+/// Called when a runtime exits with an uncaught exception or unhandled
+/// rejection. Stats are not available in watch mode, where the worker is
+/// consumed by the watcher.
+pub type CrashHook = Arc<dyn Fn(&AnyError, Option<IsolateStats>) + Send + Sync>;
+
+fn isolate_stats(worker: &mut CliMainWorker) -> IsolateStats {
+  let mut s = v8::HeapStatistics::default();
+  worker.worker.js_runtime.v8_isolate().get_heap_statistics(&mut s);
+  IsolateStats {
+    used_heap_size: s.used_heap_size(),
+    total_heap_size: s.total_heap_size(),
+    heap_size_limit: s.heap_size_limit(),
+    external_memory: s.external_memory(),
+    malloced_memory: s.malloced_memory(),
+    number_of_native_contexts: s.number_of_native_contexts(),
+    number_of_detached_contexts: s.number_of_detached_contexts(),
+  }
+}
+
 pub async fn build_worker(flags: Flags, extensions: Vec<Extension>) -> Result<CliMainWorker, AnyError> {
   // TODO(bartlomieju): actually I think it will also fail if there's an import
   // map specified and bare specifier is used on the command line
@@ -35,8 +85,8 @@ pub async fn build_worker(flags: Flags, extensions: Vec<Extension>) -> Result<Cl
   let main_module = cli_options.resolve_main_module()?;
 
   maybe_npm_install(&factory).await?;
-  //开启所有权限
-  let permissions = PermissionsContainer::allow_all();
+  //开启所有权限 离线模式下网络只允许白名单
+  let permissions = cli_options.runtime_permissions()?;
   let worker_factory = factory.create_cli_main_worker_factory().await?;
   let worker = worker_factory
     .create_custom_worker(main_module, permissions, extensions, Default::default())
@@ -48,6 +98,10 @@ pub async fn run_script(
   flags: Flags,
   stream_rx: async_channel::Receiver<TcpStream>,
   notify_rx: async_channel::Receiver<u8>,
+  stdio: Stdio,
+  crash_hook: CrashHook,
+  profile: Option<ProfileControl>,
+  heap: Option<HeapControl>,
 ) -> Result<i32, AnyError> {
   // TODO(bartlomieju): actually I think it will also fail if there's an import
   // map specified and bare specifier is used on the command line
@@ -62,20 +116,48 @@ pub async fn run_script(
   let main_module = cli_options.resolve_main_module()?;
 
   maybe_npm_install(&factory).await?;
-  let permissions = PermissionsContainer::allow_all();
+  let permissions = cli_options.runtime_permissions()?;
   let worker_factory = factory.create_cli_main_worker_factory().await?;
-  let extensions: Vec<_> = vec![cc_deno::init_ops(stream_rx)];
-  let mut worker = worker_factory
-    .create_custom_worker(main_module, permissions, extensions, Default::default())
-    .await?;
-  select! {
+  let scratch_dir = cli_options.sandbox().map(|s| s.scratch_dir.clone());
+  let extensions: Vec<_> = vec![cc_deno::init_ops(stream_rx, cli_options.product_code().cloned(), scratch_dir)];
+  let mut worker = worker_factory.create_custom_worker(main_module, permissions, extensions, stdio).await?;
+  let profile = match profile {
+    Some(control) => Some((worker.worker.create_inspector_session().await, control)),
+    None => None,
+  };
+  //采样在 runtime 的事件循环中处理 与 worker 一起轮询
+  let profiler = async move {
+    if let Some((session, control)) = profile {
+      if let Err(err) = cpu_profile::run_profiler(session, control).await {
+        log::warn!("cpu profiler stopped: {}", err);
+      }
+    }
+    std::future::pending::<()>().await
+  };
+  let heap = match heap {
+    Some(control) => Some((worker.worker.create_inspector_session().await, control)),
+    None => None,
+  };
+  let heap_sampler = async move {
+    if let Some((session, control)) = heap {
+      if let Err(err) = heap_watch::run_heap_watch(session, control).await {
+        log::warn!("heap watch stopped: {}", err);
+      }
+    }
+    std::future::pending::<()>().await
+  };
+  let result = select! {
     _ = notify_rx.recv() => {
-        Ok(0)
+        return Ok(0);
     },
-    _ =  worker.run() => {
-         Ok(0)
-    }
-  }
+    result = worker.run() => result,
+    _ = profiler => unreachable!(),
+    _ = heap_sampler => unreachable!(),
+  };
+  result.map_err(|err| {
+    crash_hook(&err, Some(isolate_stats(&mut worker)));
+    err
+  })
 }
 
 async fn maybe_npm_install(factory: &CliFactory) -> Result<(), AnyError> {
@@ -91,6 +173,8 @@ pub async fn run_with_watch(
   flags: Flags,
   stream_rx: async_channel::Receiver<TcpStream>,
   watch_rx: async_channel::Receiver<bool>,
+  stdio: impl Fn() -> Stdio,
+  crash_hook: CrashHook,
 ) -> Result<i32, AnyError> {
   let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
   let factory = CliFactoryBuilder::new().with_watcher(sender.clone()).build_from_flags(flags).await?;
@@ -98,19 +182,27 @@ pub async fn run_with_watch(
   let cli_options = factory.cli_options();
   let clear_screen = !cli_options.no_clear_screen();
   let main_module = cli_options.resolve_main_module()?;
+  let product_code = cli_options.product_code().cloned();
+  let scratch_dir = cli_options.sandbox().map(|s| s.scratch_dir.clone());
   maybe_npm_install(&factory).await?;
+  let permissions = cli_options.runtime_permissions()?;
   let create_cli_main_worker_factory = factory.create_cli_main_worker_factory_func().await?;
   let operation = |main_module: ModuleSpecifier| {
     file_watcher.reset();
-    let permissions: PermissionsContainer = PermissionsContainer::allow_all();
+    let permissions = permissions.clone();
     let create_cli_main_worker_factory = create_cli_main_worker_factory.clone();
-    let extensions: Vec<_> = vec![cc_deno::init_ops(stream_rx.clone())];
+    let extensions: Vec<_> = vec![cc_deno::init_ops(stream_rx.clone(), product_code.clone(), scratch_dir.clone())];
+    //每次重启创建新的输出管道
+    let stdio = stdio();
+    let crash_hook = crash_hook.clone();
     Ok(async move {
       let worker = create_cli_main_worker_factory()
-        .create_custom_worker(main_module, permissions, extensions, Default::default())
+        .create_custom_worker(main_module, permissions, extensions, stdio)
         .await?;
-      worker.run_for_watcher().await?;
-      Ok(())
+      worker.run_for_watcher().await.map_err(|err| {
+        crash_hook(&err, None);
+        err
+      })
     })
   };
 
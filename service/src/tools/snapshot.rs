@@ -0,0 +1,115 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+
+//! Snapshot assertions of the test runner. The snapshots of a test module
+//! are stored next to it in `__snapshots__/<file name>.json`, keyed by the
+//! full test name and the number of the assertion within the test.
+
+use crate::colors;
+use crate::util::diff::diff;
+
+use deno_core::error::AnyError;
+use deno_core::serde_json;
+use deno_core::ModuleSpecifier;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+pub const SNAPSHOT_DIR: &str = "__snapshots__";
+
+pub struct SnapshotStore {
+  path: PathBuf,
+  update: bool,
+  snapshots: RefCell<BTreeMap<String, String>>,
+  counts: RefCell<HashMap<String, usize>>,
+}
+
+impl SnapshotStore {
+  /// Loads the snapshots of a test module. Returns `None` for modules that
+  /// are not on the file system.
+  pub fn open(specifier: &ModuleSpecifier, update: bool) -> Result<Option<Self>, AnyError> {
+    let Ok(module_path) = specifier.to_file_path() else {
+      return Ok(None);
+    };
+    let (Some(dir), Some(file_name)) = (module_path.parent(), module_path.file_name()) else {
+      return Ok(None);
+    };
+    let path = dir.join(SNAPSHOT_DIR).join(format!("{}.json", file_name.to_string_lossy()));
+    let snapshots = match std::fs::read(&path) {
+      Ok(bytes) => serde_json::from_slice(&bytes)?,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+      Err(err) => return Err(err.into()),
+    };
+    Ok(Some(Self {
+      path,
+      update,
+      snapshots: RefCell::new(snapshots),
+      counts: RefCell::new(HashMap::new()),
+    }))
+  }
+
+  fn next_key(&self, test_name: &str) -> String {
+    let mut counts = self.counts.borrow_mut();
+    let count = counts.entry(test_name.to_string()).or_default();
+    *count += 1;
+    format!("{test_name} {count}")
+  }
+
+  /// Compares `actual` with the next snapshot of the test, or stores it when
+  /// updating. Returns the failure message, with a diff on mismatch.
+  pub fn assert(&self, test_name: &str, actual: String) -> Result<Option<String>, AnyError> {
+    let key = self.next_key(test_name);
+    if self.update {
+      let mut snapshots = self.snapshots.borrow_mut();
+      if snapshots.get(&key) != Some(&actual) {
+        snapshots.insert(key, actual);
+        std::fs::create_dir_all(self.path.parent().unwrap())?;
+        std::fs::write(&self.path, serde_json::to_vec_pretty(&*snapshots)?)?;
+      }
+      return Ok(None);
+    }
+    let message = match self.snapshots.borrow().get(&key) {
+      Some(expected) if *expected == actual => return Ok(None),
+      Some(expected) => format!(
+        "Snapshot \"{key}\" does not match ({} / {}):\n\n{}",
+        colors::red("- snapshot"),
+        colors::green("+ actual"),
+        diff(expected, &actual)
+      ),
+      None => format!(
+        "Missing snapshot \"{key}\" in {}. Run the tests with --update-snapshots to create it.",
+        self.path.display()
+      ),
+    };
+    Ok(Some(message))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_snapshot_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let specifier = ModuleSpecifier::from_file_path(dir.path().join("user_test.ts")).unwrap();
+    let store = SnapshotStore::open(&specifier, false).unwrap().unwrap();
+    assert!(store
+      .assert("user", "{ id: 1 }".to_string())
+      .unwrap()
+      .unwrap()
+      .starts_with("Missing snapshot \"user 1\""));
+
+    let store = SnapshotStore::open(&specifier, true).unwrap().unwrap();
+    assert_eq!(store.assert("user", "{ id: 1 }".to_string()).unwrap(), None);
+    assert_eq!(store.assert("user", "{ id: 2 }".to_string()).unwrap(), None);
+    assert!(dir.path().join(SNAPSHOT_DIR).join("user_test.ts.json").exists());
+
+    let store = SnapshotStore::open(&specifier, false).unwrap().unwrap();
+    assert_eq!(store.assert("user", "{ id: 1 }".to_string()).unwrap(), None);
+    let mismatch = store.assert("user", "{ id: 3 }".to_string()).unwrap().unwrap();
+    assert!(mismatch.starts_with("Snapshot \"user 2\" does not match"));
+    let remote = ModuleSpecifier::parse("https://deno.land/x/mod_test.ts").unwrap();
+    assert!(SnapshotStore::open(&remote, false).unwrap().is_none());
+  }
+}
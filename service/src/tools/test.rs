@@ -12,6 +12,8 @@ use crate::file_fetcher::FileFetcher;
 use crate::graph_util::graph_valid_with_cli_options;
 use crate::module_loader::ModuleLoadPreparer;
 use crate::ops;
+use crate::tools::coverage::CoverageCollector;
+use crate::tools::snapshot::SnapshotStore;
 use crate::util::checksum;
 use crate::util::file_watcher;
 use crate::util::file_watcher::ResolutionResult;
@@ -39,20 +41,26 @@ use deno_core::task::spawn_blocking;
 use deno_core::url::Url;
 use deno_core::v8;
 use deno_core::ModuleSpecifier;
+use deno_runtime::deno_fetch::Cassette;
+use deno_runtime::deno_fetch::CassetteConfig;
+use deno_runtime::deno_fetch::FetchMocks;
 use deno_runtime::deno_io::Stdio;
 use deno_runtime::deno_io::StdioPipe;
 use deno_runtime::fmt_errors::format_js_error;
 use deno_runtime::permissions::Permissions;
 use deno_runtime::permissions::PermissionsContainer;
 use deno_runtime::tokio_util::create_and_run_current_thread;
+use deno_runtime::worker::MainWorker;
 use indexmap::IndexMap;
 use indexmap::IndexSet;
 use log::Level;
+use once_cell::sync::Lazy;
 use rand::rngs::SmallRng;
 use rand::seq::SliceRandom;
 use rand::SeedableRng;
 use regex::Regex;
 use serde::Deserialize;
+use serde::Serialize;
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::collections::HashMap;
@@ -63,6 +71,7 @@ use std::io::Write;
 use std::num::NonZeroUsize;
 use std::path::Path;
 use std::path::PathBuf;
+use std::rc::Rc;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
@@ -169,6 +178,52 @@ pub enum TestOutput {
   Bytes(Vec<u8>),
 }
 
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LeakDetail {
+  pub message: String,
+  /// Stacks where the leaked ops were started or the leaked resource was
+  /// created, captured when op call tracing is enabled. The stacks are
+  /// source mapped already.
+  #[serde(default)]
+  pub traces: Vec<String>,
+}
+
+static STACK_FRAME_RE: Lazy<Regex> = lazy_regex::lazy_regex!(r"^\s*at (?:.* \()?(file://.+):(\d+):(\d+)\)?$");
+
+/// The first frame of a local module in a stack, relative to `cwd`. This is
+/// where the product code called into the runtime.
+fn stack_origin(stack: &str, cwd: &Path) -> Option<String> {
+  let captures = stack.lines().find_map(|line| STACK_FRAME_RE.captures(line))?;
+  let url = Url::parse(&captures[1]).ok()?;
+  let cwd = Url::from_directory_path(cwd).ok()?;
+  let path = match cwd.make_relative(&url) {
+    Some(relative) if !relative.starts_with("../") => format!("./{relative}"),
+    _ => url.to_string(),
+  };
+  Some(format!("{}:{}:{}", path, &captures[2], &captures[3]))
+}
+
+fn format_leaks(title: &str, details: &[LeakDetail], verb: &str, is_op_call_tracing_enabled: bool, hint: &str) -> String {
+  let cwd = std::env::current_dir().unwrap_or_default();
+  let mut string = title.to_string();
+  for detail in details {
+    string.push_str(&format!("\n  - {}", detail.message));
+    for stack in &detail.traces {
+      if let Some(origin) = stack_origin(stack, &cwd) {
+        string.push_str(&format!("\n    {verb} at {origin}"));
+      }
+      for line in stack.lines() {
+        string.push_str(&format!("\n    {}", line.trim_start()));
+      }
+    }
+  }
+  if !is_op_call_tracing_enabled {
+    string.push_str(&format!("\nTo get more details where {hint} leaked, run again with --trace-ops flag."));
+  }
+  string
+}
+
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Debug, Clone, PartialEq, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -176,8 +231,8 @@ pub enum TestFailure {
   JsError(Box<JsError>),
   FailedSteps(usize),
   IncompleteSteps,
-  LeakedOps(Vec<String>, bool), // Details, isOpCallTracingEnabled
-  LeakedResources(Vec<String>), // Details
+  LeakedOps(Vec<LeakDetail>, bool),       // Details, isOpCallTracingEnabled
+  LeakedResources(Vec<LeakDetail>, bool), // Details, isOpCallTracingEnabled
   // The rest are for steps only.
   Incomplete,
   OverlapsWithSanitizers(IndexSet<String>),   // Long names of overlapped tests
@@ -193,21 +248,10 @@ impl ToString for TestFailure {
       TestFailure::IncompleteSteps => "Completed while steps were still running. Ensure all steps are awaited with `await t.step(...)`.".to_string(),
       TestFailure::Incomplete => "Didn't complete before parent. Await step with `await t.step(...)`.".to_string(),
       TestFailure::LeakedOps(details, is_op_call_tracing_enabled) => {
-        let mut string = "Leaking async ops:".to_string();
-        for detail in details {
-          string.push_str(&format!("\n  - {}", detail));
-        }
-        if !is_op_call_tracing_enabled {
-          string.push_str("\nTo get more details where ops were leaked, run again with --trace-ops flag.");
-        }
-        string
+        format_leaks("Leaking async ops:", details, "Started", *is_op_call_tracing_enabled, "ops were")
       }
-      TestFailure::LeakedResources(details) => {
-        let mut string = "Leaking resources:".to_string();
-        for detail in details {
-          string.push_str(&format!("\n  - {}", detail));
-        }
-        string
+      TestFailure::LeakedResources(details, is_op_call_tracing_enabled) => {
+        format_leaks("Leaking resources:", details, "Created", *is_op_call_tracing_enabled, "resources were")
       }
       TestFailure::OverlapsWithSanitizers(long_names) => {
         let mut string = "Started test step while another test step with sanitizers was running:".to_string();
@@ -326,14 +370,54 @@ pub struct TestSummary {
   pub measured: usize,
   pub failures: Vec<(TestDescription, TestFailure)>,
   pub uncaught_errors: Vec<(String, Box<JsError>)>,
+  /// Wall time of the tests that ran, in the order they finished.
+  pub timings: Vec<TestTiming>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TestTiming {
+  pub name: String,
+  pub origin: String,
+  /// In milliseconds.
+  pub elapsed: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModuleTiming {
+  pub origin: String,
+  pub tests: usize,
+  /// Sum of the wall time of the tests in the module, in milliseconds.
+  pub elapsed: u64,
+}
+
+/// The slowest tests and test modules of a run, slowest first.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlowestTests {
+  pub tests: Vec<TestTiming>,
+  pub modules: Vec<ModuleTiming>,
+}
+
+/// Receives the progress of test runs next to the terminal reporter, for
+/// embedders that show results elsewhere.
+pub trait TestRunListener: Send + Sync {
+  /// A run of these modules starts.
+  fn started(&self, specifiers: &[ModuleSpecifier]);
+  fn event(&self, event: &TestEvent);
+  fn finished(&self, summary: &TestSummary, elapsed: &Duration);
+  /// The run couldn't start, e.g. because type checking failed.
+  fn failed(&self, error: &AnyError);
+}
+
+#[derive(Clone)]
 struct TestSpecifiersOptions {
   concurrent_jobs: NonZeroUsize,
   fail_fast: Option<NonZeroUsize>,
   log_level: Option<log::Level>,
   specifier: TestSpecifierOptions,
+  listener: Option<Arc<dyn TestRunListener>>,
+  report_slowest: Option<NonZeroUsize>,
 }
 
 #[derive(Debug, Clone)]
@@ -341,6 +425,10 @@ pub struct TestSpecifierOptions {
   pub shuffle: Option<u64>,
   pub filter: TestFilter,
   pub trace_ops: bool,
+  pub fetch_mocks: Option<FetchMocks>,
+  pub fetch_cassettes: Option<CassetteConfig>,
+  pub update_snapshots: bool,
+  pub isolate_per_test: bool,
 }
 
 impl TestSummary {
@@ -357,9 +445,31 @@ impl TestSummary {
       measured: 0,
       failures: Vec::new(),
       uncaught_errors: Vec::new(),
+      timings: Vec::new(),
     }
   }
 
+  /// The `n` slowest tests and test modules.
+  pub fn slowest(&self, n: usize) -> SlowestTests {
+    let mut tests = self.timings.clone();
+    tests.sort_by(|a, b| b.elapsed.cmp(&a.elapsed));
+    tests.truncate(n);
+    let mut modules: IndexMap<&str, ModuleTiming> = IndexMap::new();
+    for timing in &self.timings {
+      let module = modules.entry(&timing.origin).or_insert_with(|| ModuleTiming {
+        origin: timing.origin.clone(),
+        tests: 0,
+        elapsed: 0,
+      });
+      module.tests += 1;
+      module.elapsed += timing.elapsed;
+    }
+    let mut modules: Vec<ModuleTiming> = modules.into_values().collect();
+    modules.sort_by(|a, b| b.elapsed.cmp(&a.elapsed));
+    modules.truncate(n);
+    SlowestTests { tests, modules }
+  }
+
   fn has_failed(&self) -> bool {
     self.failed > 0 || !self.failures.is_empty()
   }
@@ -374,10 +484,11 @@ struct PrettyTestReporter {
   did_have_user_output: bool,
   started_tests: bool,
   child_results_buffer: HashMap<usize, IndexMap<usize, (TestStepDescription, TestStepResult, u64)>>,
+  report_slowest: Option<NonZeroUsize>,
 }
 
 impl PrettyTestReporter {
-  fn new(parallel: bool, echo_output: bool) -> PrettyTestReporter {
+  fn new(parallel: bool, echo_output: bool, report_slowest: Option<NonZeroUsize>) -> PrettyTestReporter {
     PrettyTestReporter {
       parallel,
       echo_output,
@@ -387,6 +498,7 @@ impl PrettyTestReporter {
       did_have_user_output: false,
       started_tests: false,
       child_results_buffer: Default::default(),
+      report_slowest,
     }
   }
 
@@ -652,6 +764,28 @@ impl PrettyTestReporter {
       }
     }
 
+    if let Some(n) = self.report_slowest.filter(|_| !summary.timings.is_empty()) {
+      let slowest = summary.slowest(n.get());
+      println!("\n{}\n", colors::gray("slowest tests:"));
+      for timing in &slowest.tests {
+        println!(
+          "{} {} {}",
+          timing.name,
+          colors::gray(format!("=> {}", self.to_relative_path_or_remote_url(&timing.origin))),
+          colors::gray(format!("({})", display::human_elapsed(timing.elapsed.into())))
+        );
+      }
+      println!("\n{}\n", colors::gray("slowest modules:"));
+      for module in &slowest.modules {
+        println!(
+          "{} {} {}",
+          self.to_relative_path_or_remote_url(&module.origin),
+          colors::gray(format!("{} tests", module.tests)),
+          colors::gray(format!("({})", display::human_elapsed(module.elapsed.into())))
+        );
+      }
+    }
+
     let status = if summary.has_failed() {
       colors::red("FAILED").to_string()
     } else {
@@ -840,26 +974,39 @@ pub fn format_test_error(js_error: &JsError) -> String {
   format_js_error(&js_error)
 }
 
-/// Test a single specifier as documentation containing test programs, an executable test module or
-/// both.
-pub async fn test_specifier(
-  worker_factory: Arc<CliMainWorkerFactory>,
+/// A worker that executed a test module, with the tests it registered.
+struct LoadedTestModule {
+  worker: MainWorker,
+  coverage_collector: Option<CoverageCollector>,
+  tests: Vec<(TestDescription, v8::Global<v8::Function>)>,
+}
+
+/// Creates a worker for the test module and executes it. Returns `None`
+/// after reporting an uncaught error of the module.
+#[allow(clippy::too_many_arguments)]
+async fn load_test_module(
+  worker_factory: &CliMainWorkerFactory,
   permissions: Permissions,
-  specifier: ModuleSpecifier,
-  mut sender: TestEventSender,
-  fail_fast_tracker: FailFastTracker,
-  options: TestSpecifierOptions,
-) -> Result<(), AnyError> {
-  if fail_fast_tracker.should_stop() {
-    return Ok(());
-  }
+  specifier: &ModuleSpecifier,
+  sender: &mut TestEventSender,
+  options: &TestSpecifierOptions,
+  cassette: Option<Rc<Cassette>>,
+  snapshots: Option<Rc<SnapshotStore>>,
+  registered_ids: Option<Vec<usize>>,
+) -> Result<Option<LoadedTestModule>, AnyError> {
   let stdout = StdioPipe::File(sender.stdout());
   let stderr = StdioPipe::File(sender.stderr());
   let mut worker = worker_factory
     .create_custom_worker(
       specifier.clone(),
       PermissionsContainer::new(permissions),
-      vec![ops::testing::deno_test::init_ops(sender.clone())],
+      vec![ops::testing::deno_test::init_ops(
+        sender.clone(),
+        options.fetch_mocks.clone(),
+        cassette,
+        snapshots,
+        registered_ids,
+      )],
       Stdio {
         stdin: StdioPipe::Inherit,
         stdout,
@@ -868,7 +1015,7 @@ pub async fn test_specifier(
     )
     .await?;
 
-  let mut coverage_collector = worker.maybe_setup_coverage_collector().await?;
+  let coverage_collector = worker.maybe_setup_coverage_collector().await?;
 
   // We execute the main module as a side module so that import.meta.main is not set.
   match worker.execute_side_module_possibly_with_npm().await {
@@ -879,7 +1026,7 @@ pub async fn test_specifier(
           specifier.to_string(),
           Box::new(error.downcast::<JsError>().unwrap()),
         ))?;
-        return Ok(());
+        return Ok(None);
       } else {
         return Err(error);
       }
@@ -899,6 +1046,106 @@ pub async fn test_specifier(
     let mut state = state_rc.borrow_mut();
     std::mem::take(&mut state.borrow_mut::<ops::testing::TestContainer>().0)
   };
+  Ok(Some(LoadedTestModule {
+    worker,
+    coverage_collector,
+    tests,
+  }))
+}
+
+impl LoadedTestModule {
+  /// Runs one of the tests of the module. Returns `false` when the test
+  /// threw an uncaught error, which cancels the remaining tests of the module.
+  async fn run_test(
+    &mut self,
+    specifier: &ModuleSpecifier,
+    sender: &mut TestEventSender,
+    fail_fast_tracker: &FailFastTracker,
+    desc: &TestDescription,
+    function: &v8::Global<v8::Function>,
+  ) -> Result<bool, AnyError> {
+    sender.send(TestEvent::Wait(desc.id))?;
+    let earlier = SystemTime::now();
+    let result = match self.worker.js_runtime.call_and_await(function).await {
+      Ok(r) => r,
+      Err(error) => {
+        if error.is::<JsError>() {
+          sender.send(TestEvent::UncaughtError(
+            specifier.to_string(),
+            Box::new(error.downcast::<JsError>().unwrap()),
+          ))?;
+          fail_fast_tracker.add_failure();
+          sender.send(TestEvent::Result(desc.id, TestResult::Cancelled, 0))?;
+          return Ok(false);
+        } else {
+          return Err(error);
+        }
+      }
+    };
+    let scope = &mut self.worker.js_runtime.handle_scope();
+    let result = v8::Local::new(scope, result);
+    let result = serde_v8::from_v8::<TestResult>(scope, result)?;
+    if matches!(result, TestResult::Failed(_)) {
+      fail_fast_tracker.add_failure();
+    }
+    let elapsed = SystemTime::now().duration_since(earlier)?.as_millis();
+    sender.send(TestEvent::Result(desc.id, result, elapsed as u64))?;
+    Ok(true)
+  }
+
+  async fn unload(mut self) -> Result<(), AnyError> {
+    // Ignore `defaultPrevented` of the `beforeunload` event. We don't allow the
+    // event loop to continue beyond what's needed to await results.
+    self.worker.dispatch_beforeunload_event(located_script_name!())?;
+    self.worker.dispatch_unload_event(located_script_name!())?;
+
+    if let Some(coverage_collector) = self.coverage_collector.as_mut() {
+      self.worker.with_event_loop(coverage_collector.stop_collecting().boxed_local()).await?;
+    }
+    Ok(())
+  }
+}
+
+/// Test a single specifier as documentation containing test programs, an executable test module or
+/// both.
+///
+/// With `isolate_per_test` the first worker only collects the tests, and each
+/// test runs in a fresh worker of its own. The workers share the module graph
+/// and the startup snapshot of the worker factory, so only the isolate and
+/// the evaluation of the module are paid again per test.
+pub async fn test_specifier(
+  worker_factory: Arc<CliMainWorkerFactory>,
+  permissions: Permissions,
+  specifier: ModuleSpecifier,
+  mut sender: TestEventSender,
+  fail_fast_tracker: FailFastTracker,
+  options: TestSpecifierOptions,
+) -> Result<(), AnyError> {
+  if fail_fast_tracker.should_stop() {
+    return Ok(());
+  }
+  let cassette = match options.fetch_cassettes.clone() {
+    Some(config) => Some(Rc::new(Cassette::open(config, &specifier)?)),
+    None => None,
+  };
+  let snapshots = SnapshotStore::open(&specifier, options.update_snapshots)?.map(Rc::new);
+  let Some(mut module) = load_test_module(
+    &worker_factory,
+    permissions.clone(),
+    &specifier,
+    &mut sender,
+    &options,
+    cassette.clone(),
+    snapshots.clone(),
+    None,
+  )
+  .await?
+  else {
+    return Ok(());
+  };
+
+  let tests = std::mem::take(&mut module.tests);
+  let registered_ids: Vec<usize> = tests.iter().map(|(d, _)| d.id).collect();
   let unfiltered = tests.len();
   let (only, no_only): (Vec<_>, Vec<_>) = tests.into_iter().partition(|(d, _)| d.only);
   let used_only = !only.is_empty();
@@ -913,6 +1160,13 @@ pub async fn test_specifier(
     filtered_out: unfiltered - tests.len(),
     used_only,
   }))?;
+  let mut module = match options.isolate_per_test {
+    true => {
+      module.unload().await?;
+      None
+    }
+    false => Some(module),
+  };
   let mut had_uncaught_error = false;
   for (desc, function) in tests {
     if fail_fast_tracker.should_stop() {
@@ -926,42 +1180,44 @@ pub async fn test_specifier(
       sender.send(TestEvent::Result(desc.id, TestResult::Cancelled, 0))?;
       continue;
     }
-    sender.send(TestEvent::Wait(desc.id))?;
-    let earlier = SystemTime::now();
-    let result = match worker.js_runtime.call_and_await(&function).await {
-      Ok(r) => r,
-      Err(error) => {
-        if error.is::<JsError>() {
-          sender.send(TestEvent::UncaughtError(
-            specifier.to_string(),
-            Box::new(error.downcast::<JsError>().unwrap()),
-          ))?;
-          fail_fast_tracker.add_failure();
+    let ok = match module.as_mut() {
+      Some(module) => module.run_test(&specifier, &mut sender, &fail_fast_tracker, &desc, &function).await?,
+      None => {
+        let isolated = load_test_module(
+          &worker_factory,
+          permissions.clone(),
+          &specifier,
+          &mut sender,
+          &options,
+          cassette.clone(),
+          snapshots.clone(),
+          Some(registered_ids.clone()),
+        )
+        .await?;
+        let Some(mut isolated) = isolated else {
           sender.send(TestEvent::Result(desc.id, TestResult::Cancelled, 0))?;
           had_uncaught_error = true;
           continue;
-        } else {
-          return Err(error);
+        };
+        // The module registers its tests in the same order on every load,
+        // unless registration depends on state outside of the module.
+        let function = isolated.tests.iter().find(|(d, _)| d.id == desc.id).map(|(_, f)| f.clone());
+        match function {
+          // An uncaught error only takes down the worker of this test.
+          Some(function) => {
+            isolated.run_test(&specifier, &mut sender, &fail_fast_tracker, &desc, &function).await?;
+          }
+          None => sender.send(TestEvent::Result(desc.id, TestResult::Cancelled, 0))?,
         }
+        isolated.unload().await?;
+        true
       }
     };
-    let scope = &mut worker.js_runtime.handle_scope();
-    let result = v8::Local::new(scope, result);
-    let result = serde_v8::from_v8::<TestResult>(scope, result)?;
-    if matches!(result, TestResult::Failed(_)) {
-      fail_fast_tracker.add_failure();
-    }
-    let elapsed = SystemTime::now().duration_since(earlier)?.as_millis();
-    sender.send(TestEvent::Result(desc.id, result, elapsed as u64))?;
+    had_uncaught_error = !ok;
   }
 
-  // Ignore `defaultPrevented` of the `beforeunload` event. We don't allow the
-  // event loop to continue beyond what's needed to await results.
-  worker.dispatch_beforeunload_event(located_script_name!())?;
-  worker.dispatch_unload_event(located_script_name!())?;
-
-  if let Some(coverage_collector) = coverage_collector.as_mut() {
-    worker.with_event_loop(coverage_collector.stop_collecting().boxed_local()).await?;
+  if let Some(module) = module {
+    module.unload().await?;
   }
   Ok(())
 }
@@ -1181,6 +1437,11 @@ async fn test_specifiers(
     specifiers
   };
 
+  let listener = options.listener.clone();
+  if let Some(listener) = &listener {
+    listener.started(&specifiers);
+  }
+
   let (sender, mut receiver) = unbounded_channel::<TestEvent>();
   let sender = TestEventSender::new(sender);
   let concurrent_jobs = options.concurrent_jobs;
@@ -1217,6 +1478,7 @@ async fn test_specifiers(
   let mut reporter = Box::new(PrettyTestReporter::new(
     concurrent_jobs.get() > 1,
     options.log_level != Some(Level::Error),
+    options.report_slowest,
   ));
 
   let handler = {
@@ -1230,6 +1492,9 @@ async fn test_specifiers(
       let mut used_only = false;
 
       while let Some(event) = receiver.recv().await {
+        if let Some(listener) = &listener {
+          listener.event(&event);
+        }
         match event {
           TestEvent::Register(description) => {
             reporter.report_register(&description);
@@ -1275,6 +1540,13 @@ async fn test_specifiers(
                   summary.failed += 1;
                 }
               }
+              if matches!(result, TestResult::Ok | TestResult::Failed(_)) {
+                summary.timings.push(TestTiming {
+                  name: description.name.clone(),
+                  origin: description.origin.clone(),
+                  elapsed,
+                });
+              }
               reporter.report_result(description, &result, elapsed);
             }
           }
@@ -1338,6 +1610,9 @@ async fn test_specifiers(
 
       let elapsed = Instant::now().duration_since(earlier);
       reporter.report_summary(&summary, &elapsed);
+      if let Some(listener) = &listener {
+        listener.finished(&summary, &elapsed);
+      }
 
       if used_only {
         return Err(generic_error("Test failed because the \"only\" option was used"));
@@ -1443,7 +1718,7 @@ async fn fetch_specifiers_with_test_mode(
   Ok(specifiers_with_mode)
 }
 
-pub async fn run_tests(cli_options: CliOptions, test_options: TestOptions) -> Result<(), AnyError> {
+pub async fn run_tests(cli_options: CliOptions, test_options: TestOptions, listener: Option<Arc<dyn TestRunListener>>) -> Result<(), AnyError> {
   let factory = CliFactory::from_cli_options(Arc::new(cli_options));
   let cli_options = factory.cli_options();
   let file_fetcher = factory.file_fetcher()?;
@@ -1486,7 +1761,13 @@ pub async fn run_tests(cli_options: CliOptions, test_options: TestOptions) -> Re
         filter: TestFilter::from_flag(&test_options.filter),
         shuffle: test_options.shuffle,
         trace_ops: test_options.trace_ops,
+        fetch_mocks: cli_options.fetch_mocks().cloned(),
+        fetch_cassettes: cli_options.fetch_cassettes().cloned(),
+        update_snapshots: test_options.update_snapshots,
+        isolate_per_test: test_options.isolate_per_test,
       },
+      listener,
+      report_slowest: test_options.report_slowest,
     },
   )
   .await?;
@@ -1494,7 +1775,13 @@ pub async fn run_tests(cli_options: CliOptions, test_options: TestOptions) -> Re
   Ok(())
 }
 
-pub async fn run_tests_with_watch(cli_options: CliOptions, test_options: TestOptions) -> Result<(), AnyError> {
+/// Re-runs the tests affected by every file change. `listener` gets the
+/// results of each run, next to the terminal output.
+pub async fn run_tests_with_watch(
+  cli_options: CliOptions,
+  test_options: TestOptions,
+  listener: Option<Arc<dyn TestRunListener>>,
+) -> Result<(), AnyError> {
   let factory = CliFactory::from_cli_options(Arc::new(cli_options));
   let cli_options = factory.cli_options();
   let module_graph_builder = factory.module_graph_builder().await?;
@@ -1515,6 +1802,7 @@ pub async fn run_tests_with_watch(cli_options: CliOptions, test_options: TestOpt
     let test_options = &test_options;
     let cli_options = cli_options.clone();
     let module_graph_builder = module_graph_builder.clone();
+    let listener = listener.clone();
 
     async move {
       let test_modules = if test_options.doc {
@@ -1578,6 +1866,9 @@ pub async fn run_tests_with_watch(cli_options: CliOptions, test_options: TestOpt
       Ok((paths_to_watch, modules_to_reload))
     }
     .map(move |result| {
+      if let (Err(err), Some(listener)) = (&result, &listener) {
+        listener.failed(err);
+      }
       if files_changed && matches!(result, Ok((_, ref modules)) if modules.is_empty()) {
         ResolutionResult::Ignore
       } else {
@@ -1604,6 +1895,7 @@ pub async fn run_tests_with_watch(cli_options: CliOptions, test_options: TestOpt
     let file_fetcher = file_fetcher.clone();
     let module_load_preparer = module_load_preparer.clone();
     let create_cli_main_worker_factory = create_cli_main_worker_factory.clone();
+    let listener = listener.clone();
 
     async move {
       let worker_factory = Arc::new(create_cli_main_worker_factory());
@@ -1613,7 +1905,12 @@ pub async fn run_tests_with_watch(cli_options: CliOptions, test_options: TestOpt
         .filter(|(specifier, _)| modules_to_reload.contains(specifier))
         .collect::<Vec<(ModuleSpecifier, TestMode)>>();
 
-      check_specifiers(&cli_options, &file_fetcher, &module_load_preparer, specifiers_with_mode.clone()).await?;
+      if let Err(err) = check_specifiers(&cli_options, &file_fetcher, &module_load_preparer, specifiers_with_mode.clone()).await {
+        if let Some(listener) = &listener {
+          listener.failed(&err);
+        }
+        return Err(err);
+      }
 
       if test_options.no_run {
         return Ok(());
@@ -1637,7 +1934,13 @@ pub async fn run_tests_with_watch(cli_options: CliOptions, test_options: TestOpt
             filter: TestFilter::from_flag(&test_options.filter),
             shuffle: test_options.shuffle,
             trace_ops: test_options.trace_ops,
+            fetch_mocks: cli_options.fetch_mocks().cloned(),
+            fetch_cassettes: cli_options.fetch_cassettes().cloned(),
+            update_snapshots: test_options.update_snapshots,
+            isolate_per_test: test_options.isolate_per_test,
           },
+          listener,
+          report_slowest: test_options.report_slowest,
         },
       )
       .await?;
@@ -1857,6 +2160,23 @@ mod inner_test {
 
   use super::*;
 
+  #[test]
+  fn test_stack_origin() {
+    let stack = "    at opAsync (ext:core/01_core.js:201:17)
+    at Object.open (ext:deno_fs/30_fs.js:590:28)
+    at loadFixture (file:///products/shop/tests/fixture.ts:12:26)
+    at file:///products/shop/tests/cart_test.ts:5:9";
+    assert_eq!(
+      stack_origin(stack, Path::new("/products/shop")),
+      Some("./tests/fixture.ts:12:26".to_string())
+    );
+    assert_eq!(
+      stack_origin(stack, Path::new("/products/admin")),
+      Some("file:///products/shop/tests/fixture.ts:12:26".to_string())
+    );
+    assert_eq!(stack_origin("    at ext:core/01_core.js:201:17", Path::new("/")), None);
+  }
+
   #[test]
   fn test_is_supported_test_ext() {
     assert!(!is_supported_test_ext(Path::new("tests/subdir/redirects")));
@@ -1901,4 +2221,32 @@ mod inner_test {
     assert!(!is_supported_test_path(Path::new("notatest.js")));
     assert!(!is_supported_test_path(Path::new("NotAtest.ts")));
   }
+
+  #[test]
+  fn test_slowest_tests() {
+    let mut summary = TestSummary::new();
+    for (name, origin, elapsed) in [
+      ("a", "file:///a_test.ts", 30),
+      ("b", "file:///b_test.ts", 50),
+      ("c", "file:///a_test.ts", 40),
+    ] {
+      summary.timings.push(TestTiming {
+        name: name.to_string(),
+        origin: origin.to_string(),
+        elapsed,
+      });
+    }
+    let slowest = summary.slowest(2);
+    let names: Vec<&str> = slowest.tests.iter().map(|t| t.name.as_str()).collect();
+    assert_eq!(names, vec!["b", "c"]);
+    assert_eq!(
+      slowest.modules[0],
+      ModuleTiming {
+        origin: "file:///a_test.ts".to_string(),
+        tests: 2,
+        elapsed: 70,
+      }
+    );
+    assert_eq!(slowest.modules.len(), 2);
+  }
 }
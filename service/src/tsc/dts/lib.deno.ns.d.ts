@@ -709,6 +709,23 @@ declare namespace Deno {
      * ```
      */
     step(fn: (t: TestContext) => void | Promise<void>): Promise<boolean>;
+
+    /** Compare a value with the snapshot stored for the current test in a
+     * `__snapshots__` directory next to the test module. Values other than
+     * strings are serialized with `Deno.inspect()`. Throws an
+     * `AssertionError` with a diff when the value doesn't match, or when no
+     * snapshot exists yet.
+     *
+     * Running the tests with `--update-snapshots` stores the values instead
+     * of comparing them.
+     *
+     * ```ts
+     * Deno.test("user", (t) => {
+     *   t.assertSnapshot({ id: 1, name: "deno" });
+     * });
+     * ```
+     */
+    assertSnapshot(value: unknown): void;
   }
 
   /** @category Testing */
@@ -681,3 +681,43 @@ interface ErrorConstructor {
   // TODO(nayeemrmn): Support `Error.prepareStackTrace()`. We currently use this
   // internally in a way that makes it unavailable for users.
 }
+
+/** Metadata the platform recorded for a request.
+ *
+ * @category Platform
+ */
+declare interface PlatformRequestContext {
+  /** Id of the request, the `x-request-id` header when the client sent one. */
+  readonly requestId: string;
+  /** Claims of the authenticated caller, `null` for anonymous requests. */
+  readonly principal: Readonly<Record<string, string>> | null;
+  readonly clientIp: string | null;
+  /** ISO country code of the client. */
+  readonly country: string | null;
+  readonly region: string | null;
+  /** Product version serving the request. */
+  readonly version: string | null;
+  readonly deploymentId: string | null;
+}
+
+/** APIs of the platform the script is deployed on.
+ *
+ * ```ts
+ * function handler(request: Request): Response {
+ *   const ctx = Platform.context(request);
+ *   return new Response(`hello ${ctx?.principal?.email ?? "anonymous"}`);
+ * }
+ * ```
+ *
+ * @category Platform
+ */
+declare var Platform: {
+  /** Context of a request forwarded by the platform, `null` for requests
+   * that didn't come through it. */
+  context(request: Request): PlatformRequestContext | null;
+  /** Reports how loaded the script is, from `0` (idle) to `1` (saturated),
+   * values outside are clamped. The platform gradually accepts fewer
+   * requests as the pressure rises. Reports expire after a few seconds, so
+   * report periodically, e.g. from a `setInterval`. */
+  reportPressure(pressure: number): void;
+};
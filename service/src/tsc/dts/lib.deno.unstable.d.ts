@@ -1582,6 +1582,56 @@ declare namespace Deno {
    */
   export function openKv(path?: string): Promise<Deno.Kv>;
 
+  /** **UNSTABLE**: New API, yet to be vetted.
+   *
+   * Handle passed to a task started with {@linkcode Deno.tasks.start}.
+   *
+   * @category Runtime Environment
+   */
+  export interface TaskContext {
+    /** Id the platform tracks the task under. */
+    readonly id: string;
+    /** Whether the platform asked the task to stop. */
+    readonly cancelled: boolean;
+    /** Reports progress between 0 and 100 with an optional message.
+     * Returns `true` when the task was cancelled and should stop. */
+    progress(percent: number, message?: string): boolean;
+  }
+
+  /** **UNSTABLE**: New API, yet to be vetted.
+   *
+   * Long running tasks tracked by the platform. A task is pending until
+   * `fn` starts, running while it reports progress, and succeeded or failed
+   * once the returned promise settles. The platform can list and cancel the
+   * tasks of a product.
+   *
+   * ```ts
+   * const id = Deno.tasks.start("import-users", async (task) => {
+   *   for (let i = 0; i < users.length; i++) {
+   *     if (task.progress((i / users.length) * 100, users[i].name)) return;
+   *     await importUser(users[i]);
+   *   }
+   * });
+   * ```
+   *
+   * @category Runtime Environment
+   */
+  export const tasks: {
+    start(name: string, fn: (task: TaskContext) => unknown): string;
+  };
+
+  /** **UNSTABLE**: New API, yet to be vetted.
+   *
+   * Private scratch directory of the worker, or `null` when the platform
+   * didn't give it one. It is the only writable directory in the filesystem
+   * sandbox, has a size quota and is removed when the worker stops.
+   * {@linkcode Deno.makeTempDir} and {@linkcode Deno.makeTempFile} create
+   * their entries here unless `dir` is given.
+   *
+   * @category File System
+   */
+  export function scratchDir(): string | null;
+
   /** **UNSTABLE**: New API, yet to be vetted.
    *
    * A key to be persisted in a {@linkcode Deno.Kv}. A key is a sequence
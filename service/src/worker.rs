@@ -1,5 +1,6 @@
 // Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
 
+use std::path::Path;
 use std::path::PathBuf;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -56,6 +57,14 @@ pub trait ModuleLoaderFactory: Send + Sync {
   fn create_source_map_getter(&self) -> Option<Box<dyn SourceMapGetter>>;
 }
 
+/// Node.js globals for products running in Node compatibility mode, code
+/// migrated from Node.js often uses them without importing.
+const NODE_GLOBALS_SHIM: &str = r#"import process from "node:process";
+import { Buffer } from "node:buffer";
+import { clearImmediate, setImmediate } from "node:timers";
+Object.assign(globalThis, { process, Buffer, global: globalThis, setImmediate, clearImmediate });
+"#;
+
 // todo(dsherret): this is temporary and we should remove this
 // once we no longer conditionally initialize the node runtime
 pub trait HasNodeSpecifierChecker: Send + Sync {
@@ -74,8 +83,12 @@ pub struct CliMainWorkerOptions {
   pub is_inspecting: bool,
   pub is_npm_main: bool,
   pub location: Option<Url>,
+  /// Runs `NODE_GLOBALS_SHIM` before the main module.
+  pub node_globals: bool,
   pub maybe_binary_npm_command_name: Option<String>,
   pub origin_data_folder_path: Option<PathBuf>,
+  /// Overrides the storage directory derived from the storage key.
+  pub origin_storage_dir: Option<PathBuf>,
   pub seed: Option<u64>,
   pub unsafely_ignore_certificate_errors: Option<Vec<String>>,
   pub unstable: bool,
@@ -213,8 +226,20 @@ impl CliMainWorker {
   }
 
   pub async fn execute_main_module_possibly_with_npm(&mut self) -> Result<(), AnyError> {
+    if !self.shared.options.node_globals {
+      let id = self.worker.preload_main_module(&self.main_module).await?;
+      return self.evaluate_module_possibly_with_npm(id).await;
+    }
+    // both modules are loaded before the node runtime is initialized so the
+    // node: imports of the shim are taken into account, then the shim runs first
+    let shim = ModuleSpecifier::parse(&format!("data:application/javascript;base64,{}", base64::encode(NODE_GLOBALS_SHIM)))?;
+    let shim_id = self.worker.preload_side_module(&shim).await?;
     let id = self.worker.preload_main_module(&self.main_module).await?;
-    self.evaluate_module_possibly_with_npm(id).await
+    if self.shared.should_initialize_node_runtime() {
+      self.initialize_main_module_for_node()?;
+    }
+    self.worker.evaluate_module(shim_id).await?;
+    self.worker.evaluate_module(id).await
   }
 
   pub async fn execute_side_module_possibly_with_npm(&mut self) -> Result<(), AnyError> {
@@ -336,7 +361,7 @@ impl CliMainWorkerFactory {
     let web_worker_pre_execute_module_cb = create_web_worker_pre_execute_module_callback(shared.clone());
 
     let maybe_storage_key = shared.storage_key_resolver.resolve_storage_key(&main_module);
-    let origin_storage_dir = maybe_storage_key.as_ref().map(|key| {
+    let derived_storage_dir = maybe_storage_key.as_ref().map(|key| {
       shared
         .options
         .origin_data_folder_path
@@ -344,6 +369,10 @@ impl CliMainWorkerFactory {
         .unwrap() // must be set if storage key resolver returns a value
         .join(checksum::gen(&[key.as_bytes()]))
     });
+    let origin_storage_dir = match &shared.options.origin_storage_dir {
+      Some(dir) => Some(adopt_origin_storage(derived_storage_dir.as_deref(), dir)),
+      None => derived_storage_dir,
+    };
     let cache_storage_dir = maybe_storage_key.map(|key| {
       // TODO(@satyarohith): storage quota management
       // Note: we currently use temp_dir() to avoid managing storage size.
@@ -405,6 +434,26 @@ impl CliMainWorkerFactory {
   }
 }
 
+/// Moves storage created under the derived directory (before the embedder
+/// set one) into `dir` the first time it is used. When the move fails the
+/// old directory keeps being used so no data is lost.
+fn adopt_origin_storage(derived: Option<&Path>, dir: &Path) -> PathBuf {
+  let derived = match derived {
+    Some(derived) if derived.exists() && !dir.exists() => derived,
+    _ => return dir.to_path_buf(),
+  };
+  if let Some(parent) = dir.parent() {
+    let _ = std::fs::create_dir_all(parent);
+  }
+  match std::fs::rename(derived, dir) {
+    Ok(_) => dir.to_path_buf(),
+    Err(err) => {
+      log::warn!("failed to move origin storage {} to {}: {}", derived.display(), dir.display(), err);
+      derived.to_path_buf()
+    }
+  }
+}
+
 // TODO(bartlomieju): this callback could have default value
 // and not be required
 fn create_web_worker_preload_module_callback(_shared: &Arc<SharedWorkerState>) -> Arc<WorkerEventCb> {